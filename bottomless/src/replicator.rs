@@ -15,9 +15,9 @@ use chrono::{DateTime, LocalResult, TimeZone, Utc};
 use std::io::SeekFrom;
 use std::ops::Deref;
 use std::path::Path;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::watch::{channel, Receiver, Sender};
 use tokio::time::{timeout_at, Instant};
@@ -44,6 +44,8 @@ pub struct Replicator {
     restore_transaction_cache_fpath: Arc<str>,
     generation: Arc<ArcSwap<Uuid>>,
     pub commits_in_current_generation: Arc<AtomicU32>,
+    /// Time of the last successful upload to S3, in milliseconds since the unix epoch, or 0.
+    last_upload_ms: Arc<AtomicU64>,
     verify_crc: bool,
     pub bucket: String,
     pub db_path: String,
@@ -54,6 +56,111 @@ pub struct Replicator {
     s3_upload_max_parallelism: usize,
 }
 
+/// A handle to inspect the backup of a [Replicator] without locking it, since the replicator is
+/// held for the duration of the uploads of a checkpoint.
+#[derive(Clone, Debug)]
+pub struct Handle {
+    client: Client,
+    bucket: String,
+    db_name: String,
+    generation: Arc<ArcSwap<Uuid>>,
+    next_frame_no: Arc<AtomicU32>,
+    last_sent_frame_no: Arc<AtomicU32>,
+    last_upload_ms: Arc<AtomicU64>,
+}
+
+/// A generation stored in the bucket.
+#[derive(Debug, Clone)]
+pub struct GenerationInfo {
+    pub generation: Uuid,
+    /// When the generation was started, if it can be told from its id.
+    pub created_at: Option<SystemTime>,
+    /// Total size of the objects of the generation, in bytes.
+    pub size: u64,
+    pub object_count: usize,
+}
+
+impl Handle {
+    /// The generation the backup is currently written to.
+    pub fn generation(&self) -> Uuid {
+        **self.generation.load()
+    }
+
+    /// Returns number of frames waiting to be replicated.
+    pub fn pending_frames(&self) -> u32 {
+        let next_frame_no = self.next_frame_no.load(Ordering::Acquire);
+        let last_sent_frame_no = self.last_sent_frame_no.load(Ordering::Acquire);
+        next_frame_no.saturating_sub(last_sent_frame_no + 1)
+    }
+
+    /// Time of the last successful upload to S3 by this replicator, if any.
+    pub fn last_upload(&self) -> Option<SystemTime> {
+        match self.last_upload_ms.load(Ordering::Acquire) {
+            0 => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+
+    /// Lists the generations of the database stored in the bucket, newest first.
+    pub async fn list_generations(&self) -> Result<Vec<GenerationInfo>> {
+        let prefix = format!("{}-", self.db_name);
+        let mut generations: Vec<GenerationInfo> = Vec::new();
+        let mut next_marker = None;
+        loop {
+            let mut request = self
+                .client
+                .list_objects()
+                .bucket(&self.bucket)
+                .prefix(&prefix);
+            if let Some(marker) = next_marker.take() {
+                request = request.marker(marker);
+            }
+            let response = request.send().await?;
+            let objs = response.contents().unwrap_or_default();
+            for obj in objs {
+                let Some(key) = obj.key() else { continue };
+                let Some((generation, _)) = key
+                    .strip_prefix(prefix.as_str())
+                    .and_then(|key| key.split_once('/')) else { continue };
+                let Ok(generation) = Uuid::parse_str(generation) else { continue };
+                let size = obj.size().max(0) as u64;
+                match generations.last_mut() {
+                    Some(last) if last.generation == generation => {
+                        last.size += size;
+                        last.object_count += 1;
+                    }
+                    _ => generations.push(GenerationInfo {
+                        generation,
+                        created_at: Replicator::generation_to_timestamp(&generation).map(|ts| {
+                            let (seconds, nanos) = ts.to_unix();
+                            UNIX_EPOCH + Duration::new(seconds, nanos)
+                        }),
+                        size,
+                        object_count: 1,
+                    }),
+                }
+            }
+            next_marker = response
+                .is_truncated()
+                .then(|| objs.last().and_then(|obj| obj.key()).map(String::from))
+                .flatten();
+            if next_marker.is_none() {
+                break;
+            }
+        }
+
+        Ok(generations)
+    }
+}
+
+fn record_upload(last_upload_ms: &AtomicU64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    last_upload_ms.store(now, Ordering::Release);
+}
+
 #[derive(Debug)]
 pub struct FetchedResults {
     pub pages: Vec<(i32, Bytes)>,
@@ -252,6 +359,7 @@ impl Replicator {
         let next_frame_no = Arc::new(AtomicU32::new(1));
         let last_sent_frame_no = Arc::new(AtomicU32::new(0));
         let commits_in_current_generation = Arc::new(AtomicU32::new(0));
+        let last_upload_ms = Arc::new(AtomicU64::new(0));
 
         let (frames_outbox, mut frames_inbox) = tokio::sync::mpsc::channel(64);
         let _local_backup = {
@@ -302,6 +410,7 @@ impl Replicator {
             let client = client.clone();
             let bucket = options.bucket_name.clone();
             let max_parallelism = options.s3_upload_max_parallelism;
+            let last_upload_ms = last_upload_ms.clone();
             tokio::spawn(async move {
                 let sem = Arc::new(tokio::sync::Semaphore::new(max_parallelism));
                 while let Some(fdesc) = frames_inbox.recv().await {
@@ -310,6 +419,7 @@ impl Replicator {
                     let permit = sem.acquire_owned().await.unwrap();
                     let client = client.clone();
                     let bucket = bucket.clone();
+                    let last_upload_ms = last_upload_ms.clone();
                     tokio::spawn(async move {
                         let fpath = format!("{}/{}", bucket, fdesc);
                         let body = ByteStream::from_path(&fpath).await.unwrap();
@@ -324,6 +434,7 @@ impl Replicator {
                             tracing::error!("Failed to send {} to S3: {}", fpath, e);
                         } else {
                             tokio::fs::remove_file(&fpath).await.unwrap();
+                            record_upload(&last_upload_ms);
                             tracing::trace!("Uploaded to S3: {}", fpath);
                         }
                         drop(permit);
@@ -337,6 +448,7 @@ impl Replicator {
             page_size: Self::UNSET_PAGE_SIZE,
             generation,
             commits_in_current_generation,
+            last_upload_ms,
             next_frame_no,
             last_sent_frame_no,
             flush_trigger,
@@ -356,6 +468,18 @@ impl Replicator {
         self.next_frame_no.load(Ordering::Acquire)
     }
 
+    pub fn handle(&self) -> Handle {
+        Handle {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            db_name: self.db_name.clone(),
+            generation: self.generation.clone(),
+            next_frame_no: self.next_frame_no.clone(),
+            last_sent_frame_no: self.last_sent_frame_no.clone(),
+            last_upload_ms: self.last_upload_ms.clone(),
+        }
+    }
+
     pub fn last_known_frame(&self) -> u32 {
         self.next_frame_no() - 1
     }
@@ -497,6 +621,8 @@ impl Replicator {
     /// Submit next `frame_count` of frames to be replicated.
     pub fn submit_frames(&mut self, frame_count: u32) {
         let prev = self.next_frame_no.fetch_add(frame_count, Ordering::SeqCst);
+        self.commits_in_current_generation
+            .fetch_add(1, Ordering::SeqCst);
         let last_sent = self.last_sent_frame_no();
         let most_recent = prev + frame_count - 1;
         if most_recent - last_sent >= self.max_frames_per_batch as u32 {
@@ -650,6 +776,7 @@ impl Replicator {
             .body(ByteStream::from(Bytes::copy_from_slice(&change_counter)))
             .send()
             .await?;
+        record_upload(&self.last_upload_ms);
         tracing::debug!("Main db snapshot complete");
         Ok(())
     }
@@ -991,6 +1118,12 @@ impl Replicator {
                         last_received_frame_no += 1;
                     }
                     main_db_writer.flush().await?;
+                    tracing::info!(
+                        "Restored frames {}-{} of {}",
+                        first_frame_no,
+                        last_frame_no,
+                        last_consistent_frame
+                    );
                 }
                 next_marker = response
                     .is_truncated()
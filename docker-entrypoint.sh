@@ -29,6 +29,12 @@ if [ "$1" = '/bin/sqld' ]; then
     replica)
       server_args+=("--primary-grpc-url" "$SQLD_PRIMARY_URL")
       ;;
+    standby)
+      SQLD_GRPC_LISTEN_ADDR="${SQLD_GRPC_LISTEN_ADDR:-"0.0.0.0:5001"}"
+      server_args+=("--grpc-listen-addr" "$SQLD_GRPC_LISTEN_ADDR")
+      server_args+=("--primary-grpc-url" "$SQLD_PRIMARY_URL")
+      server_args+=("--standby")
+      ;;
     standalone)
       ;;
   esac
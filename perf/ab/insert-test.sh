@@ -0,0 +1,11 @@
+#!/bin/bash
+
+# Measures the throughput of small concurrent writes. Run it against a primary started with and
+# without `--group-commit-window-ms` to compare.
+
+URL=$1
+CONCURRENCY=${2:-100}
+
+curl -X POST -H "Content-Type: application/json" -d @insert-setup.json $URL
+
+ab -c $CONCURRENCY -n 20000 -p insert.json -T application/json $URL
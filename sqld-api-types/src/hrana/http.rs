@@ -0,0 +1,118 @@
+//! Structures for Hrana-over-HTTP.
+
+pub use super::{
+    Batch, BatchCond, BatchResult, BatchStep, Col, ConstraintViolation, DescribeCol, DescribeParam,
+    DescribeResult, Error, NamedArg, Stmt, StmtResult, Value,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PipelineRequestBody {
+    pub baton: Option<String>,
+    pub requests: Vec<StreamRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PipelineResponseBody {
+    pub baton: Option<String>,
+    pub base_url: Option<String>,
+    pub results: Vec<StreamResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamResult {
+    Ok { response: StreamResponse },
+    Error { error: Error },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamRequest {
+    Close(CloseStreamReq),
+    Execute(ExecuteStreamReq),
+    Batch(BatchStreamReq),
+    Sequence(SequenceStreamReq),
+    Describe(DescribeStreamReq),
+    StoreSql(StoreSqlStreamReq),
+    CloseSql(CloseSqlStreamReq),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamResponse {
+    Close(CloseStreamResp),
+    Execute(ExecuteStreamResp),
+    Batch(BatchStreamResp),
+    Sequence(SequenceStreamResp),
+    Describe(DescribeStreamResp),
+    StoreSql(StoreSqlStreamResp),
+    CloseSql(CloseSqlStreamResp),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseStreamReq {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseStreamResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExecuteStreamReq {
+    pub stmt: Stmt,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExecuteStreamResp {
+    pub result: StmtResult,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchStreamReq {
+    pub batch: Batch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchStreamResp {
+    pub result: BatchResult,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SequenceStreamReq {
+    #[serde(default)]
+    pub sql: Option<String>,
+    #[serde(default)]
+    pub sql_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SequenceStreamResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DescribeStreamReq {
+    #[serde(default)]
+    pub sql: Option<String>,
+    #[serde(default)]
+    pub sql_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DescribeStreamResp {
+    pub result: DescribeResult,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreSqlStreamReq {
+    pub sql_id: i32,
+    pub sql: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreSqlStreamResp {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseSqlStreamReq {
+    pub sql_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloseSqlStreamResp {}
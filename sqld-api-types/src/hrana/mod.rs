@@ -0,0 +1,252 @@
+//! Structures in Hrana that are common for WebSockets and HTTP.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub mod http;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Error {
+    pub message: String,
+    pub code: String,
+    /// The violated constraints, for the `SQLITE_CONSTRAINT` and `TRANSACTION_ROLLED_BACK` errors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub constraint_violations: Vec<ConstraintViolation>,
+}
+
+/// A violated constraint. SQLite reports the table and the constraint for most constraints, but
+/// the table, the referenced table and the row of a foreign key are only known for the
+/// violations of deferred foreign keys, found at commit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConstraintViolation {
+    /// `FOREIGN_KEY`, `UNIQUE`, `PRIMARY_KEY`, `NOT_NULL`, `CHECK`, `TRIGGER` or `OTHER`.
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<String>,
+    /// The columns of the constraint, or its name for a `CHECK` constraint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub constraint: Option<String>,
+    /// The table referenced by a foreign key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(
+        default,
+        with = "option_i64_as_str",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rowid: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stmt {
+    #[serde(default)]
+    pub sql: Option<String>,
+    #[serde(default)]
+    pub sql_id: Option<i32>,
+    #[serde(default)]
+    pub args: Vec<Value>,
+    #[serde(default)]
+    pub named_args: Vec<NamedArg>,
+    #[serde(default)]
+    pub want_rows: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NamedArg {
+    pub name: String,
+    pub value: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StmtResult {
+    pub cols: Vec<Col>,
+    pub rows: Vec<Vec<Value>>,
+    pub affected_row_count: u64,
+    #[serde(with = "option_i64_as_str")]
+    pub last_insert_rowid: Option<i64>,
+    #[serde(
+        default,
+        with = "option_u64_as_str",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub replication_index: Option<u64>,
+    /// Whether the primary executed the statements on behalf of the replica, as it does for all
+    /// the statements of a transaction that a replica forwards to it, reads included.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub proxied: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Col {
+    pub name: Option<String>,
+    pub decltype: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Batch {
+    pub steps: Vec<BatchStep>,
+    #[serde(
+        default,
+        with = "option_u64_as_str",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expected_replication_index: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchStep {
+    pub stmt: Stmt,
+    #[serde(default)]
+    pub condition: Option<BatchCond>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchResult {
+    pub step_results: Vec<Option<StmtResult>>,
+    pub step_errors: Vec<Option<Error>>,
+    #[serde(
+        default,
+        with = "option_u64_as_str",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub replication_index: Option<u64>,
+    /// Whether the primary executed the statements on behalf of the replica, as it does for all
+    /// the statements of a transaction that a replica forwards to it, reads included.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub proxied: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchCond {
+    Ok { step: i32 },
+    Error { step: i32 },
+    Not { cond: Box<BatchCond> },
+    And { conds: Vec<BatchCond> },
+    Or { conds: Vec<BatchCond> },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DescribeResult {
+    pub params: Vec<DescribeParam>,
+    pub cols: Vec<DescribeCol>,
+    pub is_explain: bool,
+    pub is_readonly: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DescribeParam {
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DescribeCol {
+    pub name: String,
+    pub decltype: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Value {
+    Null,
+    Integer {
+        #[serde(with = "i64_as_str")]
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
+    Text {
+        value: Arc<str>,
+    },
+    Blob {
+        #[serde(with = "bytes_as_base64", rename = "base64")]
+        value: Bytes,
+    },
+}
+
+mod i64_as_str {
+    use serde::{de, ser};
+    use serde::{de::Error as _, Serialize as _};
+
+    pub fn serialize<S: ser::Serializer>(value: &i64, ser: S) -> Result<S::Ok, S::Error> {
+        value.to_string().serialize(ser)
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<i64, D::Error> {
+        let str_value = <&'de str as de::Deserialize>::deserialize(de)?;
+        str_value.parse().map_err(|_| {
+            D::Error::invalid_value(
+                de::Unexpected::Str(str_value),
+                &"decimal integer as a string",
+            )
+        })
+    }
+}
+
+pub(crate) mod option_i64_as_str {
+    use serde::{de, ser};
+    use serde::{de::Error as _, Serialize as _};
+
+    pub fn serialize<S: ser::Serializer>(value: &Option<i64>, ser: S) -> Result<S::Ok, S::Error> {
+        value.map(|v| v.to_string()).serialize(ser)
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<Option<i64>, D::Error> {
+        let str_value = <Option<&'de str> as de::Deserialize>::deserialize(de)?;
+        str_value
+            .map(|str_value| {
+                str_value.parse().map_err(|_| {
+                    D::Error::invalid_value(
+                        de::Unexpected::Str(str_value),
+                        &"decimal integer as a string",
+                    )
+                })
+            })
+            .transpose()
+    }
+}
+
+mod option_u64_as_str {
+    use serde::{de, ser};
+    use serde::{de::Error as _, Serialize as _};
+
+    pub fn serialize<S: ser::Serializer>(value: &Option<u64>, ser: S) -> Result<S::Ok, S::Error> {
+        value.map(|v| v.to_string()).serialize(ser)
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<Option<u64>, D::Error> {
+        let str_value = <Option<&'de str> as de::Deserialize>::deserialize(de)?;
+        str_value
+            .map(|str_value| {
+                str_value.parse().map_err(|_| {
+                    D::Error::invalid_value(
+                        de::Unexpected::Str(str_value),
+                        &"decimal integer as a string",
+                    )
+                })
+            })
+            .transpose()
+    }
+}
+
+mod bytes_as_base64 {
+    use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+    use bytes::Bytes;
+    use serde::{de, ser};
+    use serde::{de::Error as _, Serialize as _};
+
+    pub fn serialize<S: ser::Serializer>(value: &Bytes, ser: S) -> Result<S::Ok, S::Error> {
+        STANDARD_NO_PAD.encode(value).serialize(ser)
+    }
+
+    pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<Bytes, D::Error> {
+        let text = <&'de str as de::Deserialize>::deserialize(de)?;
+        let text = text.trim_end_matches('=');
+        let bytes = STANDARD_NO_PAD.decode(text).map_err(|_| {
+            D::Error::invalid_value(de::Unexpected::Str(text), &"binary data encoded as base64")
+        })?;
+        Ok(Bytes::from(bytes))
+    }
+}
@@ -0,0 +1,562 @@
+//! Structures for the `POST /` and `POST /v1/explain` APIs.
+
+use std::collections::HashMap;
+
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use serde::de::Error as _;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize};
+
+/// Media type of the typed response format.
+pub const V2_CONTENT_TYPE: &str = "application/vnd.sqld.v2+json";
+
+/// A batch of statements, executed in order.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HttpQuery {
+    pub statements: Vec<QueryObject>,
+    /// Session settings applied before the statements are executed, like with `SET`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub settings: HashMap<String, serde_json::Value>,
+    /// The statements are rejected if the database changed since this replication index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_replication_index: Option<u64>,
+    /// Skip the statements that follow a failed one. They are executed by default, unless the
+    /// batch begins a transaction, which is then rolled back as a whole.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stop_on_error: bool,
+    /// Execute the statements atomically, in a transaction of their own: they are all rolled back
+    /// if one of them fails. The statements must not begin or end transactions themselves.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub transaction: bool,
+}
+
+/// A statement and its parameters. A statement without parameters can be sent as a plain string.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryObject {
+    pub q: String,
+    pub params: Params,
+}
+
+impl From<&str> for QueryObject {
+    fn from(q: &str) -> Self {
+        q.to_string().into()
+    }
+}
+
+impl From<String> for QueryObject {
+    fn from(q: String) -> Self {
+        Self {
+            q,
+            params: Params::Positional(Vec::new()),
+        }
+    }
+}
+
+/// The parameters of a statement, bound by position (`?`, `?1`) or by name (`:name`, `$name`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Params {
+    Positional(Vec<Value>),
+    Named(HashMap<String, Value>),
+}
+
+/// An SQLite value, mapped to its closest JSON type. Blobs are encoded as
+/// `{"base64": "..."}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// The response to an [`HttpQuery`], in the default format: one result per statement.
+pub type Response = Vec<Option<StepResult>>;
+
+/// The result of a statement of a batch, in the default format.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StepResult {
+    Ok {
+        results: ResultSet,
+    },
+    Error {
+        error: String,
+        /// Whether the failure of the statement rolled back the transaction of the batch.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        rolled_back: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ExecutionMeta>,
+}
+
+/// What a statement did to the database, like `sqlite3_changes()` and
+/// `sqlite3_last_insert_rowid()`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct ExecutionMeta {
+    /// The rows inserted, updated or deleted by the statement, `None` for the other statements.
+    pub affected_rows: Option<u64>,
+    /// The rowid of the last row inserted by the statement, `None` if it's not an `INSERT`.
+    pub last_insert_rowid: Option<i64>,
+}
+
+/// The response to an [`HttpQuery`], in the typed format requested with [`V2_CONTENT_TYPE`].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TypedResponse {
+    pub results: Vec<Option<TypedStepResult>>,
+}
+
+/// The result of a statement of a batch, in the typed format.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TypedStepResult {
+    Ok(TypedResultSet),
+    Error { error: StepError },
+}
+
+/// A result set with values encoded like in the Hrana protocol.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TypedResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<crate::hrana::Value>>,
+    pub affected_row_count: u64,
+    #[serde(with = "crate::hrana::option_i64_as_str")]
+    pub last_insert_rowid: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StepError {
+    pub message: String,
+    /// Stable code of the error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// The id under which the server logged an internal error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub constraint_violations: Vec<crate::hrana::ConstraintViolation>,
+    /// Whether the failure of the statement rolled back the transaction of the batch.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rolled_back: bool,
+}
+
+/// A line of the results streamed as newline-delimited JSON, with `?stream=true`. Each statement
+/// that runs gives a `columns` line, a `row` line per row, and a `done` or an `error` line: the
+/// statements skipped after a failure give no line.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamLine {
+    Columns {
+        step: usize,
+        columns: Vec<crate::hrana::Col>,
+    },
+    Row {
+        step: usize,
+        row: Vec<crate::hrana::Value>,
+    },
+    Done {
+        step: usize,
+        row_count: u64,
+        affected_row_count: u64,
+        #[serde(with = "crate::hrana::option_i64_as_str")]
+        last_insert_rowid: Option<i64>,
+    },
+    Error {
+        step: usize,
+        error: StepError,
+    },
+}
+
+/// The body of the responses to requests that failed as a whole.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    /// Stable code of the error, if it was raised by the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// The id under which the server logged an internal error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_id: Option<String>,
+}
+
+/// The response to `POST /v1/explain`: the rows of the `EXPLAIN` or `EXPLAIN QUERY PLAN`
+/// statement, and, for the latter, the query plan as a tree.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExplainResponse {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// The top-level steps of the query plan, `None` for the bytecode of an `EXPLAIN`.
+    pub plan: Option<Vec<PlanNode>>,
+}
+
+/// A step of a query plan, with the steps it is made of.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PlanNode {
+    pub id: i64,
+    pub detail: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<PlanNode>,
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Value::Null => serializer.serialize_none(),
+            Value::Integer(i) => serializer.serialize_i64(*i),
+            Value::Real(x) => serializer.serialize_f64(*x),
+            Value::Text(s) => serializer.serialize_str(s),
+            Value::Blob(b) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("base64", &BASE64_STANDARD_NO_PAD.encode(b))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Value;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a valid SQLite value")
+            }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Null)
+            }
+
+            fn visit_unit<E>(self) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Null)
+            }
+
+            fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Text(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Text(v.to_string()))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Integer(v))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Integer(v as i64))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Real(v))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                match map.next_entry::<String, String>()? {
+                    Some((k, v)) => {
+                        if k == "base64" {
+                            // FIXME: If the blog payload is too big, it may block the main thread
+                            // for too long in an async context. In this case, it may be necessary
+                            // to offload deserialization to a separate thread.
+                            let data = BASE64_STANDARD_NO_PAD
+                                .decode(v.trim_end_matches('='))
+                                .map_err(|e| {
+                                    A::Error::invalid_value(
+                                        serde::de::Unexpected::Str(&v),
+                                        &e.to_string().as_str(),
+                                    )
+                                })?;
+
+                            Ok(Value::Blob(data))
+                        } else {
+                            Err(A::Error::unknown_field(&k, &["blob"]))
+                        }
+                    }
+                    None => Err(A::Error::missing_field("blob")),
+                }
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Value::Integer(v as _))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Params {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Params;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an array or a map of parameters")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let mut params = Vec::new();
+                while let Some(val) = seq.next_element::<Value>()? {
+                    params.push(val);
+                }
+
+                Ok(Params::Positional(params))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut inner = HashMap::new();
+                while let Some((k, v)) = map.next_entry::<String, Value>()? {
+                    inner.insert(k, v);
+                }
+
+                Ok(Params::Named(inner))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryObject {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = QueryObject;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a string or an object")
+            }
+
+            fn visit_str<E>(self, q: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(q.into())
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut q = None;
+                let mut params = None;
+                while let Some(k) = map.next_key::<String>()? {
+                    match k.as_str() {
+                        "q" => {
+                            if q.is_none() {
+                                q.replace(map.next_value::<String>()?);
+                            } else {
+                                return Err(A::Error::duplicate_field("q"));
+                            }
+                        }
+                        "params" => {
+                            if params.is_none() {
+                                params.replace(map.next_value::<Params>()?);
+                            } else {
+                                return Err(A::Error::duplicate_field("params"));
+                            }
+                        }
+                        _ => return Err(A::Error::unknown_field(&k, &["q", "params"])),
+                    }
+                }
+
+                Ok(QueryObject {
+                    q: q.ok_or_else(|| A::Error::missing_field("q"))?,
+                    params: params.unwrap_or(Params::Positional(Vec::new())),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Integer(-42),
+            Value::Real(1.5),
+            Value::Text("hello".into()),
+            Value::Blob(b"hello\n".to_vec()),
+        ];
+        let json = serde_json::to_value(&values).unwrap();
+        assert_eq!(
+            json,
+            json!([null, -42, 1.5, "hello", {"base64": "aGVsbG8K"}])
+        );
+        assert_eq!(serde_json::from_value::<Vec<Value>>(json).unwrap(), values);
+    }
+
+    #[test]
+    fn statements_round_trip() {
+        let query = HttpQuery {
+            statements: vec![
+                "select 1".into(),
+                QueryObject {
+                    q: "select ?".into(),
+                    params: Params::Positional(vec![Value::Integer(1)]),
+                },
+            ],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&query).unwrap();
+        let parsed: HttpQuery = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.statements[0].q, "select 1");
+        assert_eq!(parsed.statements[0].params, Params::Positional(Vec::new()));
+        assert_eq!(parsed.statements[1].params, query.statements[1].params);
+
+        let parsed: HttpQuery =
+            serde_json::from_str(r#"{"statements": ["select 1"], "settings": {"a": "b"}}"#)
+                .unwrap();
+        assert_eq!(parsed.statements[0].q, "select 1");
+        assert_eq!(parsed.settings["a"], "b");
+    }
+
+    #[test]
+    fn parse_responses() {
+        let response: Response = serde_json::from_value(json!([
+            {"results": {"columns": ["x"], "rows": [[1], [{"base64": "aGVsbG8K"}]]}},
+            {"error": "no such table: t"},
+            null,
+            {"error": "UNIQUE constraint failed: t.x", "rolled_back": true},
+        ]))
+        .unwrap();
+        assert!(matches!(
+            &response[0],
+            Some(StepResult::Ok { results }) if results.rows[1][0] == Value::Blob(b"hello\n".to_vec())
+        ));
+        assert!(matches!(
+            &response[1],
+            Some(StepResult::Error {
+                rolled_back: false,
+                ..
+            })
+        ));
+        assert!(response[2].is_none());
+        assert!(matches!(
+            &response[3],
+            Some(StepResult::Error {
+                rolled_back: true,
+                ..
+            })
+        ));
+
+        let response: TypedResponse = serde_json::from_value(json!({"results": [
+            {
+                "columns": ["x"],
+                "rows": [[{"type": "integer", "value": "9223372036854775807"}]],
+                "affected_row_count": 0,
+                "last_insert_rowid": null,
+            },
+            {"error": {"message": "no such table: t"}},
+        ]}))
+        .unwrap();
+        assert!(matches!(
+            &response.results[0],
+            Some(TypedStepResult::Ok(TypedResultSet { rows, .. }))
+                if matches!(rows[0][0], crate::hrana::Value::Integer { value: i64::MAX })
+        ));
+        assert!(matches!(
+            &response.results[1],
+            Some(TypedStepResult::Error { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_stream_lines() {
+        let body = r#"{"type":"columns","step":0,"columns":[{"name":"x","decltype":"INTEGER"}]}
+{"type":"row","step":0,"row":[{"type":"integer","value":"1"}]}
+{"type":"done","step":0,"row_count":1,"affected_row_count":0,"last_insert_rowid":null}
+{"type":"error","step":1,"error":{"message":"no such table: t","code":"SQLITE_ERROR"}}
+"#;
+        let lines = body
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<StreamLine>, _>>()
+            .unwrap();
+        assert!(matches!(
+            &lines[0],
+            StreamLine::Columns { step: 0, columns } if columns[0].name.as_deref() == Some("x")
+        ));
+        assert!(matches!(
+            &lines[1],
+            StreamLine::Row { step: 0, row } if matches!(row[0], crate::hrana::Value::Integer { value: 1 })
+        ));
+        assert!(matches!(
+            &lines[2],
+            StreamLine::Done {
+                step: 0,
+                row_count: 1,
+                last_insert_rowid: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &lines[3],
+            StreamLine::Error { step: 1, error } if error.code.as_deref() == Some("SQLITE_ERROR")
+        ));
+    }
+}
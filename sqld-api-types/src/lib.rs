@@ -0,0 +1,9 @@
+//! Types of the requests and responses of the APIs of `sqld`.
+//!
+//! The server serializes its messages with these types, so clients depending on this crate can't
+//! drift from the server.
+//! - [`http`]: the `POST /` and `POST /v1/explain` APIs, documented in `docs/http_api.md`,
+//! - [`hrana`]: the Hrana protocol, over WebSockets and HTTP, documented in `docs/HRANA_*_SPEC.md`.
+
+pub mod hrana;
+pub mod http;
@@ -0,0 +1,230 @@
+//! A thin client for the HTTP API of `sqld`.
+//!
+//! The requests and responses are the types of `sqld-api-types`, which the server serializes its
+//! messages with. Batches of statements are sent to `POST /`, and transactions are run on a Hrana
+//! stream, over `POST /v2/pipeline`.
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sqld_api_types::hrana::http::{
+    CloseStreamReq, ExecuteStreamReq, PipelineRequestBody, PipelineResponseBody, StreamRequest,
+    StreamResponse, StreamResult,
+};
+use sqld_api_types::http::{ErrorResponse, HttpQuery, Response, StepResult};
+
+pub use sqld_api_types::hrana;
+pub use sqld_api_types::http::{ExecutionMeta, Params, QueryObject, ResultSet, Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("request failed with status {status}: {message}")]
+    Api { status: StatusCode, message: String },
+    #[error("statement {} failed: {message}", .step + 1)]
+    Statement { step: usize, message: String },
+    #[error("stream request failed: {} ({})", .0.message, .0.code)]
+    Stream(hrana::Error),
+    #[error("unexpected response from the server: {0}")]
+    UnexpectedResponse(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone)]
+enum Auth {
+    Bearer(String),
+    Basic { user: String, password: String },
+}
+
+/// A client of an `sqld` node, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    url: String,
+    auth: Option<Auth>,
+}
+
+impl Client {
+    /// Creates a client of the node listening for HTTP requests at `url`, like
+    /// `http://127.0.0.1:8080`.
+    pub fn new(url: impl Into<String>) -> Self {
+        let mut url = url.into();
+        while url.ends_with('/') {
+            url.pop();
+        }
+
+        Self {
+            http: reqwest::Client::new(),
+            url,
+            auth: None,
+        }
+    }
+
+    /// Authenticates the requests with a JWT.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth = Some(Auth::Bearer(token.into()));
+        self
+    }
+
+    /// Authenticates the requests with HTTP basic authentication.
+    pub fn with_basic_auth(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some(Auth::Basic {
+            user: user.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    /// Executes `stmts` in order, and returns the result set of each statement. Fails with
+    /// [`Error::Statement`] if one of the statements failed.
+    pub async fn execute<I, S>(&self, stmts: I) -> Result<Vec<ResultSet>>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<QueryObject>,
+    {
+        let query = HttpQuery {
+            statements: stmts.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        };
+        let response: Response = self.post(&format!("{}/", self.url), &query).await?;
+
+        response
+            .into_iter()
+            .enumerate()
+            .map(|(step, result)| match result {
+                Some(StepResult::Ok { results }) => Ok(results),
+                Some(StepResult::Error { error, .. }) => Err(Error::Statement {
+                    step,
+                    message: error,
+                }),
+                // statements that don't return anything, like `BEGIN`
+                None => Ok(ResultSet {
+                    columns: Vec::new(),
+                    rows: Vec::new(),
+                    meta: None,
+                }),
+            })
+            .collect()
+    }
+
+    /// Begins a transaction. The transaction is rolled back if it is dropped without being
+    /// committed, once the server expires its stream.
+    pub async fn transaction(&self) -> Result<Transaction<'_>> {
+        let mut txn = Transaction {
+            client: self,
+            baton: None,
+            base_url: None,
+        };
+        txn.execute("BEGIN", []).await?;
+        Ok(txn)
+    }
+
+    async fn post<B: Serialize, R: DeserializeOwned>(&self, url: &str, body: &B) -> Result<R> {
+        let resp = self
+            .authenticate(self.http.post(url))
+            .json(body)
+            .send()
+            .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await?;
+            let message = match serde_json::from_str::<ErrorResponse>(&body) {
+                Ok(ErrorResponse { error, .. }) => error,
+                Err(_) => body,
+            };
+            return Err(Error::Api { status, message });
+        }
+
+        Ok(resp.json().await?)
+    }
+
+    fn authenticate(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.auth {
+            Some(Auth::Bearer(token)) => req.bearer_auth(token),
+            Some(Auth::Basic { user, password }) => req.basic_auth(user, Some(password)),
+            None => req,
+        }
+    }
+}
+
+/// A transaction, run on a Hrana stream.
+pub struct Transaction<'a> {
+    client: &'a Client,
+    baton: Option<String>,
+    base_url: Option<String>,
+}
+
+impl Transaction<'_> {
+    /// Executes a statement in the transaction.
+    pub async fn execute(
+        &mut self,
+        sql: impl Into<String>,
+        args: impl IntoIterator<Item = hrana::Value>,
+    ) -> Result<hrana::StmtResult> {
+        let stmt = hrana::Stmt {
+            sql: Some(sql.into()),
+            sql_id: None,
+            args: args.into_iter().collect(),
+            named_args: Vec::new(),
+            want_rows: Some(true),
+        };
+        match self
+            .pipeline(vec![StreamRequest::Execute(ExecuteStreamReq { stmt })])
+            .await?
+            .pop()
+        {
+            Some(StreamResponse::Execute(resp)) => Ok(resp.result),
+            other => Err(Error::UnexpectedResponse(format!("{other:?}"))),
+        }
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.finish("COMMIT").await
+    }
+
+    pub async fn rollback(self) -> Result<()> {
+        self.finish("ROLLBACK").await
+    }
+
+    async fn finish(mut self, sql: &str) -> Result<()> {
+        let stmt = hrana::Stmt {
+            sql: Some(sql.into()),
+            sql_id: None,
+            args: Vec::new(),
+            named_args: Vec::new(),
+            want_rows: Some(false),
+        };
+        self.pipeline(vec![
+            StreamRequest::Execute(ExecuteStreamReq { stmt }),
+            StreamRequest::Close(CloseStreamReq {}),
+        ])
+        .await?;
+        Ok(())
+    }
+
+    async fn pipeline(&mut self, requests: Vec<StreamRequest>) -> Result<Vec<StreamResponse>> {
+        let url = format!(
+            "{}/v2/pipeline",
+            self.base_url.as_deref().unwrap_or(&self.client.url)
+        );
+        let body = PipelineRequestBody {
+            baton: self.baton.take(),
+            requests,
+        };
+        let resp: PipelineResponseBody = self.client.post(&url, &body).await?;
+        self.baton = resp.baton;
+        if let Some(base_url) = resp.base_url {
+            self.base_url = Some(base_url.trim_end_matches('/').to_string());
+        }
+
+        resp.results
+            .into_iter()
+            .map(|result| match result {
+                StreamResult::Ok { response } => Ok(response),
+                StreamResult::Error { error } => Err(Error::Stream(error)),
+            })
+            .collect()
+    }
+}
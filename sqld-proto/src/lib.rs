@@ -0,0 +1,228 @@
+//! Protocol buffers definitions of the RPC services of sqld: the replication log, streamed by the
+//! primary to its replicas, and the write proxy, through which replicas forward their writes to
+//! the primary.
+//!
+//! The `.proto` files are in the `proto` directory of this crate, for clients in other languages.
+//! The packages are versioned, and evolve following the compatibility rules of the README.
+
+use std::fmt;
+
+pub mod wal_log {
+    pub mod v1 {
+        #![allow(clippy::all)]
+        tonic::include_proto!("wal_log.v1");
+    }
+}
+
+pub mod proxy {
+    pub mod v1 {
+        #![allow(clippy::all)]
+        tonic::include_proto!("proxy.v1");
+    }
+}
+
+use self::wal_log::v1::snapshot_chunk::Chunk;
+use self::wal_log::v1::{Frame, ProtocolVersion, SnapshotChunk, SnapshotHeader};
+
+/// Major version of the protocol. Nodes only talk to nodes of the same major version.
+pub const PROTOCOL_MAJOR: u32 = 1;
+/// Minor version of the protocol, bumped by the backward compatible changes.
+pub const PROTOCOL_MINOR: u32 = 7;
+/// The metadata in which a replica presents the `HelloResponse.session_token` of its handshake.
+pub const REPLICA_SESSION_METADATA: &str = "x-sqld-replica-session";
+
+/// The packages of the protocol, and their names before they were versioned.
+const LEGACY_PACKAGES: &[(&str, &str)] = &[("wal_log", "wal_log.v1"), ("proxy", "proxy.v1")];
+
+impl ProtocolVersion {
+    /// The version of the protocol defined by this crate.
+    pub const CURRENT: Self = Self {
+        major: PROTOCOL_MAJOR,
+        minor: PROTOCOL_MINOR,
+    };
+
+    /// The version spoken by the nodes that predate the versioning of the protocol.
+    pub const UNVERSIONED: Self = Self { major: 1, minor: 0 };
+
+    /// Checks the version a peer sent, if any, and returns it.
+    pub fn check_peer(version: Option<&Self>) -> Result<Self, IncompatibleVersion> {
+        let version = version.cloned().unwrap_or(Self::UNVERSIONED);
+        if version.major != PROTOCOL_MAJOR {
+            return Err(IncompatibleVersion { peer: version });
+        }
+
+        Ok(version)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl From<SnapshotHeader> for SnapshotChunk {
+    fn from(header: SnapshotHeader) -> Self {
+        Self {
+            chunk: Some(Chunk::Header(header)),
+        }
+    }
+}
+
+impl From<Frame> for SnapshotChunk {
+    fn from(frame: Frame) -> Self {
+        Self {
+            chunk: Some(Chunk::Frame(frame)),
+        }
+    }
+}
+
+/// A peer speaks a major version of the protocol other than ours.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncompatibleVersion {
+    pub peer: ProtocolVersion,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "peer speaks version {} of the sqld replication protocol, which is incompatible with version {}: primaries and replicas must run the same major version",
+            self.peer,
+            ProtocolVersion::CURRENT
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleVersion {}
+
+/// Maps the path of a gRPC call made by a client that predates the versioning of the packages,
+/// like `/wal_log.ReplicationLog/Hello`, to the path of the current package.
+pub fn upgrade_legacy_path(path: &str) -> Option<String> {
+    let (package, rest) = path.strip_prefix('/')?.split_once('.')?;
+    let (_, versioned) = LEGACY_PACKAGES
+        .iter()
+        .find(|(legacy, _)| *legacy == package)?;
+    // versioned paths have a version after the package name, like `wal_log.v1.ReplicationLog`
+    let is_version = |s: &str| {
+        s.strip_prefix('v').map_or(false, |n| {
+            !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())
+        })
+    };
+    if rest
+        .split_once('.')
+        .map_or(false, |(version, _)| is_version(version))
+    {
+        return None;
+    }
+
+    Some(format!("/{versioned}.{rest}"))
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use super::wal_log::v1::{CompressionCodec, HelloRequest, HelloResponse, ReplicationMode};
+    use super::*;
+
+    /// `HelloRequest` before the versioning of the protocol.
+    #[derive(Clone, PartialEq, Message)]
+    struct HelloRequestV0 {
+        #[prost(string, repeated, tag = "1")]
+        table_filter: Vec<String>,
+    }
+
+    /// `HelloResponse` before the versioning of the protocol.
+    #[derive(Clone, PartialEq, Message)]
+    struct HelloResponseV0 {
+        #[prost(string, tag = "1")]
+        generation_id: String,
+        #[prost(uint64, tag = "2")]
+        generation_start_index: u64,
+        #[prost(string, tag = "3")]
+        database_id: String,
+        #[prost(enumeration = "ReplicationMode", tag = "4")]
+        mode: i32,
+        #[prost(string, optional, tag = "5")]
+        logical_log_id: Option<String>,
+        #[prost(string, repeated, tag = "6")]
+        advertise_addrs: Vec<String>,
+    }
+
+    #[test]
+    fn old_client_hello() {
+        let old = HelloRequestV0 {
+            table_filter: vec!["users".into()],
+        };
+        let req = HelloRequest::decode(old.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(req.table_filter, ["users"]);
+        assert_eq!(req.protocol_version, None);
+        assert_eq!(
+            ProtocolVersion::check_peer(req.protocol_version.as_ref()),
+            Ok(ProtocolVersion::UNVERSIONED)
+        );
+
+        // an old client ignores the fields it doesn't know
+        let resp = HelloResponse {
+            generation_id: "generation".into(),
+            generation_start_index: 42,
+            database_id: "database".into(),
+            mode: ReplicationMode::Physical.into(),
+            logical_log_id: None,
+            advertise_addrs: vec!["http://primary:8080".into()],
+            protocol_version: Some(ProtocolVersion::CURRENT),
+            namespace: None,
+            session_token: None,
+            frame_batches: true,
+            compression_codec: CompressionCodec::Zstd.into(),
+            next_frame_no: Some(1024),
+        };
+        let old = HelloResponseV0::decode(resp.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(old.generation_id, resp.generation_id);
+        assert_eq!(old.generation_start_index, 42);
+        assert_eq!(old.database_id, resp.database_id);
+        assert_eq!(old.advertise_addrs, resp.advertise_addrs);
+    }
+
+    #[test]
+    fn incompatible_versions() {
+        let current = ProtocolVersion::CURRENT;
+        assert_eq!(ProtocolVersion::check_peer(Some(&current)), Ok(current));
+        let newer_minor = ProtocolVersion {
+            major: PROTOCOL_MAJOR,
+            minor: PROTOCOL_MINOR + 1,
+        };
+        assert_eq!(
+            ProtocolVersion::check_peer(Some(&newer_minor)),
+            Ok(newer_minor)
+        );
+
+        let newer_major = ProtocolVersion {
+            major: PROTOCOL_MAJOR + 1,
+            minor: 0,
+        };
+        let err = ProtocolVersion::check_peer(Some(&newer_major)).unwrap_err();
+        assert_eq!(err.peer, newer_major);
+        assert!(err.to_string().contains("version 2.0"), "{err}");
+    }
+
+    #[test]
+    fn legacy_paths() {
+        assert_eq!(
+            upgrade_legacy_path("/wal_log.ReplicationLog/Hello").as_deref(),
+            Some("/wal_log.v1.ReplicationLog/Hello")
+        );
+        assert_eq!(
+            upgrade_legacy_path("/proxy.Proxy/Execute").as_deref(),
+            Some("/proxy.v1.Proxy/Execute")
+        );
+        assert_eq!(
+            upgrade_legacy_path("/wal_log.v1.ReplicationLog/Hello"),
+            None
+        );
+        assert_eq!(upgrade_legacy_path("/proxy.v1.Proxy/Execute"), None);
+        assert_eq!(upgrade_legacy_path("/grpc.health.v1.Health/Check"), None);
+        assert_eq!(upgrade_legacy_path("/"), None);
+    }
+}
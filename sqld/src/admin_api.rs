@@ -1,29 +1,104 @@
 use anyhow::Context as _;
-use axum::{extract::State, Json};
-use serde::Deserialize;
+use axum::extract::{Path, Query, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::io::StreamReader;
 
+use crate::auth::{Auth, Authenticated, Authorized};
+use crate::connections::{self, ConnectionStatus};
+use crate::database::analyze::{AnalyzeError, AutoAnalyze};
 use crate::database::config::{DatabaseConfig, DatabaseConfigStore};
+use crate::database::dump::loader::{DumpLoader, LoadError, LoadedDatabase, StagedDatabase};
+use crate::database::query_stats::{QueryStats, QueryStatsEntry, SortKey};
+use crate::database::vacuum::{Vacuum, VacuumError, VacuumRequest};
+use crate::http::stats::StatsResponse;
+use crate::namespace::{
+    NamespaceError, NamespaceInfo, NamespaceQuotas, NamespaceStats, NamespaceStore,
+};
+use crate::reload::{ReloadResult, Reloader};
+use crate::replication::backup::{Backup, BackupError, BackupStatus, GenerationStatus};
+use crate::replication::restore::{self, RestoreError, RestorePoint, RestoreTarget};
+use crate::replication::standby::{PromoteError, Promotion, Standby};
+use crate::replication::{FrameNo, ReplicationLogger};
+use crate::rpc::replication_log::{ReplicaStatus, Replicas};
+use crate::stats::Stats;
+use crate::utils::shared::Shared;
 
 struct AppState {
     db_config_store: Arc<DatabaseConfigStore>,
+    /// Only set on the primary
+    vacuum: Option<Arc<Vacuum>>,
+    /// Only set on the primary
+    logger: Option<Arc<ReplicationLogger>>,
+    db_path: PathBuf,
+    /// Only set if statistics collection is enabled
+    query_stats: Option<Arc<QueryStats>>,
+    /// Only set on the primary
+    auto_analyze: Option<Arc<AutoAnalyze>>,
+    stats: Stats,
+    /// Only set on a standby
+    standby: Option<Arc<Standby>>,
+    /// Only set on a primary serving namespaces
+    namespaces: Option<Arc<NamespaceStore>>,
+    /// Only set on the primary
+    replicas: Option<Arc<Replicas>>,
+    /// Only set on the primary, with bottomless replication enabled
+    backup: Option<Arc<Backup>>,
+    /// Only set on the primary
+    dump_loader: Option<Arc<DumpLoader>>,
+    max_load_size: u64,
+    /// Only set if the configuration can be reloaded
+    reloader: Option<Arc<Reloader>>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_admin_api(
     addr: SocketAddr,
+    auth: Shared<Arc<Auth>>,
     db_config_store: Arc<DatabaseConfigStore>,
+    vacuum: Option<Arc<Vacuum>>,
+    logger: Option<Arc<ReplicationLogger>>,
+    db_path: PathBuf,
+    query_stats: Option<Arc<QueryStats>>,
+    auto_analyze: Option<Arc<AutoAnalyze>>,
+    stats: Stats,
+    standby: Option<Arc<Standby>>,
+    namespaces: Option<Arc<NamespaceStore>>,
+    replicas: Option<Arc<Replicas>>,
+    backup: Option<Arc<Backup>>,
+    dump_loader: Option<Arc<DumpLoader>>,
+    max_load_size: u64,
+    reloader: Arc<Reloader>,
 ) -> anyhow::Result<()> {
-    use axum::routing::{get, post};
-    let router = axum::Router::new()
-        .route("/", get(handle_get_index))
-        .route("/v1/config", get(handle_get_config))
-        .route("/v1/block", post(handle_post_block))
-        .with_state(Arc::new(AppState { db_config_store }));
+    let app_state = AppState {
+        db_config_store,
+        vacuum,
+        logger,
+        db_path,
+        query_stats,
+        auto_analyze,
+        stats,
+        standby,
+        namespaces,
+        replicas,
+        backup,
+        dump_loader,
+        max_load_size,
+        reloader: reloader.can_reload().then_some(reloader),
+    };
 
     let server = hyper::Server::try_bind(&addr)
         .context("Could not bind admin HTTP API server")?
-        .serve(router.into_make_service());
+        .serve(router(app_state, auth).into_make_service());
 
     tracing::info!(
         "Listening for admin HTTP API requests on {}",
@@ -33,10 +108,156 @@ pub async fn run_admin_api(
     Ok(())
 }
 
+fn router(app_state: AppState, auth: Shared<Arc<Auth>>) -> axum::Router {
+    use axum::routing::{get, post};
+    axum::Router::new()
+        .route("/", get(handle_get_index))
+        .route("/v1/config", get(handle_get_config))
+        .route("/v1/block", post(handle_post_block))
+        .route("/v1/vacuum", post(handle_post_vacuum))
+        .route("/v1/analyze", post(handle_post_analyze))
+        .route("/v1/query_stats", get(handle_get_query_stats))
+        .route("/v1/query_stats/reset", post(handle_post_query_stats_reset))
+        .route("/v1/hard_reset/rearm", post(handle_post_hard_reset_rearm))
+        .route(
+            "/v1/storage/clear_degraded",
+            post(handle_post_storage_clear_degraded),
+        )
+        .route("/v1/stats", get(handle_get_stats))
+        .route("/v1/connections", get(handle_get_connections))
+        .route(
+            "/v1/connections/:id/interrupt",
+            post(handle_post_connection_interrupt),
+        )
+        .route("/v1/replication", get(handle_get_replication))
+        .route("/v1/promote", post(handle_post_promote))
+        .route("/v1/restore/:name", post(handle_post_restore))
+        .route("/v1/load", post(handle_post_load))
+        .route("/v1/reload", post(handle_post_reload))
+        .route("/v1/backup/status", get(handle_get_backup_status))
+        .route("/v1/backup/snapshot", post(handle_post_backup_snapshot))
+        .route("/v1/backup/generations", get(handle_get_backup_generations))
+        .route("/v1/namespaces", get(handle_get_namespaces))
+        .route(
+            "/v1/namespaces/:name/create",
+            post(handle_post_create_namespace),
+        )
+        .route(
+            "/v1/namespaces/:name/stats",
+            get(handle_get_namespace_stats),
+        )
+        .route(
+            "/v1/namespaces/:name/quotas",
+            post(handle_post_namespace_quotas),
+        )
+        .layer(axum::middleware::from_fn_with_state(auth, authenticate))
+        .with_state(Arc::new(app_state))
+}
+
+/// Only lets through the requests with full access, according to the admin authentication.
+async fn authenticate<B>(
+    State(auth): State<Shared<Arc<Auth>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let auth_header = req.headers().get(hyper::header::AUTHORIZATION);
+    match auth.get().authenticate_http(auth_header) {
+        Ok(Authenticated::Authorized(Authorized::FullAccess)) => next.run(req).await,
+        Ok(_) => (
+            axum::http::StatusCode::FORBIDDEN,
+            "the admin API requires full access",
+        )
+            .into_response(),
+        Err(err) => (axum::http::StatusCode::UNAUTHORIZED, err.to_string()).into_response(),
+    }
+}
+
 async fn handle_get_index() -> &'static str {
     "Welcome to the sqld admin API"
 }
 
+async fn handle_get_stats(State(app_state): State<Arc<AppState>>) -> Json<StatsResponse> {
+    let mut resp = StatsResponse::from(&app_state.stats);
+    if let Some(auto_analyze) = app_state.auto_analyze.as_ref() {
+        resp.analyze = auto_analyze.tables();
+    }
+    Json(resp)
+}
+
+async fn handle_get_connections() -> Json<Vec<ConnectionStatus>> {
+    Json(connections::list())
+}
+
+#[derive(Debug, Deserialize)]
+struct InterruptParams {
+    /// Whether to close the connection once interrupted.
+    #[serde(default)]
+    close: bool,
+}
+
+async fn handle_post_connection_interrupt(
+    Path(id): Path<u64>,
+    Query(params): Query<InterruptParams>,
+) -> (axum::http::StatusCode, String) {
+    if connections::interrupt(id, params.close) {
+        (axum::http::StatusCode::OK, "OK".into())
+    } else {
+        (
+            axum::http::StatusCode::NOT_FOUND,
+            format!("no client connection with id {id}"),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct ReplicationResponse {
+    database_id: String,
+    generation_id: String,
+    generation_start_index: FrameNo,
+    /// Last frame committed to the replication log.
+    frame_no: Option<FrameNo>,
+    /// First frame of the replication log, the previous ones are in snapshots.
+    log_start_frame_no: FrameNo,
+    log_frame_count: u64,
+    replicas: Vec<ReplicaStatus>,
+}
+
+async fn handle_get_replication(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<ReplicationResponse>, (axum::http::StatusCode, String)> {
+    let (Some(logger), Some(replicas)) = (app_state.logger.as_ref(), app_state.replicas.as_ref())
+    else {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "replication status is only reported by the primary".into(),
+        ));
+    };
+
+    let database_id = logger.database_id().map_err(|err| {
+        tracing::warn!("Could not read the database id: {err}");
+        (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed".to_string(),
+        )
+    })?;
+    let frame_no = *logger.new_frame_notifier.borrow();
+    let (log_start_frame_no, log_frame_count) = {
+        let log_file = logger.log_file.read();
+        let header = log_file.header();
+        (header.start_frame_no, header.frame_count)
+    };
+
+    Ok(Json(ReplicationResponse {
+        database_id: database_id.to_string(),
+        generation_id: logger.generation.id.to_string(),
+        generation_start_index: logger.generation.start_index,
+        frame_no: (frame_no != FrameNo::MAX).then_some(frame_no),
+        log_start_frame_no,
+        log_frame_count,
+        replicas: replicas.status(),
+    }))
+}
+
 async fn handle_get_config(State(app_state): State<Arc<AppState>>) -> Json<Arc<DatabaseConfig>> {
     Json(app_state.db_config_store.get())
 }
@@ -66,3 +287,578 @@ async fn handle_post_block(
         }
     }
 }
+
+async fn handle_post_vacuum(
+    State(app_state): State<Arc<AppState>>,
+    Json(req): Json<VacuumRequest>,
+) -> (axum::http::StatusCode, String) {
+    let Some(vacuum) = app_state.vacuum.as_ref() else {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            "vacuum can only be performed on the primary".into(),
+        );
+    };
+
+    match vacuum.start(req) {
+        Ok(()) => (axum::http::StatusCode::ACCEPTED, "Vacuum started".into()),
+        Err(err @ VacuumError::InProgress) => (axum::http::StatusCode::CONFLICT, err.to_string()),
+        Err(err @ VacuumError::IncrementalDisabled) => {
+            (axum::http::StatusCode::BAD_REQUEST, err.to_string())
+        }
+    }
+}
+
+async fn handle_post_analyze(
+    State(app_state): State<Arc<AppState>>,
+) -> (axum::http::StatusCode, String) {
+    let Some(auto_analyze) = app_state.auto_analyze.as_ref() else {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            "analyze can only be performed on the primary".into(),
+        );
+    };
+
+    match auto_analyze.start() {
+        Ok(()) => (axum::http::StatusCode::ACCEPTED, "Analyze started".into()),
+        Err(err @ AnalyzeError::InProgress) => (axum::http::StatusCode::CONFLICT, err.to_string()),
+    }
+}
+
+fn default_query_stats_limit() -> usize {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryStatsParams {
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default = "default_query_stats_limit")]
+    limit: usize,
+}
+
+#[derive(Serialize)]
+struct QueryStatsResponse {
+    sample_rate: f64,
+    queries: Vec<QueryStatsEntry>,
+}
+
+const QUERY_STATS_DISABLED: &str =
+    "query statistics collection is disabled, start the server with `--stats-collection`";
+
+async fn handle_get_query_stats(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<QueryStatsParams>,
+) -> Result<Json<QueryStatsResponse>, (axum::http::StatusCode, &'static str)> {
+    let Some(query_stats) = app_state.query_stats.as_ref() else {
+        return Err((axum::http::StatusCode::NOT_FOUND, QUERY_STATS_DISABLED));
+    };
+
+    Ok(Json(QueryStatsResponse {
+        sample_rate: query_stats.sample_rate(),
+        queries: query_stats.top(params.sort, params.limit),
+    }))
+}
+
+async fn handle_post_query_stats_reset(
+    State(app_state): State<Arc<AppState>>,
+) -> (axum::http::StatusCode, &'static str) {
+    let Some(query_stats) = app_state.query_stats.clone() else {
+        return (axum::http::StatusCode::NOT_FOUND, QUERY_STATS_DISABLED);
+    };
+
+    match tokio::task::spawn_blocking(move || query_stats.reset()).await {
+        Ok(Ok(())) => (axum::http::StatusCode::OK, "OK"),
+        Ok(Err(err)) => {
+            tracing::warn!("Could not reset query stats: {err}");
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed")
+        }
+        Err(err) => {
+            tracing::warn!("Query stats reset task failed: {err}");
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed")
+        }
+    }
+}
+
+async fn handle_post_hard_reset_rearm() -> (axum::http::StatusCode, &'static str) {
+    match crate::HARD_RESET.rearm() {
+        Ok(()) => (axum::http::StatusCode::OK, "OK"),
+        Err(err) => {
+            tracing::warn!("Could not re-arm hard resets: {err}");
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed")
+        }
+    }
+}
+
+async fn handle_post_storage_clear_degraded(
+    State(app_state): State<Arc<AppState>>,
+) -> (axum::http::StatusCode, String) {
+    if !crate::STORAGE_HEALTH.is_degraded() {
+        return (axum::http::StatusCode::OK, "OK".into());
+    }
+
+    let res = tokio::task::spawn_blocking(move || {
+        crate::STORAGE_HEALTH.clear(&app_state.db_path, app_state.logger.as_deref())
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => (axum::http::StatusCode::OK, "OK".into()),
+        Ok(Err(err)) => {
+            tracing::warn!("Storage is still degraded: {err}");
+            (
+                axum::http::StatusCode::CONFLICT,
+                format!("Storage is still degraded: {err}"),
+            )
+        }
+        Err(err) => {
+            tracing::warn!("Storage check task failed: {err}");
+            (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed".into(),
+            )
+        }
+    }
+}
+
+/// Delay between the response to a promotion and the restart of the promoted standby.
+const PROMOTION_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+async fn handle_post_promote(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Promotion>, (axum::http::StatusCode, String)> {
+    let Some(standby) = app_state.standby.as_ref() else {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "only a standby can be promoted".into(),
+        ));
+    };
+
+    match standby.promote().await {
+        Ok(promotion) => {
+            // restart as a primary, once the response is sent
+            tokio::spawn(async {
+                tokio::time::sleep(PROMOTION_RESTART_DELAY).await;
+                crate::RESTART.notify_one();
+            });
+            Ok(Json(promotion))
+        }
+        Err(err @ (PromoteError::AlreadyPromoted | PromoteError::NotCaughtUp { .. })) => {
+            Err((axum::http::StatusCode::CONFLICT, err.to_string()))
+        }
+        Err(PromoteError::Other(err)) => {
+            tracing::warn!("Could not promote the standby: {err}");
+            Err((
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed".into(),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreReq {
+    /// Restore the last transaction committed at or before this frame.
+    #[serde(default)]
+    frame_no: Option<FrameNo>,
+    /// Restore the last transaction committed at or before this time, in milliseconds since the
+    /// unix epoch.
+    #[serde(default)]
+    timestamp_ms: Option<u64>,
+}
+
+async fn handle_post_restore(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(req): Json<RestoreReq>,
+) -> Result<Json<RestorePoint>, (axum::http::StatusCode, String)> {
+    let Some(logger) = app_state.logger.clone() else {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "restores can only be performed on the primary".into(),
+        ));
+    };
+    let target = match (req.frame_no, req.timestamp_ms) {
+        (Some(frame_no), None) => RestoreTarget::FrameNo(frame_no),
+        (None, Some(ms)) => {
+            RestoreTarget::Timestamp(std::time::UNIX_EPOCH + Duration::from_millis(ms))
+        }
+        _ => {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                "exactly one of `frame_no` and `timestamp_ms` is required".into(),
+            ))
+        }
+    };
+
+    let db_path = app_state.db_path.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        let export_path = restore::export_path(&db_path, &name)?;
+        restore::restore_to(&logger, target, &export_path)
+    })
+    .await;
+    match res {
+        Ok(Ok(point)) => Ok(Json(point)),
+        Ok(Err(err @ (RestoreError::InvalidName(_) | RestoreError::Unreachable(_)))) => {
+            Err((axum::http::StatusCode::BAD_REQUEST, err.to_string()))
+        }
+        Ok(Err(err @ RestoreError::AlreadyExists(_))) => {
+            Err((axum::http::StatusCode::CONFLICT, err.to_string()))
+        }
+        Ok(Err(err)) => {
+            tracing::warn!("Could not restore the database: {err}");
+            Err((
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not restore the database: {err}"),
+            ))
+        }
+        Err(err) => {
+            tracing::warn!("Restore task failed: {err}");
+            Err((
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed".into(),
+            ))
+        }
+    }
+}
+
+/// Loads the SQLite database file in the body into the database: its tables are copied, with their
+/// rows, indexes, triggers and views.
+async fn handle_post_load(
+    State(app_state): State<Arc<AppState>>,
+    body: axum::body::Body,
+) -> Result<Json<LoadedDatabase>, (axum::http::StatusCode, String)> {
+    let Some(dump_loader) = app_state.dump_loader.clone() else {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "database files can only be loaded on the primary".into(),
+        ));
+    };
+
+    let body = StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    let res = match StagedDatabase::upload(body, &app_state.db_path, app_state.max_load_size).await
+    {
+        Ok(staged) => dump_loader.load_database(staged).await,
+        Err(err) => Err(err),
+    };
+    match res {
+        Ok(loaded) => Ok(Json(loaded)),
+        Err(err @ LoadError::TooLarge(_)) => {
+            Err((axum::http::StatusCode::PAYLOAD_TOO_LARGE, err.to_string()))
+        }
+        Err(
+            err @ (LoadError::NotADatabase
+            | LoadError::HotJournal(_)
+            | LoadError::Corrupted(_)
+            | LoadError::VirtualTable(_)),
+        ) => Err((axum::http::StatusCode::BAD_REQUEST, err.to_string())),
+        Err(err @ (LoadError::Conflict(_) | LoadError::Busy)) => {
+            Err((axum::http::StatusCode::CONFLICT, err.to_string()))
+        }
+        Err(err @ LoadError::Internal(_)) => {
+            tracing::warn!("Could not load the database file: {err}");
+            Err((
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not load the database file: {err}"),
+            ))
+        }
+    }
+}
+
+/// Reads the configuration again, and applies the changes to the settings that can be reloaded,
+/// like `SIGHUP`.
+async fn handle_post_reload(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<ReloadResult>, (axum::http::StatusCode, String)> {
+    let Some(reloader) = app_state.reloader.clone() else {
+        return Err((
+            axum::http::StatusCode::BAD_REQUEST,
+            "the configuration of this server can't be reloaded".into(),
+        ));
+    };
+
+    match tokio::task::spawn_blocking(move || reloader.reload()).await {
+        Ok(Ok(res)) => Ok(Json(res)),
+        Ok(Err(err)) => Err((axum::http::StatusCode::BAD_REQUEST, err.to_string())),
+        Err(err) => Err((
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Could not reload the configuration: {err}"),
+        )),
+    }
+}
+
+const BACKUP_DISABLED: &str =
+    "bottomless replication is not enabled, start the primary with `--enable-bottomless-replication`";
+
+fn backup_error(err: BackupError) -> (axum::http::StatusCode, String) {
+    match err {
+        BackupError::InProgress | BackupError::Busy => {
+            (axum::http::StatusCode::CONFLICT, err.to_string())
+        }
+        BackupError::Timeout(_) => (axum::http::StatusCode::GATEWAY_TIMEOUT, err.to_string()),
+        BackupError::Other(err) => {
+            tracing::warn!("Backup operation failed: {err}");
+            (
+                axum::http::StatusCode::BAD_GATEWAY,
+                format!("Backup operation failed: {err}"),
+            )
+        }
+    }
+}
+
+async fn handle_get_backup_status(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<BackupStatus>, (axum::http::StatusCode, &'static str)> {
+    let Some(backup) = app_state.backup.as_ref() else {
+        return Err((axum::http::StatusCode::NOT_FOUND, BACKUP_DISABLED));
+    };
+
+    Ok(Json(backup.status()))
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotResponse {
+    generation: uuid::Uuid,
+}
+
+async fn handle_post_backup_snapshot(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<SnapshotResponse>, (axum::http::StatusCode, String)> {
+    let Some(backup) = app_state.backup.as_ref() else {
+        return Err((axum::http::StatusCode::NOT_FOUND, BACKUP_DISABLED.into()));
+    };
+
+    let generation = backup.snapshot().await.map_err(backup_error)?;
+    Ok(Json(SnapshotResponse { generation }))
+}
+
+async fn handle_get_backup_generations(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<GenerationStatus>>, (axum::http::StatusCode, String)> {
+    let Some(backup) = app_state.backup.as_ref() else {
+        return Err((axum::http::StatusCode::NOT_FOUND, BACKUP_DISABLED.into()));
+    };
+
+    Ok(Json(backup.generations().await.map_err(backup_error)?))
+}
+
+const NAMESPACES_DISABLED: &str =
+    "namespaces are not enabled, start the primary with `--enable-namespaces`";
+
+async fn handle_get_namespaces(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<Vec<NamespaceInfo>>, (axum::http::StatusCode, &'static str)> {
+    let Some(namespaces) = app_state.namespaces.as_ref() else {
+        return Err((axum::http::StatusCode::NOT_FOUND, NAMESPACES_DISABLED));
+    };
+
+    Ok(Json(namespaces.list()))
+}
+
+async fn handle_post_create_namespace(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> (axum::http::StatusCode, String) {
+    let Some(namespaces) = app_state.namespaces.as_ref() else {
+        return (
+            axum::http::StatusCode::NOT_FOUND,
+            NAMESPACES_DISABLED.into(),
+        );
+    };
+
+    match namespaces.create(&name).await {
+        Ok(()) => (axum::http::StatusCode::CREATED, "Namespace created".into()),
+        Err(err @ NamespaceError::InvalidName(_)) => {
+            (axum::http::StatusCode::BAD_REQUEST, err.to_string())
+        }
+        Err(err @ NamespaceError::AlreadyExists(_)) => {
+            (axum::http::StatusCode::CONFLICT, err.to_string())
+        }
+        Err(err) => {
+            tracing::warn!("Could not create namespace `{name}`: {err}");
+            (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed".into(),
+            )
+        }
+    }
+}
+
+async fn handle_get_namespace_stats(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<NamespaceStats>, (axum::http::StatusCode, String)> {
+    let Some(namespaces) = app_state.namespaces.as_ref() else {
+        return Err((
+            axum::http::StatusCode::NOT_FOUND,
+            NAMESPACES_DISABLED.into(),
+        ));
+    };
+
+    match namespaces.stats(&name) {
+        Ok(stats) => Ok(Json(stats)),
+        Err(err @ NamespaceError::NotFound(_)) => {
+            Err((axum::http::StatusCode::NOT_FOUND, err.to_string()))
+        }
+        Err(err) => {
+            tracing::warn!("Could not get the stats of namespace `{name}`: {err}");
+            Err((
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed".into(),
+            ))
+        }
+    }
+}
+
+async fn handle_post_namespace_quotas(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(quotas): Json<NamespaceQuotas>,
+) -> (axum::http::StatusCode, String) {
+    let Some(namespaces) = app_state.namespaces.as_ref() else {
+        return (
+            axum::http::StatusCode::NOT_FOUND,
+            NAMESPACES_DISABLED.into(),
+        );
+    };
+
+    match namespaces.set_quotas(&name, quotas) {
+        Ok(()) => (axum::http::StatusCode::OK, "Quotas set".into()),
+        Err(err @ NamespaceError::NotFound(_)) => {
+            (axum::http::StatusCode::NOT_FOUND, err.to_string())
+        }
+        Err(err @ NamespaceError::InvalidQuotas(_)) => {
+            (axum::http::StatusCode::BAD_REQUEST, err.to_string())
+        }
+        Err(err) => {
+            tracing::warn!("Could not set the quotas of namespace `{name}`: {err}");
+            (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed".into(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn test_router(auth: Auth) -> axum::Router {
+        let app_state = AppState {
+            db_config_store: Arc::new(DatabaseConfigStore::new_test()),
+            vacuum: None,
+            logger: None,
+            db_path: PathBuf::new(),
+            query_stats: None,
+            auto_analyze: None,
+            stats: Stats::default(),
+            standby: None,
+            namespaces: None,
+            replicas: None,
+            backup: None,
+            dump_loader: None,
+            max_load_size: 0,
+            reloader: None,
+        };
+        router(app_state, Arc::new(auth).into())
+    }
+
+    async fn get(router: axum::Router, path: &str, auth: Option<&str>) -> axum::http::StatusCode {
+        let mut req = Request::builder().uri(path);
+        if let Some(auth) = auth {
+            req = req.header("authorization", auth);
+        }
+        let resp = router
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        resp.status()
+    }
+
+    #[tokio::test]
+    async fn admin_auth() {
+        let auth = Auth {
+            http_basic: Some("YWRtaW46c2VjcmV0".into()),
+            ..Default::default()
+        };
+        let router = test_router(auth);
+        for path in ["/v1/stats", "/v1/config"] {
+            assert_eq!(
+                get(router.clone(), path, None).await,
+                axum::http::StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                get(router.clone(), path, Some("Basic d3Jvbmc6d3Jvbmc=")).await,
+                axum::http::StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                get(router.clone(), path, Some("Basic YWRtaW46c2VjcmV0")).await,
+                axum::http::StatusCode::OK
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_routes_without_auth() {
+        let router = test_router(Auth {
+            disabled: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            get(router.clone(), "/v1/stats", None).await,
+            axum::http::StatusCode::OK
+        );
+        assert_eq!(
+            get(router.clone(), "/v1/unknown", None).await,
+            axum::http::StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get(router.clone(), "/v1/connections", None).await,
+            axum::http::StatusCode::OK
+        );
+        assert_eq!(
+            get(router.clone(), "/v1/replication", None).await,
+            axum::http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get(router.clone(), "/v1/backup/status", None).await,
+            axum::http::StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get(router.clone(), "/v1/namespaces/tenant/stats", None).await,
+            axum::http::StatusCode::NOT_FOUND
+        );
+
+        let resp = router
+            .clone()
+            .oneshot(Request::post("/v1/promote").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::BAD_REQUEST);
+        let resp = router
+            .clone()
+            .oneshot(Request::post("/v1/load").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::BAD_REQUEST);
+        let resp = router
+            .clone()
+            .oneshot(Request::post("/v1/reload").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::BAD_REQUEST);
+
+        let connection = connections::register(crate::metrics::Frontend::Http, None);
+        let interrupt = |id: u64| {
+            let req = Request::post(format!("/v1/connections/{id}/interrupt?close=true"));
+            router.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+        let resp = interrupt(connection.id()).await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        assert!(connection.is_closed());
+        let resp = interrupt(u64::MAX).await.unwrap();
+        assert_eq!(resp.status(), axum::http::StatusCode::NOT_FOUND);
+    }
+}
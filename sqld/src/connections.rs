@@ -0,0 +1,417 @@
+//! Registry of the client connections open on the frontends, listed by the admin API.
+//!
+//! A frontend registers each client connection for as long as it is open, and runs the tasks that
+//! serve it in the scope of [`scope`]. The database connections opened in that scope, by the
+//! [`crate::database::instrumented::InstrumentedDbFactory`] of the frontend, report their activity
+//! and the state of their transaction to the client connection. The database connections opened
+//! in that scope register with it, so that the admin API can interrupt their statements.
+//!
+//! The frontends take a [`ConnectionPermit`] from the [`ConnectionLimiter`] before they accept a
+//! connection, and answer that the server is busy once the limits are reached.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+use crate::metrics::{self, Frontend};
+
+tokio::task_local! {
+    static CONNECTION: Arc<ClientConnection>;
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: Lazy<Mutex<BTreeMap<u64, Weak<ClientConnection>>>> =
+    Lazy::new(Default::default);
+
+pub struct ClientConnection {
+    id: u64,
+    frontend: Frontend,
+    peer_addr: Option<SocketAddr>,
+    opened_at: Instant,
+    /// Milliseconds between `opened_at` and the last activity of the connection.
+    last_activity_ms: AtomicU64,
+    /// Number of database connections of the client that are in a transaction.
+    open_txns: AtomicUsize,
+    /// The database connections opened on behalf of the client.
+    dbs: Mutex<Vec<Weak<dyn Interrupt>>>,
+    /// Cancelled when the connection must be closed by its frontend.
+    closed: CancellationToken,
+}
+
+/// Interrupts the statement running on a database connection, and rolls back its transaction.
+/// Called from any thread.
+pub trait Interrupt: Send + Sync {
+    fn interrupt(&self);
+}
+
+/// The state of a client connection, as reported by the admin API.
+#[derive(Debug, Serialize)]
+pub struct ConnectionStatus {
+    pub id: u64,
+    pub frontend: &'static str,
+    pub peer_addr: Option<SocketAddr>,
+    pub in_transaction: bool,
+    /// Milliseconds since the last activity of the connection.
+    pub idle_ms: u64,
+    /// Milliseconds since the connection was opened.
+    pub age_ms: u64,
+}
+
+/// The client connections of a frontend, as reported by the stats.
+#[derive(Debug, Serialize)]
+pub struct ConnectionCounts {
+    pub open: u64,
+    /// Connections refused because the connection limits were reached.
+    pub rejected_total: u64,
+}
+
+/// Registers a client connection, until the returned handle and its clones are dropped.
+pub fn register(frontend: Frontend, peer_addr: Option<SocketAddr>) -> Arc<ClientConnection> {
+    let connection = Arc::new(ClientConnection {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        frontend,
+        peer_addr,
+        opened_at: Instant::now(),
+        last_activity_ms: AtomicU64::new(0),
+        open_txns: AtomicUsize::new(0),
+        dbs: Mutex::new(Vec::new()),
+        closed: CancellationToken::new(),
+    });
+    CONNECTIONS
+        .lock()
+        .insert(connection.id, Arc::downgrade(&connection));
+    connection
+}
+
+/// The client connection served by the current task, if any.
+pub fn current() -> Option<Arc<ClientConnection>> {
+    CONNECTION.try_with(Arc::clone).ok()
+}
+
+/// Runs `f` on behalf of `connection`.
+pub async fn scope<F: Future>(connection: Option<Arc<ClientConnection>>, f: F) -> F::Output {
+    match connection {
+        Some(connection) => CONNECTION.scope(connection, f).await,
+        None => f.await,
+    }
+}
+
+/// Interrupts the database connections of the client connection `id`, and closes it if `close` is
+/// set. Returns `false` if there is no such connection.
+pub fn interrupt(id: u64, close: bool) -> bool {
+    let Some(connection) = CONNECTIONS.lock().get(&id).and_then(Weak::upgrade) else {
+        return false;
+    };
+    connection.interrupt(close);
+    true
+}
+
+/// Lists the open client connections, by id.
+pub fn list() -> Vec<ConnectionStatus> {
+    let connections: Vec<_> = CONNECTIONS
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect();
+    connections.iter().map(|c| c.status()).collect()
+}
+
+/// Counts the client connections of each frontend, by label of the frontend.
+pub fn counts() -> BTreeMap<&'static str, ConnectionCounts> {
+    Frontend::ALL
+        .into_iter()
+        .map(|f| {
+            let m = metrics::frontend(f);
+            let counts = ConnectionCounts {
+                open: m.open_connections(),
+                rejected_total: m.connections_rejected.load(Ordering::Relaxed),
+            };
+            (f.label(), counts)
+        })
+        .collect()
+}
+
+impl ClientConnection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Records an activity of the connection.
+    pub fn touch(&self) {
+        let elapsed = self.opened_at.elapsed().as_millis() as u64;
+        self.last_activity_ms.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Records that one of the database connections entered (`true`) or left (`false`) a
+    /// transaction.
+    pub fn set_in_txn(&self, in_txn: bool) {
+        if in_txn {
+            self.open_txns.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.open_txns.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Registers a database connection opened on behalf of the client, for as long as it lives.
+    pub fn register_db(&self, db: Weak<dyn Interrupt>) {
+        let mut dbs = self.dbs.lock();
+        dbs.retain(|db| db.strong_count() > 0);
+        dbs.push(db);
+    }
+
+    fn interrupt(&self, close: bool) {
+        let dbs: Vec<_> = self.dbs.lock().iter().filter_map(Weak::upgrade).collect();
+        tracing::info!(
+            "interrupting {} database connections of client connection #{}",
+            dbs.len(),
+            self.id
+        );
+        for db in dbs {
+            db.interrupt();
+        }
+        if close {
+            self.closed.cancel();
+        }
+    }
+
+    /// Whether the connection was closed by the admin API.
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_cancelled()
+    }
+
+    /// Resolves once the connection is closed by the admin API.
+    pub fn closed(&self) -> WaitForCancellationFuture<'_> {
+        self.closed.cancelled()
+    }
+
+    fn status(&self) -> ConnectionStatus {
+        let age_ms = self.opened_at.elapsed().as_millis() as u64;
+        ConnectionStatus {
+            id: self.id,
+            frontend: self.frontend.label(),
+            peer_addr: self.peer_addr,
+            in_transaction: self.open_txns.load(Ordering::Relaxed) > 0,
+            idle_ms: age_ms.saturating_sub(self.last_activity_ms.load(Ordering::Relaxed)),
+            age_ms,
+        }
+    }
+}
+
+/// Limits of the number of client connections open at once, `None` for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Limit of the connections of all the frontends.
+    pub total: Option<usize>,
+    /// Limit of the connections of the HTTP API.
+    pub http: Option<usize>,
+    /// Limit of the Hrana connections over WebSockets, including those upgraded from HTTP.
+    pub ws: Option<usize>,
+}
+
+impl ConnectionLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.total.is_some() || self.http.is_some() || self.ws.is_some()
+    }
+
+    fn frontend(&self, frontend: Frontend) -> Option<usize> {
+        match frontend {
+            Frontend::Http => self.http,
+            Frontend::Ws => self.ws,
+        }
+    }
+}
+
+/// A connection refused because a limit is reached.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ServerBusy {
+    #[error("server busy: the limit of {0} connections is reached, retry later")]
+    Total(usize),
+    #[error("server busy: the limit of {limit} {frontend} connections is reached, retry later")]
+    Frontend {
+        frontend: &'static str,
+        limit: usize,
+    },
+}
+
+impl ServerBusy {
+    pub fn code(&self) -> &'static str {
+        "SERVER_BUSY"
+    }
+}
+
+/// Enforces the [`ConnectionLimits`], shared by the frontends.
+pub struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    /// Connections holding a permit, by frontend.
+    open: Mutex<[usize; 2]>,
+}
+
+/// Holds a place for a connection until it is dropped.
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    frontend: Frontend,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            open: Mutex::new([0; 2]),
+        }
+    }
+
+    /// Takes a place for a new connection of `frontend`, or fails at once if the limit of the
+    /// frontend or of all the frontends is reached.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        frontend: Frontend,
+    ) -> Result<ConnectionPermit, ServerBusy> {
+        let mut open = self.open.lock();
+        let busy = match (self.limits.total, self.limits.frontend(frontend)) {
+            (_, Some(limit)) if open[frontend as usize] >= limit => Some(ServerBusy::Frontend {
+                frontend: frontend.label(),
+                limit,
+            }),
+            (Some(limit), _) if open.iter().sum::<usize>() >= limit => {
+                Some(ServerBusy::Total(limit))
+            }
+            _ => None,
+        };
+        if let Some(busy) = busy {
+            metrics::frontend(frontend)
+                .connections_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(busy);
+        }
+        open[frontend as usize] += 1;
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            frontend,
+        })
+    }
+
+    /// Number of connections of `frontend` holding a permit.
+    #[cfg(test)]
+    pub fn open(&self, frontend: Frontend) -> usize {
+        self.open.lock()[frontend as usize]
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.open.lock()[self.frontend as usize] -= 1;
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn register_connections() {
+        let addr: SocketAddr = "10.0.0.1:4242".parse().unwrap();
+        let connection = register(Frontend::Ws, Some(addr));
+        let id = connection.id();
+        let find = || list().into_iter().find(|c| c.id == id);
+
+        let status = find().unwrap();
+        assert_eq!(status.frontend, "ws");
+        assert_eq!(status.peer_addr, Some(addr));
+        assert!(!status.in_transaction);
+
+        let in_scope = scope(Some(connection.clone()), async {
+            current().map(|c| c.id())
+        })
+        .await;
+        assert_eq!(in_scope, Some(id));
+        assert!(current().is_none());
+
+        connection.set_in_txn(true);
+        assert!(find().unwrap().in_transaction);
+        connection.set_in_txn(false);
+        assert!(!find().unwrap().in_transaction);
+
+        drop(connection);
+        assert!(find().is_none());
+    }
+
+    #[tokio::test]
+    async fn interrupt_connections() {
+        struct Counter(AtomicUsize);
+
+        impl Interrupt for Counter {
+            fn interrupt(&self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let connection = register(Frontend::Http, None);
+        let db = Arc::new(Counter(AtomicUsize::new(0)));
+        let dropped_db: Arc<dyn Interrupt> = Arc::new(Counter(AtomicUsize::new(0)));
+        connection.register_db(Arc::downgrade(&db) as Weak<dyn Interrupt>);
+        connection.register_db(Arc::downgrade(&dropped_db));
+        drop(dropped_db);
+
+        assert!(interrupt(connection.id(), false));
+        assert_eq!(db.0.load(Ordering::Relaxed), 1);
+        assert!(!connection.is_closed());
+
+        assert!(interrupt(connection.id(), true));
+        assert_eq!(db.0.load(Ordering::Relaxed), 2);
+        assert!(connection.is_closed());
+        connection.closed().await;
+
+        let id = connection.id();
+        drop(connection);
+        assert!(!interrupt(id, false));
+    }
+
+    #[test]
+    fn limit_connections() {
+        let limiter = Arc::new(ConnectionLimiter::new(ConnectionLimits {
+            total: Some(3),
+            http: Some(2),
+            ws: None,
+        }));
+
+        let http1 = limiter.try_acquire(Frontend::Http).unwrap();
+        let _http2 = limiter.try_acquire(Frontend::Http).unwrap();
+        let err = limiter.try_acquire(Frontend::Http).err().unwrap();
+        assert!(
+            matches!(err, ServerBusy::Frontend { limit: 2, .. }),
+            "{err}"
+        );
+
+        // the limit of all the frontends counts the connections of the others
+        let _ws1 = limiter.try_acquire(Frontend::Ws).unwrap();
+        let err = limiter.try_acquire(Frontend::Ws).err().unwrap();
+        assert!(matches!(err, ServerBusy::Total(3)), "{err}");
+        assert_eq!(err.code(), "SERVER_BUSY");
+        assert_eq!(limiter.open(Frontend::Http), 2);
+        assert_eq!(limiter.open(Frontend::Ws), 1);
+
+        // a closed connection gives its place back
+        drop(http1);
+        assert_eq!(limiter.open(Frontend::Http), 1);
+        let _ws2 = limiter.try_acquire(Frontend::Ws).unwrap();
+        assert!(limiter.try_acquire(Frontend::Http).is_err());
+    }
+}
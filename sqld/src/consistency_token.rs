@@ -0,0 +1,261 @@
+//! Consistency tokens, for read-after-write consistency across the nodes of a database.
+//!
+//! The response to a write carries a token with the replication index of the write. A read that
+//! presents the token to any node of the database waits until that node has applied the write.
+//! Tokens are signed with a key shared by all the nodes, so that clients can't make a replica wait
+//! for a frame that will never come.
+//!
+//! A token is a base64-encoded byte string composed of:
+//!
+//! - payload (33 bytes):
+//!     - version (1 byte)
+//!     - `database_id` (16 bytes)
+//!     - `frame_no` (8 bytes, big endian)
+//!     - `issued_at`, in seconds since the UNIX epoch (8 bytes, big endian)
+//! - MAC (32 bytes): an authentication code generated with HMAC-SHA256
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use hmac::Mac;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::replication::FrameNo;
+
+/// Header of the responses to writes, and of the reads that must see these writes.
+pub const CONSISTENCY_TOKEN_HEADER: &str = "x-sqld-consistency-token";
+/// How long a token is accepted after it was issued.
+pub const TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a read waits for the node to apply the write of its token.
+pub const MAX_WAIT: Duration = Duration::from_secs(5);
+
+const VERSION: u8 = 1;
+const PAYLOAD_LEN: usize = 33;
+const TOKEN_LEN: usize = PAYLOAD_LEN + 32;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("malformed consistency token")]
+    Malformed,
+    #[error("consistency token with an invalid signature")]
+    InvalidSignature,
+    #[error("expired consistency token")]
+    Expired,
+    #[error("consistency token of another database ({0})")]
+    OtherDatabase(Uuid),
+    #[error("the node has not applied the write of the consistency token yet, retry later")]
+    NotCaughtUp,
+}
+
+/// Issues and verifies the consistency tokens of a database.
+pub struct ConsistencyTokens {
+    key: Vec<u8>,
+    database_id: Uuid,
+    /// The last frame applied by a replica, `None` on a primary, which has applied all the frames
+    /// it issues tokens for.
+    applied_frame_no: Option<watch::Receiver<FrameNo>>,
+}
+
+impl ConsistencyTokens {
+    pub fn new(
+        key: &[u8],
+        database_id: Uuid,
+        applied_frame_no: Option<watch::Receiver<FrameNo>>,
+    ) -> Self {
+        Self {
+            key: key.to_vec(),
+            database_id,
+            applied_frame_no,
+        }
+    }
+
+    fn mac(&self) -> hmac::Hmac<sha2::Sha256> {
+        // HMAC accepts keys of any size
+        hmac::Hmac::new_from_slice(&self.key).unwrap()
+    }
+
+    /// Issues a token for a write at replication index `frame_no`.
+    pub fn issue(&self, frame_no: FrameNo) -> String {
+        self.issue_at(frame_no, SystemTime::now())
+    }
+
+    fn issue_at(&self, frame_no: FrameNo, now: SystemTime) -> String {
+        let issued_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut token = [0; TOKEN_LEN];
+        token[0] = VERSION;
+        token[1..17].copy_from_slice(self.database_id.as_bytes());
+        token[17..25].copy_from_slice(&frame_no.to_be_bytes());
+        token[25..33].copy_from_slice(&issued_at.to_be_bytes());
+
+        let mut mac = self.mac();
+        mac.update(&token[..PAYLOAD_LEN]);
+        token[PAYLOAD_LEN..].copy_from_slice(&mac.finalize().into_bytes());
+
+        BASE64_STANDARD_NO_PAD.encode(token)
+    }
+
+    /// Verifies `token`, and returns the replication index of its write.
+    pub fn verify(&self, token: &str) -> Result<FrameNo, TokenError> {
+        self.verify_at(token, SystemTime::now())
+    }
+
+    fn verify_at(&self, token: &str, now: SystemTime) -> Result<FrameNo, TokenError> {
+        let token = BASE64_STANDARD_NO_PAD
+            .decode(token)
+            .map_err(|_| TokenError::Malformed)?;
+        if token.len() != TOKEN_LEN || token[0] != VERSION {
+            return Err(TokenError::Malformed);
+        }
+
+        let mut mac = self.mac();
+        mac.update(&token[..PAYLOAD_LEN]);
+        mac.verify_slice(&token[PAYLOAD_LEN..])
+            .map_err(|_| TokenError::InvalidSignature)?;
+
+        let database_id = Uuid::from_slice(&token[1..17]).unwrap();
+        let frame_no = FrameNo::from_be_bytes(token[17..25].try_into().unwrap());
+        let issued_at = u64::from_be_bytes(token[25..33].try_into().unwrap());
+
+        let issued_at = UNIX_EPOCH + Duration::from_secs(issued_at);
+        // tokens issued by a node with a clock ahead of ours are not expired
+        if now.duration_since(issued_at).unwrap_or_default() > TOKEN_TTL {
+            return Err(TokenError::Expired);
+        }
+        if database_id != self.database_id {
+            return Err(TokenError::OtherDatabase(database_id));
+        }
+
+        Ok(frame_no)
+    }
+
+    /// Verifies `token`, and waits, for at most [`MAX_WAIT`], until the node has applied its
+    /// write.
+    pub async fn wait_for(&self, token: &str) -> Result<(), TokenError> {
+        let frame_no = self.verify(token)?;
+        let Some(applied) = self.applied_frame_no.clone() else {
+            return Ok(());
+        };
+
+        if wait_until_applied(applied, frame_no, MAX_WAIT).await {
+            Ok(())
+        } else {
+            Err(TokenError::NotCaughtUp)
+        }
+    }
+}
+
+/// Waits, for at most `timeout`, until a replica has applied `frame_no`, and returns whether it
+/// has. `applied` is [`FrameNo::MAX`] until the replica applies its first frame.
+pub async fn wait_until_applied(
+    mut applied: watch::Receiver<FrameNo>,
+    frame_no: FrameNo,
+    timeout: Duration,
+) -> bool {
+    let caught_up = tokio::time::timeout(timeout, async {
+        loop {
+            let current = *applied.borrow_and_update();
+            if current != FrameNo::MAX && current >= frame_no {
+                return true;
+            }
+            if applied.changed().await.is_err() {
+                return false;
+            }
+        }
+    })
+    .await;
+    caught_up.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(key: &[u8], database_id: Uuid) -> ConsistencyTokens {
+        ConsistencyTokens::new(key, database_id, None)
+    }
+
+    #[test]
+    fn issue_and_verify() {
+        let tokens = tokens(b"secret", Uuid::new_v4());
+        let token = tokens.issue(42);
+        assert_eq!(tokens.verify(&token), Ok(42));
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let tokens = tokens(b"secret", Uuid::new_v4());
+        let token = BASE64_STANDARD_NO_PAD.decode(tokens.issue(42)).unwrap();
+
+        // a client moving the frame number forward
+        let mut tampered = token.clone();
+        tampered[24] += 1;
+        assert_eq!(
+            tokens.verify(&BASE64_STANDARD_NO_PAD.encode(&tampered)),
+            Err(TokenError::InvalidSignature)
+        );
+
+        // a token signed with another key
+        let forged = self::tokens(b"other secret", tokens.database_id).issue(42);
+        assert_eq!(tokens.verify(&forged), Err(TokenError::InvalidSignature));
+
+        assert_eq!(
+            tokens.verify(&BASE64_STANDARD_NO_PAD.encode(&token[..PAYLOAD_LEN])),
+            Err(TokenError::Malformed)
+        );
+        assert_eq!(tokens.verify("not a token!"), Err(TokenError::Malformed));
+    }
+
+    #[test]
+    fn expired_tokens() {
+        let tokens = tokens(b"secret", Uuid::new_v4());
+        let now = SystemTime::now();
+        let token = tokens.issue_at(42, now);
+        assert_eq!(tokens.verify_at(&token, now + TOKEN_TTL), Ok(42));
+        assert_eq!(
+            tokens.verify_at(&token, now + TOKEN_TTL + Duration::from_secs(1)),
+            Err(TokenError::Expired)
+        );
+        // issued by a node with a clock ahead
+        assert_eq!(
+            tokens.verify_at(&token, now - Duration::from_secs(10)),
+            Ok(42)
+        );
+    }
+
+    #[test]
+    fn token_of_other_database() {
+        let other_id = Uuid::new_v4();
+        let token = tokens(b"secret", other_id).issue(42);
+        assert_eq!(
+            tokens(b"secret", Uuid::new_v4()).verify(&token),
+            Err(TokenError::OtherDatabase(other_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_for_replica() {
+        let database_id = Uuid::new_v4();
+        let (sender, receiver) = watch::channel(10);
+        let replica = ConsistencyTokens::new(b"secret", database_id, Some(receiver));
+        let token = tokens(b"secret", database_id).issue(12);
+
+        let wait = tokio::spawn(async move { replica.wait_for(&token).await });
+        tokio::task::yield_now().await;
+        assert!(!wait.is_finished());
+        sender.send_replace(12);
+        assert_eq!(wait.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn wait_until_applied_times_out() {
+        let (sender, receiver) = watch::channel(FrameNo::MAX);
+        let timeout = Duration::from_millis(50);
+        // nothing applied yet
+        assert!(!wait_until_applied(receiver.clone(), 0, timeout).await);
+        sender.send_replace(10);
+        assert!(wait_until_applied(receiver.clone(), 10, timeout).await);
+        assert!(!wait_until_applied(receiver, 11, timeout).await);
+    }
+}
@@ -0,0 +1,565 @@
+//! Automatic `ANALYZE`, to keep the statistics of the query planner fresh.
+//!
+//! The rows changed in each table are counted as statements are executed. Once a table had more
+//! changes than the threshold since its last analysis, it is analyzed again with
+//! `PRAGMA analysis_limit` on the primary, during a low-traffic window, when no statement was
+//! executed for a while. Tables are analyzed one at a time, and an analysis that runs past its
+//! budget is interrupted, so that writes are never held back by more than the budget. The
+//! counters and the time of the last analysis of each table are kept in the `_sqld_analyze_stats`
+//! table.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use rusqlite::ErrorCode;
+use serde::Serialize;
+
+use super::vacuum::WithConnection;
+use crate::replication::logical::quote_ident;
+
+/// Maximum number of tables tracked. Changes to new tables are ignored past that limit.
+const MAX_TABLES: usize = 10_000;
+/// Number of SQLite VM instructions between two checks of the budget.
+const BUDGET_CHECK_INTERVAL: i32 = 1000;
+/// Pause between the analysis of two tables, to let other transactions through.
+const ANALYZE_PAUSE: Duration = Duration::from_millis(10);
+/// Lowest `analysis_limit` a table falls back to when its analysis keeps exceeding the budget.
+const MIN_ANALYSIS_LIMIT: u32 = 50;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS _sqld_analyze_stats (
+    table_name TEXT PRIMARY KEY,
+    changes_since_analyze INTEGER NOT NULL,
+    last_analyzed INTEGER
+)";
+
+#[derive(Debug, Clone)]
+pub struct AutoAnalyzeConfig {
+    /// Rows changed in a table before it is analyzed again. Tables are only analyzed on demand if
+    /// not set.
+    pub threshold: Option<u64>,
+    /// Time without any statement after which the server is considered idle.
+    pub idle_window: Duration,
+    /// `PRAGMA analysis_limit` of the analysis, 0 for no limit.
+    pub analysis_limit: u32,
+    /// Maximum time the analysis of a single table can hold the write lock.
+    pub budget: Duration,
+}
+
+impl Default for AutoAnalyzeConfig {
+    fn default() -> Self {
+        Self {
+            threshold: None,
+            idle_window: Duration::from_secs(1),
+            analysis_limit: 1000,
+            budget: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TableAnalyze {
+    pub table: String,
+    /// Rows changed since the last analysis of the table
+    pub changes_since_analyze: u64,
+    /// Unix timestamp of the last analysis, in seconds
+    pub last_analyzed: Option<u64>,
+    /// `analysis_limit` of the table, once its analysis exceeded the budget
+    #[serde(skip)]
+    analysis_limit: Option<u32>,
+    /// Whether the entry changed since it was last flushed
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AnalyzeError {
+    #[error("an analysis is already in progress")]
+    InProgress,
+}
+
+pub struct AutoAnalyze {
+    config: AutoAnalyzeConfig,
+    with_conn: Arc<WithConnection>,
+    tables: Mutex<HashMap<String, TableAnalyze>>,
+    started_at: Instant,
+    /// Time of the last statement, in milliseconds since `started_at`
+    last_activity: AtomicU64,
+    running: AtomicBool,
+}
+
+impl AutoAnalyze {
+    pub fn new(config: AutoAnalyzeConfig, with_conn: Arc<WithConnection>) -> Self {
+        Self {
+            config,
+            with_conn,
+            tables: Default::default(),
+            started_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether tables are analyzed automatically, rather than only on demand.
+    pub fn is_enabled(&self) -> bool {
+        self.config.threshold.is_some()
+    }
+
+    /// Records that a statement was executed, which delays the automatic analysis.
+    pub fn record_activity(&self) {
+        self.last_activity.store(
+            self.started_at.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Records that `changes` rows of `table` were inserted, updated or deleted.
+    pub fn record_changes(&self, table: &str, changes: u64) {
+        if changes == 0 || table.starts_with("sqlite_") {
+            return;
+        }
+
+        let mut tables = self.tables.lock();
+        if tables.len() >= MAX_TABLES && !tables.contains_key(table) {
+            return;
+        }
+
+        let entry = tables
+            .entry(table.to_string())
+            .or_insert_with_key(|table| TableAnalyze {
+                table: table.clone(),
+                ..Default::default()
+            });
+        entry.changes_since_analyze += changes;
+        entry.dirty = true;
+    }
+
+    fn is_idle(&self) -> bool {
+        let idle_ms = (self.started_at.elapsed().as_millis() as u64)
+            .saturating_sub(self.last_activity.load(Ordering::Relaxed));
+        idle_ms >= self.config.idle_window.as_millis() as u64
+    }
+
+    /// Returns the tables that changed enough to be analyzed, most changed first, if the server
+    /// is idle.
+    pub fn due_tables(&self) -> Vec<String> {
+        let Some(threshold) = self.config.threshold else { return Vec::new() };
+        if !self.is_idle() {
+            return Vec::new();
+        }
+
+        let mut due: Vec<_> = self
+            .tables
+            .lock()
+            .values()
+            .filter(|t| t.changes_since_analyze >= threshold.max(1))
+            .map(|t| (t.changes_since_analyze, t.table.clone()))
+            .collect();
+        due.sort_by(|a, b| b.cmp(a));
+        due.into_iter().map(|(_, table)| table).collect()
+    }
+
+    /// Returns the tracked tables, by name.
+    pub fn tables(&self) -> Vec<TableAnalyze> {
+        let mut tables: Vec<_> = self.tables.lock().values().cloned().collect();
+        tables.sort_by(|a, b| a.table.cmp(&b.table));
+        tables
+    }
+
+    /// Analyzes the tables that are due, if the server is idle and no analysis is running.
+    /// Returns the number of tables analyzed.
+    pub fn run_due(&self) -> anyhow::Result<usize> {
+        let tables = self.due_tables();
+        if tables.is_empty() || self.running.swap(true, Ordering::SeqCst) {
+            return Ok(0);
+        }
+
+        let res = self.analyze_tables(&tables, true);
+        self.running.store(false, Ordering::SeqCst);
+        let analyzed = res?;
+        self.flush()?;
+
+        Ok(analyzed)
+    }
+
+    /// Analyzes all the tables of the database in the background, whatever their changes and
+    /// the traffic.
+    pub fn start(self: &Arc<Self>) -> Result<(), AnalyzeError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(AnalyzeError::InProgress);
+        }
+
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let res = this.run_all();
+            this.running.store(false, Ordering::SeqCst);
+            if let Err(e) = res.and_then(|_| this.flush()) {
+                tracing::error!("analysis failed: {e}");
+            }
+        });
+
+        Ok(())
+    }
+
+    fn run_all(&self) -> anyhow::Result<usize> {
+        let mut tables = Vec::new();
+        (self.with_conn)(&mut |conn| {
+            tables = conn
+                .prepare(
+                    "SELECT name FROM sqlite_schema
+                    WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'",
+                )?
+                .query_map((), |row| row.get::<_, String>(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(())
+        })?;
+        let tables: Vec<_> = tables.iter().map(|t| t.to_lowercase()).collect();
+
+        self.analyze_tables(&tables, false)
+    }
+
+    /// Analyzes `tables`, one at a time. The automatic analysis stops as soon as traffic resumes
+    /// or a table exceeds the budget, and the remaining tables are analyzed on the next idle
+    /// window.
+    fn analyze_tables(&self, tables: &[String], automatic: bool) -> anyhow::Result<usize> {
+        let mut analyzed = 0;
+        (self.with_conn)(&mut |conn| {
+            for table in tables {
+                if automatic && !self.is_idle() {
+                    break;
+                }
+
+                match self.analyze_table(conn, table)? {
+                    true => analyzed += 1,
+                    false if automatic => break,
+                    false => (),
+                }
+                std::thread::sleep(ANALYZE_PAUSE);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(analyzed)
+    }
+
+    /// Runs `ANALYZE` on `table` within the budget. Returns false if the budget was exceeded.
+    fn analyze_table(&self, conn: &rusqlite::Connection, table: &str) -> anyhow::Result<bool> {
+        let exists = conn
+            .prepare_cached(
+                "SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ? COLLATE NOCASE",
+            )?
+            .exists([table])?;
+        if !exists {
+            self.tables.lock().remove(table);
+            conn.execute(CREATE_TABLE, ())?;
+            conn.execute(
+                "DELETE FROM _sqld_analyze_stats WHERE table_name = ?",
+                [table],
+            )?;
+            return Ok(true);
+        }
+
+        let (changes, analysis_limit) = match self.tables.lock().get(table) {
+            Some(entry) => (entry.changes_since_analyze, entry.analysis_limit),
+            None => (0, None),
+        };
+        let analysis_limit = analysis_limit.unwrap_or(self.config.analysis_limit);
+        conn.pragma_update(None, "analysis_limit", analysis_limit)?;
+
+        let start = Instant::now();
+        let deadline = start + self.config.budget;
+        conn.progress_handler(
+            BUDGET_CHECK_INTERVAL,
+            Some(move || Instant::now() >= deadline),
+        );
+        let res = conn.execute_batch(&format!("ANALYZE {}", quote_ident(table)));
+        conn.progress_handler(0, None::<fn() -> bool>);
+
+        let mut tables = self.tables.lock();
+        let entry = tables
+            .entry(table.to_string())
+            .or_insert_with_key(|table| TableAnalyze {
+                table: table.clone(),
+                ..Default::default()
+            });
+        entry.dirty = true;
+        match res {
+            Ok(()) => {
+                tracing::debug!("analyzed table `{table}` in {:?}", start.elapsed());
+                // the changes made during the analysis count towards the next one
+                entry.changes_since_analyze = entry.changes_since_analyze.saturating_sub(changes);
+                entry.last_analyzed = Some(now());
+                Ok(true)
+            }
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == ErrorCode::OperationInterrupted =>
+            {
+                let lowered = match analysis_limit {
+                    0 => AutoAnalyzeConfig::default().analysis_limit,
+                    limit => (limit / 2).max(MIN_ANALYSIS_LIMIT),
+                };
+                tracing::warn!(
+                    "analysis of table `{table}` exceeded its budget of {:?}, retrying later with analysis_limit={lowered}",
+                    self.config.budget
+                );
+                entry.analysis_limit = Some(lowered);
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Restores the counters persisted by a previous run.
+    pub fn load(&self) -> anyhow::Result<()> {
+        (self.with_conn)(&mut |conn| {
+            conn.execute(CREATE_TABLE, ())?;
+            let mut stmt = conn.prepare(
+                "SELECT table_name, changes_since_analyze, last_analyzed FROM _sqld_analyze_stats",
+            )?;
+            let rows = stmt.query_map((), |row| {
+                Ok(TableAnalyze {
+                    table: row.get(0)?,
+                    changes_since_analyze: row.get(1)?,
+                    last_analyzed: row.get(2)?,
+                    analysis_limit: None,
+                    dirty: false,
+                })
+            })?;
+
+            let mut tables = self.tables.lock();
+            for entry in rows {
+                let entry = entry?;
+                tables.insert(entry.table.clone(), entry);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Writes the entries that changed since the last flush to the stats table.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let dirty: Vec<_> = self
+            .tables
+            .lock()
+            .values_mut()
+            .filter(|e| e.dirty)
+            .map(|e| {
+                e.dirty = false;
+                e.clone()
+            })
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let res = (self.with_conn)(&mut |conn| {
+            conn.execute(CREATE_TABLE, ())?;
+            conn.execute("BEGIN", ())?;
+            let mut stmt =
+                conn.prepare_cached("INSERT OR REPLACE INTO _sqld_analyze_stats VALUES (?, ?, ?)")?;
+            for e in dirty.iter() {
+                if let Err(e) = stmt.execute((&e.table, e.changes_since_analyze, e.last_analyzed)) {
+                    let _ = conn.execute("ROLLBACK", ());
+                    return Err(e.into());
+                }
+            }
+            conn.execute("COMMIT", ())?;
+            Ok(())
+        });
+
+        if res.is_err() {
+            // try again on the next flush
+            let mut tables = self.tables.lock();
+            for e in dirty {
+                if let Some(entry) = tables.get_mut(&e.table) {
+                    entry.dirty = true;
+                }
+            }
+        }
+
+        res
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_conn(path: std::path::PathBuf) -> Arc<WithConnection> {
+        Arc::new(
+            move |f: &mut dyn FnMut(&rusqlite::Connection) -> anyhow::Result<()>| {
+                let conn = rusqlite::Connection::open(&path)?;
+                conn.execute_batch("PRAGMA journal_mode = WAL")?;
+                // like the connections opened by sqld
+                conn.busy_timeout(Duration::from_secs(5))?;
+                f(&conn)
+            },
+        )
+    }
+
+    fn fill(with_conn: &WithConnection, rows: u64) {
+        with_conn(&mut |conn| {
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS test (x INTEGER, y TEXT);
+                CREATE INDEX IF NOT EXISTS test_x ON test (x);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {rows})
+                INSERT INTO test SELECT i % 100, randomblob(16) FROM n;"
+            ))?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn stat_rows(with_conn: &WithConnection) -> u64 {
+        let mut count = 0;
+        with_conn(&mut |conn| {
+            count = conn
+                .query_row(
+                    "SELECT count(*) FROM sqlite_schema WHERE name = 'sqlite_stat1'",
+                    (),
+                    |row| row.get(0),
+                )
+                .and_then(|exists: u64| match exists {
+                    0 => Ok(0),
+                    _ => conn.query_row(
+                        "SELECT count(*) FROM sqlite_stat1 WHERE tbl = 'test'",
+                        (),
+                        |row| row.get(0),
+                    ),
+                })?;
+            Ok(())
+        })
+        .unwrap();
+        count
+    }
+
+    #[test]
+    fn analyze_past_threshold_when_idle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let with_conn = with_conn(tmp.path().join("data"));
+        fill(&*with_conn, 1000);
+
+        let analyze = AutoAnalyze::new(
+            AutoAnalyzeConfig {
+                threshold: Some(100),
+                idle_window: Duration::from_millis(50),
+                ..Default::default()
+            },
+            with_conn.clone(),
+        );
+        analyze.load().unwrap();
+        analyze.record_changes("test", 99);
+        analyze.record_changes("sqlite_stat1", 1000);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(analyze.due_tables().is_empty());
+        assert_eq!(analyze.run_due().unwrap(), 0);
+
+        // past the threshold, but not idle
+        analyze.record_changes("test", 1);
+        analyze.record_activity();
+        assert!(analyze.due_tables().is_empty());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(analyze.due_tables(), ["test"]);
+        assert_eq!(analyze.run_due().unwrap(), 1);
+        assert!(stat_rows(&*with_conn) > 0);
+        assert!(analyze.due_tables().is_empty());
+
+        let tables = analyze.tables();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].changes_since_analyze, 0);
+        assert!(tables[0].last_analyzed.is_some());
+
+        // the counters survive restarts
+        analyze.record_changes("test", 10);
+        analyze.flush().unwrap();
+        let restarted = AutoAnalyze::new(AutoAnalyzeConfig::default(), with_conn);
+        restarted.load().unwrap();
+        assert_eq!(restarted.tables()[0].changes_since_analyze, 10);
+        assert_eq!(restarted.tables()[0].last_analyzed, tables[0].last_analyzed);
+        // automatic analysis is disabled without a threshold
+        assert!(restarted.due_tables().is_empty());
+    }
+
+    #[test]
+    fn budget_exceeded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let with_conn = with_conn(tmp.path().join("data"));
+        fill(&*with_conn, 10_000);
+
+        let analyze = AutoAnalyze::new(
+            AutoAnalyzeConfig {
+                threshold: Some(1),
+                idle_window: Duration::ZERO,
+                analysis_limit: 0,
+                budget: Duration::ZERO,
+            },
+            with_conn.clone(),
+        );
+        analyze.record_changes("test", 10_000);
+        assert_eq!(analyze.run_due().unwrap(), 0);
+        assert_eq!(stat_rows(&*with_conn), 0);
+
+        // the table is still due, with a lower limit
+        assert_eq!(analyze.due_tables(), ["test"]);
+        let tables = analyze.tables.lock();
+        assert_eq!(tables["test"].changes_since_analyze, 10_000);
+        assert_eq!(
+            tables["test"].analysis_limit,
+            Some(AutoAnalyzeConfig::default().analysis_limit)
+        );
+    }
+
+    #[test]
+    fn concurrent_writes_are_delayed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let with_conn = with_conn(tmp.path().join("data"));
+        fill(&*with_conn, 100_000);
+
+        let analyze = Arc::new(AutoAnalyze::new(
+            AutoAnalyzeConfig {
+                threshold: Some(1),
+                idle_window: Duration::ZERO,
+                analysis_limit: 0,
+                budget: Duration::from_secs(1),
+            },
+            with_conn.clone(),
+        ));
+        analyze.record_changes("test", 100_000);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = std::thread::spawn({
+            let with_conn = with_conn.clone();
+            let done = done.clone();
+            move || {
+                let mut writes = 0;
+                with_conn(&mut |conn| {
+                    while !done.load(Ordering::SeqCst) || writes == 0 {
+                        conn.execute("INSERT INTO test VALUES (1, 'concurrent')", ())?;
+                        writes += 1;
+                    }
+                    Ok(())
+                })
+                .map(|_| writes)
+            }
+        });
+
+        let analyzed = analyze.run_due();
+        done.store(true, Ordering::SeqCst);
+        // the writes waited for the analysis, none of them failed
+        let writes = writer.join().unwrap().unwrap();
+        assert!(writes > 0);
+        assert_eq!(analyzed.unwrap(), 1);
+        assert!(stat_rows(&*with_conn) > 0);
+    }
+}
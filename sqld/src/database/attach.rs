@@ -0,0 +1,215 @@
+//! `ATTACH DATABASE` of the databases allowed with `--attachable-database`.
+//!
+//! Clients can't attach arbitrary files of the host: they attach a database by the logical name
+//! it was given in the allowlist, and the statement is rewritten to the path of its file, in the
+//! database directory, right before it is executed. Every node resolves the names with its own
+//! allowlist, so that replicas attach their local copy of the files.
+//!
+//! The databases are attached read-only. The WAL hook of a connection sees the frames of all its
+//! databases, so the frames written to an attached database would end up in the replication log
+//! of the main database, and be applied to the main database of the replicas. The attached files
+//! are not replicated nor backed up either.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+
+use crate::error::Error;
+
+static ALLOWLIST: Lazy<RwLock<Allowlist>> = Lazy::new(Default::default);
+
+#[derive(Debug, Default)]
+struct Allowlist {
+    db_path: PathBuf,
+    /// The path of each database, relative to `db_path`.
+    databases: HashMap<String, PathBuf>,
+}
+
+/// A database that clients may attach, parsed from `name=path`, see `--attachable-database`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachableDatabase {
+    pub name: String,
+    /// Relative to the database directory.
+    pub path: PathBuf,
+}
+
+impl FromStr for AttachableDatabase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, path)) = s.split_once('=') else {
+            return Err(format!("expected `name=path`, got `{s}`"))
+        };
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "invalid database name `{name}`: expected ASCII letters, digits, `-` or `_`"
+            ));
+        }
+        let path = PathBuf::from(path.trim());
+        // the files of the allowlist can't be out of the database directory
+        if path.as_os_str().is_empty()
+            || !path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(format!(
+                "invalid path `{}` for database `{name}`: expected a path relative to the database directory, without `..`",
+                path.display()
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            path,
+        })
+    }
+}
+
+/// Sets the databases that can be attached by the connections of the process, in `db_path`.
+pub fn set_attachable_databases(
+    db_path: &Path,
+    databases: &[AttachableDatabase],
+) -> anyhow::Result<()> {
+    let mut allowlist = Allowlist {
+        db_path: db_path.to_path_buf(),
+        databases: HashMap::new(),
+    };
+    for db in databases {
+        if allowlist
+            .databases
+            .insert(db.name.clone(), db.path.clone())
+            .is_some()
+        {
+            anyhow::bail!("attachable database `{}` is declared twice", db.name);
+        }
+    }
+    *ALLOWLIST.write() = allowlist;
+
+    Ok(())
+}
+
+/// An `ATTACH DATABASE` or `DETACH DATABASE` statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachCommand {
+    /// Attaches the database with the logical name `name` under the schema `alias`.
+    Attach {
+        name: String,
+        alias: String,
+    },
+    Detach {
+        alias: String,
+    },
+}
+
+impl AttachCommand {
+    /// The statement executed for the command, with the path of the attached database. Fails if
+    /// the database is not in the allowlist, or resolves out of the database directory.
+    pub fn to_sql(&self) -> Result<String, Error> {
+        let allowlist = ALLOWLIST.read();
+        if allowlist.databases.is_empty() {
+            return Err(Error::AttachNotAllowed(
+                "no database can be attached, see `--attachable-database`".into(),
+            ));
+        }
+
+        match self {
+            Self::Attach { name, alias } => {
+                let Some(path) = allowlist.databases.get(name) else {
+                    return Err(Error::AttachNotAllowed(format!(
+                        "`{name}` is not an attachable database"
+                    )))
+                };
+                let path = resolve(&allowlist.db_path, path).map_err(|e| {
+                    tracing::warn!("attachable database `{name}` can't be attached: {e}");
+                    Error::AttachNotAllowed(format!("database `{name}` can't be attached"))
+                })?;
+                Ok(format!(
+                    "ATTACH DATABASE '{}' AS {}",
+                    read_only_uri(&path).replace('\'', "''"),
+                    quote(alias)
+                ))
+            }
+            Self::Detach { alias } => Ok(format!("DETACH DATABASE {}", quote(alias))),
+        }
+    }
+}
+
+/// The path of the file of an attachable database. The file must exist, and, symbolic links
+/// resolved, be in the database directory.
+fn resolve(db_path: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let db_path = db_path.canonicalize()?;
+    let path = db_path.join(path).canonicalize()?;
+    if !path.starts_with(&db_path) {
+        anyhow::bail!("`{}` is not in the database directory", path.display());
+    }
+    if !path.is_file() {
+        anyhow::bail!("`{}` is not a file", path.display());
+    }
+
+    Ok(path)
+}
+
+/// The URI opening the database at `path` read-only.
+fn read_only_uri(path: &Path) -> String {
+    let mut uri = String::from("file:");
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?mode=ro");
+
+    uri
+}
+
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_attachable_databases() {
+        assert_eq!(
+            "reports=attached/reports.db".parse(),
+            Ok(AttachableDatabase {
+                name: "reports".into(),
+                path: "attached/reports.db".into(),
+            })
+        );
+
+        let invalid = [
+            "reports",
+            "=reports.db",
+            "re ports=reports.db",
+            "reports=",
+            "reports=../reports.db",
+            "reports=attached/../../reports.db",
+            "reports=/etc/passwd",
+        ];
+        for s in invalid {
+            assert!(s.parse::<AttachableDatabase>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn read_only_uris() {
+        assert_eq!(
+            read_only_uri(Path::new("/data/a?b#c%d.db")),
+            "file:/data/a%3fb%23c%25d.db?mode=ro"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Budget shared by the page caches of the connections.
+//!
+//! Every connection has its own SQLite page cache, so with a fixed `cache_size` the memory used
+//! by the caches grows with the number of open connections. With a budget, each connection gets
+//! an equal share of the total cache size, which is recomputed as connections are opened and
+//! closed. A connection applies its share before executing a program: the share of a connection
+//! is capped to what the other connections leave of the budget, so that the caches never exceed
+//! the budget, even while idle connections still hold on to a larger share.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// The budget of the connections of the process.
+pub static CACHE_BUDGET: Lazy<Arc<CacheBudget>> = Lazy::new(Default::default);
+
+/// Part of its share that a connection configures as its `cache_size`: SQLite sizes the cache
+/// without the headers of the pages, which are counted in the memory it reports as used.
+const CACHE_SIZE_RATIO: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheUsage {
+    /// The share of the budget applied by the connection, if the budget is limited.
+    pub budget_bytes: Option<u64>,
+    /// Memory used by the page cache of the connection, as of its last program.
+    pub used_bytes: u64,
+}
+
+#[derive(Default)]
+pub struct CacheBudget {
+    /// Total size of the page caches, in bytes, or 0 if unlimited.
+    total_bytes: AtomicU64,
+    connections: Mutex<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    usage: HashMap<u64, CacheUsage>,
+}
+
+impl CacheBudget {
+    pub fn new(total_bytes: Option<u64>) -> Self {
+        let this = Self::default();
+        this.set_total(total_bytes);
+        this
+    }
+
+    /// Sets the total size of the page caches. Connections apply their new share before executing
+    /// their next program.
+    pub fn set_total(&self, total_bytes: Option<u64>) {
+        self.total_bytes
+            .store(total_bytes.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    fn total(&self) -> Option<u64> {
+        match self.total_bytes.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some(total),
+        }
+    }
+
+    /// Registers a new connection, which is unregistered when the share is dropped.
+    pub fn register(self: &Arc<Self>) -> CacheShare {
+        let mut connections = self.connections.lock();
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.usage.insert(id, CacheUsage::default());
+
+        CacheShare {
+            budget: self.clone(),
+            id,
+            applied_bytes: None,
+        }
+    }
+
+    /// Returns the cache usage of every open connection.
+    pub fn usage(&self) -> Vec<CacheUsage> {
+        self.connections.lock().usage.values().copied().collect()
+    }
+
+    /// Returns the memory used by the page caches of all the open connections.
+    pub fn used_bytes(&self) -> u64 {
+        self.connections
+            .lock()
+            .usage
+            .values()
+            .map(|u| u.used_bytes)
+            .sum()
+    }
+}
+
+/// The share of the budget of a connection.
+pub struct CacheShare {
+    budget: Arc<CacheBudget>,
+    id: u64,
+    applied_bytes: Option<u64>,
+}
+
+impl CacheShare {
+    /// Resizes the page cache of `conn` to the current share of the connection, if it changed.
+    pub fn apply(&mut self, conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        let Some(total) = self.budget.total() else {
+            return Ok(());
+        };
+
+        let share = {
+            let mut connections = self.budget.connections.lock();
+            let fair_share = total / connections.usage.len() as u64;
+            let others: u64 = connections
+                .usage
+                .iter()
+                .filter(|(id, _)| **id != self.id)
+                .filter_map(|(_, u)| u.budget_bytes)
+                .sum();
+            let share = fair_share.min(total.saturating_sub(others));
+            if let Some(usage) = connections.usage.get_mut(&self.id) {
+                usage.budget_bytes = Some(share);
+            }
+            share
+        };
+
+        if self.applied_bytes != Some(share) {
+            // a negative cache size is a size in KiB, rather than a number of pages
+            let cache_size_kib = (share as f64 * CACHE_SIZE_RATIO) as i64 / 1024;
+            conn.pragma_update(None, "cache_size", -cache_size_kib)?;
+            self.applied_bytes = Some(share);
+        }
+
+        Ok(())
+    }
+
+    /// Records the memory currently used by the page cache of `conn`.
+    pub fn record_usage(&self, conn: &rusqlite::Connection) {
+        let mut used = 0;
+        let mut highwater = 0;
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_db_status(
+                conn.handle(),
+                rusqlite::ffi::SQLITE_DBSTATUS_CACHE_USED,
+                &mut used,
+                &mut highwater,
+                0,
+            )
+        };
+        if rc != rusqlite::ffi::SQLITE_OK {
+            return;
+        }
+
+        if let Some(usage) = self.budget.connections.lock().usage.get_mut(&self.id) {
+            usage.used_bytes = used as u64;
+        }
+    }
+}
+
+impl Drop for CacheShare {
+    fn drop(&mut self) {
+        self.budget.connections.lock().usage.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE_SIZE: u64 = 4096;
+
+    fn setup_db(path: &std::path::Path) {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = wal;
+            CREATE TABLE t (x);
+            WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 4000)
+            INSERT INTO t SELECT randomblob(1000) FROM n;",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn caches_stay_within_budget() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        setup_db(&path);
+
+        let total = 256 * PAGE_SIZE;
+        let budget = Arc::new(CacheBudget::new(Some(total)));
+        let mut conns = (0..8)
+            .map(|_| {
+                let conn = rusqlite::Connection::open(&path).unwrap();
+                (conn, budget.register())
+            })
+            .collect::<Vec<_>>();
+
+        let read_all = |conn: &rusqlite::Connection, share: &mut CacheShare| {
+            share.apply(conn).unwrap();
+            let n: i64 = conn
+                .query_row("SELECT count(*) FROM t WHERE length(x) = 1000", (), |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(n, 4000);
+            share.record_usage(conn);
+        };
+
+        for _ in 0..3 {
+            for (conn, share) in conns.iter_mut() {
+                read_all(conn, share);
+                assert!(budget.used_bytes() <= total);
+            }
+        }
+        let usage = budget.usage();
+        assert_eq!(usage.len(), 8);
+        for u in &usage {
+            assert_eq!(u.budget_bytes, Some(total / 8));
+            assert!(u.used_bytes > 0);
+        }
+
+        // the remaining connections grow into the share of the closed connections
+        conns.truncate(4);
+        for (conn, share) in conns.iter_mut() {
+            read_all(conn, share);
+            assert!(budget.used_bytes() <= total);
+        }
+        let usage = budget.usage();
+        assert_eq!(usage.len(), 4);
+        assert!(usage.iter().all(|u| u.budget_bytes == Some(total / 4)));
+
+        // new connections only get what the others leave, until they shrink
+        let mut conns = conns
+            .into_iter()
+            .chain((0..4).map(|_| {
+                let conn = rusqlite::Connection::open(&path).unwrap();
+                (conn, budget.register())
+            }))
+            .collect::<Vec<_>>();
+        let budgets = || -> u64 { budget.usage().iter().filter_map(|u| u.budget_bytes).sum() };
+        for _ in 0..2 {
+            for (conn, share) in conns.iter_mut().rev() {
+                read_all(conn, share);
+                assert!(budgets() <= total);
+            }
+        }
+        assert!(budget
+            .usage()
+            .iter()
+            .all(|u| u.budget_bytes == Some(total / 8)));
+        assert!(budget.used_bytes() <= total);
+    }
+
+    #[test]
+    fn unlimited_budget() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        setup_db(&path);
+
+        let budget = Arc::new(CacheBudget::default());
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut share = budget.register();
+        share.apply(&conn).unwrap();
+        let cache_size: i64 = conn
+            .query_row("PRAGMA cache_size", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(cache_size, -2000);
+
+        conn.query_row("SELECT count(*) FROM t", (), |_| Ok(()))
+            .unwrap();
+        share.record_usage(&conn);
+        assert_eq!(budget.usage()[0].budget_bytes, None);
+        assert!(budget.used_bytes() > 0);
+
+        drop(share);
+        assert!(budget.usage().is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+//! Periodic checkpoints of the WAL of the primary.
+//!
+//! The replication hook ignores the checkpoints weaker than `TRUNCATE`, including SQLite's
+//! automatic checkpoints, so nothing else moves the WAL back into the database file. Once the
+//! interval since the last checkpoint has elapsed, or the WAL outgrew its cap, it is checkpointed
+//! with `PRAGMA wal_checkpoint(TRUNCATE)` through a connection of its own, which goes through the
+//! replication hook: the frames are already in the replication log, which doesn't depend on the
+//! WAL, and the hook uploads the frames that are not backed up yet before the checkpoint.
+//!
+//! A checkpoint can't complete while readers are using older versions of the database. It is then
+//! retried on the next check, and counted in the `sqld_checkpoints_total` metric.
+//!
+//! The interval and the cap are read on every check, so that a reloaded configuration applies to
+//! the next one.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::vacuum::WithConnection;
+use crate::metrics::{self, CheckpointOutcome};
+use crate::utils::shared::Shared;
+
+/// How long a checkpoint waits for the readers of older versions of the database, while holding
+/// back the writers.
+const BUSY_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default)]
+pub struct CheckpointConfig {
+    /// Time after which the WAL is checkpointed, if it's not empty.
+    pub interval: Option<Duration>,
+    /// Size of the WAL in bytes above which it is checkpointed.
+    pub max_wal_size: Option<u64>,
+}
+
+impl CheckpointConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.interval.is_some() || self.max_wal_size.is_some()
+    }
+}
+
+pub struct Checkpointer {
+    config: Shared<CheckpointConfig>,
+    wal_path: PathBuf,
+    with_conn: Arc<WithConnection>,
+    /// Time of the last completed checkpoint.
+    last_checkpoint: Mutex<Instant>,
+    /// Checkpoints blocked by readers since the last completed one.
+    busy_attempts: Mutex<u32>,
+}
+
+impl Checkpointer {
+    pub fn new(
+        config: impl Into<Shared<CheckpointConfig>>,
+        db_path: PathBuf,
+        with_conn: Arc<WithConnection>,
+    ) -> Self {
+        Self {
+            config: config.into(),
+            wal_path: db_path.join("data-wal"),
+            with_conn,
+            last_checkpoint: Mutex::new(Instant::now()),
+            busy_attempts: Mutex::new(0),
+        }
+    }
+
+    fn wal_size(&self) -> u64 {
+        self.wal_path.metadata().map_or(0, |m| m.len())
+    }
+
+    /// Whether the WAL should be checkpointed now.
+    pub fn is_due(&self) -> bool {
+        let config = self.config.get();
+        if !config.is_enabled() {
+            return false;
+        }
+        let wal_size = self.wal_size();
+        metrics::set_wal_size(wal_size);
+        if wal_size == 0 {
+            return false;
+        }
+        let over_size = config
+            .max_wal_size
+            .map_or(false, |max_wal_size| wal_size > max_wal_size);
+        let over_time = config.interval.map_or(false, |interval| {
+            self.last_checkpoint.lock().elapsed() >= interval
+        });
+
+        over_size || over_time
+    }
+
+    /// Checkpoints the WAL, and truncates it.
+    pub fn checkpoint(&self) -> CheckpointOutcome {
+        let mut busy = false;
+        let res = (self.with_conn)(&mut |conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            busy = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| {
+                row.get::<_, i32>(0)
+            })? != 0;
+            Ok(())
+        });
+
+        let outcome = match res {
+            Ok(()) if busy => {
+                let mut attempts = self.busy_attempts.lock();
+                *attempts += 1;
+                if *attempts % 10 == 0 {
+                    tracing::warn!(
+                        "the WAL ({} bytes) could not be checkpointed in the last {} attempts, because of long-running readers",
+                        self.wal_size(),
+                        *attempts
+                    );
+                }
+                CheckpointOutcome::Busy
+            }
+            Ok(()) => {
+                *self.busy_attempts.lock() = 0;
+                *self.last_checkpoint.lock() = Instant::now();
+                CheckpointOutcome::Ok
+            }
+            Err(e) => {
+                tracing::warn!("checkpoint failed: {e}");
+                CheckpointOutcome::Error
+            }
+        };
+        metrics::record_checkpoint(outcome);
+        metrics::set_wal_size(self.wal_size());
+
+        outcome
+    }
+
+    /// Checkpoints the WAL if it's due, see [`Self::is_due`].
+    pub fn run_if_due(&self) -> Option<CheckpointOutcome> {
+        self.is_due().then(|| self.checkpoint())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_conn(db_path: PathBuf) -> Arc<WithConnection> {
+        Arc::new(
+            move |f: &mut dyn FnMut(&rusqlite::Connection) -> anyhow::Result<()>| {
+                let conn = rusqlite::Connection::open(db_path.join("data"))?;
+                conn.execute_batch("PRAGMA journal_mode = WAL")?;
+                f(&conn)
+            },
+        )
+    }
+
+    /// A connection that never checkpoints on its own, like the connections of sqld.
+    fn writer(db_path: &std::path::Path) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open(db_path.join("data")).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA wal_autocheckpoint = 0;
+            CREATE TABLE IF NOT EXISTS test (x);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn insert(conn: &rusqlite::Connection, rows: u32) {
+        for _ in 0..rows {
+            conn.execute("INSERT INTO test VALUES (randomblob(1000))", ())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn wal_stays_below_cap() {
+        let tmp = tempfile::tempdir().unwrap();
+        const CAP: u64 = 200_000;
+        let checkpointer = Checkpointer::new(
+            CheckpointConfig {
+                interval: None,
+                max_wal_size: Some(CAP),
+            },
+            tmp.path().to_path_buf(),
+            with_conn(tmp.path().to_path_buf()),
+        );
+        let conn = writer(tmp.path());
+
+        let mut checkpoints = 0;
+        for _ in 0..20 {
+            insert(&conn, 50);
+            if let Some(outcome) = checkpointer.run_if_due() {
+                assert_eq!(outcome, CheckpointOutcome::Ok);
+                checkpoints += 1;
+                assert_eq!(checkpointer.wal_size(), 0);
+            }
+            assert!(checkpointer.wal_size() <= CAP);
+        }
+        assert!(checkpoints > 1);
+
+        let count: u32 = conn
+            .query_row("SELECT count(*) FROM test", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1000);
+    }
+
+    #[test]
+    fn checkpoint_on_interval() {
+        let tmp = tempfile::tempdir().unwrap();
+        let checkpointer = Checkpointer::new(
+            CheckpointConfig {
+                interval: Some(Duration::from_millis(100)),
+                max_wal_size: None,
+            },
+            tmp.path().to_path_buf(),
+            with_conn(tmp.path().to_path_buf()),
+        );
+        let conn = writer(tmp.path());
+        insert(&conn, 10);
+        assert!(!checkpointer.is_due());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(checkpointer.run_if_due(), Some(CheckpointOutcome::Ok));
+        assert_eq!(checkpointer.wal_size(), 0);
+        // an empty WAL is not checkpointed
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!checkpointer.is_due());
+    }
+
+    #[test]
+    fn retry_checkpoint_blocked_by_reader() {
+        let tmp = tempfile::tempdir().unwrap();
+        let checkpointer = Checkpointer::new(
+            CheckpointConfig {
+                interval: None,
+                max_wal_size: Some(1),
+            },
+            tmp.path().to_path_buf(),
+            with_conn(tmp.path().to_path_buf()),
+        );
+        let conn = writer(tmp.path());
+        insert(&conn, 10);
+
+        // a reader of the current version of the database
+        let mut reader = writer(tmp.path());
+        let txn = reader.transaction().unwrap();
+        let _: u32 = txn
+            .query_row("SELECT count(*) FROM test", (), |row| row.get(0))
+            .unwrap();
+        insert(&conn, 10);
+
+        assert_eq!(checkpointer.run_if_due(), Some(CheckpointOutcome::Busy));
+        assert!(checkpointer.is_due());
+
+        drop(txn);
+        assert_eq!(checkpointer.run_if_due(), Some(CheckpointOutcome::Ok));
+        assert_eq!(checkpointer.wal_size(), 0);
+    }
+}
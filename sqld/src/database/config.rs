@@ -22,6 +22,11 @@ pub struct DatabaseConfig {
     /// The reason why operations are blocked. This will be included in [`Error::Blocked`].
     #[serde(default)]
     pub block_reason: Option<String>,
+    /// Maximum size of the database, in pages, applied to its connections with
+    /// `PRAGMA max_page_count`. It comes from the size quota of the namespace of the database,
+    /// and isn't persisted with the rest of the configuration.
+    #[serde(skip)]
+    pub max_page_count: Option<u64>,
 }
 
 impl DatabaseConfigStore {
@@ -55,11 +60,22 @@ impl DatabaseConfigStore {
         self.config.lock().clone()
     }
 
-    pub fn store(&self, config: DatabaseConfig) -> Result<()> {
+    /// Sets the maximum size of the database, see [`DatabaseConfig::max_page_count`].
+    pub fn set_max_page_count(&self, max_page_count: Option<u64>) {
+        let mut config = self.config.lock();
+        let mut new_config = (**config).clone();
+        new_config.max_page_count = max_page_count;
+        *config = Arc::new(new_config);
+    }
+
+    pub fn store(&self, mut config: DatabaseConfig) -> Result<()> {
         let data = serde_json::to_vec_pretty(&config)?;
         fs::write(&self.tmp_config_path, data)?;
         fs::rename(&self.tmp_config_path, &self.config_path)?;
-        *self.config.lock() = Arc::new(config);
+        let mut current = self.config.lock();
+        // the size quota is not part of the stored configuration
+        config.max_page_count = current.max_page_count;
+        *current = Arc::new(config);
         Ok(())
     }
 }
@@ -0,0 +1,278 @@
+//! Detail of the constraint violations reported by SQLite.
+//!
+//! SQLite reports a constraint violation with an extended error code and a message like
+//! `UNIQUE constraint failed: t.a, t.b`, from which the kind, the table and the columns of the
+//! constraint are extracted. The message of a foreign key violation names neither the table nor
+//! the row: the violations of deferred foreign keys, only found at commit, are listed with
+//! `PRAGMA foreign_key_check` instead, before the transaction is rolled back.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rusqlite::ffi;
+use rusqlite::ErrorCode;
+
+/// Maximum number of violations listed by [`foreign_key_violations`].
+pub const MAX_REPORTED_VIOLATIONS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintKind {
+    ForeignKey,
+    Unique,
+    PrimaryKey,
+    NotNull,
+    Check,
+    Trigger,
+    Other,
+}
+
+impl ConstraintKind {
+    fn from_extended_code(code: i32) -> Self {
+        match code {
+            ffi::SQLITE_CONSTRAINT_FOREIGNKEY => Self::ForeignKey,
+            ffi::SQLITE_CONSTRAINT_UNIQUE => Self::Unique,
+            ffi::SQLITE_CONSTRAINT_PRIMARYKEY | ffi::SQLITE_CONSTRAINT_ROWID => Self::PrimaryKey,
+            ffi::SQLITE_CONSTRAINT_NOTNULL => Self::NotNull,
+            ffi::SQLITE_CONSTRAINT_CHECK => Self::Check,
+            ffi::SQLITE_CONSTRAINT_TRIGGER => Self::Trigger,
+            _ => Self::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ForeignKey => "FOREIGN_KEY",
+            Self::Unique => "UNIQUE",
+            Self::PrimaryKey => "PRIMARY_KEY",
+            Self::NotNull => "NOT_NULL",
+            Self::Check => "CHECK",
+            Self::Trigger => "TRIGGER",
+            Self::Other => "OTHER",
+        }
+    }
+}
+
+impl FromStr for ConstraintKind {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "FOREIGN_KEY" => Self::ForeignKey,
+            "UNIQUE" => Self::Unique,
+            "PRIMARY_KEY" => Self::PrimaryKey,
+            "NOT_NULL" => Self::NotNull,
+            "CHECK" => Self::Check,
+            "TRIGGER" => Self::Trigger,
+            _ => Self::Other,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstraintViolation {
+    pub kind: ConstraintKind,
+    /// The table of the row violating the constraint, if known.
+    pub table: Option<String>,
+    /// The columns of the constraint, or its name for a `CHECK` constraint, if known.
+    pub constraint: Option<String>,
+    /// The table referenced by a violated foreign key, if known.
+    pub parent: Option<String>,
+    /// The rowid of the row violating the constraint, only known for the violations found at
+    /// commit.
+    pub rowid: Option<i64>,
+}
+
+impl ConstraintViolation {
+    /// Extracts the detail of a constraint violation from an error of SQLite, or returns `None` if
+    /// `error` is not a constraint violation.
+    pub fn from_sqlite_error(error: &rusqlite::Error) -> Option<Self> {
+        let rusqlite::Error::SqliteFailure(e, message) = error else {
+            return None;
+        };
+        if e.code != ErrorCode::ConstraintViolation {
+            return None;
+        }
+
+        let kind = ConstraintKind::from_extended_code(e.extended_code);
+        let detail = message
+            .as_deref()
+            .and_then(|m| m.split_once("constraint failed: "))
+            .map(|(_, detail)| detail.trim());
+        let (table, constraint) = match (kind, detail) {
+            // a list of columns: `t.a, t.b`
+            (
+                ConstraintKind::Unique | ConstraintKind::PrimaryKey | ConstraintKind::NotNull,
+                Some(detail),
+            ) => {
+                let mut table = None;
+                let columns = detail
+                    .split(", ")
+                    .map(|column| match column.split_once('.') {
+                        Some((t, column)) => {
+                            table = Some(t.to_string());
+                            column
+                        }
+                        None => column,
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                (table, Some(columns))
+            }
+            // the name of the constraint
+            (_, Some(detail)) if !detail.is_empty() => (None, Some(detail.to_string())),
+            _ => (None, None),
+        };
+
+        Some(Self {
+            kind,
+            table,
+            constraint,
+            parent: None,
+            rowid: None,
+        })
+    }
+}
+
+impl fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} constraint", self.kind.as_str())?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, " ({constraint})")?;
+        }
+        if let Some(table) = &self.table {
+            write!(f, " on `{table}`")?;
+        }
+        if let Some(rowid) = self.rowid {
+            write!(f, " at rowid {rowid}")?;
+        }
+        if let Some(parent) = &self.parent {
+            write!(f, " referencing `{parent}`")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the first [`MAX_REPORTED_VIOLATIONS`] rows violating the foreign keys of the database.
+pub fn foreign_key_violations(
+    conn: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<ConstraintViolation>> {
+    let mut check = conn
+        .prepare("SELECT \"table\", rowid, parent, fkid FROM pragma_foreign_key_check() LIMIT ?")?;
+    let mut columns = conn.prepare(
+        "SELECT group_concat(\"from\", ', ') FROM pragma_foreign_key_list(?) WHERE id = ?",
+    )?;
+
+    let mut violations = Vec::new();
+    let mut rows = check.query([MAX_REPORTED_VIOLATIONS as i64])?;
+    while let Some(row) = rows.next()? {
+        let table: String = row.get(0)?;
+        let fkid: i64 = row.get(3)?;
+        let constraint = columns
+            .query_row(rusqlite::params![table, fkid], |row| row.get(0))
+            .ok()
+            .flatten();
+        violations.push(ConstraintViolation {
+            kind: ConstraintKind::ForeignKey,
+            table: Some(table),
+            constraint,
+            parent: Some(row.get(2)?),
+            rowid: row.get(1)?,
+        });
+    }
+
+    Ok(violations)
+}
+
+/// Logs the rows of the database that violate its foreign keys, which were written before the
+/// foreign keys were enforced.
+pub fn warn_foreign_key_violations(conn: &rusqlite::Connection) {
+    match foreign_key_violations(conn) {
+        Ok(violations) if violations.is_empty() => (),
+        Ok(violations) => {
+            tracing::warn!("foreign keys are enforced, but these rows already violate them:");
+            for violation in &violations {
+                tracing::warn!("- {violation}");
+            }
+            if violations.len() == MAX_REPORTED_VIOLATIONS {
+                tracing::warn!("- ... (run `PRAGMA foreign_key_check` to list all the violations)");
+            }
+        }
+        Err(e) => tracing::warn!("failed to check the foreign keys of the database: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn violation(conn: &rusqlite::Connection, sql: &str) -> ConstraintViolation {
+        let e = conn.execute_batch(sql).unwrap_err();
+        ConstraintViolation::from_sqlite_error(&e).unwrap()
+    }
+
+    #[test]
+    fn violation_detail() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE parent (id INTEGER PRIMARY KEY, name TEXT NOT NULL, UNIQUE (id, name));
+            CREATE TABLE child (
+                id INTEGER PRIMARY KEY,
+                parent_id INTEGER REFERENCES parent (id),
+                n INTEGER CONSTRAINT positive CHECK (n > 0)
+            );
+            INSERT INTO parent VALUES (1, 'a');",
+        )
+        .unwrap();
+
+        let v = violation(&conn, "INSERT INTO parent VALUES (1, 'a')");
+        assert_eq!(v.kind, ConstraintKind::PrimaryKey);
+        assert_eq!(v.table.as_deref(), Some("parent"));
+        assert_eq!(v.constraint.as_deref(), Some("id"));
+
+        let v = violation(&conn, "INSERT INTO parent VALUES (2, NULL)");
+        assert_eq!(v.kind, ConstraintKind::NotNull);
+        assert_eq!(v.table.as_deref(), Some("parent"));
+        assert_eq!(v.constraint.as_deref(), Some("name"));
+
+        let v = violation(&conn, "INSERT INTO child VALUES (1, 1, 0)");
+        assert_eq!(v.kind, ConstraintKind::Check);
+        assert_eq!(v.constraint.as_deref(), Some("positive"));
+
+        let v = violation(&conn, "INSERT INTO child VALUES (1, 42, 1)");
+        assert_eq!(v.kind, ConstraintKind::ForeignKey);
+        assert_eq!(v.table, None);
+
+        let e = conn.execute_batch("SELECT * FROM missing").unwrap_err();
+        assert!(ConstraintViolation::from_sqlite_error(&e).is_none());
+    }
+
+    #[test]
+    fn list_foreign_key_violations() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE parent (id INTEGER PRIMARY KEY);
+            CREATE TABLE child (id INTEGER PRIMARY KEY, a INTEGER, b INTEGER,
+                FOREIGN KEY (a) REFERENCES parent (id));
+            INSERT INTO parent VALUES (1);
+            INSERT INTO child VALUES (1, 1, 0), (2, 42, 0);",
+        )
+        .unwrap();
+
+        let violations = foreign_key_violations(&conn).unwrap();
+        assert_eq!(
+            violations,
+            vec![ConstraintViolation {
+                kind: ConstraintKind::ForeignKey,
+                table: Some("child".into()),
+                constraint: Some("a".into()),
+                parent: Some("parent".into()),
+                rowid: Some(2),
+            }]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "FOREIGN_KEY constraint (a) on `child` at rowid 2 referencing `parent`"
+        );
+    }
+}
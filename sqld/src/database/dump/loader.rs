@@ -1,22 +1,103 @@
+use std::ffi::CString;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::anyhow;
-use rusqlite::ErrorCode;
+use anyhow::{anyhow, Context};
+use rusqlite::{ErrorCode, OptionalExtension};
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::sync::{mpsc, oneshot};
 
 use crate::database::libsql::open_db;
+use crate::replication::logical::quote_ident;
 use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
-use crate::replication::ReplicationLogger;
+use crate::replication::{ReplicationLogger, WAL_PAGE_SIZE};
 
 type OpMsg = Box<dyn FnOnce(&rusqlite::Connection) + 'static + Send + Sync>;
 
+/// Number of statements of an imported dump executed in a single transaction.
+const IMPORT_BATCH_SIZE: u64 = 10_000;
+/// The first bytes of an SQLite database file.
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+/// The tables of a database file that are not copied into the database.
+const INTERNAL_TABLES: &str =
+    "name NOT GLOB 'sqlite_*' AND name NOT GLOB '_sqld_*' AND name != 'libsql_wasm_func_table'";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("the database is not empty, set `force=true` to import the dump anyway")]
+    NotEmpty,
+    #[error("another dump or database is being loaded")]
+    Busy,
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("not an SQLite database file")]
+    NotADatabase,
+    #[error(
+        "the database file has a hot journal, `{}`: open the database with `sqlite3` and run \
+        `PRAGMA wal_checkpoint(TRUNCATE)` to move the journal into the database file, then load it",
+        .0.display()
+    )]
+    HotJournal(PathBuf),
+    #[error("the database file failed the integrity check: {0}")]
+    Corrupted(String),
+    #[error("the database file is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("`{0}` already exists in the database")]
+    Conflict(String),
+    #[error(
+        "virtual table `{0}` can't be copied into an existing database, load the database file \
+        into a fresh primary instead"
+    )]
+    VirtualTable(String),
+    #[error("another dump or database is being loaded")]
+    Busy,
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+/// What was copied from a database file into the database.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LoadedDatabase {
+    pub tables: u64,
+    pub rows: u64,
+}
+
+/// The progress of an import, reported after every transaction.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportProgress {
+    /// The number of statements committed so far.
+    Committed(u64),
+    /// The dump was imported, with that many statements.
+    Done(u64),
+    /// The import failed. The statements committed before the failure are kept.
+    Failed { committed: u64, error: String },
+}
+
 #[derive(Debug)]
 pub struct DumpLoader {
     sender: mpsc::Sender<OpMsg>,
+    /// Set while a dump or a database file is being loaded.
+    importing: Arc<AtomicBool>,
+    import_batch_size: u64,
+}
+
+/// Clears the import flag of the loader when the load is over.
+struct ImportGuard(Arc<AtomicBool>);
+
+impl Drop for ImportGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
 }
 
 impl DumpLoader {
@@ -67,7 +148,17 @@ impl DumpLoader {
 
         ok_rcv.await??;
 
-        Ok(Self { sender })
+        Ok(Self {
+            sender,
+            importing: Arc::new(AtomicBool::new(false)),
+            import_batch_size: IMPORT_BATCH_SIZE,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_import_batch_size(mut self, import_batch_size: u64) -> Self {
+        self.import_batch_size = import_batch_size;
+        self
     }
 
     /// Attempts to load the dump at `path` into the database.
@@ -88,6 +179,382 @@ impl DumpLoader {
 
         Ok(())
     }
+
+    /// Imports the dump read from `reader` into the database, in transactions of
+    /// `IMPORT_BATCH_SIZE` statements. Unless `force` is set, the database must be empty.
+    ///
+    /// The dump is read and executed in the background, and its progress is sent to the returned
+    /// channel, which ends with `ImportProgress::Done` or `ImportProgress::Failed`.
+    pub async fn import_dump(
+        &self,
+        reader: impl AsyncBufRead + Unpin + Send + 'static,
+        force: bool,
+    ) -> Result<mpsc::Receiver<ImportProgress>, ImportError> {
+        if self.importing.swap(true, Ordering::AcqRel) {
+            return Err(ImportError::Busy);
+        }
+        let guard = ImportGuard(self.importing.clone());
+        let batch_size = self.import_batch_size;
+
+        let (stmt_snd, mut stmt_rcv) = mpsc::channel(64);
+        let (progress_snd, progress_rcv) = mpsc::channel(16);
+        let (ready_snd, ready_rcv) = oneshot::channel();
+        self.sender
+            .send(Box::new(move |conn| {
+                // the import is over before it is reported, so that another one can follow
+                let ready = match is_empty(conn) {
+                    Ok(empty) if empty || force => Ok(()),
+                    Ok(_) => Err(ImportError::NotEmpty),
+                    Err(e) => Err(anyhow::Error::from(e).into()),
+                };
+                if let Err(e) = ready {
+                    drop(guard);
+                    let _ = ready_snd.send(Err(e));
+                    return;
+                }
+                if ready_snd.send(Ok(())).is_err() {
+                    return;
+                }
+
+                tracing::info!("importing dump");
+                let mut committed = 0;
+                let res = perform_import(
+                    conn,
+                    &mut stmt_rcv,
+                    &progress_snd,
+                    batch_size,
+                    &mut committed,
+                );
+                let progress = match res {
+                    Ok(()) => {
+                        tracing::info!("dump imported, {committed} statements executed");
+                        ImportProgress::Done(committed)
+                    }
+                    Err(e) => {
+                        if !conn.is_autocommit() {
+                            let _ = conn.execute_batch("ROLLBACK");
+                        }
+                        tracing::warn!("failed to import dump: {e:#}");
+                        ImportProgress::Failed {
+                            committed,
+                            error: format!("{e:#}"),
+                        }
+                    }
+                };
+                drop(guard);
+                let _ = progress_snd.blocking_send(progress);
+            }))
+            .await
+            .map_err(|_| anyhow!("dump loader channel closed"))?;
+        ready_rcv
+            .await
+            .map_err(|_| anyhow!("dump loader stopped"))??;
+
+        tokio::spawn(read_statements(reader, stmt_snd));
+
+        Ok(progress_rcv)
+    }
+
+    /// Copies the tables of the staged database file into the database, with their rows, indexes,
+    /// triggers and views, in a single transaction. None of them may already exist.
+    pub async fn load_database(&self, staged: StagedDatabase) -> Result<LoadedDatabase, LoadError> {
+        if self.importing.swap(true, Ordering::AcqRel) {
+            return Err(LoadError::Busy);
+        }
+        let guard = ImportGuard(self.importing.clone());
+
+        let (snd, ret) = oneshot::channel();
+        self.sender
+            .send(Box::new(move |conn| {
+                tracing::info!("loading database file");
+                let res = copy_database(conn, staged.file.path());
+                drop(staged);
+                drop(guard);
+                match res {
+                    Ok(ref loaded) => tracing::info!(
+                        "database file loaded, {} tables and {} rows copied",
+                        loaded.tables,
+                        loaded.rows
+                    ),
+                    Err(ref e) => tracing::warn!("failed to load database file: {e}"),
+                }
+                let _ = snd.send(res);
+            }))
+            .await
+            .map_err(|_| anyhow!("dump loader channel closed"))?;
+
+        ret.await.map_err(|_| anyhow!("dump loader stopped"))?
+    }
+}
+
+/// A copy of a database file to load, in the database directory. The copy is checked, and is
+/// removed once dropped, unless it is moved into place.
+pub struct StagedDatabase {
+    file: NamedTempFile,
+}
+
+impl StagedDatabase {
+    /// Copies the database file at `source`, which must not have a hot journal.
+    pub fn copy_from(source: &Path, db_path: &Path) -> Result<Self, LoadError> {
+        if let Some(journal) = hot_journal(source) {
+            return Err(LoadError::HotJournal(journal));
+        }
+
+        let mut file = staging_file(db_path)?;
+        let mut source_file =
+            File::open(source).with_context(|| format!("failed to open `{}`", source.display()))?;
+        io::copy(&mut source_file, file.as_file_mut())
+            .with_context(|| format!("failed to copy `{}`", source.display()))?;
+
+        Self::check(file)
+    }
+
+    /// Writes the database file uploaded in `body`, which is at most `max_size` bytes long.
+    pub async fn upload(
+        body: impl AsyncRead + Unpin,
+        db_path: &Path,
+        max_size: u64,
+    ) -> Result<Self, LoadError> {
+        let file = staging_file(db_path)?;
+        let mut out = tokio::fs::File::from_std(
+            file.reopen()
+                .context("failed to open the staged database file")?,
+        );
+        let written = tokio::io::copy(&mut body.take(max_size + 1), &mut out)
+            .await
+            .context("failed to read the uploaded database file")?;
+        if written > max_size {
+            return Err(LoadError::TooLarge(max_size));
+        }
+        out.flush()
+            .await
+            .context("failed to write the staged database file")?;
+        drop(out);
+
+        tokio::task::spawn_blocking(move || Self::check(file))
+            .await
+            .context("database file check panicked")?
+    }
+
+    /// Checks the header and the integrity of the database file. A database in WAL mode is
+    /// switched to the rollback journal, so that it is attached without the WAL methods.
+    fn check(file: NamedTempFile) -> Result<Self, LoadError> {
+        let mut header = [0; SQLITE_HEADER.len()];
+        let has_header = file
+            .reopen()
+            .and_then(|mut f| f.read_exact(&mut header))
+            .is_ok();
+        if !has_header || &header != SQLITE_HEADER {
+            return Err(LoadError::NotADatabase);
+        }
+
+        let corrupted = |e: rusqlite::Error| LoadError::Corrupted(e.to_string());
+        let conn = rusqlite::Connection::open(file.path()).map_err(corrupted)?;
+        conn.pragma_update_and_check(None, "journal_mode", "DELETE", |_| Ok(()))
+            .map_err(corrupted)?;
+        let problems = conn
+            .prepare("PRAGMA integrity_check(10)")
+            .and_then(|mut stmt| {
+                stmt.query_map((), |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(corrupted)?;
+        if problems != ["ok"] {
+            return Err(LoadError::Corrupted(problems.join("; ")));
+        }
+
+        Ok(Self { file })
+    }
+
+    /// Moves the database file into place, as the database of `db_path`. The database is converted
+    /// to the page size of the replication log, and switched to WAL mode.
+    pub fn place(self, db_path: &Path) -> Result<(), LoadError> {
+        let conn = rusqlite::Connection::open(self.file.path())
+            .context("failed to open the staged database file")?;
+        let page_size: i32 = conn
+            .pragma_query_value(None, "page_size", |row| row.get(0))
+            .context("failed to read the page size of the database file")?;
+        if page_size != WAL_PAGE_SIZE {
+            tracing::info!(
+                "converting the database file from {page_size} to {WAL_PAGE_SIZE} bytes pages"
+            );
+            conn.pragma_update(None, "page_size", WAL_PAGE_SIZE)
+                .and_then(|()| conn.execute_batch("VACUUM"))
+                .context("failed to convert the page size of the database file")?;
+        }
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .context("failed to switch the database file to WAL mode")?;
+        drop(conn);
+
+        self.file
+            .persist(db_path.join("data"))
+            .context("failed to move the database file into place")?;
+
+        Ok(())
+    }
+}
+
+fn staging_file(db_path: &Path) -> anyhow::Result<NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix("load-")
+        .suffix(".db")
+        .tempfile_in(db_path)
+        .context("failed to create the staged database file")
+}
+
+/// Returns the journal of the database file at `path` that holds transactions, if any.
+fn hot_journal(path: &Path) -> Option<PathBuf> {
+    ["-wal", "-journal"]
+        .into_iter()
+        .map(|suffix| {
+            let mut journal = path.as_os_str().to_owned();
+            journal.push(suffix);
+            PathBuf::from(journal)
+        })
+        .find(|journal| journal.metadata().map_or(false, |m| m.len() > 0))
+}
+
+fn copy_database(conn: &rusqlite::Connection, path: &Path) -> Result<LoadedDatabase, LoadError> {
+    let path = path
+        .to_str()
+        .context("invalid path of the staged database file")?;
+    conn.execute("ATTACH DATABASE ?1 AS load_from", [path])
+        .context("failed to attach the database file")?;
+    let res = conn
+        .execute_batch("BEGIN IMMEDIATE")
+        .context("failed to begin the copy")
+        .map_err(LoadError::from)
+        .and_then(|()| copy_attached(conn))
+        .and_then(|loaded| {
+            conn.execute_batch("COMMIT")
+                .context("failed to commit the copy")?;
+            Ok(loaded)
+        });
+    if !conn.is_autocommit() {
+        let _ = conn.execute_batch("ROLLBACK");
+    }
+    let _ = conn.execute_batch("DETACH DATABASE load_from");
+
+    res
+}
+
+/// Copies the tables of the database attached as `load_from`, then their indexes, triggers and
+/// views, so that the rows are copied before the indexes are built and the triggers are set.
+fn copy_attached(conn: &rusqlite::Connection) -> Result<LoadedDatabase, LoadError> {
+    let objects = query_strings(
+        conn,
+        &format!(
+            "SELECT name, sql FROM load_from.sqlite_schema
+            WHERE sql NOT NULL AND tbl_name NOT GLOB 'sqlite_*' AND {INTERNAL_TABLES}
+            ORDER BY type != 'table', rowid"
+        ),
+    )
+    .context("failed to read the schema of the database file")?;
+    for (name, sql) in &objects {
+        if sql.starts_with("CREATE VIRTUAL TABLE") {
+            return Err(LoadError::VirtualTable(name.clone()));
+        }
+        let exists = conn
+            .query_row(
+                "SELECT 1 FROM main.sqlite_schema WHERE name = ?1",
+                [name],
+                |_| Ok(()),
+            )
+            .optional()
+            .context("failed to read the schema of the database")?;
+        if exists.is_some() {
+            return Err(LoadError::Conflict(name.clone()));
+        }
+    }
+
+    let mut loaded = LoadedDatabase::default();
+    let tables = objects
+        .iter()
+        .filter(|(_, sql)| sql.starts_with("CREATE TABLE"));
+    for (name, sql) in tables {
+        conn.execute_batch(sql)
+            .with_context(|| format!("failed to create table `{name}`"))?;
+        let columns = copied_columns(conn, name)
+            .with_context(|| format!("failed to read the columns of table `{name}`"))?;
+        let table = quote_ident(name);
+        loaded.rows += conn
+            .execute(
+                &format!(
+                    "INSERT INTO main.{table} ({columns}) SELECT {columns} FROM load_from.{table}"
+                ),
+                (),
+            )
+            .with_context(|| format!("failed to copy the rows of table `{name}`"))?
+            as u64;
+        loaded.tables += 1;
+    }
+
+    let has_sequence = conn
+        .query_row(
+            "SELECT 1 FROM load_from.sqlite_schema JOIN main.sqlite_schema USING (name)
+            WHERE name = 'sqlite_sequence'",
+            (),
+            |_| Ok(()),
+        )
+        .optional()
+        .context("failed to read the schema of the database file")?
+        .is_some();
+    if has_sequence {
+        conn.execute_batch(
+            "DELETE FROM main.sqlite_sequence WHERE name IN (SELECT name FROM load_from.sqlite_sequence);
+            INSERT INTO main.sqlite_sequence SELECT * FROM load_from.sqlite_sequence;",
+        )
+        .context("failed to copy the sequences of the AUTOINCREMENT tables")?;
+    }
+
+    for (name, sql) in objects
+        .iter()
+        .filter(|(_, sql)| !sql.starts_with("CREATE TABLE"))
+    {
+        conn.execute_batch(sql)
+            .with_context(|| format!("failed to create `{name}`"))?;
+    }
+
+    Ok(loaded)
+}
+
+/// Returns the columns of table `name` of `load_from` to copy, quoted: the columns that are not
+/// generated, and the rowid, unless the table is `WITHOUT ROWID` or has an `INTEGER PRIMARY KEY`.
+fn copied_columns(conn: &rusqlite::Connection, name: &str) -> rusqlite::Result<String> {
+    let mut columns = Vec::new();
+    let mut pk = Vec::new();
+    let mut stmt =
+        conn.prepare("SELECT name, type, pk, hidden FROM pragma_table_xinfo(?1, 'load_from')")?;
+    let mut rows = stmt.query([name])?;
+    while let Some(row) = rows.next()? {
+        let column: String = row.get(0)?;
+        if row.get::<_, i64>(2)? > 0 {
+            pk.push(row.get::<_, String>(1)?);
+        }
+        if row.get::<_, i64>(3)? == 0 {
+            columns.push(quote_ident(&column));
+        }
+    }
+    let without_rowid = conn.query_row(
+        "SELECT wr FROM pragma_table_list(?1, 'load_from')",
+        [name],
+        |row| row.get::<_, bool>(0),
+    )?;
+    let has_rowid_alias = matches!(&pk[..], [ty] if ty.eq_ignore_ascii_case("INTEGER"));
+    if !without_rowid && !has_rowid_alias {
+        columns.insert(0, "rowid".to_string());
+    }
+
+    Ok(columns.join(", "))
+}
+
+fn query_strings(
+    conn: &rusqlite::Connection,
+    sql: &str,
+) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
 }
 
 const WASM_TABLE_CREATE: &str =
@@ -95,38 +562,386 @@ const WASM_TABLE_CREATE: &str =
 
 fn perform_load_dump(conn: &rusqlite::Connection, path: PathBuf) -> anyhow::Result<()> {
     let mut f = BufReader::new(File::open(path)?);
-    let mut curr = String::new();
+    let mut splitter = StatementSplitter::default();
     let mut line = String::new();
     let mut skipped_wasm_table = false;
-    while let Ok(n) = f.read_line(&mut curr) {
+    while let Ok(n) = f.read_line(&mut line) {
         if n == 0 {
             break;
         }
-        let frag = curr.trim();
+        let stmt = splitter.push_line(&line);
+        line.clear();
+        let Some(stmt) = stmt else { continue };
 
-        if frag.is_empty() || frag.starts_with("--") {
-            curr.clear();
+        // This is a hack to ignore the libsql_wasm_func_table table because it is already created
+        // by the system.
+        if !skipped_wasm_table && stmt == WASM_TABLE_CREATE {
+            skipped_wasm_table = true;
             continue;
         }
 
-        line.push_str(frag);
-        curr.clear();
+        conn.execute(&stmt, ())?;
+    }
+
+    Ok(())
+}
+
+/// Whether the database has no table, besides the internal ones.
+fn is_empty(conn: &rusqlite::Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        &format!("SELECT count(*) FROM sqlite_schema WHERE {INTERNAL_TABLES}"),
+        (),
+        |row| row.get::<_, u64>(0),
+    )
+    .map(|n| n == 0)
+}
+
+/// Reads the statements of a dump from `reader`, and sends them to `statements` until the end of
+/// the dump, or until the import stops.
+async fn read_statements(
+    mut reader: impl AsyncBufRead + Unpin,
+    statements: mpsc::Sender<io::Result<String>>,
+) {
+    let mut splitter = StatementSplitter::default();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let stmt = match reader.read_line(&mut line).await {
+            Ok(0) => match splitter.finish() {
+                Some(stmt) => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("incomplete statement at the end of the dump: `{stmt}`"),
+                )),
+                None => return,
+            },
+            Ok(_) => match splitter.push_line(&line) {
+                Some(stmt) => Ok(stmt),
+                None => continue,
+            },
+            Err(e) => Err(e),
+        };
+        let failed = stmt.is_err();
+        if statements.send(stmt).await.is_err() || failed {
+            return;
+        }
+    }
+}
 
+/// Executes the statements of a dump in transactions of `batch_size` statements, and reports the
+/// statements committed to `progress`. The transactions of the dump itself are ignored, and the
+/// pragmas leading the dump are executed outside of a transaction, where they take effect.
+fn perform_import(
+    conn: &rusqlite::Connection,
+    statements: &mut mpsc::Receiver<io::Result<String>>,
+    progress: &mpsc::Sender<ImportProgress>,
+    batch_size: u64,
+    committed: &mut u64,
+) -> anyhow::Result<()> {
+    let mut pending = 0;
+    let mut skipped_wasm_table = false;
+    while let Some(stmt) = statements.blocking_recv() {
+        let stmt = stmt.context("failed to read the dump")?;
+        match first_keyword(&stmt).to_ascii_uppercase().as_str() {
+            "BEGIN" | "COMMIT" | "END" => continue,
+            "ROLLBACK" => anyhow::bail!("the dump ends with a rollback, it is incomplete"),
+            "PRAGMA" if pending == 0 => {
+                conn.execute_batch(&stmt)?;
+                *committed += 1;
+                continue;
+            }
+            _ => (),
+        }
         // This is a hack to ignore the libsql_wasm_func_table table because it is already created
         // by the system.
-        if !skipped_wasm_table && line == WASM_TABLE_CREATE {
+        if !skipped_wasm_table && stmt == WASM_TABLE_CREATE {
             skipped_wasm_table = true;
-            line.clear();
             continue;
         }
 
-        if line.ends_with(';') {
-            conn.execute(&line, ())?;
-            line.clear();
-        } else {
-            line.push(' ');
+        if pending == 0 {
+            conn.execute_batch("BEGIN IMMEDIATE")?;
         }
+        conn.execute_batch(&stmt)
+            .with_context(|| format!("statement {} failed", *committed + pending + 1))?;
+        pending += 1;
+        if pending == batch_size {
+            conn.execute_batch("COMMIT")?;
+            *committed += pending;
+            pending = 0;
+            let _ = progress.blocking_send(ImportProgress::Committed(*committed));
+        }
+    }
+
+    if pending > 0 {
+        conn.execute_batch("COMMIT")?;
+        *committed += pending;
     }
 
     Ok(())
 }
+
+fn first_keyword(stmt: &str) -> &str {
+    stmt.split(|c: char| c.is_whitespace() || c == ';')
+        .next()
+        .unwrap_or_default()
+}
+
+/// Reassembles the statements of a dump from its lines. A statement ends with the line on which it
+/// is complete, so that the statements spanning several lines, such as triggers, are kept whole.
+#[derive(Default)]
+struct StatementSplitter {
+    stmt: String,
+}
+
+impl StatementSplitter {
+    /// Pushes the next line of the dump, and returns the statement it completes, if any.
+    fn push_line(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim();
+        if self.stmt.is_empty() && (trimmed.is_empty() || trimmed.starts_with("--")) {
+            return None;
+        }
+
+        self.stmt.push_str(line.trim_end_matches(['\r', '\n']));
+        self.stmt.push('\n');
+        if !is_complete(&self.stmt) {
+            return None;
+        }
+
+        let stmt = std::mem::take(&mut self.stmt);
+        Some(stmt.trim().to_string())
+    }
+
+    /// Returns the incomplete statement left at the end of the dump, if any.
+    fn finish(self) -> Option<String> {
+        let stmt = self.stmt.trim();
+        (!stmt.is_empty()).then(|| stmt.to_string())
+    }
+}
+
+fn is_complete(s: &str) -> bool {
+    match CString::new(s) {
+        Ok(s) => unsafe { rusqlite::ffi::sqlite3_complete(s.as_ptr()) != 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE_SCHEMA: &str = "
+        CREATE TABLE items (name TEXT, price REAL);
+        CREATE INDEX items_name ON items (name);
+        CREATE TABLE orders (id INTEGER PRIMARY KEY AUTOINCREMENT, item TEXT);
+        CREATE TABLE tags (tag TEXT PRIMARY KEY, item TEXT) WITHOUT ROWID;
+        CREATE TRIGGER orders_item AFTER INSERT ON orders
+        BEGIN
+            INSERT INTO tags VALUES (new.item || '-ordered', new.item);
+        END;
+        CREATE VIEW cheap_items AS SELECT name FROM items WHERE price < 2;
+        INSERT INTO items VALUES ('apple', 1.5), ('pear', 2.5), ('plum', 0.5);
+        DELETE FROM items WHERE name = 'pear';
+        INSERT INTO orders (item) VALUES ('apple'), ('plum');
+        DELETE FROM orders WHERE item = 'plum';
+    ";
+
+    /// Creates the database file `source.db` in `dir`, with `page_size` bytes pages.
+    fn source_database(dir: &Path, page_size: u32) -> PathBuf {
+        let path = dir.join("source.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.pragma_update(None, "page_size", page_size).unwrap();
+        conn.execute_batch(SOURCE_SCHEMA).unwrap();
+        path
+    }
+
+    fn query<T: rusqlite::types::FromSql>(path: &Path, sql: &str) -> Vec<T> {
+        let conn = rusqlite::Connection::open(path.join("data")).unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map((), |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn staged_database_is_checked() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let not_a_db = dir.path().join("dump.sql");
+        std::fs::write(&not_a_db, "CREATE TABLE t (x);\n").unwrap();
+        assert!(matches!(
+            StagedDatabase::copy_from(&not_a_db, dir.path()),
+            Err(LoadError::NotADatabase)
+        ));
+
+        let wal_mode = dir.path().join("wal.db");
+        let conn = rusqlite::Connection::open(&wal_mode).unwrap();
+        conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+        conn.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (1);")
+            .unwrap();
+        assert!(matches!(
+            StagedDatabase::copy_from(&wal_mode, dir.path()),
+            Err(LoadError::HotJournal(journal)) if journal.ends_with("wal.db-wal")
+        ));
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))
+            .unwrap();
+        assert!(StagedDatabase::copy_from(&wal_mode, dir.path()).is_ok());
+        drop(conn);
+
+        let source = source_database(dir.path(), 4096);
+        let mut bytes = std::fs::read(&source).unwrap();
+        bytes[4096..8192].fill(0xff);
+        let corrupted = dir.path().join("corrupted.db");
+        std::fs::write(&corrupted, bytes).unwrap();
+        assert!(matches!(
+            StagedDatabase::copy_from(&corrupted, dir.path()),
+            Err(LoadError::Corrupted(_))
+        ));
+
+        // the rejected files are not left behind
+        let staged = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("load-")
+            })
+            .count();
+        assert_eq!(staged, 0);
+    }
+
+    #[tokio::test]
+    async fn uploaded_database_is_size_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = source_database(dir.path(), 4096);
+        let bytes = std::fs::read(source).unwrap();
+
+        let res = StagedDatabase::upload(&bytes[..], dir.path(), bytes.len() as u64 - 1).await;
+        assert!(matches!(res, Err(LoadError::TooLarge(_))));
+        let res = StagedDatabase::upload(&bytes[..], dir.path(), bytes.len() as u64).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn database_is_copied_into_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = source_database(dir.path(), 1024);
+        let db_dir = tempfile::tempdir().unwrap();
+        let path = db_dir.path();
+        let logger = ReplicationLogger::open(path, 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let loader = DumpLoader::new(path.to_path_buf(), logger.clone(), None)
+            .await
+            .unwrap();
+        let mut ctx = ReplicationLoggerHookCtx::new(logger.clone(), None);
+        let conn = open_db(path, &REPLICATION_METHODS, &mut ctx, None).unwrap();
+        conn.execute_batch("CREATE TABLE existing (x); INSERT INTO existing VALUES (42);")
+            .unwrap();
+        let frame_no = *logger.new_frame_notifier.borrow();
+
+        let staged = StagedDatabase::copy_from(&source, path).unwrap();
+        let loaded = loader.load_database(staged).await.unwrap();
+        assert_eq!(loaded, LoadedDatabase { tables: 3, rows: 5 });
+        assert!(*logger.new_frame_notifier.borrow() > frame_no);
+
+        assert_eq!(
+            query::<String>(
+                path,
+                "SELECT rowid || ':' || name FROM items ORDER BY rowid"
+            ),
+            ["1:apple", "3:plum"]
+        );
+        assert_eq!(
+            query::<String>(path, "SELECT * FROM cheap_items"),
+            ["apple", "plum"]
+        );
+        assert_eq!(query::<i64>(path, "SELECT x FROM existing"), [42]);
+        assert_eq!(
+            query::<String>(path, "SELECT name FROM sqlite_schema WHERE type = 'index'"),
+            ["items_name"]
+        );
+
+        // the sequence and the trigger of `orders` are copied
+        conn.execute_batch("INSERT INTO orders (item) VALUES ('kiwi')")
+            .unwrap();
+        assert_eq!(
+            query::<String>(path, "SELECT id || ':' || item FROM orders"),
+            ["1:apple", "3:kiwi"]
+        );
+        assert_eq!(
+            query::<String>(path, "SELECT tag FROM tags"),
+            ["apple-ordered", "kiwi-ordered", "plum-ordered"]
+        );
+
+        let staged = StagedDatabase::copy_from(&source, path).unwrap();
+        assert!(matches!(
+            loader.load_database(staged).await,
+            Err(LoadError::Conflict(name)) if name == "items"
+        ));
+        assert_eq!(query::<i64>(path, "SELECT count(*) FROM items"), [2]);
+    }
+
+    #[test]
+    fn database_is_placed_into_fresh_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = source_database(dir.path(), 1024);
+        let db_dir = tempfile::tempdir().unwrap();
+        let path = db_dir.path();
+
+        StagedDatabase::copy_from(&source, path)
+            .unwrap()
+            .place(path)
+            .unwrap();
+        assert_eq!(
+            query::<i64>(path, "PRAGMA page_size"),
+            [WAL_PAGE_SIZE as i64]
+        );
+        assert_eq!(query::<String>(path, "PRAGMA journal_mode"), ["wal"]);
+
+        let logger = ReplicationLogger::open(path, 0, None, true, Box::new(|_| Ok(()))).unwrap();
+        assert!(*logger.new_frame_notifier.borrow() > 0);
+        assert_eq!(
+            query::<String>(path, "SELECT name FROM items ORDER BY rowid"),
+            ["apple", "plum"]
+        );
+    }
+
+    #[test]
+    fn split_dump_statements() {
+        let dump = "PRAGMA foreign_keys=OFF;\n\
+            BEGIN TRANSACTION;\n\
+            -- a comment\n\
+            CREATE TABLE t (x TEXT);\n\
+            INSERT INTO t VALUES('a;\n\
+            b');\n\
+            CREATE TRIGGER tr AFTER INSERT ON t BEGIN\n  \
+            INSERT INTO t VALUES('c');\n\
+            END;\n\
+            COMMIT;\n";
+        let mut splitter = StatementSplitter::default();
+        let stmts = dump
+            .split_inclusive('\n')
+            .filter_map(|line| splitter.push_line(line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stmts,
+            [
+                "PRAGMA foreign_keys=OFF;",
+                "BEGIN TRANSACTION;",
+                "CREATE TABLE t (x TEXT);",
+                "INSERT INTO t VALUES('a;\nb');",
+                "CREATE TRIGGER tr AFTER INSERT ON t BEGIN\n  INSERT INTO t VALUES('c');\nEND;",
+                "COMMIT;",
+            ]
+        );
+        assert_eq!(splitter.finish(), None);
+
+        let mut splitter = StatementSplitter::default();
+        assert_eq!(splitter.push_line("INSERT INTO t VALUES(1)\n"), None);
+        assert_eq!(
+            splitter.finish().as_deref(),
+            Some("INSERT INTO t VALUES(1)")
+        );
+    }
+}
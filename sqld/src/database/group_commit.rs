@@ -0,0 +1,99 @@
+//! Group commit of small writes on the primary.
+//!
+//! Every transaction committed on the primary pays for a sync of the WAL. With many clients each
+//! writing a single row, the syncs, and not SQLite, cap the throughput. With group commit, the
+//! single-statement writes that arrive within a short window are collected in a group, which is
+//! executed in a single transaction on a dedicated connection: the whole group pays for a single
+//! sync. The writes of a group are isolated from each other by savepoints, and each write gets its
+//! response once the group is committed.
+
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+/// Maximum number of writes in a group. A full group is executed without waiting for the end of
+/// the window.
+const MAX_GROUP_SIZE: usize = 1024;
+
+/// Collects the writes submitted within a window into groups.
+pub struct GroupCommit<W> {
+    sender: mpsc::UnboundedSender<W>,
+}
+
+impl<W: Send + 'static> GroupCommit<W> {
+    /// Spawns the task collecting the groups, which are passed to `execute_group`. A group is
+    /// started by the first write submitted after the previous group was passed on, and lasts
+    /// for `window`.
+    pub fn spawn(window: Duration, mut execute_group: impl FnMut(Vec<W>) + Send + 'static) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(first) = receiver.recv().await {
+                let mut group = vec![first];
+                let end_of_window = tokio::time::sleep(window);
+                tokio::pin!(end_of_window);
+                while group.len() < MAX_GROUP_SIZE {
+                    tokio::select! {
+                        write = receiver.recv() => match write {
+                            Some(write) => group.push(write),
+                            None => break,
+                        },
+                        _ = &mut end_of_window => break,
+                    }
+                }
+
+                tracing::trace!("executing group of {} writes", group.len());
+                execute_group(group);
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Adds `write` to the current group. The write is given back if the group commit task
+    /// stopped.
+    pub fn submit(&self, write: W) -> Result<(), W> {
+        self.sender.send(write).map_err(|e| e.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn group_writes_within_window() {
+        let groups = Arc::new(Mutex::new(Vec::new()));
+        let group_commit = GroupCommit::spawn(Duration::from_millis(200), {
+            let groups = groups.clone();
+            move |group| groups.lock().unwrap().push(group)
+        });
+
+        group_commit.submit(1).unwrap();
+        group_commit.submit(2).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        group_commit.submit(3).unwrap();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        group_commit.submit(4).unwrap();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        assert_eq!(*groups.lock().unwrap(), vec![vec![1, 2, 3], vec![4]]);
+    }
+
+    #[tokio::test]
+    async fn execute_full_groups_right_away() {
+        let groups = Arc::new(Mutex::new(Vec::new()));
+        let group_commit = GroupCommit::spawn(Duration::from_secs(60), {
+            let groups = groups.clone();
+            move |group: Vec<usize>| groups.lock().unwrap().push(group.len())
+        });
+
+        for i in 0..MAX_GROUP_SIZE + 1 {
+            group_commit.submit(i).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(*groups.lock().unwrap(), vec![MAX_GROUP_SIZE]);
+    }
+}
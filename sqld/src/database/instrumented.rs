@@ -0,0 +1,378 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use rusqlite::types::ValueRef;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::{self, Authenticated};
+use crate::connections::{self, ClientConnection};
+use crate::error::Error;
+use crate::metrics::{self, Frontend};
+use crate::query_analysis::State;
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+use crate::rate_limit::RateLimiter;
+use crate::replication::FrameNo;
+
+use super::factory::DbFactory;
+use super::{Database, DescribeResult, Program};
+
+/// Creates the databases of a frontend, which record the metrics of the statements they execute,
+/// and report their activity to the client connection they are created for.
+pub struct InstrumentedDbFactory<D> {
+    inner: Arc<dyn DbFactory<Db = D>>,
+    frontend: Frontend,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<D> InstrumentedDbFactory<D> {
+    pub fn new(inner: Arc<dyn DbFactory<Db = D>>, frontend: Frontend) -> Self {
+        Self {
+            inner,
+            frontend,
+            rate_limiter: None,
+        }
+    }
+
+    /// Rejects the programs of the clients over their budgets. The limiter is shared by the
+    /// frontends.
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Database> DbFactory for InstrumentedDbFactory<D> {
+    type Db = InstrumentedDatabase<D>;
+
+    async fn create(&self) -> Result<Self::Db, Error> {
+        let inner = self.inner.create().await?;
+        Ok(InstrumentedDatabase {
+            inner,
+            frontend: self.frontend,
+            rate_limiter: self.rate_limiter.clone(),
+            connection: connections::current().map(|c| Arc::downgrade(&c)),
+            in_txn: AtomicBool::new(false),
+        })
+    }
+}
+
+pub struct InstrumentedDatabase<D> {
+    inner: D,
+    frontend: Frontend,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    connection: Option<Weak<ClientConnection>>,
+    /// Whether the database is in a transaction, as reported to the client connection.
+    in_txn: AtomicBool,
+}
+
+impl<D> InstrumentedDatabase<D> {
+    fn report(&self, state: Option<State>) {
+        let Some(connection) = self.connection.as_ref().and_then(Weak::upgrade) else {
+            return;
+        };
+        connection.touch();
+        if let Some(state) = state {
+            let in_txn = state == State::Txn;
+            if self.in_txn.swap(in_txn, Ordering::Relaxed) != in_txn {
+                connection.set_in_txn(in_txn);
+            }
+        }
+    }
+}
+
+impl<D> Drop for InstrumentedDatabase<D> {
+    fn drop(&mut self) {
+        // a dropped database rolls back its transaction
+        if self.in_txn.load(Ordering::Relaxed) {
+            self.report(Some(State::Init));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Database> Database for InstrumentedDatabase<D> {
+    async fn execute_program<B: QueryResultBuilder>(
+        &self,
+        pgm: Program,
+        auth: Authenticated,
+        builder: B,
+    ) -> crate::Result<(B, State)> {
+        let metrics = metrics::frontend(self.frontend);
+        let source_ip = self
+            .connection
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .and_then(|c| c.peer_addr())
+            .map(|addr| addr.ip());
+        let identity = auth::current_identity();
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            let principal = identity.as_ref().and_then(|i| i.principal.as_deref());
+            check_rate_limit(
+                rate_limiter,
+                self.frontend,
+                source_ip,
+                principal,
+                !pgm.is_read_only(),
+            )?;
+        }
+        metrics.batch_size.observe(pgm.steps().len() as f64);
+        auth::audit(identity.as_ref(), source_ip, None, pgm.steps().len());
+
+        let builder = CountOutcomes {
+            inner: builder,
+            frontend: self.frontend,
+            outcome: None,
+        };
+        let start = Instant::now();
+        let res = self.inner.execute_program(pgm, auth, builder).await;
+        metrics.duration.observe(start.elapsed().as_secs_f64());
+
+        match res {
+            Ok((builder, state)) => {
+                self.report(Some(state));
+                Ok((builder.inner, state))
+            }
+            Err(e) => {
+                // the whole program failed, its statements reported no outcome
+                metrics.statements_error.fetch_add(1, Ordering::Relaxed);
+                self.report(None);
+                Err(e)
+            }
+        }
+    }
+
+    async fn describe(&self, sql: String, auth: Authenticated) -> crate::Result<DescribeResult> {
+        self.inner.describe(sql, auth).await
+    }
+}
+
+/// Takes a program from the budgets of its client, see [`RateLimiter::check`], and counts it in
+/// the metrics of `frontend` if it's rejected.
+pub fn check_rate_limit(
+    rate_limiter: &RateLimiter,
+    frontend: Frontend,
+    source_ip: Option<IpAddr>,
+    principal: Option<&str>,
+    write: bool,
+) -> Result<(), Error> {
+    let res = rate_limiter.check(source_ip, principal, write);
+    if res.is_err() {
+        metrics::frontend(frontend).rate_limited[write as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    res
+}
+
+/// Counts the outcome of the statements that were executed. The skipped steps are not counted.
+struct CountOutcomes<B> {
+    inner: B,
+    frontend: Frontend,
+    /// Whether the current step succeeded, if it was executed.
+    outcome: Option<bool>,
+}
+
+impl<B: QueryResultBuilder> QueryResultBuilder for CountOutcomes<B> {
+    type Ret = B::Ret;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        self.outcome = None;
+        self.inner.init(config)
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.outcome = None;
+        self.inner.begin_step()
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        let metrics = metrics::frontend(self.frontend);
+        match self.outcome.take() {
+            Some(true) => metrics.statements_ok.fetch_add(1, Ordering::Relaxed),
+            Some(false) => metrics.statements_error.fetch_add(1, Ordering::Relaxed),
+            None => 0,
+        };
+        self.inner
+            .finish_step(affected_row_count, last_insert_rowid)
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        self.outcome = Some(false);
+        self.inner.step_error(error)
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        // every executed statement describes its columns first, even if it has none
+        self.outcome = Some(true);
+        self.inner.cols_description(cols)
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_rows()
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.begin_row()
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.inner.add_row_value(v)
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_row()
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish_rows()
+    }
+
+    fn replication_index(&mut self, index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        self.inner.replication_index(index)
+    }
+
+    fn proxied(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.proxied()
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.finish()
+    }
+
+    fn cancellation(&self) -> Option<CancellationToken> {
+        self.inner.cancellation()
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.inner.into_ret()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::Authorized;
+    use crate::query_result_builder::IgnoreResult;
+
+    use super::*;
+
+    /// Reports a successful, a failed and a skipped statement.
+    struct ThreeSteps;
+
+    #[async_trait::async_trait]
+    impl Database for ThreeSteps {
+        async fn execute_program<B: QueryResultBuilder>(
+            &self,
+            _pgm: Program,
+            _auth: Authenticated,
+            mut builder: B,
+        ) -> crate::Result<(B, State)> {
+            builder.init(&QueryBuilderConfig::default())?;
+            builder.begin_step()?;
+            builder.cols_description(std::iter::empty::<(&str, Option<&str>)>())?;
+            builder.finish_step(1, None)?;
+            builder.begin_step()?;
+            builder.step_error(Error::LibSqlTxTimeout(Duration::from_secs(5)))?;
+            builder.finish_step(0, None)?;
+            builder.begin_step()?;
+            builder.finish_step(0, None)?;
+            builder.finish()?;
+            Ok((builder, State::Init))
+        }
+
+        async fn describe(
+            &self,
+            _sql: String,
+            _auth: Authenticated,
+        ) -> crate::Result<DescribeResult> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn count_statement_outcomes() {
+        let factory: Arc<dyn DbFactory<Db = ThreeSteps>> =
+            Arc::new(|| async { Ok::<_, Error>(ThreeSteps) });
+        let factory = InstrumentedDbFactory::new(factory, Frontend::Ws);
+        let db = factory.create().await.unwrap();
+
+        let metrics = metrics::frontend(Frontend::Ws);
+        let ok = metrics.statements_ok.load(Ordering::Relaxed);
+        let error = metrics.statements_error.load(Ordering::Relaxed);
+        db.execute_program(
+            Program::seq(&["SELECT 1", "SELECT 2", "SELECT 3"]),
+            Authenticated::Authorized(Authorized::FullAccess),
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(metrics.statements_ok.load(Ordering::Relaxed), ok + 1);
+        assert_eq!(metrics.statements_error.load(Ordering::Relaxed), error + 1);
+        assert!(
+            crate::metrics::render().contains("sqld_batch_size_bucket{frontend=\"ws\",le=\"5\"}")
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_shared_by_the_frontends() {
+        use crate::auth::{AuthMethod, Identity};
+        use crate::rate_limit::{RateLimit, RateLimitConfig};
+
+        let factory: Arc<dyn DbFactory<Db = ThreeSteps>> =
+            Arc::new(|| async { Ok::<_, Error>(ThreeSteps) });
+        let rate_limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+            per_principal: Some(RateLimit {
+                reads: 2,
+                writes: 2,
+            }),
+            ..Default::default()
+        }));
+        let http = InstrumentedDbFactory::new(factory.clone(), Frontend::Http)
+            .with_rate_limiter(Some(rate_limiter.clone()))
+            .create()
+            .await
+            .unwrap();
+        let ws = InstrumentedDbFactory::new(factory, Frontend::Ws)
+            .with_rate_limiter(Some(rate_limiter))
+            .create()
+            .await
+            .unwrap();
+
+        let alice = Identity {
+            principal: Some("alice".into()),
+            method: AuthMethod::Jwt,
+            namespace: None,
+        };
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let read = || Program::seq(&["SELECT 1"]);
+        let limited = &metrics::frontend(Frontend::Ws).rate_limited[0];
+        let before = limited.load(Ordering::Relaxed);
+        auth::with_identity(alice, async {
+            http.execute_program(read(), auth, IgnoreResult)
+                .await
+                .unwrap();
+            ws.execute_program(read(), auth, IgnoreResult)
+                .await
+                .unwrap();
+            let res = ws.execute_program(read(), auth, IgnoreResult).await;
+            assert!(matches!(res, Err(Error::RateLimited(_))));
+        })
+        .await;
+        assert_eq!(limited.load(Ordering::Relaxed), before + 1);
+
+        // the anonymous clients without an address are not limited
+        ws.execute_program(read(), auth, IgnoreResult)
+            .await
+            .unwrap();
+    }
+}
@@ -1,32 +1,78 @@
+use std::borrow::Cow;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 
 use crossbeam::channel::RecvTimeoutError;
-use rusqlite::{ErrorCode, OpenFlags, StatementStatus};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization, TransactionOperation};
+use rusqlite::types::ValueRef;
+use rusqlite::{ErrorCode, InterruptHandle, OpenFlags, StatementStatus};
 use sqld_libsql_bindings::wal_hook::WalMethodsHook;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 use tracing::warn;
 
-use crate::auth::{Authenticated, Authorized};
-use crate::error::Error;
+use crate::auth::{self, Authenticated, Authorized};
+use crate::connections::{self, Interrupt};
+use crate::error::{redact_sql, Error};
 use crate::libsql::wal_hook::WalHook;
 use crate::query::Query;
-use crate::query_analysis::{State, StmtKind};
+use crate::query_analysis::{
+    is_reserved_table, DenyMatch, DenyRule, State, Statement, StmtClass, StmtKind,
+};
 use crate::query_result_builder::{QueryBuilderConfig, QueryResultBuilder};
+use crate::replication::primary::change_log::{ChangeCapture, ChangeLog};
+use crate::replication::{FrameNo, WAL_PAGE_SIZE};
 use crate::stats::Stats;
+use crate::storage_health;
+use crate::utils::panic::catch_panic;
+use crate::utils::shared::Shared;
 use crate::Result;
 
+use super::analyze::AutoAnalyze;
+use super::cache_budget::{CacheShare, CACHE_BUDGET};
 use super::config::DatabaseConfigStore;
+use super::constraint::{foreign_key_violations, ConstraintKind, ConstraintViolation};
 use super::factory::DbFactory;
+use super::group_commit::GroupCommit;
+use super::pool::{Pool, PoolConfig};
+use super::query_stats::QueryStats;
+use super::session_state::{SessionState, SqliteTxn};
+use super::settings::{
+    RequireParameterized, ResultLimit, ResultLimits, SessionConfig, SessionSettings,
+    SettingCommand, SettingsError, UnknownSettings,
+};
 use super::{
     Cond, Database, DescribeCol, DescribeParam, DescribeResponse, DescribeResult, Program, Step,
-    TXN_TIMEOUT,
 };
 
 /// Internal message used to communicate between the database thread and the `LibSqlDb` handle.
 type ExecCallback = Box<dyn FnOnce(Result<&mut Connection>) -> anyhow::Result<()> + Send + 'static>;
 
+/// Number of virtual machine instructions between two checks of the statement timeout and of the
+/// cancellation of the results.
+const INTERRUPT_CHECK_INTERVAL: i32 = 1000;
+
+/// Name of the savepoint wrapping the batches executed inside a transaction.
+const BATCH_SAVEPOINT: &str = "sqld_batch";
+
+/// Name of the savepoint wrapping each write of a group, see [`super::group_commit`].
+const GROUP_WRITE_SAVEPOINT: &str = "sqld_group_write";
+/// The largest maximum number of pages of SQLite, set when a size quota is removed.
+const SQLITE_MAX_PAGE_COUNT: u64 = 0xfffffffe;
+
+type GroupedWrites = GroupCommit<Box<dyn GroupedWrite>>;
+
+/// The savepoint wrapping a batch executed inside an open transaction: if a statement of the batch
+/// fails, the whole batch is rolled back, but the transaction remains open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BatchSavepoint {
+    None,
+    Open,
+    /// A statement failed, and the batch was rolled back: the remaining steps are skipped.
+    RolledBack,
+}
+
 pub struct LibSqlDbFactory<W: WalHook + 'static> {
     db_path: PathBuf,
     hook: &'static WalMethodsHook<W>,
@@ -35,6 +81,16 @@ pub struct LibSqlDbFactory<W: WalHook + 'static> {
     config_store: Arc<DatabaseConfigStore>,
     extensions: Vec<PathBuf>,
     max_response_size: u64,
+    change_log: Option<Arc<ChangeLog>>,
+    query_stats: Option<Arc<QueryStats>>,
+    auto_analyze: Option<Arc<AutoAnalyze>>,
+    replication_index: Option<watch::Receiver<FrameNo>>,
+    /// Replaced when the configuration is reloaded, for the connections opened, or reset, after.
+    session_config: Shared<SessionConfig>,
+    /// Only set if group commit is enabled.
+    group_commit: Option<Arc<GroupedWrites>>,
+    /// The idle connections, if they are pooled.
+    pool: Option<Arc<Pool<DbWorker>>>,
     /// In wal mode, closing the last database takes time, and causes other databases creation to
     /// return sqlite busy. To mitigate that, we hold on to one connection
     _db: Option<LibSqlDb>,
@@ -45,6 +101,7 @@ where
     W: WalHook + 'static + Sync + Send,
     W::Context: Send + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new<F>(
         db_path: PathBuf,
         hook: &'static WalMethodsHook<W>,
@@ -53,6 +110,12 @@ where
         config_store: Arc<DatabaseConfigStore>,
         extensions: Vec<PathBuf>,
         max_response_size: u64,
+        change_log: Option<Arc<ChangeLog>>,
+        query_stats: Option<Arc<QueryStats>>,
+        auto_analyze: Option<Arc<AutoAnalyze>>,
+        replication_index: Option<watch::Receiver<FrameNo>>,
+        session_config: impl Into<Shared<SessionConfig>>,
+        group_commit_window: Option<Duration>,
     ) -> Result<Self>
     where
         F: Fn() -> W::Context + Sync + Send + 'static,
@@ -65,12 +128,27 @@ where
             config_store,
             extensions,
             max_response_size,
+            change_log,
+            query_stats,
+            auto_analyze,
+            replication_index,
+            session_config: session_config.into(),
+            group_commit: None,
+            pool: None,
             _db: None,
         };
 
         let db = this.try_create_db().await?;
         this._db = Some(db);
 
+        if let Some(window) = group_commit_window {
+            // the groups are executed on a dedicated connection
+            let group_db = this.try_create_db().await?;
+            this.group_commit = Some(Arc::new(GroupCommit::spawn(window, move |writes| {
+                group_db.execute_group(writes)
+            })));
+        }
+
         Ok(this)
     }
 
@@ -103,8 +181,48 @@ where
         }
     }
 
+    /// Keeps the dropped connections open in a pool, and checks the new connections out of it,
+    /// see [`super::pool`]. `min_idle` connections are opened right away.
+    pub async fn with_pool(mut self, config: PoolConfig) -> Result<Self> {
+        if !config.is_enabled() {
+            return Ok(self);
+        }
+        let pool = Pool::spawn(config);
+        for _ in 0..config.min_idle.min(config.max_idle) {
+            let _: Result<_, _> = pool.put(self.spawn_worker().await?);
+        }
+        self.pool = Some(pool);
+        Ok(self)
+    }
+
     async fn create_database(&self) -> Result<LibSqlDb> {
-        LibSqlDb::new(
+        let worker = self.spawn_worker().await?;
+        Ok(self.handle(&worker, None))
+    }
+
+    /// Checks a connection out of the pool, or opens a new one, that goes to the pool once
+    /// dropped.
+    async fn checkout(&self, pool: &Arc<Pool<DbWorker>>) -> Result<LibSqlDb> {
+        let worker = match pool.take() {
+            Some(worker) => worker,
+            None => self.spawn_worker().await?,
+        };
+        let checkout = Arc::new(Checkout {
+            worker: Some(worker.clone()),
+            pool: Arc::downgrade(pool),
+            session_config: self.session_config.clone(),
+        });
+        Ok(self.handle(&worker, Some(checkout)))
+    }
+
+    fn handle(&self, worker: &DbWorker, checkout: Option<Arc<Checkout>>) -> LibSqlDb {
+        let mut db = LibSqlDb::from_worker(worker, checkout);
+        db.group_commit = self.group_commit.clone();
+        db
+    }
+
+    async fn spawn_worker(&self) -> Result<DbWorker> {
+        LibSqlDb::spawn_worker(
             self.db_path.clone(),
             self.extensions.clone(),
             self.hook,
@@ -114,6 +232,12 @@ where
             QueryBuilderConfig {
                 max_size: Some(self.max_response_size),
             },
+            self.change_log.clone(),
+            self.query_stats.clone(),
+            self.auto_analyze.clone(),
+            self.replication_index.clone(),
+            self.session_config.get(),
+            false,
         )
         .await
     }
@@ -128,13 +252,95 @@ where
     type Db = LibSqlDb;
 
     async fn create(&self) -> Result<Self::Db, Error> {
-        self.create_database().await
+        match &self.pool {
+            Some(pool) => self.checkout(pool).await,
+            None => self.create_database().await,
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct LibSqlDb {
     sender: crossbeam::channel::Sender<ExecCallback>,
+    /// Where the single-statement writes go, if group commit is enabled.
+    group_commit: Option<Arc<GroupedWrites>>,
+    /// Keeps the interrupt of the database registered with the client connection it was opened
+    /// for, if any.
+    _interrupt: Arc<DbInterrupt>,
+    /// Returns the connection to the pool of its factory once the last handle is dropped, if it
+    /// was checked out of a pool.
+    _checkout: Option<Arc<Checkout>>,
+}
+
+/// The thread of a connection, which runs until all the senders of its channel are dropped.
+#[derive(Clone)]
+struct DbWorker {
+    sender: crossbeam::channel::Sender<ExecCallback>,
+    interrupt_handle: Arc<InterruptHandle>,
+    interrupted: Arc<AtomicBool>,
+}
+
+/// A connection checked out of the pool of a [`LibSqlDbFactory`].
+struct Checkout {
+    worker: Option<DbWorker>,
+    pool: Weak<Pool<DbWorker>>,
+    /// The settings of the sessions the connection serves next.
+    session_config: Shared<SessionConfig>,
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        let Some(worker) = self.worker.take() else { return };
+        let sender = worker.sender.clone();
+        let pool = self.pool.clone();
+        let session_config = self.session_config.get();
+        // the connection is reset by its thread, after the programs sent before, and only goes
+        // back to the pool if it could be reset: otherwise, its thread stops once `worker` is
+        // dropped.
+        let cb: ExecCallback = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            match maybe_conn.and_then(|conn| conn.reset(session_config)) {
+                Ok(()) => {
+                    if let Some(pool) = pool.upgrade() {
+                        let _: Result<_, _> = pool.put(worker);
+                    }
+                }
+                Err(e) => tracing::debug!("closing a connection that could not be reset: {e}"),
+            }
+            Ok(())
+        });
+        let _: Result<_, _> = sender.send(cb);
+    }
+}
+
+/// Interrupts the connection of a [`LibSqlDb`] on behalf of the admin API.
+struct DbInterrupt {
+    handle: Arc<InterruptHandle>,
+    /// Set until the interrupted transaction is rolled back, see [`Connection::interrupted`].
+    interrupted: Arc<AtomicBool>,
+    sender: crossbeam::channel::Sender<ExecCallback>,
+}
+
+impl Interrupt for DbInterrupt {
+    fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.handle.interrupt();
+        // the transaction is rolled back by the database thread, once the interrupted program is
+        // done, and before the programs sent after the interruption.
+        let interrupted = self.interrupted.clone();
+        let cb: ExecCallback = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            if let Ok(conn) = maybe_conn {
+                if !conn.conn.is_autocommit() {
+                    warn!("rolling back the transaction interrupted by the administrator");
+                    conn.rollback();
+                }
+                conn.idle_since = None;
+                conn.sync_session_state();
+            }
+            interrupted.store(false, Ordering::SeqCst);
+            Ok(())
+        });
+        let _ = self.sender.send(cb);
+    }
 }
 
 pub fn open_db<'a, W>(
@@ -157,6 +363,7 @@ where
 }
 
 impl LibSqlDb {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new<W>(
         path: impl AsRef<Path> + Send + 'static,
         extensions: Vec<PathBuf>,
@@ -165,7 +372,54 @@ impl LibSqlDb {
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
         builder_config: QueryBuilderConfig,
+        change_log: Option<Arc<ChangeLog>>,
+        query_stats: Option<Arc<QueryStats>>,
+        auto_analyze: Option<Arc<AutoAnalyze>>,
+        replication_index: Option<watch::Receiver<FrameNo>>,
+        session_config: SessionConfig,
+        read_only: bool,
     ) -> crate::Result<Self>
+    where
+        W: WalHook,
+        W::Context: Send,
+    {
+        let worker = Self::spawn_worker(
+            path,
+            extensions,
+            wal_hook,
+            hook_ctx,
+            stats,
+            config_store,
+            builder_config,
+            change_log,
+            query_stats,
+            auto_analyze,
+            replication_index,
+            session_config,
+            read_only,
+        )
+        .await?;
+
+        Ok(Self::from_worker(&worker, None))
+    }
+
+    /// Opens a connection on a thread of its own.
+    #[allow(clippy::too_many_arguments)]
+    async fn spawn_worker<W>(
+        path: impl AsRef<Path> + Send + 'static,
+        extensions: Vec<PathBuf>,
+        wal_hook: &'static WalMethodsHook<W>,
+        hook_ctx: W::Context,
+        stats: Stats,
+        config_store: Arc<DatabaseConfigStore>,
+        builder_config: QueryBuilderConfig,
+        change_log: Option<Arc<ChangeLog>>,
+        query_stats: Option<Arc<QueryStats>>,
+        auto_analyze: Option<Arc<AutoAnalyze>>,
+        replication_index: Option<watch::Receiver<FrameNo>>,
+        session_config: SessionConfig,
+        read_only: bool,
+    ) -> crate::Result<DbWorker>
     where
         W: WalHook,
         W::Context: Send,
@@ -183,9 +437,16 @@ impl LibSqlDb {
                 stats,
                 config_store,
                 builder_config,
+                change_log,
+                query_stats,
+                auto_analyze,
+                replication_index,
+                session_config,
+                read_only,
             ) {
                 Ok(conn) => {
-                    let Ok(_) = init_sender.send(Ok(())) else { return };
+                    let interrupt = (conn.conn.get_interrupt_handle(), conn.interrupted.clone());
+                    let Ok(_) = init_sender.send(Ok(interrupt)) else { return };
                     conn
                 }
                 Err(e) => {
@@ -195,14 +456,21 @@ impl LibSqlDb {
             };
 
             loop {
-                let exec = match connection.timeout_deadline {
+                let exec = match connection.txn_deadline() {
                     Some(deadline) => match receiver.recv_deadline(deadline) {
                         Ok(msg) => msg,
                         Err(RecvTimeoutError::Timeout) => {
-                            warn!("transaction timed out");
-                            connection.rollback();
-                            connection.timed_out = true;
-                            connection.timeout_deadline = None;
+                            let idle = connection
+                                .idle_since
+                                .take()
+                                .map_or(Duration::ZERO, |since| since.elapsed());
+                            // the transaction may have been ended without running a program
+                            if !connection.conn.is_autocommit() {
+                                warn!("transaction timed out after being idle for {idle:?}");
+                                connection.rollback();
+                                connection.sync_session_state();
+                                connection.timed_out = Some(idle);
+                            }
                             continue;
                         }
                         Err(RecvTimeoutError::Disconnected) => break,
@@ -213,35 +481,219 @@ impl LibSqlDb {
                     },
                 };
 
-                let maybe_conn = if !connection.timed_out {
-                    Ok(&mut connection)
-                } else {
-                    Err(Error::LibSqlTxTimeout)
+                let maybe_conn = match connection.timed_out {
+                    None => Ok(&mut connection),
+                    Some(idle) => Err(Error::LibSqlTxTimeout(idle)),
                 };
 
-                if exec(maybe_conn).is_err() {
-                    tracing::warn!("Database connection closed unexpectedly");
-                    return;
-                };
+                // callbacks are expected to handle their own panics, this is only a safety net to
+                // keep the connection alive.
+                match catch_panic(|| exec(maybe_conn)) {
+                    Ok(Ok(())) => (),
+                    Ok(Err(_)) => {
+                        tracing::warn!("Database connection closed unexpectedly");
+                        return;
+                    }
+                    Err(_) => connection.rollback(),
+                }
+            }
+        });
+
+        let (handle, interrupted) = init_receiver.await??;
+
+        Ok(DbWorker {
+            sender,
+            interrupt_handle: Arc::new(handle),
+            interrupted,
+        })
+    }
+
+    /// A handle to the connection of `worker`, registered with the client connection of the
+    /// current task, if any. A connection checked out of a pool is registered anew by each of its
+    /// clients.
+    fn from_worker(worker: &DbWorker, checkout: Option<Arc<Checkout>>) -> Self {
+        let interrupt = Arc::new(DbInterrupt {
+            handle: worker.interrupt_handle.clone(),
+            interrupted: worker.interrupted.clone(),
+            sender: worker.sender.clone(),
+        });
+        if let Some(connection) = connections::current() {
+            connection.register_db(Arc::downgrade(&interrupt) as _);
+        }
+
+        Self {
+            sender: worker.sender.clone(),
+            group_commit: None,
+            _interrupt: interrupt,
+            _checkout: checkout,
+        }
+    }
+
+    /// Classifies `pgm` with SQLite, see [`Connection::classify_reads`].
+    pub async fn classify_program(&self, pgm: Program) -> Result<Program> {
+        let (resp, receiver) = oneshot::channel();
+        let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            let mut pgm = pgm;
+            let res = maybe_conn.map(|c| {
+                c.classify_reads(&mut pgm);
+                pgm
+            });
+
+            if resp.send(res).is_err() {
+                anyhow::bail!("connection closed");
             }
+
+            Ok(())
         });
 
-        init_receiver.await??;
+        let _: Result<_, _> = self.sender.send(cb);
+
+        receiver.await?
+    }
+
+    fn execute_group(&self, writes: Vec<Box<dyn GroupedWrite>>) {
+        let cb = Box::new(
+            move |maybe_conn: Result<&mut Connection>| -> anyhow::Result<()> {
+                match maybe_conn {
+                    Ok(conn) => {
+                        conn.apply_cache_budget();
+                        conn.run_group(writes);
+                        conn.cache_share.record_usage(&conn.conn);
+                    }
+                    Err(e) => {
+                        let idle = match e {
+                            Error::LibSqlTxTimeout(idle) => idle,
+                            _ => Duration::ZERO,
+                        };
+                        writes
+                            .into_iter()
+                            .for_each(|write| write.reply(Err(Error::LibSqlTxTimeout(idle)), None))
+                    }
+                }
+
+                Ok(())
+            },
+        );
+
+        let _: Result<_, _> = self.sender.send(cb);
+    }
+}
+
+/// A write waiting for its group to be executed.
+trait GroupedWrite: Send {
+    /// Executes the write, and returns whether it succeeded.
+    fn execute(&mut self, conn: &mut Connection) -> Result<bool>;
+    /// Sends the response to the write, once its group was committed, or failed.
+    fn reply(self: Box<Self>, res: Result<()>, index: Option<FrameNo>);
+}
+
+struct PendingWrite<B> {
+    pgm: Program,
+    /// The settings of the session the write comes from.
+    settings: SessionSettings,
+    /// The configuration of the session the write comes from, that the write is checked against
+    /// instead of the one of the connection of the group.
+    session_config: SessionConfig,
+    builder: B,
+    resp: oneshot::Sender<Result<(B, State)>>,
+}
+
+impl<B: QueryResultBuilder> GroupedWrite for PendingWrite<B> {
+    fn execute(&mut self, conn: &mut Connection) -> Result<bool> {
+        let _span = self
+            .settings
+            .application_name
+            .as_ref()
+            .map(|application_name| {
+                tracing::info_span!("session", application_name = %application_name).entered()
+            });
+        std::mem::swap(&mut conn.settings, &mut self.settings);
+        std::mem::swap(&mut conn.session_config, &mut self.session_config);
+        let result_limits =
+            std::mem::replace(&mut conn.result_limits, conn.session_config.result_limits);
+        let res = match catch_panic(|| {
+            self.builder.init(&conn.builder_config)?;
+            let (write, rest) = self.pgm.steps().split_first().unwrap();
+            let res = conn.execute_step(write, &[], &mut self.builder)?;
+            // the rollback following the write is skipped, see `is_groupable`
+            for _ in rest {
+                self.builder.begin_step()?;
+                self.builder.finish_step(0, None)?;
+            }
+            Ok(res)
+        }) {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::debug!(
+                    "query that caused the panic: {}",
+                    self.pgm.steps()[0].query.stmt.stmt
+                );
+                Err(Error::QueryPanicked)
+            }
+        };
+        conn.result_limits = result_limits;
+        std::mem::swap(&mut conn.session_config, &mut self.session_config);
+        std::mem::swap(&mut conn.settings, &mut self.settings);
+
+        res
+    }
 
-        Ok(Self { sender })
+    fn reply(self: Box<Self>, res: Result<()>, index: Option<FrameNo>) {
+        let Self {
+            mut builder, resp, ..
+        } = *self;
+        let res = res.and_then(|()| {
+            if let Some(index) = index {
+                builder.replication_index(index)?;
+            }
+            builder.finish()?;
+            Ok((builder, State::Init))
+        });
+        let _ = resp.send(res);
     }
 }
 
 struct Connection<'a> {
-    timeout_deadline: Option<Instant>,
+    /// Since when the open transaction is waiting for a program, see
+    /// [`SessionConfig::txn_timeout`].
+    idle_since: Option<Instant>,
     conn: sqld_libsql_bindings::Connection<'a>,
-    timed_out: bool,
+    /// Set once the transaction was rolled back for being idle, to how long it was idle.
+    timed_out: Option<Duration>,
     stats: Stats,
     config_store: Arc<DatabaseConfigStore>,
     builder_config: QueryBuilderConfig,
+    /// Set if changes made through this connection must be recorded for logical replication.
+    change_capture: Option<ChangeCapture>,
+    query_stats: Option<Arc<QueryStats>>,
+    /// Set on the primary, where the changed tables are analyzed.
+    auto_analyze: Option<Arc<AutoAnalyze>>,
+    /// The replication index of the database: the last committed frame on a primary, the last
+    /// applied frame on a replica.
+    replication_index: Option<watch::Receiver<FrameNo>>,
+    session_config: SessionConfig,
+    /// Whether the database was opened read-only, see [`Error::ReadOnlyReplica`].
+    read_only: bool,
+    /// Set when the administrator interrupted the connection: the statements fail with
+    /// [`Error::InterruptedByAdmin`] until the transaction is rolled back.
+    interrupted: Arc<AtomicBool>,
+    /// Limits on the results of the statements of the program being executed.
+    result_limits: ResultLimits,
+    /// Settings of the session this connection belongs to.
+    settings: SessionSettings,
+    batch_savepoint: BatchSavepoint,
+    /// Share of the connection in the budget of the page caches.
+    cache_share: CacheShare,
+    session_state: SessionState,
+    /// Whether the session ran a `PRAGMA`, that may have changed the settings of its connection.
+    ran_pragma: bool,
+    /// The maximum number of pages applied to the connection, see
+    /// [`super::config::DatabaseConfig::max_page_count`].
+    max_page_count: Option<u64>,
 }
 
 impl<'a> Connection<'a> {
+    #[allow(clippy::too_many_arguments)]
     fn new<W: WalHook>(
         path: &Path,
         extensions: Vec<PathBuf>,
@@ -250,14 +702,42 @@ impl<'a> Connection<'a> {
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
         builder_config: QueryBuilderConfig,
+        change_log: Option<Arc<ChangeLog>>,
+        query_stats: Option<Arc<QueryStats>>,
+        auto_analyze: Option<Arc<AutoAnalyze>>,
+        replication_index: Option<watch::Receiver<FrameNo>>,
+        session_config: SessionConfig,
+        read_only: bool,
     ) -> Result<Self> {
+        let flags = read_only.then_some(
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        );
+        let conn = open_db(path, wal_methods, hook_ctx, flags)?;
+        conn.pragma_update(None, "foreign_keys", session_config.foreign_keys)?;
+        let change_capture = change_log.map(|log| ChangeCapture::install(log, &conn));
         let this = Self {
-            conn: open_db(path, wal_methods, hook_ctx, None)?,
-            timeout_deadline: None,
-            timed_out: false,
+            conn,
+            idle_since: None,
+            timed_out: None,
             stats,
             config_store,
             builder_config,
+            change_capture,
+            query_stats,
+            auto_analyze,
+            replication_index,
+            session_config,
+            read_only,
+            interrupted: Default::default(),
+            result_limits: session_config.result_limits,
+            settings: SessionSettings::default(),
+            batch_savepoint: BatchSavepoint::None,
+            cache_share: CACHE_BUDGET.register(),
+            session_state: SessionState::new(),
+            ran_pragma: false,
+            max_page_count: None,
         };
 
         for ext in extensions {
@@ -274,27 +754,284 @@ impl<'a> Connection<'a> {
         Ok(this)
     }
 
-    fn run<B: QueryResultBuilder>(&mut self, pgm: Program, mut builder: B) -> Result<B> {
+    fn execute_program<B: QueryResultBuilder>(
+        &mut self,
+        pgm: Program,
+        builder: B,
+    ) -> Result<(B, State)> {
+        let _span = self
+            .settings
+            .application_name
+            .as_ref()
+            .map(|application_name| {
+                tracing::info_span!("session", application_name = %application_name).entered()
+            });
+        self.apply_cache_budget();
+        let steps = pgm.steps.clone();
+        let b = match catch_panic(|| self.run(pgm, builder)) {
+            Ok(res) => res?,
+            Err(_) => {
+                // queries may contain user data, so they are only logged at debug level.
+                for step in steps.iter() {
+                    tracing::debug!("query that caused the panic: {}", step.query.stmt.stmt);
+                }
+                self.rollback();
+                self.sync_session_state();
+                return Err(Error::QueryPanicked);
+            }
+        };
+        let state = self.sync_session_state();
+        self.cache_share.record_usage(&self.conn);
+
+        Ok((b, state))
+    }
+
+    /// Resizes the page cache to the share of the connection, see [`super::cache_budget`].
+    fn apply_cache_budget(&mut self) {
+        if let Err(e) = self.cache_share.apply(&self.conn) {
+            tracing::warn!("failed to resize the page cache: {e}");
+        }
+    }
+
+    fn run<B: QueryResultBuilder>(&mut self, pgm: Program, builder: B) -> Result<B> {
+        let res = self.run_steps(pgm, builder);
+        // the steps committed before a failing one are captured all the same
+        if res.is_err() && self.conn.is_autocommit() {
+            self.flush_change_capture();
+        }
+        res
+    }
+
+    fn run_steps<B: QueryResultBuilder>(&mut self, pgm: Program, mut builder: B) -> Result<B> {
         let mut results = Vec::with_capacity(pgm.steps.len());
 
         builder.init(&self.builder_config)?;
         let is_autocommit_before = self.conn.is_autocommit();
+        self.result_limits = self.session_config.result_limits.min(pgm.result_limits);
+
+        if let Some(capture) = self.change_capture.as_mut() {
+            if !pgm.is_read_only() {
+                capture.mark_dirty();
+            }
+        }
+
+        // the precondition is checked in a transaction holding the write lock, committed once the
+        // program ran, so that no other writer can sneak in between the check and the program.
+        let holds_write_lock = match pgm.expected_replication_index {
+            Some(expected) => {
+                self.lock_for_replication_index(&pgm, expected)?;
+                self.sync_session_state();
+                true
+            }
+            None => false,
+        };
+
+        self.batch_savepoint = BatchSavepoint::None;
+        if !is_autocommit_before && needs_batch_savepoint(&pgm) {
+            self.conn
+                .execute_batch(&format!("SAVEPOINT {BATCH_SAVEPOINT}"))?;
+            self.batch_savepoint = BatchSavepoint::Open;
+        }
 
         for step in pgm.steps() {
-            let res = self.execute_step(step, &results, &mut builder)?;
+            let res = if self.batch_savepoint == BatchSavepoint::RolledBack {
+                builder.begin_step()?;
+                builder.finish_step(0, None)?;
+                false
+            } else {
+                match self.execute_step(step, &results, &mut builder) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        if holds_write_lock {
+                            self.rollback();
+                        }
+                        return Err(e);
+                    }
+                }
+            };
             results.push(res);
         }
 
-        // A transaction is still open, set up a timeout
-        if is_autocommit_before && !self.conn.is_autocommit() {
-            self.timeout_deadline = Some(Instant::now() + TXN_TIMEOUT)
+        if self.batch_savepoint == BatchSavepoint::Open && !self.conn.is_autocommit() {
+            self.conn
+                .execute_batch(&format!("RELEASE {BATCH_SAVEPOINT}"))?;
+        }
+        self.batch_savepoint = BatchSavepoint::None;
+
+        // a ROLLBACK or a COMMIT in the program may have ended the transaction already
+        if holds_write_lock && !self.conn.is_autocommit() {
+            if let Err(e) = self.conn.execute_batch("COMMIT") {
+                let e = self.handle_storage_error(e.into());
+                let e = self.handle_constraint_error(e, StmtKind::TxnEnd);
+                if !self.conn.is_autocommit() {
+                    self.rollback();
+                }
+                return Err(e);
+            }
+        }
+
+        // A transaction is still open: it's rolled back if no program comes in time
+        self.idle_since = (!self.conn.is_autocommit()).then(Instant::now);
+
+        if self.conn.is_autocommit() {
+            self.flush_change_capture();
         }
 
+        if let Some(index) = self.current_replication_index() {
+            builder.replication_index(index)?;
+        }
         builder.finish()?;
 
         Ok(builder)
     }
 
+    /// Executes a group of independent writes in a single transaction. Each write runs under its
+    /// own savepoint, so that a failing write doesn't affect the others. The writes that succeed
+    /// get their response once the transaction is committed.
+    fn run_group(&mut self, writes: Vec<Box<dyn GroupedWrite>>) {
+        if let Some(capture) = self.change_capture.as_mut() {
+            capture.mark_dirty();
+        }
+
+        if let Err(e) = self.conn.execute_batch("BEGIN IMMEDIATE") {
+            tracing::warn!("could not begin the transaction of a group of writes, executing them one by one: {e}");
+            for mut write in writes {
+                let res = write.execute(self).map(|_| ());
+                write.reply(res, self.current_replication_index());
+            }
+            self.flush_change_capture();
+            return;
+        }
+        self.sync_session_state();
+
+        let savepoint = format!("SAVEPOINT {GROUP_WRITE_SAVEPOINT}");
+        let release = format!("RELEASE {GROUP_WRITE_SAVEPOINT}");
+        let rollback =
+            format!("ROLLBACK TO {GROUP_WRITE_SAVEPOINT}; RELEASE {GROUP_WRITE_SAVEPOINT}");
+        let mut executed = Vec::with_capacity(writes.len());
+        let mut writes = writes.into_iter();
+        while let Some(mut write) = writes.next() {
+            if let Err(e) = self.conn.execute_batch(&savepoint) {
+                write.reply(Err(e.into()), None);
+                continue;
+            }
+
+            let res = write.execute(self);
+            if self.conn.is_autocommit() {
+                // the failure rolled back the whole transaction, with the writes executed so far.
+                // The remaining writes go in a new group.
+                write.reply(res.map(|_| ()), None);
+                for write in executed {
+                    write.reply(Err(group_rolled_back()), None);
+                }
+                self.flush_change_capture();
+                self.sync_session_state();
+                return self.run_group(writes.collect());
+            }
+
+            match res {
+                Ok(true) => match self.conn.execute_batch(&release) {
+                    Ok(()) => executed.push(write),
+                    Err(e) => {
+                        let _ = self.conn.execute_batch(&rollback);
+                        write.reply(Err(e.into()), None);
+                    }
+                },
+                res => {
+                    if let Err(e) = self.conn.execute_batch(&rollback) {
+                        tracing::warn!(
+                            "failed to roll back a write of a group to its savepoint: {e}"
+                        );
+                    }
+                    write.reply(res.map(|_| ()), None);
+                }
+            }
+        }
+
+        match self.conn.execute_batch("COMMIT") {
+            Ok(()) => {
+                let index = self.current_replication_index();
+                for write in executed {
+                    write.reply(Ok(()), index);
+                }
+            }
+            Err(e) => {
+                let e = self.handle_storage_error(e.into());
+                tracing::warn!("failed to commit a group of writes: {e}");
+                self.rollback();
+                for write in executed {
+                    write.reply(Err(group_rolled_back()), None);
+                }
+            }
+        }
+        self.flush_change_capture();
+        self.sync_session_state();
+    }
+
+    /// Whether the connection has state of its own that the writes of the session depend on: the
+    /// settings changed by a pragma, the temporary objects, or the attached databases. Its writes
+    /// can't run on the connection of a group.
+    fn has_session_local_state(&self) -> Result<bool> {
+        if self.ran_pragma {
+            return Ok(true);
+        }
+        let has_state = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM temp.sqlite_master)
+                OR EXISTS (SELECT 1 FROM pragma_database_list WHERE name NOT IN ('main', 'temp'))",
+            (),
+            |row| row.get(0),
+        )?;
+        Ok(has_state)
+    }
+
+    /// Updates the state of the session after the connection itself began or ended a transaction.
+    fn sync_session_state(&mut self) -> State {
+        self.session_state.sync(SqliteTxn::of(&self.conn))
+    }
+
+    fn flush_change_capture(&mut self) {
+        if let Some(capture) = self.change_capture.as_mut() {
+            capture.flush(&self.conn);
+        }
+    }
+
+    fn current_replication_index(&self) -> Option<FrameNo> {
+        let index = *self.replication_index.as_ref()?.borrow();
+        (index != FrameNo::MAX).then_some(index)
+    }
+
+    /// Takes the write lock, and checks that the database wasn't written to since the `expected`
+    /// replication index. On success, the transaction holding the lock is left open.
+    fn lock_for_replication_index(&mut self, pgm: &Program, expected: FrameNo) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            return Err(Error::ReplicationIndexUnsupported("inside a transaction"));
+        }
+        if pgm
+            .steps()
+            .iter()
+            .any(|step| step.query.stmt.kind == StmtKind::TxnBegin)
+        {
+            return Err(Error::ReplicationIndexUnsupported(
+                "with a batch that begins a transaction",
+            ));
+        }
+        if self.current_replication_index().is_none() {
+            return Err(Error::ReplicationIndexUnsupported(
+                "on a database without replication index",
+            ));
+        }
+
+        self.conn.execute_batch("BEGIN IMMEDIATE")?;
+        // commits are published before the write lock is released, so the index is up to date.
+        let current = self.current_replication_index().unwrap_or_default();
+        if current > expected {
+            self.rollback();
+            return Err(Error::ReplicationIndexConflict { expected, current });
+        }
+
+        Ok(())
+    }
+
     fn execute_step(
         &mut self,
         step: &Step,
@@ -314,15 +1051,41 @@ impl<'a> Connection<'a> {
         };
 
         let (affected_row_count, last_insert_rowid) = if enabled {
-            match self.execute_query(&step.query, builder) {
+            self.apply_size_quota()?;
+            self.ran_pragma |= step.query.stmt.class == Some(StmtClass::Pragma);
+            let res = match step.query.stmt.setting.as_ref() {
+                Some(setting) => self.execute_setting(setting, builder),
+                None => self.execute_interruptible_query(&step.query, builder),
+            };
+            match res {
                 // builder error interupt the execution of query. we should exit immediately.
                 Err(e @ Error::BuilderError(_)) => return Err(e),
                 Err(e) => {
+                    let e = self.handle_read_only_error(e);
+                    let e = self.handle_size_quota_error(e);
+                    let e = self.handle_storage_error(e);
+                    let e = self.handle_constraint_error(e, step.query.stmt.kind);
+                    let e = self.rollback_batch(e, results.len());
+                    // settings never reach SQLite, their failure leaves the transaction as it was
+                    if step.query.stmt.setting.is_none() {
+                        self.session_state.after_statement(
+                            step.query.stmt.kind,
+                            false,
+                            SqliteTxn::of(&self.conn),
+                        );
+                    }
                     builder.step_error(e)?;
                     enabled = false;
                     (0, None)
                 }
-                Ok(x) => x,
+                Ok(x) => {
+                    self.session_state.after_statement(
+                        step.query.stmt.kind,
+                        true,
+                        SqliteTxn::of(&self.conn),
+                    );
+                    x
+                }
             }
         } else {
             (0, None)
@@ -333,65 +1096,405 @@ impl<'a> Connection<'a> {
         Ok(enabled)
     }
 
-    fn execute_query(
-        &self,
-        query: &Query,
-        builder: &mut impl QueryResultBuilder,
-    ) -> Result<(u64, Option<i64>)> {
-        tracing::trace!("executing query: {}", query.stmt.stmt);
-
-        let config = self.config_store.get();
-        let blocked = match query.stmt.kind {
-            StmtKind::Read | StmtKind::TxnBegin | StmtKind::Other => config.block_reads,
-            StmtKind::Write => config.block_reads || config.block_writes,
-            StmtKind::TxnEnd => false,
-        };
-        if blocked {
-            return Err(Error::Blocked(config.block_reason.clone()));
+    /// On a read-only database, the writes fail with [`Error::ReadOnlyReplica`] rather than with
+    /// the error of SQLite, and so do the writes to the databases attached read-only, with
+    /// [`Error::AttachedDatabaseReadOnly`]. The transaction in progress, if any, stays open.
+    fn handle_read_only_error(&self, error: Error) -> Error {
+        match error {
+            Error::RusqliteError(rusqlite::Error::SqliteFailure(e, msg))
+                if e.code == ErrorCode::ReadOnly =>
+            {
+                if self.read_only {
+                    Error::ReadOnlyReplica
+                } else if self.has_attached_databases() {
+                    Error::AttachedDatabaseReadOnly
+                } else {
+                    Error::RusqliteError(rusqlite::Error::SqliteFailure(e, msg))
+                }
+            }
+            error => error,
         }
+    }
 
-        let mut stmt = self.conn.prepare(&query.stmt.stmt)?;
+    fn has_attached_databases(&self) -> bool {
+        self.conn
+            .query_row(
+                "SELECT count(*) FROM pragma_database_list WHERE name NOT IN ('main', 'temp')",
+                (),
+                |row| row.get::<_, u32>(0),
+            )
+            .map_or(false, |count| count > 0)
+    }
 
-        let cols = stmt.columns();
-        let cols_count = cols.len();
-        builder.cols_description(cols.iter())?;
-        drop(cols);
+    /// Applies the size quota of the database to the connection, if it changed.
+    fn apply_size_quota(&mut self) -> Result<()> {
+        let max_page_count = self.config_store.get().max_page_count;
+        if max_page_count != self.max_page_count {
+            // SQLite never lowers the maximum below the current size of the database
+            self.conn.pragma_update(
+                None,
+                "max_page_count",
+                max_page_count.unwrap_or(SQLITE_MAX_PAGE_COUNT),
+            )?;
+            self.max_page_count = max_page_count;
+        }
 
-        query
-            .params
-            .bind(&mut stmt)
-            .map_err(Error::LibSqlInvalidQueryParams)?;
+        Ok(())
+    }
 
-        let mut qresult = stmt.raw_query();
-        builder.begin_rows()?;
-        while let Some(row) = qresult.next()? {
-            builder.begin_row()?;
-            for i in 0..cols_count {
-                let val = row.get_ref(i)?;
-                builder.add_row_value(val)?;
+    /// With a size quota, the writes that need more pages than the quota allows fail with
+    /// [`Error::SizeQuotaExceeded`]: SQLite reports them as if the disk was full, and they must not
+    /// degrade the storage.
+    fn handle_size_quota_error(&self, error: Error) -> Error {
+        let Some(max_page_count) = self.max_page_count else {
+            return error;
+        };
+        match error {
+            Error::RusqliteError(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == ErrorCode::DiskFull =>
+            {
+                Error::SizeQuotaExceeded(max_page_count * WAL_PAGE_SIZE as u64)
             }
-            builder.finish_row()?;
+            error => error,
         }
+    }
 
-        builder.finish_rows()?;
+    /// Enters the degraded mode if `error` was caused by the storage. The transaction in progress
+    /// is rolled back, so that it isn't partially applied.
+    fn handle_storage_error(&mut self, error: Error) -> Error {
+        match error {
+            Error::RusqliteError(e) if storage_health::is_storage_error(&e) => {
+                crate::STORAGE_HEALTH.degrade(e.to_string());
+                if !self.conn.is_autocommit() {
+                    self.rollback();
+                }
+                Error::StorageDegraded(e.to_string())
+            }
+            error => error,
+        }
+    }
 
-        // sqlite3_changes() is only modified for INSERT, UPDATE or DELETE; it is not reset for SELECT,
-        // but we want to return 0 in that case.
-        let affected_row_count = match query.stmt.is_iud {
-            true => self.conn.changes(),
-            false => 0,
+    /// Adds the detail of the violated constraint to a constraint violation of a statement of
+    /// kind `kind`. A commit failing because of deferred foreign keys leaves the transaction open:
+    /// it is rolled back, after listing the violations.
+    fn handle_constraint_error(&mut self, error: Error, kind: StmtKind) -> Error {
+        let Error::RusqliteError(e) = error else {
+            return error;
         };
-
-        // sqlite3_last_insert_rowid() only makes sense for INSERTs into a rowid table. we can't detect
-        // a rowid table, but at least we can detect an INSERT
-        let last_insert_rowid = match query.stmt.is_insert {
-            true => Some(self.conn.last_insert_rowid()),
-            false => None,
+        let Some(violation) = ConstraintViolation::from_sqlite_error(&e) else {
+            return Error::RusqliteError(e);
         };
 
-        drop(qresult);
-
-        self.update_stats(&stmt);
+        if matches!(kind, StmtKind::TxnEnd | StmtKind::Release)
+            && violation.kind == ConstraintKind::ForeignKey
+            && !self.conn.is_autocommit()
+        {
+            let violations = match foreign_key_violations(&self.conn) {
+                Ok(violations) if !violations.is_empty() => violations,
+                Ok(_) => {
+                    return Error::ConstraintViolation {
+                        message: e.to_string(),
+                        violation,
+                    }
+                }
+                Err(check_error) => {
+                    tracing::warn!("failed to list the violated foreign keys: {check_error}");
+                    vec![violation]
+                }
+            };
+            // the batch savepoint goes with the transaction
+            self.batch_savepoint = BatchSavepoint::None;
+            self.rollback();
+            return Error::DeferredConstraintViolation(violations);
+        }
+
+        Error::ConstraintViolation {
+            message: e.to_string(),
+            violation,
+        }
+    }
+
+    /// Rolls back the batch after its step `step` failed with `error`, if the batch is wrapped in a
+    /// savepoint.
+    fn rollback_batch(&mut self, error: Error, step: usize) -> Error {
+        // an interrupted transaction is rolled back as a whole
+        if self.batch_savepoint != BatchSavepoint::Open
+            || matches!(error, Error::InterruptedByAdmin)
+        {
+            return error;
+        }
+
+        // some errors make SQLite roll back the whole transaction, savepoint included
+        if self.conn.is_autocommit() {
+            self.batch_savepoint = BatchSavepoint::None;
+            return error;
+        }
+
+        let rollback = format!("ROLLBACK TO {BATCH_SAVEPOINT}; RELEASE {BATCH_SAVEPOINT}");
+        match self.conn.execute_batch(&rollback) {
+            Ok(()) => {
+                self.batch_savepoint = BatchSavepoint::RolledBack;
+                Error::BatchRolledBack {
+                    step,
+                    source: Box::new(error),
+                }
+            }
+            Err(e) => {
+                tracing::warn!("failed to roll back the batch to its savepoint: {e}");
+                self.batch_savepoint = BatchSavepoint::None;
+                error
+            }
+        }
+    }
+
+    fn execute_setting(
+        &mut self,
+        setting: &SettingCommand,
+        builder: &mut impl QueryResultBuilder,
+    ) -> Result<(u64, Option<i64>)> {
+        if let SettingCommand::Show { name } = setting {
+            let value = match self.settings.show(name) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    self.handle_settings_error(e)?;
+                    None
+                }
+            };
+            builder.cols_description([(name.as_str(), None::<&str>)])?;
+            builder.begin_rows()?;
+            builder.begin_row()?;
+            builder.add_row_value(
+                value
+                    .as_deref()
+                    .map_or(ValueRef::Null, |v| ValueRef::Text(v.as_bytes())),
+            )?;
+            builder.finish_row()?;
+            builder.finish_rows()?;
+
+            return Ok((0, None));
+        }
+
+        let res = match setting {
+            SettingCommand::Set { name, value } => self.settings.set(name, value.as_deref()),
+            SettingCommand::Reset { name: Some(name) } => self.settings.set(name, None),
+            SettingCommand::Reset { name: None } => {
+                self.settings = SessionSettings::default();
+                Ok(())
+            }
+            SettingCommand::SetTransaction(modes) => modes.check(),
+            SettingCommand::Show { .. } => unreachable!(),
+        };
+        if let Err(e) = res {
+            self.handle_settings_error(e)?;
+        }
+
+        builder.cols_description(std::iter::empty::<(&str, Option<&str>)>())?;
+        builder.begin_rows()?;
+        builder.finish_rows()?;
+
+        Ok((0, None))
+    }
+
+    /// Unknown settings are ignored if configured so, all other errors are returned.
+    fn handle_settings_error(&self, e: SettingsError) -> Result<()> {
+        match e {
+            SettingsError::Unknown(name)
+                if self.session_config.unknown_settings == UnknownSettings::Warn =>
+            {
+                tracing::warn!("ignoring unknown setting `{name}`");
+                Ok(())
+            }
+            e => Err(Error::InvalidSetting(e)),
+        }
+    }
+
+    /// Executes the query, interrupted when it runs past the statement timeout, when the
+    /// builder's results are cancelled, or by the administrator.
+    fn execute_interruptible_query(
+        &self,
+        query: &Query,
+        builder: &mut impl QueryResultBuilder,
+    ) -> Result<(u64, Option<i64>)> {
+        // the rest of an interrupted program is not executed
+        if self.interrupted.load(Ordering::SeqCst) {
+            return Err(Error::InterruptedByAdmin);
+        }
+
+        let timeout = self
+            .settings
+            .effective_statement_timeout(&self.session_config);
+        let cancellation = builder.cancellation();
+        let res = if timeout.is_none() && cancellation.is_none() {
+            self.execute_query(query, builder)
+        } else {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let cancelled = cancellation.clone();
+            self.conn.progress_handler(
+                INTERRUPT_CHECK_INTERVAL,
+                Some(move || {
+                    deadline.map_or(false, |deadline| Instant::now() >= deadline)
+                        || cancelled.as_ref().map_or(false, |c| c.is_cancelled())
+                }),
+            );
+            let res = self.execute_query(query, builder);
+            self.conn.progress_handler(0, None::<fn() -> bool>);
+            res
+        };
+
+        match res {
+            Err(Error::RusqliteError(rusqlite::Error::SqliteFailure(e, _)))
+                if e.code == ErrorCode::OperationInterrupted
+                    && self.interrupted.load(Ordering::SeqCst) =>
+            {
+                Err(Error::InterruptedByAdmin)
+            }
+            Err(Error::RusqliteError(rusqlite::Error::SqliteFailure(e, _)))
+                if e.code == ErrorCode::OperationInterrupted
+                    && (timeout.is_some() || cancellation.is_some()) =>
+            {
+                match timeout {
+                    Some(timeout) if !cancellation.map_or(false, |c| c.is_cancelled()) => {
+                        Err(Error::StatementTimeout(timeout))
+                    }
+                    _ => Err(Error::StatementCancelled),
+                }
+            }
+            res => res,
+        }
+    }
+
+    fn execute_query(
+        &self,
+        query: &Query,
+        builder: &mut impl QueryResultBuilder,
+    ) -> Result<(u64, Option<i64>)> {
+        tracing::trace!("executing query: {}", query.stmt.stmt);
+
+        let config = self.config_store.get();
+        let blocked = match query.stmt.kind {
+            StmtKind::Read | StmtKind::TxnBegin | StmtKind::Savepoint | StmtKind::Other => {
+                config.block_reads
+            }
+            StmtKind::Write => config.block_reads || config.block_writes,
+            StmtKind::TxnEnd | StmtKind::Release | StmtKind::RollbackTo => false,
+        };
+        if blocked {
+            return Err(Error::Blocked(config.block_reason.clone()));
+        }
+        if query.stmt.kind == StmtKind::Write {
+            crate::STORAGE_HEALTH.check_writable()?;
+        }
+
+        if let Some(literal) = query.stmt.inline_literal.as_ref() {
+            let rejected = match self.session_config.require_parameterized {
+                Some(RequireParameterized::All) => true,
+                Some(RequireParameterized::Writes) => query.stmt.kind == StmtKind::Write,
+                None => false,
+            };
+            if rejected {
+                return Err(Error::InlineLiteral(literal.clone()));
+            }
+        }
+        if let Some(rule) = self
+            .session_config
+            .denied_statements
+            .check(query.stmt.denied_by)
+        {
+            return Err(Error::StatementDenied(rule));
+        }
+        // the database of an `ATTACH` that can't be parsed can't be checked against the allowlist
+        if query.stmt.is_raw && query.stmt.class == Some(StmtClass::Attach) {
+            return Err(Error::AttachNotAllowed(
+                "the statement could not be parsed".into(),
+            ));
+        }
+        let sql = match query.stmt.attach.as_ref() {
+            Some(attach) => Cow::Owned(attach.to_sql()?),
+            None => Cow::Borrowed(query.stmt.stmt.as_str()),
+        };
+
+        let query_stats = self
+            .query_stats
+            .as_ref()
+            .filter(|stats| stats.should_sample());
+        if let Some(auto_analyze) = self.auto_analyze.as_ref() {
+            auto_analyze.record_activity();
+        }
+        let start = Instant::now();
+
+        let mut stmt = if query.stmt.is_raw {
+            self.prepare_raw(&sql)?
+        } else {
+            self.conn.prepare(&sql)?
+        };
+
+        let cols = stmt.columns();
+        let cols_count = cols.len();
+        builder.cols_description(cols.iter())?;
+        drop(cols);
+
+        query
+            .params
+            .bind(&mut stmt)
+            .map_err(Error::LibSqlInvalidQueryParams)?;
+
+        let mut qresult = stmt.raw_query();
+        let mut rows_returned = 0;
+        let mut bytes_returned = 0;
+        let limits = self.result_limits;
+        builder.begin_rows()?;
+        while let Some(row) = qresult.next()? {
+            rows_returned += 1;
+            if let Some(max) = limits.max_rows.filter(|max| rows_returned > *max) {
+                return Err(Error::ResultLimitExceeded(ResultLimit::Rows(max)));
+            }
+            builder.begin_row()?;
+            for i in 0..cols_count {
+                let val = row.get_ref(i)?;
+                bytes_returned += value_size(&val);
+                if let Some(max) = limits.max_bytes.filter(|max| bytes_returned > *max) {
+                    return Err(Error::ResultLimitExceeded(ResultLimit::Bytes(max)));
+                }
+                builder.add_row_value(val)?;
+            }
+            builder.finish_row()?;
+        }
+
+        builder.finish_rows()?;
+
+        // sqlite3_changes() is only modified for INSERT, UPDATE or DELETE; it is not reset for SELECT,
+        // but we want to return 0 in that case.
+        let affected_row_count = match query.stmt.is_iud {
+            true => self.conn.changes(),
+            false => 0,
+        };
+
+        // sqlite3_last_insert_rowid() only makes sense for INSERTs into a rowid table. we can't detect
+        // a rowid table, but at least we can detect an INSERT
+        let last_insert_rowid = match query.stmt.is_insert {
+            true => Some(self.conn.last_insert_rowid()),
+            false => None,
+        };
+
+        drop(qresult);
+
+        self.update_stats(&stmt);
+
+        if let (Some(auto_analyze), Some(table)) = (
+            self.auto_analyze.as_ref(),
+            query.stmt.written_table.as_ref(),
+        ) {
+            auto_analyze.record_changes(table, affected_row_count);
+        }
+
+        if let Some(query_stats) = query_stats {
+            query_stats.record(
+                &query.stmt.stmt,
+                start.elapsed(),
+                stmt.get_status(StatementStatus::RowsRead) as u64,
+                rows_returned,
+            );
+        }
 
         Ok((affected_row_count, last_insert_rowid))
     }
@@ -400,6 +1503,243 @@ impl<'a> Connection<'a> {
         let _ = self.conn.execute("ROLLBACK", ());
     }
 
+    /// Resets the state left by the session of the connection, before it serves another session
+    /// out of the pool of its factory: its transaction, its temporary tables, views and triggers,
+    /// its attached databases and its settings, which are replaced by `session_config`.
+    fn reset(&mut self, session_config: SessionConfig) -> Result<()> {
+        self.session_config = session_config;
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+
+        // the tables are dropped last, the views and triggers may depend on them
+        let temp_objects = self
+            .conn
+            .prepare(
+                "SELECT type, name FROM temp.sqlite_master
+                WHERE type IN ('view', 'trigger', 'table') ORDER BY type = 'table'",
+            )?
+            .query_map((), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (kind, name) in temp_objects {
+            let name = name.replace('"', "\"\"");
+            self.conn
+                .execute_batch(&format!("DROP {kind} IF EXISTS temp.\"{name}\""))?;
+        }
+
+        let attached = self
+            .conn
+            .prepare("SELECT name FROM pragma_database_list WHERE name NOT IN ('main', 'temp')")?
+            .query_map((), |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for name in attached {
+            let name = name.replace('"', "\"\"");
+            self.conn
+                .execute_batch(&format!("DETACH DATABASE \"{name}\""))?;
+        }
+
+        self.conn
+            .pragma_update(None, "foreign_keys", self.session_config.foreign_keys)?;
+        self.idle_since = None;
+        self.interrupted.store(false, Ordering::SeqCst);
+        self.settings = SessionSettings::default();
+        self.batch_savepoint = BatchSavepoint::None;
+        self.result_limits = self.session_config.result_limits;
+        self.ran_pragma = false;
+        self.sync_session_state();
+
+        Ok(())
+    }
+
+    /// When the open transaction is rolled back if no program comes, if it ever is.
+    fn txn_deadline(&self) -> Option<Instant> {
+        Some(self.idle_since? + self.session_config.txn_timeout?)
+    }
+
+    /// Classifies the statements that the parser didn't understand, using SQLite itself.
+    fn classify_raw_statements(&self, pgm: &mut Program) {
+        if !pgm.steps.iter().any(|step| step.query.stmt.is_raw) {
+            return;
+        }
+
+        for step in Arc::make_mut(&mut pgm.steps).iter_mut() {
+            if step.query.stmt.is_raw {
+                self.classify(&mut step.query.stmt);
+            }
+        }
+    }
+
+    /// Classifies the statements of `pgm` with SQLite, for the nodes that can't execute writes
+    /// themselves, like replicas. On top of the raw statements, the statements that the parser
+    /// classified as reads are checked: a statement the parser got wrong, or that fires a trigger,
+    /// becomes a write as soon as SQLite sees it write to a table.
+    fn classify_reads(&self, pgm: &mut Program) {
+        if !pgm.is_read_only() && !pgm.steps.iter().any(|step| step.query.stmt.is_raw) {
+            return;
+        }
+
+        for step in Arc::make_mut(&mut pgm.steps).iter_mut() {
+            let stmt = &mut step.query.stmt;
+            if stmt.is_raw {
+                self.classify(stmt);
+            } else if stmt.kind == StmtKind::Read && stmt.setting.is_none() {
+                let mut classified = stmt.clone();
+                self.classify(&mut classified);
+                if classified.kind != StmtKind::Read || classified.is_iud {
+                    stmt.kind = StmtKind::Write;
+                }
+            }
+        }
+    }
+
+    /// Prepares `stmt` to find out whether it writes to the database, and whether it begins or
+    /// ends a transaction. If SQLite can't prepare it either, e.g because it depends on a previous
+    /// statement of the same program, the statement is left as a write, and the error will be
+    /// reported when it is executed.
+    fn classify(&self, stmt: &mut Statement) {
+        #[derive(Default)]
+        struct Actions {
+            txn: Option<TransactionOperation>,
+            savepoint: Option<(TransactionOperation, String)>,
+            insert: bool,
+            update_or_delete: bool,
+            attach: bool,
+            pragma: bool,
+            schema: bool,
+        }
+
+        let actions = Arc::new(std::sync::Mutex::new(Actions::default()));
+        self.conn.authorizer(Some({
+            let actions = actions.clone();
+            move |ctx: AuthContext<'_>| {
+                let mut actions = actions.lock().unwrap();
+                match ctx.action {
+                    AuthAction::Transaction { operation } => actions.txn = Some(operation),
+                    AuthAction::Savepoint {
+                        operation,
+                        savepoint_name,
+                    } => actions.savepoint = Some((operation, savepoint_name.to_lowercase())),
+                    AuthAction::Insert { .. } => actions.insert = true,
+                    AuthAction::Update { .. } | AuthAction::Delete { .. } => {
+                        actions.update_or_delete = true
+                    }
+                    AuthAction::Attach { .. } | AuthAction::Detach { .. } => actions.attach = true,
+                    AuthAction::Pragma { .. } => actions.pragma = true,
+                    AuthAction::CreateIndex { .. }
+                    | AuthAction::CreateTable { .. }
+                    | AuthAction::CreateTrigger { .. }
+                    | AuthAction::CreateView { .. }
+                    | AuthAction::CreateVtable { .. }
+                    | AuthAction::DropIndex { .. }
+                    | AuthAction::DropTable { .. }
+                    | AuthAction::DropTrigger { .. }
+                    | AuthAction::DropView { .. }
+                    | AuthAction::DropVtable { .. }
+                    | AuthAction::AlterTable { .. } => actions.schema = true,
+                    _ => (),
+                }
+                Authorization::Allow
+            }
+        }));
+        let readonly = self.conn.prepare(&stmt.stmt).map(|s| s.readonly());
+        self.conn
+            .authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+
+        let Ok(readonly) = readonly else { return };
+        let mut actions = actions.lock().unwrap();
+        stmt.kind = match (actions.txn, &actions.savepoint) {
+            (Some(TransactionOperation::Begin), _) => StmtKind::TxnBegin,
+            (Some(_), _) => StmtKind::TxnEnd,
+            (None, Some((TransactionOperation::Begin, _))) => StmtKind::Savepoint,
+            (None, Some((TransactionOperation::Release, _))) => StmtKind::Release,
+            (None, Some((_, _))) => StmtKind::RollbackTo,
+            (None, None) if readonly => StmtKind::Read,
+            (None, None) => StmtKind::Write,
+        };
+        stmt.savepoint = actions.savepoint.take().map(|(_, name)| name);
+        stmt.is_insert = actions.insert;
+        stmt.is_iud = actions.insert || actions.update_or_delete;
+        // a class found from the keywords of the statement is kept, see `StmtClass::of_raw`
+        if stmt.class == Some(StmtClass::Write) {
+            stmt.class = match stmt.kind {
+                StmtKind::TxnBegin
+                | StmtKind::TxnEnd
+                | StmtKind::Savepoint
+                | StmtKind::Release
+                | StmtKind::RollbackTo => None,
+                _ if actions.attach => Some(StmtClass::Attach),
+                _ if actions.pragma => Some(StmtClass::Pragma),
+                _ if actions.schema => Some(StmtClass::Ddl),
+                StmtKind::Read => Some(StmtClass::Read),
+                _ => Some(StmtClass::Write),
+            };
+        }
+    }
+
+    /// Prepares a statement that the parser didn't understand, and so that escaped its checks:
+    /// SQLite denies the pragmas, the attached databases, the vacuums, and the writes to the
+    /// reserved tables. The actions that SQLite authorizes are checked against
+    /// `--denied-statements`, assuming that the `WHERE` clauses they can't see match all the rows.
+    fn prepare_raw(&self, sql: &str) -> Result<rusqlite::Statement<'_>> {
+        let denied = Arc::new(std::sync::Mutex::new(None));
+        let denied_statements = self.session_config.denied_statements;
+        self.conn.authorizer(Some({
+            let denied = denied.clone();
+            move |ctx: AuthContext<'_>| {
+                let error = match ctx.action {
+                    AuthAction::Pragma { pragma_name, .. } => {
+                        Error::NotAuthorized(format!("PRAGMA {pragma_name}"))
+                    }
+                    AuthAction::Attach { .. } | AuthAction::Detach { .. } => {
+                        Error::NotAuthorized("ATTACH".to_string())
+                    }
+                    AuthAction::Insert { table_name }
+                    | AuthAction::Update { table_name, .. }
+                    | AuthAction::Delete { table_name }
+                    | AuthAction::CreateTable { table_name }
+                    | AuthAction::DropTable { table_name }
+                    | AuthAction::AlterTable { table_name, .. }
+                    | AuthAction::CreateIndex { table_name, .. }
+                    | AuthAction::DropIndex { table_name, .. }
+                    | AuthAction::CreateTrigger { table_name, .. }
+                    | AuthAction::DropTrigger { table_name, .. }
+                        if is_reserved_table(table_name) =>
+                    {
+                        Error::NotAuthorized(format!("writes to the reserved table `{table_name}`"))
+                    }
+                    ref action => match raw_deny_rule(action)
+                        .and_then(|rule| denied_statements.check(Some(rule)))
+                    {
+                        Some(rule) => Error::StatementDenied(rule),
+                        None => return Authorization::Allow,
+                    },
+                };
+                // the first denied action is reported
+                denied.lock().unwrap().get_or_insert(error);
+                Authorization::Deny
+            }
+        }));
+        // `VACUUM` isn't seen by the authorizer
+        let res = match StmtClass::of_raw(sql) {
+            StmtClass::Vacuum => Err(Error::NotAuthorized(
+                "VACUUM in a statement that could not be parsed".into(),
+            )),
+            _ => self.conn.prepare(sql).map_err(Error::from),
+        };
+        self.conn
+            .authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+
+        match denied.lock().unwrap().take() {
+            Some(Error::NotAuthorized(reason)) => Err(Error::NotAuthorized(format!(
+                "{reason} in a statement that could not be parsed"
+            ))),
+            Some(error) => Err(error),
+            None => res,
+        }
+    }
+
     fn update_stats(&self, stmt: &rusqlite::Statement) {
         let rows_read = stmt.get_status(StatementStatus::RowsRead);
         let rows_written = stmt.get_status(StatementStatus::RowsWritten);
@@ -443,6 +1783,32 @@ impl<'a> Connection<'a> {
     }
 }
 
+/// Whether a batch executed inside an open transaction is wrapped in a savepoint. Batches that
+/// begin or end transactions, or manage savepoints, themselves are not, and neither are read-only
+/// batches, which can't leave the transaction in an intermediate state.
+fn needs_batch_savepoint(pgm: &Program) -> bool {
+    !pgm.is_read_only()
+        && !pgm.steps().iter().any(|step| {
+            matches!(
+                step.query.stmt.kind,
+                StmtKind::TxnBegin
+                    | StmtKind::TxnEnd
+                    | StmtKind::Savepoint
+                    | StmtKind::Release
+                    | StmtKind::RollbackTo
+            )
+        })
+}
+
+/// The size of a value, as counted by [`ResultLimits::max_bytes`].
+fn value_size(value: &ValueRef) -> u64 {
+    match value {
+        ValueRef::Null => 0,
+        ValueRef::Integer(_) | ValueRef::Real(_) => 8,
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => bytes.len() as u64,
+    }
+}
+
 fn eval_cond(cond: &Cond, results: &[bool]) -> Result<bool> {
     let get_step_res = |step: usize| -> Result<bool> {
         let res = results.get(step).ok_or(Error::InvalidBatchStep(step))?;
@@ -473,12 +1839,19 @@ fn check_program_auth(auth: Authenticated, pgm: &Program) -> Result<()> {
                 ));
             }
             (StmtKind::Read, Authenticated::Authorized(_)) => (),
-            (StmtKind::TxnBegin, _) | (StmtKind::TxnEnd, _) => (),
+            (
+                StmtKind::TxnBegin
+                | StmtKind::TxnEnd
+                | StmtKind::Savepoint
+                | StmtKind::Release
+                | StmtKind::RollbackTo,
+                _,
+            ) => (),
             (_, Authenticated::Authorized(Authorized::FullAccess)) => (),
             _ => {
                 return Err(Error::NotAuthorized(format!(
                     "Current session is not authorized to run: {}",
-                    query.stmt.stmt
+                    redact_sql(&query.stmt.stmt)
                 )));
             }
         }
@@ -486,6 +1859,48 @@ fn check_program_auth(auth: Authenticated, pgm: &Program) -> Result<()> {
     Ok(())
 }
 
+/// Whether `pgm` can be executed as part of a group of writes: it must be a single, unconditional
+/// write, optionally followed by the rollback added by [`Database::execute_batch_or_rollback`].
+/// The statements that change the schema, the settings of the connection, or its temporary
+/// objects, are executed on the connection of their session.
+fn is_groupable(pgm: &Program) -> bool {
+    let [write, rest @ ..] = pgm.steps() else {
+        return false;
+    };
+    let is_write = write.cond.is_none()
+        && write.query.stmt.kind == StmtKind::Write
+        && write.query.stmt.class == Some(StmtClass::Write)
+        && !mentions_temp_schema(&write.query.stmt.stmt)
+        && write.query.stmt.setting.is_none()
+        && pgm.expected_replication_index.is_none()
+        && pgm.result_limits.is_none();
+    // outside of a transaction, the rollback of a failed write is a no-op
+    let is_rollback_on_failure = |step: &Step| {
+        step.query.stmt.kind == StmtKind::TxnEnd
+            && matches!(&step.cond, Some(Cond::Not { cond }) if matches!(**cond, Cond::Ok { step: 0 }))
+    };
+
+    match rest {
+        [] => is_write,
+        [rollback] => is_write && is_rollback_on_failure(rollback),
+        _ => false,
+    }
+}
+
+/// Whether `sql` may refer to the temporary schema, which is local to a connection.
+fn mentions_temp_schema(sql: &str) -> bool {
+    sql.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .any(|word| word.eq_ignore_ascii_case("temp") || word.eq_ignore_ascii_case("temporary"))
+}
+
+/// The error of the writes of a group that was rolled back as a whole.
+fn group_rolled_back() -> Error {
+    crate::STORAGE_HEALTH
+        .check_writable()
+        .err()
+        .unwrap_or_else(|| Error::Internal("the group of writes was rolled back".into()))
+}
+
 fn check_describe_auth(auth: Authenticated) -> Result<()> {
     match auth {
         Authenticated::Anonymous => {
@@ -503,19 +1918,43 @@ impl Database for LibSqlDb {
         auth: Authenticated,
         builder: B,
     ) -> Result<(B, State)> {
-        check_program_auth(auth, &pgm)?;
         let (resp, receiver) = oneshot::channel();
+        let group_commit = self.group_commit.clone();
+        // the program runs on the thread of the connection, out of the task of the client
+        let principal = auth::current_identity().and_then(|identity| identity.principal);
         let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            let mut pgm = pgm;
             let res = maybe_conn.and_then(|c| {
-                let b = c.run(pgm, builder)?;
-                let state = if c.conn.is_autocommit() {
-                    State::Init
-                } else {
-                    State::Txn
-                };
-
-                Ok((b, state))
+                c.classify_raw_statements(&mut pgm);
+                check_program_auth(auth, &pgm)?;
+                c.session_config.allowed_statement_classes.check(
+                    auth,
+                    principal.as_deref(),
+                    &pgm,
+                )?;
+                Ok(c)
             });
+            let res = match (res, group_commit) {
+                // the response is sent once the group of the write is committed
+                (Ok(c), Some(group_commit))
+                    if c.conn.is_autocommit()
+                        && is_groupable(&pgm)
+                        && matches!(c.has_session_local_state(), Ok(false)) =>
+                {
+                    let write: Box<dyn GroupedWrite> = Box::new(PendingWrite {
+                        pgm,
+                        settings: c.settings.clone(),
+                        session_config: c.session_config,
+                        builder,
+                        resp,
+                    });
+                    if let Err(write) = group_commit.submit(write) {
+                        write.reply(Err(Error::Internal("group commit is stopped".into())), None);
+                    }
+                    return Ok(());
+                }
+                (res, _) => res.and_then(|c| c.execute_program(pgm, builder)),
+            };
 
             if resp.send(res).is_err() {
                 anyhow::bail!("connection closed");
@@ -548,22 +1987,77 @@ impl Database for LibSqlDb {
     }
 }
 
+/// The rule of `--denied-statements` that an action of a statement that could not be parsed may
+/// match. The `WHERE` clauses can't be seen, so all the deletes and updates match, and so do all
+/// the `ALTER TABLE`. The deletes and updates of SQLite's own tables come with schema changes.
+/// The attached databases are always denied by [`Connection::prepare_raw`].
+fn raw_deny_rule(action: &AuthAction<'_>) -> Option<DenyMatch> {
+    let rule = match action {
+        AuthAction::DropTable { .. } | AuthAction::DropTempTable { .. } => DenyRule::DropTable,
+        AuthAction::DropIndex { .. } | AuthAction::DropTempIndex { .. } => DenyRule::DropIndex,
+        AuthAction::DropView { .. } | AuthAction::DropTempView { .. } => DenyRule::DropView,
+        AuthAction::DropTrigger { .. } | AuthAction::DropTempTrigger { .. } => {
+            DenyRule::DropTrigger
+        }
+        AuthAction::CreateTrigger { .. } | AuthAction::CreateTempTrigger { .. } => {
+            DenyRule::CreateTrigger
+        }
+        AuthAction::AlterTable { .. } => DenyRule::AlterDropColumn,
+        AuthAction::Delete { table_name } if !table_name.starts_with("sqlite_") => {
+            DenyRule::TruncateLikeDelete
+        }
+        AuthAction::Update { table_name, .. } if !table_name.starts_with("sqlite_") => {
+            DenyRule::UnqualifiedUpdate
+        }
+        _ => return None,
+    };
+
+    Some(DenyMatch {
+        rule,
+        tautology: false,
+    })
+}
+
 #[cfg(test)]
 mod test {
     use itertools::Itertools;
-
-    use crate::query_result_builder::{test::test_driver, IgnoreResult};
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::{with_identity, AuthMethod, Identity};
+    use crate::database::query_stats::SortKey;
+    use crate::database::settings::{AllowedStatementClasses, DeniedStatements, DenyRule};
+    use crate::database::DEFAULT_TXN_TIMEOUT;
+    use crate::query::Params;
+    use crate::query_result_builder::{
+        test::test_driver, Column, IgnoreResult, QueryResultBuilderError, StepResult,
+        StepResultsBuilder,
+    };
+    use crate::replication::logical::LogicalOffset;
 
     use super::*;
 
     fn setup_test_conn(ctx: &mut ()) -> Connection {
         let mut conn = Connection {
-            timeout_deadline: None,
+            idle_since: None,
             conn: sqld_libsql_bindings::Connection::test(ctx),
-            timed_out: false,
+            timed_out: None,
             stats: Stats::default(),
             config_store: Arc::new(DatabaseConfigStore::new_test()),
             builder_config: QueryBuilderConfig::default(),
+            change_capture: None,
+            query_stats: None,
+            auto_analyze: None,
+            replication_index: None,
+            session_config: SessionConfig::default(),
+            read_only: false,
+            interrupted: Default::default(),
+            result_limits: ResultLimits::default(),
+            settings: SessionSettings::default(),
+            batch_savepoint: BatchSavepoint::None,
+            cache_share: CACHE_BUDGET.register(),
+            session_state: SessionState::new(),
+            ran_pragma: false,
+            max_page_count: None,
         };
 
         let stmts = std::iter::once("create table test (x)")
@@ -574,6 +2068,824 @@ mod test {
         conn
     }
 
+    fn raw_program(stmts: &[&str]) -> Program {
+        let mut pgm = Program::seq(&[]);
+        Arc::make_mut(&mut pgm.steps).extend(stmts.iter().map(|stmt| Step {
+            cond: None,
+            query: Query {
+                stmt: Statement::raw(stmt),
+                params: Params::empty(),
+                want_rows: true,
+            },
+        }));
+        pgm
+    }
+
+    #[test]
+    fn raw_statements_are_classified_by_sqlite() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.run(
+            Program::seq(&["create table kv (k primary key, v)"]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        let mut pgm = raw_program(&[
+            "create virtual table docs using fts5(body)",
+            "insert into kv values (1, 'a') on conflict (k) do update set v = excluded.v",
+            "insert into kv values (1, 'b') on conflict (k) do update set v = excluded.v",
+            // can't be prepared before the table is created, it stays a write
+            "insert into docs values ('hello world')",
+            "select * from kv",
+            "begin",
+            "commit",
+        ]);
+        conn.classify_raw_statements(&mut pgm);
+
+        let kinds = pgm
+            .steps()
+            .iter()
+            .map(|step| step.query.stmt.kind)
+            .collect_vec();
+        assert_eq!(
+            kinds,
+            [
+                StmtKind::Write,
+                StmtKind::Write,
+                StmtKind::Write,
+                StmtKind::Write,
+                StmtKind::Read,
+                StmtKind::TxnBegin,
+                StmtKind::TxnEnd,
+            ]
+        );
+        assert!(pgm.steps()[1].query.stmt.is_insert);
+        assert!(pgm.steps()[1].query.stmt.is_iud);
+        assert!(!pgm.steps()[4].query.stmt.is_iud);
+
+        conn.run(pgm, IgnoreResult).unwrap();
+        assert!(conn.conn.is_autocommit());
+        let v: String = conn
+            .conn
+            .query_row("select v from kv where k = 1", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(v, "b");
+        let count: i64 = conn
+            .conn
+            .query_row(
+                "select count(*) from docs where docs match 'hello'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn raw_statements_rejected_by_sqlite() {
+        let mut ctx = ();
+        let conn = setup_test_conn(&mut ctx);
+        let mut pgm = raw_program(&["select from from"]);
+        conn.classify_raw_statements(&mut pgm);
+        assert_eq!(pgm.steps()[0].query.stmt.kind, StmtKind::Write);
+
+        let res = conn.execute_query(&pgm.steps()[0].query, &mut IgnoreResult);
+        assert!(matches!(res, Err(Error::RusqliteError(_))));
+    }
+
+    #[test]
+    fn raw_statements_escaping_the_parser_are_denied() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.conn
+            .execute_batch("create table libsql_wasm_func_table (name text primary key, body text)")
+            .unwrap();
+
+        for sql in [
+            "pragma query_only = on",
+            "vacuum",
+            "insert into libsql_wasm_func_table values ('f', 'body')",
+            "delete from libsql_wasm_func_table",
+            "drop table libsql_wasm_func_table",
+        ] {
+            let pgm = raw_program(&[sql]);
+            let res = conn.execute_query(&pgm.steps()[0].query, &mut IgnoreResult);
+            assert!(matches!(res, Err(Error::NotAuthorized(_))), "{sql}");
+        }
+        let query_only: i64 = conn
+            .conn
+            .query_row("pragma query_only", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(query_only, 0);
+
+        // the other raw statements are run as usual
+        let pgm = raw_program(&["insert into test values (1)"]);
+        conn.execute_query(&pgm.steps()[0].query, &mut IgnoreResult)
+            .unwrap();
+
+        // and checked against the denied statements, whatever their `WHERE` clause
+        conn.session_config.denied_statements =
+            DeniedStatements::new(&[DenyRule::TruncateLikeDelete, DenyRule::DropTable], false);
+        for (sql, rule) in [
+            ("delete from test where x = 1", DenyRule::TruncateLikeDelete),
+            ("drop table test", DenyRule::DropTable),
+        ] {
+            let pgm = raw_program(&[sql]);
+            let res = conn.execute_query(&pgm.steps()[0].query, &mut IgnoreResult);
+            assert!(
+                matches!(res, Err(Error::StatementDenied(r)) if r == rule),
+                "{sql}"
+            );
+        }
+        let pgm = raw_program(&["update test set x = 2"]);
+        conn.execute_query(&pgm.steps()[0].query, &mut IgnoreResult)
+            .unwrap();
+    }
+
+    #[test]
+    fn session_state_follows_transactions() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let state = |conn: &mut Connection, sql: &[&str]| {
+            conn.execute_program(Program::seq(sql), IgnoreResult)
+                .unwrap()
+                .1
+        };
+
+        assert_eq!(state(&mut conn, &["begin", "select 1"]), State::Txn);
+        assert_eq!(state(&mut conn, &["select * from missing"]), State::Invalid);
+        assert_eq!(state(&mut conn, &["select 1"]), State::Invalid);
+        assert_eq!(state(&mut conn, &["rollback"]), State::Init);
+
+        // a write failing in autocommit mode doesn't leave a transaction behind
+        assert_eq!(
+            state(&mut conn, &["insert into missing values (1)"]),
+            State::Init
+        );
+
+        // the transaction of a timed out session is rolled back by the connection
+        assert_eq!(state(&mut conn, &["savepoint s"]), State::Txn);
+        conn.rollback();
+        assert_eq!(conn.sync_session_state(), State::Init);
+    }
+
+    const TRIGGERS_SCHEMA: &[&str] = &[
+        "create table orders (id integer primary key, amount)",
+        "create table totals (n, amount)",
+        "insert into totals values (0, 0)",
+        "create trigger count_orders after insert on orders \
+        begin update totals set n = n + 1, amount = amount + new.amount; end",
+        "create view big_orders as select * from orders where amount >= 100",
+        "create trigger insert_big_orders instead of insert on big_orders \
+        begin insert into orders (amount) values (max(new.amount, 100)); end",
+    ];
+
+    #[test]
+    fn triggers_on_tables_and_views() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.run(Program::seq(TRIGGERS_SCHEMA), IgnoreResult)
+            .unwrap();
+        conn.run(
+            Program::seq(&[
+                "insert into orders (amount) values (10)",
+                "insert into big_orders (amount) values (20)",
+            ]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        let totals: (i64, i64) = conn
+            .conn
+            .query_row("select n, amount from totals", (), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(totals, (2, 110));
+    }
+
+    #[test]
+    fn reads_are_verified_by_sqlite() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.run(Program::seq(TRIGGERS_SCHEMA), IgnoreResult)
+            .unwrap();
+
+        let mut pgm = Program::seq(&["select * from big_orders", "select n from totals"]);
+        conn.classify_reads(&mut pgm);
+        assert!(pgm.is_read_only());
+
+        // a write that the parser would have taken for a read is caught by SQLite, including
+        // the writes made by the triggers
+        let mut pgm = Program::seq(&["select * from totals"]);
+        Arc::make_mut(&mut pgm.steps)[0].query.stmt.stmt =
+            "insert into big_orders (amount) values (1)".into();
+        assert!(pgm.is_read_only());
+        conn.classify_reads(&mut pgm);
+        assert!(!pgm.is_read_only());
+
+        let mut pgm = raw_program(&["select amount from orders"]);
+        conn.classify_reads(&mut pgm);
+        assert!(pgm.is_read_only());
+    }
+
+    #[test]
+    fn query_stats_counters() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let query_stats = Arc::new(QueryStats::new(1.0, None));
+        conn.query_stats = Some(query_stats.clone());
+
+        conn.run(
+            Program::seq(&[
+                "select * from test where rowid = 1",
+                "select * from test where rowid = 2",
+                "select count(*) from test where x = 'hello world'",
+            ]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        let top = query_stats.top(SortKey::RowsScanned, 10);
+        assert_eq!(top.len(), 2);
+        // the full scan reads all rows to return one
+        assert_eq!(top[0].count, 1);
+        assert!(top[0].rows_scanned >= 100);
+        assert_eq!(top[0].rows_returned, 1);
+        // lookups by rowid only read the row they return
+        assert_eq!(top[1].count, 2);
+        assert!(top[1].rows_scanned < 10);
+        assert_eq!(top[1].rows_returned, 2);
+    }
+
+    #[test]
+    fn session_settings() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "set application_name = 'worker-7'",
+                    "set statement_timeout to 500",
+                    "show statement_timeout",
+                    "set search_path = 'main'",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Err(Error::InvalidSetting(SettingsError::Unknown(_)))
+            ]
+        ));
+        assert_eq!(conn.settings.application_name.as_deref(), Some("worker-7"));
+        assert_eq!(
+            conn.settings.statement_timeout,
+            Some(Duration::from_millis(500))
+        );
+
+        conn.session_config.unknown_settings = UnknownSettings::Warn;
+        let results = conn
+            .run(
+                Program::seq(&["set search_path = 'main'", "reset all"]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(results[..], [StepResult::Ok, StepResult::Ok]));
+        assert_eq!(conn.settings, SessionSettings::default());
+    }
+
+    #[test]
+    fn result_limits() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.session_config.result_limits = ResultLimits {
+            max_rows: Some(10),
+            max_bytes: None,
+        };
+
+        // the statement exceeding the limit fails, the others are not affected
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "select 1",
+                    "select * from test",
+                    "select * from test limit 10",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(Error::ResultLimitExceeded(ResultLimit::Rows(10))),
+                StepResult::Ok
+            ]
+        ));
+
+        // a program can lower the limits, but not raise them
+        let run = |conn: &mut Connection, sql: &str, max_rows, max_bytes| {
+            let pgm = Program::seq(&[sql]).with_result_limits(ResultLimits {
+                max_rows,
+                max_bytes,
+            });
+            conn.run(pgm, StepResultsBuilder::default())
+                .unwrap()
+                .into_ret()
+        };
+        assert!(matches!(
+            run(&mut conn, "select * from test", Some(1000), None)[..],
+            [StepResult::Err(Error::ResultLimitExceeded(
+                ResultLimit::Rows(10)
+            ))]
+        ));
+        assert!(matches!(
+            run(&mut conn, "select * from test limit 5", None, Some(50))[..],
+            [StepResult::Err(Error::ResultLimitExceeded(
+                ResultLimit::Bytes(50)
+            ))]
+        ));
+        assert!(matches!(
+            run(&mut conn, "select * from test limit 4", None, Some(50))[..],
+            [StepResult::Ok]
+        ));
+    }
+
+    #[test]
+    fn set_transaction() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+
+        let results = conn
+            .run(
+                Program::seq(&["begin", "set transaction read only"]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(Error::InvalidSetting(
+                    SettingsError::UnsupportedTransactionMode(_)
+                ))
+            ]
+        ));
+        // the transaction is still usable
+        assert_eq!(conn.session_state.get(), State::Txn);
+
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "set transaction isolation level serializable, read write",
+                    "insert into test values (1)",
+                    "commit",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [StepResult::Ok, StepResult::Ok, StepResult::Ok]
+        ));
+        assert_eq!(conn.session_state.get(), State::Init);
+    }
+
+    #[test]
+    fn statement_timeout_is_capped_by_query_timeout() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.session_config.query_timeout = Some(Duration::from_millis(50));
+
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "set statement_timeout = '1h'",
+                    "with recursive c(x) as (select 1 union all select x + 1 from c limit 100000000) select count(*) from c",
+                    "select count(*) from test",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(Error::StatementTimeout(timeout)),
+                StepResult::Ok
+            ] if timeout == Duration::from_millis(50)
+        ));
+    }
+
+    #[test]
+    fn require_parameterized() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let stmts = [
+            "insert into test values ('hello')",
+            "select * from test where x = 'hello'",
+            "select * from test limit 1",
+            "create table other (y default 0)",
+        ];
+
+        conn.session_config.require_parameterized = Some(RequireParameterized::Writes);
+        let results = conn
+            .run(Program::seq(&stmts), StepResultsBuilder::default())
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Err(Error::InlineLiteral(_)),
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Ok
+            ]
+        ));
+
+        conn.session_config.require_parameterized = Some(RequireParameterized::All);
+        let results = conn
+            .run(Program::seq(&stmts[1..3]), StepResultsBuilder::default())
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [StepResult::Err(Error::InlineLiteral(_)), StepResult::Ok]
+        ));
+    }
+
+    #[test]
+    fn size_quota() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let page_count: u64 = conn
+            .conn
+            .query_row("pragma page_count", (), |row| row.get(0))
+            .unwrap();
+        let stmts = [
+            "insert into test values (randomblob(100000))",
+            "insert into test values (1)",
+        ];
+
+        conn.config_store.set_max_page_count(Some(page_count + 2));
+        let results = conn
+            .run(Program::seq(&stmts), StepResultsBuilder::default())
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [StepResult::Err(Error::SizeQuotaExceeded(_)), StepResult::Ok]
+        ));
+
+        // the quota is lifted without reopening the connection
+        conn.config_store.set_max_page_count(None);
+        let results = conn
+            .run(Program::seq(&stmts[..1]), StepResultsBuilder::default())
+            .unwrap()
+            .into_ret();
+        assert!(matches!(results[..], [StepResult::Ok]));
+    }
+
+    #[test]
+    fn denied_statements() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let stmts = [
+            "delete from test",
+            "delete from test where 1 = 1",
+            "delete from test where x = 'nothing'",
+            "drop table test",
+        ];
+
+        conn.session_config.denied_statements =
+            DeniedStatements::new(&[DenyRule::TruncateLikeDelete], false);
+        let results = conn
+            .run(Program::seq(&stmts[..3]), StepResultsBuilder::default())
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Err(Error::StatementDenied(DenyRule::TruncateLikeDelete)),
+                StepResult::Ok,
+                StepResult::Ok
+            ]
+        ));
+
+        conn.session_config.denied_statements =
+            DeniedStatements::new(&[DenyRule::TruncateLikeDelete, DenyRule::DropTable], true);
+        let results = conn
+            .run(Program::seq(&stmts), StepResultsBuilder::default())
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Err(Error::StatementDenied(DenyRule::TruncateLikeDelete)),
+                StepResult::Err(Error::StatementDenied(DenyRule::TruncateLikeDelete)),
+                StepResult::Ok,
+                StepResult::Err(Error::StatementDenied(DenyRule::DropTable))
+            ]
+        ));
+    }
+
+    #[test]
+    fn failed_batch_is_rolled_back_inside_transaction() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let count = |conn: &Connection| {
+            conn.conn
+                .query_row("select count(*) from t", (), |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+
+        conn.run(
+            Program::seq(&[
+                "create table t (x unique)",
+                "begin",
+                "insert into t values (1)",
+            ]),
+            IgnoreResult,
+        )
+        .unwrap();
+
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "insert into t values (2)",
+                    "insert into t values (1)",
+                    "insert into t values (3)",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(Error::BatchRolledBack { step: 1, .. }),
+                StepResult::Skipped
+            ]
+        ));
+        // the batch is discarded, but the transaction is still open
+        assert!(!conn.conn.is_autocommit());
+        assert_eq!(count(&conn), 1);
+
+        conn.run(
+            Program::seq(&["insert into t values (2)", "commit"]),
+            IgnoreResult,
+        )
+        .unwrap();
+        assert_eq!(count(&conn), 2);
+
+        // outside of a transaction, the statements before the failure are kept
+        let results = conn
+            .run(
+                Program::seq(&["insert into t values (3)", "insert into t values (1)"]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(Error::ConstraintViolation { .. })
+            ]
+        ));
+        assert_eq!(count(&conn), 3);
+    }
+
+    const FOREIGN_KEYS_SCHEMA: &[&str] = &[
+        "pragma foreign_keys = on",
+        "create table parent (id integer primary key)",
+        "create table child (id integer primary key, \
+            parent_id integer references parent (id) deferrable initially deferred, \
+            name text not null unique)",
+        "create table pet (id integer primary key, owner_id integer references parent (id))",
+        "insert into parent values (1)",
+    ];
+
+    #[test]
+    fn constraint_violations() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        conn.run(Program::seq(FOREIGN_KEYS_SCHEMA), IgnoreResult)
+            .unwrap();
+        let count = |conn: &Connection, table: &str| {
+            conn.conn
+                .query_row(&format!("select count(*) from {table}"), (), |row| {
+                    row.get::<_, i64>(0)
+                })
+                .unwrap()
+        };
+
+        // single statements: the detail comes from the error of SQLite
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "insert into child values (1, 1, 'a')",
+                    "insert into child values (2, 1, 'a')",
+                    "insert into pet values (1, 42)",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        let [StepResult::Ok, StepResult::Err(unique), StepResult::Err(foreign_key)] = &results[..]
+        else {
+            panic!("unexpected results: {results:?}");
+        };
+        assert_eq!(
+            unique.constraint_violations(),
+            vec![ConstraintViolation {
+                kind: ConstraintKind::Unique,
+                table: Some("child".into()),
+                constraint: Some("name".into()),
+                parent: None,
+                rowid: None,
+            }]
+        );
+        assert!(matches!(
+            &foreign_key.constraint_violations()[..],
+            [ConstraintViolation {
+                kind: ConstraintKind::ForeignKey,
+                ..
+            }]
+        ));
+
+        // a batch in a transaction is rolled back, with the detail of the violation
+        conn.run(Program::seq(&["begin"]), IgnoreResult).unwrap();
+        let results = conn
+            .run(
+                Program::seq(&[
+                    "insert into child values (3, 1, 'b')",
+                    "insert into child values (4, 1, 'b')",
+                ]),
+                StepResultsBuilder::default(),
+            )
+            .unwrap()
+            .into_ret();
+        assert!(matches!(
+            &results[1],
+            StepResult::Err(e @ Error::BatchRolledBack { .. }) if e.constraint_violations().len() == 1
+        ));
+        conn.run(Program::seq(&["rollback"]), IgnoreResult).unwrap();
+
+        // deferred foreign keys are checked at commit, which rolls the transaction back
+        let (_, state) = conn
+            .execute_program(
+                Program::seq(&[
+                    "begin",
+                    "insert into child values (5, 1, 'c')",
+                    "insert into child values (6, 42, 'd')",
+                ]),
+                IgnoreResult,
+            )
+            .unwrap();
+        assert_eq!(state, State::Txn);
+        let (results, state) = conn
+            .execute_program(Program::seq(&["commit"]), StepResultsBuilder::default())
+            .unwrap();
+        let results = results.into_ret();
+        let [StepResult::Err(e @ Error::DeferredConstraintViolation(_))] = &results[..] else {
+            panic!("unexpected results: {results:?}");
+        };
+        assert_eq!(
+            e.constraint_violations(),
+            vec![ConstraintViolation {
+                kind: ConstraintKind::ForeignKey,
+                table: Some("child".into()),
+                constraint: Some("parent_id".into()),
+                parent: Some("parent".into()),
+                rowid: Some(6),
+            }]
+        );
+        assert!(e.to_string().contains("rolled back"));
+        assert_eq!(state, State::Init);
+        assert_eq!(count(&conn, "child"), 1);
+    }
+
+    #[tokio::test]
+    async fn foreign_keys_are_enforced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let make_db = |foreign_keys| {
+            LibSqlDb::new(
+                tmp.path().to_path_buf(),
+                Vec::new(),
+                &TRANSPARENT_METHODS,
+                (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                QueryBuilderConfig::default(),
+                None,
+                None,
+                None,
+                None,
+                SessionConfig {
+                    foreign_keys,
+                    ..Default::default()
+                },
+                false,
+            )
+        };
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let insert = Program::seq(&["insert into pet values (1, 42)"]);
+
+        let db = make_db(false).await.unwrap();
+        db.execute_program(Program::seq(&FOREIGN_KEYS_SCHEMA[1..]), auth, IgnoreResult)
+            .await
+            .unwrap();
+        let (results, _) = db
+            .execute_program(insert.clone(), auth, StepResultsBuilder::default())
+            .await
+            .unwrap();
+        assert!(matches!(results.into_ret()[..], [StepResult::Ok]));
+
+        let db = make_db(true).await.unwrap();
+        let insert = Program::seq(&["insert into pet values (2, 42)"]);
+        let (results, _) = db
+            .execute_program(insert, auth, StepResultsBuilder::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Err(Error::ConstraintViolation { .. })]
+        ));
+    }
+
+    #[test]
+    fn expected_replication_index() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let (index_sender, index_receiver) = watch::channel(5);
+        conn.replication_index = Some(index_receiver);
+        let count = |conn: &Connection| {
+            conn.conn
+                .query_row("select count(*) from test where x = 'guarded'", (), |row| {
+                    row.get::<_, i64>(0)
+                })
+                .unwrap()
+        };
+        let write = |expected| {
+            Program::seq(&["insert into test values ('guarded')"])
+                .with_expected_replication_index(Some(expected))
+        };
+
+        conn.run(write(5), IgnoreResult).unwrap();
+        assert_eq!(count(&conn), 1);
+        assert!(conn.conn.is_autocommit());
+
+        // another writer committed since
+        index_sender.send_replace(7);
+        assert!(matches!(
+            conn.run(write(5), IgnoreResult),
+            Err(Error::ReplicationIndexConflict {
+                expected: 5,
+                current: 7
+            })
+        ));
+        assert_eq!(count(&conn), 1);
+        assert!(conn.conn.is_autocommit());
+
+        // a batch that doesn't write is checked all the same, and leaves the index unchanged
+        let read = |expected| {
+            Program::seq(&["select count(*) from test"])
+                .with_expected_replication_index(Some(expected))
+        };
+        assert!(matches!(
+            conn.run(read(5), IgnoreResult),
+            Err(Error::ReplicationIndexConflict { .. })
+        ));
+        conn.run(read(7), IgnoreResult).unwrap();
+        assert_eq!(conn.current_replication_index(), Some(7));
+        assert!(conn.conn.is_autocommit());
+
+        assert!(matches!(
+            conn.run(
+                Program::seq(&["begin", "insert into test values ('guarded')", "commit"])
+                    .with_expected_replication_index(Some(7)),
+                IgnoreResult
+            ),
+            Err(Error::ReplicationIndexUnsupported(_))
+        ));
+        assert_eq!(count(&conn), 1);
+    }
+
     #[test]
     fn test_libsql_conn_builder_driver() {
         test_driver(1000, |b| {
@@ -582,4 +2894,1200 @@ mod test {
             conn.run(Program::seq(&["select * from test"]), b)
         })
     }
+
+    /// A builder that panics, or fails, when it receives a value.
+    enum FaultyBuilder {
+        Panic,
+        Fail,
+    }
+
+    impl QueryResultBuilder for FaultyBuilder {
+        type Ret = ();
+
+        fn init(&mut self, _config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn finish_step(
+            &mut self,
+            _affected_row_count: u64,
+            _last_insert_rowid: Option<i64>,
+        ) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn step_error(&mut self, _error: Error) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn cols_description<'a>(
+            &mut self,
+            _cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+        ) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn add_row_value(&mut self, _v: ValueRef) -> Result<(), QueryResultBuilderError> {
+            match self {
+                Self::Panic => panic!("injected panic"),
+                Self::Fail => Err(QueryResultBuilderError::ResponseTooLarge(0)),
+            }
+        }
+
+        fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+            Ok(())
+        }
+
+        fn into_ret(self) -> Self::Ret {}
+    }
+
+    #[tokio::test]
+    async fn panic_only_affects_its_own_query() {
+        let tmp = tempfile::tempdir().unwrap();
+        let make_db = || {
+            LibSqlDb::new(
+                tmp.path().to_path_buf(),
+                Vec::new(),
+                &TRANSPARENT_METHODS,
+                (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                QueryBuilderConfig::default(),
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                false,
+            )
+        };
+        let db = make_db().await.unwrap();
+        let other_db = make_db().await.unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_program(
+            Program::seq(&["create table test (x)", "insert into test values (42)"]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        let res = db
+            .execute_program(
+                Program::seq(&["begin", "select * from test"]),
+                auth,
+                FaultyBuilder::Panic,
+            )
+            .await;
+        assert!(matches!(res, Err(Error::QueryPanicked)));
+
+        // the transaction of the panicking query was rolled back, and both connections keep
+        // serving queries.
+        for db in [&db, &other_db] {
+            let (_, state) = db
+                .execute_program(Program::seq(&["select * from test"]), auth, IgnoreResult)
+                .await
+                .unwrap();
+            assert_eq!(state, State::Init);
+        }
+    }
+
+    #[test]
+    fn steps_committed_before_a_failure_are_captured() {
+        let mut ctx = ();
+        let mut conn = setup_test_conn(&mut ctx);
+        let tmp = tempfile::tempdir().unwrap();
+        let change_log = Arc::new(ChangeLog::open(tmp.path()).unwrap());
+        conn.change_capture = Some(ChangeCapture::install(change_log.clone(), &conn.conn));
+
+        let res = conn.run(
+            Program::seq(&["insert into test values ('captured')", "select * from test"]),
+            FaultyBuilder::Fail,
+        );
+        assert!(matches!(res, Err(Error::BuilderError(_))));
+
+        let batches = change_log
+            .batches_after(change_log.log_id(), LogicalOffset::default())
+            .unwrap();
+        assert!(batches.iter().any(|batch| !batch.changes.is_empty()));
+    }
+
+    async fn db_with_txn_timeout(path: &Path, txn_timeout: Option<Duration>) -> LibSqlDb {
+        LibSqlDb::new(
+            path.to_path_buf(),
+            Vec::new(),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+            None,
+            None,
+            SessionConfig {
+                txn_timeout,
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn count_rows(path: &Path) -> i64 {
+        rusqlite::Connection::open(path.join("data"))
+            .unwrap()
+            .query_row("select count(*) from test", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn idle_transaction_times_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = db_with_txn_timeout(tmp.path(), Some(Duration::from_millis(200))).await;
+        let other_db = db_with_txn_timeout(tmp.path(), None).await;
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_program(Program::seq(&["create table test (x)"]), auth, IgnoreResult)
+            .await
+            .unwrap();
+        db.execute_program(Program::seq(&["begin"]), auth, IgnoreResult)
+            .await
+            .unwrap();
+        // the transaction is kept open as long as programs come in time
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            db.execute_program(
+                Program::seq(&["insert into test values (42)"]),
+                auth,
+                IgnoreResult,
+            )
+            .await
+            .unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let res = db
+            .execute_program(Program::seq(&["commit"]), auth, IgnoreResult)
+            .await;
+        match res {
+            Err(e @ Error::LibSqlTxTimeout(idle)) => {
+                assert!(idle >= Duration::from_millis(200));
+                assert!(e.to_string().contains("rolled back after being idle for"));
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(_, state)| state)),
+        }
+
+        // the transaction was rolled back, and released the write lock
+        let (results, _) = other_db
+            .execute_program(
+                Program::seq(&["insert into test values (1)"]),
+                auth,
+                StepResultsBuilder::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(results.into_ret()[..], [StepResult::Ok]));
+        assert_eq!(count_rows(tmp.path()), 1);
+    }
+
+    #[tokio::test]
+    async fn transaction_without_timeout_stays_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = db_with_txn_timeout(tmp.path(), None).await;
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_program(
+            Program::seq(&[
+                "create table test (x)",
+                "begin",
+                "insert into test values (42)",
+            ]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+        // longer than the default timeout
+        tokio::time::sleep(DEFAULT_TXN_TIMEOUT + Duration::from_secs(1)).await;
+        let (results, state) = db
+            .execute_program(
+                Program::seq(&["commit"]),
+                auth,
+                StepResultsBuilder::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(state, State::Init);
+        assert!(matches!(results.into_ret()[..], [StepResult::Ok]));
+        assert_eq!(count_rows(tmp.path()), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn interrupted_by_admin() {
+        use crate::metrics::Frontend;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let connection = connections::register(Frontend::Http, None);
+        let db = connections::scope(
+            Some(connection.clone()),
+            db_with_txn_timeout(tmp.path(), None),
+        )
+        .await;
+        let db = Arc::new(db);
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_program(
+            Program::seq(&[
+                "create table test (x)",
+                "begin",
+                "insert into test values (1)",
+            ]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        // the running statement fails, and the rest of the program is not executed
+        let running = tokio::spawn({
+            let db = db.clone();
+            async move {
+                db.execute_program(
+                    Program::seq(&[
+                        "with recursive c(x) as (select 1 union all select x + 1 from c) select count(*) from c",
+                        "insert into test values (2)",
+                    ]),
+                    auth,
+                    StepResultsBuilder::default(),
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(connections::interrupt(connection.id(), false));
+        let (results, _) = running.await.unwrap().unwrap();
+        let results = results.into_ret();
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Err(Error::InterruptedByAdmin),
+                StepResult::Err(Error::InterruptedByAdmin)
+            ]
+        ));
+
+        // the transaction was rolled back, and the connection can be used again
+        let (results, state) = db
+            .execute_program(
+                Program::seq(&["insert into test values (3)"]),
+                auth,
+                StepResultsBuilder::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(results.into_ret()[..], [StepResult::Ok]));
+        assert_eq!(state, State::Init);
+        assert_eq!(count_rows(tmp.path()), 1);
+    }
+
+    #[tokio::test]
+    async fn allowed_statement_classes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            Vec::new(),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+            None,
+            None,
+            SessionConfig {
+                allowed_statement_classes: AllowedStatementClasses::new(
+                    &[StmtClass::Read, StmtClass::Write, StmtClass::Pragma],
+                    &[StmtClass::Read],
+                )
+                .with_principals(&["reporting=read+ddl".parse().unwrap()]),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+        let full_access = Authenticated::Authorized(Authorized::FullAccess);
+        let read_only = Authenticated::Authorized(Authorized::ReadOnly);
+
+        // nothing is executed when a statement of the program is not allowed
+        let res = db
+            .execute_program(
+                Program::seq(&["select 1", "create table test (x)"]),
+                full_access,
+                IgnoreResult,
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::StatementClassNotAllowed(StmtClass::Ddl))
+        ));
+
+        db.execute_program(
+            Program::seq(&["begin", "pragma table_list", "select 1", "commit"]),
+            full_access,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+        let res = db
+            .execute_program(
+                Program::seq(&["pragma table_list"]),
+                read_only,
+                IgnoreResult,
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::StatementClassNotAllowed(StmtClass::Pragma))
+        ));
+        db.execute_program(Program::seq(&["select 1"]), read_only, IgnoreResult)
+            .await
+            .unwrap();
+
+        // the statements that can't be parsed are classified by SQLite, or denied when in doubt
+        let res = db
+            .execute_program(
+                raw_program(&["create table test (x) strict"]),
+                full_access,
+                IgnoreResult,
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::StatementClassNotAllowed(StmtClass::Ddl))
+        ));
+        let res = db
+            .execute_program(
+                raw_program(&["attach 'other.db' as \"other"]),
+                full_access,
+                IgnoreResult,
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::StatementClassNotAllowed(StmtClass::Attach))
+        ));
+
+        // a principal is restricted to its classes, among those allowed to every caller
+        let reporting = Identity {
+            principal: Some("reporting".into()),
+            method: AuthMethod::Jwt,
+            namespace: None,
+        };
+        let res = with_identity(
+            reporting.clone(),
+            db.execute_program(
+                Program::seq(&["pragma table_list"]),
+                full_access,
+                IgnoreResult,
+            ),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(Error::StatementClassNotAllowed(StmtClass::Pragma))
+        ));
+        let res = with_identity(
+            reporting.clone(),
+            db.execute_program(
+                Program::seq(&["create table test (x)"]),
+                full_access,
+                IgnoreResult,
+            ),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(Error::StatementClassNotAllowed(StmtClass::Ddl))
+        ));
+        with_identity(
+            reporting,
+            db.execute_program(Program::seq(&["select 1"]), full_access, IgnoreResult),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn group_commit_isolates_failing_writes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = LibSqlDbFactory::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            || (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Vec::new(),
+            u64::MAX,
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let db = factory.create().await.unwrap();
+        db.execute_program(
+            Program::seq(&["create table test (x not null)"]),
+            auth,
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+
+        let mut dbs = Vec::new();
+        for _ in 0..3 {
+            dbs.push(factory.create().await.unwrap());
+        }
+        let writes = dbs.iter().zip([
+            "insert into test values (1)",
+            "insert into test values (null)",
+            "insert into test values (2)",
+        ]);
+        let results = futures::future::join_all(writes.map(|(db, sql)| {
+            db.execute_program(Program::seq(&[sql]), auth, StepResultsBuilder::default())
+        }))
+        .await
+        .into_iter()
+        .map(|res| {
+            let (builder, state) = res.unwrap();
+            assert_eq!(state, State::Init);
+            builder.into_ret()
+        })
+        .collect_vec();
+        assert!(matches!(results[0][..], [StepResult::Ok]));
+        assert!(matches!(
+            results[1][..],
+            [StepResult::Err(Error::RusqliteError(_))]
+        ));
+        assert!(matches!(results[2][..], [StepResult::Ok]));
+
+        // writes in an interactive transaction are not grouped
+        let (_, state) = db
+            .execute_program(
+                Program::seq(&["begin", "insert into test values (3)"]),
+                auth,
+                IgnoreResult,
+            )
+            .await
+            .unwrap();
+        assert_eq!(state, State::Txn);
+        let (_, state) = db
+            .execute_program(
+                Program::seq(&["insert into test values (4)"]),
+                auth,
+                IgnoreResult,
+            )
+            .await
+            .unwrap();
+        assert_eq!(state, State::Txn);
+        db.execute_program(Program::seq(&["rollback"]), auth, IgnoreResult)
+            .await
+            .unwrap();
+
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        let values: Vec<i64> = conn
+            .prepare("select x from test order by x")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn group_commit_keeps_session_state_apart() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = LibSqlDbFactory::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            || (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Vec::new(),
+            u64::MAX,
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let a = factory.create().await.unwrap();
+        let b = factory.create().await.unwrap();
+        async fn execute(db: &LibSqlDb, auth: Authenticated, sql: &str) {
+            let (builder, _) = db
+                .execute_program(Program::seq(&[sql]), auth, StepResultsBuilder::default())
+                .await
+                .unwrap();
+            assert!(matches!(builder.into_ret()[..], [StepResult::Ok]), "{sql}");
+        }
+
+        execute(&a, auth, "create table test (x)").await;
+        // the temporary table of `a` shadows the table of the database, on its connection only
+        execute(&a, auth, "create temp table test (x)").await;
+        tokio::join!(
+            execute(&a, auth, "insert into test values ('a')"),
+            execute(&b, auth, "insert into test values ('b')"),
+        );
+
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        let values: Vec<String> = conn
+            .prepare("select x from test")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(values, ["b"]);
+    }
+
+    #[tokio::test]
+    async fn group_commit_checks_writes_against_their_session_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let session_config = Shared::new(SessionConfig::default());
+        let factory = LibSqlDbFactory::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            || (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Vec::new(),
+            u64::MAX,
+            None,
+            None,
+            None,
+            None,
+            session_config.clone(),
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let execute = |db: LibSqlDb, sql: &'static str| async move {
+            db.execute_program(Program::seq(&[sql]), auth, StepResultsBuilder::default())
+                .await
+                .unwrap()
+                .0
+                .into_ret()
+        };
+        let db = factory.create().await.unwrap();
+        assert!(matches!(
+            execute(db, "create table test (x)").await[..],
+            [StepResult::Ok]
+        ));
+
+        // the connection of the groups was opened before the configuration was reloaded
+        session_config.set(SessionConfig {
+            require_parameterized: Some(RequireParameterized::Writes),
+            ..SessionConfig::default()
+        });
+        let db = factory.create().await.unwrap();
+        assert!(matches!(
+            execute(db, "insert into test values (42)").await[..],
+            [StepResult::Err(Error::InlineLiteral(_))]
+        ));
+    }
+
+    #[tokio::test]
+    async fn savepoints_in_batches() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            Vec::new(),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let batch = |stmts: &[&str]| {
+            stmts
+                .iter()
+                .map(|sql| Query {
+                    stmt: Statement::parse(sql).next().unwrap().unwrap(),
+                    params: Params::empty(),
+                    want_rows: false,
+                })
+                .collect_vec()
+        };
+        let execute =
+            |stmts: &[&str]| db.execute_batch(batch(stmts), auth, StepResultsBuilder::default());
+        execute(&["create table test (x)"]).await.unwrap();
+
+        // a savepoint outside of a transaction begins one, that stays open until it is released
+        let (_, state) = execute(&[
+            "savepoint a",
+            "insert into test values (1)",
+            "savepoint b",
+            "insert into test values (2)",
+            "rollback to b",
+            "release b",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(state, State::Txn);
+        let (_, state) = execute(&["insert into test values (3)", "release a"])
+            .await
+            .unwrap();
+        assert_eq!(state, State::Init);
+
+        // savepoints nested in a transaction leave it open
+        let (_, state) = execute(&["begin", "savepoint c", "insert into test values (4)"])
+            .await
+            .unwrap();
+        assert_eq!(state, State::Txn);
+        let (_, state) = execute(&["release c", "savepoint d", "rollback to d"])
+            .await
+            .unwrap();
+        assert_eq!(state, State::Txn);
+        let (_, state) = execute(&["commit"]).await.unwrap();
+        assert_eq!(state, State::Init);
+
+        // the release of an unknown savepoint fails, without leaving the connection invalid
+        let (results, state) = execute(&["release missing"]).await.unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Err(Error::RusqliteError(_))]
+        ));
+        assert_eq!(state, State::Init);
+
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        let values: Vec<i64> = conn
+            .prepare("select x from test order by x")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(values, vec![1, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn read_only_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let make_db = |read_only| {
+            LibSqlDb::new(
+                tmp.path().to_path_buf(),
+                Vec::new(),
+                &TRANSPARENT_METHODS,
+                (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                QueryBuilderConfig::default(),
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                read_only,
+            )
+        };
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let execute = |db: &LibSqlDb, stmts: &[&str]| {
+            db.execute_program(Program::seq(stmts), auth, StepResultsBuilder::default())
+        };
+        let is_read_only_error =
+            |results: &[StepResult]| matches!(results, [StepResult::Err(Error::ReadOnlyReplica)]);
+
+        let db = make_db(false).await.unwrap();
+        execute(
+            &db,
+            &["create table test (x)", "insert into test values (1)"],
+        )
+        .await
+        .unwrap();
+
+        let read_only_db = make_db(true).await.unwrap();
+        let (results, state) = execute(&read_only_db, &["select * from test"])
+            .await
+            .unwrap();
+        assert!(matches!(results.into_ret()[..], [StepResult::Ok]));
+        assert_eq!(state, State::Init);
+        for write in ["insert into test values (2)", "create table other (x)"] {
+            let (results, state) = execute(&read_only_db, &[write]).await.unwrap();
+            assert!(is_read_only_error(&results.into_ret()), "{write}");
+            assert_eq!(state, State::Init);
+        }
+
+        // a rejected write fails the transaction, which stays open until it is rolled back
+        let (_, state) = execute(&read_only_db, &["begin", "select * from test"])
+            .await
+            .unwrap();
+        assert_eq!(state, State::Txn);
+        let (results, state) = execute(&read_only_db, &["insert into test values (2)"])
+            .await
+            .unwrap();
+        assert!(is_read_only_error(&results.into_ret()));
+        assert_eq!(state, State::Invalid);
+        let (_, state) = execute(&read_only_db, &["rollback"]).await.unwrap();
+        assert_eq!(state, State::Init);
+
+        // the other connections can still write
+        execute(&db, &["insert into test values (3)"])
+            .await
+            .unwrap();
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        let count: i64 = conn
+            .query_row("select count(*) from test", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn attach_databases_of_the_allowlist() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("attached")).unwrap();
+        for path in [
+            tmp.path().join("attached/reports.db"),
+            outside.path().join("secret.db"),
+        ] {
+            rusqlite::Connection::open(path)
+                .unwrap()
+                .execute_batch("create table report (x); insert into report values (1);")
+                .unwrap();
+        }
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.db"),
+            tmp.path().join("attached/escape.db"),
+        )
+        .unwrap();
+        crate::database::attach::set_attachable_databases(
+            tmp.path(),
+            &[
+                "reports=attached/reports.db".parse().unwrap(),
+                "escape=attached/escape.db".parse().unwrap(),
+                "missing=attached/missing.db".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            Vec::new(),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let execute = |stmts: &[&str]| {
+            db.execute_program(Program::seq(stmts), auth, StepResultsBuilder::default())
+        };
+
+        for sql in [
+            "ATTACH '../../etc/passwd' AS x",
+            "ATTACH '/etc/passwd' AS x",
+            "ATTACH 'attached/reports.db' AS x",
+            "ATTACH 'unknown' AS x",
+            "ATTACH 'escape' AS x",
+            "ATTACH 'missing' AS x",
+        ] {
+            let (results, _) = execute(&[sql]).await.unwrap();
+            assert!(
+                matches!(
+                    results.into_ret()[..],
+                    [StepResult::Err(Error::AttachNotAllowed(_))]
+                ),
+                "{sql}"
+            );
+        }
+
+        let (results, _) = execute(&[
+            "ATTACH 'reports' AS reports",
+            "select * from reports.report",
+            "create table test (x)",
+            "insert into test select x from reports.report",
+        ])
+        .await
+        .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Ok
+            ]
+        ));
+
+        // the attached databases are read-only
+        let (results, _) = execute(&["insert into reports.report values (2)"])
+            .await
+            .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Err(Error::AttachedDatabaseReadOnly)]
+        ));
+
+        let (results, _) = execute(&["DETACH reports", "select * from reports.report"])
+            .await
+            .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Ok, StepResult::Err(Error::RusqliteError(_))]
+        ));
+        let count: i64 = rusqlite::Connection::open(tmp.path().join("attached/reports.db"))
+            .unwrap()
+            .query_row("select count(*) from report", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_statements_of_a_batch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            Vec::new(),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        let execute = |stmts: &[&str], stop_on_error| {
+            let batch = stmts
+                .iter()
+                .map(|sql| Query {
+                    stmt: Statement::parse(sql).next().unwrap().unwrap(),
+                    params: Params::empty(),
+                    want_rows: false,
+                })
+                .collect();
+            db.execute_batch_or_rollback(
+                batch,
+                None,
+                crate::database::settings::ResultLimits::default(),
+                stop_on_error,
+                Authenticated::Authorized(Authorized::FullAccess),
+                StepResultsBuilder::default(),
+            )
+        };
+        let rows = || {
+            rusqlite::Connection::open(tmp.path().join("data"))
+                .unwrap()
+                .prepare("select x from t order by x")
+                .unwrap()
+                .query_map((), |row| row.get(0))
+                .unwrap()
+                .collect::<Result<Vec<i64>, _>>()
+                .unwrap()
+        };
+
+        // the statements after a failed one are executed
+        let (results, state) = execute(
+            &[
+                "create table t (x unique)",
+                "insert into t values (1)",
+                "insert into t values (1)",
+                "insert into t values (2)",
+            ],
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Err(_),
+                StepResult::Ok
+            ]
+        ));
+        assert_eq!(state, State::Init);
+        assert_eq!(rows(), [1, 2]);
+
+        // unless asked otherwise
+        let (results, _) = execute(
+            &[
+                "insert into t values (3)",
+                "insert into t values (1)",
+                "insert into t values (4)",
+            ],
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Ok, StepResult::Err(_), StepResult::Skipped]
+        ));
+        assert_eq!(rows(), [1, 2, 3]);
+
+        // a batch that begins a transaction is executed all or nothing
+        let (results, state) = execute(
+            &[
+                "begin",
+                "insert into t values (5)",
+                "insert into t values (1)",
+                "insert into t values (6)",
+                "commit",
+            ],
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [
+                StepResult::Ok,
+                StepResult::Ok,
+                StepResult::Err(_),
+                StepResult::Skipped,
+                StepResult::Skipped
+            ]
+        ));
+        assert_eq!(state, State::Init);
+        assert_eq!(rows(), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn transactional_batches() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            Vec::new(),
+            &TRANSPARENT_METHODS,
+            (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            QueryBuilderConfig::default(),
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        let execute = |stmts: &[&str]| {
+            let batch = stmts
+                .iter()
+                .map(|sql| Query {
+                    stmt: Statement::parse(sql).next().unwrap().unwrap(),
+                    params: Params::empty(),
+                    want_rows: false,
+                })
+                .collect();
+            db.execute_transaction(
+                batch,
+                None,
+                crate::database::settings::ResultLimits::default(),
+                Authenticated::Authorized(Authorized::FullAccess),
+                StepResultsBuilder::default(),
+            )
+        };
+        let rows = || {
+            rusqlite::Connection::open(tmp.path().join("data"))
+                .unwrap()
+                .prepare("select x from t order by x")
+                .unwrap()
+                .query_map((), |row| row.get(0))
+                .unwrap()
+                .collect::<Result<Vec<i64>, _>>()
+                .unwrap()
+        };
+
+        let (results, state) = execute(&[
+            "create table t (x unique)",
+            "insert into t values (1)",
+            "insert into t values (2)",
+        ])
+        .await
+        .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Ok, StepResult::Ok, StepResult::Ok]
+        ));
+        assert_eq!(state, State::Init);
+        assert_eq!(rows(), [1, 2]);
+
+        // a failed statement leaves no rows behind
+        let (results, state) = execute(&[
+            "insert into t values (3)",
+            "insert into t values (1)",
+            "insert into t values (4)",
+        ])
+        .await
+        .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Ok, StepResult::Err(_), StepResult::Skipped]
+        ));
+        assert_eq!(state, State::Init);
+        assert_eq!(rows(), [1, 2]);
+
+        // the failure of the last statement rolls back the others as well
+        let (results, _) = execute(&["insert into t values (5)", "insert into t values (2)"])
+            .await
+            .unwrap();
+        assert!(matches!(
+            results.into_ret()[..],
+            [StepResult::Ok, StepResult::Err(_)]
+        ));
+        assert_eq!(rows(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn pooled_connections_are_reset() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = LibSqlDbFactory::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            || (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Vec::new(),
+            u64::MAX,
+            None,
+            None,
+            None,
+            None,
+            SessionConfig {
+                foreign_keys: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .unwrap()
+        .with_pool(PoolConfig {
+            max_idle: 1,
+            min_idle: 0,
+            idle_timeout: Duration::from_secs(60),
+        })
+        .await
+        .unwrap();
+        let pool = factory.pool.clone().unwrap();
+        async fn run(db: &LibSqlDb, stmts: &[&str]) -> (Vec<StepResult>, State) {
+            let auth = Authenticated::Authorized(Authorized::FullAccess);
+            let (results, state) = db
+                .execute_program(Program::seq(stmts), auth, StepResultsBuilder::default())
+                .await
+                .unwrap();
+            (results.into_ret(), state)
+        }
+        async fn wait_idle(pool: &Pool<DbWorker>) {
+            let start = Instant::now();
+            while pool.idle() == 0 {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let db = factory.create().await.unwrap();
+        run(&db, FOREIGN_KEYS_SCHEMA).await;
+        let (results, state) = run(
+            &db,
+            &[
+                "create temp table scratch (x)",
+                "pragma foreign_keys = off",
+                "begin",
+                "insert into parent values (2)",
+            ],
+        )
+        .await;
+        assert!(results.iter().all(|res| matches!(res, StepResult::Ok)));
+        assert_eq!(state, State::Txn);
+        drop(db);
+        wait_idle(&pool).await;
+
+        // the connection is reused, without the state left by its previous client
+        let db = factory.create().await.unwrap();
+        assert_eq!(pool.idle(), 0);
+        let (results, state) = run(
+            &db,
+            &[
+                "create temp table scratch (x)",
+                "insert into pet values (1, 42)",
+            ],
+        )
+        .await;
+        assert!(matches!(
+            results[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(Error::ConstraintViolation { .. })
+            ]
+        ));
+        assert_eq!(state, State::Init);
+        drop(db);
+        wait_idle(&pool).await;
+
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        let parents: i64 = conn
+            .query_row("select count(*) from parent", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(parents, 1);
+    }
 }
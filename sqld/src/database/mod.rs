@@ -3,30 +3,63 @@ use std::time::Duration;
 
 use crate::auth::Authenticated;
 use crate::query::{Params, Query};
-use crate::query_analysis::{State, Statement};
+use crate::query_analysis::{State, Statement, StmtKind};
 use crate::query_result_builder::{IgnoreResult, QueryResultBuilder};
+use crate::replication::FrameNo;
 use crate::Result;
 
+use self::settings::ResultLimits;
+
+pub mod analyze;
+pub mod attach;
+pub mod cache_budget;
+pub mod checkpoint;
 pub mod config;
+pub mod constraint;
 pub mod dump;
 pub mod factory;
+pub mod group_commit;
+pub mod instrumented;
 pub mod libsql;
+pub mod pool;
+pub mod query_stats;
+pub mod session_state;
+pub mod settings;
+pub mod vacuum;
 pub mod write_proxy;
 
-const TXN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time after which a transaction waiting for a program is rolled back, by default.
+pub const DEFAULT_TXN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub struct Program {
     pub steps: Arc<Vec<Step>>,
+    /// If set, the program is rejected with [`crate::error::Error::ReplicationIndexConflict`]
+    /// when the database was written to since this replication index.
+    pub expected_replication_index: Option<FrameNo>,
+    /// Limits on the results of the statements, on top of those of the server.
+    pub result_limits: ResultLimits,
 }
 
 impl Program {
     pub fn new(steps: Vec<Step>) -> Self {
         Self {
             steps: Arc::new(steps),
+            expected_replication_index: None,
+            result_limits: ResultLimits::default(),
         }
     }
 
+    pub fn with_expected_replication_index(mut self, index: Option<FrameNo>) -> Self {
+        self.expected_replication_index = index;
+        self
+    }
+
+    pub fn with_result_limits(mut self, limits: ResultLimits) -> Self {
+        self.result_limits = limits;
+        self
+    }
+
     pub fn is_read_only(&self) -> bool {
         self.steps.iter().all(|s| s.query.stmt.is_read_only())
     }
@@ -101,35 +134,31 @@ pub trait Database: Send + Sync + 'static {
         reponse_builder: B,
     ) -> Result<(B, State)>;
 
-    /// Execute all the queries in the batch sequentially.
-    /// If an query in the batch fails, the remaining queries are ignores, and the batch current
-    /// transaction (if any) is rolledback.
+    /// Execute all the queries in the batch sequentially, each with its own result.
+    /// If a query of a transactional batch (see [`is_transactional_batch`]) fails, or of any batch
+    /// with `stop_on_error`, the remaining queries are ignored, and the batch current transaction
+    /// (if any) is rolledback. Otherwise, the remaining queries are executed.
     async fn execute_batch_or_rollback<B: QueryResultBuilder>(
         &self,
         batch: Vec<Query>,
+        expected_replication_index: Option<FrameNo>,
+        result_limits: ResultLimits,
+        stop_on_error: bool,
         auth: Authenticated,
         result_builder: B,
     ) -> Result<(B, State)> {
         let batch_len = batch.len();
-        let mut steps = make_batch_program(batch);
+        let stop_on_error = stop_on_error || is_transactional_batch(&batch);
+        let mut steps = make_batch_program(batch, stop_on_error);
 
-        if !steps.is_empty() {
+        if stop_on_error && !steps.is_empty() {
             // We add a conditional rollback step if the last step was not sucessful.
-            steps.push(Step {
-                query: Query {
-                    stmt: Statement::parse("ROLLBACK").next().unwrap().unwrap(),
-                    params: Params::empty(),
-                    want_rows: false,
-                },
-                cond: Some(Cond::Not {
-                    cond: Box::new(Cond::Ok {
-                        step: steps.len() - 1,
-                    }),
-                }),
-            })
+            steps.push(rollback_on_failure(steps.len() - 1));
         }
 
-        let pgm = Program::new(steps);
+        let pgm = Program::new(steps)
+            .with_expected_replication_index(expected_replication_index)
+            .with_result_limits(result_limits);
 
         // ignore the rollback result
         let builder = result_builder.take(batch_len);
@@ -138,6 +167,59 @@ pub trait Database: Send + Sync + 'static {
         Ok((builder.into_inner(), state))
     }
 
+    /// Executes the batch atomically, in a `BEGIN IMMEDIATE` transaction of its own, which is
+    /// rolled back as soon as a query fails: the remaining queries are ignored. The batch must not
+    /// control the transaction itself, see [`controls_transaction`]. The results are those of the
+    /// queries, and the batch fails if the transaction can't be committed.
+    async fn execute_transaction<B: QueryResultBuilder>(
+        &self,
+        batch: Vec<Query>,
+        expected_replication_index: Option<FrameNo>,
+        result_limits: ResultLimits,
+        auth: Authenticated,
+        result_builder: B,
+    ) -> Result<(B, State)> {
+        // the precondition is checked in a transaction of its own, which holds the write lock
+        // until the program is executed
+        if expected_replication_index.is_some() {
+            return self
+                .execute_batch_or_rollback(
+                    batch,
+                    expected_replication_index,
+                    result_limits,
+                    true,
+                    auth,
+                    result_builder,
+                )
+                .await;
+        }
+
+        let batch_len = batch.len();
+        let mut steps = Vec::with_capacity(batch_len + 3);
+        steps.push(Step {
+            query: internal_query("BEGIN IMMEDIATE"),
+            cond: None,
+        });
+        for query in batch.into_iter().chain([internal_query("COMMIT")]) {
+            // only execute if the previous step was a success
+            let cond = Some(Cond::Ok {
+                step: steps.len() - 1,
+            });
+            steps.push(Step { cond, query });
+        }
+        steps.push(rollback_on_failure(steps.len() - 1));
+
+        let pgm = Program::new(steps).with_result_limits(result_limits);
+        // the results of `BEGIN` and `COMMIT` are not those of the batch
+        let builder = result_builder.take(batch_len).skip(1);
+        let (builder, state) = self.execute_program(pgm, auth, builder).await?;
+        let (builder, commit_error) = builder.into_inner_and_error_after();
+        match commit_error {
+            Some(e) => Err(e),
+            None => Ok((builder, state)),
+        }
+    }
+
     /// Execute all the queries in the batch sequentially.
     /// If an query in the batch fails, the remaining queries are ignored
     async fn execute_batch<B: QueryResultBuilder>(
@@ -146,22 +228,14 @@ pub trait Database: Send + Sync + 'static {
         auth: Authenticated,
         result_builder: B,
     ) -> Result<(B, State)> {
-        let steps = make_batch_program(batch);
+        let steps = make_batch_program(batch, true);
         let pgm = Program::new(steps);
         self.execute_program(pgm, auth, result_builder).await
     }
 
     async fn rollback(&self, auth: Authenticated) -> Result<()> {
-        self.execute_batch(
-            vec![Query {
-                stmt: Statement::parse("ROLLBACK").next().unwrap().unwrap(),
-                params: Params::empty(),
-                want_rows: false,
-            }],
-            auth,
-            IgnoreResult,
-        )
-        .await?;
+        self.execute_batch(vec![internal_query("ROLLBACK")], auth, IgnoreResult)
+            .await?;
 
         Ok(())
     }
@@ -170,10 +244,44 @@ pub trait Database: Send + Sync + 'static {
     async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult>;
 }
 
-fn make_batch_program(batch: Vec<Query>) -> Vec<Step> {
+fn internal_query(sql: &str) -> Query {
+    Query {
+        stmt: Statement::parse(sql).next().unwrap().unwrap(),
+        params: Params::empty(),
+        want_rows: false,
+    }
+}
+
+/// A `ROLLBACK` executed if the step `step` was not successful.
+fn rollback_on_failure(step: usize) -> Step {
+    Step {
+        query: internal_query("ROLLBACK"),
+        cond: Some(Cond::Not {
+            cond: Box::new(Cond::Ok { step }),
+        }),
+    }
+}
+
+/// Whether the statement begins or ends a transaction, or a savepoint.
+pub fn controls_transaction(query: &Query) -> bool {
+    !matches!(
+        query.stmt.kind,
+        StmtKind::Read | StmtKind::Write | StmtKind::Other
+    )
+}
+
+/// Whether the batch begins a transaction. It is then executed all or nothing: the statements
+/// after a failed one are ignored, and the transaction is rolled back.
+pub fn is_transactional_batch(batch: &[Query]) -> bool {
+    batch
+        .iter()
+        .any(|q| matches!(q.stmt.kind, StmtKind::TxnBegin | StmtKind::Savepoint))
+}
+
+fn make_batch_program(batch: Vec<Query>, stop_on_error: bool) -> Vec<Step> {
     let mut steps = Vec::with_capacity(batch.len());
     for (i, query) in batch.into_iter().enumerate() {
-        let cond = if i > 0 {
+        let cond = if i > 0 && stop_on_error {
             // only execute if the previous step was a success
             Some(Cond::Ok { step: i - 1 })
         } else {
@@ -0,0 +1,175 @@
+//! Pool of the idle connections of a database factory.
+//!
+//! Opening a connection spawns its thread and opens SQLite, which short requests pay on every
+//! connection. With a pool, a connection that is dropped is reset and kept open, and the next
+//! connection is checked out of the pool instead of opened. The connection checked out by a
+//! client is pinned to it until it is dropped, so that an interactive transaction keeps its
+//! connection; the transaction left open by a client is rolled back when its connection is reset.
+
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Idle connections kept open at most, the others are closed when they are returned. The pool
+    /// is disabled if 0.
+    pub max_idle: usize,
+    /// Idle connections kept open, even once they expired.
+    pub min_idle: usize,
+    /// Time after which an idle connection is closed.
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 0,
+            min_idle: 0,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_idle > 0
+    }
+}
+
+struct Idle<T> {
+    item: T,
+    since: Instant,
+}
+
+pub struct Pool<T> {
+    config: PoolConfig,
+    /// The idle items, the most recently returned last.
+    idle: Mutex<Vec<Idle<T>>>,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Creates a pool, whose expired items are closed by a task until the pool is dropped.
+    pub fn spawn(config: PoolConfig) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            config,
+            idle: Mutex::new(Vec::with_capacity(config.max_idle)),
+        });
+        tokio::spawn(run_eviction(Arc::downgrade(&pool)));
+        pool
+    }
+
+    /// Takes the most recently returned item, whose caches are the warmest.
+    pub fn take(&self) -> Option<T> {
+        self.idle.lock().pop().map(|idle| idle.item)
+    }
+
+    /// Returns `item` to the pool, or gives it back if the pool is full.
+    pub fn put(&self, item: T) -> Result<(), T> {
+        let mut idle = self.idle.lock();
+        if idle.len() >= self.config.max_idle {
+            return Err(item);
+        }
+        idle.push(Idle {
+            item,
+            since: Instant::now(),
+        });
+        Ok(())
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    /// Removes the items that have been idle for longer than the timeout, but the `min_idle`
+    /// most recently returned.
+    fn evict(&self, now: Instant) -> Vec<T> {
+        let mut idle = self.idle.lock();
+        let expired = idle
+            .iter()
+            .take_while(|idle| {
+                now.saturating_duration_since(idle.since) >= self.config.idle_timeout
+            })
+            .count()
+            .min(idle.len().saturating_sub(self.config.min_idle));
+        idle.drain(..expired).map(|idle| idle.item).collect()
+    }
+}
+
+async fn run_eviction<T: Send + 'static>(pool: Weak<Pool<T>>) {
+    let period = match pool.upgrade() {
+        Some(pool) => (pool.config.idle_timeout / 2).max(Duration::from_millis(10)),
+        None => return,
+    };
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(pool) = pool.upgrade() else { return };
+        // the items are closed out of the lock of the pool
+        let expired = pool.evict(Instant::now());
+        if !expired.is_empty() {
+            tracing::debug!("closing {} idle connections", expired.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(max_idle: usize, min_idle: usize) -> PoolConfig {
+        PoolConfig {
+            max_idle,
+            min_idle,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn take_the_warmest_item() {
+        let pool = Pool::spawn(config(2, 0));
+        assert_eq!(pool.take(), None);
+
+        pool.put(1).unwrap();
+        pool.put(2).unwrap();
+        // the pool is full
+        assert_eq!(pool.put(3), Err(3));
+        assert_eq!(pool.idle(), 2);
+
+        assert_eq!(pool.take(), Some(2));
+        assert_eq!(pool.take(), Some(1));
+        assert_eq!(pool.take(), None);
+    }
+
+    #[tokio::test]
+    async fn evict_expired_items() {
+        let pool = Pool::spawn(config(4, 1));
+        for i in 0..3 {
+            pool.put(i).unwrap();
+        }
+        let now = Instant::now();
+        assert!(pool.evict(now).is_empty());
+
+        // the most recently returned item is kept
+        let later = now + Duration::from_secs(61);
+        assert_eq!(pool.evict(later), vec![0, 1]);
+        assert_eq!(pool.take(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn eviction_task() {
+        let pool = Pool::spawn(PoolConfig {
+            max_idle: 2,
+            min_idle: 0,
+            idle_timeout: Duration::from_millis(20),
+        });
+        pool.put(1).unwrap();
+        let start = Instant::now();
+        while pool.idle() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
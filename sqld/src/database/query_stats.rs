@@ -0,0 +1,412 @@
+//! Per-statement execution statistics, to find out which queries would benefit from an index.
+//!
+//! Statements are aggregated by fingerprint: the statement as re-rendered from its AST, with its
+//! literals replaced by `?`. Only a sample of the executions is recorded. On the primary, the
+//! statistics are periodically flushed to the `_sqld_query_stats` table, so that they survive
+//! restarts and can be queried like any other table.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fallible_iterator::FallibleIterator;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlite3_parser::ast::fmt::{ToTokens, TokenStream};
+use sqlite3_parser::dialect::TokenType;
+use sqlite3_parser::lexer::sql::Parser;
+
+use super::vacuum::WithConnection;
+
+/// Maximum number of distinct fingerprints tracked. Executions of statements with a new
+/// fingerprint are ignored past that limit.
+const MAX_FINGERPRINTS: usize = 10_000;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS _sqld_query_stats (
+    fingerprint TEXT PRIMARY KEY,
+    count INTEGER NOT NULL,
+    total_time_us INTEGER NOT NULL,
+    max_time_us INTEGER NOT NULL,
+    rows_scanned INTEGER NOT NULL,
+    rows_returned INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+)";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct QueryStatsEntry {
+    pub fingerprint: String,
+    /// Number of sampled executions
+    pub count: u64,
+    pub total_time_us: u64,
+    pub max_time_us: u64,
+    /// Rows visited by the statement, as reported by `sqlite3_stmt_status`
+    pub rows_scanned: u64,
+    pub rows_returned: u64,
+    /// Unix timestamp of the last sampled execution, in seconds
+    pub last_seen: u64,
+    /// Whether the entry changed since it was last flushed
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    TotalTime,
+    MaxTime,
+    Count,
+    RowsScanned,
+}
+
+impl SortKey {
+    fn key(&self, entry: &QueryStatsEntry) -> u64 {
+        match self {
+            SortKey::TotalTime => entry.total_time_us,
+            SortKey::MaxTime => entry.max_time_us,
+            SortKey::Count => entry.count,
+            SortKey::RowsScanned => entry.rows_scanned,
+        }
+    }
+}
+
+pub struct QueryStats {
+    sample_rate: f64,
+    entries: Mutex<HashMap<String, QueryStatsEntry>>,
+    /// Set on the primary, where the statistics are persisted.
+    with_conn: Option<Arc<WithConnection>>,
+}
+
+impl QueryStats {
+    pub fn new(sample_rate: f64, with_conn: Option<Arc<WithConnection>>) -> Self {
+        Self {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            entries: Default::default(),
+            with_conn,
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Decides whether the next execution is recorded.
+    pub fn should_sample(&self) -> bool {
+        self.sample_rate >= 1.0 || rand::thread_rng().gen_bool(self.sample_rate)
+    }
+
+    pub fn record(&self, sql: &str, duration: Duration, rows_scanned: u64, rows_returned: u64) {
+        let fingerprint = fingerprint(sql);
+        let time_us = duration.as_micros() as u64;
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_FINGERPRINTS && !entries.contains_key(&fingerprint) {
+            return;
+        }
+
+        let entry = entries
+            .entry(fingerprint)
+            .or_insert_with_key(|fingerprint| QueryStatsEntry {
+                fingerprint: fingerprint.clone(),
+                ..Default::default()
+            });
+        entry.count += 1;
+        entry.total_time_us += time_us;
+        entry.max_time_us = entry.max_time_us.max(time_us);
+        entry.rows_scanned += rows_scanned;
+        entry.rows_returned += rows_returned;
+        entry.last_seen = now();
+        entry.dirty = true;
+    }
+
+    /// Returns the `limit` entries with the highest `sort` key.
+    pub fn top(&self, sort: SortKey, limit: usize) -> Vec<QueryStatsEntry> {
+        let mut entries: Vec<_> = self.entries.lock().values().cloned().collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(sort.key(e)));
+        entries.truncate(limit);
+        entries
+    }
+
+    /// Restores the statistics persisted by a previous run.
+    pub fn load(&self) -> anyhow::Result<()> {
+        let Some(with_conn) = self.with_conn.as_ref() else { return Ok(()) };
+        with_conn(&mut |conn| {
+            conn.execute(CREATE_TABLE, ())?;
+            let mut stmt = conn.prepare(
+                "SELECT fingerprint, count, total_time_us, max_time_us, rows_scanned, rows_returned, last_seen
+                FROM _sqld_query_stats",
+            )?;
+            let rows = stmt.query_map((), |row| {
+                Ok(QueryStatsEntry {
+                    fingerprint: row.get(0)?,
+                    count: row.get(1)?,
+                    total_time_us: row.get(2)?,
+                    max_time_us: row.get(3)?,
+                    rows_scanned: row.get(4)?,
+                    rows_returned: row.get(5)?,
+                    last_seen: row.get(6)?,
+                    dirty: false,
+                })
+            })?;
+
+            let mut entries = self.entries.lock();
+            for entry in rows {
+                let entry = entry?;
+                entries.insert(entry.fingerprint.clone(), entry);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Writes the entries that changed since the last flush to the stats table.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let Some(with_conn) = self.with_conn.as_ref() else { return Ok(()) };
+        let dirty: Vec<_> = self
+            .entries
+            .lock()
+            .values_mut()
+            .filter(|e| e.dirty)
+            .map(|e| {
+                e.dirty = false;
+                e.clone()
+            })
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let res = with_conn(&mut |conn| {
+            conn.execute(CREATE_TABLE, ())?;
+            conn.execute("BEGIN", ())?;
+            let mut stmt = conn.prepare_cached(
+                "INSERT OR REPLACE INTO _sqld_query_stats VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for e in dirty.iter() {
+                if let Err(e) = stmt.execute((
+                    &e.fingerprint,
+                    e.count,
+                    e.total_time_us,
+                    e.max_time_us,
+                    e.rows_scanned,
+                    e.rows_returned,
+                    e.last_seen,
+                )) {
+                    let _ = conn.execute("ROLLBACK", ());
+                    return Err(e.into());
+                }
+            }
+            conn.execute("COMMIT", ())?;
+            Ok(())
+        });
+
+        if res.is_err() {
+            // try again on the next flush
+            let mut entries = self.entries.lock();
+            for e in dirty {
+                if let Some(entry) = entries.get_mut(&e.fingerprint) {
+                    entry.dirty = true;
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Forgets all statistics, including the persisted ones.
+    pub fn reset(&self) -> anyhow::Result<()> {
+        self.entries.lock().clear();
+        if let Some(with_conn) = self.with_conn.as_ref() {
+            with_conn(&mut |conn| {
+                conn.execute(CREATE_TABLE, ())?;
+                conn.execute("DELETE FROM _sqld_query_stats", ())?;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Renders tokens the same way the AST `Display` implementation does, replacing literals with
+/// `?`, and collapsing lists of literals into a single `?`.
+struct Fingerprint {
+    out: String,
+    spaced: bool,
+}
+
+fn is_literal(ty: TokenType) -> bool {
+    matches!(
+        ty,
+        TokenType::TK_STRING
+            | TokenType::TK_BLOB
+            | TokenType::TK_INTEGER
+            | TokenType::TK_FLOAT
+            | TokenType::TK_VARIABLE
+    )
+}
+
+impl TokenStream for Fingerprint {
+    type Error = std::convert::Infallible;
+
+    fn append(&mut self, ty: TokenType, value: Option<&str>) -> Result<(), Self::Error> {
+        if is_literal(ty) && self.out.ends_with("?,") {
+            // `IN (1, 2, 3)` and `IN (1, 2)` have the same fingerprint
+            self.out.pop();
+            return Ok(());
+        }
+
+        if !self.spaced {
+            match ty {
+                TokenType::TK_COMMA | TokenType::TK_SEMI | TokenType::TK_RP | TokenType::TK_DOT => {
+                }
+                _ => {
+                    self.out.push(' ');
+                    self.spaced = true;
+                }
+            }
+        }
+
+        if is_literal(ty) {
+            self.out.push('?');
+            self.spaced = false;
+            return Ok(());
+        }
+
+        if let Some(s) = ty.as_str() {
+            self.out.push_str(s);
+            self.spaced = ty == TokenType::TK_LP || ty == TokenType::TK_DOT;
+        }
+
+        if let Some(s) = value {
+            self.spaced = s.bytes().all(|b| b.is_ascii_whitespace());
+            self.out.push_str(s);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the normalized form of `sql`, with its literals stripped.
+pub fn fingerprint(sql: &str) -> String {
+    let mut parser = Parser::new(sql.as_bytes());
+    match parser.next() {
+        Ok(Some(cmd)) => {
+            let mut fp = Fingerprint {
+                out: String::with_capacity(sql.len()),
+                spaced: true,
+            };
+            let _ = cmd.to_tokens(&mut fp);
+            fp.out
+        }
+        // the statement was already executed, so this is unlikely: fall back to the statement
+        // with its whitespaces collapsed.
+        _ => sql.split_whitespace().collect::<Vec<_>>().join(" "),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprint_strips_literals() {
+        assert_eq!(
+            fingerprint("select * from users where id = 42 and name = 'bob'"),
+            fingerprint("SELECT *   FROM users\nWHERE id = 7 AND name = 'alice'"),
+        );
+        assert_eq!(
+            fingerprint("select * from users where id = 42"),
+            "SELECT * FROM users WHERE id = ?"
+        );
+        assert_eq!(
+            fingerprint("insert into t values (x'00', 1.5, :name)"),
+            fingerprint("insert into t values (1, 2)"),
+        );
+        assert_ne!(
+            fingerprint("select * from users where id = 1"),
+            fingerprint("select * from users where email = 1"),
+        );
+    }
+
+    #[test]
+    fn fingerprint_collapses_lists() {
+        assert_eq!(
+            fingerprint("select * from t where id in (1, 2, 3)"),
+            fingerprint("select * from t where id in (4)"),
+        );
+    }
+
+    #[test]
+    fn counters() {
+        let stats = QueryStats::new(1.0, None);
+        assert!(stats.should_sample());
+        stats.record(
+            "select * from t where id = 1",
+            Duration::from_micros(10),
+            100,
+            1,
+        );
+        stats.record(
+            "select * from t where id = 2",
+            Duration::from_micros(30),
+            100,
+            0,
+        );
+        stats.record("select count(*) from t", Duration::from_micros(5), 100, 1);
+
+        let top = stats.top(SortKey::TotalTime, 10);
+        assert_eq!(top.len(), 2);
+        assert_eq!(
+            top[0].fingerprint,
+            fingerprint("select * from t where id = 0")
+        );
+        assert_eq!(top[0].count, 2);
+        assert_eq!(top[0].total_time_us, 40);
+        assert_eq!(top[0].max_time_us, 30);
+        assert_eq!(top[0].rows_scanned, 200);
+        assert_eq!(top[0].rows_returned, 1);
+
+        assert_eq!(stats.top(SortKey::TotalTime, 1).len(), 1);
+        assert!(QueryStats::new(0.0, None)
+            .top(SortKey::Count, 10)
+            .is_empty());
+        assert!(!QueryStats::new(0.0, None).should_sample());
+    }
+
+    #[test]
+    fn persisted_across_restarts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let with_conn: Arc<WithConnection> = Arc::new({
+            let path = tmp.path().join("data");
+            move |f: &mut dyn FnMut(&rusqlite::Connection) -> anyhow::Result<()>| {
+                f(&rusqlite::Connection::open(&path)?)
+            }
+        });
+
+        let stats = QueryStats::new(1.0, Some(with_conn.clone()));
+        stats.load().unwrap();
+        stats.record("select 1", Duration::from_micros(10), 0, 1);
+        stats.flush().unwrap();
+        stats.record("select 2", Duration::from_micros(10), 0, 1);
+        stats.flush().unwrap();
+
+        let stats = QueryStats::new(1.0, Some(with_conn.clone()));
+        stats.load().unwrap();
+        let top = stats.top(SortKey::Count, 10);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].count, 2);
+
+        stats.reset().unwrap();
+        let stats = QueryStats::new(1.0, Some(with_conn));
+        stats.load().unwrap();
+        assert!(stats.top(SortKey::Count, 10).is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+//! Transaction state of a database session.
+//!
+//! The kind of a statement predicts how it changes the state of the session, but SQLite has the
+//! last word: a statement may fail, some errors roll back the whole transaction, and the parser
+//! may misclassify a statement. After each statement, the state is taken from the transaction
+//! state of the connection, and a disagreement with the prediction is logged.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rusqlite::ffi;
+use serde::Serialize;
+
+use crate::query_analysis::{State, StmtKind};
+
+static SESSIONS_IDLE: AtomicU64 = AtomicU64::new(0);
+static SESSIONS_IN_TXN: AtomicU64 = AtomicU64::new(0);
+static SESSIONS_FAILED: AtomicU64 = AtomicU64::new(0);
+
+fn sessions_in(state: State) -> &'static AtomicU64 {
+    match state {
+        State::Init => &SESSIONS_IDLE,
+        State::Txn => &SESSIONS_IN_TXN,
+        State::Invalid => &SESSIONS_FAILED,
+    }
+}
+
+/// Number of open sessions in each state.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SessionCounts {
+    pub idle: u64,
+    pub in_transaction: u64,
+    pub failed: u64,
+}
+
+pub fn session_counts() -> SessionCounts {
+    SessionCounts {
+        idle: SESSIONS_IDLE.load(Ordering::Relaxed),
+        in_transaction: SESSIONS_IN_TXN.load(Ordering::Relaxed),
+        failed: SESSIONS_FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// The lock held by the transaction of a connection, as reported by `sqlite3_txn_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnLock {
+    None,
+    Read,
+    Write,
+}
+
+/// The transaction state of a connection, as reported by SQLite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqliteTxn {
+    /// Whether an explicit transaction is open, i.e. the connection is not in autocommit mode.
+    pub explicit: bool,
+    pub lock: TxnLock,
+}
+
+impl SqliteTxn {
+    pub fn of(conn: &rusqlite::Connection) -> Self {
+        // SAFETY: the handle is valid as long as `conn`, and a null schema stands for all the
+        // schemas of the connection.
+        let lock = match unsafe { ffi::sqlite3_txn_state(conn.handle(), std::ptr::null()) } {
+            ffi::SQLITE_TXN_READ => TxnLock::Read,
+            ffi::SQLITE_TXN_WRITE => TxnLock::Write,
+            _ => TxnLock::None,
+        };
+
+        Self {
+            explicit: !conn.is_autocommit(),
+            lock,
+        }
+    }
+
+    /// Whether the session is in a transaction. A write lock held in autocommit mode, by a
+    /// statement that was not reset, blocks the other writers just like a transaction.
+    pub fn is_open(&self) -> bool {
+        self.explicit || self.lock == TxnLock::Write
+    }
+}
+
+/// The transaction state of a session: idle ([`State::Init`]), in a transaction ([`State::Txn`]),
+/// or in a transaction in which a statement failed ([`State::Invalid`]), until it ends.
+#[derive(Debug)]
+pub struct SessionState {
+    state: State,
+}
+
+impl SessionState {
+    pub fn new() -> Self {
+        sessions_in(State::Init).fetch_add(1, Ordering::Relaxed);
+        Self { state: State::Init }
+    }
+
+    pub fn get(&self) -> State {
+        self.state
+    }
+
+    fn set(&mut self, state: State) {
+        if state != self.state {
+            sessions_in(self.state).fetch_sub(1, Ordering::Relaxed);
+            sessions_in(state).fetch_add(1, Ordering::Relaxed);
+            self.state = state;
+        }
+    }
+
+    /// Updates the state after a statement of kind `kind` of the session was executed, `ok` if it
+    /// succeeded, leaving the connection in the transaction state `txn`.
+    pub fn after_statement(&mut self, kind: StmtKind, ok: bool, txn: SqliteTxn) -> State {
+        let mut predicted = self.state;
+        if ok {
+            predicted.step(kind);
+        }
+
+        if txn.lock == TxnLock::Write && !txn.explicit {
+            tracing::warn!("a statement holds the write lock outside of a transaction");
+        }
+
+        let actual = match (txn.is_open(), ok) {
+            (false, _) => State::Init,
+            (true, false) => State::Invalid,
+            // the transaction stays failed until it ends
+            (true, true) if self.state == State::Invalid => State::Invalid,
+            (true, true) => State::Txn,
+        };
+
+        // the outcome of a failed statement can't be predicted, and neither can a release, which
+        // only ends the transaction if it releases the savepoint that began it
+        if ok
+            && kind != StmtKind::Release
+            && predicted != State::Invalid
+            && (predicted == State::Txn) != txn.is_open()
+        {
+            tracing::warn!(
+                "transaction state predicted from a {kind:?} statement ({predicted:?}) disagrees with SQLite ({actual:?})"
+            );
+        } else if !ok && self.state != State::Init && !txn.is_open() {
+            tracing::debug!("the transaction was rolled back by a failed statement");
+        }
+
+        self.set(actual);
+        self.state
+    }
+
+    /// Updates the state after the transaction of the session was ended by the connection itself,
+    /// e.g. when it timed out.
+    pub fn sync(&mut self, txn: SqliteTxn) -> State {
+        if !txn.is_open() {
+            self.set(State::Init);
+        } else if self.state == State::Init {
+            self.set(State::Txn);
+        }
+        self.state
+    }
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SessionState {
+    fn drop(&mut self) {
+        sessions_in(self.state).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::query_analysis::Statement;
+
+    use super::*;
+
+    fn exec(state: &mut SessionState, conn: &rusqlite::Connection, sql: &str) -> State {
+        let kind = Statement::parse(sql).next().unwrap().unwrap().kind;
+        let ok = conn.execute_batch(sql).is_ok();
+        state.after_statement(kind, ok, SqliteTxn::of(conn))
+    }
+
+    fn setup() -> (rusqlite::Connection, SessionState) {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("create table test (x not null unique)")
+            .unwrap();
+        (conn, SessionState::new())
+    }
+
+    #[test]
+    fn transaction_transitions() {
+        let (conn, mut state) = setup();
+        assert_eq!(state.get(), State::Init);
+        assert_eq!(exec(&mut state, &conn, "select * from test"), State::Init);
+        assert_eq!(exec(&mut state, &conn, "begin"), State::Txn);
+        assert_eq!(
+            exec(&mut state, &conn, "insert into test values (1)"),
+            State::Txn
+        );
+        assert_eq!(exec(&mut state, &conn, "commit"), State::Init);
+        assert_eq!(exec(&mut state, &conn, "begin immediate"), State::Txn);
+        assert_eq!(exec(&mut state, &conn, "rollback"), State::Init);
+        // a savepoint outside of a transaction begins one
+        assert_eq!(exec(&mut state, &conn, "savepoint s"), State::Txn);
+        assert_eq!(exec(&mut state, &conn, "rollback to s"), State::Txn);
+        assert_eq!(exec(&mut state, &conn, "release s"), State::Init);
+    }
+
+    #[test]
+    fn failed_statements() {
+        let (conn, mut state) = setup();
+        // the implicit transaction of a failed statement is rolled back
+        assert_eq!(
+            exec(&mut state, &conn, "insert into test values (null)"),
+            State::Init
+        );
+        assert_eq!(exec(&mut state, &conn, "commit"), State::Init);
+
+        assert_eq!(exec(&mut state, &conn, "begin"), State::Txn);
+        assert_eq!(
+            exec(&mut state, &conn, "insert into test values (null)"),
+            State::Invalid
+        );
+        // the transaction stays failed until it ends
+        assert_eq!(
+            exec(&mut state, &conn, "insert into test values (1)"),
+            State::Invalid
+        );
+        assert_eq!(exec(&mut state, &conn, "begin"), State::Invalid);
+        assert_eq!(exec(&mut state, &conn, "commit"), State::Init);
+    }
+
+    #[test]
+    fn error_rolls_back_transaction() {
+        let (conn, mut state) = setup();
+        conn.execute_batch("insert into test values (1)").unwrap();
+        assert_eq!(exec(&mut state, &conn, "begin"), State::Txn);
+        assert_eq!(
+            exec(&mut state, &conn, "insert or rollback into test values (1)"),
+            State::Init
+        );
+    }
+
+    #[test]
+    fn sqlite_overrides_prediction() {
+        let (conn, mut state) = setup();
+        // a transaction begun by a statement the parser doesn't classify as such
+        let ok = conn.execute_batch("begin").is_ok();
+        assert_eq!(
+            state.after_statement(StmtKind::Other, ok, SqliteTxn::of(&conn)),
+            State::Txn
+        );
+
+        conn.execute_batch("rollback").unwrap();
+        assert_eq!(state.sync(SqliteTxn::of(&conn)), State::Init);
+    }
+
+    #[test]
+    fn sqlite_transaction_state() {
+        let (conn, _) = setup();
+        let txn = |explicit, lock| SqliteTxn { explicit, lock };
+        assert_eq!(SqliteTxn::of(&conn), txn(false, TxnLock::None));
+        // a deferred transaction takes no lock until it reads or writes
+        conn.execute_batch("begin").unwrap();
+        assert_eq!(SqliteTxn::of(&conn), txn(true, TxnLock::None));
+        conn.execute_batch("select * from test").unwrap();
+        assert_eq!(SqliteTxn::of(&conn), txn(true, TxnLock::Read));
+        conn.execute_batch("insert into test values (1)").unwrap();
+        assert_eq!(SqliteTxn::of(&conn), txn(true, TxnLock::Write));
+        conn.execute_batch("commit").unwrap();
+
+        // a statement that was not reset holds its lock
+        let mut stmt = conn.prepare("select * from test").unwrap();
+        let mut rows = stmt.query(()).unwrap();
+        rows.next().unwrap();
+        assert_eq!(SqliteTxn::of(&conn), txn(false, TxnLock::Read));
+        assert!(!SqliteTxn::of(&conn).is_open());
+    }
+}
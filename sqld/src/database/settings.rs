@@ -0,0 +1,692 @@
+//! Per-session settings.
+//!
+//! Clients change the settings of their session with `SET name = value` (or `SET name TO value`),
+//! restore their default value with `RESET name`, and read them back with `SHOW name`. These
+//! statements are handled by sqld, and never reach SQLite, and neither does `SET TRANSACTION`,
+//! which only accepts the modes of the transactions of SQLite. The settings live on the database
+//! connection of the session, and are dropped with it.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::auth::{Authenticated, Authorized};
+use crate::error::Error;
+use crate::query_analysis::DenyMatch;
+pub use crate::query_analysis::{DenyRule, StmtClass};
+
+use super::Program;
+
+/// How sqld treats settings it doesn't know about.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownSettings {
+    /// Fail the statement.
+    #[default]
+    Error,
+    /// Log a warning, and ignore the statement.
+    Warn,
+}
+
+/// Which statements must use parameters instead of literal values.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequireParameterized {
+    /// Only the statements writing to the database.
+    Writes,
+    /// All the statements, reads included.
+    All,
+}
+
+/// The statements denied on the node, see `--denied-statements`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeniedStatements {
+    /// Bit set of the denied rules.
+    rules: u16,
+    /// Also deny the statements that only match a rule because their `WHERE` clause is always
+    /// true, like `DELETE FROM t WHERE 1 = 1`.
+    pub strict: bool,
+}
+
+impl DeniedStatements {
+    pub fn new(rules: &[DenyRule], strict: bool) -> Self {
+        Self {
+            rules: rules.iter().fold(0, |set, rule| set | (1 << *rule as u16)),
+            strict,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules == 0
+    }
+
+    /// Returns the rule denying the statement that matched `denied_by`, if it is denied.
+    pub fn check(&self, denied_by: Option<DenyMatch>) -> Option<DenyRule> {
+        let DenyMatch { rule, tautology } = denied_by?;
+        let denied = self.rules & (1 << rule as u16) != 0 && (self.strict || !tautology);
+        denied.then_some(rule)
+    }
+}
+
+/// The classes of statements that each caller may run, see `--allowed-statement-classes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllowedStatementClasses {
+    /// Bit set of the classes allowed to all the callers, all of them if `None`.
+    all: Option<u8>,
+    /// Bit set of the classes allowed to the read-only callers, those of `all` if `None`.
+    read_only: Option<u8>,
+    /// Bit sets of the classes that some principals are further restricted to. The list is set
+    /// once from the configuration, and leaked so that the settings stay `Copy`.
+    principals: &'static [(String, u8)],
+}
+
+fn class_bits(classes: &[StmtClass]) -> u8 {
+    classes
+        .iter()
+        .fold(0, |set, class| set | (1 << *class as u8))
+}
+
+impl AllowedStatementClasses {
+    /// An empty list of classes allows all of them.
+    pub fn new(all: &[StmtClass], read_only: &[StmtClass]) -> Self {
+        let bits = |classes: &[StmtClass]| (!classes.is_empty()).then(|| class_bits(classes));
+        Self {
+            all: bits(all),
+            read_only: bits(read_only),
+            principals: &[],
+        }
+    }
+
+    /// Restricts the principals of `principals` to their classes, on top of the classes allowed
+    /// to every caller.
+    pub fn with_principals(mut self, principals: &[PrincipalStatementClasses]) -> Self {
+        if !principals.is_empty() {
+            let principals = principals
+                .iter()
+                .map(|p| (p.principal.clone(), class_bits(&p.classes)))
+                .collect::<Vec<_>>();
+            self.principals = Vec::leak(principals);
+        }
+        self
+    }
+
+    /// Whether `auth` may run the statements of `class` on behalf of `principal`, the principal
+    /// of the [`crate::auth::Identity`] of the client.
+    pub fn is_allowed(
+        &self,
+        auth: Authenticated,
+        principal: Option<&str>,
+        class: StmtClass,
+    ) -> bool {
+        let allowed = match auth {
+            Authenticated::Authorized(Authorized::ReadOnly) => self.read_only.or(self.all),
+            _ => self.all,
+        };
+        let restricted = principal.and_then(|principal| {
+            self.principals
+                .iter()
+                .find(|(p, _)| p == principal)
+                .map(|(_, set)| *set)
+        });
+        let bit = 1 << class as u8;
+        allowed.map_or(true, |set| set & bit != 0) && restricted.map_or(true, |set| set & bit != 0)
+    }
+
+    /// Checks that `auth` may run all the statements of `pgm` on behalf of `principal`, before
+    /// any of them is executed.
+    pub fn check(
+        &self,
+        auth: Authenticated,
+        principal: Option<&str>,
+        pgm: &Program,
+    ) -> Result<(), Error> {
+        let denied = pgm
+            .steps()
+            .iter()
+            .filter_map(|step| step.query.stmt.class)
+            .find(|class| !self.is_allowed(auth, principal, *class));
+        match denied {
+            Some(class) => Err(Error::StatementClassNotAllowed(class)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The classes of statements a principal may run, parsed from `principal=class+class`, see
+/// `--principal-statement-classes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrincipalStatementClasses {
+    pub principal: String,
+    pub classes: Vec<StmtClass>,
+}
+
+impl FromStr for PrincipalStatementClasses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((principal, classes)) = s.split_once('=') else {
+            return Err(format!("expected `principal=class+class`, got `{s}`"))
+        };
+        if principal.is_empty() {
+            return Err(format!("missing principal in `{s}`"));
+        }
+        let classes = classes
+            .split('+')
+            .map(|class| <StmtClass as clap::ValueEnum>::from_str(class.trim(), true))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            principal: principal.to_string(),
+            classes,
+        })
+    }
+}
+
+/// Server-wide configuration of the sessions.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionConfig {
+    /// Maximum execution time of a statement. Sessions can lower it, but not raise it.
+    pub query_timeout: Option<Duration>,
+    /// Time after which an open transaction is rolled back if no program comes. Transactions
+    /// never time out if `None`.
+    pub txn_timeout: Option<Duration>,
+    pub unknown_settings: UnknownSettings,
+    /// If set, statements with literal values where a parameter could be used are rejected.
+    pub require_parameterized: Option<RequireParameterized>,
+    /// Enforce the foreign key constraints on every connection.
+    pub foreign_keys: bool,
+    /// Classes of statements that are rejected.
+    pub denied_statements: DeniedStatements,
+    /// Classes of statements that each caller may run.
+    pub allowed_statement_classes: AllowedStatementClasses,
+    /// Limits on the results of each statement. Requests can lower them, but not raise them.
+    pub result_limits: ResultLimits,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            query_timeout: None,
+            txn_timeout: Some(super::DEFAULT_TXN_TIMEOUT),
+            unknown_settings: UnknownSettings::default(),
+            require_parameterized: None,
+            foreign_keys: true,
+            denied_statements: DeniedStatements::default(),
+            allowed_statement_classes: AllowedStatementClasses::default(),
+            result_limits: ResultLimits::default(),
+        }
+    }
+}
+
+/// Limits on the results of a single statement. A statement whose results exceed them fails with
+/// [`crate::error::Error::ResultLimitExceeded`], without affecting the results of the other
+/// statements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ResultLimits {
+    /// Maximum number of rows.
+    pub max_rows: Option<u64>,
+    /// Maximum size of the values of the rows: 8 bytes for a number, the length of a text or a
+    /// blob.
+    pub max_bytes: Option<u64>,
+}
+
+impl ResultLimits {
+    pub fn is_none(&self) -> bool {
+        self.max_rows.is_none() && self.max_bytes.is_none()
+    }
+
+    /// The stricter of the limits of `self` and `other`.
+    pub fn min(self, other: Self) -> Self {
+        fn min(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        Self {
+            max_rows: min(self.max_rows, other.max_rows),
+            max_bytes: min(self.max_bytes, other.max_bytes),
+        }
+    }
+}
+
+/// The limit of [`ResultLimits`] exceeded by the results of a statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultLimit {
+    Rows(u64),
+    Bytes(u64),
+}
+
+impl fmt::Display for ResultLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rows(max) => write!(f, "{max} rows"),
+            Self::Bytes(max) => write!(f, "{max} bytes"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionSettings {
+    /// Name of the client application, attached to the logs of the statements of the session.
+    pub application_name: Option<String>,
+    /// Maximum execution time of the statements of the session.
+    pub statement_timeout: Option<Duration>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsError {
+    #[error("unrecognized setting `{0}`")]
+    Unknown(String),
+    #[error("invalid value for setting `{name}`: `{value}`")]
+    InvalidValue { name: String, value: String },
+    #[error("unsupported transaction mode `{0}`: transactions are read-write and serializable")]
+    UnsupportedTransactionMode(String),
+}
+
+impl SessionSettings {
+    /// Sets the setting `name` to `value`, or to its default value if `value` is `None`.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), SettingsError> {
+        match name {
+            "application_name" => self.application_name = value.map(ToString::to_string),
+            "statement_timeout" => {
+                let timeout = value
+                    .map(|v| {
+                        parse_duration(v).ok_or_else(|| SettingsError::InvalidValue {
+                            name: name.to_string(),
+                            value: v.to_string(),
+                        })
+                    })
+                    .transpose()?;
+                // like in postgres, a timeout of 0 disables the timeout
+                self.statement_timeout = timeout.filter(|t| !t.is_zero());
+            }
+            _ => return Err(SettingsError::Unknown(name.to_string())),
+        }
+
+        Ok(())
+    }
+
+    pub fn show(&self, name: &str) -> Result<String, SettingsError> {
+        match name {
+            "application_name" => Ok(self.application_name.clone().unwrap_or_default()),
+            "statement_timeout" => Ok(self
+                .statement_timeout
+                .map_or_else(|| "0".to_string(), |t| format!("{}ms", t.as_millis()))),
+            _ => Err(SettingsError::Unknown(name.to_string())),
+        }
+    }
+
+    /// The timeout of the statements of the session: the session's `statement_timeout`, capped by
+    /// the server-wide `query_timeout`.
+    pub fn effective_statement_timeout(&self, config: &SessionConfig) -> Option<Duration> {
+        match (self.statement_timeout, config.query_timeout) {
+            (Some(session), Some(global)) => Some(session.min(global)),
+            (session, global) => session.or(global),
+        }
+    }
+}
+
+/// Parses a duration in milliseconds, or with one of the `ms`, `s`, `min` or `h` units.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value: u64 = s[..unit_start].parse().ok()?;
+    let millis = match s[unit_start..].trim() {
+        "" | "ms" => value,
+        "s" => value.checked_mul(1000)?,
+        "min" => value.checked_mul(60 * 1000)?,
+        "h" => value.checked_mul(60 * 60 * 1000)?,
+        _ => return None,
+    };
+
+    Some(Duration::from_millis(millis))
+}
+
+/// A statement that reads or changes a setting of the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingCommand {
+    /// `SET name = value`, `value` is `None` for `SET name TO DEFAULT`.
+    Set { name: String, value: Option<String> },
+    /// `RESET name`, or `RESET ALL` if `name` is `None`.
+    Reset { name: Option<String> },
+    /// `SHOW name`
+    Show { name: String },
+    /// `SET TRANSACTION mode, ...`
+    SetTransaction(TransactionModes),
+}
+
+/// The modes of a `SET TRANSACTION` statement, `None` for the modes it leaves unspecified.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionModes {
+    /// `READ ONLY`, or `READ WRITE`.
+    pub read_only: Option<bool>,
+    /// The isolation level, in lowercase, like `read committed`.
+    pub isolation: Option<String>,
+}
+
+const ISOLATION_LEVELS: &[&str] = &[
+    "serializable",
+    "repeatable read",
+    "read committed",
+    "read uncommitted",
+];
+
+impl TransactionModes {
+    /// Parses the comma-separated modes following `SET TRANSACTION`.
+    fn parse(s: &str) -> Option<Self> {
+        let mut modes = Self::default();
+        for mode in s.split(',') {
+            let mode = mode
+                .split_whitespace()
+                .map(str::to_ascii_lowercase)
+                .collect::<Vec<_>>()
+                .join(" ");
+            match mode.as_str() {
+                "read only" => modes.read_only = Some(true),
+                "read write" => modes.read_only = Some(false),
+                _ => {
+                    let level = mode.strip_prefix("isolation level ")?;
+                    let level = ISOLATION_LEVELS.iter().find(|l| **l == level)?;
+                    modes.isolation = Some(level.to_string());
+                }
+            }
+        }
+
+        Some(modes)
+    }
+
+    /// Checks that the modes are those of the transactions of SQLite, which always serializes
+    /// them, and doesn't prevent their writes.
+    pub fn check(&self) -> Result<(), SettingsError> {
+        if self.read_only == Some(true) {
+            return Err(SettingsError::UnsupportedTransactionMode(
+                "READ ONLY".to_string(),
+            ));
+        }
+        match self.isolation.as_deref() {
+            None | Some("serializable") => Ok(()),
+            Some(level) => Err(SettingsError::UnsupportedTransactionMode(format!(
+                "ISOLATION LEVEL {}",
+                level.to_ascii_uppercase()
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for TransactionModes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut modes = Vec::new();
+        if let Some(level) = &self.isolation {
+            modes.push(format!("ISOLATION LEVEL {}", level.to_ascii_uppercase()));
+        }
+        match self.read_only {
+            Some(true) => modes.push("READ ONLY".to_string()),
+            Some(false) => modes.push("READ WRITE".to_string()),
+            None => (),
+        }
+        write!(f, "{}", modes.join(", "))
+    }
+}
+
+impl SettingCommand {
+    /// Recognizes `SET`, `SET TRANSACTION`, `RESET` and `SHOW` statements. Returns `None` for any
+    /// other statement.
+    pub fn parse(sql: &str) -> Option<Self> {
+        let sql = sql.trim().trim_end_matches(';');
+        let (keyword, rest) = split_word(sql);
+        let cmd = match keyword.to_ascii_lowercase().as_str() {
+            "set" => {
+                if let Some(modes) = strip_keyword(rest, "transaction") {
+                    return TransactionModes::parse(modes).map(Self::SetTransaction);
+                }
+                let rest = strip_keyword(rest, "session").unwrap_or(rest);
+                let (name, rest) = split_word(rest);
+                let rest = rest.trim_start();
+                let value = rest
+                    .strip_prefix('=')
+                    .or_else(|| strip_keyword(rest, "to"))?;
+                Self::Set {
+                    name: name.to_ascii_lowercase(),
+                    value: parse_value(value.trim())?,
+                }
+            }
+            "reset" => {
+                let (name, rest) = split_word(rest);
+                if !rest.trim().is_empty() {
+                    return None;
+                }
+                let name = (!name.eq_ignore_ascii_case("all")).then(|| name.to_ascii_lowercase());
+                Self::Reset { name }
+            }
+            "show" => {
+                let (name, rest) = split_word(rest);
+                if !rest.trim().is_empty() {
+                    return None;
+                }
+                Self::Show {
+                    name: name.to_ascii_lowercase(),
+                }
+            }
+            _ => return None,
+        };
+
+        match &cmd {
+            Self::Set { name, .. } | Self::Show { name } | Self::Reset { name: Some(name) }
+                if name.is_empty() =>
+            {
+                None
+            }
+            _ => Some(cmd),
+        }
+    }
+}
+
+impl fmt::Display for SettingCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Set { name, value: None } => write!(f, "SET {name} TO DEFAULT"),
+            Self::Set {
+                name,
+                value: Some(value),
+            } => write!(f, "SET {name} = '{}'", value.replace('\'', "''")),
+            Self::Reset { name: None } => write!(f, "RESET ALL"),
+            Self::Reset { name: Some(name) } => write!(f, "RESET {name}"),
+            Self::Show { name } => write!(f, "SHOW {name}"),
+            Self::SetTransaction(modes) => write!(f, "SET TRANSACTION {modes}"),
+        }
+    }
+}
+
+/// Splits the leading identifier off `s`.
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    let end = s
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    let (word, rest) = split_word(s);
+    word.eq_ignore_ascii_case(keyword).then_some(rest)
+}
+
+/// Parses the value of a `SET` statement: `DEFAULT`, a quoted string, or a single word or number.
+fn parse_value(s: &str) -> Option<Option<String>> {
+    if s.eq_ignore_ascii_case("default") {
+        return Some(None);
+    }
+
+    if let Some(quoted) = s.strip_prefix('\'') {
+        let inner = quoted.strip_suffix('\'')?;
+        return Some(Some(inner.replace("''", "'")));
+    }
+
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '\'' || c == ';') {
+        return None;
+    }
+
+    Some(Some(s.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(name: &str, value: Option<&str>) -> Option<SettingCommand> {
+        Some(SettingCommand::Set {
+            name: name.to_string(),
+            value: value.map(ToString::to_string),
+        })
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            SettingCommand::parse("SET application_name = 'worker-7'"),
+            set("application_name", Some("worker-7"))
+        );
+        assert_eq!(
+            SettingCommand::parse("set session Statement_Timeout to 500;"),
+            set("statement_timeout", Some("500"))
+        );
+        assert_eq!(
+            SettingCommand::parse("SET statement_timeout TO DEFAULT"),
+            set("statement_timeout", None)
+        );
+        assert_eq!(
+            SettingCommand::parse("set application_name = 'it''s'"),
+            set("application_name", Some("it's"))
+        );
+        assert_eq!(
+            SettingCommand::parse("RESET ALL"),
+            Some(SettingCommand::Reset { name: None })
+        );
+        assert_eq!(
+            SettingCommand::parse("show statement_timeout"),
+            Some(SettingCommand::Show {
+                name: "statement_timeout".into()
+            })
+        );
+
+        for sql in [
+            "select 1",
+            "SET",
+            "SET = 1",
+            "SET application_name 'x'",
+            "SET application_name = 'x",
+            "SET application_name = a b",
+            "SHOW a b",
+            "settings",
+        ] {
+            assert_eq!(SettingCommand::parse(sql), None, "{sql}");
+        }
+
+        // the SQL rendering of a command parses back to the same command
+        let cmd = set("application_name", Some("it's")).unwrap();
+        assert_eq!(SettingCommand::parse(&cmd.to_string()), Some(cmd));
+    }
+
+    #[test]
+    fn set_transaction() {
+        let modes = |read_only, isolation: Option<&str>| {
+            Some(SettingCommand::SetTransaction(TransactionModes {
+                read_only,
+                isolation: isolation.map(ToString::to_string),
+            }))
+        };
+        assert_eq!(
+            SettingCommand::parse("SET TRANSACTION READ ONLY"),
+            modes(Some(true), None)
+        );
+        assert_eq!(
+            SettingCommand::parse("set transaction isolation level  Read Committed, read write;"),
+            modes(Some(false), Some("read committed"))
+        );
+        for sql in [
+            "SET TRANSACTION",
+            "SET TRANSACTION SNAPSHOT '00000003-0000001B-1'",
+            "SET TRANSACTION ISOLATION LEVEL CHAOS",
+            "SET TRANSACTION READ ONLY,",
+        ] {
+            assert_eq!(SettingCommand::parse(sql), None, "{sql}");
+        }
+
+        let cmd = modes(Some(false), Some("serializable")).unwrap();
+        assert_eq!(SettingCommand::parse(&cmd.to_string()), Some(cmd));
+
+        let check = |sql| match SettingCommand::parse(sql) {
+            Some(SettingCommand::SetTransaction(modes)) => modes.check(),
+            cmd => panic!("unexpected command {cmd:?}"),
+        };
+        check("SET TRANSACTION READ WRITE").unwrap();
+        check("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE").unwrap();
+        for sql in [
+            "SET TRANSACTION READ ONLY",
+            "SET TRANSACTION ISOLATION LEVEL READ UNCOMMITTED",
+        ] {
+            assert!(matches!(
+                check(sql),
+                Err(SettingsError::UnsupportedTransactionMode(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn statement_timeout() {
+        let mut settings = SessionSettings::default();
+        let config = SessionConfig {
+            query_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.effective_statement_timeout(&config),
+            Some(Duration::from_secs(5))
+        );
+
+        settings.set("statement_timeout", Some("1s")).unwrap();
+        assert_eq!(settings.show("statement_timeout").unwrap(), "1000ms");
+        assert_eq!(
+            settings.effective_statement_timeout(&config),
+            Some(Duration::from_secs(1))
+        );
+
+        // the session can't raise the server-wide timeout
+        settings.set("statement_timeout", Some("10min")).unwrap();
+        assert_eq!(
+            settings.effective_statement_timeout(&config),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            settings.effective_statement_timeout(&SessionConfig::default()),
+            Some(Duration::from_secs(600))
+        );
+
+        settings.set("statement_timeout", Some("0")).unwrap();
+        assert_eq!(settings.show("statement_timeout").unwrap(), "0");
+        assert_eq!(
+            settings.effective_statement_timeout(&SessionConfig::default()),
+            None
+        );
+
+        assert!(matches!(
+            settings.set("statement_timeout", Some("soon")),
+            Err(SettingsError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            settings.set("search_path", Some("public")),
+            Err(SettingsError::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn parse_principal_statement_classes() {
+        let parsed: PrincipalStatementClasses = "alice=read+write".parse().unwrap();
+        assert_eq!(parsed.principal, "alice");
+        assert_eq!(parsed.classes, [StmtClass::Read, StmtClass::Write]);
+        assert!("alice".parse::<PrincipalStatementClasses>().is_err());
+        assert!("=read".parse::<PrincipalStatementClasses>().is_err());
+        assert!("alice=read+drop"
+            .parse::<PrincipalStatementClasses>()
+            .is_err());
+    }
+}
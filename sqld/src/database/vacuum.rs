@@ -0,0 +1,445 @@
+//! Space reclamation.
+//!
+//! Deleting rows never shrinks the database file, and `VACUUM` can't be run by clients. Two kinds
+//! of vacuum can be requested through the admin API on the primary:
+//! - an incremental vacuum, that runs `PRAGMA incremental_vacuum(N)` in small transactions
+//!   interleaved with the normal traffic. This requires the database to be in
+//!   `auto_vacuum=INCREMENTAL` mode (see [`enable_incremental_vacuum`]).
+//! - a full vacuum, that blocks writes, waits for the write transactions already open to end,
+//!   runs `VACUUM INTO` a temporary file while holding the write lock, and restarts the server.
+//!   The original file is only replaced by the vacuumed copy on startup, by
+//!   [`finish_full_vacuum`], and the new content of the database is recorded in the replication
+//!   log so that replicas resync from a snapshot.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::database::config::DatabaseConfigStore;
+use crate::replication::primary::logger::checkpoint_db;
+use crate::replication::WAL_PAGE_SIZE;
+use crate::stats::Stats;
+use crate::RESTART;
+
+/// Reason reported to the clients whose writes are blocked during a full vacuum.
+pub const VACUUM_BLOCK_REASON: &str = "a full vacuum is in progress";
+
+/// Complete vacuumed copy of the database, waiting to be swapped with the database file.
+const VACUUMED_FILE: &str = "data.vacuumed";
+/// Vacuumed copy of the database being written.
+const VACUUM_TMP_FILE: &str = "data.vacuum-tmp";
+/// Pause between two incremental vacuum steps, to let other transactions through.
+const INCREMENTAL_VACUUM_PAUSE: Duration = Duration::from_millis(10);
+/// How long a full vacuum waits for the write transactions open when writes were blocked.
+const WRITE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Calls the passed function with a connection to the primary database, whose writes go through
+/// the replication log.
+pub type WithConnection = dyn Fn(&mut dyn FnMut(&rusqlite::Connection) -> anyhow::Result<()>) -> anyhow::Result<()>
+    + Send
+    + Sync;
+
+fn default_pages_per_step() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum VacuumRequest {
+    Incremental {
+        /// Number of pages freed by each transaction
+        #[serde(default = "default_pages_per_step")]
+        pages_per_step: u64,
+        /// Stop after this many pages were freed
+        #[serde(default)]
+        max_pages: Option<u64>,
+    },
+    Full,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VacuumError {
+    #[error("a vacuum is already in progress")]
+    InProgress,
+    #[error("incremental vacuum requires the server to be started with `--incremental-vacuum`")]
+    IncrementalDisabled,
+}
+
+pub struct Vacuum {
+    db_path: PathBuf,
+    incremental: bool,
+    with_conn: Arc<WithConnection>,
+    db_config_store: Arc<DatabaseConfigStore>,
+    stats: Stats,
+    running: AtomicBool,
+}
+
+impl Vacuum {
+    pub fn new(
+        db_path: PathBuf,
+        incremental: bool,
+        with_conn: Arc<WithConnection>,
+        db_config_store: Arc<DatabaseConfigStore>,
+        stats: Stats,
+    ) -> Self {
+        Self {
+            db_path,
+            incremental,
+            with_conn,
+            db_config_store,
+            stats,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts a vacuum in the background. Only one vacuum can run at a time.
+    pub fn start(self: &Arc<Self>, req: VacuumRequest) -> Result<(), VacuumError> {
+        if matches!(req, VacuumRequest::Incremental { .. }) && !self.incremental {
+            return Err(VacuumError::IncrementalDisabled);
+        }
+
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(VacuumError::InProgress);
+        }
+
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let res = match req {
+                VacuumRequest::Incremental {
+                    pages_per_step,
+                    max_pages,
+                } => this.run_incremental(pages_per_step.max(1), max_pages),
+                VacuumRequest::Full => this.run_full(),
+            };
+            if let Err(e) = res {
+                tracing::error!("vacuum failed: {e}");
+            }
+            this.running.store(false, Ordering::SeqCst);
+        });
+
+        Ok(())
+    }
+
+    fn run_incremental(&self, pages_per_step: u64, max_pages: Option<u64>) -> anyhow::Result<()> {
+        tracing::info!("starting incremental vacuum");
+        let mut reclaimed = 0;
+        (self.with_conn)(&mut |conn| {
+            conn.busy_timeout(Duration::from_secs(5))?;
+            loop {
+                let step = match max_pages {
+                    Some(max) if reclaimed >= max => break,
+                    Some(max) => pages_per_step.min(max - reclaimed),
+                    None => pages_per_step,
+                };
+
+                let before = freelist_count(conn)?;
+                if before == 0 {
+                    break;
+                }
+                conn.execute_batch(&format!("PRAGMA incremental_vacuum({step})"))?;
+                let freed = before.saturating_sub(freelist_count(conn)?);
+                if freed == 0 {
+                    break;
+                }
+
+                reclaimed += freed;
+                self.stats
+                    .inc_vacuum_reclaimed(freed, freed * WAL_PAGE_SIZE as u64);
+                std::thread::sleep(INCREMENTAL_VACUUM_PAUSE);
+            }
+
+            Ok(())
+        })?;
+        tracing::info!("incremental vacuum reclaimed {reclaimed} pages");
+
+        Ok(())
+    }
+
+    fn run_full(&self) -> anyhow::Result<()> {
+        tracing::info!("starting full vacuum, writes are blocked until it completes");
+        let prev_config = self.db_config_store.get();
+        let mut config = (*prev_config).clone();
+        config.block_writes = true;
+        config.block_reason = Some(VACUUM_BLOCK_REASON.to_string());
+        self.db_config_store.store(config)?;
+
+        match self.vacuum_into() {
+            Ok(()) => {
+                // writes stay blocked until the vacuumed file is swapped in on restart. The
+                // notification is kept until the server waits for it.
+                tracing::info!("vacuumed copy of the database written, restarting");
+                RESTART.notify_one();
+                Ok(())
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(self.db_path.join(VACUUM_TMP_FILE));
+                self.db_config_store.store((*prev_config).clone())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn vacuum_into(&self) -> anyhow::Result<()> {
+        let tmp_path = self.db_path.join(VACUUM_TMP_FILE);
+        // leftover from a previous attempt
+        let _ = std::fs::remove_file(&tmp_path);
+        let tmp_path_str = tmp_path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("invalid db path"))?;
+        // `VACUUM INTO` can't run in a transaction: the write lock is held by another connection,
+        // so that no transaction opened before writes were blocked commits after the copy.
+        (self.with_conn)(&mut |lock_conn| {
+            lock_conn.busy_timeout(WRITE_LOCK_TIMEOUT)?;
+            lock_conn.execute_batch("BEGIN IMMEDIATE")?;
+            let res = (self.with_conn)(&mut |conn| {
+                conn.execute("VACUUM INTO ?", [tmp_path_str])?;
+                Ok(())
+            });
+            lock_conn.execute_batch("ROLLBACK")?;
+            res
+        })?;
+
+        File::open(&tmp_path)?.sync_all()?;
+        std::fs::rename(&tmp_path, self.db_path.join(VACUUMED_FILE))?;
+        File::open(&self.db_path)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+fn freelist_count(conn: &rusqlite::Connection) -> rusqlite::Result<u64> {
+    conn.query_row("PRAGMA freelist_count", (), |row| row.get(0))
+}
+
+/// Switches the database to `auto_vacuum=INCREMENTAL`, if it is not already. This rebuilds the
+/// whole database file once.
+pub fn enable_incremental_vacuum(with_conn: &WithConnection) -> anyhow::Result<()> {
+    with_conn(&mut |conn| {
+        let mode: u8 = conn.query_row("PRAGMA auto_vacuum", (), |row| row.get(0))?;
+        // 2 is INCREMENTAL
+        if mode != 2 {
+            tracing::info!(
+                "rebuilding database to enable incremental vacuum, this may take a while"
+            );
+            conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Completes a full vacuum interrupted by the restart, by replacing the database file with its
+/// vacuumed copy, and unblocks writes. Returns whether the database file was replaced.
+///
+/// The replacement is a single rename: a crash at any point leaves either the complete original
+/// file or the complete vacuumed copy in place.
+pub fn finish_full_vacuum(
+    db_path: &Path,
+    db_config_store: &DatabaseConfigStore,
+    stats: &Stats,
+) -> anyhow::Result<bool> {
+    // the server stopped while writing the vacuumed copy, the original file is intact.
+    let _ = std::fs::remove_file(db_path.join(VACUUM_TMP_FILE));
+
+    let vacuumed_path = db_path.join(VACUUMED_FILE);
+    let swapped = if vacuumed_path.try_exists()? {
+        tracing::info!("replacing database file with its vacuumed copy");
+        let data_path = db_path.join("data");
+        // After this, the WAL is empty: the original database file is self-contained, and no
+        // frame can be applied on top of the vacuumed copy.
+        checkpoint_db(&data_path)?;
+        let old_size = data_path.metadata()?.len();
+        let new_size = vacuumed_path.metadata()?.len();
+
+        std::fs::rename(&vacuumed_path, &data_path)?;
+        File::open(db_path)?.sync_all()?;
+        for file in ["data-wal", "data-shm"] {
+            match std::fs::remove_file(db_path.join(file)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+
+        let reclaimed = old_size.saturating_sub(new_size);
+        stats.inc_vacuum_reclaimed(reclaimed / WAL_PAGE_SIZE as u64, reclaimed);
+        tracing::info!("full vacuum reclaimed {reclaimed} bytes");
+        true
+    } else {
+        false
+    };
+
+    let config = db_config_store.get();
+    if config.block_reason.as_deref() == Some(VACUUM_BLOCK_REASON) {
+        let mut config = (*config).clone();
+        config.block_writes = false;
+        config.block_reason = None;
+        db_config_store.store(config)?;
+    }
+
+    Ok(swapped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_conn(db_path: PathBuf) -> Arc<WithConnection> {
+        Arc::new(
+            move |f: &mut dyn FnMut(&rusqlite::Connection) -> anyhow::Result<()>| {
+                let conn = rusqlite::Connection::open(db_path.join("data"))?;
+                conn.execute_batch("PRAGMA journal_mode = WAL")?;
+                f(&conn)
+            },
+        )
+    }
+
+    fn fill_and_delete(with_conn: &WithConnection) {
+        with_conn(&mut |conn| {
+            conn.execute_batch(
+                "CREATE TABLE test (x);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+                INSERT INTO test SELECT randomblob(1000) FROM n;
+                DELETE FROM test;",
+            )?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn count(with_conn: &WithConnection, sql: &str) -> u64 {
+        let mut count = 0;
+        with_conn(&mut |conn| {
+            count = conn.query_row(sql, (), |row| row.get(0))?;
+            Ok(())
+        })
+        .unwrap();
+        count
+    }
+
+    #[test]
+    fn incremental_vacuum_reclaims_pages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let with_conn = with_conn(tmp.path().to_path_buf());
+        enable_incremental_vacuum(&*with_conn).unwrap();
+        assert_eq!(count(&*with_conn, "PRAGMA auto_vacuum"), 2);
+        fill_and_delete(&*with_conn);
+        assert!(count(&*with_conn, "PRAGMA freelist_count") > 0);
+
+        let stats = Stats::default();
+        let vacuum = Vacuum::new(
+            tmp.path().to_path_buf(),
+            true,
+            with_conn,
+            Arc::new(DatabaseConfigStore::new_test()),
+            stats.clone(),
+        );
+        vacuum.run_incremental(10, None).unwrap();
+
+        assert_eq!(count(&*vacuum.with_conn, "PRAGMA freelist_count"), 0);
+        assert!(stats.vacuum_pages_reclaimed() > 0);
+    }
+
+    #[test]
+    fn full_vacuum_swap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_config_store = Arc::new(DatabaseConfigStore::load(tmp.path()).unwrap());
+        let with_conn = with_conn(tmp.path().to_path_buf());
+        fill_and_delete(&*with_conn);
+        let size_before = tmp.path().join("data").metadata().unwrap().len();
+
+        let stats = Stats::default();
+        let vacuum = Vacuum::new(
+            tmp.path().to_path_buf(),
+            false,
+            with_conn,
+            db_config_store.clone(),
+            stats.clone(),
+        );
+        vacuum.run_full().unwrap();
+        assert!(db_config_store.get().block_writes);
+        // the original file is untouched until the swap
+        assert!(tmp.path().join(VACUUMED_FILE).exists());
+        assert_eq!(
+            tmp.path().join("data").metadata().unwrap().len(),
+            size_before
+        );
+
+        assert!(finish_full_vacuum(tmp.path(), &db_config_store, &stats).unwrap());
+        assert!(!tmp.path().join(VACUUMED_FILE).exists());
+        assert!(tmp.path().join("data").metadata().unwrap().len() < size_before);
+        assert!(!db_config_store.get().block_writes);
+        assert!(stats.vacuum_bytes_reclaimed() > 0);
+        assert_eq!(count(&*vacuum.with_conn, "SELECT count(*) FROM test"), 0);
+    }
+
+    #[test]
+    fn full_vacuum_waits_for_open_write_transactions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_config_store = Arc::new(DatabaseConfigStore::load(tmp.path()).unwrap());
+        let with_conn = with_conn(tmp.path().to_path_buf());
+        fill_and_delete(&*with_conn);
+
+        // a transaction that wrote before the writes were blocked
+        let writer = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        writer
+            .execute_batch("BEGIN; INSERT INTO test VALUES ('committed late');")
+            .unwrap();
+
+        let vacuum = Arc::new(Vacuum::new(
+            tmp.path().to_path_buf(),
+            false,
+            with_conn,
+            db_config_store.clone(),
+            Stats::default(),
+        ));
+        let handle = std::thread::spawn({
+            let vacuum = vacuum.clone();
+            move || vacuum.run_full()
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!handle.is_finished());
+        writer.execute_batch("COMMIT").unwrap();
+        handle.join().unwrap().unwrap();
+
+        assert!(finish_full_vacuum(tmp.path(), &db_config_store, &Stats::default()).unwrap());
+        assert_eq!(count(&*vacuum.with_conn, "SELECT count(*) FROM test"), 1);
+    }
+
+    #[tokio::test]
+    async fn full_vacuum_restart_is_not_lost() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_config_store = Arc::new(DatabaseConfigStore::load(tmp.path()).unwrap());
+        let with_conn = with_conn(tmp.path().to_path_buf());
+        fill_and_delete(&*with_conn);
+
+        let vacuum = Vacuum::new(
+            tmp.path().to_path_buf(),
+            false,
+            with_conn,
+            db_config_store,
+            Stats::default(),
+        );
+        // the restart is requested before anyone waits for it
+        vacuum.run_full().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), RESTART.notified())
+            .await
+            .expect("the restart was lost");
+    }
+
+    #[test]
+    fn interrupted_vacuum_into_keeps_original() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_config_store = DatabaseConfigStore::load(tmp.path()).unwrap();
+        let with_conn = with_conn(tmp.path().to_path_buf());
+        fill_and_delete(&*with_conn);
+        std::fs::write(tmp.path().join(VACUUM_TMP_FILE), b"partial copy").unwrap();
+
+        assert!(!finish_full_vacuum(tmp.path(), &db_config_store, &Stats::default()).unwrap());
+        assert!(!tmp.path().join(VACUUM_TMP_FILE).exists());
+        assert_eq!(count(&*with_conn, "SELECT count(*) FROM test"), 0);
+    }
+}
@@ -1,17 +1,20 @@
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use parking_lot::Mutex as PMutex;
 use rusqlite::types::ValueRef;
 use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
-use tokio::sync::{watch, Mutex};
+use tokio::sync::{watch, Mutex, Semaphore, SemaphorePermit};
 use tonic::transport::Channel;
 use uuid::Uuid;
 
-use crate::auth::{Authenticated, Authorized};
+use crate::auth::{self, Authenticated, Authorized, Identity};
+use crate::connections;
 use crate::error::Error;
 use crate::query::Value;
-use crate::query_analysis::State;
+use crate::query_analysis::{State, StmtKind};
 use crate::query_result_builder::{
     Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
@@ -19,22 +22,140 @@ use crate::replication::FrameNo;
 use crate::rpc::proxy::rpc::proxy_client::ProxyClient;
 use crate::rpc::proxy::rpc::query_result::RowResult;
 use crate::rpc::proxy::rpc::{DisconnectMessage, ExecuteResults};
+use crate::rpc::proxy::{client_identity, replication_index_conflict_from_status, IdentityKey};
 use crate::stats::Stats;
+use crate::utils::backoff::{BackoffPolicy, Reconnect};
+use crate::utils::shared::Shared;
 use crate::Result;
 
 use super::config::DatabaseConfigStore;
+use super::query_stats::QueryStats;
+use super::settings::{AllowedStatementClasses, SessionConfig};
 use super::Program;
 use super::{factory::DbFactory, libsql::LibSqlDb, Database, DescribeResult};
 
 #[derive(Clone)]
 pub struct WriteProxyDbFactory {
-    client: ProxyClient<Channel>,
+    /// One client per channel to the primary. Each connection of the replica is pinned to one of
+    /// them, round-robin, and its session on the primary is multiplexed with the others.
+    clients: Vec<ProxyClient<Channel>>,
+    next_client: Arc<AtomicUsize>,
+    queue: Arc<WriteQueue>,
     db_path: PathBuf,
     extensions: Vec<PathBuf>,
     stats: Stats,
     config_store: Arc<DatabaseConfigStore>,
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
     max_response_size: u64,
+    query_stats: Option<Arc<QueryStats>>,
+    /// Replaced when the configuration is reloaded, for the connections opened after.
+    session_config: Shared<SessionConfig>,
+    /// Whether the connections to the local database are read-only.
+    read_only: bool,
+    link: Arc<PMutex<PrimaryLink>>,
+    /// Whether reads fail until the replica has replicated the database.
+    wait_for_primary: bool,
+    identity_key: Option<IdentityKey>,
+    /// Whether the deferred transactions that only read are served by the replica.
+    local_read_txns: bool,
+}
+
+/// Whether the primary can be reached, shared by the connections of the replica: while the primary
+/// is unreachable, the programs are not sent until the backoff delay has elapsed, and fail right
+/// away instead.
+struct PrimaryLink {
+    reconnect: Reconnect,
+    /// When the next program may be sent to the primary, if it's unreachable.
+    retry_at: Option<Instant>,
+}
+
+impl PrimaryLink {
+    fn new(backoff: BackoffPolicy) -> Self {
+        Self {
+            reconnect: Reconnect::new("primary (write proxy)", backoff),
+            retry_at: None,
+        }
+    }
+
+    /// How long until the next program may be sent to the primary, if it's still too early.
+    fn retry_in(&self) -> Option<Duration> {
+        let retry_in = self.retry_at?.saturating_duration_since(Instant::now());
+        (!retry_in.is_zero()).then_some(retry_in)
+    }
+
+    fn succeeded(&mut self) {
+        self.reconnect.succeeded();
+        self.retry_at = None;
+    }
+
+    /// Returns the delay before the next program is sent to the primary.
+    fn failed(&mut self, error: &tonic::Status) -> Duration {
+        let delay = self.reconnect.failed(error);
+        self.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+}
+
+/// Limits of the programs proxied to the primary by the connections of a replica.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteProxyLimits {
+    /// Programs sent to the primary at once, over all the channels.
+    pub max_in_flight: usize,
+    /// Programs waiting for their turn to be sent, beyond which they are rejected.
+    pub max_queued: usize,
+}
+
+impl Default for WriteProxyLimits {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 128,
+            max_queued: 1024,
+        }
+    }
+}
+
+/// Queues the programs proxied to the primary once `max_in_flight` of them are in flight.
+struct WriteQueue {
+    permits: Semaphore,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+/// Counts a program as queued until it is dropped, even if the program is cancelled.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WriteQueue {
+    fn new(limits: WriteProxyLimits) -> Self {
+        Self {
+            permits: Semaphore::new(limits.max_in_flight),
+            queued: AtomicUsize::new(0),
+            max_queued: limits.max_queued,
+        }
+    }
+
+    /// Waits for the turn of a program, which lasts until the permit is dropped.
+    async fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Ok(permit);
+        }
+        let queued = Queued(&self.queued);
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+            return Err(Error::PrimaryWriteQueueFull);
+        }
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        drop(queued);
+        Ok(permit)
+    }
 }
 
 impl WriteProxyDbFactory {
@@ -42,22 +163,43 @@ impl WriteProxyDbFactory {
     pub fn new(
         db_path: PathBuf,
         extensions: Vec<PathBuf>,
-        channel: Channel,
+        channels: Vec<Channel>,
         uri: tonic::transport::Uri,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
         max_response_size: u64,
+        query_stats: Option<Arc<QueryStats>>,
+        session_config: impl Into<Shared<SessionConfig>>,
+        read_only: bool,
+        backoff: BackoffPolicy,
+        wait_for_primary: bool,
+        limits: WriteProxyLimits,
+        identity_key: Option<IdentityKey>,
+        local_read_txns: bool,
     ) -> Self {
-        let client = ProxyClient::with_origin(channel, uri);
+        assert!(!channels.is_empty(), "no channel to the primary");
+        let clients = channels
+            .into_iter()
+            .map(|channel| ProxyClient::with_origin(channel, uri.clone()))
+            .collect();
         Self {
-            client,
+            clients,
+            next_client: Default::default(),
+            queue: Arc::new(WriteQueue::new(limits)),
             db_path,
             extensions,
             stats,
             config_store,
             applied_frame_no_receiver,
             max_response_size,
+            query_stats,
+            session_config: session_config.into(),
+            read_only,
+            link: Arc::new(PMutex::new(PrimaryLink::new(backoff))),
+            wait_for_primary,
+            identity_key,
+            local_read_txns,
         }
     }
 }
@@ -66,8 +208,9 @@ impl WriteProxyDbFactory {
 impl DbFactory for WriteProxyDbFactory {
     type Db = WriteProxyDatabase;
     async fn create(&self) -> Result<Self::Db> {
+        let client = self.next_client.fetch_add(1, Ordering::Relaxed) % self.clients.len();
         let db = WriteProxyDatabase::new(
-            self.client.clone(),
+            self.clients[client].clone(),
             self.db_path.clone(),
             self.extensions.clone(),
             self.stats.clone(),
@@ -76,6 +219,14 @@ impl DbFactory for WriteProxyDbFactory {
             QueryBuilderConfig {
                 max_size: Some(self.max_response_size),
             },
+            self.query_stats.clone(),
+            self.session_config.get(),
+            self.read_only,
+            self.link.clone(),
+            self.wait_for_primary,
+            self.queue.clone(),
+            self.identity_key.clone(),
+            self.local_read_txns,
         )
         .await?;
         Ok(db)
@@ -85,6 +236,8 @@ impl DbFactory for WriteProxyDbFactory {
 pub struct WriteProxyDatabase {
     read_db: LibSqlDb,
     write_proxy: ProxyClient<Channel>,
+    /// State of the session on the primary, as of its last reply, which decides whether the next
+    /// program may run on the replica.
     state: Mutex<State>,
     client_id: Uuid,
     /// FrameNo of the last write performed by this connection on the primary.
@@ -94,6 +247,52 @@ pub struct WriteProxyDatabase {
     /// Notifier from the repliator of the currently applied frameno
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
     builder_config: QueryBuilderConfig,
+    /// Sequence number of the next request sent to the primary, which the primary uses to answer
+    /// the retries of a request without executing it twice.
+    next_sequence_no: AtomicU64,
+    /// Checked before the programs are proxied, like on the primary.
+    allowed_statement_classes: AllowedStatementClasses,
+    link: Arc<PMutex<PrimaryLink>>,
+    wait_for_primary: bool,
+    queue: Arc<WriteQueue>,
+    /// Signs the identity of the clients whose programs are proxied.
+    identity_key: Option<IdentityKey>,
+    local_read_txns: bool,
+    /// Whether the session is in a transaction served by the replica. Otherwise, the programs of
+    /// a transaction are all proxied to the same session on the primary, reads included, until the
+    /// transaction ends.
+    in_local_txn: AtomicBool,
+}
+
+/// Number of times a request is sent again to the primary when its reply is lost.
+const MAX_PROXY_RETRIES: u32 = 3;
+
+/// Whether the request may have been executed by the primary, with its reply lost on the way.
+fn is_retryable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::Cancelled
+    )
+}
+
+/// Whether the transaction that `pgm` opens is begun by a deferred `BEGIN` or a `SAVEPOINT`, which
+/// don't announce a write.
+fn begins_deferred_txn(pgm: &Program) -> bool {
+    pgm.steps()
+        .iter()
+        .map(|step| &step.query.stmt)
+        .find(|stmt| matches!(stmt.kind, StmtKind::TxnBegin | StmtKind::Savepoint))
+        .map_or(false, |stmt| stmt.is_deferred_begin())
+}
+
+/// Whether the transaction that `pgm` opens is begun by a `BEGIN IMMEDIATE` or `BEGIN EXCLUSIVE`,
+/// which takes the write lock: such a program is an atomic batch, executed on the primary as a whole.
+fn begins_immediate_txn(pgm: &Program) -> bool {
+    pgm.steps()
+        .iter()
+        .map(|step| &step.query.stmt)
+        .find(|stmt| matches!(stmt.kind, StmtKind::TxnBegin | StmtKind::Savepoint))
+        .map_or(false, |stmt| !stmt.is_deferred_begin())
 }
 
 fn execute_results_to_builder<B: QueryResultBuilder>(
@@ -129,19 +328,26 @@ fn execute_results_to_builder<B: QueryResultBuilder>(
             }
             Some(RowResult::Error(err)) => {
                 builder.begin_step()?;
-                builder.step_error(Error::RpcQueryError(err))?;
+                builder.step_error(Error::from(err))?;
+                builder.finish_step(0, None)?;
+            }
+            // a step skipped by the primary, after a failed one
+            None => {
+                builder.begin_step()?;
                 builder.finish_step(0, None)?;
             }
-            None => (),
         }
     }
 
+    builder.proxied()?;
+    builder.replication_index(execute_result.current_frame_no)?;
     builder.finish()?;
 
     Ok(builder)
 }
 
 impl WriteProxyDatabase {
+    #[allow(clippy::too_many_arguments)]
     async fn new(
         write_proxy: ProxyClient<Channel>,
         path: PathBuf,
@@ -150,6 +356,14 @@ impl WriteProxyDatabase {
         config_store: Arc<DatabaseConfigStore>,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
         builder_config: QueryBuilderConfig,
+        query_stats: Option<Arc<QueryStats>>,
+        session_config: SessionConfig,
+        read_only: bool,
+        link: Arc<PMutex<PrimaryLink>>,
+        wait_for_primary: bool,
+        queue: Arc<WriteQueue>,
+        identity_key: Option<IdentityKey>,
+        local_read_txns: bool,
     ) -> Result<Self> {
         let read_db = LibSqlDb::new(
             path,
@@ -159,6 +373,12 @@ impl WriteProxyDatabase {
             stats,
             config_store,
             builder_config,
+            None,
+            query_stats,
+            None,
+            Some(applied_frame_no_receiver.clone()),
+            session_config,
+            read_only,
         )
         .await?;
         Ok(Self {
@@ -169,6 +389,14 @@ impl WriteProxyDatabase {
             last_write_frame_no: PMutex::new(FrameNo::MAX),
             applied_frame_no_receiver,
             builder_config,
+            next_sequence_no: AtomicU64::new(1),
+            allowed_statement_classes: session_config.allowed_statement_classes,
+            link,
+            wait_for_primary,
+            queue,
+            identity_key,
+            local_read_txns,
+            in_local_txn: AtomicBool::new(false),
         })
     }
 
@@ -179,19 +407,50 @@ impl WriteProxyDatabase {
         auth: Authenticated,
         builder: B,
     ) -> Result<(B, State)> {
+        if let Some(retry_in) = self.link.lock().retry_in() {
+            *state = State::Invalid;
+            return Err(Error::PrimaryUnreachable(retry_in));
+        }
+        // the state is left as is if the queue is full: the program didn't reach the primary
+        let _permit = self.queue.acquire().await?;
         let mut client = self.write_proxy.clone();
         let authorized: Option<i32> = match auth {
             Authenticated::Anonymous => None,
             Authenticated::Authorized(Authorized::ReadOnly) => Some(0),
             Authenticated::Authorized(Authorized::FullAccess) => Some(1),
         };
-        let req = crate::rpc::proxy::rpc::ProgramReq {
+        let expected_replication_index = pgm.expected_replication_index;
+        // a client that the replica didn't authenticate is explicitly anonymous
+        let identity = auth::current_identity().unwrap_or_else(Identity::anonymous);
+        let source_ip = connections::current()
+            .and_then(|c| c.peer_addr())
+            .map(|addr| addr.ip());
+        let mut req = crate::rpc::proxy::rpc::ProgramReq {
             client_id: self.client_id.to_string(),
             pgm: Some(pgm.into()),
             authorized,
+            sequence_no: self.next_sequence_no.fetch_add(1, Ordering::Relaxed),
+            identity: Some(client_identity(&identity, source_ip)),
+        };
+        if let Some(key) = &self.identity_key {
+            key.sign(&mut req);
+        }
+        // the retries carry the same sequence number, so the primary replays its reply if it
+        // executed the request already, even in the middle of a transaction.
+        let mut retries = 0;
+        let res = loop {
+            match client.execute(req.clone()).await {
+                Err(e) if is_retryable(&e) && retries < MAX_PROXY_RETRIES => {
+                    retries += 1;
+                    tracing::warn!("proxied request {} failed ({e}), retrying", req.sequence_no);
+                    tokio::time::sleep(Duration::from_millis(100) * retries).await;
+                }
+                res => break res,
+            }
         };
-        match client.execute(req).await {
+        match res {
             Ok(r) => {
+                self.link.lock().succeeded();
                 let execute_result = r.into_inner();
                 *state = execute_result.state().into();
                 let current_frame_no = execute_result.current_frame_no;
@@ -205,11 +464,40 @@ impl WriteProxyDatabase {
                 // Set state to invalid, so next call is sent to remote, and we have a chance
                 // to recover state.
                 *state = State::Invalid;
-                Err(Error::RpcQueryExecutionError(e))
+                if e.code() == tonic::Code::Unavailable {
+                    let retry_in = self.link.lock().failed(&e);
+                    return Err(Error::PrimaryUnreachable(retry_in));
+                }
+                let conflict = expected_replication_index
+                    .and_then(|expected| replication_index_conflict_from_status(&e, expected));
+                Err(conflict.unwrap_or(Error::RpcQueryExecutionError(e)))
             }
         }
     }
 
+    /// Executes a program of a transaction served by the replica. The transaction reads a snapshot
+    /// of the replica, which may be behind the primary, so it can't write.
+    async fn execute_in_local_txn<B: QueryResultBuilder>(
+        &self,
+        pgm: Program,
+        auth: Authenticated,
+        builder: B,
+    ) -> Result<(B, State)> {
+        let pgm = self.read_db.classify_program(pgm).await?;
+        let principal = auth::current_identity().and_then(|identity| identity.principal);
+        self.allowed_statement_classes
+            .check(auth, principal.as_deref(), &pgm)?;
+        if !pgm.is_read_only() {
+            self.in_local_txn.store(false, Ordering::Relaxed);
+            self.read_db.rollback(auth).await?;
+            return Err(Error::WriteInReadTransaction);
+        }
+        let (builder, state) = self.read_db.execute_program(pgm, auth, builder).await?;
+        self.in_local_txn
+            .store(state == State::Txn, Ordering::Relaxed);
+        Ok((builder, state))
+    }
+
     fn update_last_write_frame_no(&self, new_frame_no: FrameNo) {
         let mut last_frame_no = self.last_write_frame_no.lock();
         if *last_frame_no == FrameNo::MAX || new_frame_no > *last_frame_no {
@@ -217,6 +505,14 @@ impl WriteProxyDatabase {
         }
     }
 
+    /// Fails the reads of a replica that waits for its primary before it has replicated anything.
+    fn check_ready(&self) -> Result<()> {
+        if self.wait_for_primary && *self.applied_frame_no_receiver.borrow() == FrameNo::MAX {
+            return Err(Error::ReplicaNotReady);
+        }
+        Ok(())
+    }
+
     /// wait for the replicator to have caught up with our current write frame_no
     async fn wait_replication_sync(&self) -> Result<()> {
         let current_frame_no = *self.last_write_frame_no.lock();
@@ -249,7 +545,27 @@ impl Database for WriteProxyDatabase {
         builder: B,
     ) -> Result<(B, State)> {
         let mut state = self.state.lock().await;
-        if *state == State::Init && pgm.is_read_only() {
+        if self.in_local_txn.load(Ordering::Relaxed) {
+            return self.execute_in_local_txn(pgm, auth, builder).await;
+        }
+        // The parser only sees the statements themselves, so SQLite has the last word on whether
+        // the program writes, e.g. through a trigger: such programs are proxied to the primary
+        // rather than failing on the replica.
+        let pgm = if *state == State::Init && pgm.expected_replication_index.is_none() {
+            self.read_db.classify_program(pgm).await?
+        } else {
+            pgm
+        };
+        let principal = auth::current_identity().and_then(|identity| identity.principal);
+        self.allowed_statement_classes
+            .check(auth, principal.as_deref(), &pgm)?;
+        // programs with a precondition are checked against the primary's replication index
+        if *state == State::Init
+            && pgm.is_read_only()
+            && !begins_immediate_txn(&pgm)
+            && pgm.expected_replication_index.is_none()
+        {
+            self.check_ready()?;
             self.wait_replication_sync().await?;
             // We know that this program won't perform any writes. We attempt to run it on the
             // replica. If it leaves an open transaction, then this program is an interactive
@@ -259,6 +575,10 @@ impl Database for WriteProxyDatabase {
                 .execute_program(pgm.clone(), auth, builder)
                 .await?;
             if new_state != State::Init {
+                if self.local_read_txns && begins_deferred_txn(&pgm) {
+                    self.in_local_txn.store(true, Ordering::Relaxed);
+                    return Ok((builder, new_state));
+                }
                 self.read_db.rollback(auth).await?;
                 self.execute_remote(pgm, &mut state, auth, builder).await
             } else {
@@ -270,6 +590,7 @@ impl Database for WriteProxyDatabase {
     }
 
     async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult> {
+        self.check_ready()?;
         self.wait_replication_sync().await?;
         self.read_db.describe(sql, auth).await
     }
@@ -291,14 +612,327 @@ pub mod test {
     use arbitrary::{Arbitrary, Unstructured};
     use rand::Fill;
 
+    use std::path::Path;
+
     use super::*;
+    use crate::hrana::proto;
+    use crate::hrana::result_builder::SingleStatementBuilder;
     use crate::query_result_builder::test::test_driver;
+    use crate::query_result_builder::IgnoreResult;
+    use crate::rpc::proxy::rpc::proxy_server::ProxyServer;
+    use crate::rpc::proxy::ProxyService;
+
+    /// Replaces the data of the values of `res`, arbitrary bytes, with arbitrary bincode encoded
+    /// values.
+    fn with_arbitrary_values(
+        mut res: ExecuteResults,
+        u: &mut Unstructured,
+    ) -> arbitrary::Result<ExecuteResults> {
+        for result in res.results.iter_mut() {
+            let Some(RowResult::Row(ref mut rows)) = result.row_result else {
+                continue
+            };
+            for value in rows.rows.iter_mut().flat_map(|row| row.values.iter_mut()) {
+                value.data = bincode::serialize(&crate::query::Value::arbitrary(u)?).unwrap();
+            }
+        }
+
+        Ok(res)
+    }
+
+    #[test]
+    fn unreachable_primary_is_not_retried_before_backoff() {
+        let mut link = PrimaryLink::new(BackoffPolicy {
+            initial: Duration::from_secs(10),
+            ..Default::default()
+        });
+        assert_eq!(link.retry_in(), None);
+
+        let delay = link.failed(&tonic::Status::unavailable("connection refused"));
+        assert!(delay >= Duration::from_secs(5));
+        let retry_in = link.retry_in().unwrap();
+        assert!(retry_in <= delay);
+
+        link.succeeded();
+        assert_eq!(link.retry_in(), None);
+    }
+
+    /// Serves the write proxy of a primary, which delays its replies by `reply_delay`, and
+    /// returns the factory of the connections of a replica to it.
+    async fn replica_of_distant_primary(
+        dir: &Path,
+        reply_delay: Duration,
+        limits: WriteProxyLimits,
+    ) -> WriteProxyDbFactory {
+        let primary_path = dir.join("primary");
+        let replica_path = dir.join("replica");
+        std::fs::create_dir_all(&primary_path).unwrap();
+        std::fs::create_dir_all(&replica_path).unwrap();
+
+        let factory: Arc<dyn DbFactory<Db = LibSqlDb>> = Arc::new(move || {
+            LibSqlDb::new(
+                primary_path.clone(),
+                Vec::new(),
+                &TRANSPARENT_METHODS,
+                (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                QueryBuilderConfig::default(),
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                false,
+            )
+        });
+        let (_, new_frame_notifier) = watch::channel(0);
+        let service = ProxyService::new(factory, new_frame_notifier).with_reply_delay(reply_delay);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            Some((listener.accept().await.map(|(stream, _)| stream), listener))
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ProxyServer::new(service))
+                .serve_with_incoming(Box::pin(incoming)),
+        );
+
+        let channels = (0..4)
+            .map(|_| Channel::from_shared(addr.clone()).unwrap().connect_lazy())
+            .collect();
+        let (_, applied_frame_no_receiver) = watch::channel(FrameNo::MAX);
+        WriteProxyDbFactory::new(
+            replica_path,
+            Vec::new(),
+            channels,
+            addr.parse().unwrap(),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            applied_frame_no_receiver,
+            u64::MAX,
+            None,
+            SessionConfig::default(),
+            false,
+            BackoffPolicy::default(),
+            false,
+            limits,
+            None,
+            false,
+        )
+    }
+
+    async fn execute(db: &WriteProxyDatabase, stmts: &[&str]) -> Result<State> {
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let (_, state) = db
+            .execute_program(Program::seq(stmts), auth, IgnoreResult)
+            .await?;
+        Ok(state)
+    }
+
+    fn primary_count(dir: &Path) -> i64 {
+        rusqlite::Connection::open(dir.join("primary").join("data"))
+            .unwrap()
+            .query_row("select count(*) from t", (), |row| row.get(0))
+            .unwrap()
+    }
 
-    /// generate an arbitraty rpc value. see build.rs for usage.
-    pub fn arbitrary_rpc_value(u: &mut Unstructured) -> arbitrary::Result<Vec<u8>> {
-        let data = bincode::serialize(&crate::query::Value::arbitrary(u)?).unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_writers_are_proxied_in_parallel() {
+        const WRITERS: u32 = 16;
+        const REPLY_DELAY: Duration = Duration::from_millis(200);
+        let tmp = tempfile::tempdir().unwrap();
+        let factory =
+            replica_of_distant_primary(tmp.path(), REPLY_DELAY, WriteProxyLimits::default()).await;
+        let db = factory.create().await.unwrap();
+        execute(&db, &["create table t (x)"]).await.unwrap();
 
-        Ok(data)
+        let start = Instant::now();
+        let writers = (0..WRITERS)
+            .map(|_| {
+                let factory = factory.clone();
+                tokio::spawn(async move {
+                    let db = factory.create().await?;
+                    execute(&db, &["insert into t values (42)"]).await
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            assert_eq!(writer.await.unwrap().unwrap(), State::Init);
+        }
+        // one writer at a time would take `WRITERS * REPLY_DELAY`
+        let elapsed = start.elapsed();
+        assert!(elapsed < REPLY_DELAY * WRITERS / 4, "{elapsed:?}");
+        assert_eq!(primary_count(tmp.path()), WRITERS as i64);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transactions_stay_on_their_session() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory =
+            replica_of_distant_primary(tmp.path(), Duration::ZERO, WriteProxyLimits::default())
+                .await;
+        let db = factory.create().await.unwrap();
+        execute(&db, &["create table t (x)"]).await.unwrap();
+
+        // the other connections are spread over the other channels
+        let others = [
+            factory.create().await.unwrap(),
+            factory.create().await.unwrap(),
+        ];
+        assert_eq!(execute(&db, &["begin"]).await.unwrap(), State::Txn);
+        for other in &others {
+            assert_eq!(execute(other, &["begin"]).await.unwrap(), State::Txn);
+        }
+        assert_eq!(
+            execute(&db, &["insert into t values (42)"]).await.unwrap(),
+            State::Txn
+        );
+        assert_eq!(primary_count(tmp.path()), 0);
+        for other in &others {
+            assert_eq!(execute(other, &["rollback"]).await.unwrap(), State::Init);
+        }
+        assert_eq!(execute(&db, &["commit"]).await.unwrap(), State::Init);
+        assert_eq!(primary_count(tmp.path()), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writes_beyond_the_queue_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let limits = WriteProxyLimits {
+            max_in_flight: 1,
+            max_queued: 1,
+        };
+        let factory =
+            replica_of_distant_primary(tmp.path(), Duration::from_millis(500), limits).await;
+        let db = factory.create().await.unwrap();
+        execute(&db, &["create table t (x)"]).await.unwrap();
+
+        let mut writers = Vec::new();
+        for _ in 0..3 {
+            let db = factory.create().await.unwrap();
+            writers.push(tokio::spawn(async move {
+                execute(&db, &["insert into t values (42)"]).await
+            }));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut rejected = 0;
+        for writer in writers {
+            match writer.await.unwrap() {
+                Ok(_) => (),
+                Err(Error::PrimaryWriteQueueFull) => rejected += 1,
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+        // one write in flight, one queued
+        assert_eq!(rejected, 1);
+        assert_eq!(primary_count(tmp.path()), 2);
+    }
+
+    /// Runs a single statement, and returns its first value and whether the primary executed it.
+    async fn query(db: &WriteProxyDatabase, stmt: &str) -> Result<(proto::Value, bool)> {
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let (builder, _) = db
+            .execute_program(
+                Program::seq(&[stmt]),
+                auth,
+                SingleStatementBuilder::default(),
+            )
+            .await?;
+        let mut res = builder.into_ret()?;
+        Ok((res.rows.remove(0).remove(0), res.proxied))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transactions_read_their_writes_on_the_primary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory =
+            replica_of_distant_primary(tmp.path(), Duration::ZERO, WriteProxyLimits::default())
+                .await;
+        let db = factory.create().await.unwrap();
+        // the replica doesn't replicate in this test: it never sees the table
+        execute(&db, &["create table t (x)"]).await.unwrap();
+
+        assert_eq!(execute(&db, &["begin"]).await.unwrap(), State::Txn);
+        execute(&db, &["insert into t values (42)"]).await.unwrap();
+        let (count, proxied) = query(&db, "select count(*) from t").await.unwrap();
+        assert!(matches!(count, proto::Value::Integer { value: 1 }));
+        assert!(proxied);
+        assert_eq!(execute(&db, &["commit"]).await.unwrap(), State::Init);
+
+        // outside of a transaction, the reads are served by the replica
+        let (_, proxied) = query(&db, "select 1").await.unwrap();
+        assert!(!proxied);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deferred_read_transactions_stay_local() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut factory =
+            replica_of_distant_primary(tmp.path(), Duration::ZERO, WriteProxyLimits::default())
+                .await;
+        factory.local_read_txns = true;
+        let db = factory.create().await.unwrap();
+        execute(&db, &["create table t (x)"]).await.unwrap();
+
+        assert_eq!(execute(&db, &["begin"]).await.unwrap(), State::Txn);
+        let (_, proxied) = query(&db, "select 1").await.unwrap();
+        assert!(!proxied);
+        assert!(matches!(
+            execute(&db, &["insert into t values (42)"]).await,
+            Err(Error::WriteInReadTransaction)
+        ));
+        // the local transaction was rolled back
+        assert_eq!(execute(&db, &["select 1"]).await.unwrap(), State::Init);
+        assert_eq!(primary_count(tmp.path()), 0);
+
+        // an immediate transaction announces a write
+        assert_eq!(
+            execute(&db, &["begin immediate"]).await.unwrap(),
+            State::Txn
+        );
+        execute(&db, &["insert into t values (42)"]).await.unwrap();
+        let (_, proxied) = query(&db, "select count(*) from t").await.unwrap();
+        assert!(proxied);
+        assert_eq!(execute(&db, &["commit"]).await.unwrap(), State::Init);
+        assert_eq!(primary_count(tmp.path()), 1);
+    }
+
+    #[test]
+    fn results_stay_in_their_position() {
+        use crate::query_result_builder::{StepResult, StepResultsBuilder};
+        use crate::rpc::proxy::rpc::{self, QueryResult, ResultRows};
+
+        let ok = QueryResult {
+            row_result: Some(RowResult::Row(ResultRows::default())),
+        };
+        let res = ExecuteResults {
+            results: vec![
+                ok.clone(),
+                QueryResult {
+                    row_result: Some(RowResult::Error(rpc::Error::default())),
+                },
+                QueryResult { row_result: None },
+                ok,
+            ],
+            ..Default::default()
+        };
+        let builder = execute_results_to_builder(
+            res,
+            StepResultsBuilder::default(),
+            &QueryBuilderConfig::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            builder.into_ret()[..],
+            [
+                StepResult::Ok,
+                StepResult::Err(_),
+                StepResult::Skipped,
+                StepResult::Ok
+            ]
+        ));
     }
 
     /// In this test, we generate random ExecuteResults, and ensures that the `execute_results_to_builder` drives the builder FSM correctly.
@@ -309,6 +943,7 @@ pub mod test {
             data.try_fill(&mut rand::thread_rng()).unwrap();
             let mut un = Unstructured::new(&data);
             let res = ExecuteResults::arbitrary(&mut un).unwrap();
+            let res = with_arbitrary_values(res, &mut un).unwrap();
             execute_results_to_builder(res, b, &QueryBuilderConfig::default())
         });
     }
@@ -1,12 +1,21 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::database::constraint::ConstraintViolation;
+use crate::database::settings::{ResultLimit, SettingsError};
+use crate::query_analysis::{DenyRule, InlineLiteral, StmtClass};
 use crate::query_result_builder::QueryResultBuilderError;
+use crate::replication::FrameNo;
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("LibSQL failed to bind provided query parameters: `{0}`")]
     LibSqlInvalidQueryParams(anyhow::Error),
-    #[error("Transaction timed-out")]
-    LibSqlTxTimeout,
+    #[error("Transaction timed-out: it was rolled back after being idle for {}ms", .0.as_millis())]
+    LibSqlTxTimeout(Duration),
     #[error("Server can't handle additional transactions")]
     LibSqlTxBusy,
     #[error(transparent)]
@@ -37,6 +46,279 @@ pub enum Error {
     Blocked(Option<String>),
     #[error(transparent)]
     Json(#[from] serde_json::Error),
+    #[error("Internal Error: query execution panicked")]
+    QueryPanicked,
+    #[error(transparent)]
+    InvalidSetting(#[from] SettingsError),
+    #[error("Statement timed out after {}ms", .0.as_millis())]
+    StatementTimeout(Duration),
+    #[error("Statement was cancelled, its results are no longer wanted")]
+    StatementCancelled,
+    #[error("Query interrupted by administrator, the transaction was rolled back")]
+    InterruptedByAdmin,
+    #[error("Statement must be parameterized: {0}")]
+    InlineLiteral(InlineLiteral),
+    #[error("Statement denied by the `{0}` rule")]
+    StatementDenied(DenyRule),
+    #[error("Statement class not allowed: `{0}` statements can't be run with these credentials")]
+    StatementClassNotAllowed(StmtClass),
+    #[error("Statement {} of the batch failed, the batch was rolled back and the transaction is still open: {source}", .step + 1)]
+    BatchRolledBack { step: usize, source: Box<Error> },
+    #[error("The database changed since replication index {expected}, it is now at replication index {current}")]
+    ReplicationIndexConflict { expected: FrameNo, current: FrameNo },
+    #[error("`expected_replication_index` can't be used {0}")]
+    ReplicationIndexUnsupported(&'static str),
+    #[error("Storage is degraded, writes are rejected until it is repaired: {0}")]
+    StorageDegraded(String),
+    #[error("{message}")]
+    ConstraintViolation {
+        message: String,
+        violation: ConstraintViolation,
+    },
+    #[error("{}", deferred_violation_message(.0))]
+    DeferredConstraintViolation(Vec<ConstraintViolation>),
+    #[error("Replica is read-only, use the primary")]
+    ReadOnlyReplica,
+    #[error("The results of the statement were truncated, they exceed the limit of {0}")]
+    ResultLimitExceeded(ResultLimit),
+    #[error("The primary is unreachable, the next attempt to reach it is in {}ms", .0.as_millis())]
+    PrimaryUnreachable(Duration),
+    #[error("The replica has not replicated the database from its primary yet")]
+    ReplicaNotReady,
+    #[error("Too many writes are waiting to be sent to the primary")]
+    PrimaryWriteQueueFull,
+    #[error("Too many queries from this client, retry in {}ms", .0.as_millis())]
+    RateLimited(Duration),
+    #[error("The transaction reads from the replica and can't write, begin it with `BEGIN IMMEDIATE` to write")]
+    WriteInReadTransaction,
+    #[error("ATTACH not allowed: {0}")]
+    AttachNotAllowed(String),
+    #[error(
+        "Attached databases are read-only: their changes would be neither replicated nor backed up"
+    )]
+    AttachedDatabaseReadOnly,
+    #[error("The database reached its size quota of {0} bytes, it only accepts the writes that don't grow it")]
+    SizeQuotaExceeded(u64),
+}
+
+impl Error {
+    /// Whether the error is a failure of the server rather than of the request. The details of
+    /// these errors, like file paths or the addresses of the other nodes, must not reach clients:
+    /// see [`Error::user_error`].
+    pub fn is_internal(&self) -> bool {
+        match self {
+            Self::IOError(_)
+            | Self::Internal(_)
+            | Self::ReplicatorExited
+            | Self::Json(_)
+            | Self::QueryPanicked
+            | Self::BuilderError(QueryResultBuilderError::Internal(_)) => true,
+            Self::RusqliteError(e) => !matches!(
+                e,
+                rusqlite::Error::SqliteFailure(..) | rusqlite::Error::SqlInputError { .. }
+            ),
+            // the other statuses don't come from the primary, but from the transport
+            Self::RpcQueryExecutionError(status) => !matches!(
+                status.code(),
+                tonic::Code::PermissionDenied
+                    | tonic::Code::InvalidArgument
+                    | tonic::Code::FailedPrecondition
+            ),
+            Self::BatchRolledBack { source, .. } => source.is_internal(),
+            _ => false,
+        }
+    }
+
+    /// Stable code of the error, reported to the clients along with its message. The codes of
+    /// the statement errors are those of the Hrana protocol.
+    pub fn code(&self) -> &'static str {
+        use sqld_proto::proxy::v1::error::ErrorCode;
+
+        if self.is_internal() {
+            return "INTERNAL_ERROR";
+        }
+        match self {
+            Self::LibSqlInvalidQueryParams(_) => "ARGS_INVALID",
+            Self::LibSqlTxTimeout(_) => "TRANSACTION_TIMEOUT",
+            Self::LibSqlTxBusy => "TRANSACTION_BUSY",
+            Self::RusqliteError(rusqlite::Error::SqliteFailure(e, _)) => {
+                crate::hrana::stmt::sqlite_error_code(e.code)
+            }
+            Self::RusqliteError(_) => "SQL_INPUT_ERROR",
+            Self::RpcQueryError(e) => match e.code() {
+                ErrorCode::SqlError => "SQL_ERROR",
+                ErrorCode::TxBusy => "TRANSACTION_BUSY",
+                ErrorCode::TxTimeout => "TRANSACTION_TIMEOUT",
+                ErrorCode::Internal => "INTERNAL_ERROR",
+                ErrorCode::ConstraintViolation => "SQLITE_CONSTRAINT",
+                ErrorCode::DeferredConstraintViolation => "TRANSACTION_ROLLED_BACK",
+            },
+            Self::RpcQueryExecutionError(_) => "WRITE_PROXY_ERROR",
+            Self::DbValueError(_) => "VALUE_ERROR",
+            Self::InvalidBatchStep(_) => "INVALID_BATCH_STEP",
+            Self::NotAuthorized(_) => "NOT_AUTHORIZED",
+            Self::DbCreateTimeout => "DB_CREATE_TIMEOUT",
+            Self::BuilderError(_) => "RESPONSE_TOO_LARGE",
+            Self::Blocked(_) => "BLOCKED",
+            Self::InvalidSetting(_) => "INVALID_SETTING",
+            Self::StatementTimeout(_) => "STATEMENT_TIMEOUT",
+            Self::StatementCancelled => "STATEMENT_CANCELLED",
+            Self::InterruptedByAdmin => "INTERRUPTED_BY_ADMIN",
+            Self::InlineLiteral(_) => "INLINE_LITERAL",
+            Self::StatementDenied(_) => "STATEMENT_DENIED",
+            Self::StatementClassNotAllowed(_) => "STATEMENT_CLASS_NOT_ALLOWED",
+            Self::BatchRolledBack { .. } => "BATCH_ROLLED_BACK",
+            Self::ReplicationIndexConflict { .. } => "REPLICATION_INDEX_CONFLICT",
+            Self::ReplicationIndexUnsupported(_) => "REPLICATION_INDEX_UNSUPPORTED",
+            Self::StorageDegraded(_) => "STORAGE_DEGRADED",
+            Self::ConstraintViolation { .. } => "SQLITE_CONSTRAINT",
+            Self::DeferredConstraintViolation(_) => "TRANSACTION_ROLLED_BACK",
+            Self::ReadOnlyReplica => "READ_ONLY_REPLICA",
+            Self::ResultLimitExceeded(_) => "RESULT_LIMIT_EXCEEDED",
+            Self::PrimaryUnreachable(_) => "PRIMARY_UNREACHABLE",
+            Self::ReplicaNotReady => "REPLICA_NOT_READY",
+            Self::PrimaryWriteQueueFull => "PRIMARY_WRITE_QUEUE_FULL",
+            Self::RateLimited(_) => "RATE_LIMITED",
+            Self::WriteInReadTransaction => "WRITE_IN_READ_TRANSACTION",
+            Self::AttachNotAllowed(_) => "ATTACH_NOT_ALLOWED",
+            Self::AttachedDatabaseReadOnly => "ATTACHED_DATABASE_READ_ONLY",
+            Self::SizeQuotaExceeded(_) => "SIZE_QUOTA_EXCEEDED",
+            _ => "INTERNAL_ERROR",
+        }
+    }
+
+    /// The part of the error that can be sent to a client. An internal error is logged with its
+    /// full context, and the client only gets the id under which it was logged.
+    pub fn user_error(&self) -> UserError {
+        if self.is_internal() {
+            return InternalError::log(self).user_error();
+        }
+
+        let message = match self {
+            // the other fields of the status are metadata of the transport
+            Self::RpcQueryExecutionError(status) => status.message().to_string(),
+            e => e.to_string(),
+        };
+        UserError {
+            code: self.code(),
+            message,
+            error_id: None,
+        }
+    }
+
+    /// The constraints violated by the statement that failed with this error.
+    pub fn constraint_violations(&self) -> Vec<ConstraintViolation> {
+        match self {
+            Self::ConstraintViolation { violation, .. } => vec![violation.clone()],
+            Self::DeferredConstraintViolation(violations) => violations.clone(),
+            Self::BatchRolledBack { source, .. } => source.constraint_violations(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Identifies an internal error in the logs of the server, so that the operators can find the
+/// details of the errors reported by the clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(into = "String")]
+pub struct ErrorId(u64);
+
+impl fmt::Display for ErrorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl From<ErrorId> for String {
+    fn from(id: ErrorId) -> Self {
+        id.to_string()
+    }
+}
+
+/// The part of an error that is sent to the clients: a stable code, and a message that doesn't
+/// reveal anything about the server.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UserError {
+    pub code: &'static str,
+    pub message: String,
+    /// The id under which an internal error was logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_id: Option<ErrorId>,
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// An error whose context must stay on the server. It is logged when it is created, and the
+/// clients only get its id.
+#[derive(Debug)]
+pub struct InternalError {
+    pub id: ErrorId,
+    pub context: String,
+}
+
+impl InternalError {
+    /// Logs `error` under a new id.
+    pub fn log(error: impl fmt::Display) -> Self {
+        let id = ErrorId(rand::random());
+        let context = error.to_string();
+        tracing::error!(error_id = %id, "internal error: {context}");
+        Self { id, context }
+    }
+
+    pub fn user_error(&self) -> UserError {
+        UserError {
+            code: "INTERNAL_ERROR",
+            message: format!("Internal error, logged by the server with id {}", self.id),
+            error_id: Some(self.id),
+        }
+    }
+}
+
+/// Longest SQL included in an error message, in characters.
+const MAX_REDACTED_SQL_LEN: usize = 100;
+
+/// Redacts SQL for an error message: the string literals, which may hold the data of the users,
+/// are replaced with `?`, and long statements are truncated.
+pub fn redact_sql(sql: &str) -> String {
+    let mut redacted = String::with_capacity(sql.len().min(MAX_REDACTED_SQL_LEN));
+    let mut chars = sql.chars().peekable();
+    let mut len = 0;
+    while let Some(c) = chars.next() {
+        if len == MAX_REDACTED_SQL_LEN {
+            redacted.push_str("...");
+            break;
+        }
+        if c == '\'' {
+            // a quote in a literal is escaped by doubling it
+            while let Some(c) = chars.next() {
+                if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                    break;
+                }
+            }
+            redacted.push('?');
+        } else {
+            redacted.push(c);
+        }
+        len += 1;
+    }
+
+    redacted
+}
+
+/// Message of the [`Error::DeferredConstraintViolation`] errors.
+pub fn deferred_violation_message(violations: &[ConstraintViolation]) -> String {
+    let violations = violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "Transaction was rolled back, it violates foreign key constraints at commit: {violations}"
+    )
 }
 
 impl From<tokio::sync::oneshot::error::RecvError> for Error {
@@ -52,3 +334,54 @@ impl From<bincode::Error> for Error {
         Self::Internal(other.to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redacted_sql() {
+        assert_eq!(
+            redact_sql("insert into users values ('alice', 'it''s a secret', 42)"),
+            "insert into users values (?, ?, 42)"
+        );
+
+        let long = format!("select {}", "x, ".repeat(100));
+        let redacted = redact_sql(&long);
+        assert!(redacted.ends_with("..."));
+        assert_eq!(redacted.chars().count(), MAX_REDACTED_SQL_LEN + 3);
+    }
+
+    #[test]
+    fn user_errors() {
+        let e = Error::LibSqlTxBusy;
+        let user_error = e.user_error();
+        assert_eq!(user_error.code, "TRANSACTION_BUSY");
+        assert_eq!(user_error.message, e.to_string());
+        assert_eq!(user_error.error_id, None);
+
+        let e = Error::IOError(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "failed to open /var/lib/sqld/data.sqld/data",
+        ));
+        assert!(e.is_internal());
+        let user_error = e.user_error();
+        assert_eq!(user_error.code, "INTERNAL_ERROR");
+        assert!(!user_error.message.contains("/var/lib"));
+        assert!(user_error
+            .message
+            .contains(&user_error.error_id.unwrap().to_string()));
+
+        // the errors returned by the primary are forwarded, without the transport details
+        let e = Error::RpcQueryExecutionError(tonic::Status::permission_denied("not authorized"));
+        assert_eq!(e.user_error().message, "not authorized");
+        let e = Error::RpcQueryExecutionError(tonic::Status::unavailable("10.0.3.7:5001"));
+        assert!(!e.user_error().message.contains("10.0.3.7"));
+
+        let e = Error::BatchRolledBack {
+            step: 1,
+            source: Box::new(Error::QueryPanicked),
+        };
+        assert!(e.is_internal());
+    }
+}
@@ -0,0 +1,471 @@
+//! Guard rails around the hard resets of replicas.
+//!
+//! A replica that can't replicate from its primary (because it is ahead of it, or because the
+//! primary replicates another database) wipes its database and starts from a fresh state. If the
+//! cause of the mismatch persists, for example because the replica is configured with the wrong
+//! primary, the replica would wipe and resync its database in a loop. To prevent that:
+//! - reset requests that arrive while a reset is pending or in progress are coalesced,
+//! - two resets are at least `min_interval` apart,
+//! - after `max_per_hour` resets in the last hour, the replica stops resetting and reports itself
+//!   as unhealthy, until an operator re-arms the resets with the admin API.
+//!
+//! The history of the resets is persisted in the database directory, and survives both resets and
+//! restarts of the process, so that a crash-looping supervisor doesn't defeat the budget.
+//!
+//! A reset only removes the database and the replication state derived from it, see
+//! [`WIPED_FILES`]: the other files of the database directory, such as those placed by an
+//! operator, are kept. Primaries never reset, unless explicitly allowed to.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// Name of the file holding the history of the resets, in the database directory.
+pub const HISTORY_FILE: &str = "hard_resets.json";
+/// Name of the file describing the last reset, in the database directory.
+pub const RESET_REASON_FILE: &str = "reset-reason";
+
+/// The files of the database directory that a hard reset removes: the database file, its WAL, and
+/// the replication state derived from them.
+pub const WIPED_FILES: &[&str] = &[
+    "data",
+    "data-wal",
+    "data-shm",
+    "data-journal",
+    "data.vacuumed",
+    "data.vacuum-tmp",
+    "wallog",
+    "wallog.timestamps",
+    "temp_log",
+    "snapshots",
+    "client_wal_index",
+    "client_logical_index",
+    "snapshot.partial",
+    "snapshot.partial.json",
+    "temp",
+];
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HardResetConfig {
+    /// Minimum time between two resets. Resets requested earlier are delayed.
+    pub min_interval: Duration,
+    /// Maximum number of resets in an hour, after which the replica stops resetting.
+    pub max_per_hour: u32,
+    /// Whether the resets are allowed at all.
+    pub allowed: bool,
+}
+
+impl Default for HardResetConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(60),
+            max_per_hour: 3,
+            allowed: true,
+        }
+    }
+}
+
+/// The content of [`RESET_REASON_FILE`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetMarker {
+    /// Unix timestamp of the reset, in seconds.
+    pub at: u64,
+    pub reason: String,
+    /// The files that were removed.
+    pub wiped: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct History {
+    /// Unix timestamps, in seconds, of the resets of the last hour.
+    #[serde(default)]
+    resets: Vec<u64>,
+    #[serde(default)]
+    last_reason: Option<String>,
+    /// Set when the budget is exhausted, cleared by an operator.
+    #[serde(default)]
+    halted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The reset can proceed.
+    Now,
+    /// The reset must wait for the minimum interval between resets.
+    After(Duration),
+    /// The budget is exhausted: the reset must not happen.
+    Refused,
+}
+
+struct Budget {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    config: HardResetConfig,
+    history: History,
+}
+
+impl Budget {
+    fn load(db_path: &Path, config: HardResetConfig) -> anyhow::Result<Self> {
+        let path = db_path.join(HISTORY_FILE);
+        let history = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => History::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            tmp_path: db_path.join(format!("{HISTORY_FILE}~")),
+            path,
+            config,
+            history,
+        })
+    }
+
+    fn store(&self) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.history)?;
+        fs::write(&self.tmp_path, data)?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn admit(&mut self, now: u64, reason: &str) -> anyhow::Result<Admission> {
+        if !self.config.allowed {
+            tracing::error!(
+                "HARD RESET REFUSED: hard resets are not allowed on this node, set \
+                `--allow-hard-reset true` to allow them. The node is now reported as unhealthy. \
+                Reason of the refused reset: {reason}."
+            );
+            return Ok(Admission::Refused);
+        }
+        if self.history.halted {
+            return Ok(Admission::Refused);
+        }
+
+        self.history
+            .resets
+            .retain(|&at| now.saturating_sub(at) < WINDOW.as_secs());
+        if self.history.resets.len() >= self.config.max_per_hour as usize {
+            tracing::error!(
+                "HARD RESET BUDGET EXHAUSTED: the replica was reset {} times in the last hour, and \
+                will not reset again until resets are re-armed with `POST /v1/hard_reset/rearm` on \
+                the admin API. The replica is now reported as unhealthy. Check the address of the \
+                primary. Reason of the refused reset: {reason}. Reason of the last reset: {}.",
+                self.history.resets.len(),
+                self.history.last_reason.as_deref().unwrap_or("unknown"),
+            );
+            self.history.halted = true;
+            self.store()?;
+            return Ok(Admission::Refused);
+        }
+
+        if let Some(&last) = self.history.resets.last() {
+            let elapsed = Duration::from_secs(now.saturating_sub(last));
+            if elapsed < self.config.min_interval {
+                return Ok(Admission::After(self.config.min_interval - elapsed));
+            }
+        }
+
+        Ok(Admission::Now)
+    }
+
+    fn record(&mut self, now: u64, reason: &str) -> anyhow::Result<()> {
+        self.history.resets.push(now);
+        self.history.last_reason = Some(reason.to_string());
+        self.store()
+    }
+
+    fn rearm(&mut self) -> anyhow::Result<()> {
+        self.history.resets.clear();
+        self.history.halted = false;
+        self.store()
+    }
+}
+
+/// Requests for hard resets, and the budget that throttles them.
+pub struct HardReset {
+    /// The reasons of the reset requests.
+    sender: mpsc::UnboundedSender<String>,
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>,
+    halted: AtomicBool,
+    budget: Mutex<Option<Budget>>,
+}
+
+impl Default for HardReset {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+            halted: AtomicBool::new(false),
+            budget: Mutex::new(None),
+        }
+    }
+}
+
+impl HardReset {
+    /// Loads the history of the resets of the database at `db_path`.
+    pub fn init(&self, db_path: &Path, config: HardResetConfig) -> anyhow::Result<()> {
+        let budget = Budget::load(db_path, config)?;
+        if budget.history.halted {
+            tracing::error!(
+                "hard resets are halted since the reset budget was exhausted, the replica is \
+                reported as unhealthy until resets are re-armed with `POST /v1/hard_reset/rearm` \
+                on the admin API. Reason of the last reset: {}",
+                budget.history.last_reason.as_deref().unwrap_or("unknown"),
+            );
+        }
+        self.halted.store(budget.history.halted, Ordering::Relaxed);
+        *self.budget.lock() = Some(budget);
+        Ok(())
+    }
+
+    /// Requests a hard reset. Requests made while another one is pending are merged into it.
+    pub fn request(&self, reason: impl Into<String>) {
+        // the receiver lives as long as the sender
+        let _ = self.sender.send(reason.into());
+    }
+
+    /// Waits for a hard reset request, and returns its reason.
+    pub async fn requested(&self) -> String {
+        let mut receiver = self.receiver.lock().await;
+        let reason = receiver.recv().await.expect("hard reset sender dropped");
+        while let Ok(other) = receiver.try_recv() {
+            tracing::debug!("a hard reset is already pending, ignoring request: {other}");
+        }
+        reason
+    }
+
+    /// Drops the requests made during a reset: they are about the state that was just wiped.
+    pub fn discard_pending(&self) {
+        if let Ok(mut receiver) = self.receiver.try_lock() {
+            while receiver.try_recv().is_ok() {}
+        }
+    }
+
+    pub fn admit(&self, reason: &str) -> anyhow::Result<Admission> {
+        let mut budget = self.budget.lock();
+        let budget = budget.as_mut().expect("hard reset budget not initialized");
+        let admission = budget.admit(unix_now(), reason)?;
+        if admission == Admission::Refused {
+            self.halted.store(true, Ordering::Relaxed);
+        }
+        Ok(admission)
+    }
+
+    /// Records a reset in the history, before it wipes the database.
+    pub fn record(&self, reason: &str) -> anyhow::Result<()> {
+        let mut budget = self.budget.lock();
+        let budget = budget.as_mut().expect("hard reset budget not initialized");
+        budget.record(unix_now(), reason)
+    }
+
+    /// Clears the history of the resets, and resumes resetting the replica when needed.
+    pub fn rearm(&self) -> anyhow::Result<()> {
+        if let Some(budget) = self.budget.lock().as_mut() {
+            budget.rearm()?;
+        }
+        self.halted.store(false, Ordering::Relaxed);
+        tracing::info!("hard resets re-armed");
+        Ok(())
+    }
+
+    /// Whether the replica is healthy, i.e. it didn't stop resetting because of an exhausted budget.
+    pub fn is_healthy(&self) -> bool {
+        !self.halted.load(Ordering::Relaxed)
+    }
+}
+
+/// Removes the database and its replication state from `db_path`, and describes the reset in
+/// [`RESET_REASON_FILE`]. Returns the names of the removed files.
+pub async fn wipe(db_path: &Path, reason: &str) -> anyhow::Result<Vec<String>> {
+    let mut wiped = Vec::new();
+    for name in WIPED_FILES {
+        let path = db_path.join(name);
+        let res = match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&path).await,
+            Ok(_) => tokio::fs::remove_file(&path).await,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => Err(e),
+        };
+        res.with_context(|| format!("could not remove {}", path.display()))?;
+        tracing::warn!("hard reset: removed {}", path.display());
+        wiped.push(name.to_string());
+    }
+
+    let marker = ResetMarker {
+        at: unix_now(),
+        reason: reason.to_string(),
+        wiped: wiped.clone(),
+    };
+    tokio::fs::write(
+        db_path.join(RESET_REASON_FILE),
+        serde_json::to_vec_pretty(&marker)?,
+    )
+    .await?;
+
+    Ok(wiped)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: HardResetConfig = HardResetConfig {
+        min_interval: Duration::from_secs(60),
+        max_per_hour: 3,
+        allowed: true,
+    };
+
+    #[test]
+    fn throttle_resets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut budget = Budget::load(tmp.path(), CONFIG).unwrap();
+
+        assert_eq!(budget.admit(1000, "mismatch").unwrap(), Admission::Now);
+        budget.record(1000, "mismatch").unwrap();
+
+        // too early
+        assert_eq!(
+            budget.admit(1010, "mismatch").unwrap(),
+            Admission::After(Duration::from_secs(50))
+        );
+        assert_eq!(budget.admit(1060, "mismatch").unwrap(), Admission::Now);
+        budget.record(1060, "mismatch").unwrap();
+        budget.record(1200, "mismatch").unwrap();
+
+        // the budget is exhausted, and stays so once the resets leave the window
+        assert_eq!(budget.admit(1300, "mismatch").unwrap(), Admission::Refused);
+        assert_eq!(budget.admit(9000, "mismatch").unwrap(), Admission::Refused);
+
+        budget.rearm().unwrap();
+        assert_eq!(budget.admit(9000, "mismatch").unwrap(), Admission::Now);
+    }
+
+    #[test]
+    fn resets_leave_the_window() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut budget = Budget::load(tmp.path(), CONFIG).unwrap();
+        for at in [0, 100, 200] {
+            budget.record(at, "mismatch").unwrap();
+        }
+
+        assert_eq!(budget.admit(3601, "mismatch").unwrap(), Admission::Now);
+        assert_eq!(budget.history.resets, vec![100, 200]);
+    }
+
+    #[test]
+    fn history_survives_restarts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut budget = Budget::load(tmp.path(), CONFIG).unwrap();
+        for at in [0, 100, 200] {
+            budget.record(at, "generation mismatch").unwrap();
+        }
+        assert_eq!(budget.admit(300, "mismatch").unwrap(), Admission::Refused);
+
+        let budget = Budget::load(tmp.path(), CONFIG).unwrap();
+        assert_eq!(budget.history.resets, vec![0, 100, 200]);
+        assert_eq!(
+            budget.history.last_reason.as_deref(),
+            Some("generation mismatch")
+        );
+        assert!(budget.history.halted);
+
+        let reset = HardReset::default();
+        reset.init(tmp.path(), CONFIG).unwrap();
+        assert!(!reset.is_healthy());
+        reset.rearm().unwrap();
+        assert!(reset.is_healthy());
+        assert!(!Budget::load(tmp.path(), CONFIG).unwrap().history.halted);
+    }
+
+    #[tokio::test]
+    async fn coalesce_requests() {
+        let reset = HardReset::default();
+        reset.request("first");
+        reset.request("second");
+        assert_eq!(reset.requested().await, "first");
+
+        // requests made during the reset are dropped
+        reset.request("third");
+        reset.discard_pending();
+        reset.request("fourth");
+        assert_eq!(reset.requested().await, "fourth");
+    }
+
+    #[test]
+    fn disallowed_resets_are_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = HardResetConfig {
+            allowed: false,
+            ..CONFIG
+        };
+        let reset = HardReset::default();
+        reset.init(tmp.path(), config).unwrap();
+        assert_eq!(reset.admit("mismatch").unwrap(), Admission::Refused);
+        assert!(!reset.is_healthy());
+        // the refusal depends on the configuration, not on the history
+        assert!(!Budget::load(tmp.path(), CONFIG).unwrap().history.halted);
+    }
+
+    #[tokio::test]
+    async fn wipe_keeps_unrelated_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path();
+        for name in ["data", "data-wal", "data-shm", "wallog", "client_wal_index"] {
+            std::fs::write(path.join(name), b"state").unwrap();
+        }
+        std::fs::create_dir(path.join("snapshots")).unwrap();
+        std::fs::write(path.join("snapshots/a.snap"), b"frames").unwrap();
+        for name in [HISTORY_FILE, "config.json", "operator-notes.txt"] {
+            std::fs::write(path.join(name), b"keep").unwrap();
+        }
+        std::fs::create_dir(path.join("exports")).unwrap();
+        std::fs::write(path.join("exports/data"), b"keep").unwrap();
+
+        let wiped = wipe(path, "generation mismatch").await.unwrap();
+        assert_eq!(
+            wiped,
+            [
+                "data",
+                "data-wal",
+                "data-shm",
+                "wallog",
+                "snapshots",
+                "client_wal_index"
+            ]
+        );
+        for name in wiped {
+            assert!(!path.join(name).exists());
+        }
+        for name in [
+            HISTORY_FILE,
+            "config.json",
+            "operator-notes.txt",
+            "exports/data",
+        ] {
+            assert_eq!(std::fs::read(path.join(name)).unwrap(), b"keep", "{name}");
+        }
+
+        let marker: ResetMarker =
+            serde_json::from_slice(&std::fs::read(path.join(RESET_REASON_FILE)).unwrap()).unwrap();
+        assert_eq!(marker.reason, "generation mismatch");
+        assert_eq!(marker.wiped.len(), 6);
+        assert!(marker.at > 0);
+    }
+}
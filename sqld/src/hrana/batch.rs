@@ -1,13 +1,13 @@
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::time::Duration;
 
 use crate::auth::Authenticated;
 use crate::database::{Cond, Database, Program, Step};
 use crate::error::Error as SqldError;
-use crate::hrana::stmt::StmtError;
+use crate::hrana::stmt::{stmt_error_from_parse_error, StmtError};
 use crate::query::{Params, Query};
-use crate::query_analysis::Statement;
+use crate::query_analysis::{Statement, StmtClass};
 use crate::query_result_builder::{
     QueryResultBuilder, QueryResultBuilderError, StepResult, StepResultsBuilder,
 };
@@ -18,12 +18,20 @@ use super::{proto, ProtocolError, Version};
 
 #[derive(thiserror::Error, Debug)]
 pub enum BatchError {
-    #[error("Transaction timed out")]
-    TransactionTimeout,
+    #[error("Transaction timed out: it was rolled back after being idle for {}ms", .idle.as_millis())]
+    TransactionTimeout { idle: Duration },
     #[error("Server cannot handle additional transactions")]
     TransactionBusy,
     #[error("Response is too large")]
     ResponseTooLarge,
+    #[error("The database changed since replication index {expected}, it is now at replication index {current}")]
+    ReplicationIndexConflict { expected: u64, current: u64 },
+    #[error("`expected_replication_index` can't be used {reason}")]
+    ReplicationIndexUnsupported { reason: &'static str },
+    #[error(
+        "Statement class not allowed: `{class}` statements can't be run with these credentials"
+    )]
+    StatementClassNotAllowed { class: StmtClass },
 }
 
 fn proto_cond_to_cond(cond: &proto::BatchCond, max_step_i: usize) -> Result<Cond> {
@@ -80,7 +88,7 @@ pub fn proto_batch_to_program(
         steps.push(step);
     }
 
-    Ok(Program::new(steps))
+    Ok(Program::new(steps).with_expected_replication_index(batch.expected_replication_index))
 }
 
 pub async fn execute_batch(
@@ -100,7 +108,7 @@ pub async fn execute_batch(
 pub fn proto_sequence_to_program(sql: &str) -> Result<Program> {
     let stmts = Statement::parse(sql)
         .collect::<Result<Vec<_>>>()
-        .map_err(|err| anyhow!(StmtError::SqlParse { source: err }))?;
+        .map_err(|err| anyhow!(stmt_error_from_parse_error(err)))?;
 
     let steps = stmts
         .into_iter()
@@ -118,9 +126,7 @@ pub fn proto_sequence_to_program(sql: &str) -> Result<Program> {
             Step { cond, query }
         })
         .collect();
-    Ok(Program {
-        steps: Arc::new(steps),
-    })
+    Ok(Program::new(steps))
 }
 
 pub async fn execute_sequence(db: &impl Database, auth: Authenticated, pgm: Program) -> Result<()> {
@@ -151,11 +157,20 @@ fn catch_batch_error(sqld_error: SqldError) -> anyhow::Error {
 
 fn batch_error_from_sqld_error(sqld_error: SqldError) -> Result<BatchError, SqldError> {
     Ok(match sqld_error {
-        SqldError::LibSqlTxTimeout => BatchError::TransactionTimeout,
+        SqldError::LibSqlTxTimeout(idle) => BatchError::TransactionTimeout { idle },
         SqldError::LibSqlTxBusy => BatchError::TransactionBusy,
         SqldError::BuilderError(QueryResultBuilderError::ResponseTooLarge(_)) => {
             BatchError::ResponseTooLarge
         }
+        SqldError::ReplicationIndexConflict { expected, current } => {
+            BatchError::ReplicationIndexConflict { expected, current }
+        }
+        SqldError::ReplicationIndexUnsupported(reason) => {
+            BatchError::ReplicationIndexUnsupported { reason }
+        }
+        SqldError::StatementClassNotAllowed(class) => {
+            BatchError::StatementClassNotAllowed { class }
+        }
         sqld_error => return Err(sqld_error),
     })
 }
@@ -163,9 +178,12 @@ fn batch_error_from_sqld_error(sqld_error: SqldError) -> Result<BatchError, Sqld
 impl BatchError {
     pub fn code(&self) -> &'static str {
         match self {
-            Self::TransactionTimeout => "TRANSACTION_TIMEOUT",
+            Self::TransactionTimeout { .. } => "TRANSACTION_TIMEOUT",
             Self::TransactionBusy => "TRANSACTION_BUSY",
             Self::ResponseTooLarge => "RESPONSE_TOO_LARGE",
+            Self::ReplicationIndexConflict { .. } => "REPLICATION_INDEX_CONFLICT",
+            Self::ReplicationIndexUnsupported { .. } => "REPLICATION_INDEX_UNSUPPORTED",
+            Self::StatementClassNotAllowed { .. } => "STATEMENT_CLASS_NOT_ALLOWED",
         }
     }
 }
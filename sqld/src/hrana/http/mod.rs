@@ -108,6 +108,7 @@ fn stream_error_response(err: stream::StreamError) -> hyper::Response<hyper::Bod
         &proto::Error {
             message: err.to_string(),
             code: err.code().into(),
+            constraint_violations: Vec::new(),
         },
     )
 }
@@ -28,6 +28,7 @@ pub async fn handle<D: Database>(
             let error = proto::Error {
                 message: resp_err.to_string(),
                 code: resp_err.code().into(),
+                constraint_violations: resp_err.constraint_violations(),
             };
             proto::StreamResult::Error { error }
         }
@@ -129,4 +130,11 @@ impl StreamResponseError {
             Self::Batch(err) => err.code(),
         }
     }
+
+    pub fn constraint_violations(&self) -> Vec<proto::ConstraintViolation> {
+        match self {
+            Self::Stmt(err) => err.constraint_violations(),
+            _ => Vec::new(),
+        }
+    }
 }
@@ -3,7 +3,7 @@ use std::fmt;
 pub mod batch;
 pub mod http;
 pub mod proto;
-mod result_builder;
+pub(crate) mod result_builder;
 pub mod stmt;
 pub mod ws;
 
@@ -47,6 +47,13 @@ pub enum ProtocolError {
     #[error("SQL text {sql_id} already exists")]
     SqlExists { sql_id: i32 },
 
+    #[error("Subscription {subscription_id} not found")]
+    SubscriptionNotFound { subscription_id: i32 },
+    #[error(
+        "The client did not keep up with the changes, {skipped} batches of changes were dropped"
+    )]
+    ChangesLagged { skipped: u64 },
+
     #[error("Invalid reference to step in a batch condition")]
     BatchCondBadStep,
 
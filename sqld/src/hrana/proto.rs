@@ -1,160 +1,6 @@
 //! Structures in Hrana that are common for WebSockets and HTTP.
 
-use bytes::Bytes;
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-
-#[derive(Serialize, Debug)]
-pub struct Error {
-    pub message: String,
-    pub code: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct Stmt {
-    #[serde(default)]
-    pub sql: Option<String>,
-    #[serde(default)]
-    pub sql_id: Option<i32>,
-    #[serde(default)]
-    pub args: Vec<Value>,
-    #[serde(default)]
-    pub named_args: Vec<NamedArg>,
-    #[serde(default)]
-    pub want_rows: Option<bool>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct NamedArg {
-    pub name: String,
-    pub value: Value,
-}
-
-#[derive(Serialize, Debug)]
-pub struct StmtResult {
-    pub cols: Vec<Col>,
-    pub rows: Vec<Vec<Value>>,
-    pub affected_row_count: u64,
-    #[serde(with = "option_i64_as_str")]
-    pub last_insert_rowid: Option<i64>,
-}
-
-#[derive(Serialize, Debug)]
-pub struct Col {
-    pub name: Option<String>,
-    pub decltype: Option<String>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct Batch {
-    pub steps: Vec<BatchStep>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct BatchStep {
-    pub stmt: Stmt,
-    #[serde(default)]
-    pub condition: Option<BatchCond>,
-}
-
-#[derive(Serialize, Debug)]
-pub struct BatchResult {
-    pub step_results: Vec<Option<StmtResult>>,
-    pub step_errors: Vec<Option<Error>>,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum BatchCond {
-    Ok { step: i32 },
-    Error { step: i32 },
-    Not { cond: Box<BatchCond> },
-    And { conds: Vec<BatchCond> },
-    Or { conds: Vec<BatchCond> },
-}
-
-#[derive(Serialize, Debug)]
-pub struct DescribeResult {
-    pub params: Vec<DescribeParam>,
-    pub cols: Vec<DescribeCol>,
-    pub is_explain: bool,
-    pub is_readonly: bool,
-}
-
-#[derive(Serialize, Debug)]
-pub struct DescribeParam {
-    pub name: Option<String>,
-}
-
-#[derive(Serialize, Debug)]
-pub struct DescribeCol {
-    pub name: String,
-    pub decltype: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum Value {
-    Null,
-    Integer {
-        #[serde(with = "i64_as_str")]
-        value: i64,
-    },
-    Float {
-        value: f64,
-    },
-    Text {
-        value: Arc<str>,
-    },
-    Blob {
-        #[serde(with = "bytes_as_base64", rename = "base64")]
-        value: Bytes,
-    },
-}
-
-mod i64_as_str {
-    use serde::{de, ser};
-    use serde::{de::Error as _, Serialize as _};
-
-    pub fn serialize<S: ser::Serializer>(value: &i64, ser: S) -> Result<S::Ok, S::Error> {
-        value.to_string().serialize(ser)
-    }
-
-    pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<i64, D::Error> {
-        let str_value = <&'de str as de::Deserialize>::deserialize(de)?;
-        str_value.parse().map_err(|_| {
-            D::Error::invalid_value(
-                de::Unexpected::Str(str_value),
-                &"decimal integer as a string",
-            )
-        })
-    }
-}
-
-mod option_i64_as_str {
-    use serde::{ser, Serialize as _};
-
-    pub fn serialize<S: ser::Serializer>(value: &Option<i64>, ser: S) -> Result<S::Ok, S::Error> {
-        value.map(|v| v.to_string()).serialize(ser)
-    }
-}
-
-mod bytes_as_base64 {
-    use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
-    use bytes::Bytes;
-    use serde::{de, ser};
-    use serde::{de::Error as _, Serialize as _};
-
-    pub fn serialize<S: ser::Serializer>(value: &Bytes, ser: S) -> Result<S::Ok, S::Error> {
-        STANDARD_NO_PAD.encode(value).serialize(ser)
-    }
-
-    pub fn deserialize<'de, D: de::Deserializer<'de>>(de: D) -> Result<Bytes, D::Error> {
-        let text = <&'de str as de::Deserialize>::deserialize(de)?;
-        let text = text.trim_end_matches('=');
-        let bytes = STANDARD_NO_PAD.decode(text).map_err(|_| {
-            D::Error::invalid_value(de::Unexpected::Str(text), &"binary data encoded as base64")
-        })?;
-        Ok(Bytes::from(bytes))
-    }
-}
+pub use sqld_api_types::hrana::{
+    Batch, BatchCond, BatchResult, BatchStep, Col, ConstraintViolation, DescribeCol, DescribeParam,
+    DescribeResult, Error, NamedArg, Stmt, StmtResult, Value,
+};
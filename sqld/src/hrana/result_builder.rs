@@ -8,6 +8,7 @@ use crate::hrana::stmt::{proto_error_from_stmt_error, stmt_error_from_sqld_error
 use crate::query_result_builder::{
     Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
+use crate::replication::FrameNo;
 
 use super::proto;
 
@@ -19,6 +20,8 @@ pub struct SingleStatementBuilder {
     err: Option<crate::error::Error>,
     affected_row_count: u64,
     last_insert_rowid: Option<i64>,
+    replication_index: Option<FrameNo>,
+    proxied: bool,
     current_size: u64,
     max_response_size: u64,
 }
@@ -184,6 +187,16 @@ impl QueryResultBuilder for SingleStatementBuilder {
         Ok(())
     }
 
+    fn replication_index(&mut self, index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        self.replication_index = Some(index);
+        Ok(())
+    }
+
+    fn proxied(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.proxied = true;
+        Ok(())
+    }
+
     fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
         Ok(())
     }
@@ -196,6 +209,8 @@ impl QueryResultBuilder for SingleStatementBuilder {
                 rows: self.rows,
                 affected_row_count: self.affected_row_count,
                 last_insert_rowid: self.last_insert_rowid,
+                replication_index: self.replication_index,
+                proxied: self.proxied,
             }),
         }
     }
@@ -221,6 +236,8 @@ pub struct HranaBatchProtoBuilder {
     current_size: u64,
     max_response_size: u64,
     step_empty: bool,
+    replication_index: Option<FrameNo>,
+    proxied: bool,
 }
 
 impl QueryResultBuilder for HranaBatchProtoBuilder {
@@ -302,6 +319,16 @@ impl QueryResultBuilder for HranaBatchProtoBuilder {
         Ok(())
     }
 
+    fn replication_index(&mut self, index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        self.replication_index = Some(index);
+        Ok(())
+    }
+
+    fn proxied(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.proxied = true;
+        Ok(())
+    }
+
     fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
         Ok(())
     }
@@ -310,6 +337,8 @@ impl QueryResultBuilder for HranaBatchProtoBuilder {
         proto::BatchResult {
             step_results: self.step_results,
             step_errors: self.step_errors,
+            replication_index: self.replication_index,
+            proxied: self.proxied,
         }
     }
 }
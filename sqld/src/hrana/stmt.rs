@@ -1,14 +1,17 @@
 use anyhow::{anyhow, bail, Result};
 use std::collections::HashMap;
+use std::time::Duration;
 
 use super::result_builder::SingleStatementBuilder;
 use super::{proto, ProtocolError, Version};
 use crate::auth::Authenticated;
+use crate::database::constraint::ConstraintViolation;
+use crate::database::settings::{ResultLimit, SettingsError};
 use crate::database::{Database, DescribeResponse};
-use crate::error::Error as SqldError;
+use crate::error::{deferred_violation_message, Error as SqldError};
 use crate::hrana;
 use crate::query::{Params, Query, Value};
-use crate::query_analysis::Statement;
+use crate::query_analysis::{DenyRule, InlineLiteral, ParseLimitError, Statement, StmtClass};
 use crate::query_result_builder::{QueryResultBuilder, QueryResultBuilderError};
 
 /// An error during execution of an SQL statement.
@@ -16,6 +19,8 @@ use crate::query_result_builder::{QueryResultBuilder, QueryResultBuilderError};
 pub enum StmtError {
     #[error("SQL string could not be parsed: {source}")]
     SqlParse { source: anyhow::Error },
+    #[error("SQL string was rejected: {source}")]
+    SqlLimit { source: ParseLimitError },
     #[error("SQL string does not contain any statement")]
     SqlNoStmt,
     #[error("SQL string contains more than one statement")]
@@ -25,8 +30,8 @@ pub enum StmtError {
     #[error("Specifying both positional and named arguments is not supported")]
     ArgsBothPositionalAndNamed,
 
-    #[error("Transaction timed out")]
-    TransactionTimeout,
+    #[error("Transaction timed out: it was rolled back after being idle for {}ms", .idle.as_millis())]
+    TransactionTimeout { idle: Duration },
     #[error("Server cannot handle additional transactions")]
     TransactionBusy,
     #[error("SQLite error: {message}")]
@@ -45,6 +50,53 @@ pub enum StmtError {
     Blocked { reason: Option<String> },
     #[error("Response is too large")]
     ResponseTooLarge,
+    #[error("Invalid setting: {source}")]
+    InvalidSetting { source: SettingsError },
+    #[error("Statement timed out after {}ms", .timeout.as_millis())]
+    StatementTimeout { timeout: Duration },
+    #[error("Query interrupted by administrator, the transaction was rolled back")]
+    InterruptedByAdmin,
+    #[error("Statement must be parameterized: {literal}")]
+    InlineLiteral { literal: InlineLiteral },
+    #[error("Statement denied by the `{rule}` rule")]
+    StatementDenied { rule: DenyRule },
+    #[error(
+        "Statement class not allowed: `{class}` statements can't be run with these credentials"
+    )]
+    StatementClassNotAllowed { class: StmtClass },
+    #[error("Statement {} of the batch failed, the batch was rolled back and the transaction is still open: {source}", .step + 1)]
+    BatchRolledBack { step: usize, source: Box<StmtError> },
+    #[error("Storage is degraded, writes are rejected until it is repaired: {reason}")]
+    StorageDegraded { reason: String },
+    #[error("The primary is unreachable, the next attempt to reach it is in {}ms", .retry_in.as_millis())]
+    PrimaryUnreachable { retry_in: Duration },
+    #[error("The replica has not replicated the database from its primary yet")]
+    ReplicaNotReady,
+    #[error("Too many writes are waiting to be sent to the primary")]
+    PrimaryWriteQueueFull,
+    #[error("Too many queries from this client, retry in {}ms", .retry_in.as_millis())]
+    RateLimited { retry_in: Duration },
+    #[error("The transaction reads from the replica and can't write, begin it with `BEGIN IMMEDIATE` to write")]
+    WriteInReadTransaction,
+    #[error("{message}")]
+    ConstraintViolation {
+        message: String,
+        violation: ConstraintViolation,
+    },
+    #[error("{}", deferred_violation_message(.violations))]
+    DeferredConstraintViolation {
+        violations: Vec<ConstraintViolation>,
+    },
+    #[error("Replica is read-only, use the primary")]
+    ReadOnlyReplica,
+    #[error("ATTACH not allowed: {reason}")]
+    AttachNotAllowed { reason: String },
+    #[error(
+        "Attached databases are read-only: their changes would be neither replicated nor backed up"
+    )]
+    AttachedDatabaseReadOnly,
+    #[error("The results of the statement were truncated, they exceed the limit of {limit}")]
+    ResultLimitExceeded { limit: ResultLimit },
 }
 
 pub async fn execute_stmt(
@@ -86,7 +138,7 @@ pub fn proto_stmt_to_query(
     let mut stmt_iter = Statement::parse(sql);
     let stmt = match stmt_iter.next() {
         Some(Ok(stmt)) => stmt,
-        Some(Err(err)) => bail!(StmtError::SqlParse { source: err }),
+        Some(Err(err)) => bail!(stmt_error_from_parse_error(err)),
         None => bail!(StmtError::SqlNoStmt),
     };
 
@@ -140,7 +192,7 @@ pub fn proto_sql_to_sql<'s>(
     }
 }
 
-fn proto_value_to_value(proto_value: &proto::Value) -> Value {
+pub fn proto_value_to_value(proto_value: &proto::Value) -> Value {
     match proto_value {
         proto::Value::Null => Value::Null,
         proto::Value::Integer { value } => Value::Integer(*value),
@@ -150,7 +202,7 @@ fn proto_value_to_value(proto_value: &proto::Value) -> Value {
     }
 }
 
-fn proto_value_from_value(value: Value) -> proto::Value {
+pub fn proto_value_from_value(value: Value) -> proto::Value {
     match value {
         Value::Null => proto::Value::Null,
         Value::Integer(value) => proto::Value::Integer { value },
@@ -196,12 +248,46 @@ fn catch_stmt_error(sqld_error: SqldError) -> anyhow::Error {
 pub fn stmt_error_from_sqld_error(sqld_error: SqldError) -> Result<StmtError, SqldError> {
     Ok(match sqld_error {
         SqldError::LibSqlInvalidQueryParams(source) => StmtError::ArgsInvalid { source },
-        SqldError::LibSqlTxTimeout => StmtError::TransactionTimeout,
+        SqldError::LibSqlTxTimeout(idle) => StmtError::TransactionTimeout { idle },
         SqldError::LibSqlTxBusy => StmtError::TransactionBusy,
         SqldError::BuilderError(QueryResultBuilderError::ResponseTooLarge(_)) => {
             StmtError::ResponseTooLarge
         }
         SqldError::Blocked(reason) => StmtError::Blocked { reason },
+        SqldError::InvalidSetting(source) => StmtError::InvalidSetting { source },
+        SqldError::StatementTimeout(timeout) => StmtError::StatementTimeout { timeout },
+        SqldError::InterruptedByAdmin => StmtError::InterruptedByAdmin,
+        SqldError::InlineLiteral(literal) => StmtError::InlineLiteral { literal },
+        SqldError::StatementDenied(rule) => StmtError::StatementDenied { rule },
+        SqldError::StatementClassNotAllowed(class) => StmtError::StatementClassNotAllowed { class },
+        SqldError::StorageDegraded(reason) => StmtError::StorageDegraded { reason },
+        SqldError::PrimaryUnreachable(retry_in) => StmtError::PrimaryUnreachable { retry_in },
+        SqldError::ReplicaNotReady => StmtError::ReplicaNotReady,
+        SqldError::PrimaryWriteQueueFull => StmtError::PrimaryWriteQueueFull,
+        SqldError::RateLimited(retry_in) => StmtError::RateLimited { retry_in },
+        SqldError::WriteInReadTransaction => StmtError::WriteInReadTransaction,
+        SqldError::ConstraintViolation { message, violation } => {
+            StmtError::ConstraintViolation { message, violation }
+        }
+        SqldError::DeferredConstraintViolation(violations) => {
+            StmtError::DeferredConstraintViolation { violations }
+        }
+        SqldError::ReadOnlyReplica => StmtError::ReadOnlyReplica,
+        SqldError::AttachNotAllowed(reason) => StmtError::AttachNotAllowed { reason },
+        SqldError::AttachedDatabaseReadOnly => StmtError::AttachedDatabaseReadOnly,
+        SqldError::ResultLimitExceeded(limit) => StmtError::ResultLimitExceeded { limit },
+        SqldError::BatchRolledBack { step, source } => match stmt_error_from_sqld_error(*source) {
+            Ok(source) => StmtError::BatchRolledBack {
+                step,
+                source: Box::new(source),
+            },
+            Err(source) => {
+                return Err(SqldError::BatchRolledBack {
+                    step,
+                    source: Box::new(source),
+                })
+            }
+        },
         SqldError::RusqliteError(rusqlite_error) => match rusqlite_error {
             rusqlite::Error::SqliteFailure(sqlite_error, Some(message)) => StmtError::SqliteError {
                 source: sqlite_error,
@@ -227,10 +313,29 @@ pub fn stmt_error_from_sqld_error(sqld_error: SqldError) -> Result<StmtError, Sq
     })
 }
 
+/// Converts an error returned by [`Statement::parse`].
+pub fn stmt_error_from_parse_error(error: anyhow::Error) -> StmtError {
+    match error.downcast::<ParseLimitError>() {
+        Ok(source) => StmtError::SqlLimit { source },
+        Err(source) => StmtError::SqlParse { source },
+    }
+}
+
 pub fn proto_error_from_stmt_error(error: &StmtError) -> hrana::proto::Error {
     hrana::proto::Error {
         message: error.to_string(),
         code: error.code().into(),
+        constraint_violations: error.constraint_violations(),
+    }
+}
+
+pub fn proto_constraint_violation(violation: &ConstraintViolation) -> proto::ConstraintViolation {
+    proto::ConstraintViolation {
+        kind: violation.kind.as_str().into(),
+        table: violation.table.clone(),
+        constraint: violation.constraint.clone(),
+        parent: violation.parent.clone(),
+        rowid: violation.rowid,
     }
 }
 
@@ -238,21 +343,58 @@ impl StmtError {
     pub fn code(&self) -> &'static str {
         match self {
             Self::SqlParse { .. } => "SQL_PARSE_ERROR",
+            Self::SqlLimit { source } => match source {
+                ParseLimitError::TooLong { .. } => "SQL_TOO_LONG",
+                ParseLimitError::TooComplex { .. } => "SQL_TOO_COMPLEX",
+            },
             Self::SqlNoStmt => "SQL_NO_STATEMENT",
             Self::SqlManyStmts => "SQL_MANY_STATEMENTS",
             Self::ArgsInvalid { .. } => "ARGS_INVALID",
             Self::ArgsBothPositionalAndNamed => "ARGS_BOTH_POSITIONAL_AND_NAMED",
-            Self::TransactionTimeout => "TRANSACTION_TIMEOUT",
+            Self::TransactionTimeout { .. } => "TRANSACTION_TIMEOUT",
             Self::TransactionBusy => "TRANSACTION_BUSY",
             Self::SqliteError { source, .. } => sqlite_error_code(source.code),
             Self::SqlInputError { .. } => "SQL_INPUT_ERROR",
             Self::Blocked { .. } => "BLOCKED",
             Self::ResponseTooLarge => "RESPONSE_TOO_LARGE",
+            Self::InvalidSetting { .. } => "INVALID_SETTING",
+            Self::StatementTimeout { .. } => "STATEMENT_TIMEOUT",
+            Self::InterruptedByAdmin => "INTERRUPTED_BY_ADMIN",
+            Self::InlineLiteral { .. } => "INLINE_LITERAL",
+            Self::StatementDenied { .. } => "STATEMENT_DENIED",
+            Self::StatementClassNotAllowed { .. } => "STATEMENT_CLASS_NOT_ALLOWED",
+            Self::BatchRolledBack { .. } => "BATCH_ROLLED_BACK",
+            Self::StorageDegraded { .. } => "STORAGE_DEGRADED",
+            Self::PrimaryUnreachable { .. } => "PRIMARY_UNREACHABLE",
+            Self::ReplicaNotReady => "REPLICA_NOT_READY",
+            Self::PrimaryWriteQueueFull => "PRIMARY_WRITE_QUEUE_FULL",
+            Self::RateLimited { .. } => "RATE_LIMITED",
+            Self::WriteInReadTransaction => "WRITE_IN_READ_TRANSACTION",
+            Self::ConstraintViolation { .. } => "SQLITE_CONSTRAINT",
+            Self::DeferredConstraintViolation { .. } => "TRANSACTION_ROLLED_BACK",
+            Self::ReadOnlyReplica => "READ_ONLY_REPLICA",
+            Self::AttachNotAllowed { .. } => "ATTACH_NOT_ALLOWED",
+            Self::AttachedDatabaseReadOnly => "ATTACHED_DATABASE_READ_ONLY",
+            Self::ResultLimitExceeded { .. } => "RESULT_LIMIT_EXCEEDED",
+        }
+    }
+
+    /// The constraints violated by the statement, for the clients to inspect.
+    pub fn constraint_violations(&self) -> Vec<proto::ConstraintViolation> {
+        match self {
+            Self::ConstraintViolation { violation, .. } => {
+                vec![proto_constraint_violation(violation)]
+            }
+            Self::DeferredConstraintViolation { violations } => {
+                violations.iter().map(proto_constraint_violation).collect()
+            }
+            Self::BatchRolledBack { source, .. } => source.constraint_violations(),
+            _ => Vec::new(),
         }
     }
 }
 
-fn sqlite_error_code(code: rusqlite::ffi::ErrorCode) -> &'static str {
+pub fn sqlite_error_code(code: rusqlite::ffi::ErrorCode) -> &'static str {
     match code {
         rusqlite::ErrorCode::InternalMalfunction => "SQLITE_INTERNAL",
         rusqlite::ErrorCode::PermissionDenied => "SQLITE_PERM",
@@ -293,3 +435,49 @@ impl From<Value> for proto::Value {
         proto_value_from_value(value)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::database::constraint::ConstraintKind;
+
+    use super::*;
+
+    #[test]
+    fn constraint_violation_errors() {
+        let violation = ConstraintViolation {
+            kind: ConstraintKind::Unique,
+            table: Some("t".into()),
+            constraint: Some("x".into()),
+            parent: None,
+            rowid: None,
+        };
+        let error = SqldError::BatchRolledBack {
+            step: 1,
+            source: Box::new(SqldError::ConstraintViolation {
+                message: "UNIQUE constraint failed: t.x".into(),
+                violation: violation.clone(),
+            }),
+        };
+        let error = proto_error_from_stmt_error(&stmt_error_from_sqld_error(error).unwrap());
+        assert_eq!(error.code, "BATCH_ROLLED_BACK");
+        assert_eq!(
+            serde_json::to_value(&error.constraint_violations).unwrap(),
+            serde_json::json!([{"kind": "UNIQUE", "table": "t", "constraint": "x"}])
+        );
+
+        let error = SqldError::DeferredConstraintViolation(vec![violation]);
+        let error = proto_error_from_stmt_error(&stmt_error_from_sqld_error(error).unwrap());
+        assert_eq!(error.code, "TRANSACTION_ROLLED_BACK");
+        assert!(error.message.contains("rolled back"));
+        assert_eq!(error.constraint_violations.len(), 1);
+
+        // other errors have no violations, which are left out of the messages
+        let error = proto_error_from_stmt_error(&StmtError::TransactionTimeout {
+            idle: Duration::from_secs(5),
+        });
+        assert!(error.constraint_violations.is_empty());
+        assert!(!serde_json::to_string(&error)
+            .unwrap()
+            .contains("constraint_violations"));
+    }
+}
@@ -7,11 +7,18 @@ use std::task::{Context, Poll};
 use anyhow::{bail, Context as _, Result};
 use futures::stream::FuturesUnordered;
 use futures::{ready, FutureExt as _, StreamExt as _};
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{Interval, MissedTickBehavior};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::tungstenite;
 use tungstenite::protocol::frame::coding::CloseCode;
 
+use crate::connections::ServerBusy;
 use crate::database::Database;
+use crate::replication::logical::ChangeBatch;
+use crate::system::ShutdownPhase;
+use crate::tls::HANDSHAKE_TIMEOUT;
 
 use super::super::{ProtocolError, Version};
 use super::handshake::WebSocket;
@@ -45,14 +52,45 @@ struct ResponseFuture {
 pub(super) async fn handle_tcp(
     server: Arc<Server<impl Database>>,
     socket: tokio::net::TcpStream,
+    tls: Option<TlsAcceptor>,
     conn_id: u64,
 ) -> Result<()> {
-    let (ws, version) = handshake::handshake_tcp(socket)
-        .await
-        .context("Could not perform the WebSocket handshake on TCP connection")?;
+    let (ws, version) = match tls {
+        Some(tls) => handshake::handshake_tls(accept_tls(tls, socket).await?).await,
+        None => handshake::handshake_tcp(socket).await,
+    }
+    .context("Could not perform the WebSocket handshake on TCP connection")?;
     handle_ws(server, ws, version, conn_id).await
 }
 
+/// Answers the WebSocket handshake of a connection refused by the connection limits, within the
+/// timeout of a TLS handshake.
+pub(super) async fn reject_tcp(
+    socket: tokio::net::TcpStream,
+    tls: Option<TlsAcceptor>,
+    busy: ServerBusy,
+) -> Result<()> {
+    let reject = async move {
+        match tls {
+            Some(tls) => handshake::reject(accept_tls(tls, socket).await?, busy.to_string()).await,
+            None => handshake::reject(socket, busy.to_string()).await,
+        }
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, reject)
+        .await
+        .context("The WebSocket handshake timed out")?
+}
+
+async fn accept_tls(
+    tls: TlsAcceptor,
+    socket: tokio::net::TcpStream,
+) -> Result<TlsStream<tokio::net::TcpStream>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(socket))
+        .await
+        .context("The TLS handshake timed out")?
+        .context("Could not perform the TLS handshake on TCP connection")
+}
+
 pub(super) async fn handle_upgrade(
     server: Arc<Server<impl Database>>,
     upgrade: Upgrade,
@@ -81,11 +119,42 @@ async fn handle_ws<D: Database>(
         responses: FuturesUnordered::new(),
     };
 
+    let mut pings = conn.server.ping_interval.map(|period| {
+        let mut pings = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        pings
+    });
+    // any message from the client answers the last ping, not only its pong
+    let mut ping_answered = true;
+
+    let mut draining = false;
     loop {
+        if draining && conn.responses.is_empty() {
+            // closing the streams rolls back their transactions, which must not be left open
+            if let Some(session) = conn.session.as_mut() {
+                session.close_streams();
+            }
+            while let Some(task_res) = conn.join_set.join_next().await {
+                task_res.expect("Connection subtask failed");
+            }
+            close(
+                &mut conn,
+                CloseCode::Away,
+                "The server is shutting down".into(),
+            )
+            .await;
+            return Ok(());
+        }
+
         tokio::select! {
-            Some(client_msg_res) = conn.ws.recv() => {
+            _ = conn.server.shutdown.reached(ShutdownPhase::Drain), if !draining => {
+                // answer the requests in flight, but don't read new ones
+                draining = true;
+            },
+            Some(client_msg_res) = conn.ws.recv(), if !draining => {
                 let client_msg = client_msg_res
                     .context("Could not receive a WebSocket message")?;
+                ping_answered = true;
                 match handle_msg(&mut conn, client_msg).await {
                     Ok(true) => continue,
                     Ok(false) => break,
@@ -109,6 +178,21 @@ async fn handle_ws<D: Database>(
                     }
                 }
             },
+            _ = tick(pings.as_mut()), if !draining => {
+                if !ping_answered {
+                    tracing::warn!(
+                        "Connection #{} did not answer a ping, closing it",
+                        conn.conn_id,
+                    );
+                    close(&mut conn, CloseCode::Away, "The ping was not answered".into()).await;
+                    return Ok(());
+                }
+                conn.ws
+                    .send(tungstenite::Message::Ping(Vec::new()))
+                    .await
+                    .context("Could not send ping to the WebSocket")?;
+                ping_answered = false;
+            },
             Some(task_res) = conn.join_set.join_next() => {
                 task_res.expect("Connection subtask failed")
             },
@@ -116,6 +200,27 @@ async fn handle_ws<D: Database>(
                 let response_msg = response_res?;
                 send_msg(&mut conn, &response_msg).await?;
             },
+            changes_res = recv_changes(conn.session.as_mut()) => {
+                match changes_res {
+                    Ok(batch) => send_changes(&mut conn, &batch).await?,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        let proto_err = ProtocolError::ChangesLagged { skipped };
+                        tracing::warn!(
+                            "Connection #{} terminated due to protocol error: {}",
+                            conn.conn_id,
+                            proto_err,
+                        );
+                        let close_code = protocol_error_to_close_code(&proto_err);
+                        close(&mut conn, close_code, proto_err.to_string()).await;
+                        return Ok(())
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        if let Some(session) = conn.session.as_mut() {
+                            session.subscriptions.close();
+                        }
+                    }
+                }
+            },
             else => break,
         }
 
@@ -244,11 +349,43 @@ impl Future for ResponseFuture {
     }
 }
 
+/// Waits for the next ping, if the connection is pinged.
+async fn tick(pings: Option<&mut Interval>) {
+    match pings {
+        Some(pings) => {
+            pings.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for the next batch of changes for the subscriptions of the connection.
+async fn recv_changes<D>(
+    session: Option<&mut session::Session<D>>,
+) -> Result<Arc<ChangeBatch>, broadcast::error::RecvError> {
+    match session {
+        Some(session) => session.subscriptions.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn send_changes<D: Database>(conn: &mut Conn<D>, batch: &ChangeBatch) -> Result<()> {
+    let Some(session) = conn.session.as_ref() else {
+        return Ok(());
+    };
+
+    for event in session.subscriptions.events(batch) {
+        send_msg(conn, &proto::ServerMsg::Change(event)).await?;
+    }
+    Ok(())
+}
+
 fn downcast_error(err: anyhow::Error) -> Result<proto::Error> {
     match err.downcast_ref::<session::ResponseError>() {
         Some(error) => Ok(proto::Error {
             message: error.to_string(),
             code: error.code().into(),
+            constraint_violations: error.constraint_violations(),
         }),
         None => Err(err),
     }
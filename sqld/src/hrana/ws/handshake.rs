@@ -1,5 +1,7 @@
 use anyhow::{anyhow, bail, Context as _, Result};
 use futures::{SinkExt as _, StreamExt as _};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
 use tokio_tungstenite::tungstenite;
 use tungstenite::http;
 
@@ -9,10 +11,25 @@ use super::Upgrade;
 #[derive(Debug)]
 pub enum WebSocket {
     Tcp(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>),
+    Tls(tokio_tungstenite::WebSocketStream<TlsStream<tokio::net::TcpStream>>),
     Upgraded(tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>),
 }
 
 pub async fn handshake_tcp(socket: tokio::net::TcpStream) -> Result<(WebSocket, Version)> {
+    let (stream, version) = accept(socket).await?;
+    Ok((WebSocket::Tcp(stream), version))
+}
+
+pub async fn handshake_tls(
+    socket: TlsStream<tokio::net::TcpStream>,
+) -> Result<(WebSocket, Version)> {
+    let (stream, version) = accept(socket).await?;
+    Ok((WebSocket::Tls(stream), version))
+}
+
+async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+) -> Result<(tokio_tungstenite::WebSocketStream<S>, Version)> {
     let mut version = None;
     let callback = |req: &http::Request<()>, resp: http::Response<()>| {
         let (mut resp_parts, _) = resp.into_parts();
@@ -32,7 +49,38 @@ pub async fn handshake_tcp(socket: tokio::net::TcpStream) -> Result<(WebSocket,
     let ws_config = Some(get_ws_config());
     let stream =
         tokio_tungstenite::accept_hdr_async_with_config(socket, callback, ws_config).await?;
-    Ok((WebSocket::Tcp(stream), version.unwrap()))
+    Ok((stream, version.unwrap()))
+}
+
+/// Answers the WebSocket handshake of a connection with a `503 Service Unavailable` error.
+pub async fn reject<S: AsyncRead + AsyncWrite + Unpin>(socket: S, reason: String) -> Result<()> {
+    let callback = |_: &http::Request<()>, resp: http::Response<()>| {
+        let (mut resp_parts, _) = resp.into_parts();
+        resp_parts.status = http::StatusCode::SERVICE_UNAVAILABLE;
+        resp_parts
+            .headers
+            .insert("server", http::HeaderValue::from_static("sqld-hrana-tcp"));
+        resp_parts
+            .headers
+            .insert("retry-after", http::HeaderValue::from_static("1"));
+        Err(http::Response::from_parts(resp_parts, Some(reason)))
+    };
+    match tokio_tungstenite::accept_hdr_async(socket, callback).await {
+        Err(tungstenite::Error::Http(_)) => Ok(()),
+        Err(err) => Err(err.into()),
+        Ok(_) => bail!("The rejected WebSocket handshake succeeded"),
+    }
+}
+
+/// Answers an HTTP upgrade with a `503 Service Unavailable` error.
+pub fn reject_upgrade(upgrade: Upgrade, reason: String) {
+    let resp = http::Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .header("server", "sqld-hrana-upgrade")
+        .header("retry-after", "1")
+        .body(hyper::Body::from(reason))
+        .unwrap();
+    let _: Result<_, _> = upgrade.response_tx.send(resp);
 }
 
 pub async fn handshake_upgrade(upgrade: Upgrade) -> Result<(WebSocket, Version)> {
@@ -127,6 +175,7 @@ impl WebSocket {
     pub async fn recv(&mut self) -> Option<tungstenite::Result<tungstenite::Message>> {
         match self {
             Self::Tcp(stream) => stream.next().await,
+            Self::Tls(stream) => stream.next().await,
             Self::Upgraded(stream) => stream.next().await,
         }
     }
@@ -134,6 +183,7 @@ impl WebSocket {
     pub async fn send(&mut self, msg: tungstenite::Message) -> tungstenite::Result<()> {
         match self {
             Self::Tcp(stream) => stream.send(msg).await,
+            Self::Tls(stream) => stream.send(msg).await,
             Self::Upgraded(stream) => stream.send(msg).await,
         }
     }
@@ -1,51 +1,79 @@
 use crate::auth::Auth;
+use crate::connections::{self, ConnectionLimiter, ConnectionPermit, ServerBusy};
 use crate::database::factory::DbFactory;
 use crate::database::Database;
+use crate::metrics::{self, Frontend};
+use crate::replication::logical::ChangeFeed;
+use crate::system::{ShutdownPhase, ShutdownSignal};
+use crate::tls::TlsConfig;
+use crate::utils::panic::catch_panic_async;
 use crate::utils::services::idle_shutdown::IdleKicker;
+use crate::utils::shared::Shared;
 use anyhow::{Context as _, Result};
 use enclose::enclose;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::TlsAcceptor;
 
 pub mod proto;
 
 mod conn;
 mod handshake;
+mod pubsub;
 mod session;
 
 struct Server<D> {
     db_factory: Arc<dyn DbFactory<Db = D>>,
-    auth: Arc<Auth>,
+    /// Replaced when the configuration is reloaded, for the hello messages received after.
+    auth: Shared<Arc<Auth>>,
     idle_kicker: Option<IdleKicker>,
+    /// Changes delivered to the subscriptions, if the server captures them.
+    change_feed: Option<ChangeFeed>,
+    /// Period of the pings sent to the connections, see [`crate::Config::hrana_ping_interval`].
+    ping_interval: Option<Duration>,
     next_conn_id: AtomicU64,
+    /// Once the shutdown reaches the drain phase, the connections close after answering the
+    /// requests in flight.
+    shutdown: ShutdownSignal,
 }
 
-#[derive(Debug)]
 pub struct Accept {
     pub socket: tokio::net::TcpStream,
     pub peer_addr: SocketAddr,
+    /// Performs the TLS handshake of the connection, if the listener uses TLS.
+    pub tls: Option<TlsAcceptor>,
 }
 
 #[derive(Debug)]
 pub struct Upgrade {
     pub request: hyper::Request<hyper::Body>,
     pub response_tx: oneshot::Sender<hyper::Response<hyper::Body>>,
+    /// Address of the client of the upgraded HTTP connection.
+    pub peer_addr: Option<SocketAddr>,
 }
 
 pub async fn serve(
     db_factory: Arc<dyn DbFactory<Db = impl Database>>,
-    auth: Arc<Auth>,
+    auth: impl Into<Shared<Arc<Auth>>>,
     idle_kicker: Option<IdleKicker>,
+    change_feed: Option<ChangeFeed>,
+    ping_interval: Option<Duration>,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
     mut accept_rx: mpsc::Receiver<Accept>,
     mut upgrade_rx: mpsc::Receiver<Upgrade>,
+    shutdown: ShutdownSignal,
 ) -> Result<()> {
     let server = Arc::new(Server {
         db_factory,
-        auth,
+        auth: auth.into(),
         idle_kicker,
+        change_feed,
+        ping_interval,
         next_conn_id: AtomicU64::new(0),
+        shutdown,
     });
 
     let mut join_set = tokio::task::JoinSet::new();
@@ -55,10 +83,37 @@ pub async fn serve(
                 let conn_id = server.next_conn_id.fetch_add(1, Ordering::AcqRel);
                 tracing::info!("Received TCP connection #{} from {}", conn_id, accept.peer_addr);
 
+                let permit = match acquire(connection_limiter.as_ref()) {
+                    Ok(permit) => permit,
+                    Err(busy) => {
+                        tracing::warn!("refusing TCP connection #{}: {}", conn_id, busy);
+                        join_set.spawn(async move {
+                            if let Err(err) = conn::reject_tcp(accept.socket, accept.tls, busy).await {
+                                tracing::debug!("TCP connection #{} could not be refused: {:?}", conn_id, err);
+                            }
+                        });
+                        continue;
+                    }
+                };
+                let connection = metrics::frontend(Frontend::Ws).open_connection();
+                let client = connections::register(Frontend::Ws, Some(accept.peer_addr));
                 join_set.spawn(enclose!{(server, conn_id) async move {
-                    match conn::handle_tcp(server, accept.socket, conn_id).await {
-                        Ok(_) => tracing::info!("TCP connection #{} was terminated", conn_id),
-                        Err(err) => tracing::error!("TCP connection #{} failed: {:?}", conn_id, err),
+                    let _permit = permit;
+                    let _connection = connection;
+                    let conn = conn::handle_tcp(server, accept.socket, accept.tls, conn_id);
+                    let conn = async {
+                        tokio::select! {
+                            res = connections::scope(Some(client.clone()), conn) => res,
+                            _ = client.closed() => {
+                                tracing::info!("TCP connection #{} was closed by the administrator", conn_id);
+                                Ok(())
+                            }
+                        }
+                    };
+                    match catch_panic_async(conn).await {
+                        Ok(Ok(_)) => tracing::info!("TCP connection #{} was terminated", conn_id),
+                        Ok(Err(err)) => tracing::error!("TCP connection #{} failed: {:?}", conn_id, err),
+                        Err(_) => tracing::error!("TCP connection #{} panicked", conn_id),
                     }
                 }});
             },
@@ -66,15 +121,50 @@ pub async fn serve(
                 let conn_id = server.next_conn_id.fetch_add(1, Ordering::AcqRel);
                 tracing::info!("Received HTTP upgrade connection #{}", conn_id);
 
+                let permit = match acquire(connection_limiter.as_ref()) {
+                    Ok(permit) => permit,
+                    Err(busy) => {
+                        tracing::warn!("refusing HTTP upgrade connection #{}: {}", conn_id, busy);
+                        handshake::reject_upgrade(upgrade, busy.to_string());
+                        continue;
+                    }
+                };
+                let connection = metrics::frontend(Frontend::Ws).open_connection();
+                let client = connections::register(Frontend::Ws, upgrade.peer_addr);
                 join_set.spawn(enclose!{(server, conn_id) async move {
-                    match conn::handle_upgrade(server, upgrade, conn_id).await {
-                        Ok(_) => tracing::info!("HTTP upgrade connection #{} was terminated", conn_id),
-                        Err(err) => tracing::error!("HTTP upgrade connection #{} failed: {:?}", conn_id, err),
+                    let _permit = permit;
+                    let _connection = connection;
+                    let conn = conn::handle_upgrade(server, upgrade, conn_id);
+                    let conn = async {
+                        tokio::select! {
+                            res = connections::scope(Some(client.clone()), conn) => res,
+                            _ = client.closed() => {
+                                tracing::info!("HTTP upgrade connection #{} was closed by the administrator", conn_id);
+                                Ok(())
+                            }
+                        }
+                    };
+                    match catch_panic_async(conn).await {
+                        Ok(Ok(_)) => tracing::info!("HTTP upgrade connection #{} was terminated", conn_id),
+                        Ok(Err(err)) => tracing::error!("HTTP upgrade connection #{} failed: {:?}", conn_id, err),
+                        Err(_) => tracing::error!("HTTP upgrade connection #{} panicked", conn_id),
                     }
                 }});
             },
             Some(task_res) = join_set.join_next() => {
-                task_res.expect("Hrana connection task failed")
+                // a failed connection task only affects its own client
+                if let Err(err) = task_res {
+                    tracing::error!("Hrana connection task failed: {err}");
+                }
+            },
+            _ = server.shutdown.reached(ShutdownPhase::Drain) => {
+                tracing::info!("draining {} Hrana connections", join_set.len());
+                while let Some(task_res) = join_set.join_next().await {
+                    if let Err(err) = task_res {
+                        tracing::error!("Hrana connection task failed: {err}");
+                    }
+                }
+                return Ok(());
             },
             else => {
                 tracing::error!("hrana server loop exited");
@@ -88,7 +178,19 @@ pub async fn serve(
     }
 }
 
-pub async fn listen(bind_addr: SocketAddr, accept_tx: mpsc::Sender<Accept>) -> Result<()> {
+fn acquire(
+    limiter: Option<&Arc<ConnectionLimiter>>,
+) -> Result<Option<ConnectionPermit>, ServerBusy> {
+    limiter
+        .map(|limiter| limiter.try_acquire(Frontend::Ws))
+        .transpose()
+}
+
+pub async fn listen(
+    bind_addr: SocketAddr,
+    tls: Option<Arc<TlsConfig>>,
+    accept_tx: mpsc::Sender<Accept>,
+) -> Result<()> {
     let listener = tokio::net::TcpListener::bind(bind_addr)
         .await
         .context("Could not bind TCP listener")?;
@@ -100,6 +202,482 @@ pub async fn listen(bind_addr: SocketAddr, accept_tx: mpsc::Sender<Accept>) -> R
             .accept()
             .await
             .context("Could not accept a TCP connection")?;
-        let _: Result<_, _> = accept_tx.send(Accept { socket, peer_addr }).await;
+        let tls = tls.as_ref().map(|tls| tls.acceptor());
+        let _: Result<_, _> = accept_tx
+            .send(Accept {
+                socket,
+                peer_addr,
+                tls,
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use futures::{SinkExt as _, StreamExt as _};
+    use serde_json::{json, Value};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+    use tower_http::cors::CorsLayer;
+
+    use super::*;
+    use crate::database::config::DatabaseConfigStore;
+    use crate::database::libsql::{LibSqlDb, LibSqlDbFactory};
+    use crate::database::settings::SessionConfig;
+    use crate::http::readiness::Readiness;
+    use crate::replication::topology::Topology;
+    use crate::stats::Stats;
+    use crate::system::{RestartPolicy, System};
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    type Client = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn db_factory(path: &std::path::Path) -> Arc<dyn DbFactory<Db = LibSqlDb>> {
+        Arc::new(
+            LibSqlDbFactory::new(
+                path.to_path_buf(),
+                &TRANSPARENT_METHODS,
+                || (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                Vec::new(),
+                u64::MAX,
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                None,
+            )
+            .await
+            .unwrap(),
+        )
+    }
+
+    /// Runs an HTTP server that upgrades its connections to Hrana, and returns its address.
+    async fn start(
+        system: &mut System,
+        path: &std::path::Path,
+        ping_interval: Option<Duration>,
+    ) -> SocketAddr {
+        let db_factory = db_factory(path).await;
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let auth = Arc::new(Auth {
+            disabled: true,
+            ..Default::default()
+        });
+        // the connections are upgraded from HTTP, there is no Hrana listener
+        let (_, accept_rx) = mpsc::channel(1);
+        let (upgrade_tx, upgrade_rx) = mpsc::channel(8);
+        let hrana_http_srv = Arc::new(crate::hrana::http::Server::new(db_factory.clone(), None));
+        let topology = Arc::new(Topology::primary(Vec::new(), "test".into()));
+        let (_, frame_no) = tokio::sync::watch::channel(0);
+        let readiness = Arc::new(Readiness::new(topology.clone(), frame_no, 0));
+
+        system.register_graceful(
+            ShutdownPhase::Drain,
+            {
+                let db_factory = db_factory.clone();
+                let auth = auth.clone();
+                move |shutdown| {
+                    serve(
+                        db_factory,
+                        auth,
+                        None,
+                        None,
+                        ping_interval,
+                        None,
+                        accept_rx,
+                        upgrade_rx,
+                        shutdown,
+                    )
+                }
+            },
+            "Hrana server",
+        );
+        system.supervise(
+            "HTTP server",
+            ShutdownPhase::StopAccepting,
+            RestartPolicy::never(),
+            move |shutdown| {
+                crate::http::run_http(
+                    addr,
+                    None,
+                    None,
+                    auth.clone(),
+                    db_factory.clone(),
+                    upgrade_tx.clone(),
+                    hrana_http_srv.clone(),
+                    false,
+                    false,
+                    false,
+                    None,
+                    None,
+                    topology.clone(),
+                    readiness.clone(),
+                    u64::MAX,
+                    CorsLayer::new(),
+                    None,
+                    None,
+                    None,
+                    None,
+                    8192,
+                    shutdown,
+                )
+            },
+        );
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> Client {
+        let mut req = format!("ws://{addr}/v1/ws").into_client_request().unwrap();
+        req.headers_mut()
+            .insert("sec-websocket-protocol", "hrana2".parse().unwrap());
+        let (mut client, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+        send(&mut client, json!({"type": "hello", "jwt": null})).await;
+        assert_eq!(recv(&mut client).await["type"], "hello_ok");
+        client
+    }
+
+    async fn send(client: &mut Client, msg: Value) {
+        client.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    async fn recv(client: &mut Client) -> Value {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                Message::Text(msg) => return serde_json::from_str(&msg).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+    }
+
+    async fn request(client: &mut Client, request_id: i32, request: Value) {
+        send(
+            client,
+            json!({"type": "request", "request_id": request_id, "request": request}),
+        )
+        .await;
+    }
+
+    /// Receives the responses of `n` requests, whatever their order, by request id.
+    async fn responses(client: &mut Client, n: usize) -> HashMap<i64, Value> {
+        let mut responses = HashMap::new();
+        while responses.len() < n {
+            let msg = recv(client).await;
+            responses.insert(msg["request_id"].as_i64().unwrap(), msg);
+        }
+        responses
+    }
+
+    fn execute(stream_id: i32, sql: &str) -> Value {
+        json!({"type": "execute", "stream_id": stream_id, "stmt": {"sql": sql, "want_rows": true}})
+    }
+
+    fn count(response: &Value) -> &Value {
+        &response["response"]["result"]["rows"][0][0]["value"]
+    }
+
+    #[tokio::test]
+    async fn transactions_of_the_streams() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut system = System::new();
+        let addr = start(&mut system, tmp.path(), None).await;
+        let mut client = connect(addr).await;
+
+        // the requests are sent before their responses are read
+        request(
+            &mut client,
+            1,
+            json!({"type": "open_stream", "stream_id": 1}),
+        )
+        .await;
+        request(
+            &mut client,
+            2,
+            json!({"type": "open_stream", "stream_id": 2}),
+        )
+        .await;
+        request(&mut client, 3, execute(1, "CREATE TABLE t (x)")).await;
+        request(&mut client, 4, execute(1, "BEGIN")).await;
+        request(
+            &mut client,
+            5,
+            json!({"type": "batch", "stream_id": 1, "batch": {"steps": [
+                {"stmt": {"sql": "INSERT INTO t VALUES (?)", "args": [{"type": "integer", "value": "1"}]}},
+                {"stmt": {"sql": "INSERT INTO t VALUES (?)", "args": [{"type": "integer", "value": "2"}]}},
+            ]}}),
+        )
+        .await;
+        let responses = responses(&mut client, 5).await;
+        for request_id in 1..=5 {
+            assert_eq!(
+                responses[&request_id]["type"], "response_ok",
+                "{responses:?}"
+            );
+        }
+
+        // the transaction of a stream is not seen by the others until it commits
+        request(&mut client, 6, execute(2, "SELECT count(*) FROM t")).await;
+        assert_eq!(count(&recv(&mut client).await), "0");
+        request(&mut client, 7, execute(1, "COMMIT")).await;
+        assert_eq!(recv(&mut client).await["type"], "response_ok");
+        request(&mut client, 8, execute(2, "SELECT count(*) FROM t")).await;
+        assert_eq!(count(&recv(&mut client).await), "2");
+
+        request(
+            &mut client,
+            9,
+            json!({"type": "close_stream", "stream_id": 1}),
+        )
+        .await;
+        assert_eq!(recv(&mut client).await["type"], "response_ok");
+        request(&mut client, 10, execute(1, "SELECT 1")).await;
+        // a request to a closed stream is a protocol error
+        match client.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_are_bounded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut system = System::new();
+        let addr = start(&mut system, tmp.path(), None).await;
+        let mut client = connect(addr).await;
+
+        let n = session::MAX_STREAM_COUNT as i32;
+        for stream_id in 0..=n {
+            request(
+                &mut client,
+                stream_id,
+                json!({"type": "open_stream", "stream_id": stream_id}),
+            )
+            .await;
+        }
+        let responses = responses(&mut client, n as usize + 1).await;
+        assert_eq!(responses[&0]["type"], "response_ok");
+        assert_eq!(responses[&(n as i64)]["type"], "response_error");
+        assert_eq!(responses[&(n as i64)]["error"]["code"], "STREAM_TOO_MANY");
+
+        // a closed stream makes room for another one
+        request(
+            &mut client,
+            100,
+            json!({"type": "close_stream", "stream_id": 0}),
+        )
+        .await;
+        request(
+            &mut client,
+            101,
+            json!({"type": "open_stream", "stream_id": n}),
+        )
+        .await;
+        let responses = responses(&mut client, 2).await;
+        assert_eq!(responses[&101]["type"], "response_ok", "{responses:?}");
+    }
+
+    #[tokio::test]
+    async fn unanswered_pings_close_the_connection() {
+        const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut system = System::new();
+        let addr = start(&mut system, tmp.path(), Some(PING_INTERVAL)).await;
+
+        // a client that reads its messages answers the pings
+        let mut client = connect(addr).await;
+        let mut pings = 0;
+        let _ = tokio::time::timeout(PING_INTERVAL * 10, async {
+            while let Some(msg) = client.next().await {
+                match msg.unwrap() {
+                    Message::Ping(_) => pings += 1,
+                    msg => panic!("unexpected message {msg:?}"),
+                }
+            }
+        })
+        .await;
+        assert!(pings >= 5, "{pings} pings");
+
+        // a client that doesn't is closed
+        let mut client = connect(addr).await;
+        tokio::time::sleep(PING_INTERVAL * 5).await;
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(Message::Close(frame))) => return frame.map(|frame| frame.code),
+                    Some(Ok(_)) => continue,
+                    // the pong answering the ping may not reach the server anymore
+                    Some(Err(_)) | None => return None,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(closed, Some(CloseCode::Away) | None), "{closed:?}");
+    }
+
+    #[tokio::test]
+    async fn connection_limit() {
+        use crate::connections::ConnectionLimits;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut system = System::new();
+        let db_factory = db_factory(tmp.path()).await;
+        let auth = Arc::new(Auth {
+            disabled: true,
+            ..Default::default()
+        });
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let limiter = Arc::new(ConnectionLimiter::new(ConnectionLimits {
+            ws: Some(1),
+            ..Default::default()
+        }));
+        let (accept_tx, accept_rx) = mpsc::channel(8);
+        let (_, upgrade_rx) = mpsc::channel(1);
+        system.register_graceful(
+            ShutdownPhase::Drain,
+            enclose! {(limiter) move |shutdown| {
+                serve(
+                    db_factory,
+                    auth,
+                    None,
+                    None,
+                    None,
+                    Some(limiter),
+                    accept_rx,
+                    upgrade_rx,
+                    shutdown,
+                )
+            }},
+            "Hrana server",
+        );
+        system.register(listen(addr, None, accept_tx), "Hrana listener");
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        while limiter.open(Frontend::Ws) > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let connect = || async {
+            let mut req = format!("ws://{addr}/").into_client_request().unwrap();
+            req.headers_mut()
+                .insert("sec-websocket-protocol", "hrana2".parse().unwrap());
+            tokio_tungstenite::connect_async(req)
+                .await
+                .map(|(client, _)| client)
+        };
+        let mut first = connect().await.unwrap();
+        send(&mut first, json!({"type": "hello", "jwt": null})).await;
+        assert_eq!(recv(&mut first).await["type"], "hello_ok");
+
+        // another connection is refused at once, rather than waiting for a place
+        let err = tokio::time::timeout(Duration::from_secs(5), connect())
+            .await
+            .unwrap()
+            .unwrap_err();
+        match err {
+            tokio_tungstenite::tungstenite::Error::Http(resp) => {
+                assert_eq!(resp.status(), 503);
+                assert_eq!(resp.headers()["retry-after"], "1");
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+
+        // a closed connection gives its place back
+        drop(first);
+        let start = std::time::Instant::now();
+        while connect().await.is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_listener_over_tls() {
+        use crate::tls::test::{connector, fixture, localhost};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut system = System::new();
+        let db_factory = db_factory(tmp.path()).await;
+        let auth = Arc::new(Auth {
+            disabled: true,
+            ..Default::default()
+        });
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // the clients must present a certificate signed by the CA
+        let tls = TlsConfig::load(
+            fixture("server.pem"),
+            fixture("server-key.pem"),
+            Some(fixture("ca.pem")),
+        )
+        .unwrap();
+        let (accept_tx, accept_rx) = mpsc::channel(8);
+        let (_, upgrade_rx) = mpsc::channel(1);
+        system.register_graceful(
+            ShutdownPhase::Drain,
+            move |shutdown| {
+                serve(
+                    db_factory, auth, None, None, None, None, accept_rx, upgrade_rx, shutdown,
+                )
+            },
+            "Hrana server",
+        );
+        system.register(
+            listen(addr, Some(Arc::new(tls)), accept_tx),
+            "Hrana listener",
+        );
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let connect = |client_cert: bool| async move {
+            let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let socket = connector("ca.pem", client_cert)
+                .connect(localhost(), socket)
+                .await?;
+            let mut req = format!("wss://{addr}/").into_client_request().unwrap();
+            req.headers_mut()
+                .insert("sec-websocket-protocol", "hrana2".parse().unwrap());
+            let (client, _) = tokio_tungstenite::client_async(req, socket)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            Ok::<_, std::io::Error>(client)
+        };
+
+        // with TLS 1.3, the missing client certificate fails the WebSocket handshake, which follows
+        // the TLS handshake of the client
+        assert!(connect(false).await.is_err());
+
+        let mut client = connect(true).await.unwrap();
+        let msg = json!({"type": "hello", "jwt": null}).to_string();
+        client.send(Message::Text(msg)).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            Message::Text(msg) => {
+                let msg: Value = serde_json::from_str(&msg).unwrap();
+                assert_eq!(msg["type"], "hello_ok");
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
     }
 }
@@ -17,6 +17,7 @@ pub enum ServerMsg {
     HelloError { error: Error },
     ResponseOk { request_id: i32, response: Response },
     ResponseError { request_id: i32, error: Error },
+    Change(ChangeEvent),
 }
 
 #[derive(Deserialize, Debug)]
@@ -30,6 +31,8 @@ pub enum Request {
     Describe(DescribeReq),
     StoreSql(StoreSqlReq),
     CloseSql(CloseSqlReq),
+    Subscribe(SubscribeReq),
+    Unsubscribe(UnsubscribeReq),
 }
 
 #[derive(Serialize, Debug)]
@@ -43,6 +46,8 @@ pub enum Response {
     Describe(DescribeResp),
     StoreSql(StoreSqlResp),
     CloseSql(CloseSqlResp),
+    Subscribe(SubscribeResp),
+    Unsubscribe(UnsubscribeResp),
 }
 
 #[derive(Deserialize, Debug)]
@@ -125,3 +130,53 @@ pub struct CloseSqlReq {
 
 #[derive(Serialize, Debug)]
 pub struct CloseSqlResp {}
+
+#[derive(Deserialize, Debug)]
+pub struct SubscribeReq {
+    pub table: String,
+    #[serde(default)]
+    pub filter: Option<SubscriptionFilter>,
+}
+
+/// Restricts a subscription to the changes to some rows of the table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SubscriptionFilter {
+    /// The row with this rowid.
+    Rowid { rowid: i64 },
+    /// The rows where `column` is equal to `value`. `column` must be the leading column of an
+    /// index of the table.
+    Column { column: String, value: Value },
+}
+
+#[derive(Serialize, Debug)]
+pub struct SubscribeResp {
+    pub subscription_id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnsubscribeReq {
+    pub subscription_id: i32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UnsubscribeResp {}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub subscription_id: i32,
+    pub table: String,
+    pub op: ChangeOp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rowid: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOp {
+    /// The row was inserted or updated.
+    Upsert,
+    Delete,
+    /// The table was created, altered or dropped.
+    Schema,
+}
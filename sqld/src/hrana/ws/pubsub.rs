@@ -0,0 +1,433 @@
+//! Subscriptions to the changes to the tables of the database.
+//!
+//! A client subscribes to the changes to a table with a `subscribe` request, and the server then
+//! pushes a `change` message for every row that is inserted, updated or deleted in the table. The
+//! changes come from the [`ChangeFeed`] of the server, which only carries committed changes: on the
+//! primary, once the transaction commits, and on logical replicas, once the changes are applied.
+//!
+//! Subscriptions belong to a connection, and are dropped with it. A connection that doesn't keep up
+//! with the changes is closed with a protocol error, instead of buffering them.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Context as _, Result};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use super::proto;
+use super::session::{catch_stmt_error, ResponseError};
+use crate::auth::Authenticated;
+use crate::database::factory::DbFactory;
+use crate::database::Database;
+use crate::hrana::stmt::{self, proto_value_to_value};
+use crate::query::{Params, Query, Value};
+use crate::query_analysis::Statement;
+use crate::replication::logical::{Change, ChangeBatch, ChangeFeed};
+
+/// Maximum number of subscriptions of a connection.
+pub const MAX_SUBSCRIPTIONS: usize = 32;
+
+/// Checks that the table exists, and that the column of the filter, if any, is the leading
+/// column of one of its indexes.
+const CHECK_SUBSCRIPTION_SQL: &str = "SELECT
+    EXISTS (SELECT 1 FROM pragma_table_info(?1)),
+    ?2 IS NULL OR EXISTS (
+        SELECT 1 FROM pragma_index_list(?1) AS idx, pragma_index_info(idx.name) AS col
+        WHERE col.seqno = 0 AND col.name = ?2 COLLATE NOCASE
+    )";
+
+#[derive(Debug)]
+struct Subscription {
+    table: String,
+    filter: Option<Filter>,
+}
+
+#[derive(Debug)]
+enum Filter {
+    Rowid(i64),
+    Column { column: String, value: Value },
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: i32,
+    subscriptions: BTreeMap<i32, Subscription>,
+}
+
+/// The subscriptions of a connection.
+pub struct Subscriptions {
+    feed: Option<ChangeFeed>,
+    /// Created with the first subscription, so that connections without subscriptions don't have
+    /// to follow the feed.
+    receiver: Option<broadcast::Receiver<Arc<ChangeBatch>>>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Subscriptions {
+    pub fn new(feed: Option<ChangeFeed>) -> Self {
+        Self {
+            feed,
+            receiver: None,
+            registry: Default::default(),
+        }
+    }
+
+    /// Starts following the change feed, before the subscription is checked and registered. The
+    /// changes committed in between are not delivered.
+    fn prepare(&mut self) -> Result<()> {
+        let Some(feed) = self.feed.as_ref() else {
+            bail!(ResponseError::SubscriptionsUnavailable)
+        };
+
+        let count = self.registry.lock().subscriptions.len();
+        if count >= MAX_SUBSCRIPTIONS {
+            bail!(ResponseError::SubscriptionTooMany { count })
+        }
+
+        self.receiver.get_or_insert_with(|| feed.subscribe());
+        Ok(())
+    }
+
+    /// Removes a subscription, returns false if it doesn't exist.
+    pub fn remove(&mut self, subscription_id: i32) -> bool {
+        self.registry
+            .lock()
+            .subscriptions
+            .remove(&subscription_id)
+            .is_some()
+    }
+
+    /// Waits for the next batch of changes. Never returns if the connection has no subscription.
+    pub async fn recv(&mut self) -> Result<Arc<ChangeBatch>, broadcast::error::RecvError> {
+        match self.receiver.as_mut() {
+            Some(receiver) => receiver.recv().await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Stops following the change feed, after it was closed.
+    pub fn close(&mut self) {
+        self.receiver = None;
+        self.feed = None;
+    }
+
+    /// Returns the `change` messages for the changes of `batch` that match the subscriptions.
+    pub fn events(&self, batch: &ChangeBatch) -> Vec<proto::ChangeEvent> {
+        let registry = self.registry.lock();
+        let mut events = Vec::new();
+        for change in batch.changes.iter() {
+            for (&subscription_id, subscription) in registry.subscriptions.iter() {
+                if let Some((op, rowid)) = subscription.matches(change) {
+                    events.push(proto::ChangeEvent {
+                        subscription_id,
+                        table: change.table().to_string(),
+                        op,
+                        rowid,
+                    });
+                }
+            }
+        }
+        events
+    }
+}
+
+impl Subscription {
+    fn new(req: proto::SubscribeReq) -> Self {
+        let filter = req.filter.map(|filter| match filter {
+            proto::SubscriptionFilter::Rowid { rowid } => Filter::Rowid(rowid),
+            proto::SubscriptionFilter::Column { column, value } => Filter::Column {
+                column,
+                value: proto_value_to_value(&value),
+            },
+        });
+
+        Self {
+            table: req.table,
+            filter,
+        }
+    }
+
+    /// Returns the operation and rowid to report for `change`, or `None` if the subscription
+    /// doesn't match it. The old values of deleted rows are not known, so deletes are reported to
+    /// all the subscriptions with a column filter.
+    fn matches(&self, change: &Change) -> Option<(proto::ChangeOp, Option<i64>)> {
+        if !change.table().eq_ignore_ascii_case(&self.table) {
+            return None;
+        }
+
+        match (change, &self.filter) {
+            (Change::Schema { .. }, _) => Some((proto::ChangeOp::Schema, None)),
+            (Change::Delete { rowid, .. }, Some(Filter::Rowid(id))) if rowid != id => None,
+            (Change::Delete { rowid, .. }, _) => Some((proto::ChangeOp::Delete, Some(*rowid))),
+            (Change::Upsert { rowid, .. }, Some(Filter::Rowid(id))) if rowid != id => None,
+            (
+                Change::Upsert {
+                    rowid,
+                    columns,
+                    values,
+                    ..
+                },
+                Some(Filter::Column { column, value }),
+            ) => {
+                let index = columns
+                    .iter()
+                    .position(|c| c.eq_ignore_ascii_case(column))?;
+                values_eq(&values[index], value).then_some((proto::ChangeOp::Upsert, Some(*rowid)))
+            }
+            (Change::Upsert { rowid, .. }, _) => Some((proto::ChangeOp::Upsert, Some(*rowid))),
+        }
+    }
+}
+
+fn values_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a == b,
+        (Value::Real(a), Value::Real(b)) => a == b,
+        (Value::Integer(a), Value::Real(b)) | (Value::Real(b), Value::Integer(a)) => {
+            *a as f64 == *b
+        }
+        (Value::Text(a), Value::Text(b)) => a == b,
+        (Value::Blob(a), Value::Blob(b)) => a == b,
+        // like in SQL, NULL is not equal to anything
+        _ => false,
+    }
+}
+
+/// Handles a `subscribe` request: checks the subscription, and registers it.
+pub(super) fn subscribe<D: Database>(
+    subscriptions: &mut Subscriptions,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+    auth: Authenticated,
+    req: proto::SubscribeReq,
+) -> Result<impl std::future::Future<Output = Result<proto::Response>>> {
+    subscriptions.prepare()?;
+    let registry = subscriptions.registry.clone();
+
+    Ok(async move {
+        let column = match &req.filter {
+            Some(proto::SubscriptionFilter::Column { column, .. }) => Some(column.clone()),
+            _ => None,
+        };
+        let db = db_factory
+            .create()
+            .await
+            .context("Could not create a database connection")?;
+        check_subscription(&db, auth, &req.table, column).await?;
+
+        let mut registry = registry.lock();
+        let count = registry.subscriptions.len();
+        if count >= MAX_SUBSCRIPTIONS {
+            bail!(ResponseError::SubscriptionTooMany { count })
+        }
+        let subscription_id = registry.next_id;
+        registry.next_id += 1;
+        registry
+            .subscriptions
+            .insert(subscription_id, Subscription::new(req));
+
+        Ok(proto::Response::Subscribe(proto::SubscribeResp {
+            subscription_id,
+        }))
+    })
+}
+
+async fn check_subscription(
+    db: &impl Database,
+    auth: Authenticated,
+    table: &str,
+    column: Option<String>,
+) -> Result<()> {
+    let stmt = Statement::parse(CHECK_SUBSCRIPTION_SQL)
+        .next()
+        .expect("missing statement")?;
+    let query = Query {
+        stmt,
+        params: Params::Positional(vec![
+            Value::Text(table.to_string()),
+            column.clone().map_or(Value::Null, Value::Text),
+        ]),
+        want_rows: true,
+    };
+    let result = stmt::execute_stmt(db, auth, query)
+        .await
+        .map_err(catch_stmt_error)?;
+
+    let is_true =
+        |value: Option<&proto::Value>| matches!(value, Some(proto::Value::Integer { value: 1 }));
+    let row = result.rows.first();
+    if !is_true(row.and_then(|row| row.first())) {
+        bail!(ResponseError::SubscriptionInvalid {
+            reason: format!("table `{table}` does not exist"),
+        })
+    }
+    if !is_true(row.and_then(|row| row.get(1))) {
+        bail!(ResponseError::SubscriptionInvalid {
+            reason: format!(
+                "column `{}` is not the leading column of an index of table `{table}`",
+                column.unwrap_or_default()
+            ),
+        })
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::replication::logical::LogicalOffset;
+
+    fn upsert(table: &str, rowid: i64, queue: &str) -> Change {
+        Change::Upsert {
+            table: table.into(),
+            rowid,
+            columns: vec!["id".into(), "Queue".into()],
+            values: vec![Value::Integer(rowid), Value::Text(queue.into())],
+        }
+    }
+
+    fn subscription(table: &str, filter: Option<Filter>) -> Subscription {
+        Subscription {
+            table: table.into(),
+            filter,
+        }
+    }
+
+    #[test]
+    fn match_changes() {
+        let jobs = subscription("jobs", None);
+        assert_eq!(
+            jobs.matches(&upsert("JOBS", 1, "emails")),
+            Some((proto::ChangeOp::Upsert, Some(1)))
+        );
+        assert_eq!(jobs.matches(&upsert("other", 1, "emails")), None);
+        assert_eq!(
+            jobs.matches(&Change::Delete {
+                table: "jobs".into(),
+                rowid: 2
+            }),
+            Some((proto::ChangeOp::Delete, Some(2)))
+        );
+        assert_eq!(
+            jobs.matches(&Change::Schema {
+                table: "jobs".into(),
+                sql: None
+            }),
+            Some((proto::ChangeOp::Schema, None))
+        );
+
+        let row = subscription("jobs", Some(Filter::Rowid(1)));
+        assert!(row.matches(&upsert("jobs", 1, "emails")).is_some());
+        assert!(row.matches(&upsert("jobs", 2, "emails")).is_none());
+        assert!(row
+            .matches(&Change::Delete {
+                table: "jobs".into(),
+                rowid: 2
+            })
+            .is_none());
+
+        let queue = subscription(
+            "jobs",
+            Some(Filter::Column {
+                column: "queue".into(),
+                value: Value::Text("emails".into()),
+            }),
+        );
+        assert!(queue.matches(&upsert("jobs", 1, "emails")).is_some());
+        assert!(queue.matches(&upsert("jobs", 1, "images")).is_none());
+        // the old values of deleted rows are unknown
+        assert!(queue
+            .matches(&Change::Delete {
+                table: "jobs".into(),
+                rowid: 1
+            })
+            .is_some());
+    }
+
+    #[test]
+    fn events_of_a_batch() {
+        let mut subscriptions = Subscriptions::new(Some(ChangeFeed::new()));
+        assert!(subscriptions
+            .events(&ChangeBatch {
+                log_id: Uuid::nil(),
+                offset: LogicalOffset(1),
+                reset: false,
+                complete: true,
+                changes: vec![upsert("jobs", 1, "emails")],
+            })
+            .is_empty());
+
+        subscriptions.prepare().unwrap();
+        {
+            let mut registry = subscriptions.registry.lock();
+            registry
+                .subscriptions
+                .insert(0, subscription("jobs", Some(Filter::Rowid(2))));
+            registry.subscriptions.insert(1, subscription("jobs", None));
+        }
+
+        let events = subscriptions.events(&ChangeBatch {
+            log_id: Uuid::nil(),
+            offset: LogicalOffset(2),
+            reset: false,
+            complete: true,
+            changes: vec![upsert("jobs", 1, "emails"), upsert("jobs", 2, "emails")],
+        });
+        let events = events
+            .iter()
+            .map(|e| (e.subscription_id, e.rowid))
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![(1, Some(1)), (0, Some(2)), (1, Some(2))]);
+
+        assert!(subscriptions.remove(1));
+        assert!(!subscriptions.remove(1));
+    }
+
+    #[test]
+    fn subscriptions_are_bounded() {
+        let mut subscriptions = Subscriptions::new(Some(ChangeFeed::new()));
+        for id in 0..MAX_SUBSCRIPTIONS as i32 {
+            subscriptions
+                .registry
+                .lock()
+                .subscriptions
+                .insert(id, subscription("jobs", None));
+        }
+        let err = subscriptions.prepare().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ResponseError>(),
+            Some(ResponseError::SubscriptionTooMany { .. })
+        ));
+
+        let mut subscriptions = Subscriptions::new(None);
+        let err = subscriptions.prepare().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ResponseError>(),
+            Some(ResponseError::SubscriptionsUnavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_consumers_lag() {
+        let feed = ChangeFeed::new();
+        let mut subscriptions = Subscriptions::new(Some(feed.clone()));
+        subscriptions.prepare().unwrap();
+        for offset in 0..1000 {
+            feed.publish(Arc::new(ChangeBatch {
+                log_id: Uuid::nil(),
+                offset: LogicalOffset(offset),
+                reset: false,
+                complete: true,
+                changes: Vec::new(),
+            }));
+        }
+
+        assert!(matches!(
+            subscriptions.recv().await,
+            Err(broadcast::error::RecvError::Lagged(_))
+        ));
+    }
+}
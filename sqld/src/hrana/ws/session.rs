@@ -5,16 +5,21 @@ use futures::future::BoxFuture;
 use tokio::sync::{mpsc, oneshot};
 
 use super::super::{batch, stmt, ProtocolError, Version};
+use super::pubsub::{self, Subscriptions};
 use super::{proto, Server};
-use crate::auth::{AuthError, Authenticated};
+use crate::auth::{self, AuthError, Authenticated, Identity};
+use crate::connections;
 use crate::database::Database;
+use crate::system::ShutdownSignal;
 
 /// Session-level state of an authenticated Hrana connection.
 pub struct Session<D> {
     authenticated: Authenticated,
+    identity: Identity,
     version: Version,
     streams: HashMap<i32, StreamHandle<D>>,
     sqls: HashMap<i32, String>,
+    pub(super) subscriptions: Subscriptions,
 }
 
 struct StreamHandle<D> {
@@ -48,12 +53,20 @@ pub enum ResponseError {
     Auth { source: AuthError },
     #[error("Stream {stream_id} has failed to open")]
     StreamNotOpen { stream_id: i32 },
+    #[error("The connection already has {count} streams, it cannot open more")]
+    StreamTooMany { count: usize },
     #[error("The server already stores {count} SQL texts, it cannot store more")]
     SqlTooMany { count: usize },
     #[error(transparent)]
     Stmt(stmt::StmtError),
     #[error(transparent)]
     Batch(batch::BatchError),
+    #[error("Subscriptions to changes are only available on a primary with logical replication enabled, or on a logical replica")]
+    SubscriptionsUnavailable,
+    #[error("The connection already has {count} subscriptions, it cannot have more")]
+    SubscriptionTooMany { count: usize },
+    #[error("Invalid subscription: {reason}")]
+    SubscriptionInvalid { reason: String },
 }
 
 pub(super) fn handle_initial_hello<D: Database>(
@@ -61,16 +74,15 @@ pub(super) fn handle_initial_hello<D: Database>(
     version: Version,
     jwt: Option<String>,
 ) -> Result<Session<D>> {
-    let authenticated = server
-        .auth
-        .authenticate_jwt(jwt.as_deref())
-        .map_err(|err| anyhow!(ResponseError::Auth { source: err }))?;
+    let (authenticated, identity) = identify(server, jwt.as_deref())?;
 
     Ok(Session {
         authenticated,
+        identity,
         version,
         streams: HashMap::new(),
         sqls: HashMap::new(),
+        subscriptions: Subscriptions::new(server.change_feed.clone()),
     })
 }
 
@@ -86,11 +98,33 @@ pub(super) fn handle_repeated_hello<DB: Database>(
         })
     }
 
-    session.authenticated = server
+    (session.authenticated, session.identity) = identify(server, jwt.as_deref())?;
+    Ok(())
+}
+
+/// Authenticates the JWT of a hello message. The connections query the default database, which
+/// the JWTs restricted to a namespace don't grant access to.
+fn identify<D>(server: &Server<D>, jwt: Option<&str>) -> Result<(Authenticated, Identity)> {
+    let (authenticated, identity) = server
         .auth
-        .authenticate_jwt(jwt.as_deref())
+        .get()
+        .identify_jwt(jwt)
         .map_err(|err| anyhow!(ResponseError::Auth { source: err }))?;
-    Ok(())
+    if let Some(namespace) = &identity.namespace {
+        bail!(ResponseError::Auth {
+            source: AuthError::JwtNamespaceRestricted {
+                namespace: namespace.clone(),
+            },
+        });
+    }
+    Ok((authenticated, identity))
+}
+
+impl<D> Session<D> {
+    /// Closes all the streams, once the jobs sent to them are done.
+    pub(super) fn close_streams(&mut self) {
+        self.streams.clear();
+    }
 }
 
 pub(super) async fn handle_request<DB: Database>(
@@ -157,9 +191,19 @@ pub(super) async fn handle_request<DB: Database>(
             let stream_id = req.stream_id;
             if session.streams.contains_key(&stream_id) {
                 bail!(ProtocolError::StreamExists { stream_id })
+            } else if session.streams.len() >= MAX_STREAM_COUNT {
+                bail!(ResponseError::StreamTooMany {
+                    count: session.streams.len()
+                })
             }
 
-            let mut stream_hnd = stream_spawn(join_set, Stream { db: None });
+            let mut stream_hnd = stream_spawn(
+                join_set,
+                Stream { db: None },
+                server.shutdown.clone(),
+                session.authenticated,
+                session.identity.clone(),
+            );
             let db_factory = server.db_factory.clone();
 
             stream_respond!(&mut stream_hnd, async move |stream| {
@@ -279,24 +323,59 @@ pub(super) async fn handle_request<DB: Database>(
             session.sqls.remove(&req.sql_id);
             respond!(proto::Response::CloseSql(proto::CloseSqlResp {}));
         }
+        proto::Request::Subscribe(req) => {
+            let subscribe = pubsub::subscribe(
+                &mut session.subscriptions,
+                server.db_factory.clone(),
+                session.authenticated,
+                req,
+            )?;
+            join_set.spawn(async move {
+                let _: Result<_, _> = resp_tx.send(subscribe.await);
+            });
+        }
+        proto::Request::Unsubscribe(req) => {
+            let subscription_id = req.subscription_id;
+            if !session.subscriptions.remove(subscription_id) {
+                bail!(ProtocolError::SubscriptionNotFound { subscription_id })
+            }
+            respond!(proto::Response::Unsubscribe(proto::UnsubscribeResp {}));
+        }
     }
     Ok(resp_rx)
 }
 
 const MAX_SQL_COUNT: usize = 150;
+/// Streams that a connection can have open at once, each with a database connection of its own.
+pub const MAX_STREAM_COUNT: usize = 64;
 
 fn stream_spawn<D: Database>(
     join_set: &mut tokio::task::JoinSet<()>,
     stream: Stream<D>,
+    shutdown: ShutdownSignal,
+    auth: Authenticated,
+    identity: Identity,
 ) -> StreamHandle<D> {
     let (job_tx, mut job_rx) = mpsc::channel::<StreamJob<D>>(8);
-    join_set.spawn(async move {
+    // the stream serves the client connection of the task that opens it, with the identity the
+    // client had when it opened the stream
+    let client = connections::current();
+    let job_loop = auth::with_identity(identity, async move {
         let mut stream = stream;
         while let Some(job) = job_rx.recv().await {
             let res = (job.f)(&mut stream).await;
             let _: Result<_, _> = job.resp_tx.send(res);
         }
+
+        // A dropped database rolls back its transaction, but a replica only asks the primary to
+        // do so in the background, which may not happen before the process exits.
+        if shutdown.phase().is_some() {
+            if let Some(db) = stream.db.take() {
+                let _: Result<_, _> = db.rollback(auth).await;
+            }
+        }
     });
+    join_set.spawn(connections::scope(client, job_loop));
     StreamHandle { job_tx }
 }
 
@@ -315,7 +394,7 @@ async fn stream_respond<F, D>(
     let _: Result<_, _> = stream_hnd.job_tx.send(job).await;
 }
 
-fn catch_stmt_error(err: anyhow::Error) -> anyhow::Error {
+pub(super) fn catch_stmt_error(err: anyhow::Error) -> anyhow::Error {
     match err.downcast::<stmt::StmtError>() {
         Ok(stmt_err) => anyhow!(ResponseError::Stmt(stmt_err)),
         Err(err) => err,
@@ -335,8 +414,19 @@ impl ResponseError {
             Self::Auth { source } => source.code(),
             Self::SqlTooMany { .. } => "SQL_STORE_TOO_MANY",
             Self::StreamNotOpen { .. } => "STREAM_NOT_OPEN",
+            Self::StreamTooMany { .. } => "STREAM_TOO_MANY",
             Self::Stmt(err) => err.code(),
             Self::Batch(err) => err.code(),
+            Self::SubscriptionsUnavailable => "SUBSCRIPTIONS_UNAVAILABLE",
+            Self::SubscriptionTooMany { .. } => "SUBSCRIPTION_TOO_MANY",
+            Self::SubscriptionInvalid { .. } => "SUBSCRIPTION_INVALID",
+        }
+    }
+
+    pub fn constraint_violations(&self) -> Vec<proto::ConstraintViolation> {
+        match self {
+            Self::Stmt(err) => err.constraint_violations(),
+            _ => Vec::new(),
         }
     }
 }
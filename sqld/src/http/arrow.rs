@@ -0,0 +1,822 @@
+//! Query results in the Apache Arrow IPC stream format, for the clients that load them in
+//! dataframes.
+//!
+//! A request asks for them with `Accept: application/vnd.apache.arrow.stream`, or with
+//! `?format=arrow`. The response is the concatenation of one IPC stream per statement of the batch,
+//! each with its schema, its record batches and an end-of-stream marker. The record batches are
+//! encoded by the connection as it steps through the rows, and streamed with chunked transfer, so
+//! that large results are never held in memory as a whole.
+//!
+//! The type of a column is inferred from the values of its first record batch: INTEGER values give
+//! `Int64`, REAL `Float64`, TEXT `Utf8` and BLOB `Binary`. A column of NULLs takes the type of its
+//! declared type, or `Null`. A column with values of several types falls back to `Utf8`, with its
+//! blobs in base64: such columns are listed in the `x-sqld-warning` header, if the response didn't
+//! start yet, and flagged in the metadata of their field.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use arrow_array::{
+    ArrayRef, BinaryArray, Float64Array, Int64Array, NullArray, RecordBatch, StringArray,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use hyper::header::ACCEPT;
+use hyper::{Body, Request};
+use rusqlite::types::ValueRef;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::auth::Authenticated;
+use crate::database::settings::ResultLimits;
+use crate::database::Database;
+use crate::error::Error;
+use crate::query::{Query, Value};
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+use crate::replication::FrameNo;
+
+pub const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+/// Default number of rows of the record batches.
+pub const DEFAULT_BATCH_SIZE: usize = 8192;
+
+/// Header of the responses with columns of mixed types, converted to text.
+pub const WARNING_HEADER: &str = "x-sqld-warning";
+
+/// Metadata key of the fields of the columns of mixed types.
+const MIXED_TYPES_METADATA: &str = "sqld:mixed_types";
+
+/// Number of encoded chunks buffered for a slow client, before the connection waits for it.
+const CHANNEL_CAPACITY: usize = 4;
+
+/// Whether the request asks for the results in the Arrow format, with the `format` query
+/// parameter or, if it's absent, with the `Accept` header.
+pub fn is_requested(req: &Request<Body>) -> bool {
+    let param = req.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "format")
+            .map(|(_, value)| value)
+    });
+    if let Some(param) = param {
+        return param == "arrow";
+    }
+
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| {
+            accept.split(',').any(|media_type| {
+                media_type
+                    .split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .eq_ignore_ascii_case(ARROW_STREAM_CONTENT_TYPE)
+            })
+        })
+}
+
+/// What is known of the response when it starts, after the first record batch.
+#[derive(Debug, Default)]
+pub struct Start {
+    /// The columns of mixed types found so far, as `` `name` of statement n ``.
+    pub mixed_columns: Vec<String>,
+    /// The replication index the results are consistent with, if the execution of the batch
+    /// completed before the response started.
+    pub replication_index: Option<FrameNo>,
+}
+
+#[derive(Debug)]
+pub enum ExecuteError {
+    /// A statement of the batch failed, and the batch was rolled back.
+    Statement(Error),
+    /// The batch could not be executed.
+    Batch(Error),
+}
+
+/// Executes `batch`, and returns the body streaming its results, once the first record batch is
+/// encoded. An error that happens after that aborts the body.
+pub async fn execute<D: Database>(
+    db: D,
+    batch: Vec<Query>,
+    expected_replication_index: Option<FrameNo>,
+    result_limits: ResultLimits,
+    transaction: bool,
+    auth: Authenticated,
+    batch_size: usize,
+) -> Result<(Start, Body), ExecuteError> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let (start_sender, start) = oneshot::channel();
+    let builder = ArrowPayloadBuilder::new(batch_size, sender.clone(), start_sender);
+    let execution = tokio::spawn(async move {
+        let mut res = if transaction {
+            db.execute_transaction(
+                batch,
+                expected_replication_index,
+                result_limits,
+                auth,
+                builder,
+            )
+            .await
+        } else {
+            db.execute_batch_or_rollback(
+                batch,
+                expected_replication_index,
+                result_limits,
+                // a failed statement ends the response
+                true,
+                auth,
+                builder,
+            )
+            .await
+        };
+        if let Ok((builder, _)) = &mut res {
+            // the builder is returned with the result of the task, and must not keep `start`
+            // waiting for it
+            builder.start = None;
+        }
+        if res.is_err() {
+            // the client must not mistake the results it received for complete ones
+            let _ = sender
+                .send(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "the execution of the batch failed",
+                )))
+                .await;
+        }
+        res
+    });
+
+    match start.await {
+        Ok(start) => Ok((start, Body::wrap_stream(ReceiverStream::new(receiver)))),
+        // the builder was dropped without starting the response, on an error
+        Err(_) => match execution.await {
+            Ok(Ok((builder, _))) => {
+                Err(ExecuteError::Statement(builder.into_ret().unwrap_or_else(
+                    || Error::Internal("the Arrow response was never started".into()),
+                )))
+            }
+            Ok(Err(e)) => Err(ExecuteError::Batch(e)),
+            Err(e) => Err(ExecuteError::Batch(Error::Internal(e.to_string()))),
+        },
+    }
+}
+
+/// The result set of the current statement.
+#[derive(Default)]
+struct ResultSet {
+    names: Vec<String>,
+    decl_types: Vec<Option<String>>,
+    /// The values of the rows of the next record batch, by column.
+    columns: Vec<Vec<Value>>,
+    rows: usize,
+    /// Approximate size of the values of the next record batch.
+    size: u64,
+    /// The schema inferred from the first record batch, and the stream it's written to.
+    stream: Option<(SchemaRef, StreamWriter<Vec<u8>>)>,
+}
+
+/// Encodes the results of a batch as Arrow IPC streams, and sends them to the response as they
+/// are produced. The chunks are held until the first record batch is encoded, and are dropped if a
+/// statement fails before that, so that the failure can be reported with an error response.
+pub struct ArrowPayloadBuilder {
+    batch_size: usize,
+    /// A record batch is encoded early if its values are larger than this.
+    max_batch_size: u64,
+    step: usize,
+    result_set: ResultSet,
+    /// Index of the next value of the current row.
+    value_index: usize,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    start: Option<oneshot::Sender<Start>>,
+    /// The chunks encoded before the response started.
+    pending: Vec<Bytes>,
+    mixed_columns: Vec<String>,
+    replication_index: Option<FrameNo>,
+    error: Option<Error>,
+}
+
+impl ArrowPayloadBuilder {
+    fn new(
+        batch_size: usize,
+        sender: mpsc::Sender<io::Result<Bytes>>,
+        start: oneshot::Sender<Start>,
+    ) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            max_batch_size: u64::MAX,
+            step: 0,
+            result_set: ResultSet::default(),
+            value_index: 0,
+            sender,
+            start: Some(start),
+            pending: Vec::new(),
+            mixed_columns: Vec::new(),
+            replication_index: None,
+            error: None,
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.start.is_none()
+    }
+
+    fn send(&mut self, chunk: Bytes) -> Result<(), QueryResultBuilderError> {
+        if !self.is_started() {
+            self.pending.push(chunk);
+            return Ok(());
+        }
+
+        // the builder is driven by the thread of a connection, or on the runtime by the write
+        // proxy of a replica, where it must not block the other tasks
+        let sender = &self.sender;
+        tokio::task::block_in_place(|| sender.blocking_send(Ok(chunk)))
+            .map_err(|_| QueryResultBuilderError::Internal(anyhow!("the client went away")))
+    }
+
+    fn start(&mut self) -> Result<(), QueryResultBuilderError> {
+        let Some(start) = self.start.take() else {
+            return Ok(())
+        };
+        let _ = start.send(Start {
+            mixed_columns: std::mem::take(&mut self.mixed_columns),
+            replication_index: self.replication_index,
+        });
+        for chunk in std::mem::take(&mut self.pending) {
+            self.send(chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the schema of the current result set, inferred from the values of its first record
+    /// batch, if it isn't written yet.
+    fn begin_stream(&mut self) -> Result<(), QueryResultBuilderError> {
+        let result_set = &mut self.result_set;
+        if result_set.stream.is_some() {
+            return Ok(());
+        }
+
+        let mut fields = Vec::with_capacity(result_set.names.len());
+        for ((name, decl_type), values) in result_set
+            .names
+            .iter()
+            .zip(&result_set.decl_types)
+            .zip(&result_set.columns)
+        {
+            let field = match infer_type(values, decl_type.as_deref()) {
+                Some(data_type) => Field::new(name, data_type, true),
+                None => {
+                    self.mixed_columns
+                        .push(format!("`{name}` of statement {}", self.step));
+                    Field::new(name, DataType::Utf8, true).with_metadata(HashMap::from([(
+                        MIXED_TYPES_METADATA.to_string(),
+                        "true".to_string(),
+                    )]))
+                }
+            };
+            fields.push(field);
+        }
+        let schema = Arc::new(Schema::new(fields));
+        let writer = StreamWriter::try_new(Vec::new(), &schema)
+            .map_err(QueryResultBuilderError::from_any)?;
+        result_set.stream = Some((schema, writer));
+
+        Ok(())
+    }
+
+    /// Encodes the buffered rows of the current result set in a record batch.
+    fn write_batch(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.begin_stream()?;
+        let result_set = &mut self.result_set;
+        let (schema, writer) = result_set.stream.as_mut().expect("stream not started");
+        let columns = schema
+            .fields()
+            .iter()
+            .zip(&mut result_set.columns)
+            .map(|(field, values)| to_array(field, std::mem::take(values)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let batch = RecordBatch::try_new(schema.clone(), columns)
+            .map_err(QueryResultBuilderError::from_any)?;
+        writer
+            .write(&batch)
+            .and_then(|_| writer.flush())
+            .map_err(QueryResultBuilderError::from_any)?;
+        let chunk = std::mem::take(writer.get_mut());
+        result_set.rows = 0;
+        result_set.size = 0;
+
+        self.send(chunk.into())?;
+        self.start()
+    }
+}
+
+/// The Arrow type of a column, from its values and its declared type. Returns `None` if the column
+/// has values of several types.
+fn infer_type(values: &[Value], decl_type: Option<&str>) -> Option<DataType> {
+    let mut types = values.iter().filter_map(|value| match value {
+        Value::Null => None,
+        Value::Integer(_) => Some(DataType::Int64),
+        Value::Real(_) => Some(DataType::Float64),
+        Value::Text(_) => Some(DataType::Utf8),
+        Value::Blob(_) => Some(DataType::Binary),
+    });
+    match types.next() {
+        Some(first) => types.all(|t| t == first).then_some(first),
+        None => Some(decl_type.map_or(DataType::Null, declared_type)),
+    }
+}
+
+/// The Arrow type of a declared type, following the rules of the type affinity of SQLite. Columns
+/// with a NUMERIC affinity can hold values of any type, and have no type of their own.
+fn declared_type(decl_type: &str) -> DataType {
+    let decl_type = decl_type.to_ascii_uppercase();
+    let contains = |names: &[&str]| names.iter().any(|name| decl_type.contains(name));
+    if contains(&["INT"]) {
+        DataType::Int64
+    } else if contains(&["CHAR", "CLOB", "TEXT"]) {
+        DataType::Utf8
+    } else if contains(&["BLOB"]) {
+        DataType::Binary
+    } else if contains(&["REAL", "FLOA", "DOUB"]) {
+        DataType::Float64
+    } else {
+        DataType::Null
+    }
+}
+
+fn to_array(field: &Field, values: Vec<Value>) -> Result<ArrayRef, QueryResultBuilderError> {
+    let mismatch = |value: &Value| {
+        QueryResultBuilderError::Internal(anyhow!(
+            "column `{}` has a value of type {}, but its type was inferred as {} from its first record batch: cast its values to a single type",
+            field.name(),
+            value_type_name(value),
+            field.data_type(),
+        ))
+    };
+
+    let array: ArrayRef = match field.data_type() {
+        DataType::Int64 => Arc::new(
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Integer(i) => Ok(Some(i)),
+                    value => Err(mismatch(&value)),
+                })
+                .collect::<Result<Int64Array, _>>()?,
+        ),
+        DataType::Float64 => Arc::new(
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Real(x) => Ok(Some(x)),
+                    value => Err(mismatch(&value)),
+                })
+                .collect::<Result<Float64Array, _>>()?,
+        ),
+        // any value can be converted to text
+        DataType::Utf8 => Arc::new(
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Null => None,
+                    Value::Integer(i) => Some(i.to_string()),
+                    Value::Real(x) => Some(x.to_string()),
+                    Value::Text(s) => Some(s),
+                    Value::Blob(b) => Some(BASE64_STANDARD_NO_PAD.encode(b)),
+                })
+                .collect::<StringArray>(),
+        ),
+        DataType::Binary => Arc::new(
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Blob(b) => Ok(Some(b)),
+                    value => Err(mismatch(&value)),
+                })
+                .collect::<Result<BinaryArray, _>>()?,
+        ),
+        _ => {
+            if let Some(value) = values.iter().find(|v| !matches!(v, Value::Null)) {
+                return Err(mismatch(value));
+            }
+            Arc::new(NullArray::new(values.len()))
+        }
+    };
+
+    Ok(array)
+}
+
+fn value_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "NULL",
+        Value::Integer(_) => "INTEGER",
+        Value::Real(_) => "REAL",
+        Value::Text(_) => "TEXT",
+        Value::Blob(_) => "BLOB",
+    }
+}
+
+impl QueryResultBuilder for ArrowPayloadBuilder {
+    /// The error of the statement that failed.
+    type Ret = Option<Error>;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        if self.is_started() {
+            return Err(QueryResultBuilderError::Internal(anyhow!(
+                "the Arrow response already started"
+            )));
+        }
+        self.max_batch_size = config.max_size.unwrap_or(u64::MAX);
+        self.step = 0;
+        self.result_set = ResultSet::default();
+        self.pending.clear();
+        self.mixed_columns.clear();
+        self.replication_index = None;
+        self.error = None;
+
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.result_set = ResultSet::default();
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        _affected_row_count: u64,
+        _last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        if self.error.is_some() {
+            return Ok(());
+        }
+
+        if self.result_set.rows > 0 {
+            self.write_batch()?;
+        }
+        self.begin_stream()?;
+        let (_, writer) = self.result_set.stream.take().expect("stream not started");
+        let chunk = writer
+            .into_inner()
+            .map_err(QueryResultBuilderError::from_any)?;
+        self.send(chunk.into())?;
+        self.step += 1;
+
+        Ok(())
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        if self.is_started() {
+            let message = error.user_error().message;
+            let sender = &self.sender;
+            let _ = tokio::task::block_in_place(|| {
+                sender.blocking_send(Err(io::Error::new(io::ErrorKind::Other, message)))
+            });
+        }
+        self.error = Some(error);
+
+        Ok(())
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        for col in cols {
+            let col = col.into();
+            self.result_set.names.push(col.name.to_string());
+            self.result_set
+                .decl_types
+                .push(col.decl_ty.map(str::to_string));
+        }
+        self.result_set.columns = vec![Vec::new(); self.result_set.names.len()];
+
+        Ok(())
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.value_index = 0;
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        let value = Value::try_from(v)?;
+        self.result_set.size += match value {
+            Value::Text(ref s) => s.len() as u64,
+            Value::Blob(ref b) => b.len() as u64,
+            _ => 8,
+        };
+        self.result_set.columns[self.value_index].push(value);
+        self.value_index += 1;
+
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.result_set.rows += 1;
+        if self.result_set.rows >= self.batch_size || self.result_set.size >= self.max_batch_size {
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn replication_index(&mut self, index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        self.replication_index = Some(index);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        if self.error.is_some() {
+            return Ok(());
+        }
+
+        self.start()
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int64Type};
+    use arrow_ipc::reader::StreamReader;
+    use hyper::body::to_bytes;
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::database::config::DatabaseConfigStore;
+    use crate::database::factory::DbFactory;
+    use crate::database::libsql::{LibSqlDb, LibSqlDbFactory};
+    use crate::database::settings::SessionConfig;
+    use crate::query::Params;
+    use crate::query_analysis::Statement;
+    use crate::stats::Stats;
+
+    use super::*;
+
+    const AUTH: Authenticated = Authenticated::Authorized(Authorized::FullAccess);
+
+    async fn db(path: &std::path::Path) -> LibSqlDb {
+        LibSqlDbFactory::new(
+            path.to_path_buf(),
+            &TRANSPARENT_METHODS,
+            || (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Vec::new(),
+            u64::MAX,
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            None,
+        )
+        .await
+        .unwrap()
+        .create()
+        .await
+        .unwrap()
+    }
+
+    fn batch(stmts: &[&str]) -> Vec<Query> {
+        stmts
+            .iter()
+            .map(|sql| Query {
+                stmt: Statement::parse(sql).next().unwrap().unwrap(),
+                params: Params::empty(),
+                want_rows: true,
+            })
+            .collect()
+    }
+
+    /// Splits the IPC streams of a response, and reads them.
+    fn read_streams(data: &[u8]) -> Vec<(SchemaRef, Vec<RecordBatch>)> {
+        let mut streams = Vec::new();
+        let mut start = 0;
+        let mut offset = 0;
+        while offset < data.len() {
+            // messages are framed as `0xFFFFFFFF <metadata length> <metadata> <body>`
+            let len = i32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            offset += 8;
+            if len == 0 {
+                // end of the stream
+                let reader = StreamReader::try_new(&data[start..offset], None).unwrap();
+                let schema = reader.schema();
+                streams.push((schema, reader.collect::<Result<Vec<_>, _>>().unwrap()));
+                start = offset;
+                continue;
+            }
+            let message = arrow_ipc::root_as_message(&data[offset..offset + len]).unwrap();
+            offset += len + message.bodyLength() as usize;
+        }
+        assert_eq!(start, data.len(), "unterminated stream");
+
+        streams
+    }
+
+    fn data_types(schema: &Schema) -> Vec<DataType> {
+        schema
+            .fields()
+            .iter()
+            .map(|f| f.data_type().clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stmts = batch(&[
+            "CREATE TABLE t (id INTEGER, name TEXT, data BLOB, score REAL)",
+            "INSERT INTO t VALUES (1, 'a', x'0001', 0.5), (2, NULL, NULL, NULL), (3, 'c', x'', 1.5)",
+            "SELECT * FROM t ORDER BY id",
+        ]);
+        let (start, body) = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            AUTH,
+            2,
+        )
+        .await
+        .unwrap();
+        assert!(start.mixed_columns.is_empty());
+        let streams = read_streams(&to_bytes(body).await.unwrap());
+        assert_eq!(streams.len(), 3);
+
+        // the statements without rows have a stream without columns
+        for (schema, batches) in &streams[..2] {
+            assert!(schema.fields().is_empty());
+            assert!(batches.is_empty());
+        }
+
+        let (schema, batches) = &streams[2];
+        assert_eq!(
+            data_types(schema),
+            [
+                DataType::Int64,
+                DataType::Utf8,
+                DataType::Binary,
+                DataType::Float64
+            ]
+        );
+        assert_eq!(
+            batches
+                .iter()
+                .map(RecordBatch::num_rows)
+                .collect::<Vec<_>>(),
+            [2, 1]
+        );
+
+        let first = &batches[0];
+        assert_eq!(first.column(0).as_primitive::<Int64Type>().value(0), 1);
+        assert_eq!(first.column(0).as_primitive::<Int64Type>().value(1), 2);
+        let names = first.column(1).as_string::<i32>();
+        assert_eq!(names.value(0), "a");
+        assert!(names.is_null(1));
+        let data = first.column(2).as_binary::<i32>();
+        assert_eq!(data.value(0), [0, 1]);
+        assert!(data.is_null(1));
+        let scores = first.column(3).as_primitive::<Float64Type>();
+        assert_eq!(scores.value(0), 0.5);
+        assert!(scores.is_null(1));
+
+        let last = &batches[1];
+        assert_eq!(last.column(1).as_string::<i32>().value(0), "c");
+        // an empty blob is not NULL
+        let data = last.column(2).as_binary::<i32>();
+        assert!(!data.is_null(0));
+        assert!(data.value(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_results() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stmts = batch(&[
+            "CREATE TABLE t (id INTEGER, name VARCHAR(10), data BLOB, score DOUBLE, at DATETIME)",
+            "SELECT * FROM t",
+            "SELECT NULL AS nothing",
+        ]);
+        let (_, body) = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            AUTH,
+            10,
+        )
+        .await
+        .unwrap();
+        let streams = read_streams(&to_bytes(body).await.unwrap());
+        assert_eq!(streams.len(), 3);
+
+        // the types of an empty result set are the declared types
+        let (schema, batches) = &streams[1];
+        assert_eq!(
+            data_types(schema),
+            [
+                DataType::Int64,
+                DataType::Utf8,
+                DataType::Binary,
+                DataType::Float64,
+                DataType::Null
+            ]
+        );
+        assert!(batches.is_empty());
+
+        let (schema, batches) = &streams[2];
+        assert_eq!(data_types(schema), [DataType::Null]);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 1);
+    }
+
+    #[tokio::test]
+    async fn mixed_types() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stmts = batch(&["SELECT column1 AS v FROM (VALUES (1), ('a'), (x'ff'), (NULL))"]);
+        let (start, body) = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            AUTH,
+            10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(start.mixed_columns, ["`v` of statement 0"]);
+
+        let streams = read_streams(&to_bytes(body).await.unwrap());
+        let (schema, batches) = &streams[0];
+        let field = schema.field(0);
+        assert_eq!(field.data_type(), &DataType::Utf8);
+        assert_eq!(field.metadata()[MIXED_TYPES_METADATA], "true");
+        let values = batches[0].column(0).as_string::<i32>();
+        assert_eq!(
+            values.iter().collect::<Vec<_>>(),
+            [Some("1"), Some("a"), Some("/w"), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        // a statement that fails before the response starts gets an error response
+        let stmts = batch(&["CREATE TABLE t (x)", "SELECT * FROM missing"]);
+        let res = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            AUTH,
+            10,
+        )
+        .await;
+        assert!(matches!(res, Err(ExecuteError::Statement(_))), "{res:?}");
+
+        // a value that doesn't fit the type inferred from the first record batch aborts the
+        // response
+        let stmts = batch(&["SELECT column1 AS v FROM (VALUES (1), ('a'))"]);
+        let (_, body) = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            AUTH,
+            1,
+        )
+        .await
+        .unwrap();
+        assert!(to_bytes(body).await.is_err());
+    }
+}
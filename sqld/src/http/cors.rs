@@ -0,0 +1,256 @@
+//! CORS handling of the HTTP API, so that browser-based apps can call `sqld` directly.
+//!
+//! Preflight requests are answered by the layer itself, before the request reaches the
+//! authentication, and the CORS headers are attached to every response, errors included, so that
+//! browsers let the app read the error.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::Method;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, like `https://app.example.com`, or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed in requests. The method of the preflight request is allowed if empty.
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed in requests. The headers of the preflight request are allowed if empty.
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache the response to a preflight request.
+    pub max_age: Option<Duration>,
+    /// Allow requests with credentials (cookies, or HTTP authentication). Only allowed with
+    /// explicit origins.
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".into()],
+            allowed_methods: Vec::new(),
+            allowed_headers: Vec::new(),
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Builds the layer handling CORS, or fails if the configuration is invalid.
+    pub fn layer(&self) -> anyhow::Result<CorsLayer> {
+        let mut layer = CorsLayer::new()
+            .allow_origin(self.allow_origin()?)
+            .allow_credentials(self.allow_credentials);
+
+        layer = if self.allowed_methods.is_empty() {
+            layer.allow_methods(AllowMethods::mirror_request())
+        } else {
+            let methods = self
+                .allowed_methods
+                .iter()
+                .map(|m| Method::from_str(m).with_context(|| format!("invalid CORS method `{m}`")))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            layer.allow_methods(methods)
+        };
+
+        layer = if self.allowed_headers.is_empty() {
+            layer.allow_headers(AllowHeaders::mirror_request())
+        } else {
+            let headers = self
+                .allowed_headers
+                .iter()
+                .map(|h| {
+                    HeaderName::from_str(h).with_context(|| format!("invalid CORS header `{h}`"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            layer.allow_headers(headers)
+        };
+
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(max_age);
+        }
+
+        Ok(layer)
+    }
+
+    fn allow_origin(&self) -> anyhow::Result<AllowOrigin> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            if self.allow_credentials {
+                bail!("CORS credentials can only be allowed with explicit origins, not `*`");
+            }
+            return Ok(AllowOrigin::any());
+        }
+
+        let origins = self
+            .allowed_origins
+            .iter()
+            .map(|o| parse_origin(o))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // The console is served by `sqld` itself, so requests from the origin of the node are
+        // always allowed.
+        Ok(AllowOrigin::predicate(move |origin, parts| {
+            origins.contains(origin) || is_same_origin(origin, parts)
+        }))
+    }
+}
+
+fn parse_origin(origin: &str) -> anyhow::Result<HeaderValue> {
+    let valid = match origin.split_once("://") {
+        Some((scheme, host)) => !scheme.is_empty() && !host.is_empty() && !host.contains('/'),
+        None => false,
+    };
+    if !valid {
+        bail!("invalid CORS origin `{origin}`, expected `scheme://host[:port]` or `*`");
+    }
+
+    HeaderValue::from_str(origin).with_context(|| format!("invalid CORS origin `{origin}`"))
+}
+
+/// Whether the request comes from a page served by the node.
+fn is_same_origin(origin: &HeaderValue, parts: &Parts) -> bool {
+    let Some(host) = parts.headers.get(hyper::header::HOST) else {
+        return false;
+    };
+    match origin.to_str().ok().and_then(|o| o.split_once("://")) {
+        Some((_, origin_host)) => origin_host.as_bytes() == host.as_bytes(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Request, Response, StatusCode};
+    use tower::{Layer, ServiceExt};
+
+    use super::*;
+
+    /// Sends `req` to a service that rejects every request, like the API does without credentials.
+    async fn call(config: &CorsConfig, req: Request<Body>) -> Response<Body> {
+        let service =
+            config
+                .layer()
+                .unwrap()
+                .layer(tower::service_fn(|_req: Request<Body>| async {
+                    Ok::<_, std::convert::Infallible>(
+                        Response::builder()
+                            .status(StatusCode::UNAUTHORIZED)
+                            .body(Body::from("{\"error\": \"unauthorized\"}"))
+                            .unwrap(),
+                    )
+                }));
+        service.oneshot(req).await.unwrap()
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/v2/pipeline")
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header(
+                "access-control-request-headers",
+                "authorization, content-type",
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn explicit_config() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["https://app.example.com".into()],
+            allowed_methods: vec!["GET".into(), "POST".into()],
+            allowed_headers: vec!["authorization".into(), "content-type".into()],
+            max_age: Some(Duration::from_secs(600)),
+            allow_credentials: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_preflight_without_auth() {
+        let resp = call(&explicit_config(), preflight("https://app.example.com")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let headers = resp.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert_eq!(headers["access-control-max-age"], "600");
+        assert!(headers["access-control-allow-methods"]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+
+        // other origins get no CORS headers, so browsers block them
+        let resp = call(&explicit_config(), preflight("https://evil.example.com")).await;
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[tokio::test]
+    async fn cors_headers_on_errors() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("origin", "https://app.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let resp = call(&explicit_config(), req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("origin", "https://anywhere.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let resp = call(&CorsConfig::default(), req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+    }
+
+    #[tokio::test]
+    async fn allow_same_origin() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("host", "127.0.0.1:8080")
+            .header("origin", "http://127.0.0.1:8080")
+            .body(Body::empty())
+            .unwrap();
+        let resp = call(&explicit_config(), req).await;
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "http://127.0.0.1:8080"
+        );
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let config = CorsConfig {
+            allow_credentials: true,
+            ..Default::default()
+        };
+        assert!(config.layer().is_err());
+
+        let config = CorsConfig {
+            allowed_origins: vec!["app.example.com".into()],
+            ..Default::default()
+        };
+        assert!(config.layer().is_err());
+
+        let config = CorsConfig {
+            allowed_methods: vec!["NOT A METHOD".into()],
+            ..Default::default()
+        };
+        assert!(config.layer().is_err());
+    }
+}
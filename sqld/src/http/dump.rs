@@ -0,0 +1,408 @@
+//! SQL dumps of the database: `GET /v1/dump` streams a dump of the database, and `POST /v1/dump`
+//! imports the dump streamed in the request body.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use hyper::{Body, Request, Response, StatusCode};
+use rusqlite::OpenFlags;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::StreamReader;
+
+use crate::auth::{Authenticated, Authorized};
+use crate::database::config::DatabaseConfigStore;
+use crate::database::dump::exporter::export_dump;
+use crate::database::dump::loader::{DumpLoader, ImportError, ImportProgress};
+
+use super::error;
+use super::streamed_statement::NDJSON_CONTENT_TYPE;
+
+pub const SQL_CONTENT_TYPE: &str = "application/sql";
+
+/// Size of the chunks of an exported dump.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A line of the progress of an import.
+#[derive(Debug, Serialize)]
+struct ImportProgressLine {
+    committed: u64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<ImportProgress> for ImportProgressLine {
+    fn from(progress: ImportProgress) -> Self {
+        match progress {
+            ImportProgress::Committed(committed) => Self {
+                committed,
+                done: false,
+                error: None,
+            },
+            ImportProgress::Done(committed) => Self {
+                committed,
+                done: true,
+                error: None,
+            },
+            ImportProgress::Failed { committed, error } => Self {
+                committed,
+                done: false,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Exports and imports the dumps of the database.
+pub struct Dumps {
+    db_path: PathBuf,
+    /// The imported dumps go through the replication logger, so only the primary has a loader.
+    loader: Option<Arc<DumpLoader>>,
+    db_config_store: Arc<DatabaseConfigStore>,
+}
+
+impl Dumps {
+    pub fn new(
+        db_path: PathBuf,
+        loader: Option<Arc<DumpLoader>>,
+        db_config_store: Arc<DatabaseConfigStore>,
+    ) -> Self {
+        Self {
+            db_path,
+            loader,
+            db_config_store,
+        }
+    }
+
+    /// Streams a dump of the database, consistent because it is taken in a read transaction.
+    pub fn handle_export(&self, auth: Authenticated) -> Response<Body> {
+        if !matches!(auth, Authenticated::Authorized(_)) {
+            return error("dumps require authorization", StatusCode::FORBIDDEN);
+        }
+
+        let conn = rusqlite::Connection::open_with_flags(
+            self.db_path.join("data"),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        );
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                return error(
+                    &format!("failed to open the database: {e}"),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut writer = io::BufWriter::with_capacity(
+                CHUNK_SIZE,
+                ChannelWriter {
+                    sender: sender.clone(),
+                },
+            );
+            let res = export_dump(conn, &mut writer)
+                .and_then(|()| writer.flush().map_err(anyhow::Error::from));
+            if let Err(e) = res {
+                tracing::warn!("failed to export dump: {e:#}");
+                // the error aborts the response, so that the client can't take it for a whole dump
+                let _ = sender.blocking_send(Err(io::Error::new(io::ErrorKind::Other, e)));
+            }
+        });
+
+        Response::builder()
+            .header("Content-Type", SQL_CONTENT_TYPE)
+            .body(Body::wrap_stream(ReceiverStream::new(receiver)))
+            .unwrap()
+    }
+
+    /// Imports the dump streamed in the body of `req`, and streams the progress of the import.
+    pub async fn handle_import(&self, req: Request<Body>, auth: Authenticated) -> Response<Body> {
+        let Some(loader) = self.loader.clone() else {
+            return error(
+                "dumps are only imported by the primary",
+                StatusCode::BAD_REQUEST,
+            );
+        };
+        if auth != Authenticated::Authorized(Authorized::FullAccess) {
+            return error(
+                "importing a dump requires write access",
+                StatusCode::FORBIDDEN,
+            );
+        }
+        if !is_sql(&req) {
+            return error(
+                &format!("dumps are imported with the `{SQL_CONTENT_TYPE}` content type"),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            );
+        }
+        let config = self.db_config_store.get();
+        if config.block_writes {
+            let reason = config
+                .block_reason
+                .as_deref()
+                .unwrap_or("writes are blocked");
+            return error(reason, StatusCode::FORBIDDEN);
+        }
+        if let Err(e) = crate::STORAGE_HEALTH.check_writable() {
+            return error(&e.to_string(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        let force = is_forced(&req);
+        let reader = StreamReader::new(
+            req.into_body()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        );
+        let progress = match loader.import_dump(reader, force).await {
+            Ok(progress) => progress,
+            Err(e @ (ImportError::NotEmpty | ImportError::Busy)) => {
+                return error(&e.to_string(), StatusCode::CONFLICT)
+            }
+            Err(ImportError::Internal(e)) => {
+                return error(&format!("{e:#}"), StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+
+        let lines = ReceiverStream::new(progress).map(|progress| {
+            let mut line = serde_json::to_vec(&ImportProgressLine::from(progress)).unwrap();
+            line.push(b'\n');
+            Ok::<_, io::Error>(Bytes::from(line))
+        });
+        Response::builder()
+            .header("Content-Type", NDJSON_CONTENT_TYPE)
+            .body(Body::wrap_stream(lines))
+            .unwrap()
+    }
+}
+
+/// Sends the chunks of an exported dump to the response body.
+struct ChannelWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn is_sql(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| {
+            v.split(';').next().unwrap_or_default().trim() == SQL_CONTENT_TYPE
+        })
+}
+
+/// Whether the `force` query parameter allows a dump to be imported into a non-empty database.
+fn is_forced(req: &Request<Body>) -> bool {
+    req.uri().query().map_or(false, |query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "force" && (value == "true" || value == "1"))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::database::libsql::open_db;
+    use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
+    use crate::replication::ReplicationLogger;
+
+    use super::*;
+
+    const SCHEMA: &str = "
+        CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, avatar BLOB);
+        CREATE UNIQUE INDEX users_name ON users (name);
+        CREATE TABLE audit (user_id INTEGER, event TEXT);
+        CREATE INDEX audit_user ON audit (user_id, event);
+        CREATE TRIGGER users_audit AFTER INSERT ON users
+        BEGIN
+            INSERT INTO audit VALUES (new.id, 'created;');
+        END;
+        CREATE VIEW named_users AS SELECT name FROM users WHERE name IS NOT NULL;
+        INSERT INTO users (name, avatar) VALUES ('alice', x'00ff10'), ('bob''s', NULL);
+        INSERT INTO users (name, avatar) VALUES ('line
+        break', zeroblob(1024));
+    ";
+
+    struct Primary {
+        _dir: tempfile::TempDir,
+        path: PathBuf,
+        logger: Arc<ReplicationLogger>,
+        dumps: Dumps,
+    }
+
+    async fn primary(batch_size: u64) -> Primary {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let logger = ReplicationLogger::open(&path, 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let loader = DumpLoader::new(path.clone(), logger.clone(), None)
+            .await
+            .unwrap()
+            .with_import_batch_size(batch_size);
+        let dumps = Dumps::new(
+            path.clone(),
+            Some(Arc::new(loader)),
+            Arc::new(DatabaseConfigStore::new_test()),
+        );
+        Primary {
+            _dir: dir,
+            path,
+            logger,
+            dumps,
+        }
+    }
+
+    fn write(primary: &Primary, sql: &str) {
+        let mut ctx = ReplicationLoggerHookCtx::new(primary.logger.clone(), None);
+        let conn = open_db(&primary.path, &REPLICATION_METHODS, &mut ctx, None).unwrap();
+        conn.execute_batch(sql).unwrap();
+    }
+
+    fn query<T: rusqlite::types::FromSql>(path: &Path, sql: &str) -> Vec<T> {
+        let conn = rusqlite::Connection::open(path.join("data")).unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map((), |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    async fn export(primary: &Primary) -> String {
+        let resp = primary
+            .dumps
+            .handle_export(Authenticated::Authorized(Authorized::ReadOnly));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    async fn import(primary: &Primary, dump: &str, params: &str) -> (StatusCode, Vec<String>) {
+        let req = Request::post(format!("/v1/dump{params}"))
+            .header("Content-Type", SQL_CONTENT_TYPE)
+            .body(Body::from(dump.to_string()))
+            .unwrap();
+        let resp = primary
+            .dumps
+            .handle_import(req, Authenticated::Authorized(Authorized::FullAccess))
+            .await;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let lines = String::from_utf8(body.to_vec()).unwrap();
+        (status, lines.lines().map(str::to_string).collect())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dump_round_trip() {
+        let source = primary(2).await;
+        write(&source, SCHEMA);
+        let dump = export(&source).await;
+        assert!(dump.contains("CREATE TRIGGER users_audit"), "{dump}");
+        assert!(dump.contains("X'00ff10'"), "{dump}");
+
+        let target = primary(2).await;
+        let frame_no = *target.logger.new_frame_notifier.borrow();
+        let (status, progress) = import(&target, &dump, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let last = progress.last().unwrap();
+        assert!(last.contains(r#""done":true"#), "{progress:?}");
+        assert!(progress.len() > 1, "{progress:?}");
+
+        // the import went through the replication log
+        assert!(*target.logger.new_frame_notifier.borrow() > frame_no);
+        // and the database is the same
+        assert_eq!(export(&target).await, dump);
+        assert_eq!(
+            query::<Vec<u8>>(
+                &target.path,
+                "SELECT avatar FROM users WHERE name = 'alice'"
+            ),
+            [vec![0x00, 0xff, 0x10]]
+        );
+        assert_eq!(
+            query::<String>(&target.path, "SELECT name FROM named_users ORDER BY name"),
+            ["alice", "bob's", "line\n        break"]
+        );
+        // with its trigger
+        write(&target, "INSERT INTO users (name) VALUES ('carol')");
+        assert_eq!(
+            query::<i64>(&target.path, "SELECT count(*) FROM audit"),
+            [4]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn import_requires_empty_database() {
+        let primary = primary(2).await;
+        write(&primary, "CREATE TABLE t (x)");
+
+        let dump = "CREATE TABLE u (x);\nINSERT INTO u VALUES(1);\n";
+        let (status, _) = import(&primary, dump, "").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(query::<String>(
+            &primary.path,
+            "SELECT name FROM sqlite_schema WHERE name = 'u'"
+        )
+        .is_empty());
+
+        let (status, progress) = import(&primary, dump, "?force=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(progress.last().unwrap(), r#"{"committed":2,"done":true}"#);
+        assert_eq!(query::<i64>(&primary.path, "SELECT x FROM u"), [1]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_import_keeps_committed_transactions() {
+        let primary = primary(2).await;
+
+        let dump = "BEGIN TRANSACTION;\n\
+            CREATE TABLE t (x UNIQUE);\n\
+            INSERT INTO t VALUES(1);\n\
+            INSERT INTO t VALUES(2);\n\
+            INSERT INTO t VALUES(2);\n\
+            COMMIT;\n";
+        let (status, progress) = import(&primary, dump, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(progress[0], r#"{"committed":2}"#);
+        assert!(
+            progress[1].starts_with(r#"{"committed":2,"error":"statement 4 failed"#),
+            "{progress:?}"
+        );
+        // the failed transaction is rolled back
+        assert_eq!(query::<i64>(&primary.path, "SELECT x FROM t"), [1]);
+    }
+
+    #[tokio::test]
+    async fn dumps_are_imported_by_the_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let dumps = Dumps::new(
+            dir.path().to_path_buf(),
+            None,
+            Arc::new(DatabaseConfigStore::new_test()),
+        );
+        let req = Request::post("/v1/dump")
+            .header("Content-Type", SQL_CONTENT_TYPE)
+            .body(Body::from("CREATE TABLE t (x);"))
+            .unwrap();
+        let resp = dumps
+            .handle_import(req, Authenticated::Authorized(Authorized::FullAccess))
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
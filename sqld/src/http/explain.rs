@@ -0,0 +1,432 @@
+//! `POST /v1/explain`: the query plan of a statement, as the rows returned by SQLite and as a tree.
+//!
+//! The body is a statement, like those of the queries route. It is explained with
+//! `EXPLAIN QUERY PLAN`, unless it already is an `EXPLAIN` or `EXPLAIN QUERY PLAN` statement.
+//! SQLite only compiles the explained statement, so explaining a write never changes the database,
+//! and replicas explain the statements themselves, without going through the primary.
+
+use std::sync::Arc;
+
+use hyper::body::to_bytes;
+use hyper::{Body, Request, Response, StatusCode};
+use rusqlite::types::ValueRef;
+use sqld_api_types::http::{self as api, ExplainResponse, PlanNode};
+
+use crate::auth::Authenticated;
+use crate::database::factory::DbFactory;
+use crate::database::Database;
+use crate::error::Error;
+use crate::query::{Query, Value};
+use crate::query_analysis::{Explain, Statement};
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+
+use super::types::QueryObject;
+use super::{error, user_error};
+
+pub async fn handle<D: Database>(
+    mut req: Request<Body>,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> anyhow::Result<Response<Body>> {
+    let bytes = to_bytes(req.body_mut()).await?;
+    let query: QueryObject = match serde_json::from_slice(&bytes) {
+        Ok(query) => query,
+        Err(e) => return Ok(error(&e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    let (stmt, explain) = match parse(&query.q) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(error(&e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+
+    let db = match db_factory.create().await {
+        Ok(db) => db,
+        Err(e) => return Ok(user_error(&e, StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+    let query = Query {
+        stmt,
+        params: query.params.0,
+        want_rows: true,
+    };
+    let (columns, rows) = match db
+        .execute_batch(vec![query], auth, ExplainResultBuilder::default())
+        .await
+    {
+        Ok((builder, _)) => match builder.into_ret() {
+            Ok(result) => result,
+            Err(e) => return Ok(user_error(&e, StatusCode::BAD_REQUEST)),
+        },
+        Err(e) => return Ok(user_error(&e, StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    let plan = match explain {
+        Explain::QueryPlan => Some(plan_tree(&columns, &rows)),
+        Explain::Bytecode => None,
+    };
+    let resp = ExplainResponse {
+        columns,
+        rows,
+        plan,
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&resp)?))?)
+}
+
+/// Parses the single statement of `sql`, as an `EXPLAIN` statement.
+fn parse(sql: &str) -> anyhow::Result<(Statement, Explain)> {
+    let mut stmts = Statement::parse(sql);
+    let stmt = stmts
+        .next()
+        .transpose()?
+        .ok_or_else(|| anyhow::anyhow!("no statement to explain"))?;
+    if stmts.next().is_some() {
+        anyhow::bail!("only one statement can be explained at a time");
+    }
+    if stmt.setting.is_some() {
+        anyhow::bail!("session settings can't be explained");
+    }
+
+    let stmt = match stmt.explain {
+        Some(_) => stmt,
+        None => Statement::parse(&format!("EXPLAIN QUERY PLAN {}", stmt.stmt))
+            .next()
+            .transpose()?
+            .ok_or_else(|| anyhow::anyhow!("no statement to explain"))?,
+    };
+    // the statement is only compiled if it really is an `EXPLAIN`
+    let Some(explain) = stmt.explain else {
+        anyhow::bail!("the statement could not be explained");
+    };
+
+    Ok((stmt, explain))
+}
+
+/// Builds the tree of the steps of a query plan from the `id` and `parent` columns of the rows
+/// of `EXPLAIN QUERY PLAN`, keeping the order of the rows among siblings. The steps whose parent
+/// is not in the plan are at the top level.
+fn plan_tree(columns: &[String], rows: &[Vec<api::Value>]) -> Vec<PlanNode> {
+    let column = |name: &str| columns.iter().position(|c| c == name);
+    let (Some(id), Some(parent), Some(detail)) =
+        (column("id"), column("parent"), column("detail")) else {
+        return Vec::new()
+    };
+    let steps = rows
+        .iter()
+        .filter_map(|row| match (&row[id], &row[parent], &row[detail]) {
+            (api::Value::Integer(id), api::Value::Integer(parent), api::Value::Text(detail)) => {
+                Some((*id, *parent, detail.as_str()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    fn children(steps: &[(i64, i64, &str)], parent: i64) -> Vec<PlanNode> {
+        steps
+            .iter()
+            .filter(|(id, p, _)| *p == parent && *id != parent)
+            .map(|(id, _, detail)| PlanNode {
+                id: *id,
+                detail: detail.to_string(),
+                children: children(steps, *id),
+            })
+            .collect()
+    }
+
+    let mut roots = Vec::new();
+    for (id, parent, detail) in &steps {
+        if !steps.iter().any(|(other, ..)| other == parent) {
+            roots.push(PlanNode {
+                id: *id,
+                detail: detail.to_string(),
+                children: children(&steps, *id),
+            });
+        }
+    }
+
+    roots
+}
+
+/// Collects the columns and the rows of the statement, or its error.
+#[derive(Debug, Default)]
+struct ExplainResultBuilder {
+    columns: Vec<String>,
+    rows: Vec<Vec<api::Value>>,
+    err: Option<Error>,
+    size: u64,
+    max_size: u64,
+}
+
+impl QueryResultBuilder for ExplainResultBuilder {
+    type Ret = Result<(Vec<String>, Vec<Vec<api::Value>>), Error>;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        *self = Self {
+            max_size: config.max_size.unwrap_or(u64::MAX),
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        _affected_row_count: u64,
+        _last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        self.err = Some(error);
+        Ok(())
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.columns = cols
+            .into_iter()
+            .map(|col| {
+                let col: Column = col.into();
+                col.name.to_string()
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.rows.push(Vec::new());
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.size += match v {
+            ValueRef::Text(b) | ValueRef::Blob(b) => b.len() as u64,
+            _ => 8,
+        };
+        if self.size > self.max_size {
+            return Err(QueryResultBuilderError::ResponseTooLarge(self.max_size));
+        }
+        let v = Value::try_from(v).map_err(QueryResultBuilderError::from_any)?;
+        if let Some(row) = self.rows.last_mut() {
+            row.push(v.into());
+        }
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        match self.err {
+            Some(e) => Err(e),
+            None => Ok((self.columns, self.rows)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::database::config::DatabaseConfigStore;
+    use crate::database::libsql::{LibSqlDb, LibSqlDbFactory};
+    use crate::database::settings::SessionConfig;
+    use crate::query::Params;
+    use crate::stats::Stats;
+
+    use super::*;
+
+    const AUTH: Authenticated = Authenticated::Authorized(Authorized::FullAccess);
+
+    async fn factory(path: &std::path::Path) -> Arc<dyn DbFactory<Db = LibSqlDb>> {
+        Arc::new(
+            LibSqlDbFactory::new(
+                path.to_path_buf(),
+                &TRANSPARENT_METHODS,
+                || (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                Vec::new(),
+                u64::MAX,
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                None,
+            )
+            .await
+            .unwrap(),
+        )
+    }
+
+    async fn execute(
+        factory: &Arc<dyn DbFactory<Db = LibSqlDb>>,
+        sql: &str,
+    ) -> Vec<Vec<api::Value>> {
+        let query = Query {
+            stmt: Statement::parse(sql).next().unwrap().unwrap(),
+            params: Params::empty(),
+            want_rows: true,
+        };
+        let db = factory.create().await.unwrap();
+        let (builder, _) = db
+            .execute_batch(vec![query], AUTH, ExplainResultBuilder::default())
+            .await
+            .unwrap();
+        let (_, rows) = builder.into_ret().unwrap();
+        rows
+    }
+
+    async fn explain(
+        factory: &Arc<dyn DbFactory<Db = LibSqlDb>>,
+        sql: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let body = serde_json::to_vec(&serde_json::json!(sql)).unwrap();
+        let req = Request::post("/v1/explain").body(body.into()).unwrap();
+        let resp = handle(req, AUTH, factory.clone()).await.unwrap();
+        let status = resp.status();
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn depth(nodes: &[PlanNode]) -> usize {
+        nodes
+            .iter()
+            .map(|node| 1 + depth(&node.children))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn count(nodes: &[PlanNode]) -> usize {
+        nodes.iter().map(|node| 1 + count(&node.children)).sum()
+    }
+
+    #[tokio::test]
+    async fn plan_with_subqueries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = factory(tmp.path()).await;
+        execute(&factory, "CREATE TABLE t (a, b)").await;
+        execute(&factory, "CREATE TABLE u (c, d)").await;
+
+        let sql = "SELECT * FROM t WHERE a IN (SELECT c FROM u WHERE d > (SELECT max(b) FROM t)) \
+            UNION ALL SELECT c, d FROM u";
+        let (status, body) = explain(&factory, sql).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let resp: ExplainResponse = serde_json::from_value(body).unwrap();
+        assert_eq!(resp.columns, ["id", "parent", "notused", "detail"]);
+        let plan = resp.plan.unwrap();
+
+        // every row is a step of the tree, and the subqueries nest the steps on several levels
+        assert_eq!(count(&plan), resp.rows.len());
+        assert!(depth(&plan) >= 3, "{plan:#?}");
+        assert_eq!(plan.len(), 1, "{plan:#?}");
+        assert!(plan[0].detail.contains("COMPOUND"), "{plan:#?}");
+
+        // an explicit `EXPLAIN QUERY PLAN` gives the same plan
+        let (status, body) = explain(&factory, &format!("EXPLAIN QUERY PLAN {sql}")).await;
+        assert_eq!(status, StatusCode::OK);
+        let explicit: ExplainResponse = serde_json::from_value(body).unwrap();
+        assert_eq!(explicit.plan.unwrap(), plan);
+    }
+
+    #[tokio::test]
+    async fn explain_writes_without_executing_them() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = factory(tmp.path()).await;
+        execute(&factory, "CREATE TABLE t (a)").await;
+        execute(&factory, "INSERT INTO t VALUES (1), (2)").await;
+
+        // the bytecode has no tree
+        let (status, body) = explain(&factory, "EXPLAIN DELETE FROM t").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let resp: ExplainResponse = serde_json::from_value(body).unwrap();
+        assert!(resp.columns.iter().any(|c| c == "opcode"));
+        assert!(!resp.rows.is_empty());
+        assert_eq!(resp.plan, None);
+
+        let (status, _) = explain(&factory, "DELETE FROM t WHERE a = 1").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = explain(&factory, "EXPLAIN QUERY PLAN DROP TABLE t").await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert_eq!(
+            execute(&factory, "SELECT count(*) FROM t").await,
+            [[api::Value::Integer(2)]]
+        );
+
+        // a batch can't be explained
+        let (status, _) = explain(&factory, "SELECT 1; DELETE FROM t").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = explain(&factory, "SELECT * FROM missing").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn nested_plan_tree() {
+        let columns = ["id", "parent", "notused", "detail"].map(String::from);
+        let row = |id, parent, detail: &str| {
+            vec![
+                api::Value::Integer(id),
+                api::Value::Integer(parent),
+                api::Value::Integer(0),
+                api::Value::Text(detail.into()),
+            ]
+        };
+        let rows = [
+            row(2, 0, "SCAN t"),
+            row(5, 0, "LIST SUBQUERY 1"),
+            row(7, 5, "SCAN u"),
+            row(9, 5, "SCALAR SUBQUERY 2"),
+            row(12, 9, "SEARCH v USING INDEX i (x=?)"),
+            row(20, 0, "USE TEMP B-TREE FOR ORDER BY"),
+        ];
+        let node = |id, detail: &str, children| PlanNode {
+            id,
+            detail: detail.into(),
+            children,
+        };
+
+        assert_eq!(
+            plan_tree(&columns, &rows),
+            [
+                node(2, "SCAN t", vec![]),
+                node(
+                    5,
+                    "LIST SUBQUERY 1",
+                    vec![
+                        node(7, "SCAN u", vec![]),
+                        node(
+                            9,
+                            "SCALAR SUBQUERY 2",
+                            vec![node(12, "SEARCH v USING INDEX i (x=?)", vec![])]
+                        ),
+                    ]
+                ),
+                node(20, "USE TEMP B-TREE FOR ORDER BY", vec![]),
+            ]
+        );
+    }
+}
@@ -13,6 +13,8 @@ use crate::hrana::{self};
 enum ResponseError {
     #[error(transparent)]
     Stmt(hrana::stmt::StmtError),
+    #[error(transparent)]
+    Batch(hrana::batch::BatchError),
 }
 
 pub async fn handle_index(
@@ -81,6 +83,7 @@ pub async fn handle_batch<D: Database>(
         hrana::batch::execute_batch(&db, auth, pgm)
             .await
             .map(|result| RespBody { result })
+            .map_err(catch_batch_error)
             .context("Could not execute batch")
     })
     .await
@@ -130,21 +133,51 @@ where
 }
 
 fn response_error_response(err: ResponseError) -> hyper::Response<hyper::Body> {
+    use hrana::batch::BatchError;
     use hrana::stmt::StmtError;
     let status = match &err {
         ResponseError::Stmt(err) => match err {
             StmtError::SqlParse { .. }
+            | StmtError::SqlLimit { .. }
             | StmtError::SqlNoStmt
             | StmtError::SqlManyStmts
             | StmtError::ArgsInvalid { .. }
             | StmtError::SqlInputError { .. }
             | StmtError::ResponseTooLarge
-            | StmtError::Blocked { .. } => hyper::StatusCode::BAD_REQUEST,
+            | StmtError::ResultLimitExceeded { .. }
+            | StmtError::Blocked { .. }
+            | StmtError::InvalidSetting { .. }
+            | StmtError::InlineLiteral { .. }
+            | StmtError::BatchRolledBack { .. }
+            | StmtError::WriteInReadTransaction
+            | StmtError::ConstraintViolation { .. }
+            | StmtError::DeferredConstraintViolation { .. } => hyper::StatusCode::BAD_REQUEST,
             StmtError::ArgsBothPositionalAndNamed => hyper::StatusCode::NOT_IMPLEMENTED,
-            StmtError::TransactionTimeout | StmtError::TransactionBusy => {
+            StmtError::StatementDenied { .. }
+            | StmtError::StatementClassNotAllowed { .. }
+            | StmtError::ReadOnlyReplica
+            | StmtError::AttachNotAllowed { .. }
+            | StmtError::AttachedDatabaseReadOnly => hyper::StatusCode::FORBIDDEN,
+            StmtError::TransactionTimeout { .. }
+            | StmtError::TransactionBusy
+            | StmtError::StatementTimeout { .. }
+            | StmtError::InterruptedByAdmin
+            | StmtError::StorageDegraded { .. }
+            | StmtError::PrimaryUnreachable { .. }
+            | StmtError::ReplicaNotReady
+            | StmtError::PrimaryWriteQueueFull => hyper::StatusCode::SERVICE_UNAVAILABLE,
+            StmtError::RateLimited { .. } => hyper::StatusCode::TOO_MANY_REQUESTS,
+            StmtError::SqliteError { .. } => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        ResponseError::Batch(err) => match err {
+            BatchError::ResponseTooLarge | BatchError::ReplicationIndexUnsupported { .. } => {
+                hyper::StatusCode::BAD_REQUEST
+            }
+            BatchError::ReplicationIndexConflict { .. } => hyper::StatusCode::CONFLICT,
+            BatchError::StatementClassNotAllowed { .. } => hyper::StatusCode::FORBIDDEN,
+            BatchError::TransactionTimeout { .. } | BatchError::TransactionBusy => {
                 hyper::StatusCode::SERVICE_UNAVAILABLE
             }
-            StmtError::SqliteError { .. } => hyper::StatusCode::INTERNAL_SERVER_ERROR,
         },
     };
 
@@ -153,6 +186,7 @@ fn response_error_response(err: ResponseError) -> hyper::Response<hyper::Body> {
         &hrana::proto::Error {
             message: err.to_string(),
             code: err.code().into(),
+            constraint_violations: err.constraint_violations(),
         },
     )
 }
@@ -184,10 +218,18 @@ fn catch_stmt_error(err: anyhow::Error) -> anyhow::Error {
     }
 }
 
+fn catch_batch_error(err: anyhow::Error) -> anyhow::Error {
+    match err.downcast::<hrana::batch::BatchError>() {
+        Ok(batch_err) => anyhow!(ResponseError::Batch(batch_err)),
+        Err(err) => err,
+    }
+}
+
 impl ResponseError {
     pub fn code(&self) -> &'static str {
         match self {
             Self::Stmt(err) => err.code(),
+            Self::Batch(err) => err.code(),
         }
     }
 }
@@ -0,0 +1,922 @@
+//! Key-value API, served under `/kv` when `--enable-kv-api` is set.
+//!
+//! The entries live in the `_sqld_kv` table, created by the first write, and every operation is
+//! an SQL batch executed by the database of the request. They are authorized, proxied to the
+//! primary, replicated and accounted for like the statements of any other client.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::prelude::{BASE64_STANDARD_NO_PAD, BASE64_URL_SAFE_NO_PAD};
+use base64::Engine;
+use hyper::body::to_bytes;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rusqlite::types::ValueRef;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::Authenticated;
+use crate::database::factory::DbFactory;
+use crate::database::settings::ResultLimits;
+use crate::database::Database;
+use crate::error::Error;
+use crate::query::{Params, Query, Value};
+use crate::query_analysis::Statement;
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+
+use super::{error, user_error};
+
+/// Maximum length of a key, in bytes.
+pub const MAX_KEY_LEN: usize = 1024;
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS _sqld_kv (key TEXT PRIMARY KEY, value BLOB NOT NULL, hash TEXT NOT NULL) WITHOUT ROWID";
+
+#[derive(Debug, thiserror::Error)]
+enum KvError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("the precondition of the write of key `{0}` doesn't hold")]
+    PreconditionFailed(String),
+    #[error(transparent)]
+    Database(#[from] Error),
+}
+
+impl KvError {
+    fn into_response(self) -> Response<Body> {
+        let code = match &self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Self::Database(Error::NotAuthorized(_) | Error::ReadOnlyReplica) => {
+                StatusCode::FORBIDDEN
+            }
+            Self::Database(
+                Error::StorageDegraded(_)
+                | Error::PrimaryUnreachable(_)
+                | Error::ReplicaNotReady
+                | Error::PrimaryWriteQueueFull,
+            ) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Database(Error::RateLimited(_)) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        match self {
+            Self::Database(e) => user_error(&e, code),
+            e => error(&e.to_string(), code),
+        }
+    }
+}
+
+/// Condition on the current value of an entry for a write to apply.
+#[derive(Debug, Clone, PartialEq)]
+enum Precondition {
+    /// `If-Match: "<etag>"`: the entry has the value of this ETag.
+    Matches(String),
+    /// `If-Match: *`: the entry exists.
+    Exists,
+    /// `If-None-Match: *`: the entry doesn't exist.
+    Absent,
+}
+
+impl Precondition {
+    fn parse(if_match: Option<&str>, if_none_match: Option<&str>) -> Result<Option<Self>, KvError> {
+        match (if_match.map(str::trim), if_none_match.map(str::trim)) {
+            (None, None) => Ok(None),
+            (Some("*"), None) => Ok(Some(Self::Exists)),
+            (Some(etag), None) => Ok(Some(Self::Matches(
+                etag.trim_start_matches("W/").trim_matches('"').to_string(),
+            ))),
+            (None, Some("*")) => Ok(Some(Self::Absent)),
+            (None, Some(_)) => Err(KvError::BadRequest(
+                "`If-None-Match` only accepts `*`".into(),
+            )),
+            (Some(_), Some(_)) => Err(KvError::BadRequest(
+                "`If-Match` and `If-None-Match` can't be combined".into(),
+            )),
+        }
+    }
+}
+
+/// A put, or a delete if there is no value.
+#[derive(Debug)]
+struct Write {
+    key: String,
+    value: Option<Vec<u8>>,
+    precondition: Option<Precondition>,
+}
+
+impl Write {
+    fn query(&self) -> Query {
+        let key = ("key", Value::Text(self.key.clone()));
+        let Some(value) = &self.value else {
+            return match &self.precondition {
+                Some(Precondition::Matches(etag)) => query(
+                    "DELETE FROM _sqld_kv WHERE key = :key AND hash = :expected",
+                    [key, ("expected", Value::Text(etag.clone()))],
+                ),
+                _ => query("DELETE FROM _sqld_kv WHERE key = :key", [key]),
+            };
+        };
+
+        let value = [
+            key,
+            ("value", Value::Blob(value.clone())),
+            ("hash", Value::Text(etag(value))),
+        ];
+        match &self.precondition {
+            None => query(
+                "INSERT OR REPLACE INTO _sqld_kv (key, value, hash) VALUES (:key, :value, :hash)",
+                value,
+            ),
+            Some(Precondition::Absent) => query(
+                "INSERT OR IGNORE INTO _sqld_kv (key, value, hash) VALUES (:key, :value, :hash)",
+                value,
+            ),
+            Some(Precondition::Exists) => query(
+                "UPDATE _sqld_kv SET value = :value, hash = :hash WHERE key = :key",
+                value,
+            ),
+            Some(Precondition::Matches(etag)) => query(
+                "UPDATE _sqld_kv SET value = :value, hash = :hash WHERE key = :key AND hash = :expected",
+                value
+                    .into_iter()
+                    .chain([("expected", Value::Text(etag.clone()))]),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Blob {
+    base64: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum BatchOp {
+    Put {
+        key: String,
+        value: Blob,
+        #[serde(default)]
+        if_match: Option<String>,
+        #[serde(default)]
+        if_none_match: Option<String>,
+    },
+    Delete {
+        key: String,
+        #[serde(default)]
+        if_match: Option<String>,
+    },
+}
+
+impl TryFrom<BatchOp> for Write {
+    type Error = KvError;
+
+    fn try_from(op: BatchOp) -> Result<Self, KvError> {
+        let (key, value, precondition) = match op {
+            BatchOp::Put {
+                key,
+                value,
+                if_match,
+                if_none_match,
+            } => {
+                let value = BASE64_STANDARD_NO_PAD
+                    .decode(value.base64.trim_end_matches('='))
+                    .map_err(|e| KvError::BadRequest(format!("invalid value of `{key}`: {e}")))?;
+                let precondition =
+                    Precondition::parse(if_match.as_deref(), if_none_match.as_deref())?;
+                (key, Some(value), precondition)
+            }
+            BatchOp::Delete { key, if_match } => {
+                (key, None, Precondition::parse(if_match.as_deref(), None)?)
+            }
+        };
+        check_key(&key)?;
+
+        Ok(Self {
+            key,
+            value,
+            precondition,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    /// The ETags of the values written by the operations, `null` for the deletes.
+    etags: Vec<Option<String>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanEntry {
+    key: String,
+    value: Blob,
+    etag: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanResponse {
+    entries: Vec<ScanEntry>,
+    /// Where the next page starts, if there may be more entries.
+    cursor: Option<String>,
+}
+
+/// Serves the requests under `/kv`.
+pub async fn handle<D: Database>(
+    req: Request<Body>,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> anyhow::Result<Response<Body>> {
+    let path = req.uri().path();
+    let raw_key = path
+        .strip_prefix("/kv")
+        .map(|rest| rest.strip_prefix('/').unwrap_or(rest))
+        .unwrap_or_default();
+
+    let res = match (req.method(), raw_key) {
+        (&Method::GET, "") => scan(req, auth, db_factory).await,
+        (&Method::POST, "batch") => batch(req, auth, db_factory).await,
+        (&Method::GET | &Method::PUT | &Method::DELETE, raw_key) if !raw_key.is_empty() => {
+            match decode_key(raw_key) {
+                Ok(key) if req.method() == Method::GET => get(key, auth, db_factory).await,
+                Ok(key) if req.method() == Method::PUT => put(key, req, auth, db_factory).await,
+                Ok(key) => delete(key, req, auth, db_factory).await,
+                Err(e) => Err(e),
+            }
+        }
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())?)
+        }
+    };
+
+    Ok(res.unwrap_or_else(KvError::into_response))
+}
+
+async fn get<D: Database>(
+    key: String,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> Result<Response<Body>, KvError> {
+    let db = db_factory.create().await?;
+    let select = query(
+        "SELECT value, hash FROM _sqld_kv WHERE key = :key",
+        [("key", Value::Text(key))],
+    );
+    let rows = match execute(&db, auth, vec![select]).await {
+        Ok(mut outputs) => outputs.remove(0).rows,
+        Err(e) if is_missing_table(&e) => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    match rows.into_iter().next().as_deref() {
+        Some([Value::Blob(value), Value::Text(etag)]) => Ok(Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .header("ETag", format!("\"{etag}\""))
+            .body(Body::from(value.clone()))
+            .unwrap()),
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap()),
+    }
+}
+
+async fn put<D: Database>(
+    key: String,
+    mut req: Request<Body>,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> Result<Response<Body>, KvError> {
+    let precondition = Precondition::parse(
+        header(&req, hyper::header::IF_MATCH),
+        header(&req, hyper::header::IF_NONE_MATCH),
+    )?;
+    // the size of the body is limited to `max_request_size` by the `RequestDecompressionLayer`
+    let value = to_bytes(req.body_mut())
+        .await
+        .map_err(|e| KvError::BadRequest(format!("failed to read the value: {e}")))?
+        .to_vec();
+    let etag = etag(&value);
+    let db = db_factory.create().await?;
+    write(
+        &db,
+        auth,
+        &[Write {
+            key,
+            value: Some(value),
+            precondition,
+        }],
+    )
+    .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("ETag", format!("\"{etag}\""))
+        .body(Body::empty())
+        .unwrap())
+}
+
+async fn delete<D: Database>(
+    key: String,
+    req: Request<Body>,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> Result<Response<Body>, KvError> {
+    let precondition = Precondition::parse(header(&req, hyper::header::IF_MATCH), None)?;
+    if header(&req, hyper::header::IF_NONE_MATCH).is_some() {
+        return Err(KvError::BadRequest(
+            "`If-None-Match` doesn't apply to deletes".into(),
+        ));
+    }
+    let db = db_factory.create().await?;
+    let deleted = write(
+        &db,
+        auth,
+        &[Write {
+            key,
+            value: None,
+            precondition,
+        }],
+    )
+    .await?;
+
+    let status = if deleted[0] > 0 {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap())
+}
+
+async fn batch<D: Database>(
+    mut req: Request<Body>,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> Result<Response<Body>, KvError> {
+    let body = to_bytes(req.body_mut())
+        .await
+        .map_err(|e| KvError::BadRequest(format!("failed to read the batch: {e}")))?;
+    let batch: BatchRequest =
+        serde_json::from_slice(&body).map_err(|e| KvError::BadRequest(e.to_string()))?;
+    let writes = batch
+        .ops
+        .into_iter()
+        .map(Write::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    if writes.is_empty() {
+        return Err(KvError::BadRequest("the batch is empty".into()));
+    }
+
+    let db = db_factory.create().await?;
+    write(&db, auth, &writes).await?;
+
+    let resp = BatchResponse {
+        etags: writes
+            .iter()
+            .map(|write| write.value.as_deref().map(etag))
+            .collect(),
+    };
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&resp).unwrap()))
+        .unwrap())
+}
+
+async fn scan<D: Database>(
+    req: Request<Body>,
+    auth: Authenticated,
+    db_factory: Arc<dyn DbFactory<Db = D>>,
+) -> Result<Response<Body>, KvError> {
+    let mut prefix = String::new();
+    let mut limit = DEFAULT_SCAN_LIMIT;
+    let mut cursor = None;
+    for (name, value) in
+        url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+    {
+        match name.as_ref() {
+            "prefix" => prefix = value.into_owned(),
+            "limit" => {
+                limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit| (1..=MAX_SCAN_LIMIT).contains(limit))
+                    .ok_or_else(|| {
+                        KvError::BadRequest(format!(
+                            "`limit` must be between 1 and {MAX_SCAN_LIMIT}"
+                        ))
+                    })?
+            }
+            "cursor" => {
+                let key = BASE64_URL_SAFE_NO_PAD
+                    .decode(value.as_bytes())
+                    .ok()
+                    .and_then(|key| String::from_utf8(key).ok())
+                    .ok_or_else(|| KvError::BadRequest("invalid `cursor`".into()))?;
+                cursor = Some(key);
+            }
+            _ => (),
+        }
+    }
+
+    // keyset pagination: a page starts right after the last key of the previous one, so that
+    // concurrent writes never make a scan skip or repeat the keys that exist for its whole duration.
+    let mut conds = vec!["key >= :prefix"];
+    let mut params = vec![("limit", Value::Integer(limit as i64 + 1))];
+    if let Some(end) = prefix_end(&prefix) {
+        conds.push("key < :end");
+        params.push(("end", Value::Text(end)));
+    }
+    params.push(("prefix", Value::Text(prefix)));
+    if let Some(cursor) = cursor {
+        conds.push("key > :cursor");
+        params.push(("cursor", Value::Text(cursor)));
+    }
+    let sql = format!(
+        "SELECT key, value, hash FROM _sqld_kv WHERE {} ORDER BY key LIMIT :limit",
+        conds.join(" AND ")
+    );
+    let select = query(&sql, params);
+
+    let db = db_factory.create().await?;
+    let mut rows = match execute(&db, auth, vec![select]).await {
+        Ok(mut outputs) => outputs.remove(0).rows,
+        Err(e) if is_missing_table(&e) => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let has_more = rows.len() > limit;
+    rows.truncate(limit);
+
+    let entries = rows
+        .into_iter()
+        .filter_map(|row| match <[Value; 3]>::try_from(row).ok()? {
+            [Value::Text(key), Value::Blob(value), Value::Text(etag)] => Some(ScanEntry {
+                key,
+                value: Blob {
+                    base64: BASE64_STANDARD_NO_PAD.encode(value),
+                },
+                etag,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let cursor = entries
+        .last()
+        .filter(|_| has_more)
+        .map(|entry| BASE64_URL_SAFE_NO_PAD.encode(&entry.key));
+
+    let resp = ScanResponse { entries, cursor };
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&resp).unwrap()))
+        .unwrap())
+}
+
+/// Executes the writes atomically, and returns the number of entries each of them changed. If the
+/// precondition of a write doesn't hold, none of them is applied.
+async fn write<D: Database>(
+    db: &D,
+    auth: Authenticated,
+    writes: &[Write],
+) -> Result<Vec<u64>, KvError> {
+    // a single statement is atomic on its own
+    let in_txn = writes.len() > 1;
+    let mut batch = Vec::with_capacity(writes.len() + 2);
+    if in_txn {
+        batch.push(query("BEGIN IMMEDIATE", []));
+    }
+    batch.push(query(CREATE_TABLE, []));
+    batch.extend(writes.iter().map(Write::query));
+
+    let outputs = execute(db, auth, batch).await?;
+    let changes = outputs[outputs.len() - writes.len()..]
+        .iter()
+        .map(|output| output.affected_row_count)
+        .collect::<Vec<_>>();
+
+    let failed = writes
+        .iter()
+        .zip(&changes)
+        .find(|(write, changes)| write.precondition.is_some() && **changes == 0);
+    if let Some((write, _)) = failed {
+        if in_txn {
+            db.rollback(auth).await?;
+        }
+        return Err(KvError::PreconditionFailed(write.key.clone()));
+    }
+
+    if in_txn {
+        execute(db, auth, vec![query("COMMIT", [])]).await?;
+    }
+
+    Ok(changes)
+}
+
+/// Executes the batch, rolling back the transaction if a statement fails.
+async fn execute<D: Database>(
+    db: &D,
+    auth: Authenticated,
+    batch: Vec<Query>,
+) -> Result<Vec<StepOutput>, Error> {
+    let (builder, _) = db
+        .execute_batch_or_rollback(
+            batch,
+            None,
+            ResultLimits::default(),
+            true,
+            auth,
+            KvResultBuilder::default(),
+        )
+        .await?;
+    builder.into_ret().into_iter().collect()
+}
+
+fn query<'a>(sql: &str, params: impl IntoIterator<Item = (&'a str, Value)>) -> Query {
+    let mut stmt = Statement::parse(sql).next().unwrap().unwrap();
+    // the statements of the API are not the SQL of the client
+    stmt.inline_literal = None;
+    stmt.denied_by = None;
+    let params = params
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect::<HashMap<_, _>>();
+
+    Query {
+        stmt,
+        params: Params::new_named(params),
+        want_rows: true,
+    }
+}
+
+/// Whether the error is due to the table of the entries not being created yet, in which case
+/// there are no entries.
+fn is_missing_table(e: &Error) -> bool {
+    e.to_string().contains("no such table: _sqld_kv")
+}
+
+fn etag(value: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(value))
+}
+
+fn header(req: &Request<Body>, name: hyper::header::HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn check_key(key: &str) -> Result<(), KvError> {
+    if key.is_empty() {
+        return Err(KvError::BadRequest("the key is empty".into()));
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(KvError::BadRequest(format!(
+            "the key is longer than {MAX_KEY_LEN} bytes"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Decodes the percent-encoded key of the path of a request.
+fn decode_key(raw: &str) -> Result<String, KvError> {
+    let invalid = || KvError::BadRequest("the key is not percent-encoded UTF-8".into());
+    let mut bytes = raw.bytes();
+    let mut key = Vec::with_capacity(raw.len());
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let mut digit = || (bytes.next()? as char).to_digit(16);
+            let (hi, lo) = digit().zip(digit()).ok_or_else(invalid)?;
+            key.push((hi * 16 + lo) as u8);
+        } else {
+            key.push(b);
+        }
+    }
+    let key = String::from_utf8(key).map_err(|_| invalid())?;
+    check_key(&key)?;
+
+    Ok(key)
+}
+
+/// The smallest string greater than all the strings that start with `prefix`, if there is one.
+/// SQLite compares the keys by their UTF-8 bytes, which orders them like their code points.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars = prefix.chars().collect::<Vec<_>>();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, Default)]
+struct StepOutput {
+    rows: Vec<Vec<Value>>,
+    affected_row_count: u64,
+}
+
+/// Collects the rows and the number of changes of every step, or its error.
+#[derive(Debug, Default)]
+struct KvResultBuilder {
+    steps: Vec<Result<StepOutput, Error>>,
+    current: StepOutput,
+    err: Option<Error>,
+    size: u64,
+    max_size: u64,
+}
+
+impl QueryResultBuilder for KvResultBuilder {
+    type Ret = Vec<Result<StepOutput, Error>>;
+
+    fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        *self = Self {
+            max_size: config.max_size.unwrap_or(u64::MAX),
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        _last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        let output = StepOutput {
+            affected_row_count,
+            ..std::mem::take(&mut self.current)
+        };
+        self.steps.push(match self.err.take() {
+            Some(e) => Err(e),
+            None => Ok(output),
+        });
+        Ok(())
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        self.err = Some(error);
+        Ok(())
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        _cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.current.rows.push(Vec::new());
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        self.size += match v {
+            ValueRef::Text(b) | ValueRef::Blob(b) => b.len() as u64,
+            _ => 8,
+        };
+        if self.size > self.max_size {
+            return Err(QueryResultBuilderError::ResponseTooLarge(self.max_size));
+        }
+        let v = Value::try_from(v).map_err(QueryResultBuilderError::from_any)?;
+        if let Some(row) = self.current.rows.last_mut() {
+            row.push(v);
+        }
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn into_ret(self) -> Self::Ret {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::database::config::DatabaseConfigStore;
+    use crate::database::libsql::{LibSqlDb, LibSqlDbFactory};
+    use crate::database::settings::SessionConfig;
+    use crate::stats::Stats;
+
+    use super::*;
+
+    const AUTH: Authenticated = Authenticated::Authorized(Authorized::FullAccess);
+
+    async fn factory(path: &std::path::Path) -> Arc<dyn DbFactory<Db = LibSqlDb>> {
+        Arc::new(
+            LibSqlDbFactory::new(
+                path.to_path_buf(),
+                &TRANSPARENT_METHODS,
+                || (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                Vec::new(),
+                u64::MAX,
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                None,
+            )
+            .await
+            .unwrap(),
+        )
+    }
+
+    async fn send(
+        factory: &Arc<dyn DbFactory<Db = LibSqlDb>>,
+        req: hyper::http::request::Builder,
+        body: impl Into<Body>,
+    ) -> Response<Body> {
+        handle(req.body(body.into()).unwrap(), AUTH, factory.clone())
+            .await
+            .unwrap()
+    }
+
+    fn put(key: &str) -> hyper::http::request::Builder {
+        Request::put(format!("/kv/{key}"))
+    }
+
+    fn etag_of(resp: &Response<Body>) -> String {
+        resp.headers()["ETag"].to_str().unwrap().to_string()
+    }
+
+    async fn body(resp: Response<Body>) -> Vec<u8> {
+        to_bytes(resp.into_body()).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_race() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = factory(tmp.path()).await;
+
+        // a missing key is reported as such, without creating the table
+        let resp = send(&factory, Request::get("/kv/counter"), Body::empty()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = send(&factory, put("counter"), "0").await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let etag = etag_of(&resp);
+
+        // two clients read the same value, and try to replace it: only one of them wins
+        let writes =
+            ["1", "2"].map(|value| send(&factory, put("counter").header("If-Match", &etag), value));
+        let statuses = futures::future::join_all(writes)
+            .await
+            .iter()
+            .map(|resp| resp.status())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses
+                .iter()
+                .filter(|status| **status == StatusCode::NO_CONTENT)
+                .count(),
+            1,
+            "{statuses:?}"
+        );
+        assert!(statuses.contains(&StatusCode::PRECONDITION_FAILED));
+        let winner = if statuses[0] == StatusCode::NO_CONTENT {
+            "1"
+        } else {
+            "2"
+        };
+
+        let resp = send(&factory, Request::get("/kv/counter"), Body::empty()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(etag_of(&resp), etag);
+        assert_eq!(body(resp).await, winner.as_bytes());
+
+        // the stale ETag can't delete the entry either
+        let resp = send(
+            &factory,
+            Request::delete("/kv/counter").header("If-Match", &etag),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+        // create-only writes fail once the entry exists
+        let resp = send(&factory, put("counter").header("If-None-Match", "*"), "3").await;
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+        // a batch with a failed precondition writes nothing
+        let batch = serde_json::json!({
+            "ops": [
+                {"op": "put", "key": "other", "value": {"base64": "eA"}},
+                {"op": "put", "key": "counter", "value": {"base64": "eA"}, "if_match": etag},
+            ]
+        });
+        let resp = send(
+            &factory,
+            Request::post("/kv/batch"),
+            serde_json::to_vec(&batch).unwrap(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        let resp = send(&factory, Request::get("/kv/other"), Body::empty()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn scan_page(
+        factory: &Arc<dyn DbFactory<Db = LibSqlDb>>,
+        prefix: &str,
+        cursor: Option<&str>,
+    ) -> (Vec<String>, Option<String>) {
+        let mut uri = format!("/kv?prefix={prefix}&limit=3");
+        if let Some(cursor) = cursor {
+            uri.push_str(&format!("&cursor={cursor}"));
+        }
+        let resp = send(factory, Request::get(uri), Body::empty()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(&body(resp).await).unwrap();
+        let keys = page["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["key"].as_str().unwrap().to_string())
+            .collect();
+        (keys, page["cursor"].as_str().map(String::from))
+    }
+
+    #[tokio::test]
+    async fn scan_pagination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let factory = factory(tmp.path()).await;
+
+        let ops = ["a", "b1", "b2", "b4", "b5", "b7", "b8", "c"]
+            .iter()
+            .map(|key| serde_json::json!({"op": "put", "key": key, "value": {"base64": "eA"}}))
+            .collect::<Vec<_>>();
+        let resp = send(
+            &factory,
+            Request::post("/kv/batch"),
+            serde_json::to_vec(&serde_json::json!({ "ops": ops })).unwrap(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (keys, cursor) = scan_page(&factory, "b", None).await;
+        assert_eq!(keys, ["b1", "b2", "b4"]);
+        let cursor = cursor.unwrap();
+
+        // keys are written and deleted between the pages
+        for key in ["b0", "b3", "b6"] {
+            assert_eq!(
+                send(&factory, put(key), "y").await.status(),
+                StatusCode::NO_CONTENT
+            );
+        }
+        let resp = send(&factory, Request::delete("/kv/b7"), Body::empty()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // the scan resumes after the last key it returned, without repeating or skipping keys
+        let (keys, cursor) = scan_page(&factory, "b", Some(&cursor)).await;
+        assert_eq!(keys, ["b5", "b6", "b8"]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn prefix_ranges() {
+        assert_eq!(prefix_end(""), None);
+        assert_eq!(prefix_end("ab").as_deref(), Some("ac"));
+        assert_eq!(prefix_end("a\u{d7ff}").as_deref(), Some("a\u{e000}"));
+        assert_eq!(prefix_end("a\u{10ffff}").as_deref(), Some("b"));
+        assert_eq!(prefix_end("\u{10ffff}"), None);
+        assert_eq!(decode_key("caf%C3%A9/x").unwrap(), "café/x");
+        assert!(decode_key("%zz").is_err());
+        assert!(decode_key(&"k".repeat(MAX_KEY_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,584 @@
+//! Query results streamed as newline-delimited JSON, for the clients that process large results
+//! row by row.
+//!
+//! A request asks for them with `?stream=true`, or with `Accept: application/x-ndjson`. Each line
+//! of the response is a [`StreamLine`]: the columns of a statement, one of its rows, or its row
+//! count or error once it's done. The lines are sent as the connection steps through the rows, and
+//! a client that goes away cancels the statement being executed, so that a long scan doesn't keep
+//! running for nobody.
+
+use std::io;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use hyper::header::ACCEPT;
+use hyper::{Body, Request};
+use rusqlite::types::ValueRef;
+use sqld_api_types::http::{StepError, StreamLine};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::Authenticated;
+use crate::database::settings::ResultLimits;
+use crate::database::{is_transactional_batch, Database};
+use crate::error::Error;
+use crate::hrana::proto;
+use crate::hrana::stmt::{proto_constraint_violation, proto_value_from_value};
+use crate::query::{Query, Value};
+use crate::query_result_builder::{
+    Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
+};
+use crate::replication::FrameNo;
+
+use super::streamed_statement::NDJSON_CONTENT_TYPE;
+
+/// Number of chunks buffered for a slow client, before the connection waits for it.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// While the client is behind, the lines are gathered in chunks of about this size.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Whether the request asks for the results to be streamed, with the `stream` query parameter
+/// or, if it's absent, with the `Accept` header.
+pub fn is_requested(req: &Request<Body>) -> bool {
+    let param = req.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "stream")
+            .map(|(_, value)| value)
+    });
+    if let Some(param) = param {
+        return param == "true" || param == "1";
+    }
+
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| {
+            accept.split(',').any(|media_type| {
+                media_type
+                    .split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .eq_ignore_ascii_case(NDJSON_CONTENT_TYPE)
+            })
+        })
+}
+
+/// Executes `batch`, and returns the body streaming its results, once its first line is ready. A
+/// failure of the batch after that aborts the body. Dropping the body cancels the execution.
+pub async fn execute<D: Database>(
+    db: D,
+    batch: Vec<Query>,
+    expected_replication_index: Option<FrameNo>,
+    result_limits: ResultLimits,
+    stop_on_error: bool,
+    transaction: bool,
+    auth: Authenticated,
+) -> Result<Body, Error> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let (start_sender, start) = oneshot::channel();
+    let cancellation = CancellationToken::new();
+    let mut builder = NdjsonPayloadBuilder::new(sender.clone(), start_sender, cancellation.clone());
+    builder.is_transactional = transaction || is_transactional_batch(&batch);
+    let execution = tokio::spawn(async move {
+        let closed = sender.clone();
+        let watcher = tokio::spawn(async move {
+            closed.closed().await;
+            cancellation.cancel();
+        });
+        let mut res = if transaction {
+            db.execute_transaction(
+                batch,
+                expected_replication_index,
+                result_limits,
+                auth,
+                builder,
+            )
+            .await
+        } else {
+            db.execute_batch_or_rollback(
+                batch,
+                expected_replication_index,
+                result_limits,
+                stop_on_error,
+                auth,
+                builder,
+            )
+            .await
+        };
+        watcher.abort();
+        if let Ok((builder, _)) = &mut res {
+            // the builder is returned with the result of the task, and must not keep `start`
+            // waiting for it
+            builder.start = None;
+        }
+        if res.is_err() {
+            // the client must not mistake the results it received for complete ones
+            let _ = sender
+                .send(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "the execution of the batch failed",
+                )))
+                .await;
+        }
+        res
+    });
+
+    match start.await {
+        Ok(()) => Ok(Body::wrap_stream(ReceiverStream::new(receiver))),
+        // the builder was dropped without a line
+        Err(_) => match execution.await {
+            Ok(Ok(_)) => Ok(Body::empty()),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(Error::Internal(e.to_string())),
+        },
+    }
+}
+
+/// Encodes the results of a batch as lines of JSON, and sends them to the response as they are
+/// produced. The lines are sent one by one while the client keeps up with them, and in larger
+/// chunks when it doesn't.
+pub struct NdjsonPayloadBuilder {
+    step: usize,
+    /// Whether the current step was executed, and gives lines.
+    is_step_empty: bool,
+    is_step_error: bool,
+    /// Whether a failed step rolls back the batch, see [`is_transactional_batch`].
+    is_transactional: bool,
+    row: Vec<proto::Value>,
+    row_count: u64,
+    /// The lines that were not sent yet.
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    start: Option<oneshot::Sender<()>>,
+    cancellation: CancellationToken,
+}
+
+impl NdjsonPayloadBuilder {
+    fn new(
+        sender: mpsc::Sender<io::Result<Bytes>>,
+        start: oneshot::Sender<()>,
+        cancellation: CancellationToken,
+    ) -> Self {
+        Self {
+            step: 0,
+            is_step_empty: true,
+            is_step_error: false,
+            is_transactional: false,
+            row: Vec::new(),
+            row_count: 0,
+            buffer: Vec::new(),
+            sender,
+            start: Some(start),
+            cancellation,
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.start.is_none()
+    }
+
+    fn write_line(&mut self, line: &StreamLine) -> Result<(), QueryResultBuilderError> {
+        serde_json::to_writer(&mut self.buffer, line).map_err(QueryResultBuilderError::from_any)?;
+        self.buffer.push(b'\n');
+
+        Ok(())
+    }
+
+    /// Sends the buffered lines, unless the client is behind and there are few of them. `force`
+    /// sends them regardless.
+    fn flush(&mut self, force: bool) -> Result<(), QueryResultBuilderError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        if let Some(start) = self.start.take() {
+            let _ = start.send(());
+        }
+        if !force && self.sender.capacity() == 0 && self.buffer.len() < CHUNK_SIZE {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        // the builder is driven by the thread of a connection, or on the runtime by the write
+        // proxy of a replica, where it must not block the other tasks
+        let sender = &self.sender;
+        tokio::task::block_in_place(|| sender.blocking_send(Ok(chunk)))
+            .map_err(|_| QueryResultBuilderError::Internal(anyhow!("the client went away")))
+    }
+}
+
+impl QueryResultBuilder for NdjsonPayloadBuilder {
+    type Ret = ();
+
+    fn init(&mut self, _config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
+        if self.is_started() {
+            return Err(QueryResultBuilderError::Internal(anyhow!(
+                "the streamed response already started"
+            )));
+        }
+        self.step = 0;
+        self.buffer.clear();
+
+        Ok(())
+    }
+
+    fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.is_step_empty = true;
+        self.is_step_error = false;
+        self.row_count = 0;
+        Ok(())
+    }
+
+    fn finish_step(
+        &mut self,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
+    ) -> Result<(), QueryResultBuilderError> {
+        // the steps skipped after a failure give no line
+        if !self.is_step_empty && !self.is_step_error {
+            self.write_line(&StreamLine::Done {
+                step: self.step,
+                row_count: self.row_count,
+                affected_row_count,
+                last_insert_rowid,
+            })?;
+        }
+        self.step += 1;
+
+        self.flush(true)
+    }
+
+    fn step_error(&mut self, error: Error) -> Result<(), QueryResultBuilderError> {
+        self.is_step_empty = false;
+        self.is_step_error = true;
+        let user_error = error.user_error();
+        // the line is sent with the end of the step
+        self.write_line(&StreamLine::Error {
+            step: self.step,
+            error: StepError {
+                message: user_error.message,
+                code: Some(user_error.code.into()),
+                error_id: user_error.error_id.map(|id| id.to_string()),
+                constraint_violations: error
+                    .constraint_violations()
+                    .iter()
+                    .map(proto_constraint_violation)
+                    .collect(),
+                rolled_back: self.is_transactional,
+            },
+        })
+    }
+
+    fn cols_description<'a>(
+        &mut self,
+        cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
+    ) -> Result<(), QueryResultBuilderError> {
+        self.is_step_empty = false;
+        let columns = cols
+            .into_iter()
+            .map(|col| {
+                let col = col.into();
+                proto::Col {
+                    name: Some(col.name.to_string()),
+                    decltype: col.decl_ty.map(str::to_string),
+                }
+            })
+            .collect();
+        self.write_line(&StreamLine::Columns {
+            step: self.step,
+            columns,
+        })?;
+
+        self.flush(false)
+    }
+
+    fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.row.clear();
+        Ok(())
+    }
+
+    fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
+        let value = Value::try_from(v)?;
+        self.row.push(proto_value_from_value(value));
+        Ok(())
+    }
+
+    fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.row_count += 1;
+        let row = std::mem::take(&mut self.row);
+        self.write_line(&StreamLine::Row {
+            step: self.step,
+            row,
+        })?;
+
+        self.flush(false)
+    }
+
+    fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.flush(true)
+    }
+
+    fn cancellation(&self) -> Option<CancellationToken> {
+        Some(self.cancellation.clone())
+    }
+
+    fn into_ret(self) -> Self::Ret {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::body::{to_bytes, HttpBody};
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::auth::Authorized;
+    use crate::database::config::DatabaseConfigStore;
+    use crate::database::factory::DbFactory;
+    use crate::database::libsql::{LibSqlDb, LibSqlDbFactory};
+    use crate::database::settings::SessionConfig;
+    use crate::query::Params;
+    use crate::query_analysis::Statement;
+    use crate::query_result_builder::StepResultsBuilder;
+    use crate::stats::Stats;
+
+    use super::*;
+
+    const AUTH: Authenticated = Authenticated::Authorized(Authorized::FullAccess);
+
+    async fn db(path: &std::path::Path) -> LibSqlDb {
+        LibSqlDbFactory::new(
+            path.to_path_buf(),
+            &TRANSPARENT_METHODS,
+            || (),
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Vec::new(),
+            u64::MAX,
+            None,
+            None,
+            None,
+            None,
+            SessionConfig::default(),
+            None,
+        )
+        .await
+        .unwrap()
+        .create()
+        .await
+        .unwrap()
+    }
+
+    fn batch(stmts: &[&str]) -> Vec<Query> {
+        stmts
+            .iter()
+            .map(|sql| Query {
+                stmt: Statement::parse(sql).next().unwrap().unwrap(),
+                params: Params::empty(),
+                want_rows: true,
+            })
+            .collect()
+    }
+
+    fn parse_lines(body: &[u8]) -> Vec<StreamLine> {
+        std::str::from_utf8(body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_results() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stmts = batch(&[
+            "CREATE TABLE t (id INTEGER, name TEXT)",
+            "INSERT INTO t VALUES (1, 'a'), (2, NULL)",
+            "SELECT * FROM t ORDER BY id",
+        ]);
+        let body = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            false,
+            AUTH,
+        )
+        .await
+        .unwrap();
+        let lines = parse_lines(&to_bytes(body).await.unwrap());
+        assert_eq!(lines.len(), 8, "{lines:?}");
+
+        assert!(matches!(
+            &lines[2],
+            StreamLine::Columns { step: 1, columns } if columns.is_empty()
+        ));
+        assert!(matches!(
+            &lines[3],
+            StreamLine::Done {
+                step: 1,
+                row_count: 0,
+                affected_row_count: 2,
+                last_insert_rowid: Some(2),
+            }
+        ));
+        assert!(matches!(
+            &lines[4],
+            StreamLine::Columns { step: 2, columns }
+                if columns[0].name.as_deref() == Some("id")
+                    && columns[1].decltype.as_deref() == Some("TEXT")
+        ));
+        assert!(matches!(
+            &lines[5],
+            StreamLine::Row { step: 2, row } if matches!(
+                row[..],
+                [proto::Value::Integer { value: 1 }, proto::Value::Text { ref value }] if &**value == "a"
+            )
+        ));
+        assert!(matches!(
+            &lines[6],
+            StreamLine::Row { step: 2, row }
+                if matches!(row[..], [proto::Value::Integer { value: 2 }, proto::Value::Null])
+        ));
+        assert!(matches!(
+            &lines[7],
+            StreamLine::Done {
+                step: 2,
+                row_count: 2,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        // the failed statement gives an error line, and, with `stop_on_error`, the following
+        // ones no line
+        let stmts = batch(&["SELECT 1", "SELECT * FROM missing", "SELECT 2"]);
+        let body = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            true,
+            false,
+            AUTH,
+        )
+        .await
+        .unwrap();
+        let lines = parse_lines(&to_bytes(body).await.unwrap());
+        assert_eq!(lines.len(), 4, "{lines:?}");
+        assert!(matches!(
+            &lines[3],
+            StreamLine::Error { step: 1, error }
+                if error.message.contains("no such table") && !error.rolled_back
+        ));
+
+        // by default, the following statements are executed
+        let stmts = batch(&["SELECT 1", "SELECT * FROM missing", "SELECT 2"]);
+        let body = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            false,
+            AUTH,
+        )
+        .await
+        .unwrap();
+        let lines = parse_lines(&to_bytes(body).await.unwrap());
+        assert_eq!(lines.len(), 7, "{lines:?}");
+        assert!(matches!(&lines[3], StreamLine::Error { step: 1, .. }));
+        assert!(matches!(&lines[6], StreamLine::Done { step: 2, .. }));
+
+        // unless the batch begins a transaction, which the failed statement rolls back
+        let stmts = batch(&["BEGIN", "SELECT * FROM missing", "SELECT 2", "COMMIT"]);
+        let body = execute(
+            db(tmp.path()).await,
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            false,
+            AUTH,
+        )
+        .await
+        .unwrap();
+        let lines = parse_lines(&to_bytes(body).await.unwrap());
+        assert!(
+            matches!(&lines[..], [.., StreamLine::Error { step: 1, error }] if error.rolled_back),
+            "{lines:?}"
+        );
+
+        // a batch that can't be executed gives an error rather than a body, here on a database
+        // without replication index
+        let stmts = batch(&["SELECT 1"]);
+        let res = execute(
+            db(tmp.path()).await,
+            stmts,
+            Some(1),
+            ResultLimits::default(),
+            false,
+            false,
+            AUTH,
+        )
+        .await;
+        assert!(
+            matches!(res, Err(Error::ReplicationIndexUnsupported(_))),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_on_disconnect() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = db(tmp.path()).await;
+        // the second statement never ends, unless it's interrupted
+        let stmts = batch(&[
+            "SELECT 1",
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c",
+        ]);
+        let mut body = execute(
+            db.clone(),
+            stmts,
+            None,
+            ResultLimits::default(),
+            false,
+            false,
+            AUTH,
+        )
+        .await
+        .unwrap();
+        let first = body.data().await.unwrap().unwrap();
+        assert!(matches!(
+            parse_lines(&first)[0],
+            StreamLine::Columns { step: 0, .. }
+        ));
+        drop(body);
+
+        // the connection is free again once the statement is interrupted
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            db.execute_batch(batch(&["SELECT 1"]), AUTH, StepResultsBuilder::default()),
+        )
+        .await;
+        assert!(
+            matches!(res, Ok(Ok(_))),
+            "the statement was not interrupted"
+        );
+    }
+}
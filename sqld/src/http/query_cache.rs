@@ -0,0 +1,509 @@
+//! Cache of the responses to the read-only queries of the HTTP API.
+//!
+//! Dashboards send the same few `SELECT`s over and over, to the replicas in particular. The
+//! response to a query made of cacheable statements only (see [`Statement::is_cacheable`]) is
+//! kept, keyed on the normalized statements and their parameters, along with the replication index
+//! of the database before the query was executed. The query is executed on a connection of its
+//! own, so none of its tables can be a temporary table.
+//!
+//! Once the database commits a new frame (applied by the frame injector of a replica, or logged
+//! by the primary), all the cached responses are stale: a stale response is still served, with the
+//! replication index it corresponds to, while the first request that finds it stale executes the
+//! query again in the background to refresh it. The queries that must see a replication index are
+//! never served stale responses. A response is dropped once its TTL expired, stale or not, and the
+//! least recently used responses are dropped to keep the cache within its size.
+//!
+//! A response is cached for the credentials of the client that asked, and is only served if the
+//! query could be executed: the reads are not blocked, the statements are allowed to the client,
+//! and the client is within its rate limit. Otherwise the query is executed, and fails the usual
+//! way.
+//!
+//! [`Statement::is_cacheable`]: crate::query_analysis::Statement::is_cacheable
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+use crate::auth::Authenticated;
+use crate::connections;
+use crate::database::config::DatabaseConfigStore;
+use crate::database::instrumented::check_rate_limit;
+use crate::database::settings::{ResultLimits, SessionConfig};
+use crate::error::Error;
+use crate::metrics::{self, CacheOutcome, Frontend};
+use crate::query::{Params, Query};
+use crate::rate_limit::RateLimiter;
+use crate::replication::FrameNo;
+use crate::utils::shared::Shared;
+
+use super::result_builder::ResponseFormat;
+
+/// Header of the responses to cacheable queries: `hit`, `stale`, or `miss`.
+pub const QUERY_CACHE_HEADER: &str = "x-sqld-cache";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCacheConfig {
+    /// Size of the cached responses at most, in bytes. The cache is disabled if 0.
+    pub max_bytes: u64,
+    /// Time after which a cached response is dropped, whether the database changed or not.
+    pub ttl: Duration,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 0,
+            ttl: Duration::from_secs(10),
+        }
+    }
+}
+
+impl QueryCacheConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+}
+
+/// What the response to a query depends on, besides the database.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    format: ResponseFormat,
+    result_limits: ResultLimits,
+    stop_on_error: bool,
+    transaction: bool,
+    /// The credentials of the client, that the statements it may run depend on.
+    auth: Authenticated,
+    principal: Option<String>,
+    /// The normalized statements, with their parameters.
+    queries: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    /// The key of the query of `batch`, if all its statements are cacheable.
+    pub fn of(
+        batch: &[Query],
+        format: ResponseFormat,
+        result_limits: ResultLimits,
+        stop_on_error: bool,
+        transaction: bool,
+        auth: Authenticated,
+        principal: Option<String>,
+    ) -> Option<Self> {
+        if batch.is_empty() || !batch.iter().all(|query| query.stmt.is_cacheable) {
+            return None;
+        }
+        let queries = batch
+            .iter()
+            .map(|query| Some((query.stmt.stmt.clone(), params_key(&query.params)?)))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            format,
+            result_limits,
+            stop_on_error,
+            transaction,
+            auth,
+            principal,
+            queries,
+        })
+    }
+
+    fn size(&self) -> usize {
+        let queries: usize = self
+            .queries
+            .iter()
+            .map(|(stmt, params)| stmt.len() + params.len())
+            .sum();
+        queries + self.principal.as_ref().map_or(0, String::len)
+    }
+}
+
+/// The parameters of a statement, with the named parameters sorted so that their order doesn't
+/// matter.
+fn params_key(params: &Params) -> Option<String> {
+    let key = match params {
+        Params::Positional(values) => serde_json::to_string(values),
+        Params::Named(values) => serde_json::to_string(&values.iter().collect::<BTreeMap<_, _>>()),
+    };
+    key.ok()
+}
+
+/// A response served from the cache.
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub body: Bytes,
+    /// The replication index of the database the response corresponds to.
+    pub replication_index: FrameNo,
+    /// The database changed since the query was executed.
+    pub stale: bool,
+    /// The caller must execute the query again to refresh the response, see
+    /// [`QueryCache::insert`] and [`QueryCache::cancel_refresh`].
+    pub refresh: bool,
+}
+
+struct Entry {
+    body: Bytes,
+    replication_index: FrameNo,
+    cached_at: Instant,
+    /// Tick of the clock of the cache at the last lookup of the entry.
+    last_used: u64,
+    /// Set while the query is executed again to refresh the entry.
+    refreshing: bool,
+    size: usize,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<CacheKey, Entry>,
+    size: usize,
+    clock: u64,
+}
+
+/// What the queries are checked against before they are answered from the cache, as they would
+/// be before they are executed.
+pub struct CacheChecks {
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub config_store: Arc<DatabaseConfigStore>,
+    pub session_config: Shared<SessionConfig>,
+}
+
+pub struct QueryCache {
+    config: QueryCacheConfig,
+    /// The replication index of the database, which changes with every commit.
+    frame_no: watch::Receiver<FrameNo>,
+    state: Mutex<State>,
+    checks: Option<CacheChecks>,
+}
+
+impl QueryCache {
+    pub fn new(config: QueryCacheConfig, frame_no: watch::Receiver<FrameNo>) -> Self {
+        Self {
+            config,
+            frame_no,
+            state: Mutex::default(),
+            checks: None,
+        }
+    }
+
+    pub fn with_checks(mut self, checks: CacheChecks) -> Self {
+        self.checks = Some(checks);
+        self
+    }
+
+    /// Whether the query of `batch` may be answered from the cache: the reads are not blocked,
+    /// and `auth` may run its statements on behalf of `principal`.
+    pub fn may_answer(
+        &self,
+        batch: &[Query],
+        auth: Authenticated,
+        principal: Option<&str>,
+    ) -> bool {
+        let Some(checks) = self.checks.as_ref() else {
+            return true;
+        };
+        if checks.config_store.get().block_reads {
+            return false;
+        }
+        let classes = checks.session_config.get().allowed_statement_classes;
+        batch
+            .iter()
+            .filter_map(|query| query.stmt.class)
+            .all(|class| classes.is_allowed(auth, principal, class))
+    }
+
+    /// Takes a response served from the cache from the budgets of the client, see
+    /// [`RateLimiter::check`].
+    pub fn check_rate_limit(&self, principal: Option<&str>) -> Result<(), Error> {
+        let Some(rate_limiter) = self.checks.as_ref().and_then(|c| c.rate_limiter.as_ref()) else {
+            return Ok(());
+        };
+        let source_ip = connections::current()
+            .and_then(|c| c.peer_addr())
+            .map(|addr| addr.ip());
+        check_rate_limit(rate_limiter, Frontend::Http, source_ip, principal, false)
+    }
+
+    /// The current replication index of the database. A response must be cached with the index
+    /// read before the query was executed: a commit during the query makes the response stale.
+    pub fn replication_index(&self) -> FrameNo {
+        *self.frame_no.borrow()
+    }
+
+    /// Returns the cached response to the query of `key`, if any. A stale response is only
+    /// returned if `allow_stale`, and the first caller to get it is asked to refresh it.
+    pub fn get(&self, key: &CacheKey, allow_stale: bool) -> Option<CachedResponse> {
+        let current = self.replication_index();
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        state.clock += 1;
+        let clock = state.clock;
+
+        let cached = match state.entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.cached_at) >= self.config.ttl => {
+                state.size -= entry.size;
+                state.entries.remove(key);
+                metrics::set_query_cache_size(state.size as u64);
+                None
+            }
+            Some(entry) => {
+                let stale = entry.replication_index != current;
+                (!stale || allow_stale).then(|| {
+                    entry.last_used = clock;
+                    CachedResponse {
+                        body: entry.body.clone(),
+                        replication_index: entry.replication_index,
+                        stale,
+                        refresh: stale && !std::mem::replace(&mut entry.refreshing, true),
+                    }
+                })
+            }
+            None => None,
+        };
+        metrics::record_query_cache_lookup(match &cached {
+            Some(cached) if cached.stale => CacheOutcome::Stale,
+            Some(_) => CacheOutcome::Hit,
+            None => CacheOutcome::Miss,
+        });
+
+        cached
+    }
+
+    /// Caches the response to the query of `key`, executed once the database was at
+    /// `replication_index`. A fresh response is never replaced by a stale one, and a response
+    /// larger than the whole cache is not cached.
+    pub fn insert(&self, key: CacheKey, body: Bytes, replication_index: FrameNo) {
+        let current = self.replication_index();
+        let now = Instant::now();
+        let size = key.size() + body.len();
+        let mut state = self.state.lock();
+        let state = &mut *state;
+
+        if let Some(entry) = state.entries.get(&key) {
+            if entry.replication_index == current && replication_index != current {
+                return;
+            }
+        }
+        if let Some(entry) = state.entries.remove(&key) {
+            state.size -= entry.size;
+        }
+
+        if size as u64 <= self.config.max_bytes {
+            // the expired responses are dropped first, then the least recently used ones
+            let ttl = self.config.ttl;
+            let total = &mut state.size;
+            state.entries.retain(|_, entry| {
+                let keep = now.duration_since(entry.cached_at) < ttl;
+                if !keep {
+                    *total -= entry.size;
+                }
+                keep
+            });
+            while (state.size + size) as u64 > self.config.max_bytes {
+                let Some(lru) = state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                if let Some(entry) = state.entries.remove(&lru) {
+                    state.size -= entry.size;
+                }
+            }
+
+            state.clock += 1;
+            state.size += size;
+            state.entries.insert(
+                key,
+                Entry {
+                    body,
+                    replication_index,
+                    cached_at: now,
+                    last_used: state.clock,
+                    refreshing: false,
+                    size,
+                },
+            );
+        }
+        metrics::set_query_cache_size(state.size as u64);
+    }
+
+    /// Gives up the refresh of the response to the query of `key`, for a later request to try
+    /// again.
+    pub fn cancel_refresh(&self, key: &CacheKey) {
+        if let Some(entry) = self.state.lock().entries.get_mut(key) {
+            entry.refreshing = false;
+        }
+    }
+
+    #[cfg(test)]
+    fn size(&self) -> usize {
+        self.state.lock().size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::auth::Authorized;
+    use crate::query::Value;
+    use crate::query_analysis::Statement;
+
+    use super::*;
+
+    fn query(sql: &str, params: Params) -> Query {
+        Query {
+            stmt: Statement::parse(sql).next().unwrap().unwrap(),
+            params,
+            want_rows: true,
+        }
+    }
+
+    fn key(sql: &str) -> CacheKey {
+        CacheKey::of(
+            &[query(sql, Params::empty())],
+            ResponseFormat::V1,
+            ResultLimits::default(),
+            false,
+            false,
+            Authenticated::Authorized(Authorized::FullAccess),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn cache(max_bytes: u64, ttl: Duration) -> (QueryCache, watch::Sender<FrameNo>) {
+        let (sender, frame_no) = watch::channel(1);
+        let config = QueryCacheConfig { max_bytes, ttl };
+        (QueryCache::new(config, frame_no), sender)
+    }
+
+    #[test]
+    fn cache_keys() {
+        let of_client = |batch: &[Query], auth, principal: Option<&str>| {
+            CacheKey::of(
+                batch,
+                ResponseFormat::V1,
+                ResultLimits::default(),
+                false,
+                false,
+                auth,
+                principal.map(str::to_string),
+            )
+        };
+        let full_access = Authenticated::Authorized(Authorized::FullAccess);
+        let of = |batch: &[Query]| of_client(batch, full_access, None);
+        assert!(of(&[]).is_none());
+        assert!(of(&[query("select random()", Params::empty())]).is_none());
+        assert!(of(&[
+            query("select 1", Params::empty()),
+            query("insert into t values (1)", Params::empty()),
+        ])
+        .is_none());
+
+        // the statements are normalized
+        assert_eq!(
+            of(&[query("SELECT  *  FROM t", Params::empty())]),
+            of(&[query("select * from t", Params::empty())]),
+        );
+        // and the named parameters are sorted
+        let named = |pairs: &[(&str, i64)]| {
+            let params = pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), Value::Integer(*value)))
+                .collect::<HashMap<_, _>>();
+            of(&[query("select :a, :b", Params::Named(params))])
+        };
+        assert_eq!(named(&[("a", 1), ("b", 2)]), named(&[("b", 2), ("a", 1)]));
+        assert_ne!(named(&[("a", 1), ("b", 2)]), named(&[("a", 2), ("b", 1)]));
+        let positional = |value| {
+            of(&[query(
+                "select ?",
+                Params::Positional(vec![Value::Integer(value)]),
+            )])
+        };
+        assert_ne!(positional(1), positional(2));
+
+        // the responses are not shared between clients
+        let select = [query("select * from t", Params::empty())];
+        let read_only = Authenticated::Authorized(Authorized::ReadOnly);
+        assert_ne!(of(&select), of_client(&select, read_only, None));
+        assert_ne!(
+            of(&select),
+            of_client(&select, full_access, Some("dashboard"))
+        );
+    }
+
+    #[test]
+    fn stale_responses_are_refreshed_once() {
+        let (cache, frame_no) = cache(1024, Duration::from_secs(60));
+        let key = key("select * from t");
+        assert!(cache.get(&key, true).is_none());
+        cache.insert(key.clone(), Bytes::from_static(b"[1]"), 1);
+
+        let cached = cache.get(&key, true).unwrap();
+        assert_eq!(&cached.body[..], b"[1]");
+        assert_eq!(cached.replication_index, 1);
+        assert!(!cached.stale && !cached.refresh);
+
+        // a commit makes all the responses stale
+        frame_no.send_replace(2);
+        assert!(cache.get(&key, false).is_none());
+        let cached = cache.get(&key, true).unwrap();
+        assert!(cached.stale && cached.refresh);
+        assert_eq!(cached.replication_index, 1);
+        // a single request refreshes the response
+        assert!(!cache.get(&key, true).unwrap().refresh);
+        cache.cancel_refresh(&key);
+        assert!(cache.get(&key, true).unwrap().refresh);
+
+        cache.insert(key.clone(), Bytes::from_static(b"[2]"), 2);
+        let cached = cache.get(&key, false).unwrap();
+        assert_eq!(&cached.body[..], b"[2]");
+        assert!(!cached.stale);
+
+        // a refresh that started before the last commit doesn't replace a fresh response
+        cache.insert(key.clone(), Bytes::from_static(b"[1]"), 1);
+        assert_eq!(&cache.get(&key, false).unwrap().body[..], b"[2]");
+    }
+
+    #[test]
+    fn expired_responses_are_dropped() {
+        let (cache, _frame_no) = cache(1024, Duration::from_millis(50));
+        let key = key("select * from t");
+        cache.insert(key.clone(), Bytes::from_static(b"[1]"), 1);
+        assert!(cache.get(&key, true).is_some());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get(&key, true).is_none());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn least_recently_used_responses_are_evicted() {
+        let keys = ["select 1", "select 2", "select 3"].map(key);
+        let entry_size = keys[0].size() + 100;
+        let (cache, _frame_no) = cache(2 * entry_size as u64, Duration::from_secs(60));
+        let body = Bytes::from(vec![0; 100]);
+
+        cache.insert(keys[0].clone(), body.clone(), 1);
+        cache.insert(keys[1].clone(), body.clone(), 1);
+        assert!(cache.get(&keys[0], true).is_some());
+        cache.insert(keys[2].clone(), body.clone(), 1);
+        assert!(cache.get(&keys[0], true).is_some());
+        assert!(cache.get(&keys[1], true).is_none());
+        assert!(cache.get(&keys[2], true).is_some());
+        assert_eq!(cache.size(), 2 * entry_size);
+
+        // a response larger than the cache is not cached
+        cache.insert(keys[1].clone(), Bytes::from(vec![0; 1000]), 1);
+        assert!(cache.get(&keys[1], true).is_none());
+        assert_eq!(cache.size(), 2 * entry_size);
+    }
+}
@@ -0,0 +1,273 @@
+use std::sync::Arc;
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::replication::topology::{Role, Topology};
+use crate::replication::FrameNo;
+
+/// Decides whether the node can serve traffic, for the `/readiness` route.
+pub struct Readiness {
+    topology: Arc<Topology>,
+    /// Last frame committed by the primary, or applied by the replica.
+    frame_no: watch::Receiver<FrameNo>,
+    /// A replica is not ready while it lags more than this number of frames behind its primary.
+    max_lag_frames: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    /// Why the node is not ready.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    role: Role,
+    connected: bool,
+    frame_no: Option<FrameNo>,
+    generation_id: Option<String>,
+    /// Number of frames of the primary that the replica has not applied yet.
+    lag: Option<u64>,
+    /// Time since the primary committed the oldest frame the replica has not received.
+    lag_seconds: Option<f64>,
+}
+
+/// The replication status of the node, for the `/v1/replication/status` route.
+#[derive(Debug, Serialize)]
+struct ReplicationStatus {
+    role: Role,
+    connected: bool,
+    frame_no: Option<FrameNo>,
+    primary_frame_no: Option<FrameNo>,
+    lag_frames: Option<u64>,
+    lag_seconds: Option<f64>,
+}
+
+impl Readiness {
+    pub fn new(
+        topology: Arc<Topology>,
+        frame_no: watch::Receiver<FrameNo>,
+        max_lag_frames: u64,
+    ) -> Self {
+        Self {
+            topology,
+            frame_no,
+            max_lag_frames,
+        }
+    }
+
+    fn frame_no(&self) -> Option<FrameNo> {
+        let frame_no = *self.frame_no.borrow();
+        (frame_no != FrameNo::MAX).then_some(frame_no)
+    }
+
+    fn lag(&self) -> Option<u64> {
+        let primary_frame_no = self.topology.primary_frame_no()?;
+        match self.frame_no() {
+            Some(frame_no) => Some(primary_frame_no.saturating_sub(frame_no)),
+            None => Some(primary_frame_no + 1),
+        }
+    }
+
+    /// The lag of a replica behind its primary, as last reported by the primary. The lag is zero
+    /// as soon as the replica has applied all the frames of the primary.
+    fn lag_seconds(&self, lag: Option<u64>) -> Option<f64> {
+        match lag? {
+            0 => Some(0.0),
+            _ => self.topology.primary_lag().map(|lag| lag.as_secs_f64()),
+        }
+    }
+
+    fn check(&self) -> ReadinessResponse {
+        let role = self.topology.role();
+        let connected = self.topology.is_connected();
+        let lag = match role {
+            Role::Primary => None,
+            Role::Replica => self.lag(),
+        };
+
+        let reason = if crate::STORAGE_HEALTH.is_degraded() {
+            Some("storage is degraded: the replication log is not writable".to_string())
+        } else if !crate::HARD_RESET.is_healthy() {
+            Some("hard resets are halted: the replica can't replicate from its primary".to_string())
+        } else if !connected {
+            Some("the replica is not connected to its primary".to_string())
+        } else {
+            lag.filter(|&lag| lag > self.max_lag_frames).map(|lag| {
+                format!(
+                    "the replica lags {lag} frames behind its primary, more than {}",
+                    self.max_lag_frames
+                )
+            })
+        };
+
+        ReadinessResponse {
+            ready: reason.is_none(),
+            reason,
+            role,
+            connected,
+            frame_no: self.frame_no(),
+            generation_id: self.topology.primary_info().generation_id,
+            lag,
+            lag_seconds: self.lag_seconds(lag),
+        }
+    }
+
+    fn replication_status(&self) -> ReplicationStatus {
+        let role = self.topology.role();
+        let (primary_frame_no, lag_frames) = match role {
+            Role::Primary => (self.frame_no(), None),
+            Role::Replica => (self.topology.primary_frame_no(), self.lag()),
+        };
+
+        ReplicationStatus {
+            role,
+            connected: self.topology.is_connected(),
+            frame_no: self.frame_no(),
+            primary_frame_no,
+            lag_frames,
+            lag_seconds: self.lag_seconds(lag_frames),
+        }
+    }
+}
+
+pub fn handle_readiness(readiness: &Readiness) -> Response<Body> {
+    let resp = readiness.check();
+    let status = if resp.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let payload = serde_json::to_vec(&resp).unwrap();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(payload))
+        .unwrap()
+}
+
+pub fn handle_replication_status(readiness: &Readiness) -> Response<Body> {
+    let payload = serde_json::to_vec(&readiness.replication_status()).unwrap();
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(payload))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::replication::topology::PrimaryInfo;
+
+    use super::*;
+
+    fn replica(max_lag_frames: u64) -> (Readiness, Arc<Topology>, watch::Sender<FrameNo>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let topology = Arc::new(Topology::replica(tmp.path()).unwrap());
+        let (sender, receiver) = watch::channel(FrameNo::MAX);
+        let readiness = Readiness::new(topology.clone(), receiver, max_lag_frames);
+        (readiness, topology, sender)
+    }
+
+    async fn body(resp: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replica_disconnected_from_primary() {
+        let (readiness, topology, sender) = replica(100);
+        topology.set_connected(PrimaryInfo {
+            advertise_addrs: Vec::new(),
+            generation_id: Some("gen1".into()),
+        });
+        topology.set_primary_frame_no(10);
+        sender.send_replace(10);
+        // the connection to the primary is lost
+        topology.set_disconnected();
+
+        let resp = handle_readiness(&readiness);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = body(resp).await;
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["connected"], false);
+        assert_eq!(body["frame_no"], 10);
+        assert_eq!(body["generation_id"], "gen1");
+        assert_eq!(body["lag"], 0);
+        assert_eq!(
+            body["reason"],
+            "the replica is not connected to its primary"
+        );
+    }
+
+    #[tokio::test]
+    async fn replica_lagging_behind_primary() {
+        let (readiness, topology, sender) = replica(100);
+        topology.set_connected(PrimaryInfo::default());
+
+        // nothing was received yet
+        let resp = handle_readiness(&readiness);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await["lag"], serde_json::Value::Null);
+
+        topology.set_primary_frame_no(500);
+        let resp = handle_readiness(&readiness);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(resp).await["lag"], 501);
+
+        sender.send_replace(450);
+        let resp = handle_readiness(&readiness);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body(resp).await;
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["lag"], 50);
+        assert_eq!(body["lag_seconds"], serde_json::Value::Null);
+        assert!(body.get("reason").is_none());
+    }
+
+    #[tokio::test]
+    async fn replica_replication_status() {
+        let (readiness, topology, sender) = replica(100);
+        topology.set_connected(PrimaryInfo::default());
+        topology.set_primary_frame_no(500);
+        sender.send_replace(450);
+        topology.set_primary_lag(Some(Duration::from_millis(2500)));
+
+        let status = body(handle_replication_status(&readiness)).await;
+        assert_eq!(status["role"], "replica");
+        assert_eq!(status["frame_no"], 450);
+        assert_eq!(status["primary_frame_no"], 500);
+        assert_eq!(status["lag_frames"], 50);
+        assert_eq!(status["lag_seconds"], 2.5);
+
+        // the primary reported more frames while the replica was paused
+        topology.advance_primary_frame_no(600);
+        topology.set_primary_lag(Some(Duration::from_secs(4)));
+        let status = body(handle_replication_status(&readiness)).await;
+        assert_eq!(status["lag_frames"], 150);
+        assert_eq!(status["lag_seconds"], 4.0);
+
+        // the replica caught up since the last report of the primary
+        sender.send_replace(600);
+        let status = body(handle_readiness(&readiness)).await;
+        assert_eq!(status["lag"], 0);
+        assert_eq!(status["lag_seconds"], 0.0);
+    }
+
+    #[tokio::test]
+    async fn primary_is_ready() {
+        let topology = Arc::new(Topology::primary(Vec::new(), "gen1".into()));
+        let (_sender, receiver) = watch::channel(42);
+        let readiness = Readiness::new(topology, receiver, 0);
+
+        let resp = handle_readiness(&readiness);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body(resp).await;
+        assert_eq!(body["role"], "primary");
+        assert_eq!(body["frame_no"], 42);
+        assert_eq!(body["generation_id"], "gen1");
+        assert_eq!(body["lag"], serde_json::Value::Null);
+    }
+}
@@ -1,15 +1,92 @@
 use std::io;
 use std::ops::{Deref, DerefMut};
 
+use hyper::header::ACCEPT;
+use hyper::{Body, Request};
 use rusqlite::types::ValueRef;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 use serde_json::ser::{CompactFormatter, Formatter};
 
+use crate::hrana::stmt::proto_constraint_violation;
 use crate::query_result_builder::{
     Column, JsonFormatter, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
+use crate::replication::FrameNo;
+
+pub use sqld_api_types::http::V2_CONTENT_TYPE;
+use sqld_api_types::http::{ExecutionMeta, StepError};
+
+/// The format of the response to a batch of queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ResponseFormat {
+    /// `[{"results": {"columns": [...], "rows": [[...]]}} | {"error": "..."} | null]`, with values
+    /// mapped to their closest JSON type.
+    #[default]
+    V1,
+    /// `{"results": [{"columns": [...], "rows": [[...]], "affected_row_count": n,
+    /// "last_insert_rowid": "..."} | {"error": {"message": "..."}} | null]}`, with values encoded
+    /// like in the hrana protocol: `{"type": "integer", "value": "42"}`.
+    V2,
+}
+
+impl ResponseFormat {
+    /// Picks the format of the response from the `format` query parameter or, if it's absent, from
+    /// the `Accept` header of the request. Returns an error if the client doesn't accept any of the
+    /// formats.
+    pub fn negotiate(req: &Request<Body>) -> Result<Self, String> {
+        let param = req.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "format")
+                .map(|(_, value)| value)
+        });
+        if let Some(param) = param {
+            return match param.as_ref() {
+                "v1" => Ok(Self::V1),
+                "v2" => Ok(Self::V2),
+                other => Err(format!("unknown response format `{other}`")),
+            };
+        }
+
+        let accept = match req.headers().get(ACCEPT) {
+            Some(accept) => accept,
+            None => return Ok(Self::V1),
+        };
+        let accept = accept
+            .to_str()
+            .map_err(|_| "invalid Accept header".to_string())?;
+        let media_types = accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+            .collect::<Vec<_>>();
+        if media_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(V2_CONTENT_TYPE))
+        {
+            Ok(Self::V2)
+        } else if media_types.iter().any(|t| {
+            ["application/json", "application/*", "*/*"]
+                .iter()
+                .any(|accepted| t.eq_ignore_ascii_case(accepted))
+        }) {
+            Ok(Self::V1)
+        } else {
+            Err(format!(
+                "none of the accepted media types are supported: `{accept}`"
+            ))
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::V1 => "application/json",
+            Self::V2 => V2_CONTENT_TYPE,
+        }
+    }
+}
 
 pub struct JsonHttpPayloadBuilder {
+    format: ResponseFormat,
     formatter: JsonFormatter<CompactFormatter>,
     buffer: LimitBuffer,
     checkpoint: usize,
@@ -21,6 +98,11 @@ pub struct JsonHttpPayloadBuilder {
     step_row_count: usize,
     is_step_error: bool,
     is_step_empty: bool,
+    replication_index: Option<FrameNo>,
+    /// Whether each step is an `INSERT`, `UPDATE` or `DELETE`, if known.
+    iud_steps: Option<Vec<bool>>,
+    /// Whether a failed step rolls back the batch.
+    is_transactional: bool,
 }
 
 #[derive(Default)]
@@ -72,9 +154,17 @@ impl io::Write for LimitBuffer {
 
 struct HttpJsonValueSerializer<'a>(&'a ValueRef<'a>);
 
+/// Serializes values like the hrana protocol does.
+struct TypedJsonValueSerializer<'a>(&'a ValueRef<'a>);
+
 impl JsonHttpPayloadBuilder {
     pub fn new() -> Self {
+        Self::with_format(ResponseFormat::V1)
+    }
+
+    pub fn with_format(format: ResponseFormat) -> Self {
         Self {
+            format,
             formatter: JsonFormatter(CompactFormatter),
             buffer: LimitBuffer::new(0),
             checkpoint: 0,
@@ -83,8 +173,30 @@ impl JsonHttpPayloadBuilder {
             step_row_count: 0,
             is_step_error: false,
             is_step_empty: false,
+            replication_index: None,
+            iud_steps: None,
+            is_transactional: false,
         }
     }
+
+    /// Tells whether each step of the batch is an `INSERT`, `UPDATE` or `DELETE`. The affected
+    /// rows of the other steps are then reported as `null` in the V1 format.
+    pub fn with_iud_steps(mut self, iud_steps: Vec<bool>) -> Self {
+        self.iud_steps = Some(iud_steps);
+        self
+    }
+
+    /// Tells whether the batch is rolled back when one of its steps fails, see
+    /// [`crate::database::is_transactional_batch`]. The error of the step then says so.
+    pub fn with_transactional(mut self, is_transactional: bool) -> Self {
+        self.is_transactional = is_transactional;
+        self
+    }
+
+    /// The replication index the results are consistent with.
+    pub fn replication_index(&self) -> Option<FrameNo> {
+        self.replication_index
+    }
 }
 
 impl<'a> Serialize for HttpJsonValueSerializer<'a> {
@@ -98,17 +210,6 @@ impl<'a> Serialize for HttpJsonValueSerializer<'a> {
             base64: &'a [u8],
         }
 
-        fn serialize_b64<S>(b: &[u8], serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            use base64::Engine;
-
-            base64::prelude::BASE64_STANDARD_NO_PAD
-                .encode(b)
-                .serialize(serializer)
-        }
-
         match self.0 {
             ValueRef::Null => serializer.serialize_none(),
             ValueRef::Integer(i) => serializer.serialize_i64(*i),
@@ -121,14 +222,69 @@ impl<'a> Serialize for HttpJsonValueSerializer<'a> {
     }
 }
 
+impl<'a> Serialize for TypedJsonValueSerializer<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        match self.0 {
+            ValueRef::Null => map.serialize_entry("type", "null")?,
+            ValueRef::Integer(i) => {
+                // integers are sent as strings, because JSON numbers can't represent all i64s
+                map.serialize_entry("type", "integer")?;
+                map.serialize_entry("value", &i.to_string())?;
+            }
+            ValueRef::Real(x) => {
+                map.serialize_entry("type", "float")?;
+                map.serialize_entry("value", x)?;
+            }
+            ValueRef::Text(value) => {
+                map.serialize_entry("type", "text")?;
+                map.serialize_entry("value", std::str::from_utf8(value).expect("invalid string"))?;
+            }
+            ValueRef::Blob(value) => {
+                use base64::Engine;
+
+                map.serialize_entry("type", "blob")?;
+                map.serialize_entry(
+                    "base64",
+                    &base64::prelude::BASE64_STANDARD_NO_PAD.encode(value),
+                )?;
+            }
+        }
+        map.end()
+    }
+}
+
+fn serialize_b64<S>(b: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use base64::Engine;
+
+    base64::prelude::BASE64_STANDARD_NO_PAD
+        .encode(b)
+        .serialize(serializer)
+}
+
 impl QueryResultBuilder for JsonHttpPayloadBuilder {
     type Ret = Vec<u8>;
 
     fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
         *self = Self {
             buffer: LimitBuffer::new(config.max_size.unwrap_or(u64::MAX)),
-            ..Self::new()
+            iud_steps: self.iud_steps.take(),
+            is_transactional: self.is_transactional,
+            ..Self::with_format(self.format)
         };
+        if self.format == ResponseFormat::V2 {
+            // write fragment: `{"results":`
+            self.formatter.begin_object(&mut self.buffer)?;
+            self.formatter
+                .serialize_key(&mut self.buffer, "results", true)?;
+            self.formatter.begin_object_value(&mut self.buffer)?;
+        }
         // write fragment: `[`
         self.formatter.begin_array(&mut self.buffer)?;
         Ok(())
@@ -143,20 +299,28 @@ impl QueryResultBuilder for JsonHttpPayloadBuilder {
 
         self.checkpoint = self.buffer.len();
 
-        // write fragment: `{ "results": {`
-        self.formatter.begin_object(&mut self.buffer)?;
-        self.formatter
-            .serialize_key(&mut self.buffer, "results", true)?;
-        self.formatter.begin_object_value(&mut self.buffer)?;
-        self.formatter.begin_object(&mut self.buffer)?;
+        match self.format {
+            ResponseFormat::V1 => {
+                // write fragment: `{ "results": {`
+                self.formatter.begin_object(&mut self.buffer)?;
+                self.formatter
+                    .serialize_key(&mut self.buffer, "results", true)?;
+                self.formatter.begin_object_value(&mut self.buffer)?;
+                self.formatter.begin_object(&mut self.buffer)?;
+            }
+            ResponseFormat::V2 => {
+                // write fragment: `{`
+                self.formatter.begin_object(&mut self.buffer)?;
+            }
+        }
 
         Ok(())
     }
 
     fn finish_step(
         &mut self,
-        _affected_row_count: u64,
-        _last_insert_rowid: Option<i64>,
+        affected_row_count: u64,
+        last_insert_rowid: Option<i64>,
     ) -> Result<(), QueryResultBuilderError> {
         if self.is_step_empty && !self.is_step_error {
             // rollback buffer and write null
@@ -165,9 +329,36 @@ impl QueryResultBuilder for JsonHttpPayloadBuilder {
         } else if self.is_step_error {
             // write fragment: `}`
             self.formatter.end_object(&mut self.buffer)?;
-        } else {
-            // write fragment: `}}`
+        } else if self.format == ResponseFormat::V1 {
+            let is_iud = self.iud_steps.as_ref().map_or(true, |iud_steps| {
+                iud_steps.get(self.step_count) != Some(&false)
+            });
+            // write fragment: `,"meta": {"affected_rows": n, "last_insert_rowid": id}}}`
+            self.formatter.serialize_key_value(
+                &mut self.buffer,
+                "meta",
+                &ExecutionMeta {
+                    affected_rows: is_iud.then_some(affected_row_count),
+                    last_insert_rowid,
+                },
+                false,
+            )?;
+            self.formatter.end_object(&mut self.buffer)?;
             self.formatter.end_object(&mut self.buffer)?;
+        } else {
+            // write fragment: `,"affected_row_count": n, "last_insert_rowid": "id"}`
+            self.formatter.serialize_key_value(
+                &mut self.buffer,
+                "affected_row_count",
+                &affected_row_count,
+                false,
+            )?;
+            self.formatter.serialize_key_value(
+                &mut self.buffer,
+                "last_insert_rowid",
+                &last_insert_rowid.map(|id| id.to_string()),
+                false,
+            )?;
             self.formatter.end_object(&mut self.buffer)?;
         }
         self.formatter.end_array_value(&mut self.buffer)?;
@@ -180,10 +371,47 @@ impl QueryResultBuilder for JsonHttpPayloadBuilder {
         self.is_step_error = true;
         self.is_step_empty = false;
         self.buffer.truncate(self.checkpoint);
-        // write fragment: `{"error": "(error)"`
         self.formatter.begin_object(&mut self.buffer)?;
-        self.formatter
-            .serialize_key_value(&mut self.buffer, "error", &error.to_string(), true)?;
+        let user_error = error.user_error();
+        match self.format {
+            ResponseFormat::V1 => {
+                // write fragment: `{"error": "(error)"`
+                self.formatter.serialize_key_value(
+                    &mut self.buffer,
+                    "error",
+                    &user_error.message,
+                    true,
+                )?;
+                if self.is_transactional {
+                    // write fragment: `,"rolled_back": true`
+                    self.formatter.serialize_key_value(
+                        &mut self.buffer,
+                        "rolled_back",
+                        &true,
+                        false,
+                    )?;
+                }
+            }
+            ResponseFormat::V2 => {
+                // write fragment: `{"error": {"message": "(error)", "code": "(code)", ..}`
+                self.formatter.serialize_key_value(
+                    &mut self.buffer,
+                    "error",
+                    &StepError {
+                        message: user_error.message,
+                        code: Some(user_error.code.into()),
+                        error_id: user_error.error_id.map(|id| id.to_string()),
+                        constraint_violations: error
+                            .constraint_violations()
+                            .iter()
+                            .map(proto_constraint_violation)
+                            .collect(),
+                        rolled_back: self.is_transactional,
+                    },
+                    true,
+                )?;
+            }
+        }
 
         Ok(())
     }
@@ -231,11 +459,19 @@ impl QueryResultBuilder for JsonHttpPayloadBuilder {
     fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
         assert!(!self.is_step_error);
 
-        self.formatter.serialize_array_value(
-            &mut self.buffer,
-            &HttpJsonValueSerializer(&v),
-            self.row_value_count == 0,
-        )?;
+        let first = self.row_value_count == 0;
+        match self.format {
+            ResponseFormat::V1 => self.formatter.serialize_array_value(
+                &mut self.buffer,
+                &HttpJsonValueSerializer(&v),
+                first,
+            )?,
+            ResponseFormat::V2 => self.formatter.serialize_array_value(
+                &mut self.buffer,
+                &TypedJsonValueSerializer(&v),
+                first,
+            )?,
+        }
         self.row_value_count += 1;
 
         Ok(())
@@ -261,8 +497,17 @@ impl QueryResultBuilder for JsonHttpPayloadBuilder {
         Ok(())
     }
 
+    fn replication_index(&mut self, index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        self.replication_index = Some(index);
+        Ok(())
+    }
+
     fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
         self.formatter.end_array(&mut self.buffer)?;
+        if self.format == ResponseFormat::V2 {
+            self.formatter.end_object_value(&mut self.buffer)?;
+            self.formatter.end_object(&mut self.buffer)?;
+        }
 
         Ok(())
     }
@@ -274,6 +519,11 @@ impl QueryResultBuilder for JsonHttpPayloadBuilder {
 
 #[cfg(test)]
 mod test {
+    use rusqlite::types::Value;
+    use serde_json::json;
+
+    use crate::database::constraint::{ConstraintKind, ConstraintViolation};
+    use crate::hrana::proto;
     use crate::query_result_builder::test::random_builder_driver;
 
     use super::*;
@@ -288,4 +538,325 @@ mod test {
             serde_json::from_slice::<Vec<serde_json::Value>>(&ret).unwrap();
         }
     }
+
+    #[test]
+    fn test_json_builder_v2() {
+        #[derive(serde::Deserialize)]
+        struct Response {
+            #[allow(dead_code)]
+            results: Vec<serde_json::Value>,
+        }
+
+        for _ in 0..1000 {
+            let builder = JsonHttpPayloadBuilder::with_format(ResponseFormat::V2);
+            let ret = random_builder_driver(100, builder).into_ret();
+            serde_json::from_slice::<Response>(&ret).unwrap();
+        }
+    }
+
+    fn from_proto(value: proto::Value) -> Value {
+        match value {
+            proto::Value::Null => Value::Null,
+            proto::Value::Integer { value } => Value::Integer(value),
+            proto::Value::Float { value } => Value::Real(value),
+            proto::Value::Text { value } => Value::Text(value.to_string()),
+            proto::Value::Blob { value } => Value::Blob(value.to_vec()),
+        }
+    }
+
+    #[test]
+    fn v2_values_round_trip() {
+        let values = [
+            ValueRef::Null,
+            ValueRef::Integer(0),
+            ValueRef::Integer(42),
+            ValueRef::Integer(-1),
+            // not representable as a JSON number without loss of precision
+            ValueRef::Integer(i64::MAX),
+            ValueRef::Integer(i64::MIN),
+            ValueRef::Real(0.0),
+            ValueRef::Real(-1.5),
+            ValueRef::Real(f64::MAX),
+            ValueRef::Real(f64::MIN_POSITIVE),
+            ValueRef::Text(b""),
+            ValueRef::Text("hello \"world\" \u{1f600}\n".as_bytes()),
+            ValueRef::Blob(b""),
+            ValueRef::Blob(b"\x00\xff\x01"),
+            ValueRef::Blob(&[0xab; 1025]),
+        ];
+
+        let mut builder = JsonHttpPayloadBuilder::with_format(ResponseFormat::V2);
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+        builder.begin_step().unwrap();
+        let cols = (0..values.len())
+            .map(|i| format!("c{i}"))
+            .collect::<Vec<_>>();
+        builder
+            .cols_description(cols.iter().map(|c| (c.as_str(), None)))
+            .unwrap();
+        builder.begin_rows().unwrap();
+        builder.begin_row().unwrap();
+        for value in values {
+            builder.add_row_value(value).unwrap();
+        }
+        builder.finish_row().unwrap();
+        builder.finish_rows().unwrap();
+        builder.finish_step(0, None).unwrap();
+        builder.finish().unwrap();
+
+        let ret: serde_json::Value = serde_json::from_slice(&builder.into_ret()).unwrap();
+        let row = ret["results"][0]["rows"][0].as_array().unwrap();
+        assert_eq!(row.len(), values.len());
+        for (json, value) in row.iter().zip(values) {
+            // the values are encoded like in hrana, and decode to the original value
+            let decoded: proto::Value = serde_json::from_str(&json.to_string()).unwrap();
+            assert_eq!(from_proto(decoded), Value::from(value), "{json}");
+        }
+
+        assert_eq!(row[2], json!({"type": "integer", "value": "42"}));
+        assert_eq!(row[0], json!({"type": "null"}));
+        assert_eq!(row[14]["type"], "blob");
+    }
+
+    #[test]
+    fn v2_batch() {
+        let mut builder = JsonHttpPayloadBuilder::with_format(ResponseFormat::V2);
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+
+        builder.begin_step().unwrap();
+        builder.cols_description([("id", None::<&str>)]).unwrap();
+        builder.begin_rows().unwrap();
+        builder.finish_rows().unwrap();
+        builder.finish_step(3, Some(i64::MAX)).unwrap();
+
+        builder.begin_step().unwrap();
+        builder
+            .step_error(crate::error::Error::LibSqlTxBusy)
+            .unwrap();
+        builder.finish_step(0, None).unwrap();
+
+        builder.begin_step().unwrap();
+        builder.finish_step(0, None).unwrap();
+
+        builder.finish().unwrap();
+
+        let ret: serde_json::Value = serde_json::from_slice(&builder.into_ret()).unwrap();
+        assert_eq!(
+            ret,
+            json!({
+                "results": [
+                    {
+                        "columns": ["id"],
+                        "rows": [],
+                        "affected_row_count": 3,
+                        "last_insert_rowid": i64::MAX.to_string(),
+                    },
+                    {"error": {
+                        "message": "Server can't handle additional transactions",
+                        "code": "TRANSACTION_BUSY",
+                    }},
+                    null,
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn v2_constraint_violations() {
+        let violation = ConstraintViolation {
+            kind: ConstraintKind::ForeignKey,
+            table: Some("child".into()),
+            constraint: Some("parent_id".into()),
+            parent: Some("parent".into()),
+            rowid: Some(6),
+        };
+        let mut builder = JsonHttpPayloadBuilder::with_format(ResponseFormat::V2);
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+        builder.begin_step().unwrap();
+        builder
+            .step_error(crate::error::Error::DeferredConstraintViolation(vec![
+                violation,
+            ]))
+            .unwrap();
+        builder.finish_step(0, None).unwrap();
+        builder.finish().unwrap();
+
+        let ret: serde_json::Value = serde_json::from_slice(&builder.into_ret()).unwrap();
+        let error = &ret["results"][0]["error"];
+        assert!(error["message"].as_str().unwrap().contains("rolled back"));
+        assert_eq!(
+            error["constraint_violations"],
+            json!([{
+                "kind": "FOREIGN_KEY",
+                "table": "child",
+                "constraint": "parent_id",
+                "parent": "parent",
+                "rowid": "6",
+            }])
+        );
+    }
+
+    /// Drives `builder` through a batch with a result set, an error and an empty step.
+    fn build_batch(mut builder: JsonHttpPayloadBuilder) -> Vec<u8> {
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+
+        builder.begin_step().unwrap();
+        builder.cols_description([("x", None::<&str>)]).unwrap();
+        builder.begin_rows().unwrap();
+        for value in [ValueRef::Integer(42), ValueRef::Blob(b"hello\n")] {
+            builder.begin_row().unwrap();
+            builder.add_row_value(value).unwrap();
+            builder.finish_row().unwrap();
+        }
+        builder.finish_rows().unwrap();
+        builder.finish_step(0, None).unwrap();
+
+        builder.begin_step().unwrap();
+        builder
+            .step_error(crate::error::Error::LibSqlTxBusy)
+            .unwrap();
+        builder.finish_step(0, None).unwrap();
+
+        builder.begin_step().unwrap();
+        builder.finish_step(0, None).unwrap();
+
+        builder.finish().unwrap();
+        builder.into_ret()
+    }
+
+    #[test]
+    fn responses_match_api_types() {
+        use sqld_api_types::http as api;
+
+        let ret = build_batch(JsonHttpPayloadBuilder::new());
+        let response: api::Response = serde_json::from_slice(&ret).unwrap();
+        match &response[..] {
+            [Some(api::StepResult::Ok { results }), Some(api::StepResult::Error { .. }), None] => {
+                assert_eq!(results.columns, ["x"]);
+                assert_eq!(
+                    results.meta,
+                    Some(api::ExecutionMeta {
+                        affected_rows: Some(0),
+                        last_insert_rowid: None
+                    })
+                );
+                assert_eq!(
+                    results.rows,
+                    [
+                        [api::Value::Integer(42)],
+                        [api::Value::Blob(b"hello\n".to_vec())]
+                    ]
+                );
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        let ret = build_batch(JsonHttpPayloadBuilder::with_format(ResponseFormat::V2));
+        let response: api::TypedResponse = serde_json::from_slice(&ret).unwrap();
+        match &response.results[..] {
+            [Some(api::TypedStepResult::Ok(results)), Some(api::TypedStepResult::Error { .. }), None] =>
+            {
+                assert_eq!(results.rows.len(), 2);
+                assert!(matches!(
+                    results.rows[0][0],
+                    proto::Value::Integer { value: 42 }
+                ));
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rolled_back_errors() {
+        use sqld_api_types::http as api;
+
+        let ret = build_batch(JsonHttpPayloadBuilder::new().with_transactional(true));
+        let response: api::Response = serde_json::from_slice(&ret).unwrap();
+        assert!(matches!(
+            response[1],
+            Some(api::StepResult::Error {
+                rolled_back: true,
+                ..
+            })
+        ));
+        let ret = build_batch(JsonHttpPayloadBuilder::new());
+        assert!(!std::str::from_utf8(&ret).unwrap().contains("rolled_back"));
+
+        let ret = build_batch(
+            JsonHttpPayloadBuilder::with_format(ResponseFormat::V2).with_transactional(true),
+        );
+        let response: api::TypedResponse = serde_json::from_slice(&ret).unwrap();
+        assert!(matches!(
+            &response.results[1],
+            Some(api::TypedStepResult::Error { error }) if error.rolled_back
+        ));
+    }
+
+    #[test]
+    fn v1_execution_meta() {
+        let mut builder = JsonHttpPayloadBuilder::new().with_iud_steps(vec![true, false]);
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+        for (affected_row_count, last_insert_rowid) in [(3, Some(7)), (0, None)] {
+            builder.begin_step().unwrap();
+            builder
+                .cols_description(std::iter::empty::<(&str, Option<&str>)>())
+                .unwrap();
+            builder.begin_rows().unwrap();
+            builder.finish_rows().unwrap();
+            builder
+                .finish_step(affected_row_count, last_insert_rowid)
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let ret: serde_json::Value = serde_json::from_slice(&builder.into_ret()).unwrap();
+        assert_eq!(
+            ret[0]["results"]["meta"],
+            json!({"affected_rows": 3, "last_insert_rowid": 7})
+        );
+        // the affected rows of the other statements are not meaningful
+        assert_eq!(
+            ret[1]["results"]["meta"],
+            json!({"affected_rows": null, "last_insert_rowid": null})
+        );
+    }
+
+    fn negotiate(uri: &str, accept: Option<&str>) -> Result<ResponseFormat, String> {
+        let mut req = Request::post(uri);
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept);
+        }
+        ResponseFormat::negotiate(&req.body(Body::empty()).unwrap())
+    }
+
+    #[test]
+    fn negotiate_format() {
+        assert_eq!(negotiate("/", None), Ok(ResponseFormat::V1));
+        assert_eq!(negotiate("/", Some("*/*")), Ok(ResponseFormat::V1));
+        assert_eq!(
+            negotiate("/", Some("application/json")),
+            Ok(ResponseFormat::V1)
+        );
+        assert_eq!(
+            negotiate("/", Some(V2_CONTENT_TYPE)),
+            Ok(ResponseFormat::V2)
+        );
+        assert_eq!(
+            negotiate(
+                "/",
+                Some("application/json;q=0.5, application/vnd.sqld.v2+json; charset=utf-8")
+            ),
+            Ok(ResponseFormat::V2)
+        );
+        assert!(negotiate("/", Some("text/html")).is_err());
+
+        // the query parameter takes precedence over the Accept header
+        assert_eq!(negotiate("/?format=v2", None), Ok(ResponseFormat::V2));
+        assert_eq!(
+            negotiate("/?a=b&format=v1", Some(V2_CONTENT_TYPE)),
+            Ok(ResponseFormat::V1)
+        );
+        assert!(negotiate("/?format=v3", None).is_err());
+        assert!(negotiate("/?format=v3", Some("application/json")).is_err());
+    }
 }
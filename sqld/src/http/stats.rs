@@ -1,13 +1,45 @@
+use std::collections::BTreeMap;
+
 use hyper::{Body, Response};
 use serde::Serialize;
 
+use crate::connections::{self, ConnectionCounts};
+use crate::database::analyze::TableAnalyze;
+use crate::database::cache_budget::{CacheUsage, CACHE_BUDGET};
+use crate::database::session_state::{session_counts, SessionCounts};
+use crate::query_analysis::sql_rejected_total;
+use crate::replication::primary::logger::{frames_deduplicated_total, frames_logged_total};
+use crate::rpc::proxy::replies_replayed_total;
 use crate::stats::Stats;
+use crate::storage_health::degradations_total;
+use crate::utils::panic::panics_total;
 
 #[derive(Serialize)]
 pub struct StatsResponse {
     pub rows_read_count: u64,
     pub rows_written_count: u64,
     pub storage_bytes_used: u64,
+    pub panics_total: u64,
+    pub vacuum_pages_reclaimed: u64,
+    pub vacuum_bytes_reclaimed: u64,
+    pub storage_degraded_total: u64,
+    pub sql_rejected_total: u64,
+    pub cache_used_bytes: u64,
+    pub connection_caches: Vec<CacheUsage>,
+    pub replication_frames_logged_total: u64,
+    pub replication_frames_deduplicated_total: u64,
+    /// Part of the frames written by SQLite that were not logged, because a newer version of the
+    /// same page was written by the same transaction.
+    pub replication_dedup_ratio: f64,
+    /// Writes retried by the replicas that were answered without being executed again.
+    pub write_proxy_replies_replayed_total: u64,
+    /// Number of open sessions, by state of their transaction.
+    pub sessions: SessionCounts,
+    /// Open and refused client connections, by frontend.
+    pub connections: BTreeMap<&'static str, ConnectionCounts>,
+    /// Changes and last analysis of the tables, only served by the admin API of the primary.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub analyze: Vec<TableAnalyze>,
 }
 
 impl From<&Stats> for StatsResponse {
@@ -16,10 +48,33 @@ impl From<&Stats> for StatsResponse {
             rows_read_count: stats.rows_read(),
             rows_written_count: stats.rows_written(),
             storage_bytes_used: stats.storage_bytes_used(),
+            panics_total: panics_total(),
+            vacuum_pages_reclaimed: stats.vacuum_pages_reclaimed(),
+            vacuum_bytes_reclaimed: stats.vacuum_bytes_reclaimed(),
+            storage_degraded_total: degradations_total(),
+            sql_rejected_total: sql_rejected_total(),
+            cache_used_bytes: CACHE_BUDGET.used_bytes(),
+            connection_caches: CACHE_BUDGET.usage(),
+            replication_frames_logged_total: frames_logged_total(),
+            replication_frames_deduplicated_total: frames_deduplicated_total(),
+            replication_dedup_ratio: dedup_ratio(),
+            write_proxy_replies_replayed_total: replies_replayed_total(),
+            sessions: session_counts(),
+            connections: connections::counts(),
+            analyze: Vec::new(),
         }
     }
 }
 
+fn dedup_ratio() -> f64 {
+    let logged = frames_logged_total();
+    let deduplicated = frames_deduplicated_total();
+    match logged + deduplicated {
+        0 => 0.0,
+        total => deduplicated as f64 / total as f64,
+    }
+}
+
 impl From<Stats> for StatsResponse {
     fn from(stats: Stats) -> Self {
         (&stats).into()
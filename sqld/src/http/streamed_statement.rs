@@ -0,0 +1,651 @@
+//! Statements with large blob parameters, streamed in an `application/x-ndjson` request body.
+//!
+//! The first line of the body is the statement, with its parameters:
+//!
+//! ```json
+//! {"q": "INSERT INTO files (name, data) VALUES (?, ?)", "params": ["a.bin", null],
+//!  "blobs": [{"param": 2, "table": "files", "column": "data", "size": 209715200}]}
+//! ```
+//!
+//! and the following lines are the chunks of the streamed blobs, in base64:
+//!
+//! ```json
+//! {"blob": 0, "base64": "..."}
+//! ```
+//!
+//! The chunks are reassembled as they arrive, in memory up to a threshold, and in a temporary file
+//! beyond it. The statement is then executed with a `zeroblob` of the size of every streamed blob,
+//! which must insert a single row, and the blobs are written to that row with incremental blob IO.
+
+use std::borrow::Cow;
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use futures::TryStreamExt;
+use hyper::{Body, Response, StatusCode};
+use rusqlite::blob::ZeroBlob;
+use rusqlite::{DatabaseName, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, Take};
+use tokio_util::io::StreamReader;
+
+use crate::auth::{self, Authenticated, Authorized};
+use crate::database::config::DatabaseConfigStore;
+use crate::database::settings::SessionConfig;
+use crate::database::vacuum::WithConnection;
+use crate::query::Params;
+use crate::query_analysis::{DenyMatch, Statement, StmtClass, StmtKind};
+use crate::utils::shared::Shared;
+
+use super::types::QueryParams;
+use super::{error, user_error};
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Maximum length of a line of the request body. Blobs are split in chunks smaller than this.
+const MAX_LINE_SIZE: usize = 4 * 1024 * 1024;
+/// Size from which a streamed blob is reassembled in a temporary file rather than in memory.
+const DEFAULT_MEMORY_THRESHOLD: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StreamError {
+    #[error("request body is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("invalid streamed statement: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+fn invalid(msg: impl Into<String>) -> StreamError {
+    StreamError::Invalid(msg.into())
+}
+
+#[derive(Debug, Deserialize)]
+struct StatementLine {
+    q: String,
+    params: Option<QueryParams>,
+    #[serde(default)]
+    blobs: Vec<BlobLine>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlobLine {
+    /// The parameter bound to the blob, given as `null` in the parameters of the statement.
+    param: BlobParam,
+    table: String,
+    column: String,
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BlobParam {
+    Position(usize),
+    Name(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkLine<'a> {
+    blob: usize,
+    #[serde(borrow)]
+    base64: Cow<'a, str>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct StreamedResult {
+    pub rows_affected: u64,
+    pub last_insert_rowid: i64,
+}
+
+/// A blob reassembled from its chunks.
+struct SpooledBlob {
+    param: BlobParam,
+    table: String,
+    column: String,
+    size: u64,
+    written: u64,
+    memory: Vec<u8>,
+    file: Option<tokio::fs::File>,
+}
+
+impl SpooledBlob {
+    async fn append(
+        &mut self,
+        data: &[u8],
+        memory_threshold: usize,
+        spill_dir: &Path,
+    ) -> Result<(), StreamError> {
+        self.written += data.len() as u64;
+        if self.written > self.size {
+            return Err(invalid(format!(
+                "blob of parameter {} is larger than its declared size ({} bytes)",
+                self.param, self.size
+            )));
+        }
+
+        if self.file.is_none() && self.memory.len() + data.len() > memory_threshold {
+            let mut file = tokio::fs::File::from_std(tempfile::tempfile_in(spill_dir)?);
+            file.write_all(&self.memory).await?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        match self.file {
+            Some(ref mut file) => file.write_all(data).await?,
+            None => self.memory.extend_from_slice(data),
+        }
+
+        Ok(())
+    }
+
+    fn write_to(&mut self, out: &mut impl Write) -> io::Result<()> {
+        match self.file.take() {
+            Some(file) => {
+                let mut file = file
+                    .try_into_std()
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "blob file still in use"))?;
+                file.rewind()?;
+                io::copy(&mut file, out)?;
+            }
+            None => out.write_all(&self.memory)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for BlobParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobParam::Position(pos) => write!(f, "{pos}"),
+            BlobParam::Name(name) => write!(f, "`{name}`"),
+        }
+    }
+}
+
+/// A statement read from the request body, with its streamed blobs.
+pub struct StreamedStatement {
+    sql: String,
+    params: Params,
+    blobs: Vec<SpooledBlob>,
+    /// The rule of `--denied-statements` matched by the statement.
+    denied_by: Option<DenyMatch>,
+    class: Option<StmtClass>,
+}
+
+impl StreamedStatement {
+    /// Reads a streamed statement from `body`. At most `max_size` bytes are read from the body,
+    /// and the blobs can't be larger than `max_size` bytes in total.
+    pub async fn read(
+        body: Body,
+        max_size: u64,
+        memory_threshold: usize,
+        spill_dir: &Path,
+    ) -> Result<Self, StreamError> {
+        let reader = StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        let mut reader = reader.take(max_size + 1);
+        let mut line = Vec::new();
+
+        if !next_line(&mut reader, &mut line, max_size).await? {
+            return Err(invalid("empty request body"));
+        }
+        let statement: StatementLine = serde_json::from_slice(&line)
+            .map_err(|e| invalid(format!("invalid statement line: {e}")))?;
+        let mut stmts = Statement::parse(&statement.q);
+        let stmt = stmts
+            .next()
+            .transpose()
+            .map_err(|e| invalid(e.to_string()))?
+            .ok_or_else(|| invalid("missing statement"))?;
+        if stmts.next().is_some() {
+            return Err(invalid("only one statement can be streamed"));
+        }
+        if stmt.kind != StmtKind::Write {
+            return Err(invalid("only a write statement can be streamed"));
+        }
+        let total_size = statement.blobs.iter().map(|b| b.size).sum::<u64>();
+        if total_size > max_size {
+            return Err(StreamError::TooLarge(max_size));
+        }
+        if let Some(blob) = statement.blobs.iter().find(|b| b.size > i32::MAX as u64) {
+            return Err(invalid(format!(
+                "blob of parameter {} is larger than the maximum blob size",
+                blob.param
+            )));
+        }
+
+        let mut blobs = statement
+            .blobs
+            .into_iter()
+            .map(|blob| SpooledBlob {
+                param: blob.param,
+                table: blob.table,
+                column: blob.column,
+                size: blob.size,
+                written: 0,
+                memory: Vec::new(),
+                file: None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut data = Vec::new();
+        while next_line(&mut reader, &mut line, max_size).await? {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let chunk: ChunkLine = serde_json::from_slice(&line)
+                .map_err(|e| invalid(format!("invalid chunk line: {e}")))?;
+            let blob = blobs
+                .get_mut(chunk.blob)
+                .ok_or_else(|| invalid(format!("unknown blob {}", chunk.blob)))?;
+            data.clear();
+            BASE64_STANDARD_NO_PAD
+                .decode_vec(chunk.base64.trim_end_matches('='), &mut data)
+                .map_err(|e| {
+                    invalid(format!(
+                        "invalid base64 in chunk of blob {}: {e}",
+                        chunk.blob
+                    ))
+                })?;
+            blob.append(&data, memory_threshold, spill_dir).await?;
+        }
+
+        for blob in &mut blobs {
+            if let Some(ref mut file) = blob.file {
+                file.flush().await?;
+            }
+        }
+        if let Some(blob) = blobs.iter().find(|b| b.written != b.size) {
+            return Err(invalid(format!(
+                "blob of parameter {} is {} bytes long, but {} bytes were declared",
+                blob.param, blob.written, blob.size
+            )));
+        }
+
+        Ok(Self {
+            sql: statement.q,
+            params: statement.params.map_or_else(Params::empty, |p| p.0),
+            blobs,
+            denied_by: stmt.denied_by,
+            class: stmt.class,
+        })
+    }
+
+    /// Executes the statement, and writes the blobs to the row it inserted, in a transaction.
+    pub fn execute(&mut self, conn: &rusqlite::Connection) -> anyhow::Result<StreamedResult> {
+        let txn = rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+
+        let rows_affected = {
+            let mut stmt = conn.prepare(&self.sql)?;
+            self.params.bind(&mut stmt)?;
+            for blob in &self.blobs {
+                let index = match blob.param {
+                    BlobParam::Position(pos) => pos,
+                    BlobParam::Name(ref name) => stmt
+                        .parameter_index(name)?
+                        .ok_or_else(|| anyhow::anyhow!("no parameter named `{name}`"))?,
+                };
+                stmt.raw_bind_parameter(index, ZeroBlob(blob.size as i32))?;
+            }
+            stmt.raw_execute()? as u64
+        };
+        let last_insert_rowid = conn.last_insert_rowid();
+        if !self.blobs.is_empty() {
+            anyhow::ensure!(
+                rows_affected == 1,
+                "a statement with streamed blobs must insert a single row, it changed {rows_affected} rows"
+            );
+        }
+
+        for blob in &mut self.blobs {
+            let mut out = conn.blob_open(
+                DatabaseName::Main,
+                &blob.table,
+                &blob.column,
+                last_insert_rowid,
+                false,
+            )?;
+            blob.write_to(&mut out)?;
+        }
+        txn.commit()?;
+
+        Ok(StreamedResult {
+            rows_affected,
+            last_insert_rowid,
+        })
+    }
+}
+
+/// Reads the next line of `reader` in `line`, without the line feed. Returns `false` at the end of
+/// the body. `reader` is limited to one byte more than `max_size`.
+async fn next_line(
+    reader: &mut Take<impl AsyncBufRead + Unpin>,
+    line: &mut Vec<u8>,
+    max_size: u64,
+) -> Result<bool, StreamError> {
+    line.clear();
+    let read = (&mut *reader)
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', line)
+        .await?;
+    if reader.limit() == 0 {
+        return Err(StreamError::TooLarge(max_size));
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > MAX_LINE_SIZE {
+        return Err(invalid(format!(
+            "line longer than {MAX_LINE_SIZE} bytes, split the blobs in smaller chunks"
+        )));
+    }
+
+    Ok(read > 0)
+}
+
+/// Executes the statements streamed to the primary.
+pub struct StreamedStatements {
+    with_conn: Arc<WithConnection>,
+    db_config_store: Arc<DatabaseConfigStore>,
+    spill_dir: PathBuf,
+    max_size: u64,
+    memory_threshold: usize,
+    /// The settings of the sessions, which also apply to the streamed statements.
+    session_config: Shared<SessionConfig>,
+}
+
+impl StreamedStatements {
+    pub fn new(
+        with_conn: Arc<WithConnection>,
+        db_config_store: Arc<DatabaseConfigStore>,
+        spill_dir: PathBuf,
+        max_size: u64,
+        session_config: Shared<SessionConfig>,
+    ) -> Self {
+        Self {
+            with_conn,
+            db_config_store,
+            spill_dir,
+            max_size,
+            memory_threshold: DEFAULT_MEMORY_THRESHOLD,
+            session_config,
+        }
+    }
+
+    /// Executes the statement streamed in `body`. With `allow_denied`, the statement is executed
+    /// even if it is denied by `--denied-statements`.
+    pub async fn handle(
+        &self,
+        body: Body,
+        auth: Authenticated,
+        allow_denied: bool,
+    ) -> Response<Body> {
+        if auth != Authenticated::Authorized(Authorized::FullAccess) {
+            return error(
+                "streamed statements require write access",
+                StatusCode::FORBIDDEN,
+            );
+        }
+        let config = self.db_config_store.get();
+        if config.block_writes {
+            let reason = config
+                .block_reason
+                .as_deref()
+                .unwrap_or("writes are blocked");
+            return error(reason, StatusCode::FORBIDDEN);
+        }
+        if let Err(e) = crate::STORAGE_HEALTH.check_writable() {
+            return error(&e.to_string(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        let stmt =
+            StreamedStatement::read(body, self.max_size, self.memory_threshold, &self.spill_dir)
+                .await;
+        let mut stmt = match stmt {
+            Ok(stmt) => stmt,
+            Err(e @ StreamError::TooLarge(_)) => {
+                return error(&e.to_string(), StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            Err(e @ StreamError::Invalid(_)) => {
+                return error(&e.to_string(), StatusCode::BAD_REQUEST)
+            }
+            Err(StreamError::Io(e)) => {
+                return error(
+                    &format!("failed to read the request body: {e}"),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        };
+        let session_config = self.session_config.get();
+        let principal = auth::current_identity().and_then(|identity| identity.principal);
+        if let Some(class) = stmt.class.filter(|class| {
+            !session_config
+                .allowed_statement_classes
+                .is_allowed(auth, principal.as_deref(), *class)
+        }) {
+            let e = crate::error::Error::StatementClassNotAllowed(class);
+            return error(&e.to_string(), StatusCode::FORBIDDEN);
+        }
+        if let Some(rule) = session_config.denied_statements.check(stmt.denied_by) {
+            if !allow_denied {
+                let e = crate::error::Error::StatementDenied(rule);
+                return error(&e.to_string(), StatusCode::FORBIDDEN);
+            }
+        }
+
+        let with_conn = self.with_conn.clone();
+        let foreign_keys = session_config.foreign_keys;
+        let res = tokio::task::spawn_blocking(move || {
+            let mut res = None;
+            with_conn(&mut |conn| {
+                conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+                res = Some(stmt.execute(conn));
+                Ok(())
+            })?;
+            res.expect("statement not executed")
+        })
+        .await;
+        match res {
+            Ok(Ok(result)) => Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(&result).unwrap()))
+                .unwrap(),
+            Ok(Err(e)) => match e.downcast::<rusqlite::Error>() {
+                Ok(e) => user_error(&e.into(), StatusCode::BAD_REQUEST),
+                // failures to read the spilled blobs, whose errors mention the files
+                Err(e) if e.is::<io::Error>() => user_error(
+                    &crate::error::Error::Internal(format!("{e:#}")),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ),
+                Err(e) => error(&e.to_string(), StatusCode::BAD_REQUEST),
+            },
+            Err(e) => user_error(
+                &crate::error::Error::Internal(e.to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// Counts the bytes allocated by the current thread.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+        static PEAK: Cell<isize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| {
+                let n = allocated.get() + layout.size() as isize;
+                allocated.set(n);
+                let _ = PEAK.try_with(|peak| peak.set(peak.get().max(n)));
+            });
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let _ = ALLOCATED
+                .try_with(|allocated| allocated.set(allocated.get() - layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Starts measuring the peak of the memory allocated by the current thread.
+    fn reset_peak() -> isize {
+        let allocated = ALLOCATED.with(Cell::get);
+        PEAK.with(|peak| peak.set(allocated));
+        allocated
+    }
+
+    fn byte_at(offset: usize) -> u8 {
+        (offset % 251) as u8
+    }
+
+    /// A body streaming a blob of `size` bytes, in chunks of `chunk_size` bytes.
+    fn streamed_body(size: usize, chunk_size: usize) -> Body {
+        let statement = serde_json::json!({
+            "q": "INSERT INTO files (name, data) VALUES (?, ?)",
+            "params": ["big.bin", null],
+            "blobs": [{"param": 2, "table": "files", "column": "data", "size": size}],
+        });
+        let first = Bytes::from(format!("{statement}\n"));
+        let chunks = futures::stream::unfold(0, move |offset| async move {
+            if offset >= size {
+                return None;
+            }
+            let end = (offset + chunk_size).min(size);
+            let data = (offset..end).map(byte_at).collect::<Vec<_>>();
+            let line =
+                serde_json::json!({"blob": 0, "base64": BASE64_STANDARD_NO_PAD.encode(data)});
+            Some((Ok::<_, io::Error>(Bytes::from(format!("{line}\n"))), end))
+        });
+        Body::wrap_stream(futures::stream::once(async { Ok(first) }).chain(chunks))
+    }
+
+    fn open_db(path: &Path) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open(path.join("data")).unwrap();
+        conn.execute_batch("CREATE TABLE files (name TEXT, data BLOB)")
+            .unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn stream_blob_in_constant_memory() {
+        const SIZE: usize = 32 * 1024 * 1024;
+        const THRESHOLD: usize = 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_db(dir.path());
+
+        let before = reset_peak();
+        let mut stmt = StreamedStatement::read(
+            streamed_body(SIZE, 48 * 1024),
+            2 * SIZE as u64,
+            THRESHOLD,
+            dir.path(),
+        )
+        .await
+        .unwrap();
+        let result = stmt.execute(&conn).unwrap();
+        let peak = PEAK.with(Cell::get) - before;
+        assert!(
+            peak < 4 * THRESHOLD as isize,
+            "{peak} bytes allocated to stream a {SIZE} bytes blob"
+        );
+        assert_eq!(
+            result,
+            StreamedResult {
+                rows_affected: 1,
+                last_insert_rowid: 1,
+            }
+        );
+
+        let mut blob = conn
+            .blob_open(DatabaseName::Main, "files", "data", 1, true)
+            .unwrap();
+        assert_eq!(blob.len(), SIZE);
+        let mut data = vec![0; 64 * 1024];
+        for offset in (0..SIZE).step_by(data.len()) {
+            let read = blob.read_at(&mut data, offset).unwrap();
+            assert!(data[..read]
+                .iter()
+                .enumerate()
+                .all(|(i, b)| *b == byte_at(offset + i)));
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // the declared size of the blobs
+        let res = StreamedStatement::read(streamed_body(4096, 1024), 1024, 1024, dir.path()).await;
+        assert!(matches!(res, Err(StreamError::TooLarge(1024))));
+
+        // the size of the body
+        let res = StreamedStatement::read(streamed_body(4096, 1024), 4096, 1024, dir.path()).await;
+        assert!(matches!(res, Err(StreamError::TooLarge(4096))));
+
+        // more data than declared
+        let body = Body::from(concat!(
+            r#"{"q": "INSERT INTO files (data) VALUES (?)", "params": [null], "#,
+            r#""blobs": [{"param": 1, "table": "files", "column": "data", "size": 2}]}"#,
+            "\n",
+            r#"{"blob": 0, "base64": "aGVsbG8"}"#,
+        ));
+        let res = StreamedStatement::read(body, 4096, 1024, dir.path()).await;
+        assert!(matches!(res, Err(StreamError::Invalid(_))));
+
+        // less data than declared
+        let body = Body::from(concat!(
+            r#"{"q": "INSERT INTO files (data) VALUES (:data)", "params": {":data": null}, "#,
+            r#""blobs": [{"param": ":data", "table": "files", "column": "data", "size": 6}]}"#,
+            "\n",
+            r#"{"blob": 0, "base64": "aGVsbG8"}"#,
+        ));
+        let res = StreamedStatement::read(body, 4096, 1024, dir.path()).await;
+        assert!(matches!(res, Err(StreamError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn stream_named_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_db(dir.path());
+        let body = Body::from(concat!(
+            r#"{"q": "INSERT INTO files (name, data) VALUES ('a', :data)", "params": {":data": null}, "#,
+            r#""blobs": [{"param": ":data", "table": "files", "column": "data", "size": 11}]}"#,
+            "\n",
+            r#"{"blob": 0, "base64": "aGVsbG8"}"#,
+            "\n",
+            r#"{"blob": 0, "base64": "IHdvcmxk"}"#,
+            "\n",
+        ));
+        let mut stmt = StreamedStatement::read(body, 4096, 1024, dir.path())
+            .await
+            .unwrap();
+        stmt.execute(&conn).unwrap();
+
+        let data: Vec<u8> = conn
+            .query_row("SELECT data FROM files WHERE name = 'a'", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(data, b"hello world");
+    }
+}
@@ -0,0 +1,148 @@
+//! The connections of the HTTP server, over TLS if it is configured.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt as _;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::server::TlsStream;
+
+use crate::tls::{TlsConfig, HANDSHAKE_TIMEOUT};
+
+/// Accepts the connections of the clients, and performs their TLS handshakes concurrently.
+pub struct Incoming {
+    incoming: AddrIncoming,
+    tls: Option<Arc<TlsConfig>>,
+    handshakes: FuturesUnordered<BoxFuture<'static, Option<TlsStream<AddrStream>>>>,
+}
+
+impl Incoming {
+    pub fn new(incoming: AddrIncoming, tls: Option<Arc<TlsConfig>>) -> Self {
+        Self {
+            incoming,
+            tls,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Accept for Incoming {
+    type Conn = ClientStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            let stream = match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => stream,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if this.handshakes.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => break,
+            };
+            let Some(tls) = this.tls.as_ref() else {
+                return Poll::Ready(Some(Ok(ClientStream::Plain(stream))));
+            };
+            let acceptor = tls.acceptor();
+            this.handshakes.push(Box::pin(async move {
+                let remote_addr = stream.remote_addr();
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => Some(stream),
+                    Ok(Err(e)) => {
+                        tracing::debug!("TLS handshake with {remote_addr} failed: {e}");
+                        None
+                    }
+                    Err(_) => {
+                        tracing::debug!("TLS handshake with {remote_addr} timed out");
+                        None
+                    }
+                }
+            }));
+        }
+
+        while let Poll::Ready(Some(stream)) = this.handshakes.poll_next_unpin(cx) {
+            if let Some(stream) = stream {
+                return Poll::Ready(Some(Ok(ClientStream::Tls(Box::new(stream)))));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// The connection of a client.
+pub enum ClientStream {
+    Plain(AddrStream),
+    Tls(Box<TlsStream<AddrStream>>),
+}
+
+impl ClientStream {
+    pub fn remote_addr(&self) -> SocketAddr {
+        match self {
+            Self::Plain(stream) => stream.remote_addr(),
+            Self::Tls(stream) => stream.get_ref().0.remote_addr(),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[io::IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Plain(stream) => stream.is_write_vectored(),
+            Self::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
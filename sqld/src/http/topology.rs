@@ -0,0 +1,65 @@
+use std::convert::Infallible;
+
+use hyper::{Body, Response};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::replication::topology::{Role, Topology, TopologyEvent};
+
+#[derive(Serialize)]
+struct PrimaryResponse {
+    role: Role,
+    connected: bool,
+    advertise_addrs: Vec<String>,
+    generation_id: Option<String>,
+}
+
+pub fn handle_primary(topology: &Topology) -> Response<Body> {
+    let primary = topology.primary_info();
+    let resp = PrimaryResponse {
+        role: topology.role(),
+        connected: topology.is_connected(),
+        advertise_addrs: primary.advertise_addrs,
+        generation_id: primary.generation_id,
+    };
+
+    let payload = serde_json::to_vec(&resp).unwrap();
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(payload))
+        .unwrap()
+}
+
+fn to_sse(event: &TopologyEvent) -> String {
+    let data = serde_json::to_string(event).unwrap();
+    format!("event: {}\ndata: {data}\n\n", event.name())
+}
+
+/// Streams topology events as server-sent events, starting with the current state.
+pub fn handle_topology_events(topology: &Topology) -> Response<Body> {
+    let (current, receiver) = topology.subscribe();
+    let stream = futures::stream::unfold(
+        (Some(current), receiver),
+        |(current, mut receiver)| async move {
+            if let Some(current) = current {
+                return Some((Ok::<_, Infallible>(to_sse(&current)), (None, receiver)));
+            }
+
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((Ok(to_sse(&event)), (None, receiver))),
+                    // a slow client only misses intermediate events, the last one is always the
+                    // current state.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Body::wrap_stream(stream))
+        .unwrap()
+}
@@ -1,229 +1,96 @@
 use std::collections::HashMap;
 
-use base64::prelude::BASE64_STANDARD_NO_PAD;
-use base64::Engine;
-use serde::de::Error as _;
 use serde::{Deserialize, Serialize};
+use sqld_api_types::http as api;
 
 use crate::query;
 
+/// The wire format is defined by [`api::HttpQuery`], which the requests are deserialized with.
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(from = "api::HttpQuery")]
 pub struct HttpQuery {
     pub statements: Vec<QueryObject>,
+    /// Session settings applied before the statements are executed, like with `SET`.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub settings: HashMap<String, serde_json::Value>,
+    /// The statements are rejected if the database changed since this replication index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_replication_index: Option<u64>,
+    /// Skip the statements that follow a failed one, see [`api::HttpQuery::stop_on_error`].
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stop_on_error: bool,
+    /// Execute the statements atomically, see [`api::HttpQuery::transaction`].
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub transaction: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(from = "api::QueryObject")]
 pub struct QueryObject {
     pub q: String,
     pub params: QueryParams,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(from = "api::Params")]
 pub struct QueryParams(pub query::Params);
 
-/// Wrapper type to deserialize a payload into a query::Value
-struct ValueDeserializer(query::Value);
-
-impl<'de> Deserialize<'de> for ValueDeserializer {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct Visitor;
-        impl<'de> serde::de::Visitor<'de> for Visitor {
-            type Value = query::Value;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("a valid SQLite value")
-            }
-
-            fn visit_none<E>(self) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Null)
-            }
-
-            fn visit_unit<E>(self) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Null)
-            }
-
-            fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Text(v))
-            }
-
-            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Text(v.to_string()))
-            }
-
-            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Integer(v))
-            }
-
-            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Integer(v as i64))
-            }
-
-            fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Real(v))
-            }
-
-            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-            where
-                A: serde::de::MapAccess<'de>,
-            {
-                match map.next_entry::<&str, &str>()? {
-                    Some((k, v)) => {
-                        if k == "base64" {
-                            // FIXME: If the blog payload is too big, it may block the main thread
-                            // for too long in an async context. In this case, it may be necessary
-                            // to offload deserialization to a separate thread.
-                            let data = BASE64_STANDARD_NO_PAD.decode(v).map_err(|e| {
-                                A::Error::invalid_value(
-                                    serde::de::Unexpected::Str(v),
-                                    &e.to_string().as_str(),
-                                )
-                            })?;
-
-                            Ok(query::Value::Blob(data))
-                        } else {
-                            Err(A::Error::unknown_field(k, &["blob"]))
-                        }
-                    }
-                    None => Err(A::Error::missing_field("blob")),
-                }
-            }
-
-            fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(query::Value::Integer(v as _))
-            }
+impl From<api::HttpQuery> for HttpQuery {
+    fn from(query: api::HttpQuery) -> Self {
+        Self {
+            statements: query.statements.into_iter().map(Into::into).collect(),
+            settings: query.settings,
+            expected_replication_index: query.expected_replication_index,
+            stop_on_error: query.stop_on_error,
+            transaction: query.transaction,
         }
-
-        deserializer.deserialize_any(Visitor).map(ValueDeserializer)
     }
 }
 
-impl<'de> Deserialize<'de> for QueryParams {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct Visitor;
-        impl<'de> serde::de::Visitor<'de> for Visitor {
-            type Value = QueryParams;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("an array or a map of parameters")
-            }
-
-            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-            where
-                A: serde::de::SeqAccess<'de>,
-            {
-                let mut params = Vec::new();
-                while let Some(val) = seq.next_element::<ValueDeserializer>()? {
-                    params.push(val.0);
-                }
-
-                Ok(QueryParams(query::Params::new_positional(params)))
-            }
-
-            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-            where
-                A: serde::de::MapAccess<'de>,
-            {
-                let mut inner = HashMap::new();
-                while let Some((k, v)) = map.next_entry::<String, ValueDeserializer>()? {
-                    inner.insert(k, v.0);
-                }
-
-                Ok(QueryParams(query::Params::new_named(inner)))
-            }
+impl From<api::QueryObject> for QueryObject {
+    fn from(query: api::QueryObject) -> Self {
+        Self {
+            q: query.q,
+            params: query.params.into(),
         }
-
-        deserializer.deserialize_any(Visitor)
     }
 }
 
-impl<'de> Deserialize<'de> for QueryObject {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct Visitor;
-
-        impl<'de> serde::de::Visitor<'de> for Visitor {
-            type Value = QueryObject;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("a string or an object")
+impl From<api::Params> for QueryParams {
+    fn from(params: api::Params) -> Self {
+        let params = match params {
+            api::Params::Positional(values) => {
+                query::Params::new_positional(values.into_iter().map(Into::into).collect())
             }
-
-            fn visit_str<E>(self, q: &str) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(QueryObject {
-                    q: q.to_string(),
-                    params: QueryParams(query::Params::empty()),
-                })
+            api::Params::Named(values) => {
+                query::Params::new_named(values.into_iter().map(|(k, v)| (k, v.into())).collect())
             }
+        };
+        QueryParams(params)
+    }
+}
 
-            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-            where
-                A: serde::de::MapAccess<'de>,
-            {
-                let mut q = None;
-                let mut params = None;
-                while let Some(k) = map.next_key::<&str>()? {
-                    match k {
-                        "q" => {
-                            if q.is_none() {
-                                q.replace(map.next_value::<String>()?);
-                            } else {
-                                return Err(A::Error::duplicate_field("q"));
-                            }
-                        }
-                        "params" => {
-                            if params.is_none() {
-                                params.replace(map.next_value::<QueryParams>()?);
-                            } else {
-                                return Err(A::Error::duplicate_field("params"));
-                            }
-                        }
-                        _ => return Err(A::Error::unknown_field(k, &["q", "params"])),
-                    }
-                }
-
-                Ok(QueryObject {
-                    q: q.ok_or_else(|| A::Error::missing_field("q"))?,
-                    params: params.unwrap_or_else(|| QueryParams(query::Params::empty())),
-                })
-            }
+impl From<api::Value> for query::Value {
+    fn from(value: api::Value) -> Self {
+        match value {
+            api::Value::Null => query::Value::Null,
+            api::Value::Integer(i) => query::Value::Integer(i),
+            api::Value::Real(x) => query::Value::Real(x),
+            api::Value::Text(s) => query::Value::Text(s),
+            api::Value::Blob(b) => query::Value::Blob(b),
         }
+    }
+}
 
-        deserializer.deserialize_any(Visitor)
+impl From<query::Value> for api::Value {
+    fn from(value: query::Value) -> Self {
+        match value {
+            query::Value::Null => api::Value::Null,
+            query::Value::Integer(i) => api::Value::Integer(i),
+            query::Value::Real(x) => api::Value::Real(x),
+            query::Value::Text(s) => api::Value::Text(s),
+            query::Value::Blob(b) => api::Value::Blob(b),
+        }
     }
 }
 
@@ -247,6 +114,52 @@ mod test {
         })
     }
 
+    #[test]
+    fn bind_parsed_params() {
+        use rusqlite::types::Value;
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let read_back = |sql: &str, json: &str| -> Vec<Value> {
+            let QueryParams(params) = serde_json::from_str(json).unwrap();
+            let mut stmt = conn.prepare(sql).unwrap();
+            params.bind(&mut stmt).unwrap();
+            let count = stmt.column_count();
+            let mut rows = stmt.raw_query();
+            let row = rows.next().unwrap().unwrap();
+            (0..count).map(|i| row.get(i).unwrap()).collect()
+        };
+
+        let expected = vec![
+            Value::Null,
+            Value::Integer(-42),
+            Value::Real(1.5),
+            Value::Text("hello".into()),
+            Value::Blob(b"hello\n".to_vec()),
+        ];
+        assert_eq!(
+            read_back(
+                "SELECT ?, ?, ?, ?, ?",
+                r#"[null, -42, 1.5, "hello", {"base64": "aGVsbG8K"}]"#
+            ),
+            expected
+        );
+        assert_eq!(
+            read_back(
+                "SELECT :null, :int, $real, @text, :blob",
+                r#"{"null": null, "int": -42, "$real": 1.5, "@text": "hello", ":blob": {"base64": "aGVsbG8K"}}"#
+            ),
+            expected
+        );
+        assert_eq!(
+            read_back("SELECT ?1, :name, ?1", r#"[1, "a"]"#),
+            [
+                Value::Integer(1),
+                Value::Text("a".into()),
+                Value::Integer(1)
+            ]
+        );
+    }
+
     #[test]
     fn parse_http_query() {
         let json = r#"{"statements":["select * from test",
@@ -1,6 +1,7 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
 use std::time::Duration;
@@ -8,51 +9,117 @@ use std::time::Duration;
 use anyhow::Context as AnyhowContext;
 use enclose::enclose;
 use futures::never::Never;
+use futures::FutureExt;
 use libsql::wal_hook::TRANSPARENT_METHODS;
 use once_cell::sync::Lazy;
-use rpc::run_rpc_server;
-use tokio::sync::{mpsc, Notify};
-use tokio::task::JoinSet;
+use rpc::proxy::IdentityKey;
+use rpc::replication_log::Replicas;
+use rpc::{run_rpc_server, run_standby_rpc_server};
+use tokio::sync::{mpsc, watch, Notify};
 use tonic::transport::Channel;
 use utils::services::idle_shutdown::IdleShutdownLayer;
+use utils::supervisor::supervise;
 
+use self::database::analyze::{AutoAnalyze, AutoAnalyzeConfig};
+use self::database::attach::AttachableDatabase;
+use self::database::cache_budget::CACHE_BUDGET;
+use self::database::checkpoint::{CheckpointConfig, Checkpointer};
 use self::database::config::DatabaseConfigStore;
-use self::database::dump::loader::DumpLoader;
+use self::database::constraint;
+use self::database::dump::loader::{DumpLoader, LoadError, StagedDatabase};
 use self::database::factory::DbFactory;
+use self::database::instrumented::InstrumentedDbFactory;
 use self::database::libsql::{open_db, LibSqlDbFactory};
-use self::database::write_proxy::WriteProxyDbFactory;
+use self::database::pool::PoolConfig;
+use self::database::query_stats::QueryStats;
+use self::database::settings::{
+    AllowedStatementClasses, DeniedStatements, DenyRule, PrincipalStatementClasses,
+    RequireParameterized, ResultLimits, SessionConfig, StmtClass, UnknownSettings,
+};
+use self::database::vacuum::{self, Vacuum, WithConnection};
+use self::database::write_proxy::{WriteProxyDbFactory, WriteProxyLimits};
 use self::database::Database;
+use self::hard_reset::{Admission, HardReset, HardResetConfig};
+use self::http::cors::CorsConfig;
+use self::http::dump::Dumps;
+use self::http::query_cache::{CacheChecks, QueryCache};
+use self::http::readiness::Readiness;
+use self::http::streamed_statement::StreamedStatements;
+use self::metrics::Frontend;
+use self::namespace::{MakeNamespace, Namespace, NamespaceStore};
+use self::replication::backup::Backup;
+use self::replication::logical::{ChangeFeed, TableFilter};
+use self::replication::primary::change_log::ChangeLog;
+use self::replication::primary::frame_stream::{
+    BatchLimits, DEFAULT_BATCH_BYTES, DEFAULT_BATCH_FRAMES,
+};
 use self::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
-use self::replication::{ReplicationLogger, SnapshotCallback};
-use crate::auth::Auth;
+use self::replication::standby::{self, Promotion, Standby};
+use self::replication::topology::{Role, Topology};
+use self::replication::{FrameNo, ReplicationLogger, SnapshotCallback, SnapshotRetention};
+use self::storage_health::StorageHealth;
+use self::utils::backoff::BackoffPolicy;
+use crate::auth::{Auth, Jwks};
+use crate::connections::ConnectionLimiter;
+use crate::consistency_token::ConsistencyTokens;
 use crate::error::Error;
-use crate::replication::replica::Replicator;
+use crate::rate_limit::RateLimitConfig;
+use crate::reload::{ConfigLoader, Reloader};
+use crate::replication::replica::{LogicalReplicator, Replicator};
 use crate::stats::Stats;
+use crate::system::{RestartPolicy, ShutdownPhase, ShutdownSignal, System};
+use crate::tls::TlsConfig;
 
 use sha256::try_digest;
 
+pub use self::connections::ConnectionLimits;
+pub use self::http::query_cache::QueryCacheConfig;
+pub use self::replication::compression::ReplicationCompression;
 pub use sqld_libsql_bindings as libsql;
 
 mod admin_api;
 mod auth;
+mod connections;
+pub mod consistency_token;
 pub mod database;
 mod error;
+mod hard_reset;
 mod heartbeat;
 mod hrana;
 mod http;
+mod metrics;
+mod namespace;
 mod query;
 mod query_analysis;
 mod query_result_builder;
+pub mod rate_limit;
+pub mod reload;
 mod replication;
 pub mod rpc;
 mod stats;
+mod storage_health;
+pub mod system;
 #[cfg(test)]
 mod test;
+mod tls;
 mod utils;
 pub mod version;
 
 const MAX_CONCCURENT_DBS: usize = 128;
 const DB_CREATE_TIMEOUT: Duration = Duration::from_secs(1);
+const QUERY_STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the tables due for an automatic analysis are looked for.
+const AUTO_ANALYZE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// How often the counters of the automatic analysis are persisted.
+const AUTO_ANALYZE_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the size of the WAL and the time since the last checkpoint are checked.
+const CHECKPOINT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the snapshots that are not retained are merged.
+const SNAPSHOT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the TLS certificate files are checked for modifications.
+const TLS_RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the keys of the JWKS are fetched again.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum Backend {
@@ -63,22 +130,60 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// Trigger a hard database reset. This cause the database to be wiped, freshly restarted
 /// This is used for replicas that are left in an unrecoverabe state and should restart from a
-/// fresh state.
+/// fresh state. Resets are throttled, see [`hard_reset`].
 ///
 /// /!\ use with caution.
-pub(crate) static HARD_RESET: Lazy<Arc<Notify>> = Lazy::new(|| Arc::new(Notify::new()));
+pub(crate) static HARD_RESET: Lazy<Arc<HardReset>> = Lazy::new(Default::default);
+
+/// Trigger a clean restart of all the services, without touching the database. Use
+/// `notify_one`, so that the notification is kept until the server waits for it.
+pub(crate) static RESTART: Lazy<Arc<Notify>> = Lazy::new(|| Arc::new(Notify::new()));
+
+/// Notified on the first termination signal, to shut the server down gracefully. The notification
+/// is kept until the server waits for it, so a signal received during a reset is not lost.
+static SHUTDOWN: Lazy<Arc<Notify>> = Lazy::new(|| Arc::new(Notify::new()));
+
+/// Whether a termination signal was received.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Degraded read-only mode, entered when the storage fails. See [`storage_health`].
+pub(crate) static STORAGE_HEALTH: Lazy<StorageHealth> = Lazy::new(Default::default);
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub db_path: PathBuf,
     pub extensions_path: Option<PathBuf>,
     pub http_addr: Option<SocketAddr>,
+    /// Certificate and private key of the HTTP and Hrana listeners, which use TLS if they are set.
+    pub http_tls_cert: Option<PathBuf>,
+    pub http_tls_key: Option<PathBuf>,
+    /// CA of the client certificates, which are then required by the HTTP and Hrana listeners.
+    pub http_tls_ca_cert: Option<PathBuf>,
     pub enable_http_console: bool,
+    /// Serve the key-value API under `/kv` on the HTTP listener.
+    pub enable_kv_api: bool,
+    /// Serve Prometheus metrics under `/metrics` on the HTTP listener.
+    pub enable_metrics: bool,
+    /// Serve the databases of the namespaces next to the default database, on the primary.
+    pub enable_namespaces: bool,
+    /// Number of rows of the record batches of the results in the Arrow format.
+    pub arrow_batch_size: usize,
     pub http_auth: Option<String>,
     pub http_self_url: Option<String>,
     pub hrana_addr: Option<SocketAddr>,
+    /// Period of the pings sent to the Hrana WebSocket connections, which are closed when they
+    /// don't answer in time. Connections are not pinged if `None`.
+    pub hrana_ping_interval: Option<Duration>,
     pub admin_addr: Option<SocketAddr>,
+    /// HTTP basic authentication required by the admin API, in the same format as `http_auth`.
+    pub admin_auth: Option<String>,
     pub auth_jwt_key: Option<String>,
+    /// URL of a JSON Web Key Set, whose keys also sign the JWTs of the clients.
+    pub auth_jwks_url: Option<String>,
+    /// The JWTs of the clients must be intended for one of these audiences, if any.
+    pub auth_jwt_audience: Vec<String>,
+    /// Tolerance of the expiration checks of the JWTs, for the clock skew.
+    pub auth_jwt_leeway: Duration,
     pub backend: Backend,
     pub writer_rpc_addr: Option<String>,
     pub writer_rpc_tls: bool,
@@ -91,20 +196,262 @@ pub struct Config {
     pub rpc_server_key: Option<PathBuf>,
     pub rpc_server_ca_cert: Option<PathBuf>,
     pub bottomless_replication: Option<bottomless::replicator::Options>,
+    /// The generation of the bottomless backup the database is restored from on startup, if it is
+    /// missing or older. Defaults to the newest generation.
+    pub bottomless_restore_generation: Option<uuid::Uuid>,
     pub idle_shutdown_timeout: Option<Duration>,
     pub initial_idle_shutdown_timeout: Option<Duration>,
     pub load_from_dump: Option<PathBuf>,
+    /// An SQLite database file to load on startup. It becomes the database of a fresh primary,
+    /// and its tables are copied into an existing one.
+    pub load_from: Option<PathBuf>,
+    /// The maximum size of the database files uploaded to the admin API, in bytes.
+    pub max_load_size: u64,
     pub max_log_size: u64,
     pub max_log_duration: Option<f32>,
+    /// Rebuild the replication log from the database file on startup, even if the log looks
+    /// intact. This is the way out when the log and the database have diverged.
+    pub force_recover: bool,
+    /// The oldest snapshots of the replication log beyond this number are merged into the next one.
+    pub max_snapshots: Option<usize>,
+    /// The snapshots of the replication log older than this are merged into the next one.
+    pub max_snapshot_age: Option<Duration>,
+    /// Number of most recent snapshots of the replication log that are never merged.
+    pub min_snapshot_keep: usize,
+    /// Time after which the session of a replica that stopped replicating expires.
+    pub replica_session_ttl: Duration,
+    /// Maximum number of sessions of replicas, the handshakes beyond it are refused.
+    pub max_replica_sessions: usize,
+    /// Maximum number of frames read from the replication log at once for a replica.
+    pub replication_batch_frames: usize,
+    /// Maximum size of the frames read from the replication log at once for a replica.
+    pub replication_batch_bytes: usize,
+    /// Compression of the frames streamed to the replicas that support it.
+    pub replication_compression: ReplicationCompression,
     pub heartbeat_url: Option<String>,
     pub heartbeat_auth: Option<String>,
     pub heartbeat_period: Duration,
     pub soft_heap_limit_mb: Option<usize>,
     pub hard_heap_limit_mb: Option<usize>,
+    /// Total size of the page caches of all the connections, shared between the open connections.
+    pub total_cache_size_mb: Option<usize>,
     pub allow_replica_overwrite: bool,
+    /// Open the database of a replica read-only, so that writes are never applied locally.
+    pub read_only_replica: bool,
     pub max_response_size: u64,
+    /// Maximum number of rows of the results of a statement.
+    pub max_response_rows: Option<u64>,
+    /// Maximum size of the values of the results of a statement.
+    pub max_response_bytes: Option<u64>,
+    /// Maximum size of an HTTP request body, once decompressed.
+    pub max_request_size: u64,
+    /// Maximum length of an SQL string, in bytes. Longer SQL is rejected before it is parsed.
+    pub max_sql_length: Option<usize>,
     pub snapshot_exec: Option<String>,
     pub http_replication_addr: Option<SocketAddr>,
+    /// Record row-level changes, so replicas can be replicated logically.
+    pub enable_logical_replication: bool,
+    /// If set, the replica is replicated logically, and only receives these tables.
+    pub replicate_tables: Option<Vec<String>>,
+    /// Client-facing URLs of this primary, advertised to the replicas.
+    pub advertise_addrs: Vec<String>,
+    /// Open the database in `auto_vacuum=INCREMENTAL` mode, migrating it if necessary.
+    pub incremental_vacuum: bool,
+    /// Collect per-statement execution statistics.
+    pub stats_collection: bool,
+    /// Fraction of the statement executions sampled when `stats_collection` is enabled.
+    pub stats_sample_rate: f64,
+    /// Rows changed in a table after which it is analyzed again, automatic analysis is disabled
+    /// if not set.
+    pub auto_analyze_threshold: Option<u64>,
+    /// Time without any statement before the tables are analyzed.
+    pub auto_analyze_idle_window: Duration,
+    /// `PRAGMA analysis_limit` of the automatic analysis.
+    pub auto_analyze_limit: u32,
+    /// Maximum time the analysis of a table can block writes.
+    pub auto_analyze_budget: Duration,
+    /// Time after which a non-empty WAL is checkpointed.
+    pub checkpoint_interval: Option<Duration>,
+    /// Size of the WAL in bytes above which it is checkpointed.
+    pub max_wal_size: Option<u64>,
+    /// Maximum execution time of a statement, sessions can only lower it.
+    pub query_timeout: Option<Duration>,
+    /// Time after which an idle transaction is rolled back. Transactions never time out if `None`.
+    pub txn_timeout: Option<Duration>,
+    /// How `SET` and `SHOW` treat settings that sqld doesn't know about.
+    pub unknown_settings: UnknownSettings,
+    /// Reject statements with literal values where a parameter could be used.
+    pub require_parameterized: Option<RequireParameterized>,
+    /// Enforce the foreign key constraints, with `PRAGMA foreign_keys = ON` on every connection.
+    pub foreign_keys: bool,
+    /// Classes of statements rejected on every protocol, unless a caller with full access asks to
+    /// run them.
+    pub denied_statements: Vec<DenyRule>,
+    /// Also reject the statements whose `WHERE` clause is always true, like `WHERE 1 = 1`.
+    pub strict_denied_statements: bool,
+    /// Classes of statements that the callers may run, all of them if empty.
+    pub allowed_statement_classes: Vec<StmtClass>,
+    /// Classes of statements that the read-only callers may run, those of
+    /// `allowed_statement_classes` if empty.
+    pub read_only_allowed_statement_classes: Vec<StmtClass>,
+    /// Classes of statements that some principals are further restricted to.
+    pub principal_statement_classes: Vec<PrincipalStatementClasses>,
+    /// Queries per second allowed to each client, by source IP and by principal.
+    pub rate_limits: RateLimitConfig,
+    /// Client connections open at once, on all the frontends and on each of them.
+    pub connection_limits: ConnectionLimits,
+    /// Responses to the read-only queries of the HTTP API kept to answer the same queries, see
+    /// [`http::query_cache`].
+    pub query_cache: QueryCacheConfig,
+    /// Databases of the database directory that the clients may attach, read-only, by name.
+    pub attachable_databases: Vec<AttachableDatabase>,
+    /// Secret shared by a primary and its replicas, with which the replicas sign the identity of
+    /// the clients whose writes they proxy. The primary trusts any identity if `None`.
+    pub proxy_identity_key: Option<String>,
+    /// Minimum time between two hard resets of the replica.
+    pub hard_reset_min_interval: Duration,
+    /// Maximum number of hard resets of the replica in an hour, after which the replica stops
+    /// resetting until an operator re-arms the resets.
+    pub max_hard_resets_per_hour: u32,
+    /// Whether hard resets may wipe the database, by default only on replicas.
+    pub allow_hard_reset: Option<bool>,
+    /// Number of frames a replica can lag behind its primary and still report itself as ready.
+    pub max_replication_lag_frames: u64,
+    /// Upper bound of the delay between two attempts of a replica to reach its primary.
+    pub max_reconnect_interval: Duration,
+    /// Number of channels over which a replica proxies its writes to the primary.
+    pub write_proxy_channels: usize,
+    /// Writes a replica sends to the primary at once, over all the channels.
+    pub write_proxy_max_in_flight: usize,
+    /// Writes waiting for their turn to be sent to the primary, beyond which they are rejected.
+    pub write_proxy_queue_size: usize,
+    /// Whether a replica serves the deferred transactions itself until they write, rather than
+    /// proxying them to the primary.
+    pub local_read_transactions: bool,
+    /// Whether a replica keeps trying to reach its primary forever, serving the reads from the
+    /// database it has replicated in the meantime, rather than giving up.
+    pub wait_for_primary: bool,
+    /// Time given to the requests in flight to finish, in each phase of the shutdown.
+    pub shutdown_grace_period: Duration,
+    /// If set, the single-statement writes arriving within this window on the primary are
+    /// committed together, in a single transaction.
+    pub group_commit_window: Option<Duration>,
+    /// Idle connections kept open to be reused by the next clients, see [`database::pool`].
+    pub db_pool: PoolConfig,
+    /// Origins allowed to call the HTTP API from a browser, or `*` for any origin.
+    pub cors_allowed_origins: Vec<String>,
+    /// Methods allowed in CORS requests, any if empty.
+    pub cors_allowed_methods: Vec<String>,
+    /// Headers allowed in CORS requests, any if empty.
+    pub cors_allowed_headers: Vec<String>,
+    pub cors_max_age: Option<Duration>,
+    /// Allow CORS requests with credentials, only with explicit origins.
+    pub cors_allow_credentials: bool,
+    /// Run the replica as a warm standby, which logs the frames it replicates and can be promoted
+    /// to primary.
+    pub standby: bool,
+    /// Key shared by the nodes of the database, to sign the consistency tokens returned with the
+    /// writes. Consistency tokens are disabled if not set.
+    pub consistency_token_key: Option<String>,
+}
+
+impl Config {
+    fn batch_limits(&self) -> BatchLimits {
+        BatchLimits {
+            max_frames: self.replication_batch_frames,
+            max_bytes: self.replication_batch_bytes,
+        }
+    }
+
+    fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            query_timeout: self.query_timeout,
+            txn_timeout: self.txn_timeout,
+            unknown_settings: self.unknown_settings,
+            require_parameterized: self.require_parameterized,
+            foreign_keys: self.foreign_keys,
+            denied_statements: DeniedStatements::new(
+                &self.denied_statements,
+                self.strict_denied_statements,
+            ),
+            allowed_statement_classes: AllowedStatementClasses::new(
+                &self.allowed_statement_classes,
+                &self.read_only_allowed_statement_classes,
+            )
+            .with_principals(&self.principal_statement_classes),
+            result_limits: ResultLimits {
+                max_rows: self.max_response_rows,
+                max_bytes: self.max_response_bytes,
+            },
+        }
+    }
+
+    fn snapshot_retention(&self) -> SnapshotRetention {
+        SnapshotRetention {
+            max_snapshots: self.max_snapshots,
+            max_age: self.max_snapshot_age,
+            min_keep: self.min_snapshot_keep,
+        }
+    }
+
+    fn hard_reset_config(&self) -> HardResetConfig {
+        HardResetConfig {
+            min_interval: self.hard_reset_min_interval,
+            max_per_hour: self.max_hard_resets_per_hour,
+            allowed: self
+                .allow_hard_reset
+                .unwrap_or(self.writer_rpc_addr.is_some()),
+        }
+    }
+
+    fn reconnect_policy(&self) -> BackoffPolicy {
+        BackoffPolicy {
+            max: self.max_reconnect_interval,
+            max_attempts: if self.wait_for_primary {
+                None
+            } else {
+                BackoffPolicy::default().max_attempts
+            },
+            ..Default::default()
+        }
+    }
+
+    fn proxy_identity_key(&self) -> Option<IdentityKey> {
+        self.proxy_identity_key.as_deref().map(IdentityKey::new)
+    }
+
+    fn write_proxy_limits(&self) -> WriteProxyLimits {
+        WriteProxyLimits {
+            max_in_flight: self.write_proxy_max_in_flight,
+            max_queued: self.write_proxy_queue_size,
+        }
+    }
+
+    fn auto_analyze_config(&self) -> AutoAnalyzeConfig {
+        AutoAnalyzeConfig {
+            threshold: self.auto_analyze_threshold,
+            idle_window: self.auto_analyze_idle_window,
+            analysis_limit: self.auto_analyze_limit,
+            budget: self.auto_analyze_budget,
+        }
+    }
+
+    fn checkpoint_config(&self) -> CheckpointConfig {
+        CheckpointConfig {
+            interval: self.checkpoint_interval,
+            max_wal_size: self.max_wal_size,
+        }
+    }
+
+    fn cors_config(&self) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: self.cors_allowed_origins.clone(),
+            allowed_methods: self.cors_allowed_methods.clone(),
+            allowed_headers: self.cors_allowed_headers.clone(),
+            max_age: self.cors_max_age,
+            allow_credentials: self.cors_allow_credentials,
+        }
+    }
 }
 
 impl Default for Config {
@@ -113,12 +460,24 @@ impl Default for Config {
             db_path: "data.sqld".into(),
             extensions_path: None,
             http_addr: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
+            http_tls_cert: None,
+            http_tls_key: None,
+            http_tls_ca_cert: None,
             enable_http_console: false,
+            enable_kv_api: false,
+            enable_metrics: false,
+            enable_namespaces: false,
+            arrow_batch_size: 8192,
             http_auth: None,
             http_self_url: None,
             hrana_addr: None,
+            hrana_ping_interval: Some(Duration::from_secs(30)),
             admin_addr: None,
+            admin_auth: None,
             auth_jwt_key: None,
+            auth_jwks_url: None,
+            auth_jwt_audience: Vec::new(),
+            auth_jwt_leeway: Duration::from_secs(60),
             backend: Backend::Libsql,
             writer_rpc_addr: None,
             writer_rpc_tls: false,
@@ -131,85 +490,351 @@ impl Default for Config {
             rpc_server_key: None,
             rpc_server_ca_cert: None,
             bottomless_replication: None,
+            bottomless_restore_generation: None,
             idle_shutdown_timeout: None,
             initial_idle_shutdown_timeout: None,
             load_from_dump: None,
+            load_from: None,
+            max_load_size: 1024 * 1024 * 1024,
             max_log_size: 200,
             max_log_duration: None,
+            force_recover: false,
+            max_snapshots: None,
+            max_snapshot_age: None,
+            min_snapshot_keep: 1,
+            replica_session_ttl: rpc::replication_log::DEFAULT_REPLICA_SESSION_TTL,
+            max_replica_sessions: rpc::replication_log::DEFAULT_MAX_REPLICA_SESSIONS,
+            replication_batch_frames: DEFAULT_BATCH_FRAMES,
+            replication_batch_bytes: DEFAULT_BATCH_BYTES,
+            replication_compression: ReplicationCompression::None,
             heartbeat_url: None,
             heartbeat_auth: None,
             heartbeat_period: Duration::from_secs(30),
             soft_heap_limit_mb: None,
             hard_heap_limit_mb: None,
+            total_cache_size_mb: None,
             allow_replica_overwrite: false,
+            read_only_replica: false,
             max_response_size: 10 * 1024 * 1024, // 10MiB
+            max_response_rows: None,
+            max_response_bytes: None,
+            max_request_size: 100 * 1024 * 1024, // 100MiB
+            max_sql_length: None,
             snapshot_exec: None,
             http_replication_addr: None,
+            enable_logical_replication: false,
+            replicate_tables: None,
+            advertise_addrs: Vec::new(),
+            incremental_vacuum: false,
+            stats_collection: false,
+            stats_sample_rate: 0.1,
+            auto_analyze_threshold: AutoAnalyzeConfig::default().threshold,
+            auto_analyze_idle_window: AutoAnalyzeConfig::default().idle_window,
+            auto_analyze_limit: AutoAnalyzeConfig::default().analysis_limit,
+            auto_analyze_budget: AutoAnalyzeConfig::default().budget,
+            checkpoint_interval: None,
+            max_wal_size: None,
+            query_timeout: None,
+            txn_timeout: Some(database::DEFAULT_TXN_TIMEOUT),
+            unknown_settings: UnknownSettings::Error,
+            require_parameterized: None,
+            foreign_keys: true,
+            denied_statements: Vec::new(),
+            strict_denied_statements: false,
+            allowed_statement_classes: Vec::new(),
+            read_only_allowed_statement_classes: Vec::new(),
+            principal_statement_classes: Vec::new(),
+            rate_limits: RateLimitConfig::default(),
+            connection_limits: ConnectionLimits::default(),
+            query_cache: QueryCacheConfig::default(),
+            attachable_databases: Vec::new(),
+            proxy_identity_key: None,
+            hard_reset_min_interval: HardResetConfig::default().min_interval,
+            max_hard_resets_per_hour: HardResetConfig::default().max_per_hour,
+            allow_hard_reset: None,
+            max_replication_lag_frames: 1000,
+            max_reconnect_interval: BackoffPolicy::default().max,
+            wait_for_primary: false,
+            local_read_transactions: false,
+            write_proxy_channels: 4,
+            write_proxy_max_in_flight: WriteProxyLimits::default().max_in_flight,
+            write_proxy_queue_size: WriteProxyLimits::default().max_queued,
+            shutdown_grace_period: system::DEFAULT_GRACE_PERIOD,
+            group_commit_window: None,
+            db_pool: PoolConfig::default(),
+            cors_allowed_origins: vec!["*".into()],
+            cors_allowed_methods: Vec::new(),
+            cors_allowed_headers: Vec::new(),
+            cors_max_age: None,
+            cors_allow_credentials: false,
+            standby: false,
+            consistency_token_key: None,
+        }
+    }
+}
+
+impl Config {
+    /// Checks the settings that depend on each other. The messages name the command line options,
+    /// which are also the keys of the configuration file.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.http_tls_cert.is_some() != self.http_tls_key.is_some() {
+            anyhow::bail!("HTTP TLS requires both a certificate (`--http-tls-cert-file`) and a private key (`--http-tls-key-file`)");
+        }
+        if self.http_tls_ca_cert.is_some() && self.http_tls_cert.is_none() {
+            anyhow::bail!("client certificates (`--http-tls-ca-cert-file`) require HTTP TLS (`--http-tls-cert-file`)");
+        }
+        if self.rpc_server_tls && (self.rpc_server_cert.is_none() || self.rpc_server_key.is_none())
+        {
+            anyhow::bail!("gRPC TLS (`--grpc-tls`) requires a certificate (`--grpc-cert-file`) and a private key (`--grpc-key-file`)");
+        }
+        if self.writer_rpc_tls && (self.writer_rpc_cert.is_none() || self.writer_rpc_key.is_none())
+        {
+            anyhow::bail!("TLS to the primary (`--primary-grpc-tls`) requires a certificate (`--primary-grpc-cert-file`) and a private key (`--primary-grpc-key-file`)");
+        }
+        if self.writer_rpc_addr.is_some() && self.rpc_server_addr.is_some() && !self.standby {
+            anyhow::bail!("`--grpc-listen-addr` can only be used with `--primary-grpc-url` by a standby (`--standby`)");
+        }
+        if self.standby && self.writer_rpc_addr.is_none() {
+            anyhow::bail!(
+                "a standby (`--standby`) replicates a primary, set with `--primary-grpc-url`"
+            );
+        }
+        if self.writer_rpc_addr.is_some()
+            && (self.load_from_dump.is_some() || self.load_from.is_some())
+        {
+            anyhow::bail!("a replica can't load a dump or a database file (`--load-from-dump`, `--load-from`), load it into the primary");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configuration with its credentials and keys hidden, to be logged.
+    pub fn redacted(&self) -> Self {
+        let redact = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>".to_string());
+        Self {
+            http_auth: redact(&self.http_auth),
+            admin_auth: redact(&self.admin_auth),
+            auth_jwt_key: redact(&self.auth_jwt_key),
+            heartbeat_auth: redact(&self.heartbeat_auth),
+            proxy_identity_key: redact(&self.proxy_identity_key),
+            consistency_token_key: redact(&self.consistency_token_key),
+            ..self.clone()
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_service<D: Database>(
     db_factory: Arc<dyn DbFactory<Db = D>>,
     config: &Config,
-    join_set: &mut JoinSet<anyhow::Result<()>>,
+    reloader: &Arc<Reloader>,
+    system: &mut System,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
     db_config_store: Arc<DatabaseConfigStore>,
+    topology: Arc<Topology>,
+    frame_no: watch::Receiver<FrameNo>,
+    vacuum: Option<Arc<Vacuum>>,
+    logger: Option<Arc<ReplicationLogger>>,
+    query_stats: Option<Arc<QueryStats>>,
+    auto_analyze: Option<Arc<AutoAnalyze>>,
+    change_feed: Option<ChangeFeed>,
+    standby: Option<Arc<Standby>>,
+    streamed_statements: Option<Arc<StreamedStatements>>,
+    dump_loader: Option<Arc<DumpLoader>>,
+    consistency_tokens: Option<Arc<ConsistencyTokens>>,
+    namespaces: Option<Arc<NamespaceStore>>,
+    replicas: Option<Arc<Replicas>>,
+    backup: Option<Arc<Backup>>,
 ) -> anyhow::Result<()> {
-    let auth = get_auth(config)?;
+    let jwks = get_jwks(config).await?;
+    if let Some(jwks) = jwks.clone() {
+        system.register(
+            auth::refresh_jwks(jwks, JWKS_REFRESH_INTERVAL),
+            "JWKS refresh",
+        );
+    }
+    // the authentication is replaced when the configuration is reloaded
+    reloader.auth.set(build_auth(config, jwks)?);
+    let auth = reloader.auth.clone();
+    let tls = get_tls(config)?;
+    if let Some(tls) = tls.clone() {
+        system.register(
+            tls::watch(tls, TLS_RELOAD_CHECK_INTERVAL),
+            "TLS certificates reload",
+        );
+    }
 
     let (hrana_accept_tx, hrana_accept_rx) = mpsc::channel(8);
     let (hrana_upgrade_tx, hrana_upgrade_rx) = mpsc::channel(8);
 
+    // the budgets of the clients are shared by the frontends, and outlive the restarts
+    let rate_limiter = Some(reloader.rate_limiter.clone());
+    // and so are the limits of the connections
+    let connection_limiter = config
+        .connection_limits
+        .is_enabled()
+        .then(|| Arc::new(ConnectionLimiter::new(config.connection_limits)));
+
     if config.http_addr.is_some() || config.hrana_addr.is_some() {
-        let db_factory = db_factory.clone();
+        let db_factory: Arc<dyn DbFactory<Db = _>> = Arc::new(
+            InstrumentedDbFactory::new(db_factory.clone(), Frontend::Ws)
+                .with_rate_limiter(rate_limiter.clone()),
+        );
         let auth = auth.clone();
         let idle_kicker = idle_shutdown_layer.clone().map(|isl| isl.into_kicker());
-        join_set.spawn(async move {
-            hrana::ws::serve(
-                db_factory,
-                auth,
-                idle_kicker,
-                hrana_accept_rx,
-                hrana_upgrade_rx,
-            )
-            .await
-            .context("Hrana server failed")
-        });
+        let ping_interval = config.hrana_ping_interval;
+        let connection_limiter = connection_limiter.clone();
+        system.register_graceful(
+            ShutdownPhase::Drain,
+            move |shutdown| async move {
+                hrana::ws::serve(
+                    db_factory,
+                    auth,
+                    idle_kicker,
+                    change_feed,
+                    ping_interval,
+                    connection_limiter,
+                    hrana_accept_rx,
+                    hrana_upgrade_rx,
+                    shutdown,
+                )
+                .await
+                .context("Hrana server failed")
+            },
+            "Hrana server",
+        );
     }
 
     if let Some(addr) = config.http_addr {
+        let db_factory: Arc<dyn DbFactory<Db = _>> = Arc::new(
+            InstrumentedDbFactory::new(db_factory, Frontend::Http)
+                .with_rate_limiter(rate_limiter.clone()),
+        );
         let hrana_http_srv = Arc::new(hrana::http::Server::new(
             db_factory.clone(),
             config.http_self_url.clone(),
         ));
-        join_set.spawn(http::run_http(
-            addr,
-            auth,
-            db_factory,
-            hrana_upgrade_tx,
-            hrana_http_srv.clone(),
-            config.enable_http_console,
-            idle_shutdown_layer,
-            stats.clone(),
-        ));
-        join_set.spawn(async move {
-            hrana_http_srv.run_expire().await;
-            Ok(())
+        let enable_http_console = config.enable_http_console;
+        let enable_kv_api = config.enable_kv_api;
+        let enable_metrics = config.enable_metrics;
+        let arrow_batch_size = config.arrow_batch_size;
+        let max_request_size = config.max_request_size;
+        let cors_layer = config
+            .cors_config()
+            .layer()
+            .context("invalid CORS configuration")?;
+        // a primary has committed all the frames it reports, a replica waits until it applies them
+        let applied_frame_no = (topology.role() == Role::Replica).then(|| frame_no.clone());
+        // and the cached responses are stale once a new frame is committed, or applied
+        let query_cache = config.query_cache.is_enabled().then(|| {
+            let checks = CacheChecks {
+                rate_limiter,
+                config_store: db_config_store.clone(),
+                session_config: reloader.session_config.clone(),
+            };
+            Arc::new(QueryCache::new(config.query_cache, frame_no.clone()).with_checks(checks))
         });
+        // the dumps are imported through the replication logger of the primary
+        let dumps = Arc::new(Dumps::new(
+            config.db_path.clone(),
+            dump_loader.clone(),
+            db_config_store.clone(),
+        ));
+        let readiness = Arc::new(Readiness::new(
+            topology.clone(),
+            frame_no,
+            config.max_replication_lag_frames,
+        ));
+        // the stats are only served by the admin listener if there is one
+        let public_stats = config.admin_addr.is_none().then(|| stats.clone());
+        let namespaces = namespaces.clone();
+        // the server stops accepting connections, and finishes the requests in flight
+        system.supervise(
+            "HTTP server",
+            ShutdownPhase::StopAccepting,
+            RestartPolicy::default(),
+            enclose! {(hrana_http_srv, tls) move |shutdown| {
+                http::run_http(
+                    addr,
+                    tls.clone(),
+                    connection_limiter.clone(),
+                    auth.clone(),
+                    db_factory.clone(),
+                    hrana_upgrade_tx.clone(),
+                    hrana_http_srv.clone(),
+                    enable_http_console,
+                    enable_kv_api,
+                    enable_metrics,
+                    idle_shutdown_layer.clone(),
+                    public_stats.clone(),
+                    topology.clone(),
+                    readiness.clone(),
+                    max_request_size,
+                    cors_layer.clone(),
+                    streamed_statements.clone(),
+                    consistency_tokens.clone(),
+                    applied_frame_no.clone(),
+                    query_cache.clone(),
+                    Some(dumps.clone()),
+                    namespaces.clone(),
+                    arrow_batch_size,
+                    shutdown,
+                )
+            }},
+        );
+        system.register(
+            async move {
+                hrana_http_srv.run_expire().await;
+                Ok(())
+            },
+            "Hrana HTTP streams expiration",
+        );
     }
 
     if let Some(addr) = config.hrana_addr {
-        join_set.spawn(async move {
-            hrana::ws::listen(addr, hrana_accept_tx)
-                .await
-                .context("Hrana listener failed")
-        });
+        system.register_in(
+            ShutdownPhase::StopAccepting,
+            async move {
+                hrana::ws::listen(addr, tls, hrana_accept_tx)
+                    .await
+                    .context("Hrana listener failed")
+            },
+            "Hrana listener",
+        );
     }
 
     if let Some(addr) = config.admin_addr {
-        join_set.spawn(admin_api::run_admin_api(addr, db_config_store));
+        let db_path = config.db_path.clone();
+        reloader.admin_auth.set(get_admin_auth(config)?);
+        let admin_auth = reloader.admin_auth.clone();
+        let stats = stats.clone();
+        let max_load_size = config.max_load_size;
+        let reloader = reloader.clone();
+        system.register_in(
+            ShutdownPhase::StopAccepting,
+            supervise("admin API", RestartPolicy::default(), move || {
+                admin_api::run_admin_api(
+                    addr,
+                    admin_auth.clone(),
+                    db_config_store.clone(),
+                    vacuum.clone(),
+                    logger.clone(),
+                    db_path.clone(),
+                    query_stats.clone(),
+                    auto_analyze.clone(),
+                    stats.clone(),
+                    standby.clone(),
+                    namespaces.clone(),
+                    replicas.clone(),
+                    backup.clone(),
+                    dump_loader.clone(),
+                    max_load_size,
+                    reloader.clone(),
+                )
+            }),
+            "admin API",
+        );
     }
 
     match &config.heartbeat_url {
@@ -222,16 +847,19 @@ async fn run_service<D: Database>(
             );
             let heartbeat_url = heartbeat_url.clone();
             let heartbeat_auth = config.heartbeat_auth.clone();
-            join_set.spawn(async move {
-                heartbeat::server_heartbeat(
-                    heartbeat_url,
-                    heartbeat_auth,
-                    heartbeat_period,
-                    stats.clone(),
-                )
-                .await;
-                Ok(())
-            });
+            system.register(
+                async move {
+                    heartbeat::server_heartbeat(
+                        heartbeat_url,
+                        heartbeat_auth,
+                        heartbeat_period,
+                        stats.clone(),
+                    )
+                    .await;
+                    Ok(())
+                },
+                "heartbeat",
+            );
         }
         None => {
             tracing::warn!("No server heartbeat configured")
@@ -241,7 +869,16 @@ async fn run_service<D: Database>(
     Ok(())
 }
 
-fn get_auth(config: &Config) -> anyhow::Result<Arc<Auth>> {
+/// Fetches the keys of the JWKS, if one is configured.
+async fn get_jwks(config: &Config) -> anyhow::Result<Option<Arc<Jwks>>> {
+    match config.auth_jwks_url.clone() {
+        Some(url) => Ok(Some(Arc::new(Jwks::fetch(url).await?))),
+        None => Ok(None),
+    }
+}
+
+/// Builds the authentication of the clients, with the keys of the JWKS fetched by [`get_jwks`].
+fn build_auth(config: &Config, jwks: Option<Arc<Jwks>>) -> anyhow::Result<Arc<Auth>> {
     let mut auth = Auth::default();
 
     if let Some(arg) = config.http_auth.as_deref() {
@@ -257,7 +894,14 @@ fn get_auth(config: &Config) -> anyhow::Result<Arc<Auth>> {
         tracing::info!("Using JWT-based authentication");
     }
 
-    auth.disabled = auth.http_basic.is_none() && auth.jwt_key.is_none();
+    if let Some(jwks) = jwks {
+        auth.jwks = Some(jwks);
+        tracing::info!("Using JWT-based authentication, with the keys of a JWKS");
+    }
+    auth.jwt_audience = config.auth_jwt_audience.clone();
+    auth.jwt_leeway = config.auth_jwt_leeway;
+
+    auth.disabled = auth.http_basic.is_none() && auth.jwt_key.is_none() && auth.jwks.is_none();
     if auth.disabled {
         tracing::warn!("No authentication specified, the server will not require authentication")
     }
@@ -265,19 +909,89 @@ fn get_auth(config: &Config) -> anyhow::Result<Arc<Auth>> {
     Ok(Arc::new(auth))
 }
 
+fn get_tls(config: &Config) -> anyhow::Result<Option<Arc<TlsConfig>>> {
+    let Some(cert) = config.http_tls_cert.clone() else {
+        return Ok(None);
+    };
+    let key = config
+        .http_tls_key
+        .clone()
+        .context("the TLS certificate requires a private key")?;
+    let tls = TlsConfig::load(cert, key, config.http_tls_ca_cert.clone())
+        .context("could not load the TLS certificate")?;
+    if config.http_tls_ca_cert.is_some() {
+        tracing::info!("Using TLS, with client certificates");
+    } else {
+        tracing::info!("Using TLS");
+    }
+
+    Ok(Some(Arc::new(tls)))
+}
+
+fn get_admin_auth(config: &Config) -> anyhow::Result<Arc<Auth>> {
+    let mut auth = Auth::default();
+    if let Some(arg) = config.admin_auth.as_deref() {
+        auth.http_basic =
+            auth::parse_http_basic_auth_arg(arg).context("invalid admin API authentication")?;
+    }
+
+    auth.disabled = auth.http_basic.is_none();
+    if auth.disabled {
+        tracing::warn!("No admin API authentication specified, anyone reaching the admin listener has full control of the server")
+    }
+
+    Ok(Arc::new(auth))
+}
+
+/// Shuts the server down gracefully on the first `SIGINT` or `SIGTERM`, exits on the second.
+#[cfg(unix)]
+async fn handle_signals() -> anyhow::Result<Never> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        let name = tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        };
+        on_signal(name);
+    }
+}
+
+/// Shuts the server down gracefully on the first `CTRL-C`, exits on the second.
+#[cfg(not(unix))]
+async fn handle_signals() -> anyhow::Result<Never> {
+    loop {
+        tokio::signal::ctrl_c().await?;
+        on_signal("CTRL-C");
+    }
+}
+
+fn on_signal(name: &str) {
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        tracing::warn!("received {name} again, exiting without waiting for the shutdown");
+        std::process::exit(1);
+    }
+    tracing::info!(
+        "received {name}, shutting down gracefully... This may take some time, send it again to exit now"
+    );
+    SHUTDOWN.notify_one();
+}
+
 /// nukes current DB and start anew
-async fn hard_reset(
-    config: &Config,
-    mut join_set: JoinSet<anyhow::Result<()>>,
-) -> anyhow::Result<()> {
-    tracing::error!("received hard-reset command: reseting replica.");
+async fn hard_reset(config: &Config, system: System, reason: &str) -> anyhow::Result<()> {
+    tracing::error!("received hard-reset command: reseting replica. Reason: {reason}");
+    HARD_RESET.record(reason)?;
 
     tracing::info!("Shutting down all services...");
-    join_set.shutdown().await;
+    system.shutdown().await;
     tracing::info!("All services have been shut down.");
 
-    let db_path = &config.db_path;
-    tokio::fs::remove_dir_all(db_path).await?;
+    let wiped = hard_reset::wipe(&config.db_path, reason).await?;
+    tracing::info!("hard reset removed: {}", wiped.join(", "));
+
+    HARD_RESET.discard_pending();
 
     Ok(())
 }
@@ -307,43 +1021,170 @@ fn configure_rpc(config: &Config) -> anyhow::Result<(Channel, tonic::transport::
 
 async fn start_replica(
     config: &Config,
-    join_set: &mut JoinSet<anyhow::Result<()>>,
+    reloader: &Arc<Reloader>,
+    system: &mut System,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
     db_config_store: Arc<DatabaseConfigStore>,
+    snapshot_callback: SnapshotCallback,
 ) -> anyhow::Result<()> {
+    if config.enable_namespaces {
+        tracing::warn!("namespaces are only served by the primary, ignoring `--enable-namespaces`");
+    }
     let (channel, uri) = configure_rpc(config)?;
-    let replicator = Replicator::new(
-        config.db_path.clone(),
-        channel.clone(),
-        uri.clone(),
-        config.allow_replica_overwrite,
-    )?;
-    let applied_frame_no_receiver = replicator.current_frame_no_notifier.clone();
+    let topology = Arc::new(Topology::replica(&config.db_path)?);
+    let database_id = if config.standby || config.consistency_token_key.is_some() {
+        Some(
+            standby::upstream_database_id(
+                &config.db_path,
+                channel.clone(),
+                uri.clone(),
+                config.reconnect_policy(),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+    let standby = if let (true, Some(database_id)) = (config.standby, database_id) {
+        let standby = Arc::new(Standby::open(
+            &config.db_path,
+            database_id,
+            config.max_log_size,
+            config.max_log_duration.map(Duration::from_secs_f32),
+            snapshot_callback,
+        )?);
+        system.register(
+            supervise(
+                "periodic compactions",
+                RestartPolicy::default(),
+                enclose! {(standby) move || run_periodic_compactions(standby.logger().clone())},
+            ),
+            "periodic compactions",
+        );
+        if let Some(ref addr) = config.rpc_server_addr {
+            system.register_in(
+                ShutdownPhase::StopAccepting,
+                run_standby_rpc_server(
+                    *addr,
+                    config.rpc_server_tls,
+                    config.rpc_server_cert.clone(),
+                    config.rpc_server_key.clone(),
+                    config.rpc_server_ca_cert.clone(),
+                    standby.clone(),
+                    idle_shutdown_layer.clone(),
+                    Arc::new(Replicas::new(
+                        config.replica_session_ttl,
+                        config.max_replica_sessions,
+                    )),
+                    config.batch_limits(),
+                    config.replication_compression,
+                ),
+                "RPC server",
+            );
+        }
+        Some(standby)
+    } else {
+        None
+    };
+    let (applied_frame_no_receiver, change_feed) = match config.replicate_tables {
+        Some(ref tables) => {
+            let replicator = LogicalReplicator::new(
+                config.db_path.clone(),
+                channel.clone(),
+                uri.clone(),
+                TableFilter::new(tables),
+                topology.clone(),
+                config.reconnect_policy(),
+            )?;
+            let change_feed = replicator.change_feed();
+            system.register(replicator.run(), "logical replicator");
+            // Logical offsets can't be related to the primary frame numbers, so reads on a logical
+            // replica never wait for the replica to catch up with the connection's writes.
+            let (_, receiver) = watch::channel(FrameNo::MAX);
+            (receiver, Some(change_feed))
+        }
+        None => {
+            let replicator = Replicator::new(
+                config.db_path.clone(),
+                channel.clone(),
+                uri.clone(),
+                config.allow_replica_overwrite,
+                topology.clone(),
+                standby.clone(),
+                config.reconnect_policy(),
+            )?;
+            let applied_frame_no_receiver = replicator.current_frame_no_notifier.clone();
+            system.register(replicator.run(), "replicator");
+            // physical replicas receive frames, not row-level changes
+            (applied_frame_no_receiver, None)
+        }
+    };
 
-    join_set.spawn(replicator.run());
+    let consistency_tokens = match (&config.consistency_token_key, database_id) {
+        (Some(key), Some(database_id)) => Some(Arc::new(ConsistencyTokens::new(
+            key.as_bytes(),
+            database_id,
+            Some(applied_frame_no_receiver.clone()),
+        ))),
+        _ => None,
+    };
 
     let valid_extensions = validate_extensions(config.extensions_path.clone())?;
 
+    // replicas can't write to the database, their statistics are not persisted
+    let query_stats = config
+        .stats_collection
+        .then(|| Arc::new(QueryStats::new(config.stats_sample_rate, None)));
+
+    // each channel is a connection of its own, over which the sessions of the connections pinned to
+    // it are multiplexed
+    let write_proxy_channels = (0..config.write_proxy_channels.max(1))
+        .map(|_| configure_rpc(config).map(|(channel, _)| channel))
+        .collect::<anyhow::Result<Vec<_>>>()?;
     let factory = WriteProxyDbFactory::new(
         config.db_path.clone(),
         valid_extensions,
-        channel,
+        write_proxy_channels,
         uri,
         stats.clone(),
         db_config_store.clone(),
-        applied_frame_no_receiver,
+        applied_frame_no_receiver.clone(),
         config.max_response_size,
+        query_stats.clone(),
+        reloader.session_config.clone(),
+        config.read_only_replica,
+        config.reconnect_policy(),
+        // the logical replicas don't report which frames they applied
+        config.wait_for_primary && config.replicate_tables.is_none(),
+        config.write_proxy_limits(),
+        config.proxy_identity_key(),
+        config.local_read_transactions,
     )
     .throttled(MAX_CONCCURENT_DBS, Some(DB_CREATE_TIMEOUT));
 
     run_service(
         Arc::new(factory),
         config,
-        join_set,
+        reloader,
+        system,
         idle_shutdown_layer,
         stats,
         db_config_store,
+        topology,
+        applied_frame_no_receiver,
+        None,
+        None,
+        query_stats,
+        None,
+        change_feed,
+        standby,
+        None,
+        None,
+        consistency_tokens,
+        None,
+        None,
+        None,
     )
     .await?;
 
@@ -399,30 +1240,132 @@ fn validate_extensions(extensions_path: Option<PathBuf>) -> anyhow::Result<Vec<P
     Ok(valid_extensions)
 }
 
-pub async fn init_bottomless_replicator(
-    path: impl AsRef<std::path::Path>,
+/// A bottomless replicator, once the local database was restored from its backup.
+pub struct BottomlessRestore {
+    replicator: bottomless::replicator::Replicator,
+    action: bottomless::replicator::RestoreAction,
+    /// Set if the database file was replaced by the content of the backup.
+    pub restored: bool,
+}
+
+/// Restores the database file at `path` from the bottomless backup, if it is missing or older than
+/// the newest generation of the backup, or than `generation` if given. This must run before the
+/// replication log and the database are opened, since the database file may be replaced.
+///
+/// If the object storage can't be reached, the node starts without bottomless replication when
+/// it has a database of its own. An empty node refuses to start: it would start blank, while its
+/// content may be in the backup.
+pub async fn restore_bottomless(
+    path: &Path,
     options: bottomless::replicator::Options,
-) -> anyhow::Result<bottomless::replicator::Replicator> {
+    generation: Option<uuid::Uuid>,
+) -> anyhow::Result<Option<BottomlessRestore>> {
+    fn file_version(path: &Path) -> Option<(u64, Option<std::time::SystemTime>)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.len(), metadata.modified().ok()))
+    }
+
     tracing::debug!("Initializing bottomless replication");
-    let path = path
-        .as_ref()
+    let path_str = path
         .to_str()
         .ok_or_else(|| anyhow::anyhow!("Invalid db path"))?
         .to_owned();
-    let mut replicator = bottomless::replicator::Replicator::with_options(path, options).await?;
+    let local = file_version(path);
+    let has_local_db = local.map_or(false, |(len, _)| len > 0);
+    let backup_path = PathBuf::from(format!("{path_str}.bottomless.backup"));
+    let _ = std::fs::remove_file(&backup_path);
+
+    let start = std::time::Instant::now();
+    let res = async {
+        let mut replicator =
+            bottomless::replicator::Replicator::with_options(path_str, options).await?;
+        let action = replicator.restore(generation, None).await?;
+        anyhow::Ok((replicator, action))
+    }
+    .await;
+    let (replicator, action) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            // a restore that failed half-way leaves the previous database file aside
+            if backup_path.exists() {
+                std::fs::rename(&backup_path, path)?;
+            }
+            if !has_local_db {
+                return Err(e.context(
+                    "could not restore the database from bottomless replication: the object storage is unreachable",
+                ));
+            }
+            tracing::warn!(
+                "bottomless replication is disabled: could not reach the object storage: {e:#}"
+            );
+            return Ok(None);
+        }
+    };
+
+    let restored = file_version(path) != local;
+    if restored {
+        tracing::info!(
+            "database restored from bottomless replication in {:.1?} ({} bytes)",
+            start.elapsed(),
+            file_version(path).map_or(0, |(len, _)| len),
+        );
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || integrity_check(&path))
+            .await?
+            .context("the database restored from bottomless replication is corrupted")?;
+    }
+
+    Ok(Some(BottomlessRestore {
+        replicator,
+        action,
+        restored,
+    }))
+}
+
+/// Runs `PRAGMA integrity_check` on the database at `data_path`.
+fn integrity_check(data_path: &Path) -> anyhow::Result<()> {
+    let conn = rusqlite::Connection::open_with_flags(
+        data_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let problems = conn
+        .prepare("PRAGMA integrity_check")?
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    anyhow::ensure!(
+        problems == ["ok"],
+        "integrity check failed: {}",
+        problems.join("; ")
+    );
 
-    match replicator.restore(None, None).await? {
+    Ok(())
+}
+
+/// Starts backing up the database after it was restored with [`restore_bottomless`]. If the
+/// database file was changed since, by the recovery of the replication log, the backup starts
+/// over with a new generation.
+pub async fn init_bottomless_replicator(
+    restore: BottomlessRestore,
+    db_changed: bool,
+) -> anyhow::Result<bottomless::replicator::Replicator> {
+    let BottomlessRestore {
+        mut replicator,
+        action,
+        ..
+    } = restore;
+    match action {
         bottomless::replicator::RestoreAction::None => (),
-        bottomless::replicator::RestoreAction::SnapshotMainDbFile => {
+        bottomless::replicator::RestoreAction::ReuseGeneration(gen) if !db_changed => {
+            replicator.set_generation(gen);
+        }
+        bottomless::replicator::RestoreAction::SnapshotMainDbFile
+        | bottomless::replicator::RestoreAction::ReuseGeneration(_) => {
             replicator.new_generation();
             replicator.snapshot_main_db_file().await?;
             // Restoration process only leaves the local WAL file if it was
             // detected to be newer than its remote counterpart.
             replicator.maybe_replicate_wal().await?
         }
-        bottomless::replicator::RestoreAction::ReuseGeneration(gen) => {
-            replicator.set_generation(gen);
-        }
     }
 
     Ok(replicator)
@@ -430,27 +1373,72 @@ pub async fn init_bottomless_replicator(
 
 async fn start_primary(
     config: &Config,
-    join_set: &mut JoinSet<anyhow::Result<()>>,
+    reloader: &Arc<Reloader>,
+    system: &mut System,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
     db_config_store: Arc<DatabaseConfigStore>,
     db_is_dirty: bool,
     snapshot_callback: SnapshotCallback,
 ) -> anyhow::Result<()> {
-    let is_fresh_db = check_fresh_db(&config.db_path);
-    let logger = Arc::new(ReplicationLogger::open(
+    let vacuumed = vacuum::finish_full_vacuum(&config.db_path, &db_config_store, &stats)?;
+    let bottomless_restore = match &config.bottomless_replication {
+        Some(options) => {
+            restore_bottomless(
+                &config.db_path.join("data"),
+                options.clone(),
+                config.bottomless_restore_generation,
+            )
+            .await?
+        }
+        None => None,
+    };
+    let restored = bottomless_restore.as_ref().map_or(false, |r| r.restored);
+    let is_fresh_db = check_fresh_db(&config.db_path) && !restored;
+    // the database file to load becomes the database of a fresh primary
+    let placed = match config.load_from {
+        Some(ref path) if is_fresh_db && !config.db_path.join("data").exists() => {
+            tokio::task::block_in_place(|| {
+                StagedDatabase::copy_from(path, &config.db_path)?.place(&config.db_path)
+            })?;
+            tracing::info!("database file `{}` loaded", path.display());
+            true
+        }
+        _ => false,
+    };
+    let is_fresh_db = is_fresh_db && !placed;
+    let mut logger = ReplicationLogger::open(
         &config.db_path,
         config.max_log_size,
         config.max_log_duration.map(Duration::from_secs_f32),
-        db_is_dirty,
+        // the replication log doesn't match the restored or loaded database file, it is
+        // recovered with a new database id, so that the replicas reset and sync from scratch
+        db_is_dirty || restored || placed,
         snapshot_callback,
-    )?);
-
-    join_set.spawn(run_periodic_compactions(logger.clone()));
+    )?;
+    if let Some(promotion) = Promotion::read(&config.db_path)? {
+        promotion.resume(&mut logger)?;
+    }
+    let logger = Arc::new(logger);
 
-    let bottomless_replicator = if let Some(options) = &config.bottomless_replication {
+    if vacuumed {
+        // the database file was replaced behind the replication log's back
+        tokio::task::block_in_place(|| logger.log_database_image())?;
+    }
+    logger.check_database()?;
+
+    system.register(
+        supervise(
+            "periodic compactions",
+            RestartPolicy::default(),
+            enclose! {(logger) move || run_periodic_compactions(logger.clone())},
+        ),
+        "periodic compactions",
+    );
+
+    let bottomless_replicator = if let Some(restore) = bottomless_restore {
         Some(Arc::new(std::sync::Mutex::new(
-            init_bottomless_replicator(config.db_path.join("data"), options.clone()).await?,
+            init_bottomless_replicator(restore, logger.recovered()).await?,
         )))
     } else {
         None
@@ -469,9 +1457,138 @@ async fn start_primary(
         }
         dump_loader.load_dump(path.into()).await?;
     }
+    match config.load_from {
+        Some(ref path) if !placed => {
+            let staged =
+                tokio::task::block_in_place(|| StagedDatabase::copy_from(path, &config.db_path))?;
+            match dump_loader.load_database(staged).await {
+                Err(e @ LoadError::Conflict(_)) => anyhow::bail!(
+                    "cannot load `{}` into the existing database: {e}.\nIf the database file was already loaded, remove `--load-from`",
+                    path.display()
+                ),
+                res => res?,
+            };
+        }
+        _ => (),
+    }
+
+    let with_conn: Arc<WithConnection> = Arc::new({
+        let db_path = config.db_path.clone();
+        let logger = logger.clone();
+        let bottomless_replicator = bottomless_replicator.clone();
+        move |f: &mut dyn FnMut(&rusqlite::Connection) -> anyhow::Result<()>| {
+            let mut ctx =
+                ReplicationLoggerHookCtx::new(logger.clone(), bottomless_replicator.clone());
+            let conn = open_db(&db_path, &REPLICATION_METHODS, &mut ctx, None)?;
+            f(&conn)
+        }
+    });
+    if config.incremental_vacuum {
+        tokio::task::block_in_place(|| vacuum::enable_incremental_vacuum(&*with_conn))?;
+    }
+    let backup = bottomless_replicator
+        .as_ref()
+        .map(|replicator| Arc::new(Backup::new(&replicator.lock().unwrap(), with_conn.clone())));
+    if config.foreign_keys && !is_fresh_db {
+        tokio::task::block_in_place(|| {
+            with_conn(&mut |conn| {
+                constraint::warn_foreign_key_violations(conn);
+                Ok(())
+            })
+        })?;
+    }
+    let vacuum = Arc::new(Vacuum::new(
+        config.db_path.clone(),
+        config.incremental_vacuum,
+        with_conn.clone(),
+        db_config_store.clone(),
+        stats.clone(),
+    ));
+    let streamed_statements = Arc::new(StreamedStatements::new(
+        with_conn.clone(),
+        db_config_store.clone(),
+        config.db_path.clone(),
+        config.max_request_size,
+        reloader.session_config.clone(),
+    ));
+
+    let auto_analyze = Arc::new(AutoAnalyze::new(
+        config.auto_analyze_config(),
+        with_conn.clone(),
+    ));
+    if auto_analyze.is_enabled() {
+        tokio::task::block_in_place(|| auto_analyze.load())?;
+        system.supervise(
+            "auto analyze",
+            ShutdownPhase::StopBackground,
+            RestartPolicy::default(),
+            enclose! {(auto_analyze) move |signal| {
+                run_periodic_auto_analyze(auto_analyze.clone(), signal)
+            }},
+        );
+    }
+
+    // the checkpoints can be enabled by a reload of the configuration
+    let checkpointer = Arc::new(Checkpointer::new(
+        reloader.checkpoint_config.clone(),
+        config.db_path.clone(),
+        with_conn.clone(),
+    ));
+    system.supervise(
+        "periodic checkpoints",
+        ShutdownPhase::StopBackground,
+        RestartPolicy::default(),
+        enclose! {(checkpointer) move |signal| {
+            run_periodic_checkpoints(checkpointer.clone(), signal)
+        }},
+    );
+
+    let query_stats = if config.stats_collection {
+        let query_stats = Arc::new(QueryStats::new(config.stats_sample_rate, Some(with_conn)));
+        tokio::task::block_in_place(|| query_stats.load())?;
+        system.supervise(
+            "query stats flush",
+            ShutdownPhase::StopBackground,
+            RestartPolicy::default(),
+            enclose! {(query_stats) move |signal| {
+                run_periodic_query_stats_flush(query_stats.clone(), signal)
+            }},
+        );
+        Some(query_stats)
+    } else {
+        None
+    };
 
     let valid_extensions = validate_extensions(config.extensions_path.clone())?;
 
+    let change_log = if config.enable_logical_replication {
+        Some(Arc::new(ChangeLog::open(&config.db_path)?))
+    } else {
+        None
+    };
+    let change_feed = change_log.as_ref().map(|change_log| change_log.feed());
+
+    let namespaces = if config.enable_namespaces {
+        let namespaces = NamespaceStore::open(
+            &config.db_path,
+            make_namespace(config, reloader, &stats, &valid_extensions, db_is_dirty),
+        )
+        .await
+        .context("Could not open the namespaces")?;
+        let namespaces = Arc::new(namespaces);
+        system.register(
+            supervise(
+                "namespace compactions",
+                RestartPolicy::default(),
+                enclose! {(namespaces) move || run_periodic_namespace_compactions(namespaces.clone())},
+            ),
+            "namespace compactions",
+        );
+        Some(namespaces)
+    } else {
+        None
+    };
+
     let db_factory: Arc<_> = LibSqlDbFactory::new(
         config.db_path.clone(),
         &REPLICATION_METHODS,
@@ -484,37 +1601,111 @@ async fn start_primary(
         db_config_store.clone(),
         valid_extensions,
         config.max_response_size,
+        change_log.clone(),
+        query_stats.clone(),
+        auto_analyze.is_enabled().then(|| auto_analyze.clone()),
+        Some(logger.new_frame_notifier.subscribe()),
+        reloader.session_config.clone(),
+        config.group_commit_window,
     )
     .await?
+    .with_pool(config.db_pool)
+    .await?
     .throttled(MAX_CONCCURENT_DBS, Some(DB_CREATE_TIMEOUT))
     .into();
 
+    let replicas = Arc::new(Replicas::new(
+        config.replica_session_ttl,
+        config.max_replica_sessions,
+    ));
     if let Some(ref addr) = config.rpc_server_addr {
-        join_set.spawn(run_rpc_server(
-            *addr,
-            config.rpc_server_tls,
-            config.rpc_server_cert.clone(),
-            config.rpc_server_key.clone(),
-            config.rpc_server_ca_cert.clone(),
-            db_factory.clone(),
-            logger.clone(),
-            idle_shutdown_layer.clone(),
-        ));
+        system.register_in(
+            ShutdownPhase::StopAccepting,
+            run_rpc_server(
+                *addr,
+                config.rpc_server_tls,
+                config.rpc_server_cert.clone(),
+                config.rpc_server_key.clone(),
+                config.rpc_server_ca_cert.clone(),
+                db_factory.clone(),
+                logger.clone(),
+                change_log,
+                idle_shutdown_layer.clone(),
+                config.advertise_addrs.clone(),
+                namespaces.clone(),
+                replicas.clone(),
+                config.batch_limits(),
+                config.replication_compression,
+                config.proxy_identity_key(),
+            ),
+            "RPC server",
+        );
     }
 
+    let retention = config.snapshot_retention();
+    if retention.is_enabled() {
+        system.register(
+            supervise(
+                "snapshot cleanup",
+                RestartPolicy::default(),
+                enclose! {(logger, namespaces, replicas) move || {
+                    run_periodic_snapshot_cleanup(
+                        logger.clone(),
+                        namespaces.clone(),
+                        replicas.clone(),
+                        retention,
+                    )
+                }},
+            ),
+            "snapshot cleanup",
+        );
+    }
+
+    let topology = Arc::new(Topology::primary(
+        config.advertise_addrs.clone(),
+        logger.generation.id.to_string(),
+    ));
+    let consistency_tokens = match config.consistency_token_key {
+        Some(ref key) => Some(Arc::new(ConsistencyTokens::new(
+            key.as_bytes(),
+            logger.database_id()?,
+            None,
+        ))),
+        None => None,
+    };
+
     if let Some(ref addr) = config.http_replication_addr {
         // FIXME: let's bring it back once I figure out how Axum works
         // let auth = get_auth(config)?;
-        join_set.spawn(replication::http::run(*addr, logger));
+        system.register_in(
+            ShutdownPhase::StopAccepting,
+            replication::http::run(*addr, logger.clone()),
+            "HTTP replication server",
+        );
     }
 
     run_service(
         db_factory,
         config,
-        join_set,
+        reloader,
+        system,
         idle_shutdown_layer,
         stats,
         db_config_store,
+        topology,
+        logger.new_frame_notifier.subscribe(),
+        Some(vacuum),
+        Some(logger),
+        query_stats,
+        Some(auto_analyze),
+        change_feed,
+        None,
+        Some(streamed_statements),
+        Some(Arc::new(dump_loader)),
+        consistency_tokens,
+        namespaces,
+        Some(replicas),
+        backup,
     )
     .await?;
 
@@ -539,6 +1730,206 @@ async fn run_periodic_compactions(logger: Arc<ReplicationLogger>) -> anyhow::Res
     }
 }
 
+async fn run_periodic_snapshot_cleanup(
+    logger: Arc<ReplicationLogger>,
+    namespaces: Option<Arc<NamespaceStore>>,
+    replicas: Arc<Replicas>,
+    retention: SnapshotRetention,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(SNAPSHOT_CLEANUP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let handle = tokio::task::spawn_blocking(
+            enclose! {(logger, namespaces, replicas) move || {
+                // the snapshots are kept for the replicas that may still be transferring them
+                let pinned = |logger: &Arc<ReplicationLogger>| replicas.oldest_needed_frame_no(logger);
+                logger.retain_snapshots(retention, pinned(&logger))?;
+                if let Some(namespaces) = namespaces {
+                    namespaces.retain_snapshots(retention, pinned)?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }},
+        );
+        handle
+            .await
+            .expect("Snapshot cleanup task crashed")
+            .context("Snapshot cleanup failed")?;
+    }
+}
+
+/// Opens the namespaces like the default database, without the features that are specific to it:
+/// bottomless replication, logical replication, query statistics and automatic analysis.
+fn make_namespace(
+    config: &Config,
+    reloader: &Reloader,
+    stats: &Stats,
+    extensions: &[PathBuf],
+    db_is_dirty: bool,
+) -> MakeNamespace {
+    let stats = stats.clone();
+    let extensions = extensions.to_vec();
+    let max_log_size = config.max_log_size;
+    let max_log_duration = config.max_log_duration.map(Duration::from_secs_f32);
+    let max_response_size = config.max_response_size;
+    let session_config = reloader.session_config.clone();
+    let group_commit_window = config.group_commit_window;
+    let db_pool = config.db_pool;
+    Box::new(move |path: PathBuf| {
+        let stats = stats.clone();
+        let extensions = extensions.clone();
+        let session_config = session_config.clone();
+        async move {
+            let logger = ReplicationLogger::open(
+                &path,
+                max_log_size,
+                max_log_duration,
+                db_is_dirty,
+                Box::new(|_: &Path| Ok(())),
+            )?;
+            let logger = Arc::new(logger);
+            let db_config_store = Arc::new(DatabaseConfigStore::load(&path)?);
+            let db_factory = LibSqlDbFactory::new(
+                path,
+                &REPLICATION_METHODS,
+                {
+                    let logger = logger.clone();
+                    move || ReplicationLoggerHookCtx::new(logger.clone(), None)
+                },
+                stats,
+                db_config_store.clone(),
+                extensions,
+                max_response_size,
+                None,
+                None,
+                None,
+                Some(logger.new_frame_notifier.subscribe()),
+                session_config,
+                group_commit_window,
+            )
+            .await?
+            .with_pool(db_pool)
+            .await?
+            .throttled(MAX_CONCCURENT_DBS, Some(DB_CREATE_TIMEOUT));
+
+            Ok::<_, anyhow::Error>(Namespace {
+                db_factory: Arc::new(db_factory),
+                logger,
+                config_store: db_config_store,
+                quota: Default::default(),
+            })
+        }
+        .boxed()
+    })
+}
+
+async fn run_periodic_namespace_compactions(namespaces: Arc<NamespaceStore>) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_millis(1000));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let handle = tokio::task::spawn_blocking(enclose! {(namespaces) move || {
+            namespaces.maybe_compact()
+        }});
+        handle
+            .await
+            .expect("Compaction task crashed")
+            .context("Compaction failed")?;
+    }
+}
+
+async fn run_periodic_query_stats_flush(
+    query_stats: Arc<QueryStats>,
+    signal: ShutdownSignal,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(QUERY_STATS_FLUSH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = signal.reached(ShutdownPhase::StopBackground) => true,
+        };
+        let handle = tokio::task::spawn_blocking(enclose! {(query_stats) move || {
+            query_stats.flush()
+        }});
+        // the statistics are kept in memory, and the flush is retried on the next tick
+        if let Err(e) = handle.await.expect("Query stats flush task crashed") {
+            tracing::warn!("failed to flush query stats: {e}");
+        }
+        // the statistics gathered since the last tick are flushed before shutting down
+        if stopping {
+            return Ok(());
+        }
+    }
+}
+
+async fn run_periodic_auto_analyze(
+    auto_analyze: Arc<AutoAnalyze>,
+    signal: ShutdownSignal,
+) -> anyhow::Result<()> {
+    let mut check_interval = tokio::time::interval(AUTO_ANALYZE_CHECK_INTERVAL);
+    check_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut flush_interval = tokio::time::interval(AUTO_ANALYZE_FLUSH_INTERVAL);
+    flush_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let handle = tokio::select! {
+            _ = check_interval.tick() => {
+                if auto_analyze.due_tables().is_empty() {
+                    continue;
+                }
+                tokio::task::spawn_blocking(enclose! {(auto_analyze) move || {
+                    auto_analyze.run_due().map(|_| ())
+                }})
+            }
+            _ = flush_interval.tick() => {
+                tokio::task::spawn_blocking(enclose! {(auto_analyze) move || {
+                    auto_analyze.flush()
+                }})
+            }
+            _ = signal.reached(ShutdownPhase::StopBackground) => {
+                // the counters gathered since the last flush are persisted before shutting down
+                let handle = tokio::task::spawn_blocking(enclose! {(auto_analyze) move || {
+                    auto_analyze.flush()
+                }});
+                if let Err(e) = handle.await.expect("Auto analyze task crashed") {
+                    tracing::warn!("failed to flush analyze stats: {e}");
+                }
+                return Ok(());
+            }
+        };
+        // the tables stay due, and are analyzed on the next idle window
+        if let Err(e) = handle.await.expect("Auto analyze task crashed") {
+            tracing::warn!("automatic analysis failed: {e}");
+        }
+    }
+}
+
+async fn run_periodic_checkpoints(
+    checkpointer: Arc<Checkpointer>,
+    signal: ShutdownSignal,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(CHECKPOINT_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // a checkpoint blocked by readers is still due, and retried on the next tick
+                tokio::task::spawn_blocking(enclose! {(checkpointer) move || {
+                    checkpointer.run_if_due()
+                }})
+                .await
+                .expect("Checkpoint task crashed");
+            }
+            _ = signal.reached(ShutdownPhase::StopBackground) => return Ok(()),
+        }
+    }
+}
+
 // Periodically check the storage used by the database and save it in the Stats structure.
 // TODO: Once we have a separate fiber that does WAL checkpoints, running this routine
 // right after checkpointing is exactly where it should be done.
@@ -605,8 +1996,46 @@ fn init_sentinel_file(path: &Path) -> anyhow::Result<bool> {
     Ok(false)
 }
 
+/// Prints the format version and header fields of the replication log and snapshots of the
+/// database at `db_path`.
+pub fn check_log(db_path: &Path) -> anyhow::Result<()> {
+    replication::primary::logger::check_log(db_path, &mut std::io::stdout())
+}
+
 pub async fn run_server(config: Config) -> anyhow::Result<()> {
-    tracing::trace!("Backend: {:?}", config.backend);
+    run_server_with_tasks(config, |_| ()).await
+}
+
+/// Runs the server, which reads its configuration again with `loader` on `SIGHUP`, and when
+/// requested through the admin API, see [`reload`].
+pub async fn run_server_with_reload(config: Config, loader: ConfigLoader) -> anyhow::Result<()> {
+    run_services(config, Some(loader), |_| ()).await
+}
+
+/// Runs the server, with the tasks registered by `register_tasks` supervised alongside the
+/// services of `sqld`. `register_tasks` is called every time the services are started: on startup,
+/// and after every restart or hard reset.
+pub async fn run_server_with_tasks(
+    config: Config,
+    register_tasks: impl FnMut(&mut System),
+) -> anyhow::Result<()> {
+    run_services(config, None, register_tasks).await
+}
+
+async fn run_services(
+    config: Config,
+    loader: Option<ConfigLoader>,
+    mut register_tasks: impl FnMut(&mut System),
+) -> anyhow::Result<()> {
+    config.validate()?;
+    tracing::info!("effective configuration: {:?}", config.redacted());
+
+    utils::panic::install_panic_hook();
+    query_analysis::set_parse_limits(query_analysis::ParseLimits {
+        max_sql_length: config.max_sql_length,
+        ..Default::default()
+    });
+    database::attach::set_attachable_databases(&config.db_path, &config.attachable_databases)?;
 
     if config.bottomless_replication.is_some() {
         bottomless::static_init::register_bottomless_methods();
@@ -618,6 +2047,10 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
             sqld_libsql_bindings::ffi::sqlite3_soft_heap_limit64(soft_limit_mb as i64 * 1024 * 1024)
         };
     }
+    if let Some(total_cache_size_mb) = config.total_cache_size_mb {
+        tracing::info!("Sharing {total_cache_size_mb}MiB of page cache between the connections");
+        CACHE_BUDGET.set_total(Some(total_cache_size_mb as u64 * 1024 * 1024));
+    }
     if let Some(hard_limit_mb) = config.hard_heap_limit_mb {
         tracing::warn!("Setting hard heap limit to {hard_limit_mb}MiB");
         unsafe {
@@ -625,33 +2058,51 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
         };
     }
 
+    if !config.db_path.exists() {
+        std::fs::create_dir_all(&config.db_path)?;
+    }
+    HARD_RESET
+        .init(&config.db_path, config.hard_reset_config())
+        .context("Could not load the history of hard resets")?;
+
+    // the signals are handled outside of the system, so that they are not missed while the
+    // services are reset or restarted. The handler is aborted when the set is dropped.
+    let mut signal_handler = tokio::task::JoinSet::new();
+    signal_handler.spawn(async {
+        if let Err(e) = handle_signals().await {
+            tracing::error!("failed to listen to the termination signals: {e}");
+        }
+    });
+
+    // the reloaded settings are kept when the services are started again
+    let reloader = Arc::new(Reloader::new(config, loader));
+    if reloader.can_reload() {
+        signal_handler.spawn(enclose! {(reloader) async move {
+            if let Err(e) = reload::watch(reloader).await {
+                tracing::error!("failed to listen to the reload signal: {e}");
+            }
+        }});
+    }
+
     loop {
+        let config = reloader.config();
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            // a signal was received while the services were reset or restarted
+            tracing::info!("shutdown requested, not starting the services again");
+            return Ok(());
+        }
         if !config.db_path.exists() {
             std::fs::create_dir_all(&config.db_path)?;
         }
-        let mut join_set = JoinSet::new();
+        let mut system = System::new().with_grace_period(config.shutdown_grace_period);
 
         let (shutdown_sender, mut shutdown_receiver) = tokio::sync::mpsc::channel::<()>(1);
 
-        join_set.spawn({
-            let shutdown_sender = shutdown_sender.clone();
-            async move {
-                loop {
-                    tokio::signal::ctrl_c()
-                        .await
-                        .expect("failed to listen to CTRL-C");
-                    tracing::info!(
-                        "received CTRL-C, shutting down gracefully... This may take some time"
-                    );
-                    shutdown_sender
-                        .send(())
-                        .await
-                        .expect("failed to shutdown gracefully");
-                }
-            }
-        });
-
-        let db_is_dirty = init_sentinel_file(&config.db_path)?;
+        let mut db_is_dirty = init_sentinel_file(&config.db_path)?;
+        if config.force_recover {
+            tracing::info!("forcing the recovery of the replication log from the database file");
+            db_is_dirty = true;
+        }
 
         let snapshot_exec = config.snapshot_exec.clone();
         let snapshot_callback: SnapshotCallback = Box::new(move |snapshot_file| {
@@ -679,21 +2130,26 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
             DatabaseConfigStore::load(&config.db_path).context("Could not load database config")?,
         );
 
+        // a promoted standby runs as a primary
+        let promoted = config.standby && Promotion::read(&config.db_path)?.is_some();
         match config.writer_rpc_addr {
-            Some(_) => {
+            Some(_) if !promoted => {
                 start_replica(
                     &config,
-                    &mut join_set,
+                    &reloader,
+                    &mut system,
                     idle_shutdown_layer,
                     stats.clone(),
                     db_config_store,
+                    snapshot_callback,
                 )
                 .await?
             }
-            None => {
+            _ => {
                 start_primary(
                     &config,
-                    &mut join_set,
+                    &reloader,
+                    &mut system,
                     idle_shutdown_layer,
                     stats.clone(),
                     db_config_store,
@@ -705,26 +2161,67 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
         }
 
         if config.heartbeat_url.is_some() {
-            join_set.spawn(run_storage_monitor(config.db_path.clone(), stats));
+            system.register(
+                run_storage_monitor(config.db_path.clone(), stats),
+                "storage monitor",
+            );
         }
 
+        register_tasks(&mut system);
+
         let reset = HARD_RESET.clone();
+        let restart = RESTART.clone();
+        // a reset that waits for the minimum interval between resets
+        let mut delayed_reset: Option<(tokio::time::Instant, String)> = None;
         loop {
-            tokio::select! {
-                _ = reset.notified() => {
-                    hard_reset(&config, join_set).await?;
+            let reason = tokio::select! {
+                reason = reset.requested() => reason,
+                _ = tokio::time::sleep_until(
+                    delayed_reset.as_ref().map_or_else(tokio::time::Instant::now, |(at, _)| *at)
+                ), if delayed_reset.is_some() => {
+                    delayed_reset.take().unwrap().1
+                },
+                _ = restart.notified() => {
+                    tracing::info!("restarting all services");
+                    system.shutdown().await;
+                    // clean shutdown, remove sentinel file
+                    std::fs::remove_file(sentinel_file_path(&config.db_path))?;
                     break;
                 },
+                _ = SHUTDOWN.notified() => {
+                    system.shutdown().await;
+                    // clean shutdown, remove sentinel file
+                    std::fs::remove_file(sentinel_file_path(&config.db_path))?;
+                    return Ok(())
+                }
                 _ = shutdown_receiver.recv() => {
-                    join_set.shutdown().await;
+                    system.shutdown().await;
                     // clean shutdown, remove sentinel file
                     std::fs::remove_file(sentinel_file_path(&config.db_path))?;
                     return Ok(())
                 }
-                Some(res) = join_set.join_next() => {
-                    res??;
+                Some(exit) = system.next_exit() => {
+                    match exit.result {
+                        Ok(()) => tracing::info!("{} exited", exit.name),
+                        Err(e) => return Err(e.context(format!("{} failed", exit.name))),
+                    }
+                    continue;
                 },
                 else => return Ok(()),
+            };
+
+            match reset.admit(&reason)? {
+                Admission::Now => {
+                    hard_reset(&config, system, &reason).await?;
+                    break;
+                }
+                Admission::After(delay) => {
+                    tracing::warn!("delaying hard reset by {delay:?} to throttle resets: {reason}");
+                    delayed_reset = Some((tokio::time::Instant::now() + delay, reason));
+                }
+                Admission::Refused => {
+                    tracing::debug!("refused hard reset, the reset budget is exhausted: {reason}");
+                }
             }
         }
     }
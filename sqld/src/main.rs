@@ -1,4 +1,5 @@
 use std::env;
+use std::ffi::OsString;
 use std::fs::{self, OpenOptions};
 use std::io::{stdout, Write};
 use std::net::SocketAddr;
@@ -7,8 +8,16 @@ use std::time::Duration;
 
 use anyhow::{bail, Context as _, Result};
 use bytesize::ByteSize;
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use mimalloc::MiMalloc;
+use sqld::database::attach::AttachableDatabase;
+use sqld::database::pool::PoolConfig;
+use sqld::database::settings::{
+    DenyRule, PrincipalStatementClasses, RequireParameterized, StmtClass, UnknownSettings,
+};
+use sqld::rate_limit::{PrincipalRateLimit, RateLimit, RateLimitConfig};
+use sqld::reload::ConfigLoader;
 use sqld::{database::dump::exporter::export_dump, version::Version, Config};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
@@ -23,6 +32,13 @@ static GLOBAL: MiMalloc = MiMalloc;
 #[command(name = "sqld")]
 #[command(about = "SQL daemon", version = Version::default(), long_about = None)]
 struct Cli {
+    /// A TOML configuration file. Its keys are the names of the options, such as
+    /// `http_listen_addr` or `max-log-size`, and its values are strings, numbers, booleans, or
+    /// arrays for the options that are repeated. The environment variables and the options given
+    /// on the command line take precedence over it. The defaults are listed in this help.
+    #[clap(long, env = "SQLD_CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+
     #[clap(long, short, default_value = "data.sqld", env = "SQLD_DB_PATH")]
     db_path: PathBuf,
 
@@ -37,24 +53,76 @@ struct Cli {
 
     #[clap(long, default_value = "127.0.0.1:8080", env = "SQLD_HTTP_LISTEN_ADDR")]
     http_listen_addr: SocketAddr,
+    /// Certificate of the HTTP listener and of the legacy Hrana listener, in PEM format. The
+    /// listeners use TLS when it is set. The certificate is reloaded on `SIGHUP`, and when its
+    /// file is modified.
+    #[clap(long, env = "SQLD_HTTP_TLS_CERT_FILE", requires = "http_tls_key_file")]
+    http_tls_cert_file: Option<PathBuf>,
+    /// Private key of `--http-tls-cert-file`, in PEM format.
+    #[clap(long, env = "SQLD_HTTP_TLS_KEY_FILE", requires = "http_tls_cert_file")]
+    http_tls_key_file: Option<PathBuf>,
+    /// CA of the client certificates, in PEM format. The clients of the HTTP and Hrana listeners
+    /// must then present a certificate signed by it.
+    #[clap(
+        long,
+        env = "SQLD_HTTP_TLS_CA_CERT_FILE",
+        requires = "http_tls_cert_file"
+    )]
+    http_tls_ca_cert_file: Option<PathBuf>,
     #[clap(long)]
     enable_http_console: bool,
+    /// Serve a key-value API under `/kv` on the HTTP listener, stored in the `_sqld_kv` table.
+    #[clap(long, env = "SQLD_ENABLE_KV_API")]
+    enable_kv_api: bool,
+    /// Serve Prometheus metrics on `GET /metrics` on the HTTP listener.
+    #[clap(long, env = "SQLD_ENABLE_METRICS")]
+    enable_metrics: bool,
+    /// Serve the databases of namespaces next to the default database, under
+    /// `/v1/namespaces/{name}/query`. Namespaces are created with the admin API, and are only
+    /// served by the primary.
+    #[clap(long, env = "SQLD_ENABLE_NAMESPACES")]
+    enable_namespaces: bool,
+    /// Number of rows of the record batches of the query results requested in the Apache Arrow
+    /// format (`Accept: application/vnd.apache.arrow.stream`).
+    #[clap(long, env = "SQLD_ARROW_BATCH_SIZE", default_value = "8192")]
+    arrow_batch_size: usize,
 
     /// Address and port for the legacy, Web-Socket-only Hrana server.
     #[clap(long, short = 'l', env = "SQLD_HRANA_LISTEN_ADDR")]
     hrana_listen_addr: Option<SocketAddr>,
 
+    /// Period of the pings sent to the Hrana WebSocket connections, in seconds. A connection that
+    /// doesn't answer a ping before the next one is closed. `0` disables the pings.
+    #[clap(long, env = "SQLD_HRANA_PING_INTERVAL_S", default_value = "30")]
+    hrana_ping_interval_s: u64,
+
     /// The address and port for the admin HTTP API.
     #[clap(long, env = "SQLD_ADMIN_LISTEN_ADDR")]
     admin_listen_addr: Option<SocketAddr>,
+    /// HTTP basic authentication required by the admin HTTP API, in the same format as
+    /// `--http-auth`. When the admin API is enabled, `/v1/stats` is only served by the admin API.
+    #[clap(long, env = "SQLD_ADMIN_AUTH")]
+    admin_auth: Option<String>,
 
     /// Path to a file with a JWT decoding key used to authenticate clients in the Hrana and HTTP
     /// APIs. The key is either a PKCS#8-encoded Ed25519 public key in PEM, or just plain bytes of
-    /// the Ed25519 public key in URL-safe base64.
+    /// the Ed25519 public key in URL-safe base64, or an RSA public key in PEM for RS256 tokens.
     ///
     /// You can also pass the key directly in the env variable SQLD_AUTH_JWT_KEY.
     #[clap(long, env = "SQLD_AUTH_JWT_KEY_FILE")]
     auth_jwt_key_file: Option<PathBuf>,
+    /// URL of a JSON Web Key Set (JWKS) whose keys sign the JWTs of the clients, which name their
+    /// key in their `kid` header. The keys are fetched at startup, and again every 5 minutes.
+    #[clap(long, env = "SQLD_AUTH_JWKS_URL")]
+    auth_jwks_url: Option<String>,
+    /// The JWTs must be intended for one of these audiences, in their `aud` claim. The audience
+    /// is not checked if none is given.
+    #[clap(long, env = "SQLD_AUTH_JWT_AUDIENCE", value_delimiter = ',')]
+    auth_jwt_audience: Vec<String>,
+    /// Tolerance of the checks of the `exp` and `nbf` claims of the JWTs, in seconds, for the
+    /// clock skew between the issuer of the tokens and the server.
+    #[clap(long, env = "SQLD_AUTH_JWT_LEEWAY_S", default_value = "60")]
+    auth_jwt_leeway_s: u64,
     /// Specifies legacy HTTP basic authentication. The argument must be in format "basic:$PARAM",
     /// where $PARAM is base64-encoded string "$USERNAME:$PASSWORD".
     #[clap(long, env = "SQLD_HTTP_AUTH")]
@@ -65,11 +133,8 @@ struct Cli {
     http_self_url: Option<String>,
 
     /// The address and port the inter-node RPC protocol listens to. Example: `0.0.0.0:5001`.
-    #[clap(
-        long,
-        conflicts_with = "primary_grpc_url",
-        env = "SQLD_GRPC_LISTEN_ADDR"
-    )]
+    /// Only a primary or a standby (`--standby`) listens to it.
+    #[clap(long, env = "SQLD_GRPC_LISTEN_ADDR")]
     grpc_listen_addr: Option<SocketAddr>,
     #[clap(
         long,
@@ -116,6 +181,14 @@ struct Cli {
     no_welcome: bool,
     #[clap(long, env = "SQLD_ENABLE_BOTTOMLESS_REPLICATION")]
     enable_bottomless_replication: bool,
+    /// The generation of the bottomless backup to restore the database from on startup, if the
+    /// local database is missing or older than it. Defaults to the newest generation.
+    #[clap(
+        long,
+        env = "SQLD_BOTTOMLESS_RESTORE_GENERATION",
+        requires = "enable_bottomless_replication"
+    )]
+    bottomless_restore_generation: Option<uuid::Uuid>,
     /// The duration, in second, after which to shutdown the server if no request have been
     /// received.
     /// By default, the server doesn't shutdown when idle.
@@ -133,6 +206,20 @@ struct Cli {
     #[clap(long, env = "SQLD_LOAD_DUMP_PATH", conflicts_with = "primary_grpc_url")]
     load_from_dump: Option<PathBuf>,
 
+    /// Load the SQLite database file at the provided path. A fresh primary starts from a copy of
+    /// it, and its tables are copied into an existing database, where none of them may exist yet.
+    /// Requires that the node is not in replica mode
+    #[clap(
+        long,
+        env = "SQLD_LOAD_FROM",
+        conflicts_with_all = ["primary_grpc_url", "load_from_dump"]
+    )]
+    load_from: Option<PathBuf>,
+
+    /// Maximum size of the SQLite database files uploaded to the admin API (in MiB).
+    #[clap(long, env = "SQLD_MAX_LOAD_SIZE_MB", default_value = "1024")]
+    max_load_size_mb: u64,
+
     /// Maximum size the replication log is allowed to grow (in MB).
     /// defaults to 200MB.
     #[clap(long, env = "SQLD_MAX_LOG_SIZE", default_value = "200")]
@@ -143,6 +230,60 @@ struct Cli {
     #[clap(long, env = "SQLD_MAX_LOG_DURATION")]
     max_log_duration: Option<f32>,
 
+    /// Rebuild the replication log from the database file on startup. Use it when sqld refuses to
+    /// start because the replication log and the database have diverged. Replicas then start over
+    /// from a snapshot of the database.
+    #[clap(long, env = "SQLD_FORCE_RECOVER")]
+    force_recover: bool,
+
+    /// Maximum number of snapshots of the replication log. The oldest snapshots beyond it are
+    /// merged into the next one, unless a replica may still need them. Unlimited by default.
+    #[clap(long, env = "SQLD_MAX_SNAPSHOTS")]
+    max_snapshots: Option<usize>,
+
+    /// Maximum age of the snapshots of the replication log (in seconds). Older snapshots are
+    /// merged into the next one, unless a replica may still need them.
+    #[clap(long, env = "SQLD_MAX_SNAPSHOT_AGE_S")]
+    max_snapshot_age_s: Option<u64>,
+
+    /// Number of most recent snapshots of the replication log that are always kept as they are.
+    #[clap(long, env = "SQLD_MIN_SNAPSHOT_KEEP", default_value = "1")]
+    min_snapshot_keep: usize,
+
+    /// Time after which the session of a replica that stopped replicating expires (in seconds).
+    /// The replica then performs a new handshake.
+    #[clap(long, env = "SQLD_REPLICA_SESSION_TTL_S", default_value = "3600")]
+    replica_session_ttl_s: u64,
+
+    /// Maximum number of replica sessions. The handshakes beyond it fail with a
+    /// `RESOURCE_EXHAUSTED` status, until sessions expire.
+    #[clap(long, env = "SQLD_MAX_REPLICA_SESSIONS", default_value = "10000")]
+    max_replica_sessions: usize,
+
+    /// Maximum number of frames read from the replication log at once for a replica. The next
+    /// frames are read once the replica received them.
+    #[clap(long, env = "SQLD_REPLICATION_BATCH_FRAMES", default_value = "128")]
+    replication_batch_frames: usize,
+
+    /// Maximum size of the frames read from the replication log at once for a replica (in bytes).
+    #[clap(long, env = "SQLD_REPLICATION_BATCH_BYTES", default_value = "1048576")]
+    replication_batch_bytes: usize,
+
+    /// Compression of the frames streamed to the replicas. The replicas that don't support it
+    /// receive uncompressed frames.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "none",
+        env = "SQLD_REPLICATION_COMPRESSION"
+    )]
+    replication_compression: sqld::ReplicationCompression,
+
+    /// Print the format version and header of the replication log and snapshots of the database,
+    /// and exit without modifying them.
+    #[clap(long)]
+    check_log: bool,
+
     #[clap(subcommand)]
     utils: Option<UtilsSubcommands>,
 
@@ -171,15 +312,44 @@ struct Cli {
     #[clap(long, env = "SQLD_HARD_HEAP_LIMIT_MB")]
     hard_heap_limit_mb: Option<usize>,
 
+    /// Total size of the page caches in mebibytes, divided between the open connections instead
+    /// of giving each connection its own cache of the default size.
+    #[clap(long, env = "SQLD_TOTAL_CACHE_SIZE_MB")]
+    total_cache_size_mb: Option<usize>,
+
     /// Allow the replica to overwrite its data if the primary starts replicating a different
     /// database. This is often the case when the primary goes through a recovery process.
     #[clap(long, env = "SQLD_ALLOW_REPLICA_OVERWRITE")]
     allow_replica_overwrite: bool,
 
+    /// Open the database of a replica read-only: a write that would be executed on the replica
+    /// instead of being forwarded to the primary fails, rather than diverging from the primary.
+    #[clap(long, env = "SQLD_READ_ONLY_REPLICA")]
+    read_only_replica: bool,
+
     /// Set the maximum size for a response. e.g 5KB, 10MB...
     #[clap(long, env = "SQLD_MAX_RESPONSE_SIZE", default_value = "10MB")]
     max_response_size: ByteSize,
 
+    /// Maximum number of rows of the results of a statement. A statement returning more rows
+    /// fails, without failing the response, and the results of the other statements are kept.
+    #[clap(long, env = "SQLD_MAX_RESPONSE_ROWS")]
+    max_response_rows: Option<u64>,
+
+    /// Maximum size of the values of the results of a statement, e.g 1MB. A statement returning
+    /// more fails, without failing the response, and the results of the other statements are kept.
+    #[clap(long, env = "SQLD_MAX_RESPONSE_BYTES")]
+    max_response_bytes: Option<ByteSize>,
+
+    /// Set the maximum size for an HTTP request body, once decompressed. e.g 5KB, 10MB...
+    #[clap(long, env = "SQLD_MAX_REQUEST_SIZE", default_value = "100MB")]
+    max_request_size: ByteSize,
+
+    /// Set the maximum length of an SQL string, in bytes. Longer SQL is rejected before it is
+    /// parsed.
+    #[clap(long, env = "SQLD_MAX_SQL_LENGTH")]
+    max_sql_length: Option<usize>,
+
     /// Set a command to execute when a snapshot file is generated.
     #[clap(long, env = "SQLD_SNAPSHOT_EXEC")]
     snapshot_exec: Option<String>,
@@ -187,6 +357,336 @@ struct Cli {
     /// The address and port for the replication HTTP API.
     #[clap(long, env = "SQLD_HTTP_REPLICATION_LISTEN_ADDR")]
     http_replication_listen_addr: Option<SocketAddr>,
+
+    /// Record row-level changes on the primary, so that replicas can be replicated logically with
+    /// `--replicate-tables`.
+    #[clap(long, env = "SQLD_ENABLE_LOGICAL_REPLICATION")]
+    enable_logical_replication: bool,
+
+    /// Comma-separated list of tables to replicate from the primary. If set, the replica is
+    /// replicated logically (row by row) instead of physically, and only contains these tables.
+    /// The primary must be started with `--enable-logical-replication`.
+    #[clap(
+        long,
+        env = "SQLD_REPLICATE_TABLES",
+        value_delimiter = ',',
+        requires = "primary_grpc_url"
+    )]
+    replicate_tables: Option<Vec<String>>,
+
+    /// Client-facing URLs of this primary, advertised to the replicas so that clients talking to
+    /// a replica can find the primary. Can be repeated, or separated by commas.
+    #[clap(
+        long = "advertise-addr",
+        env = "SQLD_ADVERTISE_ADDRS",
+        value_delimiter = ','
+    )]
+    advertise_addrs: Vec<String>,
+
+    /// Open the database in `auto_vacuum=INCREMENTAL` mode, so that space can be reclaimed with
+    /// an incremental vacuum through the admin API. Enabling it on an existing database rebuilds
+    /// the database file once.
+    #[clap(long, env = "SQLD_INCREMENTAL_VACUUM")]
+    incremental_vacuum: bool,
+
+    /// Collect per-statement execution statistics, exposed by the admin API and persisted in the
+    /// `_sqld_query_stats` table on the primary.
+    #[clap(long, env = "SQLD_STATS_COLLECTION")]
+    stats_collection: bool,
+
+    /// Fraction of the statement executions sampled by `--stats-collection`, between 0 and 1.
+    #[clap(long, env = "SQLD_STATS_SAMPLE_RATE", default_value = "0.1")]
+    stats_sample_rate: f64,
+
+    /// Analyze a table again once this many of its rows were inserted, updated or deleted, to
+    /// keep the statistics of the query planner fresh. The analysis runs on the primary when no
+    /// statement was executed for `--auto-analyze-idle-ms`. Disabled by default, tables can
+    /// still be analyzed with `POST /v1/analyze` on the admin API.
+    #[clap(long, env = "SQLD_AUTO_ANALYZE_THRESHOLD")]
+    auto_analyze_threshold: Option<u64>,
+
+    /// Time without any statement, in milliseconds, before the tables are analyzed.
+    #[clap(long, env = "SQLD_AUTO_ANALYZE_IDLE_MS", default_value = "1000")]
+    auto_analyze_idle_ms: u64,
+
+    /// `PRAGMA analysis_limit` of the automatic analysis: the approximate number of rows of each
+    /// index visited. 0 for no limit.
+    #[clap(long, env = "SQLD_AUTO_ANALYZE_LIMIT", default_value = "1000")]
+    auto_analyze_limit: u32,
+
+    /// Maximum time the analysis of a table can block writes, in milliseconds. An analysis that
+    /// takes longer is interrupted, and retried later with a lower limit.
+    #[clap(long, env = "SQLD_AUTO_ANALYZE_BUDGET_MS", default_value = "100")]
+    auto_analyze_budget_ms: u64,
+
+    /// Checkpoint the WAL into the database file once this many seconds elapsed since the last
+    /// checkpoint. The WAL is never checkpointed if neither this nor `--max-wal-size` is set.
+    #[clap(long, env = "SQLD_CHECKPOINT_INTERVAL_S")]
+    checkpoint_interval_s: Option<u64>,
+
+    /// Checkpoint the WAL into the database file once it grows past this size, in megabytes.
+    /// Checkpoints blocked by long-running readers are retried, so the WAL can temporarily
+    /// exceed it.
+    #[clap(long, env = "SQLD_MAX_WAL_SIZE")]
+    max_wal_size: Option<u64>,
+
+    /// Maximum execution time of a statement, in milliseconds. Sessions can lower it with
+    /// `SET statement_timeout`, but not raise it.
+    #[clap(long, env = "SQLD_QUERY_TIMEOUT_MS")]
+    query_timeout_ms: Option<u64>,
+
+    /// Time after which a transaction that waits for a statement is rolled back, in seconds. The
+    /// next statement of the connection then fails. `0` never rolls back idle transactions.
+    #[clap(long, env = "SQLD_TXN_TIMEOUT_S", default_value = "5")]
+    txn_timeout_s: u64,
+
+    /// What to do with `SET` and `SHOW` statements on settings that sqld doesn't know about.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "error",
+        env = "SQLD_UNKNOWN_SETTINGS"
+    )]
+    unknown_settings: UnknownSettings,
+
+    /// Reject the statements with literal values in the `VALUES` of an `INSERT`, the `SET` of an
+    /// `UPDATE` or the comparisons of a `WHERE` clause, where a parameter could be used. Either
+    /// only for the statements that write to the database, or for all statements.
+    #[clap(long, value_enum, env = "SQLD_REQUIRE_PARAMETERIZED")]
+    require_parameterized: Option<RequireParameterized>,
+
+    /// Enforce the foreign key constraints on every connection. Existing rows violating them are
+    /// logged at startup. Pass `--foreign-keys false` to keep SQLite's default of not enforcing
+    /// them.
+    #[clap(
+        long,
+        env = "SQLD_FOREIGN_KEYS",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    foreign_keys: bool,
+
+    /// Comma-separated classes of statements to reject, on every protocol, like
+    /// `drop_table,truncate_like_delete`. A caller with full access can run them anyway with the
+    /// `x-sqld-allow-denied-statements: true` header.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        env = "SQLD_DENIED_STATEMENTS"
+    )]
+    denied_statements: Vec<DenyRule>,
+
+    /// Also reject the statements that only escape `--denied-statements` with a `WHERE` clause
+    /// that is always true, like `DELETE FROM t WHERE 1 = 1`.
+    #[clap(long, env = "SQLD_STRICT_DENIED_STATEMENTS")]
+    strict_denied_statements: bool,
+
+    /// Comma-separated list of the classes of statements that callers may run, among `read`,
+    /// `write`, `ddl`, `pragma`, `attach` and `vacuum`. All the classes are allowed if not set.
+    /// Transactions can always be controlled.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        env = "SQLD_ALLOWED_STATEMENT_CLASSES"
+    )]
+    allowed_statement_classes: Vec<StmtClass>,
+
+    /// Comma-separated list of the classes of statements that callers with a read-only token may
+    /// run, instead of `--allowed-statement-classes`.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        env = "SQLD_READ_ONLY_ALLOWED_STATEMENT_CLASSES"
+    )]
+    read_only_allowed_statement_classes: Vec<StmtClass>,
+
+    /// Comma-separated list of principals restricted to some classes of statements, on top of
+    /// `--allowed-statement-classes`, like `alice=read+write,reporting=read`. The principal of a
+    /// client is the user of its HTTP basic credentials, or the `sub` claim of its JWT. The
+    /// primary also applies them to the writes proxied by its replicas.
+    #[clap(long, value_delimiter = ',', env = "SQLD_PRINCIPAL_STATEMENT_CLASSES")]
+    principal_statement_classes: Vec<PrincipalStatementClasses>,
+
+    /// Queries per second allowed to each client IP, as `reads/writes`, like `100/10`. `0` doesn't
+    /// limit them. The queries over the limit fail at once, with a `429` code over HTTP.
+    #[clap(long, env = "SQLD_IP_RATE_LIMIT")]
+    ip_rate_limit: Option<RateLimit>,
+    /// Queries per second allowed to each authenticated principal, as `reads/writes`, whatever
+    /// the addresses it connects from.
+    #[clap(long, env = "SQLD_PRINCIPAL_RATE_LIMIT")]
+    principal_rate_limit: Option<RateLimit>,
+    /// Comma-separated list of the limits of some principals, instead of
+    /// `--principal-rate-limit`, like `batch=1000/500,reporting=50/0`.
+    #[clap(long, value_delimiter = ',', env = "SQLD_PRINCIPAL_RATE_LIMITS")]
+    principal_rate_limits: Vec<PrincipalRateLimit>,
+
+    /// Maximum number of client connections open at once, on all the frontends together. The
+    /// connections over the limit are refused with a `503` code and a `SERVER_BUSY` error.
+    #[clap(long, env = "SQLD_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+    /// Maximum number of HTTP connections open at once.
+    #[clap(long, env = "SQLD_MAX_HTTP_CONNECTIONS")]
+    max_http_connections: Option<usize>,
+    /// Maximum number of Hrana connections over WebSockets open at once, including those upgraded
+    /// from HTTP connections.
+    #[clap(long, env = "SQLD_MAX_WS_CONNECTIONS")]
+    max_ws_connections: Option<usize>,
+
+    /// Total size of the responses to the read-only queries of the HTTP API kept in a cache, in
+    /// mebibytes. The responses to the same queries are served from the cache, stale ones while
+    /// they are refreshed. Disabled by default.
+    #[clap(long, env = "SQLD_QUERY_CACHE_SIZE_MB", default_value = "0")]
+    query_cache_size_mb: u64,
+    /// Time after which a cached response is dropped, whether the database changed or not, in
+    /// seconds.
+    #[clap(long, env = "SQLD_QUERY_CACHE_TTL_S", default_value = "10")]
+    query_cache_ttl_s: u64,
+
+    /// Comma-separated list of the databases that clients may attach with
+    /// `ATTACH DATABASE 'name' AS alias`, like `reports=attached/reports.db`. The paths are
+    /// relative to the database directory, and the databases are attached read-only. No database
+    /// can be attached if not set.
+    #[clap(
+        long = "attachable-database",
+        value_delimiter = ',',
+        env = "SQLD_ATTACHABLE_DATABASES"
+    )]
+    attachable_databases: Vec<AttachableDatabase>,
+
+    /// Secret shared by a primary and its replicas. The replicas sign with it the identity of the
+    /// clients whose writes they proxy, and the primary rejects the writes whose identity is
+    /// missing or not signed with it.
+    #[clap(long, env = "SQLD_PROXY_IDENTITY_KEY")]
+    proxy_identity_key: Option<String>,
+
+    /// Commit the single-statement writes arriving within this window, in milliseconds, in a
+    /// single transaction on the primary. This trades a little latency for a much higher
+    /// throughput of small writes. Disabled by default.
+    #[clap(long, env = "SQLD_GROUP_COMMIT_WINDOW_MS")]
+    group_commit_window_ms: Option<u64>,
+
+    /// Number of idle connections to a database kept open to be reused by the next clients,
+    /// instead of opening a connection for each of them. Disabled (0) by default.
+    #[clap(long, env = "SQLD_DB_POOL_SIZE", default_value = "0")]
+    db_pool_size: usize,
+
+    /// Number of idle pooled connections kept open even once they have been idle for longer than
+    /// `--db-pool-idle-timeout-s`.
+    #[clap(long, env = "SQLD_DB_POOL_MIN_IDLE", default_value = "0")]
+    db_pool_min_idle: usize,
+
+    /// Time after which an idle pooled connection is closed, in seconds.
+    #[clap(long, env = "SQLD_DB_POOL_IDLE_TIMEOUT_S", default_value = "60")]
+    db_pool_idle_timeout_s: u64,
+
+    /// Minimum time between two hard resets of a replica, in seconds. A replica resets (wipes its
+    /// database and replicates it again from scratch) when it can't replicate from its primary.
+    #[clap(long, env = "SQLD_HARD_RESET_MIN_INTERVAL_S", default_value = "60")]
+    hard_reset_min_interval_s: u64,
+
+    /// Maximum number of hard resets of a replica in an hour. Once exceeded, the replica stops
+    /// resetting and reports itself as unhealthy, until the resets are re-armed with
+    /// `POST /v1/hard_reset/rearm` on the admin API.
+    #[clap(long, env = "SQLD_MAX_HARD_RESETS_PER_HOUR", default_value = "3")]
+    max_hard_resets_per_hour: u32,
+
+    /// Whether hard resets may wipe the database. Defaults to true on replicas, and to false on
+    /// primaries, which never reset unless explicitly allowed to.
+    #[clap(long, env = "SQLD_ALLOW_HARD_RESET")]
+    allow_hard_reset: Option<bool>,
+
+    /// Number of frames a replica can lag behind its primary and still report itself as ready on
+    /// `GET /readiness`.
+    #[clap(long, env = "SQLD_MAX_REPLICATION_LAG_FRAMES", default_value = "1000")]
+    max_replication_lag_frames: u64,
+
+    /// Upper bound of the delay between two attempts of a replica to reach its primary, in
+    /// seconds. The delay doubles after each failed attempt, with some jitter, up to this bound.
+    #[clap(long, env = "SQLD_MAX_RECONNECT_INTERVAL_S", default_value = "30")]
+    max_reconnect_interval_s: u64,
+
+    /// Number of connections over which a replica proxies its writes to the primary. The sessions
+    /// of the clients of the replica are spread over them, and multiplexed.
+    #[clap(long, env = "SQLD_WRITE_PROXY_CHANNELS", default_value = "4")]
+    write_proxy_channels: usize,
+
+    /// Maximum number of writes a replica sends to the primary at once. The other writes wait for
+    /// their turn.
+    #[clap(long, env = "SQLD_WRITE_PROXY_MAX_IN_FLIGHT", default_value = "128")]
+    write_proxy_max_in_flight: usize,
+
+    /// Maximum number of writes waiting to be sent to the primary. Beyond it, the writes fail with
+    /// `503 Service Unavailable` and the `PRIMARY_WRITE_QUEUE_FULL` error code.
+    #[clap(long, env = "SQLD_WRITE_PROXY_QUEUE_SIZE", default_value = "1024")]
+    write_proxy_queue_size: usize,
+
+    /// Serve the transactions begun with `BEGIN` or `BEGIN DEFERRED` on a replica from the replica,
+    /// rather than proxying them to the primary. Such a transaction reads the replica, and fails
+    /// with a `WRITE_IN_READ_TRANSACTION` error if it writes: transactions that write must begin
+    /// with `BEGIN IMMEDIATE`.
+    #[clap(long, env = "SQLD_LOCAL_READ_TRANSACTIONS")]
+    local_read_transactions: bool,
+
+    /// Keep trying to reach the primary forever instead of exiting after 100 failed attempts. In
+    /// the meantime, the replica serves the reads from the database it has already replicated,
+    /// and answers `503 Service Unavailable` if it hasn't replicated anything yet.
+    #[clap(long, env = "SQLD_WAIT_FOR_PRIMARY")]
+    wait_for_primary: bool,
+
+    /// Time given to the requests in flight to finish when the server shuts down, in seconds. New
+    /// requests are rejected with `503 Service Unavailable` in the meantime, and the transactions
+    /// left open by the clients are rolled back.
+    #[clap(long, env = "SQLD_SHUTDOWN_GRACE_PERIOD_S", default_value = "10")]
+    shutdown_grace_period_s: u64,
+
+    /// Origins allowed to call the HTTP API from a browser, like `https://app.example.com`, or
+    /// `*` for any origin. Can be repeated, or separated by commas.
+    #[clap(
+        long = "cors-allowed-origin",
+        env = "SQLD_CORS_ALLOWED_ORIGINS",
+        value_delimiter = ',',
+        default_value = "*"
+    )]
+    cors_allowed_origins: Vec<String>,
+
+    /// Methods allowed in CORS requests, separated by commas. Any method is allowed by default.
+    #[clap(long, env = "SQLD_CORS_ALLOWED_METHODS", value_delimiter = ',')]
+    cors_allowed_methods: Vec<String>,
+
+    /// Headers allowed in CORS requests, separated by commas. Any header is allowed by default.
+    #[clap(long, env = "SQLD_CORS_ALLOWED_HEADERS", value_delimiter = ',')]
+    cors_allowed_headers: Vec<String>,
+
+    /// How long browsers may cache the response to a CORS preflight request, in seconds.
+    #[clap(long, env = "SQLD_CORS_MAX_AGE_S")]
+    cors_max_age_s: Option<u64>,
+
+    /// Allow CORS requests with credentials. Requires explicit `--cors-allowed-origin`s.
+    #[clap(long, env = "SQLD_CORS_ALLOW_CREDENTIALS")]
+    cors_allow_credentials: bool,
+
+    /// Run the replica as a warm standby: the frames it replicates are also logged, and served to
+    /// other replicas on `--grpc-listen-addr`. The standby is promoted to primary with
+    /// `POST /v1/promote` on the admin API.
+    #[clap(
+        long,
+        env = "SQLD_STANDBY",
+        requires = "primary_grpc_url",
+        conflicts_with = "replicate_tables"
+    )]
+    standby: bool,
+
+    /// Key signing the consistency tokens returned with the writes, which make the reads wait for
+    /// these writes on any node. Must be the same on all the nodes of the database.
+    #[clap(
+        long,
+        env = "SQLD_CONSISTENCY_TOKEN_KEY",
+        conflicts_with = "replicate_tables"
+    )]
+    consistency_token_key: Option<String>,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -230,17 +730,97 @@ impl Cli {
         match (&self.grpc_listen_addr, &self.primary_grpc_url) {
             (None, None) => eprintln!("standalone"),
             (Some(addr), None) => eprintln!("primary ({addr})"),
+            (None, Some(url)) if self.standby => eprintln!("standby (primary at {url})"),
             (None, Some(url)) => eprintln!("replica (primary at {url})"),
-            _ => unreachable!("invalid configuration!"),
+            (Some(addr), Some(url)) if self.standby => eprintln!("standby ({addr}, primary at {url})"),
+            // rejected by `config_from_args`
+            (Some(_), Some(_)) => eprintln!("invalid"),
         };
         eprintln!("\t- database path: {}", self.db_path.display());
         let extensions_str = self.extensions_path.clone().map_or("<disabled>".to_string(), |x| x.display().to_string());
         eprintln!("\t- extensions path: {extensions_str}");
         eprintln!("\t- listening for HTTP requests on: {}", self.http_listen_addr);
+        eprintln!("\t- http_tls: {}", if self.http_tls_cert_file.is_some() { "yes" } else { "no" });
         eprintln!("\t- grpc_tls: {}", if self.grpc_tls { "yes" } else { "no" });
     }
 }
 
+/// Parses the command line `args`, with the settings of the configuration file given with
+/// `--config-file` as defaults.
+fn cli_from_args(mut args: Vec<OsString>) -> Result<Cli> {
+    // the options missing from the command line may be in the file
+    let matches = Cli::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)?;
+    if let Some(path) = matches.get_one::<PathBuf>("config_file") {
+        let file_args = config_file_args(path, &matches)?;
+        // the options of the file go before the subcommand, if any
+        args.splice(1..1, file_args);
+    }
+
+    let matches = Cli::command().try_get_matches_from(&args)?;
+    Ok(Cli::from_arg_matches(&matches)?)
+}
+
+/// Reads the configuration file at `path`, and returns the options it sets that are not set by
+/// the environment or the command line, as parsed in `matches`.
+fn config_file_args(path: &Path, matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read the configuration file `{}`", path.display()))?;
+    let settings: toml::value::Table = toml::from_str(&contents)
+        .with_context(|| format!("invalid configuration file `{}`", path.display()))?;
+
+    let command = Cli::command();
+    let mut args = Vec::new();
+    for (key, value) in settings {
+        let name = key.replace('-', "_");
+        let arg = command.get_arguments().find(|arg| {
+            arg.get_id().as_str() == name
+                || arg.get_long().map(|long| long.replace('-', "_")) == Some(name.clone())
+        });
+        let Some(arg) = arg.filter(|arg| arg.get_long().is_some() && arg.get_id() != "config_file")
+        else {
+            bail!("unknown setting `{key}` in the configuration file `{}`", path.display());
+        };
+        if matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::EnvVariable | ValueSource::CommandLine)
+        ) {
+            continue;
+        }
+
+        let long = arg.get_long().unwrap_or_default();
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => bail!(
+                    "setting `{key}` of the configuration file `{}` must be a string, a number or a boolean",
+                    path.display()
+                ),
+            };
+            if arg.get_action().takes_values() {
+                args.push(format!("--{long}={value}").into());
+            } else if value == "true" {
+                args.push(format!("--{long}").into());
+            } else if value != "false" {
+                bail!(
+                    "setting `{key}` of the configuration file `{}` must be a boolean",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    Ok(args)
+}
+
 fn config_from_args(args: Cli) -> Result<Config> {
     let auth_jwt_key = if let Some(file_path) = args.auth_jwt_key_file {
         let data = fs::read_to_string(file_path).context("Could not read file with JWT key")?;
@@ -259,10 +839,23 @@ fn config_from_args(args: Cli) -> Result<Config> {
         db_path: args.db_path,
         extensions_path: args.extensions_path,
         http_addr: Some(args.http_listen_addr),
+        http_tls_cert: args.http_tls_cert_file,
+        http_tls_key: args.http_tls_key_file,
+        http_tls_ca_cert: args.http_tls_ca_cert_file,
         enable_http_console: args.enable_http_console,
+        enable_kv_api: args.enable_kv_api,
+        enable_metrics: args.enable_metrics,
+        enable_namespaces: args.enable_namespaces,
+        arrow_batch_size: args.arrow_batch_size,
         hrana_addr: args.hrana_listen_addr,
+        hrana_ping_interval: (args.hrana_ping_interval_s != 0)
+            .then(|| Duration::from_secs(args.hrana_ping_interval_s)),
         admin_addr: args.admin_listen_addr,
+        admin_auth: args.admin_auth,
         auth_jwt_key,
+        auth_jwks_url: args.auth_jwks_url,
+        auth_jwt_audience: args.auth_jwt_audience,
+        auth_jwt_leeway: Duration::from_secs(args.auth_jwt_leeway_s),
         http_auth: args.http_auth,
         http_self_url: args.http_self_url,
         backend: args.backend,
@@ -281,22 +874,102 @@ fn config_from_args(args: Cli) -> Result<Config> {
         } else {
             None
         },
+        bottomless_restore_generation: args.bottomless_restore_generation,
         idle_shutdown_timeout: args.idle_shutdown_timeout_s.map(Duration::from_secs),
         initial_idle_shutdown_timeout: args
             .initial_idle_shutdown_timeout_s
             .map(Duration::from_secs),
         load_from_dump: args.load_from_dump,
+        load_from: args.load_from,
+        max_load_size: args.max_load_size_mb * 1024 * 1024,
         max_log_size: args.max_log_size,
         max_log_duration: args.max_log_duration,
+        force_recover: args.force_recover,
+        max_snapshots: args.max_snapshots,
+        max_snapshot_age: args.max_snapshot_age_s.map(Duration::from_secs),
+        min_snapshot_keep: args.min_snapshot_keep,
+        replica_session_ttl: Duration::from_secs(args.replica_session_ttl_s),
+        max_replica_sessions: args.max_replica_sessions,
+        replication_batch_frames: args.replication_batch_frames,
+        replication_batch_bytes: args.replication_batch_bytes,
+        replication_compression: args.replication_compression,
         heartbeat_url: args.heartbeat_url,
         heartbeat_auth: args.heartbeat_auth,
         heartbeat_period: Duration::from_secs(args.heartbeat_period_s),
         soft_heap_limit_mb: args.soft_heap_limit_mb,
         hard_heap_limit_mb: args.hard_heap_limit_mb,
+        total_cache_size_mb: args.total_cache_size_mb,
         allow_replica_overwrite: args.allow_replica_overwrite,
+        read_only_replica: args.read_only_replica,
         max_response_size: args.max_response_size.0,
+        max_response_rows: args.max_response_rows,
+        max_response_bytes: args.max_response_bytes.map(|size| size.0),
+        max_request_size: args.max_request_size.0,
+        max_sql_length: args.max_sql_length,
         snapshot_exec: args.snapshot_exec,
         http_replication_addr: args.http_replication_listen_addr,
+        enable_logical_replication: args.enable_logical_replication,
+        replicate_tables: args.replicate_tables,
+        advertise_addrs: args.advertise_addrs,
+        incremental_vacuum: args.incremental_vacuum,
+        stats_collection: args.stats_collection,
+        stats_sample_rate: args.stats_sample_rate,
+        auto_analyze_threshold: args.auto_analyze_threshold,
+        auto_analyze_idle_window: Duration::from_millis(args.auto_analyze_idle_ms),
+        auto_analyze_limit: args.auto_analyze_limit,
+        auto_analyze_budget: Duration::from_millis(args.auto_analyze_budget_ms),
+        checkpoint_interval: args.checkpoint_interval_s.map(Duration::from_secs),
+        max_wal_size: args.max_wal_size.map(|mb| mb * 1_000_000),
+        query_timeout: args.query_timeout_ms.map(Duration::from_millis),
+        txn_timeout: (args.txn_timeout_s != 0).then(|| Duration::from_secs(args.txn_timeout_s)),
+        unknown_settings: args.unknown_settings,
+        require_parameterized: args.require_parameterized,
+        foreign_keys: args.foreign_keys,
+        denied_statements: args.denied_statements,
+        strict_denied_statements: args.strict_denied_statements,
+        allowed_statement_classes: args.allowed_statement_classes,
+        read_only_allowed_statement_classes: args.read_only_allowed_statement_classes,
+        principal_statement_classes: args.principal_statement_classes,
+        rate_limits: RateLimitConfig {
+            per_ip: args.ip_rate_limit,
+            per_principal: args.principal_rate_limit,
+            principals: args.principal_rate_limits,
+        },
+        connection_limits: sqld::ConnectionLimits {
+            total: args.max_connections,
+            http: args.max_http_connections,
+            ws: args.max_ws_connections,
+        },
+        query_cache: sqld::QueryCacheConfig {
+            max_bytes: args.query_cache_size_mb * 1024 * 1024,
+            ttl: Duration::from_secs(args.query_cache_ttl_s),
+        },
+        attachable_databases: args.attachable_databases,
+        proxy_identity_key: args.proxy_identity_key,
+        hard_reset_min_interval: Duration::from_secs(args.hard_reset_min_interval_s),
+        max_hard_resets_per_hour: args.max_hard_resets_per_hour,
+        allow_hard_reset: args.allow_hard_reset,
+        max_replication_lag_frames: args.max_replication_lag_frames,
+        max_reconnect_interval: Duration::from_secs(args.max_reconnect_interval_s),
+        wait_for_primary: args.wait_for_primary,
+        local_read_transactions: args.local_read_transactions,
+        write_proxy_channels: args.write_proxy_channels,
+        write_proxy_max_in_flight: args.write_proxy_max_in_flight,
+        write_proxy_queue_size: args.write_proxy_queue_size,
+        shutdown_grace_period: Duration::from_secs(args.shutdown_grace_period_s),
+        group_commit_window: args.group_commit_window_ms.map(Duration::from_millis),
+        db_pool: PoolConfig {
+            max_idle: args.db_pool_size,
+            min_idle: args.db_pool_min_idle,
+            idle_timeout: Duration::from_secs(args.db_pool_idle_timeout_s),
+        },
+        cors_allowed_origins: args.cors_allowed_origins,
+        cors_allowed_methods: args.cors_allowed_methods,
+        cors_allowed_headers: args.cors_allowed_headers,
+        cors_max_age: args.cors_max_age_s.map(Duration::from_secs),
+        cors_allow_credentials: args.cors_allow_credentials,
+        standby: args.standby,
+        consistency_token_key: args.consistency_token_key,
     })
 }
 
@@ -356,7 +1029,17 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let args = Cli::parse();
+    let args = match cli_from_args(env::args_os().collect()) {
+        Ok(args) => args,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => return Err(e),
+        },
+    };
+
+    if args.check_log {
+        return sqld::check_log(&args.db_path);
+    }
 
     match args.utils {
         Some(UtilsSubcommands::Dump { path }) => {
@@ -372,9 +1055,127 @@ async fn main() -> Result<()> {
         None => {
             args.print_welcome_message();
             let config = config_from_args(args)?;
-            sqld::run_server(config).await?;
+            // a reload reads the same command line, environment and configuration file again
+            let loader: ConfigLoader =
+                Box::new(|| config_from_args(cli_from_args(env::args_os().collect())?));
+            sqld::run_server_with_reload(config, loader).await?;
 
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    /// Held while the command line is parsed: the environment is shared by the tests.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn cli(args: &[&str]) -> Result<Cli> {
+        cli_with_env(args, &[])
+    }
+
+    /// Parses `args` with the variables of `vars` set in the environment.
+    fn cli_with_env(args: &[&str], vars: &[(&str, &str)]) -> Result<Cli> {
+        let _env = ENV.lock();
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let args = ["sqld"].iter().chain(args).map(OsString::from).collect();
+        let res = cli_from_args(args);
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        res
+    }
+
+    fn config_file(dir: &Path, contents: &str) -> PathBuf {
+        let path = dir.join("sqld.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn config_file_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config_file(
+            dir.path(),
+            r#"
+            http_listen_addr = "0.0.0.0:9000"
+            max-log-size = 10
+            max_log_duration = 5.0
+            advertise_addrs = ["sqld-1:8080", "sqld-2:8080"]
+            enable_http_console = true
+            enable_kv_api = false
+            "#,
+        );
+
+        let args = cli(&["--config-file", path.to_str().unwrap()]).unwrap();
+        assert_eq!(args.http_listen_addr, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(args.max_log_size, 10);
+        assert_eq!(args.max_log_duration, Some(5.0));
+        assert_eq!(args.advertise_addrs, ["sqld-1:8080", "sqld-2:8080"]);
+        assert!(args.enable_http_console);
+        assert!(!args.enable_kv_api);
+
+        // the environment overrides the file, and the command line overrides both
+        let args = cli_with_env(
+            &[
+                "--config-file",
+                path.to_str().unwrap(),
+                "--max-log-size",
+                "30",
+            ],
+            &[("SQLD_MAX_LOG_SIZE", "20"), ("SQLD_MAX_LOG_DURATION", "7")],
+        )
+        .unwrap();
+        assert_eq!(args.max_log_size, 30);
+        assert_eq!(args.max_log_duration, Some(7.0));
+        assert_eq!(args.http_listen_addr, "0.0.0.0:9000".parse().unwrap());
+    }
+
+    #[test]
+    fn invalid_config_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = |contents: &str| {
+            let path = config_file(dir.path(), contents);
+            let err = cli(&["--config-file", path.to_str().unwrap()]).unwrap_err();
+            err.to_string()
+                .replace(&path.display().to_string(), "sqld.toml")
+        };
+
+        assert_eq!(
+            error("unknown_option = 1"),
+            "unknown setting `unknown_option` in the configuration file `sqld.toml`"
+        );
+        assert_eq!(
+            error("config_file = \"other.toml\""),
+            "unknown setting `config_file` in the configuration file `sqld.toml`"
+        );
+        assert_eq!(
+            error("min_snapshot_keep = { size = 10 }"),
+            "setting `min_snapshot_keep` of the configuration file `sqld.toml` must be a string, a number or a boolean"
+        );
+        assert_eq!(
+            error("enable_http_console = \"yes\""),
+            "setting `enable_http_console` of the configuration file `sqld.toml` must be a boolean"
+        );
+        assert_eq!(
+            error("min_snapshot_keep ="),
+            "invalid configuration file `sqld.toml`"
+        );
+
+        let path = config_file(dir.path(), "min_snapshot_keep = \"large\"");
+        let err = cli(&["--config-file", path.to_str().unwrap()]).unwrap_err();
+        assert!(err.downcast_ref::<clap::Error>().is_some());
+
+        let err = cli(&["--config-file", "missing.toml"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "could not read the configuration file `missing.toml`"
+        );
+    }
+}
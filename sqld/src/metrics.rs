@@ -0,0 +1,531 @@
+//! Prometheus metrics, served in the text exposition format on `GET /metrics`.
+//!
+//! The metrics are process-wide, like the counters of the stats: the statements executed through
+//! each frontend are counted by [`crate::database::instrumented::InstrumentedDatabase`], the
+//! replication metrics by the replication log and its service. Labels never contain SQL.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::replication::primary::logger::frames_logged_total;
+
+/// Upper bounds of the buckets of the latency of the programs, in seconds.
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+/// Upper bounds of the buckets of the number of statements of the programs.
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 500.0];
+/// Upper bounds of the buckets of the duration of the snapshots, in seconds.
+const SNAPSHOT_DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// The protocol through which a client reached the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frontend {
+    /// The HTTP API, including Hrana over HTTP.
+    Http,
+    /// Hrana over WebSockets.
+    Ws,
+}
+
+impl Frontend {
+    pub const ALL: [Self; 2] = [Self::Http, Self::Ws];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Ws => "ws",
+        }
+    }
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Number of observations in each bucket, the last one is `+Inf`.
+    buckets: Vec<AtomicU64>,
+    /// Bits of the `f64` sum of the observations.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {count}");
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+/// The metrics of the statements executed through a frontend.
+pub struct FrontendMetrics {
+    pub statements_ok: AtomicU64,
+    pub statements_error: AtomicU64,
+    /// Programs rejected by the rate limits, reads and writes.
+    pub rate_limited: [AtomicU64; 2],
+    /// Number of statements of the programs.
+    pub batch_size: Histogram,
+    /// Time to execute the programs, in seconds.
+    pub duration: Histogram,
+    /// Connections refused because the connection limits were reached.
+    pub connections_rejected: AtomicU64,
+    open_connections: AtomicI64,
+}
+
+impl FrontendMetrics {
+    fn new() -> Self {
+        Self {
+            statements_ok: AtomicU64::new(0),
+            statements_error: AtomicU64::new(0),
+            rate_limited: [AtomicU64::new(0), AtomicU64::new(0)],
+            batch_size: Histogram::new(BATCH_SIZE_BUCKETS),
+            duration: Histogram::new(DURATION_BUCKETS),
+            connections_rejected: AtomicU64::new(0),
+            open_connections: AtomicI64::new(0),
+        }
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn open_connection(&'static self) -> ConnectionGuard {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: self }
+    }
+
+    pub fn open_connections(&self) -> u64 {
+        self.open_connections.load(Ordering::Relaxed).max(0) as u64
+    }
+}
+
+pub struct ConnectionGuard {
+    metrics: &'static FrontendMetrics,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .open_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+static FRONTENDS: Lazy<[FrontendMetrics; 2]> =
+    Lazy::new(|| [FrontendMetrics::new(), FrontendMetrics::new()]);
+
+/// Frames streamed to each replica, by address of the replica.
+static FRAMES_STREAMED: Lazy<Mutex<BTreeMap<IpAddr, Arc<AtomicU64>>>> = Lazy::new(Default::default);
+
+static SNAPSHOT_DURATION: Lazy<Histogram> = Lazy::new(|| Histogram::new(SNAPSHOT_DURATION_BUCKETS));
+
+static REPLICA_SESSIONS_EVICTED: AtomicU64 = AtomicU64::new(0);
+
+/// Checkpoints of the WAL, by outcome: completed, blocked by readers, failed.
+static CHECKPOINTS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+/// Size of the WAL of the database, as of the last check of the checkpointer.
+static WAL_SIZE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Lookups of the query cache, by outcome: fresh hit, stale hit, miss.
+static QUERY_CACHE_LOOKUPS: [AtomicU64; 3] =
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+/// Size of the responses held by the query cache.
+static QUERY_CACHE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Lag of this replica behind its primary, as last reported by the primary, `u64::MAX` if unknown.
+static REPLICATION_LAG_FRAMES: AtomicU64 = AtomicU64::new(u64::MAX);
+static REPLICATION_LAG_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+pub fn frontend(frontend: Frontend) -> &'static FrontendMetrics {
+    &FRONTENDS[frontend as usize]
+}
+
+/// Returns the counter of the frames streamed to the replica at `addr`.
+pub fn frames_streamed(addr: IpAddr) -> Arc<AtomicU64> {
+    FRAMES_STREAMED.lock().entry(addr).or_default().clone()
+}
+
+/// Time to create a snapshot of the replication log, in seconds.
+pub fn snapshot_duration() -> &'static Histogram {
+    &SNAPSHOT_DURATION
+}
+
+/// Sessions of replicas evicted after they expired.
+pub fn replica_sessions_evicted() -> &'static AtomicU64 {
+    &REPLICA_SESSIONS_EVICTED
+}
+
+/// The outcome of a checkpoint of the WAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointOutcome {
+    Ok,
+    /// The checkpoint could not complete because of readers of older versions of the database.
+    Busy,
+    Error,
+}
+
+impl CheckpointOutcome {
+    const ALL: [Self; 3] = [Self::Ok, Self::Busy, Self::Error];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Busy => "busy",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Records a checkpoint of the WAL.
+pub fn record_checkpoint(outcome: CheckpointOutcome) {
+    CHECKPOINTS[outcome as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The outcome of a lookup of the query cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOutcome {
+    Hit,
+    /// The cached response was served, but the database changed since.
+    Stale,
+    Miss,
+}
+
+impl CacheOutcome {
+    const ALL: [Self; 3] = [Self::Hit, Self::Stale, Self::Miss];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Stale => "stale",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+/// Records a lookup of the query cache.
+pub fn record_query_cache_lookup(outcome: CacheOutcome) {
+    QUERY_CACHE_LOOKUPS[outcome as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn set_query_cache_size(bytes: u64) {
+    QUERY_CACHE_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+pub fn set_wal_size(bytes: u64) {
+    WAL_SIZE_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+/// Records the lag of this replica behind its primary, in frames and in time.
+pub fn set_replication_lag(frames: u64, lag: Option<Duration>) {
+    REPLICATION_LAG_FRAMES.store(frames, Ordering::Relaxed);
+    let lag_ms = lag.map_or(u64::MAX, |lag| lag.as_millis() as u64);
+    REPLICATION_LAG_MS.store(lag_ms, Ordering::Relaxed);
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Renders all the metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "sqld_statements_total",
+        "counter",
+        "Statements executed, by outcome.",
+    );
+    for f in Frontend::ALL {
+        let m = frontend(f);
+        for (outcome, count) in [("ok", &m.statements_ok), ("error", &m.statements_error)] {
+            let _ = writeln!(
+                out,
+                "sqld_statements_total{{frontend=\"{}\",outcome=\"{outcome}\"}} {}",
+                f.label(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "sqld_rate_limited_total",
+        "counter",
+        "Programs rejected by the rate limits, by kind.",
+    );
+    for f in Frontend::ALL {
+        let m = frontend(f);
+        for (kind, count) in ["read", "write"].into_iter().zip(&m.rate_limited) {
+            let _ = writeln!(
+                out,
+                "sqld_rate_limited_total{{frontend=\"{}\",kind=\"{kind}\"}} {}",
+                f.label(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "sqld_batch_size",
+        "histogram",
+        "Number of statements of the executed programs.",
+    );
+    for f in Frontend::ALL {
+        let labels = format!("frontend=\"{}\"", f.label());
+        frontend(f)
+            .batch_size
+            .render(&mut out, "sqld_batch_size", &labels);
+    }
+
+    header(
+        &mut out,
+        "sqld_query_duration_seconds",
+        "histogram",
+        "Time to execute a program.",
+    );
+    for f in Frontend::ALL {
+        let labels = format!("frontend=\"{}\"", f.label());
+        frontend(f)
+            .duration
+            .render(&mut out, "sqld_query_duration_seconds", &labels);
+    }
+
+    header(
+        &mut out,
+        "sqld_open_connections",
+        "gauge",
+        "Client connections currently open.",
+    );
+    for f in Frontend::ALL {
+        let _ = writeln!(
+            out,
+            "sqld_open_connections{{frontend=\"{}\"}} {}",
+            f.label(),
+            frontend(f).open_connections.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "sqld_connections_rejected_total",
+        "counter",
+        "Client connections refused because the connection limits were reached.",
+    );
+    for f in Frontend::ALL {
+        let _ = writeln!(
+            out,
+            "sqld_connections_rejected_total{{frontend=\"{}\"}} {}",
+            f.label(),
+            frontend(f).connections_rejected.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "sqld_replication_frames_logged_total",
+        "counter",
+        "Frames written to the replication log.",
+    );
+    let _ = writeln!(
+        out,
+        "sqld_replication_frames_logged_total {}",
+        frames_logged_total()
+    );
+
+    header(
+        &mut out,
+        "sqld_replication_frames_streamed_total",
+        "counter",
+        "Frames streamed to each replica.",
+    );
+    for (addr, count) in FRAMES_STREAMED.lock().iter() {
+        let _ = writeln!(
+            out,
+            "sqld_replication_frames_streamed_total{{replica=\"{addr}\"}} {}",
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "sqld_snapshot_duration_seconds",
+        "histogram",
+        "Time to create a snapshot of the replication log.",
+    );
+    SNAPSHOT_DURATION.render(&mut out, "sqld_snapshot_duration_seconds", "");
+
+    header(
+        &mut out,
+        "sqld_replica_sessions_evicted_total",
+        "counter",
+        "Sessions of replicas evicted after they expired.",
+    );
+    let _ = writeln!(
+        out,
+        "sqld_replica_sessions_evicted_total {}",
+        REPLICA_SESSIONS_EVICTED.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut out,
+        "sqld_checkpoints_total",
+        "counter",
+        "Checkpoints of the WAL, by outcome.",
+    );
+    for outcome in CheckpointOutcome::ALL {
+        let _ = writeln!(
+            out,
+            "sqld_checkpoints_total{{outcome=\"{}\"}} {}",
+            outcome.label(),
+            CHECKPOINTS[outcome as usize].load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "sqld_wal_size_bytes",
+        "gauge",
+        "Size of the WAL of the database.",
+    );
+    let _ = writeln!(
+        out,
+        "sqld_wal_size_bytes {}",
+        WAL_SIZE_BYTES.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut out,
+        "sqld_query_cache_lookups_total",
+        "counter",
+        "Lookups of the query cache, by outcome.",
+    );
+    for outcome in CacheOutcome::ALL {
+        let _ = writeln!(
+            out,
+            "sqld_query_cache_lookups_total{{outcome=\"{}\"}} {}",
+            outcome.label(),
+            QUERY_CACHE_LOOKUPS[outcome as usize].load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "sqld_query_cache_bytes",
+        "gauge",
+        "Size of the responses held by the query cache.",
+    );
+    let _ = writeln!(
+        out,
+        "sqld_query_cache_bytes {}",
+        QUERY_CACHE_BYTES.load(Ordering::Relaxed)
+    );
+
+    let lag_frames = REPLICATION_LAG_FRAMES.load(Ordering::Relaxed);
+    if lag_frames != u64::MAX {
+        header(
+            &mut out,
+            "sqld_replication_lag_frames",
+            "gauge",
+            "Frames of the primary the replica has not applied yet.",
+        );
+        let _ = writeln!(out, "sqld_replication_lag_frames {lag_frames}");
+    }
+    let lag_ms = REPLICATION_LAG_MS.load(Ordering::Relaxed);
+    if lag_ms != u64::MAX {
+        header(
+            &mut out,
+            "sqld_replication_lag_seconds",
+            "gauge",
+            "Time since the primary committed the oldest frame the replica has not received.",
+        );
+        let _ = writeln!(
+            out,
+            "sqld_replication_lag_seconds {}",
+            lag_ms as f64 / 1000.0
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_histogram() {
+        static BOUNDS: &[f64] = &[1.0, 5.0];
+        let histogram = Histogram::new(BOUNDS);
+        histogram.observe(0.5);
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        histogram.observe(10.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test", "frontend=\"http\"");
+        assert_eq!(
+            out,
+            "test_bucket{frontend=\"http\",le=\"1\"} 2\n\
+            test_bucket{frontend=\"http\",le=\"5\"} 3\n\
+            test_bucket{frontend=\"http\",le=\"+Inf\"} 4\n\
+            test_sum{frontend=\"http\"} 14.5\n\
+            test_count{frontend=\"http\"} 4\n"
+        );
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test", "");
+        assert!(out.starts_with("test_bucket{le=\"1\"} 2\n"));
+        assert!(out.ends_with("test_sum 14.5\ntest_count 4\n"));
+    }
+
+    #[test]
+    fn render_replication_lag() {
+        set_replication_lag(12, Some(Duration::from_millis(1500)));
+        let out = render();
+        assert!(out.contains("sqld_replication_lag_frames 12\n"), "{out}");
+        assert!(out.contains("sqld_replication_lag_seconds 1.5\n"), "{out}");
+
+        // the primary doesn't know when the frames were committed
+        set_replication_lag(12, None);
+        assert!(!render().contains("sqld_replication_lag_seconds"));
+    }
+}
@@ -0,0 +1,637 @@
+//! Namespaces: databases served by the primary next to its default database.
+//!
+//! Each namespace lives in its own directory, `<db_path>/namespaces/<name>/`, with its own
+//! replication log. Namespaces are only created explicitly, through the admin API: a query to a
+//! namespace that doesn't exist fails, rather than creating the files of a new database.
+//!
+//! The quotas of the namespaces are kept in a registry, a SQLite database next to them, and are
+//! changed through the admin API without reopening the namespaces. They limit the size of the
+//! database, and the requests that a namespace serves at once and per second, so that one tenant
+//! can't starve the others.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use parking_lot::{Mutex, RwLock};
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::database::config::DatabaseConfigStore;
+use crate::database::factory::{DbFactory, TrackedDb};
+use crate::database::libsql::LibSqlDb;
+use crate::rate_limit::Bucket;
+use crate::replication::{FrameNo, ReplicationLogger, SnapshotRetention, WAL_PAGE_SIZE};
+
+/// Directory of the namespaces, in the database directory.
+const NAMESPACES_DIR: &str = "namespaces";
+/// The registry of the namespaces, in the directory of the namespaces.
+const REGISTRY_FILE: &str = "registry.db";
+const MAX_NAME_LEN: usize = 64;
+
+/// The databases of the namespaces.
+pub type NamespaceDb = TrackedDb<LibSqlDb>;
+
+pub struct Namespace {
+    pub db_factory: Arc<dyn DbFactory<Db = NamespaceDb>>,
+    pub logger: Arc<ReplicationLogger>,
+    pub config_store: Arc<DatabaseConfigStore>,
+    pub quota: Quota,
+}
+
+/// Opens the namespace whose files are in the given directory, creating them if needed.
+pub type MakeNamespace =
+    Box<dyn Fn(PathBuf) -> BoxFuture<'static, anyhow::Result<Namespace>> + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NamespaceError {
+    #[error("invalid namespace name `{0}`: names are 1 to 64 ASCII letters, digits, `-` or `_`")]
+    InvalidName(String),
+    #[error("namespace `{0}` does not exist")]
+    NotFound(String),
+    #[error("namespace `{0}` already exists")]
+    AlreadyExists(String),
+    #[error("invalid quotas: {0}")]
+    InvalidQuotas(&'static str),
+    #[error("namespace `{name}` is serving its quota of {max} connections, retry later")]
+    TooManyConnections { name: String, max: u32 },
+    #[error("too many requests to namespace `{name}`, retry in {}ms", .retry_in.as_millis())]
+    RateLimited { name: String, retry_in: Duration },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceInfo {
+    pub name: String,
+    /// Last frame of the replication log of the namespace.
+    pub frame_no: Option<FrameNo>,
+    pub generation_id: String,
+}
+
+/// The quotas of a namespace. `None` doesn't limit the resource.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceQuotas {
+    /// Maximum size of the database, in bytes. The writes that would grow the database past it
+    /// fail, the others are still accepted.
+    #[serde(default)]
+    pub max_db_size: Option<u64>,
+    /// Maximum number of requests served at once.
+    #[serde(default)]
+    pub max_connections: Option<u32>,
+    /// Maximum number of requests per second, in bursts of up to a second of requests.
+    #[serde(default)]
+    pub max_requests_per_sec: Option<u32>,
+}
+
+impl NamespaceQuotas {
+    fn validate(&self) -> Result<(), NamespaceError> {
+        if self
+            .max_db_size
+            .map_or(false, |size| size < WAL_PAGE_SIZE as u64)
+        {
+            return Err(NamespaceError::InvalidQuotas(
+                "`max_db_size` must be at least a page, 4096 bytes",
+            ));
+        }
+        if self.max_connections == Some(0) || self.max_requests_per_sec == Some(0) {
+            return Err(NamespaceError::InvalidQuotas(
+                "`max_connections` and `max_requests_per_sec` must be positive",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn max_page_count(&self) -> Option<u64> {
+        self.max_db_size.map(|size| size / WAL_PAGE_SIZE as u64)
+    }
+}
+
+/// The quotas of a namespace, and the requests it serves.
+#[derive(Default)]
+pub struct Quota {
+    quotas: RwLock<NamespaceQuotas>,
+    /// Budget of the requests per second, full until the first request.
+    requests: Mutex<Option<Bucket>>,
+    connections: AtomicU32,
+    rejected_requests: AtomicU64,
+}
+
+/// A request admitted by the quotas of its namespace, that counts as a connection until dropped.
+pub struct QuotaPermit<'a>(&'a Quota);
+
+impl Drop for QuotaPermit<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Quota {
+    pub fn quotas(&self) -> NamespaceQuotas {
+        *self.quotas.read()
+    }
+
+    /// Replaces the quotas, the budget of the requests starts over.
+    fn set(&self, quotas: NamespaceQuotas, config_store: &DatabaseConfigStore) {
+        *self.quotas.write() = quotas;
+        *self.requests.lock() = None;
+        config_store.set_max_page_count(quotas.max_page_count());
+    }
+
+    /// Admits a request to the namespace `name`, unless it exceeds the quotas of the namespace.
+    pub fn admit(&self, name: &str) -> Result<QuotaPermit<'_>, NamespaceError> {
+        let quotas = self.quotas();
+        let connections = self.connections.fetch_add(1, Ordering::SeqCst) + 1;
+        let permit = QuotaPermit(self);
+        if let Some(max) = quotas.max_connections.filter(|max| connections > *max) {
+            self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+            return Err(NamespaceError::TooManyConnections {
+                name: name.to_string(),
+                max,
+            });
+        }
+        if let Some(rate) = quotas.max_requests_per_sec {
+            let now = Instant::now();
+            let mut requests = self.requests.lock();
+            let bucket = requests.get_or_insert_with(|| Bucket::full(rate, now));
+            bucket.refill(rate, now);
+            if bucket.tokens < 1.0 {
+                self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+                return Err(NamespaceError::RateLimited {
+                    name: name.to_string(),
+                    retry_in: Duration::from_secs_f64((1.0 - bucket.tokens) / rate as f64),
+                });
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        Ok(permit)
+    }
+}
+
+/// The quotas of a namespace and its usage, reported by the admin API.
+#[derive(Debug, Serialize)]
+pub struct NamespaceStats {
+    pub name: String,
+    pub quotas: NamespaceQuotas,
+    /// Bytes of the database file and of its WAL.
+    pub db_size: u64,
+    /// Bytes used by the namespace on disk: its database, replication log and snapshots.
+    pub storage_bytes: u64,
+    /// Requests being served.
+    pub connections: u32,
+    /// Requests rejected for exceeding the quotas of connections or of requests per second.
+    pub rejected_requests: u64,
+}
+
+/// The registry of the namespaces, where their quotas are persisted.
+struct Registry {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+impl Registry {
+    fn open(root: &Path) -> anyhow::Result<Self> {
+        let conn = rusqlite::Connection::open(root.join(REGISTRY_FILE))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS quotas (namespace TEXT PRIMARY KEY, quotas TEXT NOT NULL)",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn quotas(&self, name: &str) -> anyhow::Result<NamespaceQuotas> {
+        let quotas: Option<String> = self
+            .conn
+            .lock()
+            .query_row(
+                "SELECT quotas FROM quotas WHERE namespace = ?",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match quotas {
+            Some(quotas) => Ok(serde_json::from_str(&quotas)?),
+            None => Ok(NamespaceQuotas::default()),
+        }
+    }
+
+    fn set_quotas(&self, name: &str, quotas: &NamespaceQuotas) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO quotas (namespace, quotas) VALUES (?, ?)",
+            [name, &serde_json::to_string(quotas)?],
+        )?;
+        Ok(())
+    }
+}
+
+/// Bytes used by the files under `path`.
+fn disk_usage(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            disk_usage(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+pub struct NamespaceStore {
+    root: PathBuf,
+    make_namespace: MakeNamespace,
+    registry: Registry,
+    namespaces: RwLock<BTreeMap<String, Arc<Namespace>>>,
+    /// Serializes the creation of the namespaces.
+    create_lock: tokio::sync::Mutex<()>,
+}
+
+fn validate_name(name: &str) -> Result<(), NamespaceError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(NamespaceError::InvalidName(name.to_string()))
+    }
+}
+
+impl NamespaceStore {
+    /// Opens the namespaces that were created in `db_path`.
+    pub async fn open(db_path: &Path, make_namespace: MakeNamespace) -> anyhow::Result<Self> {
+        let root = db_path.join(NAMESPACES_DIR);
+        std::fs::create_dir_all(&root)?;
+        let registry = Registry::open(&root)?;
+
+        let mut namespaces = BTreeMap::new();
+        for entry in std::fs::read_dir(&root)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if name.starts_with(REGISTRY_FILE) {
+                continue;
+            }
+            if !entry.file_type()?.is_dir() || validate_name(&name).is_err() {
+                tracing::warn!("ignoring unexpected entry `{name}` in the namespaces directory");
+                continue;
+            }
+            let namespace = make_namespace(entry.path()).await?;
+            namespace
+                .quota
+                .set(registry.quotas(&name)?, &namespace.config_store);
+            namespaces.insert(name, Arc::new(namespace));
+        }
+        tracing::info!("opened {} namespaces", namespaces.len());
+
+        Ok(Self {
+            root,
+            make_namespace,
+            registry,
+            namespaces: RwLock::new(namespaces),
+            create_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Opens the namespaces of `db_path` with the default settings.
+    #[cfg(test)]
+    pub async fn open_test(db_path: &Path) -> anyhow::Result<Self> {
+        use futures::FutureExt;
+
+        use crate::database::libsql::LibSqlDbFactory;
+        use crate::database::settings::SessionConfig;
+        use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
+        use crate::stats::Stats;
+
+        let make_namespace: MakeNamespace = Box::new(|path: PathBuf| {
+            async move {
+                let logger = ReplicationLogger::open(&path, 0, None, false, Box::new(|_| Ok(())))?;
+                let logger = Arc::new(logger);
+                let config_store = Arc::new(DatabaseConfigStore::load(&path)?);
+                let db_factory = LibSqlDbFactory::new(
+                    path,
+                    &REPLICATION_METHODS,
+                    {
+                        let logger = logger.clone();
+                        move || ReplicationLoggerHookCtx::new(logger.clone(), None)
+                    },
+                    Stats::default(),
+                    config_store.clone(),
+                    Vec::new(),
+                    u64::MAX,
+                    None,
+                    None,
+                    None,
+                    Some(logger.new_frame_notifier.subscribe()),
+                    SessionConfig::default(),
+                    None,
+                )
+                .await?
+                .throttled(8, None);
+                Ok::<_, anyhow::Error>(Namespace {
+                    db_factory: Arc::new(db_factory),
+                    logger,
+                    config_store,
+                    quota: Quota::default(),
+                })
+            }
+            .boxed()
+        });
+        Self::open(db_path, make_namespace).await
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Namespace>, NamespaceError> {
+        self.namespaces
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| NamespaceError::NotFound(name.to_string()))
+    }
+
+    pub async fn create(&self, name: &str) -> Result<(), NamespaceError> {
+        validate_name(name)?;
+        let _guard = self.create_lock.lock().await;
+        if self.namespaces.read().contains_key(name) {
+            return Err(NamespaceError::AlreadyExists(name.to_string()));
+        }
+
+        // the files of a namespace removed by hand are gone, but its quotas are kept, as they would
+        // be on restart
+        let quotas = self.registry.quotas(name)?;
+        let path = self.root.join(name);
+        std::fs::create_dir(&path).map_err(anyhow::Error::from)?;
+        match (self.make_namespace)(path.clone()).await {
+            Ok(namespace) => {
+                namespace.quota.set(quotas, &namespace.config_store);
+                self.namespaces
+                    .write()
+                    .insert(name.to_string(), Arc::new(namespace));
+                tracing::info!("created namespace `{name}`");
+                Ok(())
+            }
+            Err(e) => {
+                // don't leave a half-created namespace behind, it would be opened on restart
+                let _ = std::fs::remove_dir_all(&path);
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<NamespaceInfo> {
+        self.namespaces
+            .read()
+            .iter()
+            .map(|(name, namespace)| {
+                let frame_no = *namespace.logger.new_frame_notifier.borrow();
+                NamespaceInfo {
+                    name: name.clone(),
+                    frame_no: (frame_no != FrameNo::MAX).then_some(frame_no),
+                    generation_id: namespace.logger.generation.id.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces the quotas of the namespace `name`, they apply to the requests in flight too.
+    pub fn set_quotas(&self, name: &str, quotas: NamespaceQuotas) -> Result<(), NamespaceError> {
+        quotas.validate()?;
+        let namespace = self.get(name)?;
+        self.registry.set_quotas(name, &quotas)?;
+        namespace.quota.set(quotas, &namespace.config_store);
+        tracing::info!("set the quotas of namespace `{name}`: {quotas:?}");
+        Ok(())
+    }
+
+    pub fn stats(&self, name: &str) -> Result<NamespaceStats, NamespaceError> {
+        let namespace = self.get(name)?;
+        let path = self.root.join(name);
+        let file_size = |file: &str| std::fs::metadata(path.join(file)).map_or(0, |m| m.len());
+        Ok(NamespaceStats {
+            name: name.to_string(),
+            quotas: namespace.quota.quotas(),
+            db_size: file_size("data") + file_size("data-wal"),
+            storage_bytes: disk_usage(&path).map_err(anyhow::Error::from)?,
+            connections: namespace.quota.connections.load(Ordering::SeqCst),
+            rejected_requests: namespace.quota.rejected_requests.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Compacts the replication logs of the namespaces that need it.
+    pub fn maybe_compact(&self) -> anyhow::Result<()> {
+        let loggers: Vec<_> = self
+            .namespaces
+            .read()
+            .values()
+            .map(|namespace| namespace.logger.clone())
+            .collect();
+        for logger in loggers {
+            logger.maybe_compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges the snapshots of the namespaces that `retention` doesn't keep, but those that the
+    /// replicas of each namespace, which need the frames from `pinned(logger)` on, may still be
+    /// transferring.
+    pub fn retain_snapshots(
+        &self,
+        retention: SnapshotRetention,
+        pinned: impl Fn(&Arc<ReplicationLogger>) -> Option<FrameNo>,
+    ) -> anyhow::Result<()> {
+        let loggers: Vec<_> = self
+            .namespaces
+            .read()
+            .values()
+            .map(|namespace| namespace.logger.clone())
+            .collect();
+        for logger in loggers {
+            logger.retain_snapshots(retention, pinned(&logger))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::{Authenticated, Authorized};
+    use crate::database::{Database, Program};
+    use crate::query_result_builder::IgnoreResult;
+
+    use super::*;
+
+    #[test]
+    fn namespace_names() {
+        for name in ["tenant", "tenant-1", "Tenant_2", &"a".repeat(64)] {
+            assert!(validate_name(name).is_ok(), "{name}");
+        }
+        for name in ["", "..", "a/b", "a b", "tenant.db", &"a".repeat(65)] {
+            assert!(
+                matches!(validate_name(name), Err(NamespaceError::InvalidName(_))),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn create_and_reopen_namespaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = NamespaceStore::open_test(tmp.path()).await.unwrap();
+        assert!(matches!(
+            store.get("tenant"),
+            Err(NamespaceError::NotFound(_))
+        ));
+        // a namespace is not created by looking it up
+        assert!(!tmp.path().join("namespaces/tenant").exists());
+
+        store.create("tenant").await.unwrap();
+        assert!(matches!(
+            store.create("tenant").await,
+            Err(NamespaceError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            store.create("../tenant").await,
+            Err(NamespaceError::InvalidName(_))
+        ));
+
+        let db = store
+            .get("tenant")
+            .unwrap()
+            .db_factory
+            .create()
+            .await
+            .unwrap();
+        db.execute_program(
+            Program::seq(&["CREATE TABLE t (x)", "INSERT INTO t VALUES (1)"]),
+            Authenticated::Authorized(Authorized::FullAccess),
+            IgnoreResult,
+        )
+        .await
+        .unwrap();
+        drop(db);
+        assert!(tmp.path().join("namespaces/tenant/data").exists());
+        assert!(tmp.path().join("namespaces/tenant/wallog").exists());
+        // the default database is left alone
+        assert!(!tmp.path().join("data").exists());
+
+        let namespaces = store.list();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0].name, "tenant");
+        assert!(namespaces[0].frame_no.is_some());
+        drop(store);
+
+        let store = NamespaceStore::open_test(tmp.path()).await.unwrap();
+        let names: Vec<_> = store.list().into_iter().map(|ns| ns.name).collect();
+        assert_eq!(names, ["tenant"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quotas_are_persisted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = NamespaceStore::open_test(tmp.path()).await.unwrap();
+        store.create("tenant").await.unwrap();
+        let quotas = NamespaceQuotas {
+            max_db_size: Some(1 << 20),
+            max_connections: Some(10),
+            max_requests_per_sec: None,
+        };
+        store.set_quotas("tenant", quotas).unwrap();
+        assert!(matches!(
+            store.set_quotas("missing", quotas),
+            Err(NamespaceError::NotFound(_))
+        ));
+        for invalid in [
+            NamespaceQuotas {
+                max_db_size: Some(100),
+                ..Default::default()
+            },
+            NamespaceQuotas {
+                max_requests_per_sec: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                store.set_quotas("tenant", invalid),
+                Err(NamespaceError::InvalidQuotas(_))
+            ));
+        }
+        let namespace = store.get("tenant").unwrap();
+        assert_eq!(namespace.quota.quotas(), quotas);
+        assert_eq!(namespace.config_store.get().max_page_count, Some(256));
+        drop(namespace);
+        drop(store);
+
+        let store = NamespaceStore::open_test(tmp.path()).await.unwrap();
+        // the registry is not mistaken for a namespace
+        let names: Vec<_> = store.list().into_iter().map(|ns| ns.name).collect();
+        assert_eq!(names, ["tenant"]);
+        let stats = store.stats("tenant").unwrap();
+        assert_eq!(stats.quotas, quotas);
+        assert!(stats.storage_bytes > 0);
+        let namespace = store.get("tenant").unwrap();
+        assert_eq!(namespace.config_store.get().max_page_count, Some(256));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quotas_are_per_namespace() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = NamespaceStore::open_test(tmp.path()).await.unwrap();
+        store.create("a").await.unwrap();
+        store.create("b").await.unwrap();
+        store
+            .set_quotas(
+                "a",
+                NamespaceQuotas {
+                    max_connections: Some(2),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let a = store.get("a").unwrap();
+        let b = store.get("b").unwrap();
+
+        let permits = [a.quota.admit("a").unwrap(), a.quota.admit("a").unwrap()];
+        assert!(matches!(
+            a.quota.admit("a"),
+            Err(NamespaceError::TooManyConnections { max: 2, .. })
+        ));
+        // the other namespaces are still served
+        let _others: Vec<_> = (0..10).map(|_| b.quota.admit("b").unwrap()).collect();
+        drop(permits);
+        let _permit = a.quota.admit("a").unwrap();
+
+        let stats = store.stats("a").unwrap();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.rejected_requests, 1);
+        assert_eq!(store.stats("b").unwrap().rejected_requests, 0);
+    }
+
+    #[test]
+    fn rate_limited_requests() {
+        let quota = Quota::default();
+        *quota.quotas.write() = NamespaceQuotas {
+            max_requests_per_sec: Some(2),
+            ..Default::default()
+        };
+        quota.admit("a").unwrap();
+        quota.admit("a").unwrap();
+        match quota.admit("a") {
+            Err(NamespaceError::RateLimited { retry_in, .. }) => {
+                assert!(retry_in > Duration::ZERO && retry_in <= Duration::from_millis(500))
+            }
+            _ => panic!("the request should be rate limited"),
+        }
+        assert_eq!(quota.rejected_requests.load(Ordering::Relaxed), 1);
+    }
+}
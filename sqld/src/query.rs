@@ -131,9 +131,14 @@ impl Params {
                                 )?;
                                 self.get_pos(pos)
                             }
-                            _ => self
-                                .get_named(name)
-                                .or_else(|| self.get_named(chars.as_str())),
+                            _ => match self {
+                                Params::Named(_) => self
+                                    .get_named(name)
+                                    .or_else(|| self.get_named(chars.as_str())),
+                                // like SQLite, values in an array are bound by index, whatever
+                                // the prefix of the parameter
+                                Params::Positional(_) => self.get_pos(index),
+                            },
                         }
                     }
                     None => self.get_pos(index),
@@ -253,6 +258,21 @@ mod test {
         assert!(params.bind(&mut stmt).is_err());
     }
 
+    #[test]
+    fn test_bind_params_positional_values_named_params() {
+        let con = rusqlite::Connection::open_in_memory().unwrap();
+        let mut stmt = con.prepare("SELECT ?1 || :name").unwrap();
+        let params = Params::new_positional(vec![Value::Integer(10), Value::Text("hello".into())]);
+        params.bind(&mut stmt).unwrap();
+
+        assert_eq!(stmt.expanded_sql().unwrap(), "SELECT 10 || 'hello'");
+
+        let mut stmt = con.prepare("SELECT ?1 || :name").unwrap();
+        let params = Params::new_positional(vec![Value::Integer(10)]);
+        let err = params.bind(&mut stmt).unwrap_err();
+        assert!(err.to_string().contains(":name"), "{err}");
+    }
+
     #[test]
     fn test_bind_params_invalid_positional() {
         let con = rusqlite::Connection::open_in_memory().unwrap();
@@ -1,8 +1,135 @@
+use std::ffi::CString;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use fallible_iterator::FallibleIterator;
-use sqlite3_parser::ast::{Cmd, PragmaBody, QualifiedName, Stmt};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use sqlite3_parser::ast::{
+    AlterTableBody, Cmd, Expr, FromClause, InsertBody, JoinConstraint, Literal, OneSelect,
+    Operator, PragmaBody, QualifiedName, ResultColumn, Select, SelectTable, Stmt, UnaryOperator,
+};
 use sqlite3_parser::lexer::sql::{Parser, ParserError};
 
+use crate::database::attach::AttachCommand;
+use crate::database::settings::SettingCommand;
+
+/// Deepest nesting of parentheses accepted in SQL. The AST of the statement is walked recursively,
+/// so deeper nesting could overflow the stack.
+const MAX_NESTING_DEPTH: usize = 200;
+/// Longest time spent parsing the statements of an SQL string.
+const MAX_PARSE_TIME: Duration = Duration::from_secs(1);
+
+static PARSE_LIMITS: Lazy<RwLock<ParseLimits>> = Lazy::new(Default::default);
+static SQL_REJECTED_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of SQL strings rejected because they exceeded the parse limits.
+pub fn sql_rejected_total() -> u64 {
+    SQL_REJECTED_TOTAL.load(Ordering::Relaxed)
+}
+
+/// Sets the limits applied to all the SQL parsed by the node.
+pub fn set_parse_limits(limits: ParseLimits) {
+    *PARSE_LIMITS.write() = limits;
+}
+
+/// Limits on the SQL accepted from clients, so that a pathological statement can't exhaust the
+/// stack or the CPU of the node.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    pub max_sql_length: Option<usize>,
+    pub max_nesting_depth: usize,
+    pub max_parse_time: Duration,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_sql_length: None,
+            max_nesting_depth: MAX_NESTING_DEPTH,
+            max_parse_time: MAX_PARSE_TIME,
+        }
+    }
+}
+
+/// SQL rejected before or while it was parsed.
+#[derive(Debug, thiserror::Error)]
+pub enum ParseLimitError {
+    #[error("SQL string is {length} bytes long, the maximum is {max}")]
+    TooLong { length: usize, max: usize },
+    #[error("statement too complex: {reason}")]
+    TooComplex { reason: String },
+}
+
+impl ParseLimits {
+    /// Checks `sql` before it is parsed.
+    fn check(&self, sql: &str) -> Result<(), ParseLimitError> {
+        if let Some(max) = self.max_sql_length {
+            if sql.len() > max {
+                return Err(ParseLimitError::TooLong {
+                    length: sql.len(),
+                    max,
+                });
+            }
+        }
+
+        if nesting_depth(sql) > self.max_nesting_depth {
+            return Err(ParseLimitError::TooComplex {
+                reason: format!(
+                    "parentheses are nested more than {} levels deep",
+                    self.max_nesting_depth
+                ),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the deepest nesting of parentheses in `sql`, ignoring the ones in literals, quoted
+/// identifiers and comments.
+fn nesting_depth(sql: &str) -> usize {
+    let bytes = sql.as_bytes();
+    let mut max_depth = 0;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'(' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b')' => depth = depth.saturating_sub(1),
+            quote @ (b'\'' | b'"' | b'`' | b'[') => {
+                let end = if quote == b'[' { b']' } else { quote };
+                // doubled quotes are escaped quotes, so they can be skipped as two literals
+                i += bytes[i + 1..]
+                    .iter()
+                    .position(|&b| b == end)
+                    .map_or(bytes.len(), |n| n + 1);
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i += bytes[i..]
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .unwrap_or(bytes.len());
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += bytes[i + 2..]
+                    .windows(2)
+                    .position(|w| w == b"*/")
+                    .map_or(bytes.len(), |n| n + 3);
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    max_depth
+}
+
 /// A group of statements to be executed together.
 #[derive(Debug, Clone)]
 pub struct Statement {
@@ -11,6 +138,31 @@ pub struct Statement {
     /// Is the statement an INSERT, UPDATE or DELETE?
     pub is_iud: bool,
     pub is_insert: bool,
+    /// The parser didn't understand the statement, and it is passed to SQLite as is. Until SQLite
+    /// classifies it, the statement is conservatively considered a write, unless it is an `EXPLAIN`.
+    pub is_raw: bool,
+    /// Set if the statement reads or changes a setting of the session, rather than the database.
+    pub setting: Option<SettingCommand>,
+    /// The first literal value of the statement that could have been a parameter, if any.
+    pub inline_literal: Option<InlineLiteral>,
+    /// The rule of `--denied-statements` matched by the statement, if any.
+    pub denied_by: Option<DenyMatch>,
+    /// The class of the statement, checked against `--allowed-statement-classes`. Transaction
+    /// control statements and settings have none, and are always allowed.
+    pub class: Option<StmtClass>,
+    /// The table of the main database changed by an INSERT, UPDATE or DELETE, in lowercase.
+    pub written_table: Option<String>,
+    /// The savepoint of a SAVEPOINT, RELEASE or ROLLBACK TO statement, in lowercase.
+    pub savepoint: Option<String>,
+    /// Set for the `EXPLAIN` statements, which are read-only whatever the statement they explain.
+    pub explain: Option<Explain>,
+    /// Set for the `ATTACH` and `DETACH` statements, which are rewritten before they are executed.
+    pub attach: Option<AttachCommand>,
+    /// The statement is a `SELECT` whose results only depend on the contents of the database: it
+    /// doesn't read the temporary schema, and only calls deterministic built-in functions. The
+    /// unqualified tables of a statement may still be temporary tables created by a previous
+    /// statement of the same connection.
+    pub is_cacheable: bool,
 }
 
 impl Default for Statement {
@@ -19,6 +171,40 @@ impl Default for Statement {
     }
 }
 
+/// The output of an `EXPLAIN` statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Explain {
+    /// `EXPLAIN`: the bytecode of the statement.
+    Bytecode,
+    /// `EXPLAIN QUERY PLAN`: the steps of the query plan, with the `id` of their `parent`.
+    QueryPlan,
+}
+
+impl Explain {
+    fn of(cmd: &Cmd) -> Option<Self> {
+        match cmd {
+            Cmd::Explain(_) => Some(Self::Bytecode),
+            Cmd::ExplainQueryPlan(_) => Some(Self::QueryPlan),
+            Cmd::Stmt(_) => None,
+        }
+    }
+
+    /// Recognizes the `EXPLAIN` statements that the parser doesn't understand, from their first
+    /// words.
+    fn of_raw(stmt: &str) -> Option<Self> {
+        let mut words = stmt
+            .split_ascii_whitespace()
+            .map(|word| word.to_ascii_uppercase());
+        if words.next()? != "EXPLAIN" {
+            return None;
+        }
+        match (words.next().as_deref(), words.next().as_deref()) {
+            (Some("QUERY"), Some("PLAN")) => Some(Self::QueryPlan),
+            _ => Some(Self::Bytecode),
+        }
+    }
+}
+
 /// Classify statement in categories of interest.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum StmtKind {
@@ -26,6 +212,12 @@ pub enum StmtKind {
     TxnBegin,
     /// The end of a transaction
     TxnEnd,
+    /// A savepoint, which begins a transaction outside of one.
+    Savepoint,
+    /// The release of a savepoint, which commits the transaction if the savepoint began it.
+    Release,
+    /// A rollback to a savepoint, which leaves the transaction open.
+    RollbackTo,
     Read,
     Write,
     Other,
@@ -35,8 +227,85 @@ fn is_temp(name: &QualifiedName) -> bool {
     name.db_name.as_ref().map(|n| n.0.as_str()) == Some("TEMP")
 }
 
+/// Returns the table changed by an INSERT, UPDATE or DELETE, unless it is in an attached or
+/// temporary database.
+fn written_table(c: &Cmd) -> Option<String> {
+    let name = match c {
+        Cmd::Stmt(
+            Stmt::Insert { tbl_name, .. }
+            | Stmt::Update { tbl_name, .. }
+            | Stmt::Delete { tbl_name, .. },
+        ) => tbl_name,
+        _ => return None,
+    };
+    match name.db_name.as_ref() {
+        Some(db) if !unquote(&db.0).eq_ignore_ascii_case("main") => None,
+        _ => Some(unquote(&name.name.0).to_lowercase()),
+    }
+}
+
+/// Returns the `ATTACH` or `DETACH` command of `c`. The attached database must be named with a
+/// string literal, and the schema with an identifier or a string literal.
+fn attach_command(c: &Cmd) -> Result<Option<AttachCommand>> {
+    fn name(expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Id(id) => Some(unquote(&id.0)),
+            Expr::Name(name) => Some(unquote(&name.0)),
+            Expr::Literal(Literal::String(s)) => Some(unquote(s)),
+            _ => None,
+        }
+    }
+
+    let command = match c {
+        Cmd::Stmt(Stmt::Attach { expr, db_name, key }) => {
+            if key.is_some() {
+                anyhow::bail!("ATTACH doesn't support keys");
+            }
+            let Expr::Literal(Literal::String(db)) = expr else {
+                anyhow::bail!("ATTACH expects the name of an attachable database, as a string")
+            };
+            let alias =
+                name(db_name).ok_or_else(|| anyhow::anyhow!("ATTACH expects a schema name"))?;
+            AttachCommand::Attach {
+                name: unquote(db),
+                alias,
+            }
+        }
+        Cmd::Stmt(Stmt::Detach(db_name)) => AttachCommand::Detach {
+            alias: name(db_name).ok_or_else(|| anyhow::anyhow!("DETACH expects a schema name"))?,
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(command))
+}
+
+/// Strips the quotes around an identifier, as written in the SQL.
+fn unquote(ident: &str) -> String {
+    let quotes = [('"', '"'), ('`', '`'), ('[', ']'), ('\'', '\'')];
+    for (open, close) in quotes {
+        if let Some(inner) = ident
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+        {
+            return if open == '[' {
+                inner.to_string()
+            } else {
+                inner.replace(&format!("{close}{close}"), &close.to_string())
+            };
+        }
+    }
+
+    ident.to_string()
+}
+
 fn is_reserved_tbl(name: &QualifiedName) -> bool {
-    let n = name.name.0.to_lowercase();
+    is_reserved_table(&name.name.0)
+}
+
+/// Whether `name` is a table of sqld or its tools, that the clients can't write to.
+pub fn is_reserved_table(name: &str) -> bool {
+    let n = name.to_lowercase();
     n == "_litestream_seq" || n == "_litestream_lock" || n == "libsql_wasm_func_table"
 }
 
@@ -47,10 +316,15 @@ fn write_if_not_reserved(name: &QualifiedName) -> Option<StmtKind> {
 impl StmtKind {
     fn kind(cmd: &Cmd) -> Option<Self> {
         match cmd {
-            Cmd::Explain(Stmt::Pragma(name, body)) => Self::pragma_kind(name, body.as_ref()),
-            Cmd::Explain(_) => Some(Self::Other),
-            Cmd::ExplainQueryPlan(_) => Some(Self::Other),
+            // the explained statement is only compiled, never executed
+            Cmd::Explain(_) | Cmd::ExplainQueryPlan(_) => Some(Self::Read),
             Cmd::Stmt(Stmt::Begin { .. }) => Some(Self::TxnBegin),
+            Cmd::Stmt(Stmt::Savepoint(_)) => Some(Self::Savepoint),
+            Cmd::Stmt(Stmt::Release(_)) => Some(Self::Release),
+            Cmd::Stmt(Stmt::Rollback {
+                savepoint_name: Some(_),
+                ..
+            }) => Some(Self::RollbackTo),
             Cmd::Stmt(Stmt::Commit { .. } | Stmt::Rollback { .. }) => Some(Self::TxnEnd),
             Cmd::Stmt(
                 Stmt::CreateVirtualTable { tbl_name, .. }
@@ -91,7 +365,17 @@ impl StmtKind {
                 }
                 | Stmt::CreateIndex { .. },
             ) => Some(Self::Write),
+            Cmd::Stmt(
+                Stmt::CreateView {
+                    temporary: false,
+                    view_name,
+                    ..
+                }
+                | Stmt::DropView { view_name, .. },
+            ) if !is_temp(view_name) => Some(Self::Write),
             Cmd::Stmt(Stmt::Select { .. }) => Some(Self::Read),
+            // the databases are attached read-only, and each node attaches its own files
+            Cmd::Stmt(Stmt::Attach { .. } | Stmt::Detach(_)) => Some(Self::Read),
             Cmd::Stmt(Stmt::Pragma(name, body)) => Self::pragma_kind(name, body.as_ref()),
             _ => None,
         }
@@ -164,25 +448,714 @@ impl StmtKind {
     }
 }
 
-/// The state of a transaction for a series of statement
+/// A literal value in a position where a parameter could have been used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineLiteral {
+    /// The literal, as written in the statement.
+    pub literal: String,
+    pub position: LiteralPosition,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LiteralPosition {
+    /// In a row of the `VALUES` of an `INSERT`.
+    InsertValues,
+    /// In the new value of a column, in an `UPDATE`.
+    UpdateSet { column: String },
+    /// In a comparison of a `WHERE` clause.
+    Where,
+}
+
+impl fmt::Display for InlineLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let position = match &self.position {
+            LiteralPosition::InsertValues => "the VALUES of the INSERT".to_string(),
+            LiteralPosition::UpdateSet { column } => format!("the new value of column `{column}`"),
+            LiteralPosition::Where => "the WHERE clause".to_string(),
+        };
+        write!(
+            f,
+            "literal `{}` in {position}, use a parameter (such as `?`) instead",
+            self.literal
+        )
+    }
+}
+
+impl InlineLiteral {
+    /// Finds the first literal of `cmd` that could be a parameter: in the rows of an `INSERT`, the
+    /// values of an `UPDATE`, and the comparisons of `WHERE` clauses. The literals of DDL
+    /// statements, pragmas, and `LIMIT` and `OFFSET` clauses are not reported.
+    fn find(cmd: &Cmd) -> Option<Self> {
+        let Cmd::Stmt(stmt) = cmd else {
+            return None
+        };
+
+        match stmt {
+            Stmt::Insert {
+                body: InsertBody::Select(select, _),
+                ..
+            } => select_literal(select, true),
+            Stmt::Update {
+                sets, where_clause, ..
+            } => sets
+                .iter()
+                .find_map(|set| {
+                    let literal = value_literal(&set.expr)?;
+                    let column = set
+                        .col_names
+                        .iter()
+                        .map(|name| name.0.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    Some(Self {
+                        literal,
+                        position: LiteralPosition::UpdateSet { column },
+                    })
+                })
+                .or_else(|| where_clause.as_ref().and_then(where_literal)),
+            Stmt::Delete { where_clause, .. } => where_clause.as_ref().and_then(where_literal),
+            Stmt::Select(select) => select_literal(select, false),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the first literal in the `WHERE` clauses of `select`, or in its `VALUES` rows if it is
+/// the body of an `INSERT`.
+fn select_literal(select: &Select, is_insert: bool) -> Option<InlineLiteral> {
+    let compounds = select.body.compounds.iter().flatten().map(|c| &c.select);
+    std::iter::once(&select.body.select)
+        .chain(compounds)
+        .find_map(|one| match one {
+            OneSelect::Select { where_clause, .. } => where_clause.as_ref().and_then(where_literal),
+            OneSelect::Values(rows) if is_insert => rows
+                .iter()
+                .flatten()
+                .find_map(value_literal)
+                .map(|literal| InlineLiteral {
+                    literal,
+                    position: LiteralPosition::InsertValues,
+                }),
+            OneSelect::Values(_) => None,
+        })
+}
+
+/// Returns the first literal compared to something in the `WHERE` clause `expr`.
+fn where_literal(expr: &Expr) -> Option<InlineLiteral> {
+    let literal = match expr {
+        Expr::Binary(lhs, Operator::And | Operator::Or, rhs) => {
+            return where_literal(lhs).or_else(|| where_literal(rhs))
+        }
+        Expr::Unary(_, expr) => return where_literal(expr),
+        Expr::Parenthesized(exprs) => return exprs.iter().find_map(where_literal),
+        Expr::Exists(select) | Expr::Subquery(select) => return select_literal(select, false),
+        Expr::InSelect { lhs, rhs, .. } => {
+            return value_literal(lhs)
+                .map(|literal| InlineLiteral {
+                    literal,
+                    position: LiteralPosition::Where,
+                })
+                .or_else(|| select_literal(rhs, false))
+        }
+        Expr::Binary(
+            lhs,
+            Operator::Equals
+            | Operator::NotEquals
+            | Operator::Less
+            | Operator::LessEquals
+            | Operator::Greater
+            | Operator::GreaterEquals
+            | Operator::Is
+            | Operator::IsNot,
+            rhs,
+        ) => value_literal(lhs).or_else(|| value_literal(rhs)),
+        Expr::Between {
+            lhs, start, end, ..
+        } => [lhs, start, end].into_iter().find_map(|e| value_literal(e)),
+        Expr::InList { lhs, rhs, .. } => {
+            value_literal(lhs).or_else(|| rhs.iter().flatten().find_map(value_literal))
+        }
+        Expr::Like { lhs, rhs, .. } => value_literal(lhs).or_else(|| value_literal(rhs)),
+        _ => None,
+    }?;
+
+    Some(InlineLiteral {
+        literal,
+        position: LiteralPosition::Where,
+    })
+}
+
+/// Returns the first literal value in `expr`. `NULL` and the `CURRENT_*` keywords are not values
+/// that a client would interpolate, and are not reported.
+fn value_literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Literal(Literal::Numeric(n)) => Some(n.clone()),
+        Expr::Literal(Literal::String(s)) => Some(s.clone()),
+        Expr::Literal(Literal::Blob(b)) => Some(format!("X'{b}'")),
+        Expr::Literal(_) => None,
+        Expr::Binary(lhs, _, rhs) => value_literal(lhs).or_else(|| value_literal(rhs)),
+        Expr::Unary(_, expr) | Expr::Collate(expr, _) | Expr::Cast { expr, .. } => {
+            value_literal(expr)
+        }
+        Expr::IsNull(expr) | Expr::NotNull(expr) => value_literal(expr),
+        Expr::Parenthesized(exprs) => exprs.iter().find_map(value_literal),
+        Expr::FunctionCall { args, .. } => args.iter().flatten().find_map(value_literal),
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => base
+            .iter()
+            .map(|e| &**e)
+            .chain(when_then_pairs.iter().flat_map(|(w, t)| [w, t]))
+            .chain(else_expr.iter().map(|e| &**e))
+            .find_map(value_literal),
+        Expr::Between {
+            lhs, start, end, ..
+        } => [lhs, start, end].into_iter().find_map(|e| value_literal(e)),
+        Expr::InList { lhs, rhs, .. } => {
+            value_literal(lhs).or_else(|| rhs.iter().flatten().find_map(value_literal))
+        }
+        Expr::Like { lhs, rhs, .. } => value_literal(lhs).or_else(|| value_literal(rhs)),
+        _ => None,
+    }
+}
+
+/// Whether `c` is a `SELECT` that can be cached, see [`Statement::is_cacheable`]. The parts of the
+/// statement that are not walked, like the `FILTER` and `OVER` clauses, make it not cacheable.
+fn is_cacheable(c: &Cmd) -> bool {
+    match c {
+        Cmd::Stmt(Stmt::Select(select)) => cacheable_select(select),
+        _ => false,
+    }
+}
+
+fn cacheable_select(select: &Select) -> bool {
+    let compounds = select.body.compounds.iter().flatten().map(|c| &c.select);
+    select
+        .with
+        .iter()
+        .flat_map(|with| &with.ctes)
+        .all(|cte| cacheable_select(&cte.select))
+        && std::iter::once(&select.body.select)
+            .chain(compounds)
+            .all(cacheable_one_select)
+        && select
+            .order_by
+            .iter()
+            .flatten()
+            .all(|col| cacheable_expr(&col.expr))
+        && select.limit.iter().all(|limit| {
+            cacheable_expr(&limit.expr) && limit.offset.iter().all(|e| cacheable_expr(e))
+        })
+}
+
+fn cacheable_one_select(select: &OneSelect) -> bool {
+    match select {
+        OneSelect::Select {
+            columns,
+            from,
+            where_clause,
+            group_by,
+            ..
+        } => {
+            columns.iter().all(|col| match col {
+                ResultColumn::Expr(expr, _) => cacheable_expr(expr),
+                _ => true,
+            }) && from.iter().all(cacheable_from)
+                && where_clause.iter().all(|e| cacheable_expr(e))
+                && group_by.iter().all(|group_by| {
+                    group_by.exprs.iter().all(|e| cacheable_expr(e))
+                        && group_by.having.iter().all(|e| cacheable_expr(e))
+                })
+        }
+        OneSelect::Values(rows) => rows.iter().flatten().all(cacheable_expr),
+    }
+}
+
+fn cacheable_from(from: &FromClause) -> bool {
+    from.select.iter().all(|table| cacheable_table(table))
+        && from.joins.iter().flatten().all(|join| {
+            cacheable_table(&join.table)
+                && match &join.constraint {
+                    Some(JoinConstraint::On(expr)) => cacheable_expr(expr),
+                    _ => true,
+                }
+        })
+}
+
+fn cacheable_table(table: &SelectTable) -> bool {
+    match table {
+        SelectTable::Table(name, ..) => !is_temp_schema(name),
+        SelectTable::TableCall(name, args, ..) => {
+            !is_temp_schema(name)
+                && is_deterministic(&name.name.0)
+                && args.iter().flatten().all(cacheable_expr)
+        }
+        SelectTable::Select(select, ..) => cacheable_select(select),
+        SelectTable::Sub(from, ..) => cacheable_from(from),
+    }
+}
+
+fn cacheable_expr(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(Literal::CurrentDate | Literal::CurrentTime | Literal::CurrentTimestamp) => {
+            false
+        }
+        Expr::Literal(_)
+        | Expr::Id(_)
+        | Expr::Name(_)
+        | Expr::Qualified(..)
+        | Expr::Variable(_) => true,
+        Expr::DoublyQualified(db_name, ..) => !is_temp_db(&db_name.0),
+        Expr::FunctionCall {
+            name,
+            args,
+            filter_over,
+            ..
+        } => {
+            filter_over.is_none()
+                && is_deterministic(&name.0)
+                && args.iter().flatten().all(cacheable_expr)
+        }
+        Expr::FunctionCallStar { name, filter_over } => {
+            filter_over.is_none() && is_deterministic(&name.0)
+        }
+        Expr::Binary(lhs, _, rhs) => cacheable_expr(lhs) && cacheable_expr(rhs),
+        Expr::Unary(_, expr)
+        | Expr::Collate(expr, _)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::NotNull(expr) => cacheable_expr(expr),
+        Expr::Parenthesized(exprs) => exprs.iter().all(cacheable_expr),
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => base
+            .iter()
+            .map(|e| &**e)
+            .chain(when_then_pairs.iter().flat_map(|(w, t)| [w, t]))
+            .chain(else_expr.iter().map(|e| &**e))
+            .all(cacheable_expr),
+        Expr::Between {
+            lhs, start, end, ..
+        } => [lhs, start, end].into_iter().all(|e| cacheable_expr(e)),
+        Expr::InList { lhs, rhs, .. } => {
+            cacheable_expr(lhs) && rhs.iter().flatten().all(cacheable_expr)
+        }
+        Expr::InSelect { lhs, rhs, .. } => cacheable_expr(lhs) && cacheable_select(rhs),
+        Expr::InTable { lhs, rhs, args, .. } => {
+            cacheable_expr(lhs) && !is_temp_schema(rhs) && args.iter().flatten().all(cacheable_expr)
+        }
+        Expr::Exists(select) | Expr::Subquery(select) => cacheable_select(select),
+        Expr::Like {
+            lhs, rhs, escape, ..
+        } => cacheable_expr(lhs) && cacheable_expr(rhs) && escape.iter().all(|e| cacheable_expr(e)),
+        _ => false,
+    }
+}
+
+fn is_temp_db(name: &str) -> bool {
+    let name = unquote(name);
+    name.eq_ignore_ascii_case("temp") || name.eq_ignore_ascii_case("temporary")
+}
+
+/// Whether `name` is in the temporary schema, or is the table of the temporary schema.
+fn is_temp_schema(name: &QualifiedName) -> bool {
+    let table = unquote(&name.name.0).to_lowercase();
+    name.db_name.as_ref().map_or(false, |db| is_temp_db(&db.0))
+        || table == "sqlite_temp_master"
+        || table == "sqlite_temp_schema"
+}
+
+/// Whether the built-in function `name` always returns the same result for the same arguments.
+/// The date and time functions are not, as they may be given `'now'`, and neither are the
+/// functions of the extensions, which are unknown.
+fn is_deterministic(name: &str) -> bool {
+    matches!(
+        unquote(name).to_lowercase().as_str(),
+        "abs"
+            | "char"
+            | "coalesce"
+            | "concat"
+            | "concat_ws"
+            | "format"
+            | "glob"
+            | "hex"
+            | "ifnull"
+            | "iif"
+            | "instr"
+            | "length"
+            | "like"
+            | "likelihood"
+            | "likely"
+            | "lower"
+            | "ltrim"
+            | "max"
+            | "min"
+            | "nullif"
+            | "octet_length"
+            | "printf"
+            | "quote"
+            | "replace"
+            | "round"
+            | "rtrim"
+            | "sign"
+            | "soundex"
+            | "substr"
+            | "substring"
+            | "trim"
+            | "typeof"
+            | "unhex"
+            | "unicode"
+            | "unlikely"
+            | "upper"
+            | "zeroblob"
+            // aggregates
+            | "avg"
+            | "count"
+            | "group_concat"
+            | "string_agg"
+            | "sum"
+            | "total"
+            // math
+            | "acos"
+            | "acosh"
+            | "asin"
+            | "asinh"
+            | "atan"
+            | "atan2"
+            | "atanh"
+            | "ceil"
+            | "ceiling"
+            | "cos"
+            | "cosh"
+            | "degrees"
+            | "exp"
+            | "floor"
+            | "ln"
+            | "log"
+            | "log10"
+            | "log2"
+            | "mod"
+            | "pi"
+            | "pow"
+            | "power"
+            | "radians"
+            | "sin"
+            | "sinh"
+            | "sqrt"
+            | "tan"
+            | "tanh"
+            | "trunc"
+            // JSON
+            | "json"
+            | "json_array"
+            | "json_array_length"
+            | "json_each"
+            | "json_extract"
+            | "json_group_array"
+            | "json_group_object"
+            | "json_insert"
+            | "json_object"
+            | "json_patch"
+            | "json_quote"
+            | "json_remove"
+            | "json_replace"
+            | "json_set"
+            | "json_tree"
+            | "json_type"
+            | "json_valid"
+    )
+}
+
+/// Classes of statements that operators can deny with `--denied-statements`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum DenyRule {
+    /// `DROP TABLE`.
+    DropTable,
+    /// `DROP INDEX`.
+    DropIndex,
+    /// `DROP VIEW`.
+    DropView,
+    /// `DROP TRIGGER`.
+    DropTrigger,
+    /// `ALTER TABLE ... DROP COLUMN`.
+    AlterDropColumn,
+    /// `DELETE` without a `WHERE` clause, which empties the table.
+    TruncateLikeDelete,
+    /// `UPDATE` without a `WHERE` clause, which changes all the rows of the table.
+    UnqualifiedUpdate,
+    /// `ATTACH DATABASE`.
+    Attach,
+    /// `DETACH DATABASE`.
+    Detach,
+    /// `CREATE TRIGGER`.
+    CreateTrigger,
+}
+
+/// A rule of `--denied-statements` matched by a statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DenyMatch {
+    pub rule: DenyRule,
+    /// The statement only matches because its `WHERE` clause is always true, like `WHERE 1 = 1`:
+    /// it is only denied in strict mode.
+    pub tautology: bool,
+}
+
+impl DenyRule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DropTable => "drop_table",
+            Self::DropIndex => "drop_index",
+            Self::DropView => "drop_view",
+            Self::DropTrigger => "drop_trigger",
+            Self::AlterDropColumn => "alter_drop_column",
+            Self::TruncateLikeDelete => "truncate_like_delete",
+            Self::UnqualifiedUpdate => "unqualified_update",
+            Self::Attach => "attach",
+            Self::Detach => "detach",
+            Self::CreateTrigger => "create_trigger",
+        }
+    }
+
+    /// Finds the rule matched by `cmd`, if any.
+    fn find(cmd: &Cmd) -> Option<DenyMatch> {
+        let Cmd::Stmt(stmt) = cmd else {
+            return None
+        };
+        let unqualified = |rule, where_clause: &Option<Expr>| match where_clause {
+            None => Some(DenyMatch {
+                rule,
+                tautology: false,
+            }),
+            Some(expr) if const_truth(expr) == Some(true) => Some(DenyMatch {
+                rule,
+                tautology: true,
+            }),
+            Some(_) => None,
+        };
+
+        let rule = match stmt {
+            Stmt::DropTable { .. } => Self::DropTable,
+            Stmt::DropIndex { .. } => Self::DropIndex,
+            Stmt::DropView { .. } => Self::DropView,
+            Stmt::DropTrigger { .. } => Self::DropTrigger,
+            Stmt::AlterTable(_, AlterTableBody::DropColumn(_)) => Self::AlterDropColumn,
+            Stmt::Attach { .. } => Self::Attach,
+            Stmt::Detach(_) => Self::Detach,
+            Stmt::CreateTrigger { .. } => Self::CreateTrigger,
+            Stmt::Delete { where_clause, .. } => {
+                return unqualified(Self::TruncateLikeDelete, where_clause)
+            }
+            Stmt::Update { where_clause, .. } => {
+                return unqualified(Self::UnqualifiedUpdate, where_clause)
+            }
+            _ => return None,
+        };
+
+        Some(DenyMatch {
+            rule,
+            tautology: false,
+        })
+    }
+}
+
+impl fmt::Display for DenyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Classes of statements that operators can allow with `--allowed-statement-classes`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum StmtClass {
+    /// Statements that only read, like `SELECT` or `EXPLAIN`.
+    Read,
+    /// Statements that change the rows of the tables, like `INSERT`, `UPDATE` or `DELETE`.
+    Write,
+    /// Statements that change the schema: `CREATE`, `DROP` and `ALTER TABLE`.
+    Ddl,
+    /// `PRAGMA`.
+    Pragma,
+    /// `ATTACH DATABASE` and `DETACH DATABASE`.
+    Attach,
+    /// `VACUUM`.
+    Vacuum,
+}
+
+impl StmtClass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Ddl => "ddl",
+            Self::Pragma => "pragma",
+            Self::Attach => "attach",
+            Self::Vacuum => "vacuum",
+        }
+    }
+
+    /// The class of `cmd`, `None` for the statements controlling the transactions.
+    fn of(cmd: &Cmd) -> Option<Self> {
+        let stmt = match cmd {
+            Cmd::Explain(_) | Cmd::ExplainQueryPlan(_) => return Some(Self::Read),
+            Cmd::Stmt(stmt) => stmt,
+        };
+        let class = match stmt {
+            Stmt::Begin { .. }
+            | Stmt::Commit { .. }
+            | Stmt::Rollback { .. }
+            | Stmt::Savepoint(_)
+            | Stmt::Release(_) => return None,
+            Stmt::Select { .. } => Self::Read,
+            Stmt::CreateTable { .. }
+            | Stmt::CreateVirtualTable { .. }
+            | Stmt::CreateIndex { .. }
+            | Stmt::CreateView { .. }
+            | Stmt::CreateTrigger { .. }
+            | Stmt::DropTable { .. }
+            | Stmt::DropIndex { .. }
+            | Stmt::DropView { .. }
+            | Stmt::DropTrigger { .. }
+            | Stmt::AlterTable(..) => Self::Ddl,
+            Stmt::Pragma(..) => Self::Pragma,
+            Stmt::Attach { .. } | Stmt::Detach(_) => Self::Attach,
+            Stmt::Vacuum(..) => Self::Vacuum,
+            _ => Self::Write,
+        };
+
+        Some(class)
+    }
+
+    /// The class of a statement that the parser doesn't understand. A statement that mentions
+    /// `ATTACH`, `DETACH`, `PRAGMA` or `VACUUM` anywhere is assumed to be one, so that it is
+    /// denied when in doubt. Otherwise, it is a write until SQLite classifies it.
+    pub fn of_raw(stmt: &str) -> Self {
+        stmt.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .find_map(|word| match word.to_ascii_uppercase().as_str() {
+                "ATTACH" | "DETACH" => Some(Self::Attach),
+                "PRAGMA" => Some(Self::Pragma),
+                "VACUUM" => Some(Self::Vacuum),
+                _ => None,
+            })
+            .unwrap_or(Self::Write)
+    }
+}
+
+impl fmt::Display for StmtClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A constant value in a predicate made of literals.
+enum ConstValue {
+    Number(f64),
+    Text(String),
+}
+
+/// Evaluates `expr` if it is made only of literals, like `1 = 1`, `'a' <> 'b'` or `x OR 1`.
+fn const_truth(expr: &Expr) -> Option<bool> {
+    match expr {
+        Expr::Binary(lhs, Operator::Or, rhs) => match (const_truth(lhs), const_truth(rhs)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+        Expr::Binary(lhs, Operator::And, rhs) => match (const_truth(lhs), const_truth(rhs)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+        Expr::Binary(lhs, op, rhs) => {
+            let ordering = match (const_value(lhs)?, const_value(rhs)?) {
+                (ConstValue::Number(l), ConstValue::Number(r)) => l.partial_cmp(&r)?,
+                (ConstValue::Text(l), ConstValue::Text(r)) => l.cmp(&r),
+                // SQLite compares values of different types according to their affinity
+                _ => return None,
+            };
+            match op {
+                Operator::Equals | Operator::Is => Some(ordering.is_eq()),
+                Operator::NotEquals | Operator::IsNot => Some(ordering.is_ne()),
+                Operator::Less => Some(ordering.is_lt()),
+                Operator::LessEquals => Some(ordering.is_le()),
+                Operator::Greater => Some(ordering.is_gt()),
+                Operator::GreaterEquals => Some(ordering.is_ge()),
+                _ => None,
+            }
+        }
+        Expr::Unary(UnaryOperator::Not, expr) => const_truth(expr).map(|b| !b),
+        Expr::Parenthesized(exprs) if exprs.len() == 1 => const_truth(&exprs[0]),
+        expr => match const_value(expr)? {
+            ConstValue::Number(n) => Some(n != 0.0),
+            ConstValue::Text(s) => Some(s.trim().parse::<f64>().map_or(false, |n| n != 0.0)),
+        },
+    }
+}
+
+fn const_value(expr: &Expr) -> Option<ConstValue> {
+    match expr {
+        Expr::Literal(Literal::Numeric(n)) => n.parse().ok().map(ConstValue::Number),
+        Expr::Literal(Literal::String(s)) => {
+            let s = s
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+                .unwrap_or(s);
+            Some(ConstValue::Text(s.replace("''", "'")))
+        }
+        Expr::Id(id) if id.0.eq_ignore_ascii_case("true") => Some(ConstValue::Number(1.0)),
+        Expr::Id(id) if id.0.eq_ignore_ascii_case("false") => Some(ConstValue::Number(0.0)),
+        Expr::Unary(UnaryOperator::Positive, expr) => const_value(expr),
+        Expr::Unary(UnaryOperator::Negative, expr) => match const_value(expr)? {
+            ConstValue::Number(n) => Some(ConstValue::Number(-n)),
+            ConstValue::Text(_) => None,
+        },
+        Expr::Parenthesized(exprs) if exprs.len() == 1 => const_value(&exprs[0]),
+        // a comparison is worth 0 or 1
+        Expr::Binary(..) | Expr::Unary(UnaryOperator::Not, _) => {
+            const_truth(expr).map(|b| ConstValue::Number(if b { 1.0 } else { 0.0 }))
+        }
+        _ => None,
+    }
+}
+
+/// The state of a transaction for a series of statement. The state of a session, checked against
+/// SQLite after each statement, is tracked by [`crate::database::session_state::SessionState`].
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum State {
     /// The txn in an opened state
     Txn,
     /// The txn in a closed state
     Init,
-    /// This is an invalid state for the state machine
+    /// This is an invalid state for the state machine. For a session, a statement of its open
+    /// transaction failed, or the state is unknown.
     Invalid,
 }
 
 impl State {
+    /// Predicts the state after a statement of kind `kind` succeeds. Whether a RELEASE ends the
+    /// transaction depends on the savepoint it releases: the transaction is predicted to stay open,
+    /// see [`predict_final_state`] for the statements whose savepoints are known.
     pub fn step(&mut self, kind: StmtKind) {
         *self = match (*self, kind) {
             (State::Txn, StmtKind::TxnBegin) | (State::Init, StmtKind::TxnEnd) => State::Invalid,
             (State::Txn, StmtKind::TxnEnd) => State::Init,
-            (state, StmtKind::Other | StmtKind::Write | StmtKind::Read) => state,
+            // SQLite rejects the release of, or the rollback to, a savepoint that doesn't exist
+            (
+                state,
+                StmtKind::Other
+                | StmtKind::Write
+                | StmtKind::Read
+                | StmtKind::Release
+                | StmtKind::RollbackTo,
+            ) => state,
             (State::Invalid, _) => State::Invalid,
-            (State::Init, StmtKind::TxnBegin) => State::Txn,
+            (State::Init, StmtKind::TxnBegin | StmtKind::Savepoint) => State::Txn,
+            (State::Txn, StmtKind::Savepoint) => State::Txn,
         };
     }
 
@@ -199,10 +1172,85 @@ impl Statement {
             kind: StmtKind::Read,
             is_iud: false,
             is_insert: false,
+            is_raw: false,
+            setting: None,
+            inline_literal: None,
+            denied_by: None,
+            class: Some(StmtClass::Read),
+            written_table: None,
+            savepoint: None,
+            explain: None,
+            attach: None,
+            is_cacheable: false,
+        }
+    }
+
+    /// A statement that the parser doesn't understand, but SQLite may.
+    pub fn raw(stmt: &str) -> Self {
+        if let Some(setting) = SettingCommand::parse(stmt) {
+            return Self::setting(setting);
+        }
+
+        let explain = Explain::of_raw(stmt);
+        Self {
+            stmt: stmt.to_string(),
+            kind: match explain {
+                Some(_) => StmtKind::Read,
+                None => StmtKind::Write,
+            },
+            is_iud: false,
+            is_insert: false,
+            is_raw: true,
+            setting: None,
+            inline_literal: None,
+            // checked against the actions that SQLite authorizes when it is prepared
+            denied_by: None,
+            class: Some(match explain {
+                Some(_) => StmtClass::Read,
+                None => StmtClass::of_raw(stmt),
+            }),
+            written_table: None,
+            savepoint: None,
+            explain,
+            attach: None,
+            is_cacheable: false,
         }
     }
 
+    /// A statement handled by sqld itself, it doesn't touch the database, and can be served by
+    /// replicas.
+    pub fn setting(setting: SettingCommand) -> Self {
+        Self {
+            stmt: setting.to_string(),
+            kind: StmtKind::Read,
+            is_iud: false,
+            is_insert: false,
+            is_raw: false,
+            setting: Some(setting),
+            inline_literal: None,
+            denied_by: None,
+            class: None,
+            written_table: None,
+            savepoint: None,
+            explain: None,
+            attach: None,
+            is_cacheable: false,
+        }
+    }
+
+    /// Parses the statements of `s`, within the limits set with [`set_parse_limits`].
     pub fn parse(s: &str) -> impl Iterator<Item = Result<Self>> + '_ {
+        let limits = *PARSE_LIMITS.read();
+        Self::parse_with_limits(s, limits)
+    }
+
+    fn parse_with_limits(s: &str, limits: ParseLimits) -> impl Iterator<Item = Result<Self>> + '_ {
+        fn reject(e: ParseLimitError) -> anyhow::Error {
+            tracing::debug!("rejected SQL: {e}");
+            SQL_REJECTED_TOTAL.fetch_add(1, Ordering::Relaxed);
+            e.into()
+        }
+
         fn parse_inner(
             original: &str,
             stmt_count: u64,
@@ -220,6 +1268,16 @@ impl Statement {
                         kind,
                         is_iud: false,
                         is_insert: false,
+                        is_raw: false,
+                        setting: None,
+                        inline_literal: None,
+                        denied_by: None,
+                        class: StmtClass::of(&c),
+                        written_table: None,
+                        savepoint: None,
+                        explain: None,
+                        attach: None,
+                        is_cacheable: false,
                     });
                 }
             }
@@ -229,12 +1287,40 @@ impl Statement {
                 Cmd::Stmt(Stmt::Insert { .. } | Stmt::Update { .. } | Stmt::Delete { .. })
             );
             let is_insert = matches!(c, Cmd::Stmt(Stmt::Insert { .. }));
+            let inline_literal = InlineLiteral::find(&c);
+            let denied_by = DenyRule::find(&c);
+            let class = StmtClass::of(&c);
+            let written_table = written_table(&c);
+            let savepoint = match &c {
+                Cmd::Stmt(
+                    Stmt::Savepoint(name)
+                    | Stmt::Release(name)
+                    | Stmt::Rollback {
+                        savepoint_name: Some(name),
+                        ..
+                    },
+                ) => Some(unquote(&name.0).to_lowercase()),
+                _ => None,
+            };
+            let explain = Explain::of(&c);
+            let attach = attach_command(&c)?;
+            let is_cacheable = is_cacheable(&c);
 
             Ok(Statement {
                 stmt: c.to_string(),
                 kind,
                 is_iud,
                 is_insert,
+                is_raw: false,
+                setting: None,
+                inline_literal,
+                denied_by,
+                class,
+                written_table,
+                savepoint,
+                explain,
+                attach,
+                is_cacheable,
             })
         }
         // The parser needs to be boxed because it's large, and you don't want it on the stack.
@@ -244,46 +1330,753 @@ impl Statement {
         // - https://github.com/gwenn/lemon-rs/pull/19
         let mut parser = Box::new(Parser::new(s.as_bytes()).peekable());
         let mut stmt_count = 0;
+        // The part of `s` being parsed, the parser starts over after a statement it doesn't
+        // understand, and the number of statements parsed in it.
+        let mut rest = s;
+        let mut rest_count = 0;
+        // Set once a limit was hit, nothing is returned after the error.
+        let mut done = false;
+        let mut rejected = limits.check(s).err();
+        let started_at = Instant::now();
         std::iter::from_fn(move || {
+            if let Some(e) = rejected.take() {
+                done = true;
+                return Some(Err(reject(e)));
+            }
+
+            if done {
+                return None;
+            }
+
             stmt_count += 1;
-            match parser.next() {
-                Ok(Some(cmd)) => Some(parse_inner(
-                    s,
-                    stmt_count,
-                    parser.peek().map_or(true, |o| o.is_some()),
-                    cmd,
-                )),
+            let next = parser.next();
+            if started_at.elapsed() > limits.max_parse_time {
+                done = true;
+                return Some(Err(reject(ParseLimitError::TooComplex {
+                    reason: format!(
+                        "parsing took longer than {}ms",
+                        limits.max_parse_time.as_millis()
+                    ),
+                })));
+            }
+
+            match next {
+                Ok(Some(cmd)) => {
+                    rest_count += 1;
+                    Some(parse_inner(
+                        s,
+                        stmt_count,
+                        parser.peek().map_or(true, |o| o.is_some()),
+                        cmd,
+                    ))
+                }
                 Ok(None) => None,
-                Err(sqlite3_parser::lexer::sql::Error::ParserError(
-                    ParserError::SyntaxError {
-                        token_type: _,
-                        found: Some(found),
-                    },
-                    Some((line, col)),
-                )) => Some(Err(anyhow::anyhow!(
-                    "syntax error around L{line}:{col}: `{found}`"
-                ))),
-                Err(e) => Some(Err(e.into())),
+                Err(e) => {
+                    match e {
+                        sqlite3_parser::lexer::sql::Error::ParserError(
+                            ParserError::SyntaxError {
+                                token_type: _,
+                                found: Some(found),
+                            },
+                            Some((line, col)),
+                        ) => tracing::debug!(
+                            "syntax error around L{line}:{col}: `{found}`, falling back to a raw statement"
+                        ),
+                        e => tracing::debug!("{e}, falling back to a raw statement"),
+                    }
+                    // only the statement that failed is passed to SQLite as is, the parser starts
+                    // over after it, so that the next ones are still checked.
+                    let failed = split_statements(rest).get(rest_count).copied();
+                    let Some(failed) = failed else {
+                        done = true;
+                        return None;
+                    };
+                    let end = failed.as_ptr() as usize - rest.as_ptr() as usize + failed.len();
+                    rest = &rest[end..];
+                    rest_count = 0;
+                    parser = Box::new(Parser::new(rest.as_bytes()).peekable());
+                    Some(Ok(Statement::raw(failed)))
+                }
             }
         })
     }
 
+    /// Whether the statement begins a deferred transaction, which only takes the write lock on its
+    /// first write, unlike `BEGIN IMMEDIATE` and `BEGIN EXCLUSIVE`.
+    pub fn is_deferred_begin(&self) -> bool {
+        match self.kind {
+            StmtKind::Savepoint => true,
+            StmtKind::TxnBegin => {
+                let stmt = self.stmt.to_ascii_uppercase();
+                !stmt.contains("IMMEDIATE") && !stmt.contains("EXCLUSIVE")
+            }
+            _ => false,
+        }
+    }
+
     pub fn is_read_only(&self) -> bool {
         matches!(
             self.kind,
-            StmtKind::Read | StmtKind::TxnEnd | StmtKind::TxnBegin
+            StmtKind::Read
+                | StmtKind::TxnEnd
+                | StmtKind::TxnBegin
+                | StmtKind::Savepoint
+                | StmtKind::Release
+                | StmtKind::RollbackTo
         )
     }
 }
 
+/// Splits `s` into statements the way SQLite does, without parsing them.
+fn split_statements(s: &str) -> Vec<&str> {
+    fn is_complete(s: &str) -> bool {
+        match CString::new(s) {
+            Ok(s) => unsafe { rusqlite::ffi::sqlite3_complete(s.as_ptr()) != 0 },
+            Err(_) => false,
+        }
+    }
+
+    fn push(stmts: &mut Vec<&str>, stmt: &str) {
+        let stmt = stmt.trim_matches(|c: char| c == ';' || c.is_whitespace());
+        if !stmt.is_empty() {
+            stmts.push(stmt);
+        }
+    }
+
+    let mut stmts = Vec::new();
+    let mut start = 0;
+    for (i, _) in s.match_indices(';') {
+        if is_complete(&s[start..=i]) {
+            push(&mut stmts, &s[start..=i]);
+            start = i + 1;
+        }
+    }
+    // the last statement doesn't need to be terminated
+    push(&mut stmts, &s[start..]);
+
+    stmts
+}
+
 /// Given a an initial state and an array of queries, attempts to predict what the final state will
-/// be
+/// be. Like in SQLite, the release of the savepoint that began a transaction commits it.
 pub fn predict_final_state<'a>(
     mut state: State,
     stmts: impl Iterator<Item = &'a Statement>,
 ) -> State {
+    // the savepoints opened by `stmts`, and whether the outermost one began the transaction
+    let mut savepoints: Vec<&str> = Vec::new();
+    let mut began_txn = false;
     for stmt in stmts {
+        let savepoint = stmt.savepoint.as_deref().unwrap_or_default();
+        let position = savepoints.iter().rposition(|name| *name == savepoint);
+        match stmt.kind {
+            StmtKind::Savepoint => {
+                if state == State::Init {
+                    savepoints.clear();
+                    began_txn = true;
+                }
+                savepoints.push(savepoint);
+            }
+            StmtKind::Release => {
+                if let Some(position) = position {
+                    savepoints.truncate(position);
+                    if savepoints.is_empty() && began_txn && state == State::Txn {
+                        began_txn = false;
+                        state = State::Init;
+                        continue;
+                    }
+                }
+            }
+            StmtKind::RollbackTo => {
+                if let Some(position) = position {
+                    savepoints.truncate(position + 1);
+                }
+            }
+            StmtKind::TxnEnd => {
+                savepoints.clear();
+                began_txn = false;
+            }
+            _ => (),
+        }
         state.step(stmt.kind);
     }
     state
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_statements_like_sqlite() {
+        assert_eq!(
+            split_statements("select 1; select ';' ;; select 2"),
+            ["select 1", "select ';'", "select 2"]
+        );
+        assert_eq!(
+            split_statements(
+                "create trigger t after insert on x begin insert into y values (1); end; select 1;"
+            ),
+            [
+                "create trigger t after insert on x begin insert into y values (1); end",
+                "select 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn fallback_to_raw_statements() {
+        let stmts = Statement::parse("select 1; select from from; select 2")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(stmts.len(), 3);
+        assert!(!stmts[0].is_raw);
+        assert_eq!(stmts[0].kind, StmtKind::Read);
+        // statements that are not understood are considered writes until SQLite classifies them
+        assert!(stmts[1].is_raw);
+        assert_eq!(stmts[1].stmt, "select from from");
+        assert_eq!(stmts[1].kind, StmtKind::Write);
+        // the statements after it are still parsed
+        assert!(!stmts[2].is_raw);
+        assert_eq!(stmts[2].kind, StmtKind::Read);
+
+        let stmts = Statement::parse("select from from; select 1; select from from")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(stmts.len(), 3);
+        assert!(stmts[0].is_raw);
+        assert!(!stmts[1].is_raw);
+        assert!(stmts[2].is_raw);
+        assert_eq!(stmts[2].stmt, "select from from");
+    }
+
+    #[test]
+    fn deferred_begins() {
+        let deferred = |sql| {
+            Statement::parse(sql)
+                .next()
+                .unwrap()
+                .unwrap()
+                .is_deferred_begin()
+        };
+        assert!(deferred("begin"));
+        assert!(deferred("begin deferred transaction"));
+        assert!(deferred("savepoint s"));
+        assert!(!deferred("begin immediate"));
+        assert!(!deferred("BEGIN EXCLUSIVE"));
+        assert!(!deferred("select 1"));
+    }
+
+    #[test]
+    fn reject_deeply_nested_expressions() {
+        let depth = 100_000;
+        let sql = format!("select {}1{}", "(".repeat(depth), ")".repeat(depth));
+        let total = sql_rejected_total();
+        let mut stmts = Statement::parse_with_limits(&sql, ParseLimits::default());
+        let err = stmts.next().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ParseLimitError::TooComplex { .. })
+        ));
+        assert!(stmts.next().is_none());
+        assert!(sql_rejected_total() > total);
+
+        // parentheses in literals and comments are not nesting
+        let sql = format!(
+            "select '{}' /* {} */ -- {}\n, (1)",
+            "(".repeat(depth),
+            "(".repeat(depth),
+            "(".repeat(depth)
+        );
+        assert_eq!(nesting_depth(&sql), 1);
+        assert!(Statement::parse_with_limits(&sql, ParseLimits::default())
+            .collect::<Result<Vec<_>>>()
+            .is_ok());
+    }
+
+    #[test]
+    fn reject_long_sql() {
+        let limits = ParseLimits {
+            max_sql_length: Some(16),
+            ..Default::default()
+        };
+        assert!(Statement::parse_with_limits("select 1", limits)
+            .collect::<Result<Vec<_>>>()
+            .is_ok());
+        let err = Statement::parse_with_limits("select 1; select 2; select 3", limits)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ParseLimitError::TooLong {
+                length: 28,
+                max: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn setting_statements() {
+        let stmts =
+            Statement::parse("select 1; set application_name = 'worker'; show application_name")
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(stmts.len(), 3);
+        assert!(stmts[0].setting.is_none());
+        for stmt in &stmts[1..] {
+            assert!(stmt.setting.is_some());
+            assert!(!stmt.is_raw);
+            // settings don't touch the database
+            assert_eq!(stmt.kind, StmtKind::Read);
+        }
+    }
+
+    fn inline_literal(sql: &str) -> Option<InlineLiteral> {
+        Statement::parse(sql)
+            .next()
+            .unwrap()
+            .unwrap()
+            .inline_literal
+    }
+
+    #[test]
+    fn find_inline_literals() {
+        let literal = |literal: &str, position| {
+            Some(InlineLiteral {
+                literal: literal.into(),
+                position,
+            })
+        };
+
+        let cases = [
+            (
+                "INSERT INTO t (a, b) VALUES (?, 'x')",
+                literal("'x'", LiteralPosition::InsertValues),
+            ),
+            (
+                "INSERT INTO t VALUES (?, ?), (?, lower('X'))",
+                literal("'X'", LiteralPosition::InsertValues),
+            ),
+            (
+                "UPDATE t SET a = ?, b = 42 WHERE id = ?",
+                literal("42", LiteralPosition::UpdateSet { column: "b".into() }),
+            ),
+            (
+                "UPDATE t SET a = a + 1 WHERE id = ?",
+                literal("1", LiteralPosition::UpdateSet { column: "a".into() }),
+            ),
+            (
+                "UPDATE t SET a = ? WHERE id = 7",
+                literal("7", LiteralPosition::Where),
+            ),
+            (
+                "DELETE FROM t WHERE a = ? AND (b > 3 OR c = ?)",
+                literal("3", LiteralPosition::Where),
+            ),
+            (
+                "DELETE FROM t WHERE name LIKE 'a%'",
+                literal("'a%'", LiteralPosition::Where),
+            ),
+            (
+                "DELETE FROM t WHERE a IN (?, 2)",
+                literal("2", LiteralPosition::Where),
+            ),
+            (
+                "DELETE FROM t WHERE a BETWEEN ? AND 10",
+                literal("10", LiteralPosition::Where),
+            ),
+            (
+                "DELETE FROM t WHERE id IN (SELECT id FROM u WHERE name = 'bob')",
+                literal("'bob'", LiteralPosition::Where),
+            ),
+            (
+                "SELECT * FROM t WHERE a = x'00'",
+                literal("X'00'", LiteralPosition::Where),
+            ),
+            // no literal in a position where a parameter could be used
+            (
+                "INSERT INTO t VALUES (?, :b, NULL, CURRENT_TIMESTAMP)",
+                None,
+            ),
+            ("INSERT INTO t SELECT * FROM u WHERE a = ?", None),
+            ("INSERT INTO t DEFAULT VALUES", None),
+            ("UPDATE t SET a = NULL WHERE b IS NULL", None),
+            ("DELETE FROM t WHERE a = ? LIMIT 10", None),
+            (
+                "SELECT * FROM t WHERE a = ? ORDER BY b LIMIT 10 OFFSET 5",
+                None,
+            ),
+            ("SELECT 1, 'x' FROM t", None),
+            (
+                "CREATE TABLE t (a INTEGER DEFAULT 0, b TEXT CHECK (b != ''))",
+                None,
+            ),
+            ("CREATE INDEX i ON t (a) WHERE a > 0", None),
+            ("PRAGMA user_version", None),
+            ("BEGIN", None),
+        ];
+
+        for (sql, expected) in cases {
+            assert_eq!(inline_literal(sql), expected, "{sql}");
+        }
+    }
+
+    fn deny_match(sql: &str) -> Option<DenyMatch> {
+        let cmd = Parser::new(sql.as_bytes()).next().unwrap().unwrap();
+        DenyRule::find(&cmd)
+    }
+
+    #[test]
+    fn find_denied_statements() {
+        let denied = |rule| {
+            Some(DenyMatch {
+                rule,
+                tautology: false,
+            })
+        };
+
+        let cases = [
+            ("DROP TABLE t", denied(DenyRule::DropTable)),
+            ("DROP TABLE IF EXISTS main.t", denied(DenyRule::DropTable)),
+            ("CREATE TABLE t (a)", None),
+            ("DROP INDEX i", denied(DenyRule::DropIndex)),
+            ("CREATE INDEX i ON t (a)", None),
+            ("DROP VIEW v", denied(DenyRule::DropView)),
+            ("CREATE VIEW v AS SELECT * FROM t", None),
+            ("DROP TRIGGER tr", denied(DenyRule::DropTrigger)),
+            (
+                "ALTER TABLE t DROP COLUMN a",
+                denied(DenyRule::AlterDropColumn),
+            ),
+            ("ALTER TABLE t ADD COLUMN b", None),
+            ("ALTER TABLE t RENAME TO u", None),
+            ("DELETE FROM t", denied(DenyRule::TruncateLikeDelete)),
+            ("DELETE FROM t WHERE a = ?", None),
+            ("UPDATE t SET a = ?", denied(DenyRule::UnqualifiedUpdate)),
+            ("UPDATE t SET a = ? WHERE b = ?", None),
+            (
+                "ATTACH DATABASE 'other.db' AS other",
+                denied(DenyRule::Attach),
+            ),
+            ("DETACH DATABASE other", denied(DenyRule::Detach)),
+            (
+                "CREATE TRIGGER tr AFTER INSERT ON t BEGIN DELETE FROM u; END",
+                denied(DenyRule::CreateTrigger),
+            ),
+            ("SELECT * FROM t", None),
+            ("INSERT INTO t VALUES (?)", None),
+        ];
+
+        for (sql, expected) in cases {
+            assert_eq!(deny_match(sql), expected, "{sql}");
+        }
+    }
+
+    #[test]
+    fn statement_classes() {
+        let class = |sql: &str| {
+            let cmd = Parser::new(sql.as_bytes()).next().unwrap().unwrap();
+            StmtClass::of(&cmd)
+        };
+
+        let cases = [
+            ("SELECT * FROM t", Some(StmtClass::Read)),
+            ("EXPLAIN QUERY PLAN SELECT * FROM t", Some(StmtClass::Read)),
+            ("INSERT INTO t VALUES (?)", Some(StmtClass::Write)),
+            ("DELETE FROM t WHERE a = ?", Some(StmtClass::Write)),
+            ("CREATE TABLE t (a)", Some(StmtClass::Ddl)),
+            ("CREATE INDEX i ON t (a)", Some(StmtClass::Ddl)),
+            ("ALTER TABLE t ADD COLUMN b", Some(StmtClass::Ddl)),
+            ("DROP VIEW v", Some(StmtClass::Ddl)),
+            ("PRAGMA user_version", Some(StmtClass::Pragma)),
+            ("PRAGMA foreign_keys = 0", Some(StmtClass::Pragma)),
+            (
+                "ATTACH DATABASE 'other.db' AS other",
+                Some(StmtClass::Attach),
+            ),
+            ("DETACH DATABASE other", Some(StmtClass::Attach)),
+            ("VACUUM", Some(StmtClass::Vacuum)),
+            ("BEGIN", None),
+            ("SAVEPOINT s", None),
+            ("COMMIT", None),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(class(sql), expected, "{sql}");
+        }
+
+        // the statements that can't be parsed are denied when in doubt
+        assert_eq!(
+            StmtClass::of_raw("attach 'other.db' as \"weird name"),
+            StmtClass::Attach
+        );
+        assert_eq!(
+            StmtClass::of_raw("select 1 from pragma_table_list("),
+            StmtClass::Write
+        );
+        assert_eq!(
+            StmtClass::of_raw("pRaGmA journal_mode=off;"),
+            StmtClass::Pragma
+        );
+        assert_eq!(
+            StmtClass::of_raw("insert into t values (1"),
+            StmtClass::Write
+        );
+    }
+
+    #[test]
+    fn find_tautologies() {
+        let tautologies = [
+            "DELETE FROM t WHERE 1 = 1",
+            "DELETE FROM t WHERE 1",
+            "DELETE FROM t WHERE true",
+            "DELETE FROM t WHERE 'a' = 'a'",
+            "DELETE FROM t WHERE (2 > 1)",
+            "DELETE FROM t WHERE NOT 0",
+            "DELETE FROM t WHERE a = ? OR 1 = 1",
+            "DELETE FROM t WHERE 1 = 1 AND 2 <> 3",
+            "UPDATE t SET a = ? WHERE -1 < 0",
+        ];
+        for sql in tautologies {
+            assert!(
+                matches!(
+                    deny_match(sql),
+                    Some(DenyMatch {
+                        tautology: true,
+                        ..
+                    })
+                ),
+                "{sql}"
+            );
+        }
+
+        let predicates = [
+            "DELETE FROM t WHERE 1 = 0",
+            "DELETE FROM t WHERE 0",
+            "DELETE FROM t WHERE 'a' = 'b'",
+            "DELETE FROM t WHERE a = 1",
+            "DELETE FROM t WHERE a = a",
+            "DELETE FROM t WHERE a = ? AND 1 = 1",
+            "DELETE FROM t WHERE 1 = '1'",
+            "UPDATE t SET a = ? WHERE b OR 0",
+        ];
+        for sql in predicates {
+            assert_eq!(deny_match(sql), None, "{sql}");
+        }
+    }
+
+    #[test]
+    fn views_and_triggers_are_writes() {
+        let stmts = Statement::parse(
+            "create view v as select * from t; \
+            create trigger tr instead of insert on v begin insert into t values (new.x); end; \
+            insert into v values (1); \
+            drop view v",
+        )
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(stmts.len(), 4);
+        for stmt in stmts {
+            assert!(!stmt.is_raw);
+            assert_eq!(stmt.kind, StmtKind::Write, "{}", stmt.stmt);
+        }
+    }
+
+    #[test]
+    fn savepoint_statements() {
+        let parse = |sql: &str| Statement::parse(sql).next().unwrap().unwrap();
+        let savepoint = parse("SAVEPOINT \"Outer\"");
+        assert_eq!(savepoint.kind, StmtKind::Savepoint);
+        assert_eq!(savepoint.savepoint.as_deref(), Some("outer"));
+        assert_eq!(parse("RELEASE SAVEPOINT a").kind, StmtKind::Release);
+        assert_eq!(parse("ROLLBACK TO a").kind, StmtKind::RollbackTo);
+        assert_eq!(
+            parse("ROLLBACK TRANSACTION TO SAVEPOINT a").kind,
+            StmtKind::RollbackTo
+        );
+        assert_eq!(parse("ROLLBACK").kind, StmtKind::TxnEnd);
+
+        let predict = |state, sql: &str| {
+            let stmts = Statement::parse(sql)
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+            predict_final_state(state, stmts.iter())
+        };
+        // a savepoint outside of a transaction begins one, and its release ends it
+        assert_eq!(predict(State::Init, "savepoint a"), State::Txn);
+        assert_eq!(predict(State::Init, "savepoint a; release a"), State::Init);
+        assert_eq!(
+            predict(State::Init, "savepoint a; savepoint b; release b"),
+            State::Txn
+        );
+        assert_eq!(
+            predict(State::Init, "savepoint a; savepoint b; release a"),
+            State::Init
+        );
+        assert_eq!(
+            predict(State::Init, "savepoint a; rollback to a; release a"),
+            State::Init
+        );
+        assert_eq!(
+            predict(
+                State::Init,
+                "savepoint a; savepoint b; rollback to a; release b"
+            ),
+            State::Txn
+        );
+        // nested in a transaction, savepoints leave it open
+        assert_eq!(
+            predict(State::Init, "begin; savepoint a; release a"),
+            State::Txn
+        );
+        assert_eq!(predict(State::Txn, "savepoint a; release a"), State::Txn);
+        assert_eq!(
+            predict(State::Init, "begin; savepoint a; rollback to a; commit"),
+            State::Init
+        );
+        // the release of an unknown savepoint doesn't invalidate the prediction
+        assert_eq!(predict(State::Init, "release a"), State::Init);
+        assert_eq!(predict(State::Txn, "release a"), State::Txn);
+        assert_eq!(predict(State::Init, "savepoint a; release b"), State::Txn);
+    }
+
+    #[test]
+    fn unsupported_statements_are_still_rejected() {
+        // the parser understands these, but they can't be allowed
+        assert!(Statement::parse("PRAGMA case_sensitive_like = 1")
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn written_tables() {
+        let written_table =
+            |sql: &str| Statement::parse(sql).next().unwrap().unwrap().written_table;
+        assert_eq!(
+            written_table("insert into Users values (1)").as_deref(),
+            Some("users")
+        );
+        assert_eq!(
+            written_table("update main.\"Order\" set x = 1").as_deref(),
+            Some("order")
+        );
+        assert_eq!(
+            written_table("delete from [my table] where id = 1").as_deref(),
+            Some("my table")
+        );
+        assert_eq!(written_table("insert into temp.t values (1)"), None);
+        assert_eq!(written_table("select * from t"), None);
+    }
+
+    #[test]
+    fn cacheable_statements() {
+        let cacheable = |sql: &str| Statement::parse(sql).next().unwrap().unwrap().is_cacheable;
+        assert!(cacheable("select * from t where id = ?"));
+        assert!(cacheable(
+            "with recent as (select * from t order by ts desc limit 10) \
+            select upper(name), count(*) from recent join u on u.id = recent.uid \
+            group by name having sum(x) > 1 order by 2 limit 5"
+        ));
+        assert!(cacheable(
+            "select json_extract(data, '$.a') from t where id in (select id from u)"
+        ));
+        assert!(cacheable("select coalesce(x, 0) from main.t"));
+
+        // non-deterministic functions
+        assert!(!cacheable("select random()"));
+        assert!(!cacheable("select * from t where x > abs(random())"));
+        assert!(!cacheable("select datetime('now')"));
+        assert!(!cacheable("select * from t where ts < current_timestamp"));
+        assert!(!cacheable(
+            "select * from t where id in (select id from u order by random() limit 1)"
+        ));
+        assert!(!cacheable("select last_insert_rowid()"));
+        assert!(!cacheable("select uuid4()"));
+        // the temporary schema
+        assert!(!cacheable("select * from temp.t"));
+        assert!(!cacheable("select * from \"TEMP\".t"));
+        assert!(!cacheable("select name from sqlite_temp_master"));
+        assert!(!cacheable("select * from t join temp.u on t.id = u.id"));
+        assert!(!cacheable("select temp.t.x from temp.t"));
+        // not selects
+        assert!(!cacheable("insert into t values (1)"));
+        assert!(!cacheable("explain select 1"));
+        assert!(!cacheable("pragma table_info(t)"));
+        assert!(!Statement::raw("select from from").is_cacheable);
+    }
+
+    #[test]
+    fn explain_statements_are_reads() {
+        let parse = |sql: &str| Statement::parse(sql).next().unwrap().unwrap();
+        let cases = [
+            ("EXPLAIN DELETE FROM t", Explain::Bytecode),
+            ("EXPLAIN QUERY PLAN SELECT * FROM t", Explain::QueryPlan),
+            (
+                "explain query plan insert into t values (1)",
+                Explain::QueryPlan,
+            ),
+            ("EXPLAIN DROP TABLE t", Explain::Bytecode),
+            ("EXPLAIN PRAGMA wal_checkpoint", Explain::Bytecode),
+        ];
+        for (sql, explain) in cases {
+            let stmt = parse(sql);
+            assert!(!stmt.is_raw, "{sql}");
+            assert_eq!(stmt.explain, Some(explain), "{sql}");
+            assert_eq!(stmt.kind, StmtKind::Read, "{sql}");
+            assert!(stmt.is_read_only(), "{sql}");
+            assert!(!stmt.is_iud, "{sql}");
+            assert_eq!(stmt.written_table, None, "{sql}");
+        }
+
+        // the statements that can't be parsed are recognized by their first words
+        let raw = Statement::raw("EXPLAIN QUERY PLAN select from from");
+        assert_eq!(raw.explain, Some(Explain::QueryPlan));
+        assert!(raw.is_read_only());
+        let raw = Statement::raw("explain\ndelete from from");
+        assert_eq!(raw.explain, Some(Explain::Bytecode));
+        assert!(raw.is_read_only());
+        assert!(!Statement::raw("delete from from").is_read_only());
+        assert_eq!(parse("DELETE FROM t WHERE a = ?").explain, None);
+    }
+
+    #[test]
+    fn attach_statements() {
+        let parse = |sql: &str| Statement::parse(sql).next().unwrap();
+        let attach = |name: &str, alias: &str| AttachCommand::Attach {
+            name: name.into(),
+            alias: alias.into(),
+        };
+        let cases = [
+            ("ATTACH 'reports' AS reports", attach("reports", "reports")),
+            (
+                "attach database 'reports' as \"My Reports\"",
+                attach("reports", "My Reports"),
+            ),
+            ("ATTACH DATABASE 'it''s' AS [r]", attach("it's", "r")),
+            // the name is looked up in the allowlist, it is never used as a path
+            (
+                "ATTACH '../../etc/passwd' AS x",
+                attach("../../etc/passwd", "x"),
+            ),
+            (
+                "DETACH DATABASE reports",
+                AttachCommand::Detach {
+                    alias: "reports".into(),
+                },
+            ),
+        ];
+        for (sql, command) in cases {
+            let stmt = parse(sql).unwrap();
+            assert_eq!(stmt.attach, Some(command), "{sql}");
+            assert_eq!(stmt.class, Some(StmtClass::Attach), "{sql}");
+            assert_eq!(stmt.kind, StmtKind::Read, "{sql}");
+        }
+
+        // the attached file must be named by a literal
+        assert!(parse("ATTACH 'a' || 'b' AS x").is_err());
+        assert!(parse("ATTACH readfile('x') AS x").is_err());
+        assert!(parse("ATTACH 'reports' AS reports KEY 'secret'").is_err());
+        assert_eq!(parse("SELECT 1").unwrap().attach, None);
+    }
+}
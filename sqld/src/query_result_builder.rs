@@ -6,6 +6,9 @@ use bytesize::ByteSize;
 use rusqlite::types::ValueRef;
 use serde::Serialize;
 use serde_json::ser::Formatter;
+use tokio_util::sync::CancellationToken;
+
+use crate::replication::FrameNo;
 
 #[derive(Debug)]
 pub enum QueryResultBuilderError {
@@ -112,8 +115,23 @@ pub trait QueryResultBuilder: Send + 'static {
     fn finish_row(&mut self) -> Result<(), QueryResultBuilderError>;
     /// end adding rows
     fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError>;
+    /// the replication index the results are consistent with. Called at most once, before
+    /// `finish`.
+    fn replication_index(&mut self, _index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
+    /// the results were computed by the primary on behalf of the replica, reads included. Called
+    /// at most once, before `finish`.
+    fn proxied(&mut self) -> Result<(), QueryResultBuilderError> {
+        Ok(())
+    }
     /// finish serialization.
     fn finish(&mut self) -> Result<(), QueryResultBuilderError>;
+    /// a token cancelled when the results are no longer wanted, like when the client went away.
+    /// The statement being executed is then interrupted.
+    fn cancellation(&self) -> Option<CancellationToken> {
+        None
+    }
     /// returns the inner ret
     fn into_ret(self) -> Self::Ret;
     /// Returns a `QueryResultBuilder` that wraps Self and takes at most `n` steps
@@ -122,9 +140,11 @@ pub trait QueryResultBuilder: Send + 'static {
         Self: Sized,
     {
         Take {
+            skip: 0,
             limit,
             count: 0,
             inner: self,
+            error_after: None,
         }
     }
 }
@@ -374,15 +394,34 @@ impl QueryResultBuilder for IgnoreResult {
 
 // A builder that wraps another builder, but takes at most `n` steps
 pub struct Take<B> {
+    skip: usize,
     limit: usize,
     count: usize,
     inner: B,
+    /// The error of the first step after the taken ones.
+    error_after: Option<crate::error::Error>,
 }
 
 impl<B> Take<B> {
+    /// Skips the first `n` steps, before taking the others.
+    pub fn skip(mut self, n: usize) -> Self {
+        self.skip = n;
+        self
+    }
+
     pub fn into_inner(self) -> B {
         self.inner
     }
+
+    /// Returns the inner builder, and the error of the first step after the taken ones, like the
+    /// `COMMIT` of a transaction.
+    pub fn into_inner_and_error_after(self) -> (B, Option<crate::error::Error>) {
+        (self.inner, self.error_after)
+    }
+
+    fn is_taken(&self) -> bool {
+        self.count >= self.skip && self.count < self.skip + self.limit
+    }
 }
 
 impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
@@ -390,11 +429,12 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
 
     fn init(&mut self, config: &QueryBuilderConfig) -> Result<(), QueryResultBuilderError> {
         self.count = 0;
+        self.error_after = None;
         self.inner.init(config)
     }
 
     fn begin_step(&mut self) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.begin_step()
         } else {
             Ok(())
@@ -406,19 +446,22 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
         affected_row_count: u64,
         last_insert_rowid: Option<i64>,
     ) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner
                 .finish_step(affected_row_count, last_insert_rowid)?;
-            self.count += 1;
         }
+        self.count += 1;
 
         Ok(())
     }
 
     fn step_error(&mut self, error: crate::error::Error) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.step_error(error)
         } else {
+            if self.count == self.skip + self.limit {
+                self.error_after = Some(error);
+            }
             Ok(())
         }
     }
@@ -427,7 +470,7 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
         &mut self,
         cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
     ) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.cols_description(cols)
         } else {
             Ok(())
@@ -435,7 +478,7 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
     }
 
     fn begin_rows(&mut self) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.begin_rows()
         } else {
             Ok(())
@@ -443,7 +486,7 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
     }
 
     fn begin_row(&mut self) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.begin_row()
         } else {
             Ok(())
@@ -451,7 +494,7 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
     }
 
     fn add_row_value(&mut self, v: ValueRef) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.add_row_value(v)
         } else {
             Ok(())
@@ -459,7 +502,7 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
     }
 
     fn finish_row(&mut self) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.finish_row()
         } else {
             Ok(())
@@ -467,17 +510,29 @@ impl<B: QueryResultBuilder> QueryResultBuilder for Take<B> {
     }
 
     fn finish_rows(&mut self) -> Result<(), QueryResultBuilderError> {
-        if self.count < self.limit {
+        if self.is_taken() {
             self.inner.finish_rows()
         } else {
             Ok(())
         }
     }
 
+    fn replication_index(&mut self, index: FrameNo) -> Result<(), QueryResultBuilderError> {
+        self.inner.replication_index(index)
+    }
+
+    fn proxied(&mut self) -> Result<(), QueryResultBuilderError> {
+        self.inner.proxied()
+    }
+
     fn finish(&mut self) -> Result<(), QueryResultBuilderError> {
         self.inner.finish()
     }
 
+    fn cancellation(&self) -> Option<CancellationToken> {
+        self.inner.cancellation()
+    }
+
     fn into_ret(self) -> Self::Ret {
         self.inner.into_ret()
     }
@@ -828,4 +883,29 @@ pub mod test {
         builder.begin_step().unwrap();
         builder.begin_rows().unwrap();
     }
+
+    #[test]
+    fn take_skipped_steps() {
+        let mut builder = StepResultsBuilder::default().take(2).skip(1);
+        builder.init(&QueryBuilderConfig::default()).unwrap();
+        for i in 0..4 {
+            builder.begin_step().unwrap();
+            if i == 3 {
+                builder
+                    .step_error(crate::error::Error::LibSqlTxBusy)
+                    .unwrap();
+            } else {
+                builder.cols_description([("hello", None)]).unwrap();
+            }
+            builder.finish_step(0, None).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let (builder, error_after) = builder.into_inner_and_error_after();
+        assert_eq!(builder.into_ret().len(), 2);
+        assert!(matches!(
+            error_after,
+            Some(crate::error::Error::LibSqlTxBusy)
+        ));
+    }
 }
@@ -0,0 +1,382 @@
+//! Rate limits of the queries of the clients, by source IP and by principal, with a budget for the
+//! reads and another one for the writes. The budgets are token buckets, which refill continuously
+//! and hold a second of queries, so that a client can send a short burst.
+//!
+//! The limiter is shared by the frontends: a client has the same budgets whichever protocol it
+//! uses. A query over budget fails at once with [`Error::RateLimited`], rather than waiting.
+//!
+//! The limits are replaced when the configuration is reloaded, and the budgets then start over.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context as _};
+use parking_lot::{Mutex, RwLock};
+
+use crate::error::Error;
+
+/// Above this number of clients, those whose budgets are full are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// The queries per second allowed to a client, written `reads/writes`. `0` doesn't limit them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+impl RateLimit {
+    fn rate(&self, write: bool) -> u32 {
+        if write {
+            self.writes
+        } else {
+            self.reads
+        }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (reads, writes) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected `reads/writes`, got `{s}`"))?;
+        let parse = |n: &str| {
+            n.trim()
+                .parse()
+                .with_context(|| format!("invalid number of queries per second `{n}`"))
+        };
+        Ok(Self {
+            reads: parse(reads)?,
+            writes: parse(writes)?,
+        })
+    }
+}
+
+/// The limit of a principal, which replaces the limit of all the principals, written
+/// `principal=reads/writes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrincipalRateLimit {
+    pub principal: String,
+    pub limit: RateLimit,
+}
+
+impl FromStr for PrincipalRateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (principal, limit) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected `principal=reads/writes`, got `{s}`"))?;
+        if principal.is_empty() {
+            return Err(anyhow!("missing principal in `{s}`"));
+        }
+        Ok(Self {
+            principal: principal.to_string(),
+            limit: limit.parse()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// The limit of each source IP.
+    pub per_ip: Option<RateLimit>,
+    /// The limit of each authenticated principal.
+    pub per_principal: Option<RateLimit>,
+    /// The limits of some principals, instead of `per_principal`.
+    pub principals: Vec<PrincipalRateLimit>,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.per_ip.is_some() || self.per_principal.is_some() || !self.principals.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Principal(String),
+}
+
+/// A budget of queries, refilled at `rate` queries per second, and holding a second of them.
+pub(crate) struct Bucket {
+    pub(crate) tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn full(rate: u32, now: Instant) -> Self {
+        Self {
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    pub(crate) fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.updated = now;
+    }
+}
+
+/// The budgets of a client.
+struct Budgets {
+    limit: RateLimit,
+    reads: Bucket,
+    writes: Bucket,
+}
+
+impl Budgets {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            reads: Bucket::full(limit.reads, now),
+            writes: Bucket::full(limit.writes, now),
+        }
+    }
+
+    fn bucket(&mut self, write: bool) -> &mut Bucket {
+        if write {
+            &mut self.writes
+        } else {
+            &mut self.reads
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.reads.refill(self.limit.reads, now);
+        self.writes.refill(self.limit.writes, now);
+        self.reads.tokens >= self.limit.reads as f64
+            && self.writes.tokens >= self.limit.writes as f64
+    }
+}
+
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    clients: Mutex<HashMap<Client, Budgets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the limits. The budgets of the clients are forgotten, and start full.
+    pub fn set_config(&self, config: RateLimitConfig) {
+        *self.config.write() = config;
+        self.clients.lock().clear();
+    }
+
+    /// Takes a query from the budgets of the client at `ip`, authenticated as `principal`. The
+    /// query is rejected with [`Error::RateLimited`] if any of the budgets is spent, and is then
+    /// taken from none of them.
+    pub fn check(
+        &self,
+        ip: Option<IpAddr>,
+        principal: Option<&str>,
+        write: bool,
+    ) -> Result<(), Error> {
+        let mut limited = Vec::with_capacity(2);
+        let config = self.config.read();
+        if let (Some(ip), Some(limit)) = (ip, config.per_ip) {
+            limited.push((Client::Ip(ip), limit));
+        }
+        if let Some(principal) = principal {
+            let limit = config
+                .principals
+                .iter()
+                .find(|p| p.principal == principal)
+                .map(|p| p.limit)
+                .or(config.per_principal);
+            if let Some(limit) = limit {
+                limited.push((Client::Principal(principal.to_string()), limit));
+            }
+        }
+        drop(config);
+        limited.retain(|(_, limit)| limit.rate(write) != 0);
+        if limited.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        if clients.len() > MAX_TRACKED_CLIENTS {
+            clients.retain(|_, budgets| !budgets.is_full(now));
+        }
+        let mut retry_in = Duration::ZERO;
+        for (client, limit) in &limited {
+            let rate = limit.rate(write);
+            let bucket = clients
+                .entry(client.clone())
+                .or_insert_with(|| Budgets::new(*limit, now))
+                .bucket(write);
+            bucket.refill(rate, now);
+            if bucket.tokens < 1.0 {
+                let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate as f64);
+                retry_in = retry_in.max(wait);
+            }
+        }
+        if !retry_in.is_zero() {
+            return Err(Error::RateLimited(retry_in));
+        }
+        for (client, _) in &limited {
+            if let Some(budgets) = clients.get_mut(client) {
+                budgets.bucket(write).tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!(
+            "100/10".parse::<RateLimit>().unwrap(),
+            RateLimit {
+                reads: 100,
+                writes: 10
+            }
+        );
+        assert!("100".parse::<RateLimit>().is_err());
+        assert!("100/x".parse::<RateLimit>().is_err());
+        assert_eq!(
+            "reporting=50/0".parse::<PrincipalRateLimit>().unwrap(),
+            PrincipalRateLimit {
+                principal: "reporting".into(),
+                limit: RateLimit {
+                    reads: 50,
+                    writes: 0
+                },
+            }
+        );
+        assert!("=50/0".parse::<PrincipalRateLimit>().is_err());
+    }
+
+    #[test]
+    fn budgets() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_ip: Some(RateLimit {
+                reads: 3,
+                writes: 1,
+            }),
+            per_principal: None,
+            principals: vec!["admin=0/0".parse().unwrap()],
+        });
+        let alice: IpAddr = [10, 0, 0, 1].into();
+        let bob: IpAddr = [10, 0, 0, 2].into();
+
+        // the reads and the writes have their own budgets
+        for _ in 0..3 {
+            limiter.check(Some(alice), None, false).unwrap();
+        }
+        let err = limiter.check(Some(alice), None, false).unwrap_err();
+        assert!(
+            matches!(err, Error::RateLimited(retry_in) if retry_in <= Duration::from_millis(334)),
+            "{err:?}"
+        );
+        limiter.check(Some(alice), None, true).unwrap();
+        assert!(limiter.check(Some(alice), None, true).is_err());
+
+        // the clients have their own budgets
+        limiter.check(Some(bob), None, true).unwrap();
+
+        // a rejected query takes from no budget: the principal's budget is unlimited here, but
+        // the IP's budget is spent
+        assert!(limiter.check(Some(alice), Some("admin"), true).is_err());
+        // the clients without an IP are only limited by their principal
+        limiter.check(None, Some("admin"), true).unwrap();
+    }
+
+    #[test]
+    fn principals() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_ip: None,
+            per_principal: Some(RateLimit {
+                reads: 1,
+                writes: 1,
+            }),
+            principals: vec!["batch=0/2".parse().unwrap()],
+        });
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        // the anonymous clients are not limited by principal
+        for _ in 0..10 {
+            limiter.check(Some(ip), None, true).unwrap();
+        }
+
+        limiter.check(Some(ip), Some("alice"), true).unwrap();
+        assert!(limiter.check(Some(ip), Some("alice"), true).is_err());
+        // the same principal from another address
+        assert!(limiter.check(None, Some("alice"), true).is_err());
+
+        // the override replaces the limit of all the principals
+        for _ in 0..10 {
+            limiter.check(Some(ip), Some("batch"), false).unwrap();
+        }
+        limiter.check(Some(ip), Some("batch"), true).unwrap();
+        limiter.check(Some(ip), Some("batch"), true).unwrap();
+        assert!(limiter.check(Some(ip), Some("batch"), true).is_err());
+    }
+
+    #[test]
+    fn refill() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_ip: Some(RateLimit {
+                reads: 20,
+                writes: 0,
+            }),
+            ..Default::default()
+        });
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        for _ in 0..20 {
+            limiter.check(Some(ip), None, false).unwrap();
+        }
+        let Err(Error::RateLimited(retry_in)) = limiter.check(Some(ip), None, false) else {
+            panic!("the budget should be spent");
+        };
+        std::thread::sleep(retry_in + Duration::from_millis(10));
+        limiter.check(Some(ip), None, false).unwrap();
+    }
+
+    #[test]
+    fn replaced_limits() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_ip: Some(RateLimit {
+                reads: 1,
+                writes: 1,
+            }),
+            ..Default::default()
+        });
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        limiter.check(Some(ip), None, false).unwrap();
+        assert!(limiter.check(Some(ip), None, false).is_err());
+
+        limiter.set_config(RateLimitConfig {
+            per_ip: Some(RateLimit {
+                reads: 2,
+                writes: 1,
+            }),
+            ..Default::default()
+        });
+        limiter.check(Some(ip), None, false).unwrap();
+        limiter.check(Some(ip), None, false).unwrap();
+        assert!(limiter.check(Some(ip), None, false).is_err());
+
+        limiter.set_config(RateLimitConfig::default());
+        for _ in 0..10 {
+            limiter.check(Some(ip), None, false).unwrap();
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Reloads of the configuration, on `SIGHUP` and through the admin API.
+//!
+//! The configuration is read again from its sources (the command line, the environment and the
+//! configuration file), and validated. The changes to the settings listed in [`RELOADABLE`] are
+//! applied at once: the authentication, the statement policies and the limits of the sessions,
+//! the rate limits and the checkpoints. The changes to the other settings are rejected, with a
+//! warning, and take effect on the next start of the server.
+//!
+//! The sessions keep the settings they were opened with, the new ones apply to the connections
+//! opened, or checked out of a pool, after the reload.
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::auth::Auth;
+use crate::database::checkpoint::CheckpointConfig;
+use crate::database::settings::SessionConfig;
+use crate::rate_limit::RateLimiter;
+use crate::utils::shared::Shared;
+use crate::Config;
+
+/// Reads the configuration again, from the same sources as on startup.
+pub type ConfigLoader = Box<dyn Fn() -> anyhow::Result<Config> + Send + Sync>;
+
+macro_rules! reloadable {
+    ($($field:ident),* $(,)?) => {
+        /// The settings that are applied by a reload, the others need a restart.
+        pub const RELOADABLE: &[&str] = &[$(stringify!($field)),*];
+
+        fn copy_reloadable(to: &mut Config, from: &Config) {
+            $(to.$field = from.$field.clone();)*
+        }
+    };
+}
+
+reloadable!(
+    http_auth,
+    admin_auth,
+    auth_jwt_key,
+    auth_jwt_audience,
+    auth_jwt_leeway,
+    denied_statements,
+    strict_denied_statements,
+    allowed_statement_classes,
+    read_only_allowed_statement_classes,
+    principal_statement_classes,
+    require_parameterized,
+    unknown_settings,
+    query_timeout,
+    txn_timeout,
+    max_response_rows,
+    max_response_bytes,
+    rate_limits,
+    checkpoint_interval,
+    max_wal_size,
+);
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct ReloadResult {
+    /// The settings that changed, and were applied.
+    pub applied: Vec<String>,
+    /// The settings that changed, but need a restart.
+    pub rejected: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("the configuration can't be reloaded, the server was not started from a configuration that can be read again")]
+    Unsupported,
+    #[error("invalid configuration, nothing was reloaded: {0:#}")]
+    Invalid(anyhow::Error),
+}
+
+/// The running configuration, and the settings of the services that a reload replaces.
+pub struct Reloader {
+    loader: Option<ConfigLoader>,
+    config: Mutex<Config>,
+    /// Set by the services once the JWKS is fetched, if any.
+    pub auth: Shared<Arc<Auth>>,
+    pub admin_auth: Shared<Arc<Auth>>,
+    pub session_config: Shared<SessionConfig>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub checkpoint_config: Shared<CheckpointConfig>,
+}
+
+impl Reloader {
+    pub fn new(config: Config, loader: Option<ConfigLoader>) -> Self {
+        Self {
+            loader,
+            auth: Shared::new(Arc::new(Auth::default())),
+            admin_auth: Shared::new(Arc::new(Auth::default())),
+            session_config: Shared::new(config.session_config()),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            checkpoint_config: Shared::new(config.checkpoint_config()),
+            config: Mutex::new(config),
+        }
+    }
+
+    pub fn can_reload(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    /// The running configuration, with the reloaded settings.
+    pub fn config(&self) -> Config {
+        self.config.lock().clone()
+    }
+
+    /// Reads the configuration again, and applies the changes to the reloadable settings. Nothing
+    /// is applied if the configuration is invalid.
+    pub fn reload(&self) -> Result<ReloadResult, ReloadError> {
+        let loader = self.loader.as_ref().ok_or(ReloadError::Unsupported)?;
+        let loaded = loader().map_err(ReloadError::Invalid)?;
+        loaded.validate().map_err(ReloadError::Invalid)?;
+
+        let mut config = self.config.lock();
+        let (applied, rejected): (Vec<_>, Vec<_>) = changed_settings(&config, &loaded)
+            .into_iter()
+            .partition(|name| RELOADABLE.contains(&name.as_str()));
+        let mut next = config.clone();
+        copy_reloadable(&mut next, &loaded);
+
+        // the keys of the JWKS are refreshed by the services, its URL needs a restart
+        let jwks = self.auth.get().jwks.clone();
+        let auth = crate::build_auth(&next, jwks).map_err(ReloadError::Invalid)?;
+        let admin_auth = crate::get_admin_auth(&next).map_err(ReloadError::Invalid)?;
+
+        self.auth.set(auth);
+        self.admin_auth.set(admin_auth);
+        self.session_config.set(next.session_config());
+        self.checkpoint_config.set(next.checkpoint_config());
+        // the budgets of the clients start over, only if the limits changed
+        if applied.iter().any(|name| name == "rate_limits") {
+            self.rate_limiter.set_config(next.rate_limits.clone());
+        }
+        *config = next;
+        drop(config);
+
+        if applied.is_empty() {
+            tracing::info!("configuration reloaded, no setting changed");
+        } else {
+            tracing::info!("configuration reloaded, applied: {}", applied.join(", "));
+        }
+        if !rejected.is_empty() {
+            tracing::warn!(
+                "the changes to these settings need a restart, and were not applied: {}",
+                rejected.join(", ")
+            );
+        }
+
+        Ok(ReloadResult { applied, rejected })
+    }
+}
+
+/// The names of the settings that differ between `a` and `b`, compared through their `Debug`
+/// representation, where each setting starts on a line of its own.
+fn changed_settings(a: &Config, b: &Config) -> Vec<String> {
+    fn settings(config: &Config) -> Vec<(String, String)> {
+        let mut settings: Vec<(String, String)> = Vec::new();
+        for line in format!("{config:#?}").lines() {
+            let field = line
+                .strip_prefix("    ")
+                .filter(|rest| !rest.starts_with(' '))
+                .and_then(|rest| rest.split_once(':'));
+            match (field, settings.last_mut()) {
+                (Some((name, _)), _) => settings.push((name.to_string(), line.to_string())),
+                (None, Some((_, value))) => {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+                (None, None) => (),
+            }
+        }
+        settings
+    }
+
+    settings(a)
+        .into_iter()
+        .zip(settings(b))
+        .filter(|((_, a), (_, b))| a != b)
+        .map(|((name, _), _)| name)
+        .collect()
+}
+
+/// Reloads the configuration on `SIGHUP`.
+pub async fn watch(reloader: Arc<Reloader>) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            let reloader = reloader.clone();
+            let res = tokio::task::spawn_blocking(move || reloader.reload()).await?;
+            if let Err(e) = res {
+                tracing::error!("could not reload the configuration: {e}");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = reloader;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::rate_limit::RateLimit;
+
+    #[test]
+    fn changed_settings_are_named() {
+        let a = Config::default();
+        let mut b = a.clone();
+        assert!(changed_settings(&a, &b).is_empty());
+
+        b.query_timeout = Some(Duration::from_secs(1));
+        b.rate_limits.per_ip = Some(RateLimit {
+            reads: 10,
+            writes: 1,
+        });
+        b.max_log_size += 1;
+        let mut changed = changed_settings(&a, &b);
+        changed.sort();
+        assert_eq!(changed, ["max_log_size", "query_timeout", "rate_limits"]);
+    }
+
+    #[test]
+    fn reload_applies_reloadable_settings() {
+        let config = Config::default();
+        let reloader = Reloader::new(config.clone(), None);
+        assert!(matches!(reloader.reload(), Err(ReloadError::Unsupported)));
+
+        let loader: ConfigLoader = Box::new(|| {
+            let mut config = Config::default();
+            config.txn_timeout = Some(Duration::from_secs(3));
+            config.http_auth = Some("basic:dXNlcjpwYXNz".into());
+            config.max_wal_size = Some(1 << 20);
+            config.enable_namespaces = true;
+            Ok(config)
+        });
+        let reloader = Reloader::new(config, Some(loader));
+        let res = reloader.reload().unwrap();
+        let mut applied = res.applied.clone();
+        applied.sort();
+        assert_eq!(applied, ["http_auth", "max_wal_size", "txn_timeout"]);
+        assert_eq!(res.rejected, ["enable_namespaces"]);
+
+        assert_eq!(
+            reloader.session_config.get().txn_timeout,
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(reloader.checkpoint_config.get().max_wal_size, Some(1 << 20));
+        assert!(!reloader.auth.get().disabled);
+        let config = reloader.config();
+        assert!(!config.enable_namespaces);
+        assert_eq!(config.txn_timeout, Some(Duration::from_secs(3)));
+
+        // the rejected settings are still reported, the others are now in place
+        let res = reloader.reload().unwrap();
+        assert!(res.applied.is_empty());
+        assert_eq!(res.rejected, ["enable_namespaces"]);
+    }
+
+    #[test]
+    fn invalid_configuration_is_not_applied() {
+        let loader: ConfigLoader = Box::new(|| {
+            let mut config = Config::default();
+            config.txn_timeout = Some(Duration::from_secs(3));
+            config.auth_jwt_key = Some("not a key".into());
+            Ok(config)
+        });
+        let reloader = Reloader::new(Config::default(), Some(loader));
+        assert!(matches!(reloader.reload(), Err(ReloadError::Invalid(_))));
+        assert_eq!(
+            reloader.session_config.get().txn_timeout,
+            Some(crate::database::DEFAULT_TXN_TIMEOUT)
+        );
+        assert_eq!(reloader.config().auth_jwt_key, None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Inspection and snapshots of the bottomless backup of the database, for the admin API.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::database::vacuum::WithConnection;
+use crate::replication::WAL_PAGE_SIZE;
+
+/// Maximum duration of the listing of the generations in the bucket.
+pub const LIST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum duration an admin request waits for a snapshot of the backup.
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("a snapshot of the backup is already in progress")]
+    InProgress,
+    #[error("the database is busy, the checkpoint could not complete")]
+    Busy,
+    #[error("the object storage did not answer within {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupStatus {
+    /// The generation the backup is currently written to.
+    pub generation: Uuid,
+    /// Time of the last successful upload since sqld started, in milliseconds since the unix
+    /// epoch.
+    pub last_upload_ms: Option<u64>,
+    /// Frames committed locally, but not sent to the object storage yet.
+    pub pending_frames: u32,
+    pub pending_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenerationStatus {
+    pub generation: Uuid,
+    /// Time the generation was started, in milliseconds since the unix epoch.
+    pub created_at_ms: Option<u64>,
+    /// Total size of the objects of the generation, in bytes.
+    pub size: u64,
+    pub object_count: usize,
+}
+
+pub struct Backup {
+    handle: bottomless::replicator::Handle,
+    /// Opens connections going through the replication hook, whose checkpoints upload the backup.
+    with_conn: Arc<WithConnection>,
+    /// Held for the duration of a snapshot.
+    snapshot: Arc<tokio::sync::Mutex<()>>,
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Backup {
+    pub fn new(
+        replicator: &bottomless::replicator::Replicator,
+        with_conn: Arc<WithConnection>,
+    ) -> Self {
+        Self {
+            handle: replicator.handle(),
+            with_conn,
+            snapshot: Default::default(),
+        }
+    }
+
+    pub fn status(&self) -> BackupStatus {
+        let pending_frames = self.handle.pending_frames();
+        BackupStatus {
+            generation: self.handle.generation(),
+            last_upload_ms: self.handle.last_upload().map(unix_ms),
+            pending_frames,
+            pending_bytes: pending_frames as u64 * WAL_PAGE_SIZE as u64,
+        }
+    }
+
+    /// Lists the generations of the backup, newest first.
+    pub async fn generations(&self) -> Result<Vec<GenerationStatus>, BackupError> {
+        let generations = tokio::time::timeout(LIST_TIMEOUT, self.handle.list_generations())
+            .await
+            .map_err(|_| BackupError::Timeout(LIST_TIMEOUT))??;
+
+        Ok(generations
+            .into_iter()
+            .map(|info| GenerationStatus {
+                generation: info.generation,
+                created_at_ms: info.created_at.map(unix_ms),
+                size: info.size,
+                object_count: info.object_count,
+            })
+            .collect())
+    }
+
+    /// Checkpoints the database, which uploads the frames that are not backed up yet and starts a
+    /// new generation with a snapshot of the database file. Returns the generation the backup is
+    /// written to once the snapshot is complete, which is unchanged if nothing was committed since
+    /// the last snapshot. If the snapshot takes longer than [`SNAPSHOT_TIMEOUT`], it carries on in
+    /// the background, and new snapshots are refused until it is done.
+    pub async fn snapshot(&self) -> Result<Uuid, BackupError> {
+        let guard = self
+            .snapshot
+            .clone()
+            .try_lock_owned()
+            .map_err(|_| BackupError::InProgress)?;
+        let with_conn = self.with_conn.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            let mut busy = false;
+            with_conn(&mut |conn| {
+                busy = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| {
+                    row.get::<_, i32>(0)
+                })? != 0;
+                Ok(())
+            })?;
+            if busy {
+                return Err(BackupError::Busy);
+            }
+            Ok(())
+        });
+
+        match tokio::time::timeout(SNAPSHOT_TIMEOUT, task).await {
+            Ok(Ok(res)) => res?,
+            Ok(Err(e)) => return Err(anyhow::anyhow!("snapshot task failed: {e}").into()),
+            Err(_) => return Err(BackupError::Timeout(SNAPSHOT_TIMEOUT)),
+        }
+
+        Ok(self.handle.generation())
+    }
+}
@@ -0,0 +1,111 @@
+//! Compression of the frames streamed to the replicas.
+//!
+//! A replica lists the codecs it supports in its handshake, and the primary answers with the codec
+//! of `--replication-compression` if the replica supports it, or with no compression. The frames
+//! of the log and of the snapshots streamed in that session are then compressed one by one,
+//! headers included.
+
+use bytes::Bytes;
+
+use crate::replication::frame::Frame;
+use crate::rpc::replication_log::rpc::CompressionCodec;
+
+/// Compression level of zstd, low enough for the compression to keep up with the stream.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The codecs supported by the replicas, by order of preference.
+pub const SUPPORTED_CODECS: &[CompressionCodec] = &[CompressionCodec::Zstd];
+
+/// The compression of the frames streamed to the replicas that support it.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplicationCompression {
+    /// Stream the frames uncompressed.
+    #[default]
+    None,
+    /// Compress the frames with zstd.
+    Zstd,
+}
+
+impl ReplicationCompression {
+    /// The codec of the streams to a replica that supports the `offered` codecs, as sent in its
+    /// handshake.
+    pub fn negotiate(self, offered: &[i32]) -> CompressionCodec {
+        let codec = match self {
+            Self::None => return CompressionCodec::None,
+            Self::Zstd => CompressionCodec::Zstd,
+        };
+        if offered.contains(&(codec as i32)) {
+            codec
+        } else {
+            CompressionCodec::None
+        }
+    }
+}
+
+/// Compresses the bytes of a frame.
+pub fn compress(codec: CompressionCodec, frame: Bytes) -> std::io::Result<Bytes> {
+    match codec {
+        CompressionCodec::None => Ok(frame),
+        CompressionCodec::Zstd => zstd::bulk::compress(&frame, ZSTD_LEVEL).map(Bytes::from),
+    }
+}
+
+/// Decompresses a frame received from the primary.
+pub fn decompress(codec: CompressionCodec, data: Bytes) -> anyhow::Result<Frame> {
+    let data = match codec {
+        CompressionCodec::None => data,
+        CompressionCodec::Zstd => zstd::bulk::decompress(&data, Frame::SIZE)?.into(),
+    };
+    Frame::try_from_bytes(data)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::frame::FrameHeader;
+    use crate::replication::WAL_PAGE_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn negotiate_codec() {
+        let zstd = CompressionCodec::Zstd as i32;
+        assert_eq!(
+            ReplicationCompression::Zstd.negotiate(&[zstd]),
+            CompressionCodec::Zstd
+        );
+        // the replica doesn't support the codec of the primary, or predates compression
+        assert_eq!(
+            ReplicationCompression::Zstd.negotiate(&[42]),
+            CompressionCodec::None
+        );
+        assert_eq!(
+            ReplicationCompression::Zstd.negotiate(&[]),
+            CompressionCodec::None
+        );
+        assert_eq!(
+            ReplicationCompression::None.negotiate(&[zstd]),
+            CompressionCodec::None
+        );
+    }
+
+    #[test]
+    fn compress_frames() {
+        let header = FrameHeader {
+            frame_no: 42,
+            checksum: 0,
+            page_no: 1,
+            size_after: 1,
+        };
+        let frame = Frame::from_parts(&header, &[7; WAL_PAGE_SIZE as usize]);
+
+        let compressed = compress(CompressionCodec::Zstd, frame.bytes()).unwrap();
+        assert!(compressed.len() < Frame::SIZE / 10);
+        let decompressed = decompress(CompressionCodec::Zstd, compressed.clone()).unwrap();
+        assert_eq!(decompressed.bytes(), frame.bytes());
+        // a replica must not mistake a compressed frame for a plain one
+        assert!(decompress(CompressionCodec::None, compressed).is_err());
+
+        let plain = compress(CompressionCodec::None, frame.bytes()).unwrap();
+        assert_eq!(plain, frame.bytes());
+    }
+}
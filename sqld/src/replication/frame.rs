@@ -6,7 +6,7 @@ use std::ops::Deref;
 use bytemuck::{bytes_of, pod_read_unaligned, try_from_bytes, Pod, Zeroable};
 use bytes::{Bytes, BytesMut};
 
-use crate::replication::WAL_PAGE_SIZE;
+use crate::replication::{CRC_64_GO_ISO, WAL_PAGE_SIZE};
 
 use super::FrameNo;
 
@@ -95,6 +95,14 @@ impl FrameBorrowed {
     pub fn page(&self) -> &[u8] {
         &self.data[size_of::<FrameHeader>()..]
     }
+
+    /// Whether the rolling checksum of this frame matches its page, the checksum of the previous
+    /// frame of the log being `previous`.
+    pub fn verify_checksum(&self, previous: u64) -> bool {
+        let mut digest = CRC_64_GO_ISO.digest_with_initial(previous);
+        digest.update(self.page());
+        digest.finalize() == self.header().checksum
+    }
 }
 
 impl Deref for Frame {
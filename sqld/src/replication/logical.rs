@@ -0,0 +1,350 @@
+//! Logical (row-level) replication.
+//!
+//! Physical replication ships raw WAL frames to the replicas, and thus can't leave out any part of
+//! the database. Logical replication instead ships row-level changes, which lets a replica only
+//! receive a subset of the tables. This comes with a few trade-offs compared to physical
+//! replication:
+//! - Changes are captured by reading the final state of the modified rows once the transaction
+//!   that modified them has ended. Applying a batch is idempotent, but a batch may already reflect
+//!   writes from transactions that committed after it: a logical replica converges to the state of
+//!   the primary, but it is not a transaction-by-transaction copy of it.
+//! - Schema changes to a table are propagated by recreating the table on the replica, and copying
+//!   all its rows again.
+//! - `WITHOUT ROWID` tables and virtual tables are not replicated.
+//! - Logical offsets ([`LogicalOffset`]) are unrelated to frame numbers ([`FrameNo`]), and are only
+//!   meaningful relative to the id of the log they come from. The log is kept in memory by the
+//!   primary, and gets a new id every time the primary restarts, or fails to capture changes. A
+//!   replica presenting an unknown log id, or an offset that is not retained anymore, is sent a
+//!   full copy of its tables.
+//!
+//! [`FrameNo`]: super::FrameNo
+
+use std::collections::HashSet;
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::query::Value;
+use crate::rpc::replication_log::rpc;
+
+/// Offset of a batch in the logical change log. Offsets are monotonically increasing for a given
+/// log id. Contrary to [`FrameNo`](super::FrameNo), they do not identify a position in the
+/// replication log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct LogicalOffset(pub u64);
+
+impl LogicalOffset {
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+/// A row-level change to a table.
+#[derive(Debug, Clone)]
+pub enum Change {
+    /// Insert or replace the row with `rowid` with `values`
+    Upsert {
+        table: String,
+        rowid: i64,
+        columns: Vec<String>,
+        values: Vec<Value>,
+    },
+    /// Delete the row with `rowid`, if it exists
+    Delete { table: String, rowid: i64 },
+    /// (Re)create the table with `sql`, or drop it if `sql` is `None`. The table is always created
+    /// empty, and is followed by upserts for all its rows.
+    Schema { table: String, sql: Option<String> },
+}
+
+impl Change {
+    pub fn table(&self) -> &str {
+        match self {
+            Change::Upsert { table, .. }
+            | Change::Delete { table, .. }
+            | Change::Schema { table, .. } => table,
+        }
+    }
+}
+
+/// A group of changes that must be applied atomically.
+#[derive(Debug, Clone)]
+pub struct ChangeBatch {
+    /// Id of the log this batch comes from.
+    pub log_id: Uuid,
+    pub offset: LogicalOffset,
+    /// The replica must drop all its tables before applying this batch.
+    pub reset: bool,
+    /// If false, more batches with the same offset follow, and must be applied in the same
+    /// transaction.
+    pub complete: bool,
+    pub changes: Vec<Change>,
+}
+
+/// Maximum number of batches buffered for the subscribers of a [`ChangeFeed`]. Subscribers that
+/// fall further behind miss batches.
+const CHANGE_FEED_CAPACITY: usize = 256;
+
+/// Broadcasts the batches of changes once they are committed to the local database: on the
+/// primary as they are captured, and on logical replicas as they are applied. Used to notify
+/// clients of the changes to the tables they subscribed to.
+#[derive(Clone)]
+pub struct ChangeFeed {
+    sender: broadcast::Sender<Arc<ChangeBatch>>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANGE_FEED_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, batch: Arc<ChangeBatch>) {
+        // there may be no subscriber
+        let _ = self.sender.send(batch);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ChangeBatch>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The set of tables a logical replica is interested in. Table names are case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct TableFilter {
+    tables: HashSet<String>,
+}
+
+impl TableFilter {
+    pub fn new<S: AsRef<str>>(tables: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            tables: tables
+                .into_iter()
+                .map(|t| t.as_ref().to_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    pub fn allows(&self, table: &str) -> bool {
+        self.tables.contains(&table.to_lowercase())
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &str> {
+        self.tables.iter().map(|s| s.as_str())
+    }
+
+    /// Returns a copy of `batch` containing only the changes to the allowed tables, or `None` if
+    /// there is nothing left to send.
+    pub fn filter_batch(&self, batch: &ChangeBatch) -> Option<ChangeBatch> {
+        let changes: Vec<_> = batch
+            .changes
+            .iter()
+            .filter(|c| self.allows(c.table()))
+            .cloned()
+            .collect();
+
+        // reset and partial batches must always be sent, so the replica can delimit its
+        // transaction.
+        if changes.is_empty() && !batch.reset && batch.complete {
+            return None;
+        }
+
+        Some(ChangeBatch {
+            log_id: batch.log_id,
+            offset: batch.offset,
+            reset: batch.reset,
+            complete: batch.complete,
+            changes,
+        })
+    }
+}
+
+/// Returns whether a table with definition `sql` can be replicated logically.
+pub fn is_replicable_table(name: &str, sql: &str) -> bool {
+    let sql = sql.to_uppercase();
+    !name.starts_with("sqlite_")
+        && !sql.starts_with("CREATE VIRTUAL")
+        && !sql
+            .replace(char::is_whitespace, "")
+            .ends_with("WITHOUTROWID")
+}
+
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Applies `batch` to `conn`, skipping changes to the tables that are not allowed by `filter`.
+/// The caller is responsible for wrapping the batch in a transaction.
+pub fn apply_batch(
+    conn: &rusqlite::Connection,
+    batch: &ChangeBatch,
+    filter: &TableFilter,
+) -> anyhow::Result<()> {
+    if batch.reset {
+        let tables = conn
+            .prepare("SELECT name, sql FROM sqlite_schema WHERE type = 'table'")?
+            .query_map((), |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        for (name, sql) in tables {
+            if is_replicable_table(&name, &sql) {
+                conn.execute(&format!("DROP TABLE {}", quote_ident(&name)), ())?;
+            }
+        }
+    }
+
+    for change in batch.changes.iter() {
+        if !filter.allows(change.table()) {
+            continue;
+        }
+
+        match change {
+            Change::Upsert {
+                table,
+                rowid,
+                columns,
+                values,
+            } => {
+                let cols = std::iter::once("rowid".to_string())
+                    .chain(columns.iter().map(|c| quote_ident(c)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let placeholders = vec!["?"; columns.len() + 1].join(", ");
+                let sql = format!(
+                    "INSERT OR REPLACE INTO {} ({cols}) VALUES ({placeholders})",
+                    quote_ident(table)
+                );
+                let mut stmt = conn.prepare_cached(&sql)?;
+                stmt.raw_bind_parameter(1, rowid)?;
+                for (i, value) in values.iter().enumerate() {
+                    stmt.raw_bind_parameter(i + 2, value)?;
+                }
+                stmt.raw_execute()
+                    .with_context(|| format!("failed to apply upsert to table `{table}`"))?;
+            }
+            Change::Delete { table, rowid } => {
+                let sql = format!("DELETE FROM {} WHERE rowid = ?", quote_ident(table));
+                conn.prepare_cached(&sql)?
+                    .execute([rowid])
+                    .with_context(|| format!("failed to apply delete to table `{table}`"))?;
+            }
+            Change::Schema { table, sql } => {
+                conn.execute(&format!("DROP TABLE IF EXISTS {}", quote_ident(table)), ())?;
+                if let Some(sql) = sql {
+                    conn.execute(sql, ())
+                        .with_context(|| format!("failed to apply schema of table `{table}`"))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl From<&Change> for rpc::LogicalChange {
+    fn from(change: &Change) -> Self {
+        use rpc::logical_change::{self, Change as RpcChange};
+
+        let (table, change) = match change {
+            Change::Upsert {
+                table,
+                rowid,
+                columns,
+                values,
+            } => (
+                table,
+                RpcChange::Upsert(logical_change::Upsert {
+                    rowid: *rowid,
+                    columns: columns.clone(),
+                    values: Bytes::from(
+                        bincode::serialize(values).expect("failed to serialize values"),
+                    ),
+                }),
+            ),
+            Change::Delete { table, rowid } => (
+                table,
+                RpcChange::Delete(logical_change::Delete { rowid: *rowid }),
+            ),
+            Change::Schema { table, sql } => (
+                table,
+                RpcChange::Schema(logical_change::Schema { sql: sql.clone() }),
+            ),
+        };
+
+        Self {
+            table: table.clone(),
+            change: Some(change),
+        }
+    }
+}
+
+impl TryFrom<rpc::LogicalChange> for Change {
+    type Error = anyhow::Error;
+
+    fn try_from(change: rpc::LogicalChange) -> anyhow::Result<Self> {
+        use rpc::logical_change::Change as RpcChange;
+
+        let table = change.table;
+        let change = match change.change.context("missing logical change")? {
+            RpcChange::Upsert(upsert) => Change::Upsert {
+                table,
+                rowid: upsert.rowid,
+                columns: upsert.columns,
+                values: bincode::deserialize(&upsert.values)?,
+            },
+            RpcChange::Delete(delete) => Change::Delete {
+                table,
+                rowid: delete.rowid,
+            },
+            RpcChange::Schema(schema) => Change::Schema {
+                table,
+                sql: schema.sql,
+            },
+        };
+
+        Ok(change)
+    }
+}
+
+impl From<&ChangeBatch> for rpc::LogicalBatch {
+    fn from(batch: &ChangeBatch) -> Self {
+        Self {
+            log_id: batch.log_id.to_string(),
+            offset: batch.offset.0,
+            reset: batch.reset,
+            changes: batch.changes.iter().map(Into::into).collect(),
+            complete: batch.complete,
+        }
+    }
+}
+
+impl TryFrom<rpc::LogicalBatch> for ChangeBatch {
+    type Error = anyhow::Error;
+
+    fn try_from(batch: rpc::LogicalBatch) -> anyhow::Result<Self> {
+        Ok(Self {
+            log_id: Uuid::from_str(&batch.log_id).context("invalid log id from primary")?,
+            offset: LogicalOffset(batch.offset),
+            reset: batch.reset,
+            complete: batch.complete,
+            changes: batch
+                .changes
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
@@ -1,12 +1,18 @@
+pub mod backup;
+pub mod compression;
 pub mod frame;
 pub mod http;
+pub mod logical;
 pub mod primary;
 pub mod replica;
+pub mod restore;
 mod snapshot;
+pub mod standby;
+pub mod topology;
 
 use crc::Crc;
 pub use primary::logger::{LogReadError, ReplicationLogger, ReplicationLoggerHook};
-pub use snapshot::SnapshotCallback;
+pub use snapshot::{HistoryTruncated, SnapshotCallback, SnapshotFile, SnapshotRetention};
 
 pub const WAL_PAGE_SIZE: i32 = 4096;
 pub const WAL_MAGIC: u64 = u64::from_le_bytes(*b"SQLDWAL\0");
@@ -0,0 +1,647 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use rusqlite::hooks::Action;
+use rusqlite::OpenFlags;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::query::Value;
+use crate::replication::logical::{
+    is_replicable_table, quote_ident, Change, ChangeBatch, ChangeFeed, LogicalOffset, TableFilter,
+};
+
+/// Maximum number of changes retained in the change log. Replicas that fall further behind are
+/// sent a full copy of their tables.
+const MAX_RETAINED_CHANGES: usize = 100_000;
+/// Maximum number of changes sent in a single batch when dumping tables.
+const DUMP_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogicalReadError {
+    #[error("the requested offset is not available, the replica must resync")]
+    ResyncRequired,
+}
+
+/// In-memory log of the row-level changes committed on the primary, used to serve logical
+/// replicas.
+pub struct ChangeLog {
+    db_path: PathBuf,
+    inner: Mutex<ChangeLogInner>,
+    new_batch_notifier: watch::Sender<LogicalOffset>,
+    feed: ChangeFeed,
+}
+
+struct ChangeLogInner {
+    log_id: Uuid,
+    last_offset: LogicalOffset,
+    batches: VecDeque<Arc<ChangeBatch>>,
+    retained_changes: usize,
+    /// The schema version and replicable tables definitions, as of the last captured batch.
+    schema_version: i64,
+    tables: HashMap<String, String>,
+}
+
+impl ChangeLog {
+    pub fn open(db_path: &Path) -> anyhow::Result<Self> {
+        let (schema_version, tables) = if db_path.join("data").exists() {
+            let conn = open_read_only(db_path)?;
+            read_schema(&conn)?
+        } else {
+            (0, HashMap::new())
+        };
+
+        let (new_batch_notifier, _) = watch::channel(LogicalOffset::default());
+
+        Ok(Self {
+            db_path: db_path.to_path_buf(),
+            inner: Mutex::new(ChangeLogInner {
+                log_id: Uuid::new_v4(),
+                last_offset: LogicalOffset::default(),
+                batches: VecDeque::new(),
+                retained_changes: 0,
+                schema_version,
+                tables,
+            }),
+            new_batch_notifier,
+            feed: ChangeFeed::new(),
+        })
+    }
+
+    pub fn log_id(&self) -> Uuid {
+        self.inner.lock().log_id
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<LogicalOffset> {
+        self.new_batch_notifier.subscribe()
+    }
+
+    /// The feed of the batches committed to the database.
+    pub fn feed(&self) -> ChangeFeed {
+        self.feed.clone()
+    }
+
+    /// Returns all the retained batches following `offset` in log `log_id`.
+    pub fn batches_after(
+        &self,
+        log_id: Uuid,
+        offset: LogicalOffset,
+    ) -> Result<Vec<Arc<ChangeBatch>>, LogicalReadError> {
+        let inner = self.inner.lock();
+        if log_id != inner.log_id || offset > inner.last_offset {
+            return Err(LogicalReadError::ResyncRequired);
+        }
+
+        if offset == inner.last_offset {
+            return Ok(Vec::new());
+        }
+
+        match inner.batches.front() {
+            Some(first) if first.offset <= offset.next() => Ok(inner
+                .batches
+                .iter()
+                .skip_while(|b| b.offset <= offset)
+                .cloned()
+                .collect()),
+            _ => Err(LogicalReadError::ResyncRequired),
+        }
+    }
+
+    /// Discards all retained batches and changes the log id, forcing all logical replicas to
+    /// resync. This is called when we fail to capture changes, and the log can't be trusted
+    /// anymore.
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock();
+        inner.log_id = Uuid::new_v4();
+        inner.batches.clear();
+        inner.retained_changes = 0;
+        let offset = inner.last_offset;
+        drop(inner);
+        self.new_batch_notifier.send_replace(offset);
+    }
+
+    /// Captures the current state of the `touched` rows, along with any schema change, and
+    /// appends it to the log as a new batch. Must be called outside of a transaction.
+    fn capture(
+        &self,
+        conn: &rusqlite::Connection,
+        touched: HashSet<(String, i64)>,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock();
+
+        // read everything from the same snapshot
+        conn.execute_batch("BEGIN")?;
+        let changes = capture_changes(conn, &mut inner, touched);
+        conn.execute_batch("COMMIT")?;
+        let changes = changes?;
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let offset = inner.last_offset.next();
+        inner.last_offset = offset;
+        inner.retained_changes += changes.len();
+        let log_id = inner.log_id;
+        let batch = Arc::new(ChangeBatch {
+            log_id,
+            offset,
+            reset: false,
+            complete: true,
+            changes,
+        });
+        inner.batches.push_back(batch.clone());
+
+        while inner.retained_changes > MAX_RETAINED_CHANGES && inner.batches.len() > 1 {
+            if let Some(batch) = inner.batches.pop_front() {
+                inner.retained_changes -= batch.changes.len();
+            }
+        }
+
+        drop(inner);
+        self.new_batch_notifier.send_replace(offset);
+        self.feed.publish(batch);
+
+        Ok(())
+    }
+
+    /// Sends a copy of all the replicable tables allowed by `filter` to `send`, in batches. Returns
+    /// the log id and offset the copy corresponds to: the replica can resume streaming from there.
+    /// Returns early if `send` returns false.
+    pub fn dump(
+        &self,
+        filter: &TableFilter,
+        mut send: impl FnMut(ChangeBatch) -> bool,
+    ) -> anyhow::Result<(Uuid, LogicalOffset)> {
+        let conn = open_read_only(&self.db_path)?;
+        // Any change committed after we read the current offset is streamed again after the dump.
+        // This is fine, since applying changes is idempotent.
+        let (log_id, offset) = {
+            let inner = self.inner.lock();
+            (inner.log_id, inner.last_offset)
+        };
+
+        conn.execute_batch("BEGIN")?;
+        let (_, tables) = read_schema(&conn)?;
+
+        let mut changes = Vec::new();
+        let mut reset = true;
+        for (table, sql) in tables.iter() {
+            if !filter.allows(table) {
+                continue;
+            }
+            changes.push(Change::Schema {
+                table: table.clone(),
+                sql: Some(sql.clone()),
+            });
+
+            let mut stmt = conn.prepare(&format!("SELECT rowid, * FROM {}", quote_ident(table)))?;
+            let columns: Vec<String> = stmt
+                .column_names()
+                .into_iter()
+                .skip(1)
+                .map(ToString::to_string)
+                .collect();
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                changes.push(read_upsert(table, &columns, row)?);
+                if changes.len() >= DUMP_BATCH_SIZE {
+                    let batch = ChangeBatch {
+                        log_id,
+                        offset,
+                        reset,
+                        complete: false,
+                        changes: std::mem::take(&mut changes),
+                    };
+                    reset = false;
+                    if !send(batch) {
+                        return Ok((log_id, offset));
+                    }
+                }
+            }
+        }
+
+        send(ChangeBatch {
+            log_id,
+            offset,
+            reset,
+            complete: true,
+            changes,
+        });
+
+        Ok((log_id, offset))
+    }
+}
+
+/// Collects the rows touched by a connection, and records their state in the [`ChangeLog`] once
+/// the connection's transaction ends.
+pub struct ChangeCapture {
+    change_log: Arc<ChangeLog>,
+    touched: Arc<Mutex<HashSet<(String, i64)>>>,
+    /// Set when the connection performed a write that may not have touched any row (e.g. a schema
+    /// change).
+    dirty: bool,
+}
+
+impl ChangeCapture {
+    /// Installs the update and rollback hooks on `conn`.
+    pub fn install(change_log: Arc<ChangeLog>, conn: &rusqlite::Connection) -> Self {
+        let touched: Arc<Mutex<HashSet<(String, i64)>>> = Default::default();
+        conn.update_hook(Some({
+            let touched = touched.clone();
+            move |action: Action, db: &str, table: &str, rowid: i64| {
+                if db == "main"
+                    && matches!(
+                        action,
+                        Action::SQLITE_INSERT | Action::SQLITE_UPDATE | Action::SQLITE_DELETE
+                    )
+                {
+                    touched.lock().insert((table.to_string(), rowid));
+                }
+            }
+        }));
+        conn.rollback_hook(Some({
+            let touched = touched.clone();
+            move || touched.lock().clear()
+        }));
+
+        Self {
+            change_log,
+            touched,
+            dirty: false,
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Records the touched rows in the change log. Must be called when `conn` is not in a
+    /// transaction.
+    pub fn flush(&mut self, conn: &rusqlite::Connection) {
+        let touched = std::mem::take(&mut *self.touched.lock());
+        if touched.is_empty() && !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        if let Err(e) = self.change_log.capture(conn, touched) {
+            tracing::error!("failed to capture logical changes, logical replicas will resync: {e}");
+            self.change_log.invalidate();
+        }
+    }
+}
+
+fn open_read_only(db_path: &Path) -> anyhow::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open_with_flags(
+        db_path.join("data"),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .context("failed to open database for logical replication")?;
+    Ok(conn)
+}
+
+fn read_schema(conn: &rusqlite::Connection) -> anyhow::Result<(i64, HashMap<String, String>)> {
+    let version = conn.query_row("PRAGMA schema_version", (), |row| row.get(0))?;
+    let tables = conn
+        .prepare("SELECT name, sql FROM sqlite_schema WHERE type = 'table'")?
+        .query_map((), |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+        .filter(|res| match res {
+            Ok((name, sql)) => is_replicable_table(name, sql),
+            Err(_) => true,
+        })
+        .collect::<Result<HashMap<String, String>, _>>()?;
+
+    Ok((version, tables))
+}
+
+fn read_upsert(table: &str, columns: &[String], row: &rusqlite::Row) -> anyhow::Result<Change> {
+    let rowid = row.get(0)?;
+    let values = (1..=columns.len())
+        .map(|i| Value::try_from(row.get_ref(i)?))
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Change::Upsert {
+        table: table.to_string(),
+        rowid,
+        columns: columns.to_vec(),
+        values,
+    })
+}
+
+fn capture_changes(
+    conn: &rusqlite::Connection,
+    inner: &mut ChangeLogInner,
+    touched: HashSet<(String, i64)>,
+) -> anyhow::Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    let mut recreated = HashSet::new();
+
+    let version: i64 = conn.query_row("PRAGMA schema_version", (), |row| row.get(0))?;
+    if version != inner.schema_version {
+        let (_, tables) = read_schema(conn)?;
+        for (table, sql) in tables.iter() {
+            if inner.tables.get(table) == Some(sql) {
+                continue;
+            }
+            changes.push(Change::Schema {
+                table: table.clone(),
+                sql: Some(sql.clone()),
+            });
+            let mut stmt = conn.prepare(&format!("SELECT rowid, * FROM {}", quote_ident(table)))?;
+            let columns: Vec<String> = stmt
+                .column_names()
+                .into_iter()
+                .skip(1)
+                .map(ToString::to_string)
+                .collect();
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                changes.push(read_upsert(table, &columns, row)?);
+            }
+            recreated.insert(table.clone());
+        }
+
+        for table in inner.tables.keys() {
+            if !tables.contains_key(table) {
+                changes.push(Change::Schema {
+                    table: table.clone(),
+                    sql: None,
+                });
+                recreated.insert(table.clone());
+            }
+        }
+
+        inner.schema_version = version;
+        inner.tables = tables;
+    }
+
+    let mut touched = touched.into_iter().collect::<Vec<_>>();
+    touched.sort();
+    for (table, rowid) in touched {
+        if recreated.contains(&table) || !inner.tables.contains_key(&table) {
+            continue;
+        }
+
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT rowid, * FROM {} WHERE rowid = ?",
+            quote_ident(&table)
+        ))?;
+        let columns: Vec<String> = stmt
+            .column_names()
+            .into_iter()
+            .skip(1)
+            .map(ToString::to_string)
+            .collect();
+        let mut rows = stmt.query([rowid])?;
+        match rows.next()? {
+            Some(row) => changes.push(read_upsert(&table, &columns, row)?),
+            None => changes.push(Change::Delete { table, rowid }),
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::logical::apply_batch;
+
+    use super::*;
+
+    struct Primary {
+        _tmp: tempfile::TempDir,
+        conn: rusqlite::Connection,
+        capture: ChangeCapture,
+        change_log: Arc<ChangeLog>,
+    }
+
+    impl Primary {
+        fn new() -> Self {
+            let tmp = tempfile::tempdir().unwrap();
+            let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+            conn.pragma_update(None, "journal_mode", "wal").unwrap();
+            let change_log = Arc::new(ChangeLog::open(tmp.path()).unwrap());
+            let capture = ChangeCapture::install(change_log.clone(), &conn);
+            Self {
+                _tmp: tmp,
+                conn,
+                capture,
+                change_log,
+            }
+        }
+
+        fn exec(&mut self, sql: &str) {
+            self.capture.mark_dirty();
+            self.conn.execute_batch(sql).unwrap();
+            self.capture.flush(&self.conn);
+        }
+    }
+
+    fn sync(
+        primary: &Primary,
+        replica: &rusqlite::Connection,
+        filter: &TableFilter,
+        offset: LogicalOffset,
+    ) -> LogicalOffset {
+        let log_id = primary.change_log.log_id();
+        let mut offset = offset;
+        for batch in primary.change_log.batches_after(log_id, offset).unwrap() {
+            if let Some(batch) = filter.filter_batch(&batch) {
+                apply_batch(replica, &batch, filter).unwrap();
+            }
+            offset = batch.offset;
+        }
+        offset
+    }
+
+    fn dump_table(conn: &rusqlite::Connection, table: &str) -> Vec<String> {
+        conn.prepare(&format!("SELECT * FROM {table} ORDER BY rowid"))
+            .unwrap()
+            .query_map((), |row| {
+                let count = row.as_ref().column_count();
+                Ok((0..count)
+                    .map(|i| format!("{:?}", row.get_ref(i).unwrap()))
+                    .collect::<Vec<_>>()
+                    .join("|"))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn table_exists(conn: &rusqlite::Connection, table: &str) -> bool {
+        conn.query_row(
+            "SELECT count(*) FROM sqlite_schema WHERE name = ?",
+            [table],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            > 0
+    }
+
+    #[test]
+    fn insert_update_delete_are_replicated() {
+        let mut primary = Primary::new();
+        let replica = rusqlite::Connection::open_in_memory().unwrap();
+        let filter = TableFilter::new(["users"]);
+
+        primary.exec("CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT)");
+        primary.exec("INSERT INTO users VALUES (1, 'alice'), (2, 'bob'), (3, 'carol')");
+        let offset = sync(&primary, &replica, &filter, LogicalOffset::default());
+        assert_eq!(
+            dump_table(&replica, "users"),
+            dump_table(&primary.conn, "users")
+        );
+
+        primary.exec("UPDATE users SET name = 'bobby' WHERE id = 2");
+        primary.exec("DELETE FROM users WHERE id = 1");
+        primary.exec(
+            "BEGIN; INSERT INTO users VALUES (4, 'dave'); DELETE FROM users WHERE id = 4; COMMIT",
+        );
+        sync(&primary, &replica, &filter, offset);
+        assert_eq!(
+            dump_table(&replica, "users"),
+            dump_table(&primary.conn, "users")
+        );
+        assert_eq!(dump_table(&replica, "users").len(), 2);
+    }
+
+    #[test]
+    fn excluded_tables_are_skipped() {
+        let mut primary = Primary::new();
+        let replica = rusqlite::Connection::open_in_memory().unwrap();
+        let filter = TableFilter::new(["Public"]);
+
+        primary.exec("CREATE TABLE public (x)");
+        primary.exec("CREATE TABLE pii (email)");
+        primary.exec("INSERT INTO public VALUES (1)");
+        primary.exec("INSERT INTO pii VALUES ('alice@example.com')");
+        sync(&primary, &replica, &filter, LogicalOffset::default());
+
+        assert!(table_exists(&replica, "public"));
+        assert!(!table_exists(&replica, "pii"));
+        assert_eq!(dump_table(&replica, "public"), vec!["Integer(1)"]);
+    }
+
+    #[test]
+    fn alter_table_is_replicated() {
+        let mut primary = Primary::new();
+        let replica = rusqlite::Connection::open_in_memory().unwrap();
+        let filter = TableFilter::new(["t"]);
+
+        primary.exec("CREATE TABLE t (a)");
+        primary.exec("INSERT INTO t VALUES (1), (2)");
+        let offset = sync(&primary, &replica, &filter, LogicalOffset::default());
+
+        primary.exec("ALTER TABLE t ADD COLUMN b DEFAULT 'x'");
+        primary.exec("UPDATE t SET b = 'y' WHERE a = 2");
+        let offset = sync(&primary, &replica, &filter, offset);
+        assert_eq!(dump_table(&replica, "t"), dump_table(&primary.conn, "t"));
+
+        primary.exec("ALTER TABLE t RENAME COLUMN a TO c");
+        let offset = sync(&primary, &replica, &filter, offset);
+        assert_eq!(dump_table(&replica, "t"), dump_table(&primary.conn, "t"));
+        let sql: String = replica
+            .query_row("SELECT sql FROM sqlite_schema WHERE name = 't'", (), |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert!(sql.contains("c"));
+
+        primary.exec("DROP TABLE t");
+        sync(&primary, &replica, &filter, offset);
+        assert!(!table_exists(&replica, "t"));
+    }
+
+    #[test]
+    fn rolled_back_changes_are_not_captured() {
+        let mut primary = Primary::new();
+        primary.exec("CREATE TABLE t (a)");
+        let log_id = primary.change_log.log_id();
+        let before = primary
+            .change_log
+            .batches_after(log_id, LogicalOffset::default())
+            .unwrap()
+            .len();
+
+        primary.exec("BEGIN; INSERT INTO t VALUES (1); ROLLBACK");
+
+        let after = primary
+            .change_log
+            .batches_after(log_id, LogicalOffset::default())
+            .unwrap()
+            .len();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn committed_changes_are_published() {
+        let mut primary = Primary::new();
+        primary.exec("CREATE TABLE t (a)");
+        let mut feed = primary.change_log.feed().subscribe();
+
+        primary.exec("BEGIN; INSERT INTO t VALUES (1); ROLLBACK");
+        primary.exec("INSERT INTO t VALUES (2)");
+
+        let batch = feed.try_recv().unwrap();
+        assert!(matches!(
+            batch.changes.as_slice(),
+            [Change::Upsert { table, values, .. }]
+                if table == "t" && matches!(values.as_slice(), [Value::Integer(2)])
+        ));
+        assert!(feed.try_recv().is_err());
+    }
+
+    #[test]
+    fn dump_then_resume() {
+        let mut primary = Primary::new();
+        let replica = rusqlite::Connection::open_in_memory().unwrap();
+        let filter = TableFilter::new(["t"]);
+
+        primary.exec("CREATE TABLE t (a)");
+        primary.exec("CREATE TABLE other (a)");
+        for i in 0..(DUMP_BATCH_SIZE + 10) {
+            primary.exec(&format!("INSERT INTO t VALUES ({i})"));
+        }
+        replica.execute_batch("CREATE TABLE stale (a)").unwrap();
+
+        let mut batches = Vec::new();
+        let (log_id, offset) = primary
+            .change_log
+            .dump(&filter, |b| {
+                batches.push(b);
+                true
+            })
+            .unwrap();
+        assert_eq!(log_id, primary.change_log.log_id());
+        assert!(batches.len() > 1);
+        assert!(batches[0].reset);
+        assert!(batches.last().unwrap().complete);
+
+        for batch in batches {
+            apply_batch(&replica, &batch, &filter).unwrap();
+        }
+        assert!(!table_exists(&replica, "stale"));
+        assert!(!table_exists(&replica, "other"));
+        assert_eq!(dump_table(&replica, "t"), dump_table(&primary.conn, "t"));
+
+        primary.exec("DELETE FROM t WHERE a < 10");
+        sync(&primary, &replica, &filter, offset);
+        assert_eq!(dump_table(&replica, "t"), dump_table(&primary.conn, "t"));
+    }
+
+    #[test]
+    fn unknown_log_requires_resync() {
+        let primary = Primary::new();
+        assert!(matches!(
+            primary
+                .change_log
+                .batches_after(Uuid::new_v4(), LogicalOffset::default()),
+            Err(LogicalReadError::ResyncRequired)
+        ));
+    }
+}
@@ -1,19 +1,54 @@
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::task::{ready, Poll};
+use std::time::Duration;
 use std::{pin::Pin, task::Context};
 
 use futures::future::BoxFuture;
 use futures::Stream;
+use tokio::sync::watch;
 
 use crate::replication::frame::Frame;
 use crate::replication::{FrameNo, LogReadError, ReplicationLogger};
 
+/// Number of times the read of a frame is retried after an I/O error, before the stream fails.
+const READ_RETRIES: u32 = 3;
+/// Delay before the first retry, multiplied by 4 for each of the next ones.
+const READ_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+pub const DEFAULT_BATCH_FRAMES: usize = 128;
+pub const DEFAULT_BATCH_BYTES: usize = 1024 * 1024;
+
+/// Bounds the frames read from the log at once by a frame stream. A batch holds at least one
+/// frame.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+    pub max_frames: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_frames: DEFAULT_BATCH_FRAMES,
+            max_bytes: DEFAULT_BATCH_BYTES,
+        }
+    }
+}
+
 /// Streams frames from the replication log starting at `current_frame_no`.
 /// Only stops if the current frame is not in the log anymore.
+///
+/// The frames are read in batches, only once the consumer polled all the frames of the previous
+/// batch: a slow consumer holds neither a blocking thread nor the lock of the log file.
 pub struct FrameStream {
+    /// The next frame yielded by the stream.
     pub(crate) current_frame_no: FrameNo,
     pub(crate) max_available_frame_no: FrameNo,
     logger: Arc<ReplicationLogger>,
+    limits: BatchLimits,
+    /// The frames read but not yet yielded, starting at `current_frame_no`.
+    batch: VecDeque<Frame>,
     state: FrameStreamState,
 }
 
@@ -24,35 +59,96 @@ impl FrameStream {
             current_frame_no: current_frameno,
             max_available_frame_no,
             logger,
+            limits: BatchLimits::default(),
+            batch: VecDeque::new(),
             state: FrameStreamState::Init,
         }
     }
 
+    pub fn with_batch_limits(mut self, limits: BatchLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Streams the frames in the batches they are read from the log.
+    pub fn batches(mut self) -> impl Stream<Item = Result<Vec<Frame>, LogReadError>> + Unpin {
+        futures::stream::poll_fn(move |cx| {
+            let Some(first) = ready!(Pin::new(&mut self).poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            Poll::Ready(Some(first.map(|first| {
+                self.current_frame_no += self.batch.len() as FrameNo;
+                std::iter::once(first).chain(self.batch.drain(..)).collect()
+            })))
+        })
+    }
+
     fn transition_state_next_frame(&mut self) {
         if matches!(self.state, FrameStreamState::Closed) {
             return;
         }
 
+        // Only the frames announced by the notifier are streamed: a standby logs the frames it
+        // receives before it applies them.
+        if self.current_frame_no >= self.max_available_frame_no {
+            let mut notifier = self.logger.new_frame_notifier.subscribe();
+            let max_available_frame_no = *notifier.borrow_and_update();
+            if max_available_frame_no > self.max_available_frame_no {
+                self.max_available_frame_no = max_available_frame_no;
+            }
+            if self.current_frame_no >= self.max_available_frame_no {
+                self.wait_frame_no(notifier);
+                return;
+            }
+        }
+
         let next_frameno = self.current_frame_no;
+        let end = self.max_available_frame_no;
+        let limits = self.limits;
         let logger = self.logger.clone();
         let fut = async move {
-            let res = tokio::task::spawn_blocking(move || logger.get_frame(next_frameno)).await;
-            match res {
-                Ok(Ok(frame)) => Ok(frame),
-                Ok(Err(e)) => Err(e),
-                Err(e) => Err(LogReadError::Error(e.into())),
+            let mut backoff = READ_RETRY_BACKOFF;
+            for attempt in 0.. {
+                let logger = logger.clone();
+                let res = tokio::task::spawn_blocking(move || {
+                    logger.get_frames(next_frameno, end, limits)
+                })
+                .await;
+                match res {
+                    Ok(Ok(frames)) => return Ok(frames),
+                    // the I/O errors may be transient
+                    Ok(Err(LogReadError::Error(e))) if attempt < READ_RETRIES => {
+                        tracing::warn!(
+                            "failed to read frame {next_frameno}, retrying in {backoff:?}: {e}"
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 4;
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(e) => return Err(LogReadError::Error(e.into())),
+                }
             }
+            unreachable!()
         };
 
-        self.state = FrameStreamState::WaitingFrame(Box::pin(fut));
+        self.state = FrameStreamState::WaitingFrames(Box::pin(fut));
+    }
+
+    fn wait_frame_no(&mut self, mut notifier: watch::Receiver<FrameNo>) {
+        let fut = async move {
+            notifier.changed().await?;
+            Ok(*notifier.borrow())
+        };
+        self.state = FrameStreamState::WaitingFrameNo(Box::pin(fut));
     }
 }
 
 enum FrameStreamState {
+    /// ready to read the next batch
     Init,
     /// waiting for new frames to replicate
     WaitingFrameNo(BoxFuture<'static, anyhow::Result<FrameNo>>),
-    WaitingFrame(BoxFuture<'static, Result<Frame, LogReadError>>),
+    WaitingFrames(BoxFuture<'static, Result<Vec<Frame>, LogReadError>>),
     Closed,
 }
 
@@ -60,6 +156,11 @@ impl Stream for FrameStream {
     type Item = Result<Frame, LogReadError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(frame) = self.batch.pop_front() {
+            self.current_frame_no += 1;
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
         match self.state {
             FrameStreamState::Init => {
                 self.transition_state_next_frame();
@@ -76,11 +177,12 @@ impl Stream for FrameStream {
                 self.transition_state_next_frame();
                 self.poll_next(cx)
             }
-            FrameStreamState::WaitingFrame(ref mut fut) => match ready!(fut.as_mut().poll(cx)) {
-                Ok(frame) => {
-                    self.current_frame_no += 1;
-                    self.transition_state_next_frame();
-                    Poll::Ready(Some(Ok(frame)))
+            FrameStreamState::WaitingFrames(ref mut fut) => match ready!(fut.as_mut().poll(cx)) {
+                Ok(frames) => {
+                    self.batch.extend(frames);
+                    // the next batch is read once this one was consumed
+                    self.state = FrameStreamState::Init;
+                    self.poll_next(cx)
                 }
 
                 Err(LogReadError::Ahead) => {
@@ -92,11 +194,7 @@ impl Stream for FrameStream {
                         self.transition_state_next_frame();
                         self.poll_next(cx)
                     } else {
-                        let fut = async move {
-                            notifier.changed().await?;
-                            Ok(*notifier.borrow())
-                        };
-                        self.state = FrameStreamState::WaitingFrameNo(Box::pin(fut));
+                        self.wait_frame_no(notifier);
                         self.poll_next(cx)
                     }
                 }
@@ -109,3 +207,72 @@ impl Stream for FrameStream {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use futures::StreamExt;
+
+    use crate::database::libsql::open_db;
+    use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
+
+    use super::*;
+
+    fn write(logger: &Arc<ReplicationLogger>, path: &Path, sql: &str) {
+        let mut ctx = ReplicationLoggerHookCtx::new(logger.clone(), None);
+        let conn = open_db(path, &REPLICATION_METHODS, &mut ctx, None).unwrap();
+        conn.execute_batch(sql).unwrap();
+    }
+
+    async fn next_frame(stream: &mut FrameStream) -> Frame {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paused_stream_does_not_block_others() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+        for i in 0..10 {
+            write(&logger, tmp.path(), &format!("INSERT INTO t VALUES ({i})"));
+        }
+        let limits = BatchLimits {
+            max_frames: 2,
+            max_bytes: usize::MAX,
+        };
+
+        // the slow consumer stops polling in the middle of a batch
+        let mut slow = FrameStream::new(logger.clone(), 0).with_batch_limits(limits);
+        assert_eq!(next_frame(&mut slow).await.header().frame_no, 0);
+
+        let last_frame_no = *logger.new_frame_notifier.borrow();
+        let mut fast = FrameStream::new(logger.clone(), 0).with_batch_limits(limits);
+        for frame_no in 0..last_frame_no {
+            assert_eq!(next_frame(&mut fast).await.header().frame_no, frame_no);
+        }
+
+        // the log can still be written
+        let write_more = tokio::task::spawn_blocking({
+            let logger = logger.clone();
+            let path = tmp.path().to_path_buf();
+            move || write(&logger, &path, "INSERT INTO t VALUES (42)")
+        });
+        tokio::time::timeout(Duration::from_secs(5), write_more)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(next_frame(&mut fast).await.header().frame_no >= last_frame_no);
+
+        // the slow consumer resumes where it stopped
+        let new_last_frame_no = *logger.new_frame_notifier.borrow();
+        for frame_no in 1..new_last_frame_no {
+            assert_eq!(next_frame(&mut slow).await.header().frame_no, frame_no);
+        }
+    }
+}
@@ -1,10 +1,13 @@
+use std::collections::{HashMap, VecDeque};
 use std::ffi::{c_int, c_void, CStr};
 use std::fs::{remove_dir_all, File, OpenOptions};
 use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::prelude::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, ensure};
 use bytemuck::{bytes_of, pod_read_unaligned, Pod, Zeroable};
@@ -22,19 +25,52 @@ use crate::libsql::ffi::{
     PageHdrIter, PgHdr, Wal, SQLITE_CHECKPOINT_TRUNCATE, SQLITE_IOERR, SQLITE_OK,
 };
 use crate::libsql::wal_hook::WalHook;
-use crate::replication::frame::{Frame, FrameHeader};
+use crate::replication::frame::{Frame, FrameBorrowed, FrameHeader};
+use crate::replication::primary::frame_stream::BatchLimits;
 use crate::replication::snapshot::{
-    find_snapshot_file, LogCompactor, SnapshotCallback, SnapshotFile,
+    check_snapshots, find_snapshot_file, migrate_snapshots, write_snapshot, LogCompactor,
+    SnapshotCallback, SnapshotFile, SnapshotRetention,
 };
 use crate::replication::{FrameNo, CRC_64_GO_ISO, WAL_MAGIC, WAL_PAGE_SIZE};
 
 init_static_wal_method!(REPLICATION_METHODS, ReplicationLoggerHook);
 
+/// Version of the replication log format written by this version of sqld.
+pub const LOG_FORMAT_VERSION: u32 = 2;
+
+/// Maximum number of frames of a transaction kept in memory before they are written to the log.
+/// The frames written by SQLite when it spills its cache are buffered until the transaction
+/// commits, so that a page spilled several times is only logged once.
+const MAX_BUFFERED_FRAMES: usize = 4096;
+
+/// Number of transactions whose commit time is remembered, to estimate the lag of the replicas.
+const MAX_COMMIT_TIMES: usize = 4096;
+
+/// File next to the log where the wall-clock commit time of the transactions is persisted.
+const COMMIT_TIMESTAMPS_FILE: &str = "wallog.timestamps";
+/// Number of transactions whose wall-clock commit time is kept on disk, for point-in-time
+/// restores. The file is trimmed when it holds twice as many.
+const MAX_COMMIT_TIMESTAMPS: u64 = 1 << 20;
+
+static FRAMES_LOGGED: AtomicU64 = AtomicU64::new(0);
+static FRAMES_DEDUPLICATED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of frames written to the replication log since the process started.
+pub fn frames_logged_total() -> u64 {
+    FRAMES_LOGGED.load(Ordering::Relaxed)
+}
+
+/// Returns the number of frames that were not written to the replication log since the process
+/// started, because a newer version of the same page was written by the same transaction.
+pub fn frames_deduplicated_total() -> u64 {
+    FRAMES_DEDUPLICATED.load(Ordering::Relaxed)
+}
+
 #[derive(PartialEq, Eq)]
-struct Version([u16; 4]);
+pub(crate) struct Version(pub [u16; 4]);
 
 impl Version {
-    fn current() -> Self {
+    pub(crate) fn current() -> Self {
         let major = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
         let minor = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
         let patch = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap();
@@ -42,11 +78,20 @@ impl Version {
     }
 }
 
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [_, major, minor, patch] = self.0;
+        write!(f, "{major}.{minor}.{patch}")
+    }
+}
+
 pub enum ReplicationLoggerHook {}
 
 #[derive(Clone)]
 pub struct ReplicationLoggerHookCtx {
     buffer: Vec<WalPage>,
+    /// Position of each page in `buffer`.
+    buffered_pages: HashMap<u32, usize>,
     logger: Arc<ReplicationLogger>,
     bottomless_replicator: Option<Arc<std::sync::Mutex<bottomless::replicator::Replicator>>>,
 }
@@ -59,6 +104,9 @@ pub struct ReplicationLoggerHookCtx {
 /// frame count.
 ///
 /// If either writing to the database of to the shadow wal fails, it must be noop.
+///
+/// The pages of a transaction are buffered until it commits, or until the buffer is full, so that
+/// the pages that SQLite writes several times, when it spills its cache, are only logged once.
 unsafe impl WalHook for ReplicationLoggerHook {
     type Context = ReplicationLoggerHookCtx;
 
@@ -83,10 +131,14 @@ unsafe impl WalHook for ReplicationLoggerHook {
         for (page_no, data) in PageHdrIter::new(page_headers, page_size as _) {
             ctx.write_frame(page_no, data)
         }
-        if let Err(e) = ctx.flush(ntruncate) {
-            tracing::error!("error writing to replication log: {e}");
-            // returning IO_ERR ensure that xUndo will be called by sqlite.
-            return SQLITE_IOERR;
+        if is_commit != 0 || ctx.buffer.len() >= MAX_BUFFERED_FRAMES {
+            if let Err(e) = ctx.flush(ntruncate) {
+                tracing::error!("error writing to replication log: {e}");
+                // the reason is reported to the clients, the error is only logged
+                crate::STORAGE_HEALTH.degrade("error writing to replication log");
+                // returning IO_ERR ensure that xUndo will be called by sqlite.
+                return SQLITE_IOERR;
+            }
         }
 
         let rc = unsafe {
@@ -198,6 +250,30 @@ unsafe impl WalHook for ReplicationLoggerHook {
                 return SQLITE_OK;
             }
         }
+
+        // The backup reads the frames it uploads from the WAL, which is emptied by the checkpoint:
+        // the frames committed so far are uploaded first. Frames committed in the meantime are
+        // part of the snapshot of the database file that starts the next generation.
+        #[allow(clippy::await_holding_lock)]
+        {
+            let ctx = Self::wal_extract_ctx(wal);
+            if let Some(replicator) = ctx.bottomless_replicator.as_mut() {
+                let runtime = tokio::runtime::Handle::current();
+                let mut replicator = replicator.lock().unwrap();
+                let last_known_frame = replicator.last_known_frame();
+                replicator.request_flush();
+                if let Err(e) = runtime.block_on(replicator.wait_until_committed(last_known_frame))
+                {
+                    tracing::error!(
+                        "Failed to wait for S3 replicator to confirm {} frames backup: {}",
+                        last_known_frame,
+                        e
+                    );
+                    return SQLITE_IOERR_WRITE;
+                }
+            }
+        }
+
         let rc = unsafe {
             orig(
                 wal,
@@ -228,17 +304,6 @@ unsafe impl WalHook for ReplicationLoggerHook {
                     tracing::debug!("No commits happened in this generation, not snapshotting");
                     return SQLITE_OK;
                 }
-                let last_known_frame = replicator.last_known_frame();
-                replicator.request_flush();
-                if let Err(e) = runtime.block_on(replicator.wait_until_committed(last_known_frame))
-                {
-                    tracing::error!(
-                        "Failed to wait for S3 replicator to confirm {} frames backup: {}",
-                        last_known_frame,
-                        e
-                    );
-                    return SQLITE_IOERR_WRITE;
-                }
                 replicator.new_generation();
                 if let Err(e) =
                     runtime.block_on(async move { replicator.snapshot_main_db_file().await })
@@ -268,12 +333,22 @@ impl ReplicationLoggerHookCtx {
         tracing::trace!("bottomless replication enabled: {bottomless_replicator:?}");
         Self {
             buffer: Default::default(),
+            buffered_pages: Default::default(),
             logger,
             bottomless_replicator,
         }
     }
 
     fn write_frame(&mut self, page_no: u32, data: &[u8]) {
+        // Like SQLite when it reads the WAL, replicas only care about the last version of a page
+        // written by a transaction.
+        if let Some(&i) = self.buffered_pages.get(&page_no) {
+            self.buffer[i].data = Bytes::copy_from_slice(data);
+            FRAMES_DEDUPLICATED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.buffered_pages.insert(page_no, self.buffer.len());
         let entry = WalPage {
             page_no,
             size_after: 0,
@@ -287,7 +362,9 @@ impl ReplicationLoggerHookCtx {
         if !self.buffer.is_empty() {
             self.buffer.last_mut().unwrap().size_after = size_after;
             self.logger.write_pages(&self.buffer)?;
+            FRAMES_LOGGED.fetch_add(self.buffer.len() as u64, Ordering::Relaxed);
             self.buffer.clear();
+            self.buffered_pages.clear();
         }
 
         Ok(())
@@ -295,13 +372,14 @@ impl ReplicationLoggerHookCtx {
 
     fn commit(&self) -> anyhow::Result<()> {
         let new_frame_no = self.logger.commit()?;
-        self.logger.new_frame_notifier.send_replace(new_frame_no);
+        self.logger.announce_frames(new_frame_no);
         Ok(())
     }
 
     fn rollback(&mut self) {
         self.logger.log_file.write().rollback();
         self.buffer.clear();
+        self.buffered_pages.clear();
     }
 }
 
@@ -329,6 +407,13 @@ pub struct LogFile {
 
     /// checksum of the last commited frame
     commited_checksum: u64,
+
+    /// set if committed frames were missing from the file when the log was opened. They were
+    /// dropped, and the log must be recovered from the database file.
+    incomplete: bool,
+    /// number of committed frames dropped when the log was opened, because they were missing from
+    /// the file or corrupted.
+    truncated_frames: u64,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -337,6 +422,9 @@ pub enum LogReadError {
     SnapshotRequired,
     #[error("requested entry is ahead of log")]
     Ahead,
+    /// The frame read from the log is not the requested one.
+    #[error("replication log is corrupted: frame {frame_no} was read as frame {found}")]
+    Corrupted { frame_no: FrameNo, found: FrameNo },
     #[error(transparent)]
     Error(#[from] anyhow::Error),
 }
@@ -352,12 +440,20 @@ impl LogFile {
     ) -> anyhow::Result<Self> {
         // FIXME: we should probably take a lock on this file, to prevent anybody else to write to
         // it.
-        let file_end = file.metadata()?.len();
+        let mut file_end = file.metadata()?.len();
+        let mut incomplete = false;
+        if file_end > 0 && file_end < size_of::<LogFileHeader>() as u64 {
+            // the header of a fresh log was only partially written: start over.
+            tracing::warn!("replication log header is truncated, resetting the log");
+            file.set_len(0)?;
+            file_end = 0;
+            incomplete = true;
+        }
 
         let header = if file_end == 0 {
             let db_id = Uuid::new_v4();
             LogFileHeader {
-                version: 2,
+                version: LOG_FORMAT_VERSION,
                 start_frame_no: 0,
                 magic: WAL_MAGIC,
                 page_size: WAL_PAGE_SIZE,
@@ -367,7 +463,17 @@ impl LogFile {
                 sqld_version: Version::current().0,
             }
         } else {
-            Self::read_header(&file)?
+            let header = Self::read_header(&file)?;
+            if header.version > LOG_FORMAT_VERSION {
+                bail!(
+                    "replication log format version {} was written by sqld {}, but this version of sqld ({}) only supports up to version {LOG_FORMAT_VERSION}: sqld {} or later is required to open it",
+                    header.version,
+                    header.sqld_version(),
+                    Version::current(),
+                    header.sqld_version(),
+                );
+            }
+            header
         };
 
         let mut this = Self {
@@ -379,11 +485,17 @@ impl LogFile {
             uncommitted_frame_count: 0,
             uncommitted_checksum: 0,
             commited_checksum: 0,
+            incomplete,
+            truncated_frames: 0,
         };
 
         if file_end == 0 {
             this.write_header()?;
-        } else if let Some(last_commited) = this.last_commited_frame_no() {
+            return Ok(this);
+        }
+
+        this.truncate_tail(file_end)?;
+        if let Some(last_commited) = this.last_commited_frame_no() {
             // file is not empty, the starting checksum is the checksum from the last entry
             let last_frame = this.frame(last_commited)?;
             this.commited_checksum = last_frame.header().checksum;
@@ -397,6 +509,103 @@ impl LogFile {
         Ok(this)
     }
 
+    /// Drops the bytes past the last committed frame, left by appends that failed half-way, for
+    /// example because the disk was full. If the file ends before the last committed frame, the
+    /// frames that are missing are dropped from the header, and the log is marked as incomplete.
+    fn truncate_tail(&mut self, file_end: u64) -> anyhow::Result<()> {
+        let committed_end = Self::absolute_byte_offset(self.header.frame_count);
+        if file_end < committed_end {
+            let frame_count =
+                (file_end - size_of::<LogFileHeader>() as u64) / Self::FRAME_SIZE as u64;
+            tracing::warn!(
+                "replication log is truncated: it contains {frame_count} complete frames, but {} were committed",
+                self.header.frame_count,
+            );
+            self.truncated_frames += self.header.frame_count - frame_count;
+            self.header.frame_count = frame_count;
+            self.incomplete = true;
+            self.write_header()?;
+        }
+
+        let end = Self::absolute_byte_offset(self.header.frame_count);
+        if file_end > end {
+            let bytes = file_end - end;
+            tracing::info!(
+                "dropping {bytes} bytes ({} uncommitted frames) at the end of the replication log",
+                (bytes + Self::FRAME_SIZE as u64 - 1) / Self::FRAME_SIZE as u64,
+            );
+            self.file.set_len(end)?;
+        }
+
+        Ok(())
+    }
+
+    /// Walks the committed frames, checking that they are numbered in sequence and that their
+    /// running checksums match. The log is cut right before the first invalid frame, and is marked
+    /// as incomplete if any frame was dropped.
+    fn validate_frames(&mut self) -> anyhow::Result<()> {
+        // The log of a standby that restarted in the middle of the log of its primary doesn't know
+        // the checksum of the frame before its first frame, and records it as 0.
+        let mut checksum = (self.header.start_frame_no == 0 || self.header.start_checksum != 0)
+            .then_some(self.header.start_checksum);
+        let mut valid = 0;
+        while valid < self.header.frame_count {
+            let frame_no = self.header.start_frame_no + valid;
+            let frame = self.read_frame_byte_offset(Self::absolute_byte_offset(valid))?;
+            if frame.header().frame_no != frame_no
+                || checksum.map_or(false, |checksum| !frame.verify_checksum(checksum))
+            {
+                break;
+            }
+            checksum = Some(frame.header().checksum);
+            valid += 1;
+        }
+
+        let dropped = self.header.frame_count - valid;
+        if dropped > 0 {
+            tracing::warn!(
+                "replication log is corrupted at frame {}: dropping it and the {} frames after it",
+                self.header.start_frame_no + valid,
+                dropped - 1,
+            );
+            self.truncated_frames += dropped;
+            self.header.frame_count = valid;
+            self.incomplete = true;
+            self.write_header()?;
+            self.file.set_len(Self::absolute_byte_offset(valid))?;
+            let checksum = checksum.unwrap_or(self.header.start_checksum);
+            self.commited_checksum = checksum;
+            self.uncommitted_checksum = checksum;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the end of the log is intact: the file contains all the frames written so far,
+    /// and the last committed frame matches the running checksum. The bytes past the frames
+    /// written so far are dropped.
+    fn verify_tail(&mut self) -> anyhow::Result<()> {
+        let file_end = self.file.metadata()?.len();
+        let end = self.next_byte_offset();
+        ensure!(
+            file_end >= end,
+            "replication log is truncated: it is {file_end} bytes long, but frames were written up to byte {end}"
+        );
+        if file_end > end {
+            self.file.set_len(end)?;
+        }
+
+        if let Some(last_commited) = self.last_commited_frame_no() {
+            let frame = self.frame(last_commited)?;
+            ensure!(
+                frame.header().checksum == self.commited_checksum,
+                "invalid replication log: checksum mismatch on frame {last_commited}"
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn read_header(file: &File) -> anyhow::Result<LogFileHeader> {
         let mut buf = [0; size_of::<LogFileHeader>()];
         file.read_exact_at(&mut buf, 0)?;
@@ -412,6 +621,29 @@ impl LogFile {
         &self.header
     }
 
+    /// Brings the log up to the current format. Returns `false` if there is no migration path
+    /// from the format of the log, and it must be rebuilt from the database file.
+    fn migrate(&mut self) -> anyhow::Result<bool> {
+        // A change of format must add the migration from the previous version here, rewriting
+        // the log in place, or side-by-side followed by an atomic rename.
+        match self.header.version {
+            LOG_FORMAT_VERSION => (),
+            // version 1 logs predate the versioning of the format.
+            _ => return Ok(false),
+        }
+
+        if self.header.sqld_version() != Version::current() {
+            tracing::info!(
+                "replication log was written by sqld {}, updating header",
+                self.header.sqld_version()
+            );
+            self.header.sqld_version = Version::current().0;
+            self.write_header()?;
+        }
+
+        Ok(true)
+    }
+
     pub fn commit(&mut self) -> anyhow::Result<()> {
         self.header.frame_count += self.uncommitted_frame_count;
         self.uncommitted_frame_count = 0;
@@ -495,6 +727,30 @@ impl LogFile {
         Ok(())
     }
 
+    /// Appends a frame received from the primary, keeping its frame_no and checksum.
+    fn push_frame(&mut self, frame: &FrameBorrowed) -> anyhow::Result<()> {
+        let byte_offset = self.next_byte_offset();
+        self.file.write_all_at(frame.as_slice(), byte_offset)?;
+
+        self.uncommitted_frame_count += 1;
+        self.uncommitted_checksum = frame.header().checksum;
+
+        Ok(())
+    }
+
+    /// Empties the log, which then starts at `frame_no`, right after a frame with checksum
+    /// `checksum`.
+    fn restart_at(&mut self, frame_no: FrameNo, checksum: u64) -> anyhow::Result<()> {
+        self.header.start_frame_no = frame_no;
+        self.header.frame_count = 0;
+        self.header.start_checksum = checksum;
+        self.uncommitted_frame_count = 0;
+        self.uncommitted_checksum = checksum;
+        self.commited_checksum = checksum;
+        self.file.set_len(Self::absolute_byte_offset(0))?;
+        self.write_header()
+    }
+
     /// offset in bytes at which to write the next frame
     fn next_byte_offset(&self) -> u64 {
         Self::absolute_byte_offset(self.header().frame_count + self.uncommitted_frame_count)
@@ -532,6 +788,10 @@ impl LogFile {
         }
 
         let frame = self.read_frame_byte_offset(self.byte_offset(frame_no)?.unwrap())?;
+        let found = frame.header().frame_no;
+        if found != frame_no {
+            return Err(LogReadError::Corrupted { frame_no, found });
+        }
 
         Ok(frame)
     }
@@ -671,7 +931,7 @@ pub struct LogFileHeader {
     pub start_frame_no: FrameNo,
     /// entry count in file
     pub frame_count: u64,
-    /// Wal file version number, currently: 2
+    /// Log format version number, currently: 2 (see [`LOG_FORMAT_VERSION`])
     pub version: u32,
     /// page size: 4096
     pub page_size: i32,
@@ -689,6 +949,29 @@ impl LogFileHeader {
     }
 }
 
+/// Prints the header of the replication log and of the snapshots of the database at `db_path`,
+/// without modifying them.
+pub fn check_log(db_path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    let log_path = db_path.join("wallog");
+    let file = File::open(&log_path)?;
+    let header = LogFile::read_header(&file)?;
+    let supported = match header.version {
+        LOG_FORMAT_VERSION => "current",
+        v if v > LOG_FORMAT_VERSION => "too recent",
+        _ => "outdated, will be rebuilt from the database file",
+    };
+    writeln!(out, "replication log: {}", log_path.display())?;
+    writeln!(out, "  format version: {} ({supported})", header.version)?;
+    writeln!(out, "  written by: sqld {}", header.sqld_version())?;
+    writeln!(out, "  database id: {}", Uuid::from_u128(header.db_id))?;
+    writeln!(out, "  start frame_no: {}", header.start_frame_no)?;
+    writeln!(out, "  frame count: {}", header.frame_count)?;
+    writeln!(out, "  start checksum: {:#018x}", header.start_checksum)?;
+    writeln!(out, "  page size: {}", header.page_size)?;
+
+    check_snapshots(db_path, out)
+}
+
 pub struct Generation {
     pub id: Uuid,
     pub start_index: u64,
@@ -703,6 +986,174 @@ impl Generation {
     }
 }
 
+/// When the last transactions of the log were committed.
+struct CommitTimes {
+    /// The frame_no following each transaction, and when it was committed, oldest first.
+    commits: VecDeque<(FrameNo, Instant)>,
+    /// The frames before this one were committed at an unknown time.
+    known_from: FrameNo,
+}
+
+impl CommitTimes {
+    fn new(next_frame_no: FrameNo) -> Self {
+        Self {
+            commits: VecDeque::new(),
+            known_from: next_frame_no,
+        }
+    }
+
+    fn next_frame_no(&self) -> FrameNo {
+        self.commits
+            .back()
+            .map_or(self.known_from, |&(frame_no, _)| frame_no)
+    }
+
+    fn record(&mut self, next_frame_no: FrameNo, at: Instant) {
+        let last = self.next_frame_no();
+        if next_frame_no < last {
+            // the log went back, e.g the standby restarted at an older frame
+            *self = Self::new(next_frame_no);
+        } else if next_frame_no > last {
+            self.commits.push_back((next_frame_no, at));
+            if self.commits.len() > MAX_COMMIT_TIMES {
+                let (frame_no, _) = self.commits.pop_front().unwrap();
+                self.known_from = frame_no;
+            }
+        }
+    }
+
+    fn lag(&self, next_offset: FrameNo, now: Instant) -> Option<Duration> {
+        if next_offset >= self.next_frame_no() {
+            return Some(Duration::ZERO);
+        }
+        if next_offset < self.known_from {
+            return None;
+        }
+        self.commits
+            .iter()
+            .find(|&&(frame_no, _)| frame_no > next_offset)
+            .map(|&(_, at)| now.saturating_duration_since(at))
+    }
+}
+
+/// A transaction in [`COMMIT_TIMESTAMPS_FILE`].
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct CommitTimestamp {
+    /// The frame_no following the transaction.
+    next_frame_no: FrameNo,
+    /// When the transaction was committed, in milliseconds since the unix epoch.
+    unix_ms: u64,
+}
+
+/// The wall-clock time at which the transactions of the log were committed, oldest first, so that
+/// a timestamp can be mapped to the frames committed before it.
+struct CommitTimestamps {
+    file: File,
+    path: PathBuf,
+    /// Number of transactions in the file.
+    count: u64,
+    /// The frame_no following the last transaction in the file.
+    next_frame_no: FrameNo,
+}
+
+impl CommitTimestamps {
+    const ENTRY_SIZE: u64 = size_of::<CommitTimestamp>() as u64;
+
+    fn open(db_path: &Path) -> anyhow::Result<Self> {
+        let path = db_path.join(COMMIT_TIMESTAMPS_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let count = file.metadata()?.len() / Self::ENTRY_SIZE;
+        // drop the partial entry of an interrupted write
+        file.set_len(count * Self::ENTRY_SIZE)?;
+        let mut this = Self {
+            file,
+            path,
+            count,
+            next_frame_no: 0,
+        };
+        if count > 0 {
+            this.next_frame_no = this.read(count - 1)?.next_frame_no;
+        }
+
+        Ok(this)
+    }
+
+    fn read(&self, nth: u64) -> anyhow::Result<CommitTimestamp> {
+        let mut buf = [0; size_of::<CommitTimestamp>()];
+        self.file.read_exact_at(&mut buf, nth * Self::ENTRY_SIZE)?;
+        Ok(pod_read_unaligned(&buf))
+    }
+
+    fn record(&mut self, next_frame_no: FrameNo, at: SystemTime) -> anyhow::Result<()> {
+        if next_frame_no < self.next_frame_no {
+            // the log went back, e.g the standby restarted at an older frame
+            self.file.set_len(0)?;
+            self.count = 0;
+        } else if next_frame_no == self.next_frame_no && self.count > 0 {
+            return Ok(());
+        }
+
+        let unix_ms = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = CommitTimestamp {
+            next_frame_no,
+            unix_ms,
+        };
+        self.file
+            .write_all_at(bytes_of(&entry), self.count * Self::ENTRY_SIZE)?;
+        self.count += 1;
+        self.next_frame_no = next_frame_no;
+
+        if self.count >= 2 * MAX_COMMIT_TIMESTAMPS {
+            self.trim()?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the last `MAX_COMMIT_TIMESTAMPS` transactions.
+    fn trim(&mut self) -> anyhow::Result<()> {
+        let keep = MAX_COMMIT_TIMESTAMPS;
+        let mut buf = vec![0; (keep * Self::ENTRY_SIZE) as usize];
+        self.file
+            .read_exact_at(&mut buf, (self.count - keep) * Self::ENTRY_SIZE)?;
+        let temp_path = self.path.with_extension("timestamps.tmp");
+        std::fs::write(&temp_path, &buf)?;
+        std::fs::rename(&temp_path, &self.path)?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.count = keep;
+
+        Ok(())
+    }
+
+    /// Returns the frame_no following the last transaction committed at or before `at`, or `None`
+    /// if no transaction is known to be committed by then.
+    fn next_frame_no_at(&self, at: SystemTime) -> anyhow::Result<Option<FrameNo>> {
+        let at_ms = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut found = None;
+        // the clock may go back, so the first transaction committed after `at` ends the search
+        for nth in 0..self.count {
+            let entry = self.read(nth)?;
+            if entry.unix_ms > at_ms {
+                break;
+            }
+            found = Some(entry.next_frame_no);
+        }
+
+        Ok(found)
+    }
+}
+
 pub struct ReplicationLogger {
     pub generation: Generation,
     pub log_file: RwLock<LogFile>,
@@ -711,6 +1162,13 @@ pub struct ReplicationLogger {
     /// a notifier channel other tasks can subscribe to, and get notified when new frames become
     /// available.
     pub new_frame_notifier: watch::Sender<FrameNo>,
+    commit_times: parking_lot::Mutex<CommitTimes>,
+    commit_timestamps: parking_lot::Mutex<CommitTimestamps>,
+    /// Set if the log was rebuilt from the database file when it was opened.
+    recovered: bool,
+    /// Frames whose next reads fail, to simulate I/O errors.
+    #[cfg(test)]
+    read_faults: parking_lot::Mutex<Vec<FrameNo>>,
 }
 
 impl ReplicationLogger {
@@ -733,14 +1191,30 @@ impl ReplicationLogger {
             .open(log_path)?;
 
         let max_log_frame_count = max_log_size * 1_000_000 / LogFile::FRAME_SIZE as u64;
-        let log_file = LogFile::new(file, max_log_frame_count, max_log_duration)?;
-        let header = log_file.header();
+        let mut log_file = LogFile::new(file, max_log_frame_count, max_log_duration)?;
+        if !dirty {
+            log_file.validate_frames()?;
+        }
+        if log_file.truncated_frames > 0 {
+            tracing::warn!(
+                "{} committed frames were truncated from the replication log",
+                log_file.truncated_frames
+            );
+        }
 
         let should_recover = if dirty {
             tracing::info!("Replication log is dirty, recovering from database file.");
             true
-        } else if header.version < 2 || header.sqld_version() != Version::current() {
-            tracing::info!("replication log version not compatible with current sqld version, recovering from database file.");
+        } else if log_file.incomplete && data_path.exists() {
+            tracing::info!(
+                "replication log is missing committed frames, recovering from database file."
+            );
+            true
+        } else if !log_file.migrate()? {
+            tracing::info!(
+                "replication log format version {} can't be migrated, recovering from database file.",
+                log_file.header().version
+            );
             true
         } else if fresh && data_path.exists() {
             tracing::info!("replication log not found, recovering from database file.");
@@ -764,6 +1238,9 @@ impl ReplicationLogger {
         let header = log_file.header();
         let generation_start_frame_no = header.start_frame_no + header.frame_count;
 
+        // the merger reads the snapshots as soon as the compactor is created.
+        migrate_snapshots(&db_path)?;
+
         let (new_frame_notifier, _) = watch::channel(generation_start_frame_no);
 
         Ok(Self {
@@ -772,6 +1249,11 @@ impl ReplicationLogger {
             log_file: RwLock::new(log_file),
             db_path,
             new_frame_notifier,
+            commit_times: parking_lot::Mutex::new(CommitTimes::new(generation_start_frame_no)),
+            commit_timestamps: parking_lot::Mutex::new(CommitTimestamps::open(&db_path)?),
+            recovered: false,
+            #[cfg(test)]
+            read_faults: Default::default(),
         })
     }
 
@@ -787,6 +1269,8 @@ impl ReplicationLogger {
         let snapshot_path = data_path.parent().unwrap().join("snapshots");
         // best effort, there may be no snapshots
         let _ = remove_dir_all(snapshot_path);
+        // the frames are renumbered, the commit times of the old ones are meaningless
+        let _ = std::fs::remove_file(data_path.parent().unwrap().join(COMMIT_TIMESTAMPS_FILE));
 
         let data_file = File::open(&data_path)?;
         let size = data_path.metadata()?.len();
@@ -811,13 +1295,136 @@ impl ReplicationLogger {
 
         assert!(data_path.pop());
 
-        Self::from_log_file(data_path, log_file, callback)
+        let mut this = Self::from_log_file(data_path, log_file, callback)?;
+        this.recovered = true;
+        Ok(this)
+    }
+
+    /// Opens the replication log of a standby, which mirrors the log of the primary of database
+    /// `database_id`. Unlike the log of a primary, it is never recovered from the database file: if
+    /// it can't be used as is, it is emptied, and starts over at the next frame received from the
+    /// primary.
+    pub fn open_standby(
+        db_path: &Path,
+        database_id: Uuid,
+        max_log_size: u64,
+        max_log_duration: Option<Duration>,
+        callback: SnapshotCallback,
+    ) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .open(db_path.join("wallog"))?;
+
+        let max_log_frame_count = max_log_size * 1_000_000 / LogFile::FRAME_SIZE as u64;
+        let mut log_file = LogFile::new(file, max_log_frame_count, max_log_duration)?;
+        if log_file.incomplete
+            || !log_file.migrate()?
+            || log_file.header.db_id != database_id.as_u128()
+        {
+            if log_file.header.frame_count > 0 {
+                tracing::info!("replication log can't be mirrored by the standby, starting over");
+            }
+            log_file = log_file.reset()?;
+            // best effort, there may be no snapshots
+            let _ = remove_dir_all(db_path.join("snapshots"));
+            let _ = std::fs::remove_file(db_path.join(COMMIT_TIMESTAMPS_FILE));
+            log_file.header.db_id = database_id.as_u128();
+            log_file.write_header()?;
+        }
+
+        Self::from_log_file(db_path.to_path_buf(), log_file, callback)
+    }
+
+    /// Whether the log was rebuilt from the database file when it was opened. The database file
+    /// is then vacuumed, and the pages of its previous version are gone.
+    pub fn recovered(&self) -> bool {
+        self.recovered
     }
 
     pub fn database_id(&self) -> anyhow::Result<Uuid> {
         Ok(Uuid::from_u128((self.log_file.read()).header().db_id))
     }
 
+    /// Returns the frame_no of the next frame written to the log.
+    pub fn next_frame_no(&self) -> FrameNo {
+        self.log_file.read().header().last_frame_no()
+    }
+
+    /// Empties the log of a standby, which then starts at `frame_no`. The log must not contain any
+    /// frame.
+    pub fn restart_at(&self, frame_no: FrameNo) -> anyhow::Result<()> {
+        let mut log_file = self.log_file.write();
+        ensure!(
+            log_file.header.frame_count == 0,
+            "the replication log ends at frame {}, it can't restart at frame {frame_no}",
+            log_file.header().last_frame_no(),
+        );
+        log_file.restart_at(frame_no, 0)
+    }
+
+    /// Appends frames received from the primary to the log of a standby, as they are. The frames
+    /// already in the log are skipped, and the log is committed at the end of every transaction.
+    /// New readers are not notified: the standby only announces the frames it has applied.
+    pub fn mirror_frames(&self, frames: &[Frame]) -> anyhow::Result<()> {
+        let mut log_file = self.log_file.write();
+        for frame in frames {
+            let frame_no = frame.header().frame_no;
+            let next_frame_no = log_file.next_frame_no();
+            if frame_no < next_frame_no {
+                continue;
+            }
+            if frame_no > next_frame_no {
+                ensure!(
+                    log_file.header.frame_count == 0 && log_file.uncommitted_frame_count == 0,
+                    "gap in the mirrored replication log: expected frame {next_frame_no}, received frame {frame_no}"
+                );
+                tracing::warn!(
+                    "mirrored replication log starts at frame {frame_no}, replicas behind it can't catch up from this node"
+                );
+                log_file.restart_at(frame_no, 0)?;
+            }
+
+            log_file.push_frame(frame)?;
+            if frame.header().size_after != 0 {
+                log_file.commit()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores a snapshot received from the primary by a standby, so that it can be served to the
+    /// replicas of the standby, and restarts the log right after the snapshot. The frames of the
+    /// snapshot are in decreasing frame_no order, and cover the changes since the end of the log.
+    pub fn mirror_snapshot<'a>(
+        &self,
+        frames: impl Iterator<Item = &'a FrameBorrowed>,
+    ) -> anyhow::Result<()> {
+        let mut log_file = self.log_file.write();
+        // a transaction interrupted by the snapshot
+        log_file.rollback();
+        // the frames logged so far go to a snapshot of their own
+        self.compact(&mut log_file)?;
+
+        let mut last = None;
+        let frames = frames.map(|frame| {
+            if last.is_none() {
+                last = Some(frame.header().into_owned());
+            }
+            Frame::try_from_bytes(Bytes::copy_from_slice(frame.as_slice()))
+        });
+        let start_frame_no = log_file.next_frame_no();
+        write_snapshot(&self.db_path, log_file.header.db_id, start_frame_no, frames)?;
+
+        if let Some(last) = last {
+            log_file.restart_at(last.frame_no + 1, last.checksum)?;
+        }
+
+        Ok(())
+    }
+
     /// Write pages to the log, without updating the file header.
     /// Returns the new frame count and checksum to commit
     fn write_pages(&self, pages: &[WalPage]) -> anyhow::Result<()> {
@@ -829,6 +1436,43 @@ impl ReplicationLogger {
         Ok(())
     }
 
+    /// Checks that the database file is at the state of the last transaction committed to the log,
+    /// by comparing their number of pages. They diverge if the database file was modified or
+    /// replaced without going through the replication log, in which case replicas would replicate
+    /// content that is not in the database. This can't be reconciled automatically: the log must
+    /// be rebuilt from the database file with `--force-recover`.
+    pub fn check_database(&self) -> anyhow::Result<()> {
+        let log_file = self.log_file.read();
+        let Some(last_commited) = log_file.last_commited_frame_no() else { return Ok(()) };
+        let log_page_count = log_file.frame(last_commited)?.header().size_after;
+        if log_page_count == 0 {
+            return Ok(());
+        }
+
+        let data_path = self.db_path.join("data");
+        ensure!(
+            data_path.try_exists()?,
+            "the replication log ends at frame {last_commited}, but the database file is missing: restart with --force-recover to rebuild the replication log from the database file"
+        );
+        // read-only, so that the WAL is not checkpointed behind the back of the replication log
+        let conn = rusqlite::Connection::open_with_flags(
+            &data_path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let db_page_count: u32 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        ensure!(
+            db_page_count == log_page_count,
+            "the database has {db_page_count} pages, but the replication log committed {log_page_count} pages at frame {last_commited}: the database was modified outside of sqld, or its replication log was replaced. Restart with --force-recover to rebuild the replication log from the database file"
+        );
+
+        Ok(())
+    }
+
+    /// Checks the end of the log, after appending to it failed. See [`LogFile::verify_tail`].
+    pub fn verify_tail(&self) -> anyhow::Result<()> {
+        self.log_file.write().verify_tail()
+    }
+
     #[allow(dead_code)]
     fn compute_checksum(wal_header: &LogFileHeader, log_file: &LogFile) -> anyhow::Result<u64> {
         tracing::debug!("computing WAL log running checksum...");
@@ -853,29 +1497,165 @@ impl ReplicationLogger {
         Ok(log_file.header().last_frame_no())
     }
 
-    pub fn get_snapshot_file(&self, from: FrameNo) -> anyhow::Result<Option<SnapshotFile>> {
-        find_snapshot_file(&self.db_path, from)
+    /// Makes the frames before `next_frame_no` available to the readers of the log, and records
+    /// that they were committed now.
+    pub fn announce_frames(&self, next_frame_no: FrameNo) {
+        self.commit_times
+            .lock()
+            .record(next_frame_no, Instant::now());
+        if let Err(e) = self
+            .commit_timestamps
+            .lock()
+            .record(next_frame_no, SystemTime::now())
+        {
+            tracing::warn!("could not record the commit time of frame {next_frame_no}: {e}");
+        }
+        self.new_frame_notifier.send_replace(next_frame_no);
     }
 
-    pub fn get_frame(&self, frame_no: FrameNo) -> Result<Frame, LogReadError> {
-        self.log_file.read().frame(frame_no)
+    /// Time since the oldest frame from `next_offset` on was committed, or zero if there is no
+    /// such frame yet. Returns `None` if the frame was committed before the oldest transaction
+    /// this node remembers, e.g before it restarted.
+    pub fn commit_lag(&self, next_offset: FrameNo) -> Option<Duration> {
+        self.commit_times.lock().lag(next_offset, Instant::now())
     }
 
-    pub fn maybe_compact(&self) -> anyhow::Result<bool> {
-        let mut log_file = self.log_file.write();
-        if !log_file.should_compact() {
-            // compaction is not necessary or impossible, so exit early
-            return Ok(false);
-        }
-
-        let last_frame = {
-            let mut frames_iter = log_file.rev_frames_iter()?;
-            let Some(last_frame_res) = frames_iter.next() else {
-                // the log file is empty, nothing to compact
-                return Ok(false)
-            };
-            last_frame_res?
-        };
+    /// Returns the frame_no following the last transaction committed at or before `at`, or `None`
+    /// if no transaction is known to be committed by then.
+    pub fn next_frame_no_at(&self, at: SystemTime) -> anyhow::Result<Option<FrameNo>> {
+        self.commit_timestamps.lock().next_frame_no_at(at)
+    }
+
+    /// Appends a copy of every page of the database file to the log, as a single transaction, and
+    /// compacts the log so that replicas load the new content from a snapshot. This must be called
+    /// after the database file was replaced without going through the replication hook.
+    pub fn log_database_image(&self) -> anyhow::Result<()> {
+        let data_path = self.db_path.join("data");
+        let data_file = File::open(&data_path)?;
+        let size = data_path.metadata()?.len();
+        ensure!(
+            size % WAL_PAGE_SIZE as u64 == 0,
+            "database file size is not a multiple of page size"
+        );
+        let num_page = size / WAL_PAGE_SIZE as u64;
+
+        let mut log_file = self.log_file.write();
+        let mut buf = [0; WAL_PAGE_SIZE as usize];
+        for i in 0..num_page {
+            data_file.read_exact_at(&mut buf, i * WAL_PAGE_SIZE as u64)?;
+            log_file.push_page(&WalPage {
+                page_no: i as u32 + 1,
+                size_after: if i == num_page - 1 { num_page as _ } else { 0 },
+                data: Bytes::copy_from_slice(&buf),
+            })?;
+        }
+        log_file.commit()?;
+        self.announce_frames(log_file.header().last_frame_no());
+
+        self.compact(&mut log_file)?;
+
+        Ok(())
+    }
+
+    /// Returns the snapshot containing frame `from`, if any. Fails with
+    /// [`crate::replication::HistoryTruncated`] if the frame predates all the snapshots.
+    pub fn get_snapshot_file(&self, from: FrameNo) -> anyhow::Result<Option<SnapshotFile>> {
+        find_snapshot_file(&self.db_path, from)
+    }
+
+    /// Merges the snapshots that `retention` doesn't keep, but those that the replicas needing the
+    /// frames from `pinned` on may still be transferring. Returns the number of snapshot files
+    /// removed.
+    pub fn retain_snapshots(
+        &self,
+        retention: SnapshotRetention,
+        pinned: Option<FrameNo>,
+    ) -> anyhow::Result<usize> {
+        self.compactor.retain_snapshots(retention, pinned)
+    }
+
+    pub fn get_frame(&self, frame_no: FrameNo) -> Result<Frame, LogReadError> {
+        self.read_frame(&self.log_file.read(), frame_no)
+    }
+
+    /// Reads the frames from `start` up to `end` (excluded), within the limits of a batch, holding
+    /// the lock of the log file only for the duration of the call. If a frame can't be read, the
+    /// frames read before it are returned, and the error is returned by the next call.
+    pub fn get_frames(
+        &self,
+        start: FrameNo,
+        end: FrameNo,
+        limits: BatchLimits,
+    ) -> Result<Vec<Frame>, LogReadError> {
+        let log_file = self.log_file.read();
+        let mut frames = Vec::new();
+        let mut bytes = 0;
+        for frame_no in start..end {
+            if frames.len() >= limits.max_frames || bytes >= limits.max_bytes {
+                break;
+            }
+            match self.read_frame(&log_file, frame_no) {
+                Ok(frame) => {
+                    bytes += frame.bytes().len();
+                    frames.push(frame);
+                }
+                Err(_) if !frames.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(frames)
+    }
+
+    fn read_frame(&self, log_file: &LogFile, frame_no: FrameNo) -> Result<Frame, LogReadError> {
+        #[cfg(test)]
+        {
+            let mut faults = self.read_faults.lock();
+            if let Some(i) = faults.iter().position(|&fno| fno == frame_no) {
+                faults.remove(i);
+                return Err(
+                    anyhow::anyhow!("injected fault: read of frame {frame_no} failed").into(),
+                );
+            }
+        }
+
+        match log_file.frame(frame_no) {
+            // the frame was compacted, but the snapshot of the compacted log may not be created yet
+            Err(LogReadError::SnapshotRequired) => self
+                .compactor
+                .compacting_frame(frame_no)
+                .unwrap_or(Err(LogReadError::SnapshotRequired)),
+            res => res,
+        }
+    }
+
+    /// Makes the next `count` reads of `frame_no` fail.
+    #[cfg(test)]
+    pub fn inject_read_faults(&self, frame_no: FrameNo, count: usize) {
+        self.read_faults
+            .lock()
+            .extend(std::iter::repeat(frame_no).take(count));
+    }
+
+    pub fn maybe_compact(&self) -> anyhow::Result<bool> {
+        let mut log_file = self.log_file.write();
+        if !log_file.should_compact() {
+            // compaction is not necessary or impossible, so exit early
+            return Ok(false);
+        }
+
+        self.compact(&mut log_file)
+    }
+
+    fn compact(&self, log_file: &mut LogFile) -> anyhow::Result<bool> {
+        let last_frame = {
+            let mut frames_iter = log_file.rev_frames_iter()?;
+            let Some(last_frame_res) = frames_iter.next() else {
+                // the log file is empty, nothing to compact
+                return Ok(false)
+            };
+            last_frame_res?
+        };
 
         let size_after = last_frame.header().size_after;
         assert!(size_after != 0);
@@ -885,7 +1665,7 @@ impl ReplicationLogger {
     }
 }
 
-fn checkpoint_db(data_path: &Path) -> anyhow::Result<()> {
+pub(crate) fn checkpoint_db(data_path: &Path) -> anyhow::Result<()> {
     unsafe {
         let conn = rusqlite::Connection::open(data_path)?;
         conn.pragma_query(None, "page_size", |row| {
@@ -951,6 +1731,63 @@ mod test {
         );
     }
 
+    const WALLOG_V2: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/assets/test/wallog-v2"
+    ));
+
+    #[test]
+    fn migrate_log_from_previous_sqld_version() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("wallog"), WALLOG_V2).unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+
+        let log_file = logger.log_file.read();
+        assert_eq!(log_file.header().version, LOG_FORMAT_VERSION);
+        assert!(log_file.header().sqld_version() == Version::current());
+        assert_eq!(
+            logger.database_id().unwrap().to_string(),
+            "5f0b8b3e-7d3c-4f4e-9a57-2f3c1d9e8a10"
+        );
+        // the log was not rebuilt
+        assert_eq!(log_file.header().frame_count, 3);
+        for i in 0..3 {
+            let frame = log_file.frame(i).unwrap();
+            assert_eq!(frame.header().page_no, i as u32 + 1);
+            assert!(frame.page().iter().all(|x| *x == i as u8 + 1));
+        }
+
+        // the header was updated on disk
+        let header = LogFile::read_header(&File::open(dir.path().join("wallog")).unwrap()).unwrap();
+        assert!(header.sqld_version() == Version::current());
+
+        let mut out = Vec::new();
+        check_log(dir.path(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("format version: 2 (current)"));
+        assert!(out.contains("frame count: 3"));
+    }
+
+    #[test]
+    fn refuse_log_from_newer_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut header: LogFileHeader =
+            pod_read_unaligned(&WALLOG_V2[..size_of::<LogFileHeader>()]);
+        header.version = LOG_FORMAT_VERSION + 1;
+        header.sqld_version = [0, 1, 0, 0];
+        let mut data = WALLOG_V2.to_vec();
+        data[..size_of::<LogFileHeader>()].copy_from_slice(bytes_of(&header));
+        std::fs::write(dir.path().join("wallog"), &data).unwrap();
+
+        let err = ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(())))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("sqld 1.0.0 or later is required"));
+        // the log was left untouched
+        assert_eq!(std::fs::read(dir.path().join("wallog")).unwrap(), data);
+    }
+
     #[test]
     fn index_out_of_bounds() {
         let dir = tempfile::tempdir().unwrap();
@@ -976,6 +1813,244 @@ mod test {
         logger.commit().unwrap();
     }
 
+    fn append_frames(logger: &ReplicationLogger, count: u32) {
+        let frames = (0..count)
+            .map(|i| WalPage {
+                page_no: i,
+                size_after: 0,
+                data: Bytes::from(vec![i as _; 4096]),
+            })
+            .collect::<Vec<_>>();
+        logger.write_pages(&frames).unwrap();
+        logger.commit().unwrap();
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        append_frames(&logger, 3);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("wallog"))
+            .unwrap();
+        file.write_at(&7u64.to_ne_bytes(), LogFile::absolute_byte_offset(1))
+            .unwrap();
+
+        assert!(logger.get_frame(0).is_ok());
+        assert!(matches!(
+            logger.get_frame(1),
+            Err(LogReadError::Corrupted {
+                frame_no: 1,
+                found: 7
+            })
+        ));
+    }
+
+    #[test]
+    fn truncate_partial_frame_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        append_frames(&logger, 3);
+        drop(logger);
+
+        // an append failed half-way through a frame
+        let log_path = dir.path().join("wallog");
+        let mut file = OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(&[42; 100]).unwrap();
+        drop(file);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        assert_eq!(logger.log_file.read().header().frame_count, 3);
+        assert_eq!(
+            log_path.metadata().unwrap().len(),
+            LogFile::absolute_byte_offset(3)
+        );
+        logger.verify_tail().unwrap();
+
+        append_frames(&logger, 1);
+        assert_eq!(logger.get_frame(3).unwrap().header().page_no, 0);
+    }
+
+    #[test]
+    fn recover_log_missing_committed_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open(dir.path().join("data")).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = wal; CREATE TABLE t (x); INSERT INTO t VALUES (42);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let db_id = logger.database_id().unwrap();
+        append_frames(&logger, 5);
+        drop(logger);
+
+        // the last committed frames didn't make it to the disk
+        let log_path = dir.path().join("wallog");
+        let file = OpenOptions::new().write(true).open(&log_path).unwrap();
+        file.set_len(LogFile::absolute_byte_offset(3) + 100)
+            .unwrap();
+        drop(file);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        // the log was rebuilt from the database file
+        assert_ne!(logger.database_id().unwrap(), db_id);
+        let data_pages = dir.path().join("data").metadata().unwrap().len() / 4096;
+        assert_eq!(logger.log_file.read().header().frame_count, data_pages);
+    }
+
+    fn corrupt_log(dir: &Path, offset: u64, bytes: &[u8]) {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.join("wallog"))
+            .unwrap();
+        file.write_at(bytes, offset).unwrap();
+    }
+
+    #[test]
+    fn truncate_corrupted_frames_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let db_id = logger.database_id().unwrap();
+        append_frames(&logger, 5);
+        drop(logger);
+
+        // a bit flipped in the page of frame 3
+        let page_offset = LogFile::absolute_byte_offset(3) + size_of::<FrameHeader>() as u64;
+        corrupt_log(dir.path(), page_offset + 42, &[0xff]);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        // without a database file to recover from, the frames before the corruption are kept
+        assert_eq!(logger.database_id().unwrap(), db_id);
+        assert_eq!(logger.log_file.read().header().frame_count, 3);
+        assert_eq!(logger.log_file.read().truncated_frames, 2);
+        logger.verify_tail().unwrap();
+
+        // the running checksum continues from the last valid frame
+        append_frames(&logger, 1);
+        drop(logger);
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        assert_eq!(logger.log_file.read().header().frame_count, 4);
+        assert_eq!(logger.log_file.read().truncated_frames, 0);
+    }
+
+    #[test]
+    fn truncate_misnumbered_frames_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        append_frames(&logger, 3);
+        drop(logger);
+
+        corrupt_log(
+            dir.path(),
+            LogFile::absolute_byte_offset(1),
+            &7u64.to_ne_bytes(),
+        );
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        assert_eq!(logger.log_file.read().header().frame_count, 1);
+        assert_eq!(logger.log_file.read().truncated_frames, 2);
+        assert!(matches!(logger.get_frame(1), Err(LogReadError::Ahead)));
+    }
+
+    #[test]
+    fn recover_corrupted_log_from_database_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open(dir.path().join("data")).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = wal; CREATE TABLE t (x); INSERT INTO t VALUES (42);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let db_id = logger.database_id().unwrap();
+        drop(logger);
+
+        let page_offset = LogFile::absolute_byte_offset(0) + size_of::<FrameHeader>() as u64;
+        corrupt_log(dir.path(), page_offset, &[0xff; 16]);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        assert_ne!(logger.database_id().unwrap(), db_id);
+        let data_pages = dir.path().join("data").metadata().unwrap().len() / 4096;
+        assert_eq!(logger.log_file.read().header().frame_count, data_pages);
+        logger.check_database().unwrap();
+    }
+
+    #[test]
+    fn refuse_log_with_invalid_magic() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        append_frames(&logger, 1);
+        drop(logger);
+
+        corrupt_log(dir.path(), 0, &[0; 8]);
+
+        assert!(ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).is_err());
+    }
+
+    #[test]
+    fn database_diverging_from_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open(dir.path().join("data")).unwrap();
+        conn.execute_batch("PRAGMA journal_mode = wal; CREATE TABLE t (x);")
+            .unwrap();
+        drop(conn);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        logger.check_database().unwrap();
+        // the log claims pages that are not in the database
+        write_transaction(&logger, 0..10);
+        drop(logger);
+
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let err = logger.check_database().unwrap_err();
+        assert!(err.to_string().contains("--force-recover"), "{err}");
+        drop(logger);
+
+        // what --force-recover does
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, true, Box::new(|_| Ok(()))).unwrap();
+        logger.check_database().unwrap();
+
+        std::fs::remove_file(dir.path().join("data")).unwrap();
+        write_transaction(&logger, 0..10);
+        assert!(logger.check_database().is_err());
+    }
+
+    #[test]
+    fn verify_truncated_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        append_frames(&logger, 3);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("wallog"))
+            .unwrap();
+        file.set_len(LogFile::absolute_byte_offset(2)).unwrap();
+        assert!(logger.verify_tail().is_err());
+    }
+
     #[test]
     fn log_file_test_rollback() {
         let f = tempfile::tempfile().unwrap();
@@ -1019,4 +2094,411 @@ mod test {
         log_file.commit().unwrap();
         assert_eq!(log_file.frames_iter().unwrap().count(), 6);
     }
+
+    /// Applies the committed frames of the log to an empty database, like a replica does.
+    fn replay_log(log_file: &LogFile) -> Vec<u8> {
+        let page_size = WAL_PAGE_SIZE as usize;
+        let mut db = Vec::new();
+        for frame in log_file.frames_iter().unwrap() {
+            let frame = frame.unwrap();
+            let offset = (frame.header().page_no as usize - 1) * page_size;
+            if db.len() < offset + page_size {
+                db.resize(offset + page_size, 0);
+            }
+            db[offset..offset + page_size].copy_from_slice(frame.page());
+            if frame.header().size_after != 0 {
+                db.truncate(frame.header().size_after as usize * page_size);
+            }
+        }
+
+        db
+    }
+
+    #[tokio::test]
+    async fn log_pages_once_per_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = Arc::new(
+            ReplicationLogger::open(dir.path(), 200, None, false, Box::new(|_| Ok(()))).unwrap(),
+        );
+        let mut ctx = ReplicationLoggerHookCtx::new(logger.clone(), None);
+        let conn = crate::database::libsql::open_db(
+            &dir.path().join("data"),
+            &REPLICATION_METHODS,
+            &mut ctx,
+            None,
+        )
+        .unwrap();
+        // with a small cache, SQLite spills the pages of the transaction to the WAL, and writes
+        // the counter again every time it is updated after being spilled
+        conn.execute_batch(
+            "PRAGMA cache_size = 10;
+            CREATE TABLE counter (n);
+            INSERT INTO counter VALUES (0);
+            CREATE TABLE blobs (x);",
+        )
+        .unwrap();
+
+        let deduplicated = frames_deduplicated_total();
+        conn.execute_batch("BEGIN").unwrap();
+        for _ in 0..50 {
+            conn.execute_batch(
+                "INSERT INTO blobs VALUES (randomblob(100000));
+                UPDATE counter SET n = n + 1;",
+            )
+            .unwrap();
+        }
+        conn.execute_batch("COMMIT").unwrap();
+        assert!(frames_deduplicated_total() > deduplicated);
+
+        {
+            let log_file = logger.log_file.read();
+            let mut frames = log_file.rev_frames_iter().unwrap().map(|f| f.unwrap());
+            let commit = frames.next().unwrap();
+            assert_ne!(commit.header().size_after, 0);
+            let mut pages = vec![commit.header().page_no];
+            pages.extend(
+                frames
+                    .take_while(|f| f.header().size_after == 0)
+                    .map(|f| f.header().page_no),
+            );
+            let count = pages.len();
+            pages.sort();
+            pages.dedup();
+            assert_eq!(pages.len(), count);
+        }
+
+        // a replica applying the log ends up with the same database as the primary
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))
+            .unwrap();
+        let primary = std::fs::read(dir.path().join("data")).unwrap();
+        assert_eq!(replay_log(&logger.log_file.read()), primary);
+    }
+
+    fn write_transaction(logger: &ReplicationLogger, pages: std::ops::Range<u32>) {
+        let last = pages.end - 1;
+        let pages = pages
+            .map(|page_no| WalPage {
+                page_no,
+                size_after: if page_no == last { page_no + 1 } else { 0 },
+                data: Bytes::from(vec![page_no as _; 4096]),
+            })
+            .collect::<Vec<_>>();
+        logger.write_pages(&pages).unwrap();
+        logger.commit().unwrap();
+    }
+
+    fn log_frames(logger: &ReplicationLogger) -> Vec<Frame> {
+        let log_file = logger.log_file.read();
+        (log_file.header.start_frame_no..log_file.header.last_frame_no())
+            .map(|frame_no| log_file.frame(frame_no).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn mirror_frames_of_primary() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let primary =
+            ReplicationLogger::open(primary_dir.path(), 0, None, false, Box::new(|_| Ok(())))
+                .unwrap();
+        write_transaction(&primary, 0..3);
+        write_transaction(&primary, 3..5);
+        let frames = log_frames(&primary);
+
+        let standby_dir = tempfile::tempdir().unwrap();
+        let standby = ReplicationLogger::open_standby(
+            standby_dir.path(),
+            primary.database_id().unwrap(),
+            0,
+            None,
+            Box::new(|_| Ok(())),
+        )
+        .unwrap();
+        standby.mirror_frames(&frames[..3]).unwrap();
+        // the frames already in the log are skipped
+        standby.mirror_frames(&frames).unwrap();
+        assert_eq!(standby.next_frame_no(), 5);
+        // mirrored frames are only announced once applied
+        assert_eq!(*standby.new_frame_notifier.borrow(), 0);
+        for (mirrored, frame) in log_frames(&standby).iter().zip(&frames) {
+            assert_eq!(mirrored.bytes(), frame.bytes());
+        }
+
+        // the standby can't skip frames
+        write_transaction(&primary, 5..6);
+        write_transaction(&primary, 6..7);
+        let frames = log_frames(&primary);
+        assert!(standby.mirror_frames(&frames[6..]).is_err());
+
+        // once promoted, the standby continues the log of the primary
+        write_transaction(&standby, 5..6);
+        assert_eq!(log_frames(&standby)[5].bytes(), frames[5].bytes());
+    }
+
+    #[test]
+    fn mirror_snapshot_of_primary() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let primary =
+            ReplicationLogger::open(primary_dir.path(), 0, None, false, Box::new(|_| Ok(())))
+                .unwrap();
+        write_transaction(&primary, 0..3);
+        write_transaction(&primary, 3..5);
+        let frames = log_frames(&primary);
+
+        let standby_dir = tempfile::tempdir().unwrap();
+        let database_id = primary.database_id().unwrap();
+        let standby = ReplicationLogger::open_standby(
+            standby_dir.path(),
+            database_id,
+            0,
+            None,
+            Box::new(|_| Ok(())),
+        )
+        .unwrap();
+        standby.mirror_frames(&frames[..3]).unwrap();
+        // a snapshot lists the frames in decreasing frame_no order
+        standby
+            .mirror_snapshot(frames[3..].iter().rev().map(|f| &**f))
+            .unwrap();
+
+        assert_eq!(standby.next_frame_no(), 5);
+        let snapshot_path = standby_dir
+            .path()
+            .join("snapshots")
+            .join(format!("{database_id}-3-4.snap"));
+        assert!(snapshot_path.exists());
+
+        // the log continues after the snapshot
+        write_transaction(&primary, 5..6);
+        let frames = log_frames(&primary);
+        standby.mirror_frames(&frames[5..]).unwrap();
+        assert_eq!(log_frames(&standby)[0].bytes(), frames[5].bytes());
+    }
+
+    #[test]
+    fn standby_log_of_other_database_is_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        write_transaction(&logger, 0..3);
+        drop(logger);
+
+        let database_id = Uuid::new_v4();
+        let standby =
+            ReplicationLogger::open_standby(dir.path(), database_id, 0, None, Box::new(|_| Ok(())))
+                .unwrap();
+        assert_eq!(standby.database_id().unwrap(), database_id);
+        assert_eq!(standby.next_frame_no(), 0);
+    }
+
+    #[test]
+    fn commit_times() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut times = CommitTimes::new(10);
+        times.record(15, at(1));
+        times.record(20, at(2));
+
+        assert_eq!(times.lag(20, at(5)), Some(Duration::ZERO));
+        assert_eq!(times.lag(17, at(5)), Some(Duration::from_secs(3)));
+        assert_eq!(times.lag(10, at(5)), Some(Duration::from_secs(4)));
+        // committed before the first transaction recorded
+        assert_eq!(times.lag(9, at(5)), None);
+
+        for i in 0..MAX_COMMIT_TIMES as u64 {
+            times.record(21 + i, at(3));
+        }
+        // the two oldest transactions were forgotten
+        assert_eq!(times.lag(20, at(5)), Some(Duration::from_secs(2)));
+        assert_eq!(times.lag(19, at(5)), None);
+
+        // the log restarted at an older frame
+        times.record(5, at(4));
+        assert_eq!(times.lag(5, at(5)), Some(Duration::ZERO));
+        assert_eq!(times.lag(4, at(5)), None);
+    }
+
+    #[test]
+    fn commit_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut timestamps = CommitTimestamps::open(dir.path()).unwrap();
+        timestamps.record(3, at(10)).unwrap();
+        timestamps.record(5, at(20)).unwrap();
+        // only the first commit of a frame is recorded
+        timestamps.record(5, at(25)).unwrap();
+
+        // the timestamps survive a restart
+        let mut timestamps = CommitTimestamps::open(dir.path()).unwrap();
+        assert_eq!(timestamps.next_frame_no_at(at(5)).unwrap(), None);
+        assert_eq!(timestamps.next_frame_no_at(at(10)).unwrap(), Some(3));
+        assert_eq!(timestamps.next_frame_no_at(at(22)).unwrap(), Some(5));
+
+        // the log restarted at an older frame
+        timestamps.record(2, at(30)).unwrap();
+        assert_eq!(timestamps.next_frame_no_at(at(22)).unwrap(), None);
+        assert_eq!(timestamps.next_frame_no_at(at(30)).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn lag_grows_while_replica_is_paused() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        write_transaction(&logger, 0..3);
+        logger.announce_frames(logger.next_frame_no());
+
+        // the replica received the first transaction, then stopped
+        assert_eq!(logger.commit_lag(3), Some(Duration::ZERO));
+        write_transaction(&logger, 3..5);
+        logger.announce_frames(logger.next_frame_no());
+        let lag = logger.commit_lag(3).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        write_transaction(&logger, 5..6);
+        logger.announce_frames(logger.next_frame_no());
+
+        let later = logger.commit_lag(3).unwrap();
+        assert!(later >= lag + Duration::from_millis(20), "{later:?}");
+        // frames the replica is not waiting for yet don't count
+        assert!(logger.commit_lag(5).unwrap() < later);
+        assert_eq!(logger.commit_lag(6), Some(Duration::ZERO));
+    }
+
+    /// Waits up to a few seconds for `f` to hold, as the compaction runs in the background.
+    fn wait_until(f: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn read_frames_while_compacting() {
+        let dir = tempfile::tempdir().unwrap();
+        // the compaction is held until the test releases it, once the snapshot is written
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = parking_lot::Mutex::new(released);
+        let logger = ReplicationLogger::open(
+            dir.path(),
+            0,
+            None,
+            false,
+            Box::new(move |_| Ok(released.lock().recv()?)),
+        )
+        .unwrap();
+        write_transaction(&logger, 0..10);
+        assert!(logger.maybe_compact().unwrap());
+        assert_eq!(logger.log_file.read().header.start_frame_no, 10);
+
+        // the frames are still read from the compacted log
+        assert_eq!(logger.get_frame(3).unwrap().header().frame_no, 3);
+        let frames = logger.get_frames(0, 10, BatchLimits::default()).unwrap();
+        assert_eq!(frames.len(), 10);
+
+        release.send(()).unwrap();
+        wait_until(|| matches!(logger.get_frame(3), Err(LogReadError::SnapshotRequired)));
+        // by then, the replicas find the frames in the snapshot
+        let snapshot = logger.get_snapshot_file(0).unwrap().unwrap();
+        assert_eq!(snapshot.frames_iter().count(), 10);
+        assert!(!dir.path().join("temp_log").exists());
+    }
+
+    fn disk_usage(path: &Path) -> u64 {
+        let metadata = std::fs::metadata(path).unwrap();
+        if !metadata.is_dir() {
+            return metadata.len();
+        }
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| disk_usage(&entry.unwrap().path()))
+            .sum()
+    }
+
+    #[test]
+    fn disk_usage_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+
+        // 1600 frames of the same 8 pages
+        for _ in 0..200 {
+            write_transaction(&logger, 0..8);
+            logger.maybe_compact().unwrap();
+        }
+        // the snapshots are merged in the background
+        wait_until(|| disk_usage(dir.path()) < 64 * LogFile::FRAME_SIZE as u64);
+        // a replica starting from scratch still finds the pages in the merged snapshots
+        let snapshot = logger.get_snapshot_file(0).unwrap().unwrap();
+        assert_eq!(snapshot.frames_iter().count(), 8);
+    }
+
+    /// The ranges of frames of the snapshots of the database at `db_path`, in order.
+    fn snapshot_ranges(db_path: &Path) -> Vec<(FrameNo, FrameNo)> {
+        let mut ranges: Vec<_> = std::fs::read_dir(db_path.join("snapshots"))
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                let name = name.strip_suffix(".snap")?;
+                let mut parts = name.rsplitn(3, '-');
+                let end = parts.next()?.parse().ok()?;
+                let start = parts.next()?.parse().ok()?;
+                Some((start, end))
+            })
+            .collect();
+        ranges.sort();
+        ranges
+    }
+
+    #[test]
+    fn retention_keeps_snapshots_needed_by_replicas() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        write_transaction(&logger, 0..64);
+        logger.maybe_compact().unwrap();
+        for _ in 0..4 {
+            write_transaction(&logger, 63..64);
+            logger.maybe_compact().unwrap();
+        }
+        wait_until(|| matches!(logger.get_frame(67), Err(LogReadError::SnapshotRequired)));
+        assert_eq!(
+            snapshot_ranges(dir.path()),
+            [(0, 63), (64, 64), (65, 65), (66, 66), (67, 67)]
+        );
+
+        let retention = SnapshotRetention {
+            max_snapshots: Some(2),
+            max_age: None,
+            min_keep: 1,
+        };
+        // a replica needs the frames from 65 on
+        assert_eq!(logger.retain_snapshots(retention, Some(65)).unwrap(), 1);
+        assert_eq!(
+            snapshot_ranges(dir.path()),
+            [(0, 64), (65, 65), (66, 66), (67, 67)]
+        );
+        // the replica advanced, or its session expired
+        assert_eq!(logger.retain_snapshots(retention, Some(68)).unwrap(), 2);
+        assert_eq!(snapshot_ranges(dir.path()), [(0, 66), (67, 67)]);
+
+        std::thread::sleep(Duration::from_millis(10));
+        let retention = SnapshotRetention {
+            max_snapshots: None,
+            max_age: Some(Duration::ZERO),
+            min_keep: 2,
+        };
+        assert_eq!(logger.retain_snapshots(retention, None).unwrap(), 0);
+        let retention = SnapshotRetention {
+            min_keep: 1,
+            ..retention
+        };
+        assert_eq!(logger.retain_snapshots(retention, None).unwrap(), 1);
+        assert_eq!(snapshot_ranges(dir.path()), [(0, 67)]);
+
+        // a replica starting from scratch still finds every page
+        let snapshot = logger.get_snapshot_file(0).unwrap().unwrap();
+        assert_eq!(snapshot.frames_iter().count(), 64);
+    }
 }
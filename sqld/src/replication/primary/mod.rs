@@ -1,2 +1,3 @@
+pub mod change_log;
 pub mod frame_stream;
 pub mod logger;
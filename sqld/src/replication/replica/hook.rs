@@ -81,6 +81,8 @@ pub struct InjectorHookCtx {
     pre_commit: Box<dyn Fn(FrameNo) -> anyhow::Result<()>>,
     /// invoked after injecting frames
     post_commit: Box<dyn Fn(FrameNo) -> anyhow::Result<()>>,
+    /// invoked with the frames received from the primary, before they are injected
+    mirror: Option<Box<dyn Fn(&Frames) -> anyhow::Result<()>>>,
 }
 
 impl InjectorHookCtx {
@@ -94,9 +96,18 @@ impl InjectorHookCtx {
             is_txn: false,
             pre_commit: Box::new(pre_commit),
             post_commit: Box::new(post_commit),
+            mirror: None,
         }
     }
 
+    pub fn with_mirror(
+        mut self,
+        mirror: impl Fn(&Frames) -> anyhow::Result<()> + 'static + Send,
+    ) -> Self {
+        self.mirror = Some(Box::new(mirror));
+        self
+    }
+
     fn inject_pages(
         &mut self,
         mut page_headers: Headers,
@@ -154,6 +165,13 @@ unsafe impl WalHook for InjectorHook {
         loop {
             match ctx.receiver.blocking_recv() {
                 Some(frames) => {
+                    if let Some(mirror) = &ctx.mirror {
+                        if let Err(e) = mirror(&frames) {
+                            tracing::error!("failed to mirror frames: {e}");
+                            return SQLITE_ERROR;
+                        }
+                    }
+
                     let (headers, last_frame_no, size_after) = frames.to_headers();
 
                     let ret = ctx.inject_pages(
@@ -21,6 +21,9 @@ impl<'a> FrameInjector<'a> {
             &INJECTOR_METHODS,
             hook_ctx,
         )?;
+        // the injected frames must be on disk before the replication offset is written, see
+        // `WalIndexMeta`.
+        conn.pragma_update(None, "synchronous", "FULL")?;
 
         Ok(Self { conn })
     }
@@ -0,0 +1,293 @@
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use bytemuck::{bytes_of, try_pod_read_unaligned, Pod, Zeroable};
+use futures::StreamExt;
+use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+use crate::database::libsql::open_db;
+use crate::replication::logical::{apply_batch, Change, ChangeBatch, ChangeFeed, TableFilter};
+use crate::replication::topology::{PrimaryInfo, Topology};
+use crate::rpc::replication_log::rpc::{
+    replication_log_client::ReplicationLogClient, HelloRequest, LogicalOffset, ProtocolVersion,
+    ReplicationMode,
+};
+use crate::rpc::replication_log::session_request;
+use crate::utils::backoff::{BackoffPolicy, Reconnect};
+
+type Client = ReplicationLogClient<Channel>;
+
+/// Position of a logical replica in the primary change log. Unlike [`super::meta::WalIndexMeta`],
+/// the offset is a logical offset, and is only meaningful relative to `log_id`.
+#[repr(C)]
+#[derive(Debug, Pod, Zeroable, Clone, Copy, Default)]
+struct LogicalIndexMeta {
+    /// Uuid of the primary change log
+    log_id: u128,
+    /// Offset of the last batch applied to the replica
+    offset: u64,
+    _pad: u64,
+}
+
+impl LogicalIndexMeta {
+    fn read_from_path(db_path: &Path) -> anyhow::Result<(Self, File)> {
+        let path = db_path.join("client_logical_index");
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        let mut buf = [0; size_of::<LogicalIndexMeta>()];
+        let meta = match file.read_exact_at(&mut buf, 0) {
+            Ok(()) => try_pod_read_unaligned(&buf)
+                .map_err(|_| anyhow::anyhow!("invalid logical index meta file"))?,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Self::default(),
+            Err(e) => Err(e)?,
+        };
+
+        Ok((meta, file))
+    }
+}
+
+/// The `LogicalReplicator` streams row-level changes to a subset of the primary tables, and
+/// applies them to the local database.
+pub struct LogicalReplicator {
+    client: Client,
+    filter: TableFilter,
+    meta_receiver: tokio::sync::watch::Receiver<LogicalIndexMeta>,
+    batch_sender: mpsc::Sender<ChangeBatch>,
+    /// The thread applying the batches to the local database, which only exits on error.
+    applier: JoinHandle<anyhow::Result<()>>,
+    topology: Arc<Topology>,
+    feed: ChangeFeed,
+    /// Identifies the replica to the primary across its connections.
+    replica_id: Uuid,
+    /// The token of the session opened by the last handshake, if the primary gave one.
+    session_token: Option<String>,
+    /// Spaces out the attempts to reach the primary while it's unreachable.
+    reconnect: Reconnect,
+}
+
+impl LogicalReplicator {
+    pub fn new(
+        db_path: PathBuf,
+        channel: Channel,
+        uri: tonic::transport::Uri,
+        filter: TableFilter,
+        topology: Arc<Topology>,
+        backoff: BackoffPolicy,
+    ) -> anyhow::Result<Self> {
+        let client = Client::with_origin(channel, uri);
+        let (meta, meta_file) = LogicalIndexMeta::read_from_path(&db_path)?;
+        let (meta_sender, meta_receiver) = tokio::sync::watch::channel(meta);
+        let (batch_sender, batch_receiver) = mpsc::channel(16);
+        let feed = ChangeFeed::new();
+
+        let applier = tokio::task::spawn_blocking({
+            let filter = filter.clone();
+            let feed = feed.clone();
+            move || {
+                run_applier(
+                    &db_path,
+                    filter,
+                    batch_receiver,
+                    meta_file,
+                    meta_sender,
+                    feed,
+                )
+            }
+        });
+
+        Ok(Self {
+            client,
+            filter,
+            meta_receiver,
+            batch_sender,
+            applier,
+            topology,
+            feed,
+            replica_id: Uuid::new_v4(),
+            session_token: None,
+            reconnect: Reconnect::new("primary", backoff),
+        })
+    }
+
+    /// The feed of the batches applied to the replica.
+    pub fn change_feed(&self) -> ChangeFeed {
+        self.feed.clone()
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        loop {
+            self.try_perform_handshake().await?;
+
+            let error = self
+                .replicate()
+                .await
+                .err()
+                .unwrap_or_else(|| anyhow::anyhow!("the primary closed the stream"));
+            self.topology.set_disconnected();
+            // the replica can't make progress without its applier
+            if self.applier.is_finished() {
+                return Err(self.applier_error().await);
+            }
+            let delay = self
+                .reconnect
+                .failed(format_args!("logical replication error: {error}"));
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn try_perform_handshake(&mut self) -> anyhow::Result<()> {
+        loop {
+            tracing::debug!("Attempting to perform logical handshake with primary.");
+            let req = HelloRequest {
+                table_filter: self.filter.tables().map(ToString::to_string).collect(),
+                protocol_version: Some(ProtocolVersion::CURRENT),
+                namespace: None,
+                replica_id: Some(self.replica_id.to_string()),
+                ..Default::default()
+            };
+            match self.client.hello(req).await {
+                Ok(resp) => {
+                    let hello = resp.into_inner();
+                    ProtocolVersion::check_peer(hello.protocol_version.as_ref())?;
+                    if hello.mode() != ReplicationMode::Logical {
+                        bail!("primary refused to replicate in logical mode");
+                    }
+                    self.session_token = hello.session_token;
+                    self.topology.set_connected(PrimaryInfo {
+                        advertise_addrs: hello.advertise_addrs,
+                        generation_id: Some(hello.generation_id),
+                    });
+                    self.reconnect.succeeded();
+                    return Ok(());
+                }
+                Err(e) => {
+                    let delay = self.reconnect.failed(&e);
+                    if self.reconnect.exhausted() {
+                        bail!(
+                            "couldn't connect to primary after {} tries.",
+                            self.reconnect.failures()
+                        );
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    async fn replicate(&mut self) -> anyhow::Result<()> {
+        let meta = *self.meta_receiver.borrow_and_update();
+        let offset = if meta.log_id == 0 {
+            LogicalOffset {
+                log_id: String::new(),
+                next_offset: None,
+            }
+        } else {
+            LogicalOffset {
+                log_id: Uuid::from_u128(meta.log_id).to_string(),
+                next_offset: Some(meta.offset + 1),
+            }
+        };
+
+        let req = session_request(offset, self.session_token.as_deref());
+        let mut stream = self.client.logical_entries(req).await?.into_inner();
+        while let Some(batch) = stream.next().await {
+            let batch = ChangeBatch::try_from(batch?)?;
+            if self.batch_sender.send(batch).await.is_err() {
+                bail!("logical change applier exited");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The error that stopped the applier, once it exited. Must only be called once.
+    async fn applier_error(&mut self) -> anyhow::Error {
+        match (&mut self.applier).await {
+            Ok(Ok(())) => anyhow!("logical change applier exited"),
+            Ok(Err(e)) => e.context("logical change applier failed"),
+            Err(e) => anyhow!("logical change applier crashed: {e}"),
+        }
+    }
+}
+
+fn run_applier(
+    db_path: &Path,
+    filter: TableFilter,
+    mut receiver: mpsc::Receiver<ChangeBatch>,
+    meta_file: File,
+    meta_sender: tokio::sync::watch::Sender<LogicalIndexMeta>,
+    feed: ChangeFeed,
+) -> anyhow::Result<()> {
+    let mut ctx = ();
+    let conn = open_db(db_path, &TRANSPARENT_METHODS, &mut ctx, None)?;
+    // the batches of the current transaction, published once it commits
+    let mut uncommitted = Vec::new();
+    // whether the current transaction is a full copy of the tables
+    let mut resetting = false;
+
+    while let Some(batch) = receiver.blocking_recv() {
+        // a reset batch starts a new copy of the tables, discard any partially applied one.
+        if batch.reset && !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK")?;
+            uncommitted.clear();
+        }
+        resetting |= batch.reset;
+
+        if conn.is_autocommit() {
+            conn.execute_batch("BEGIN IMMEDIATE")?;
+        }
+
+        if let Err(e) = apply_batch(&conn, &batch, &filter) {
+            conn.execute_batch("ROLLBACK")?;
+            return Err(e).context("failed to apply logical changes");
+        }
+
+        let complete = batch.complete;
+        let meta = LogicalIndexMeta {
+            log_id: batch.log_id.as_u128(),
+            offset: batch.offset.0,
+            _pad: 0,
+        };
+        if resetting {
+            // don't hold a copy of all the rows in memory: a full copy is only published as the
+            // recreation of the tables.
+            uncommitted.push(ChangeBatch {
+                changes: batch
+                    .changes
+                    .into_iter()
+                    .filter(|c| matches!(c, Change::Schema { .. }))
+                    .collect(),
+                ..batch
+            });
+        } else {
+            uncommitted.push(batch);
+        }
+
+        if complete {
+            conn.execute_batch("COMMIT")?;
+            resetting = false;
+            for batch in uncommitted.drain(..) {
+                feed.publish(Arc::new(batch));
+            }
+            // A crash between the commit and the write of the meta file causes the last batch to
+            // be sent again, which is fine since applying changes is idempotent.
+            meta_file.write_all_at(bytes_of(&meta), 0)?;
+            meta_sender.send_replace(meta);
+        }
+    }
+
+    Ok(())
+}
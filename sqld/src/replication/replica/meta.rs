@@ -6,13 +6,19 @@ use std::path::Path;
 use std::str::FromStr;
 
 use anyhow::Context;
-use bytemuck::{try_pod_read_unaligned, Pod, Zeroable};
+use bytemuck::{bytes_of, try_pod_read_unaligned, Pod, Zeroable};
 use uuid::Uuid;
 
 use crate::{replication::FrameNo, rpc::replication_log::rpc::HelloResponse};
 
 use super::error::ReplicationError;
 
+/// Replication offset of a physical replica, in the `client_wal_index` file.
+///
+/// The frames injected in the replica database are synced to its WAL before `post_commit_frame_no`
+/// is written, and the file is synced as well, so the file is never ahead of the database: after a
+/// crash, it lags by at most the last commit group, which the replica requests again. Injecting
+/// that group again writes the same page images, so it doesn't change the database.
 #[repr(C)]
 #[derive(Debug, Pod, Zeroable, Clone, Copy)]
 pub struct WalIndexMeta {
@@ -42,6 +48,20 @@ impl WalIndexMeta {
         Ok((Self::read(&file)?, file))
     }
 
+    /// Writes the meta to `file`, and waits for it to be on disk.
+    pub fn write(&self, file: &File) -> anyhow::Result<()> {
+        file.write_all_at(bytes_of(self), 0)?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Whether the replica stopped while injecting a commit group. The group may or may not be in
+    /// the database, and is requested again from `post_commit_frame_no`.
+    pub fn interrupted_commit(&self) -> bool {
+        self.pre_commit_frame_no != self.post_commit_frame_no
+    }
+
     fn read(file: &File) -> anyhow::Result<Option<Self>> {
         let mut buf = [0; size_of::<WalIndexMeta>()];
         let meta = match file.read_exact_at(&mut buf, 0) {
@@ -73,7 +93,9 @@ impl WalIndexMeta {
 
         if self.generation_id == hello_gen_id {
             Ok(self)
-        } else if self.pre_commit_frame_no <= hello.generation_start_index {
+        } else if self.pre_commit_frame_no == FrameNo::MAX
+            || self.pre_commit_frame_no < hello.generation_start_index
+        {
             // Ok: generation changed, but we aren't ahead of primary
             self.generation_id = hello_gen_id;
             Ok(self)
@@ -82,6 +104,23 @@ impl WalIndexMeta {
         }
     }
 
+    pub fn database_id(&self) -> Uuid {
+        Uuid::from_u128(self.database_id)
+    }
+
+    /// Describes the state of the replica and the primary, for the logs of a hard reset.
+    pub fn mismatch_details(&self, hello: &HelloResponse) -> String {
+        format!(
+            "replica: database {}, generation {}, next frame {}; primary: database {}, generation {}, generation start {}",
+            Uuid::from_u128(self.database_id),
+            Uuid::from_u128(self.generation_id),
+            self.pre_commit_frame_no,
+            hello.database_id,
+            hello.generation_id,
+            hello.generation_start_index,
+        )
+    }
+
     pub fn new_from_hello(hello: HelloResponse) -> anyhow::Result<WalIndexMeta> {
         let database_id = Uuid::from_str(&hello.database_id)
             .context("invalid database id from primary")?
@@ -98,3 +137,39 @@ impl WalIndexMeta {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hello(generation_id: Uuid, generation_start_index: FrameNo) -> HelloResponse {
+        HelloResponse {
+            generation_id: generation_id.to_string(),
+            generation_start_index,
+            database_id: Uuid::from_u128(1).to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_new_generation() {
+        let meta = WalIndexMeta {
+            pre_commit_frame_no: 9,
+            post_commit_frame_no: 9,
+            generation_id: 1,
+            database_id: 1,
+        };
+
+        // the new generation starts right after the last frame of the replica
+        let merged = meta
+            .merge_from_hello(hello(Uuid::from_u128(2), 10))
+            .unwrap();
+        assert_eq!(merged.generation_id, 2);
+        // the replica has a frame the new primary doesn't have
+        assert!(matches!(
+            meta.merge_from_hello(hello(Uuid::from_u128(2), 9)),
+            Err(ReplicationError::Lagging)
+        ));
+        assert!(meta.merge_from_hello(hello(Uuid::from_u128(1), 0)).is_ok());
+    }
+}
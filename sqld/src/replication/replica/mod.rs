@@ -1,8 +1,11 @@
 mod error;
 mod hook;
 mod injector;
+mod logical;
 mod meta;
 mod replicator;
 mod snapshot;
 
+pub use logical::LogicalReplicator;
+pub use meta::WalIndexMeta;
 pub use replicator::Replicator;
@@ -1,30 +1,41 @@
-use std::os::unix::prelude::FileExt;
+use std::convert::Infallible;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::bail;
-use bytemuck::bytes_of;
+use futures::stream::BoxStream;
 use futures::StreamExt;
 use parking_lot::Mutex;
 use tokio::sync::{mpsc, watch};
+use tokio::time::MissedTickBehavior;
 use tonic::transport::Channel;
+use uuid::Uuid;
 
+use crate::metrics;
+use crate::replication::compression::{self, SUPPORTED_CODECS};
 use crate::replication::frame::Frame;
 use crate::replication::replica::error::ReplicationError;
-use crate::replication::replica::snapshot::TempSnapshot;
+use crate::replication::replica::snapshot::{receive_snapshot, PartialSnapshot, TempSnapshot};
+use crate::replication::standby::{Standby, UpstreamGeneration};
+use crate::replication::topology::{PrimaryInfo, Topology};
 use crate::replication::FrameNo;
 use crate::rpc::replication_log::rpc::{
-    replication_log_client::ReplicationLogClient, HelloRequest, LogOffset,
+    self, replication_log_client::ReplicationLogClient, CompressionCodec, HelloRequest, LogOffset,
+    ProtocolVersion,
 };
-use crate::rpc::replication_log::NEED_SNAPSHOT_ERROR_MSG;
+use crate::rpc::replication_log::{
+    session_request, HISTORY_TRUNCATED_ERROR_MSG, LOG_CORRUPTED_ERROR_MSG, NEED_SNAPSHOT_ERROR_MSG,
+};
+use crate::utils::backoff::{BackoffPolicy, Reconnect};
 use crate::HARD_RESET;
 
 use super::hook::{Frames, InjectorHookCtx};
 use super::injector::FrameInjector;
 use super::meta::WalIndexMeta;
 
-const HANDSHAKE_MAX_RETRIES: usize = 100;
+/// How often the replica asks the primary how far behind it is.
+const LOG_STATUS_INTERVAL: Duration = Duration::from_secs(5);
 
 type Client = ReplicationLogClient<Channel>;
 
@@ -32,11 +43,27 @@ type Client = ReplicationLogClient<Channel>;
 /// transaction boundaries.
 pub struct Replicator {
     client: Client,
+    primary_uri: tonic::transport::Uri,
     db_path: PathBuf,
     meta: Arc<Mutex<Option<WalIndexMeta>>>,
     pub current_frame_no_notifier: watch::Receiver<FrameNo>,
     allow_replica_overwrite: bool,
     frames_sender: mpsc::Sender<Frames>,
+    topology: Arc<Topology>,
+    /// Set if the replica is a standby, which logs the frames it receives.
+    standby: Option<Arc<Standby>>,
+    /// Identifies the replica to the primary across its connections.
+    replica_id: Uuid,
+    /// The token of the session opened by the last handshake, if the primary gave one.
+    session_token: Option<String>,
+    /// Whether the primary streams the frames in batches.
+    frame_batches: bool,
+    /// The codec of the frames streamed by the primary.
+    codec: CompressionCodec,
+    /// Whether the primary can resume the transfer of snapshots.
+    resumable_snapshots: bool,
+    /// Spaces out the attempts to reach the primary while it's unreachable.
+    reconnect: Reconnect,
 }
 
 impl Replicator {
@@ -45,9 +72,24 @@ impl Replicator {
         channel: Channel,
         uri: tonic::transport::Uri,
         allow_replica_overwrite: bool,
+        topology: Arc<Topology>,
+        standby: Option<Arc<Standby>>,
+        backoff: BackoffPolicy,
     ) -> anyhow::Result<Self> {
-        let client = Client::with_origin(channel, uri);
+        let client = Client::with_origin(channel, uri.clone());
         let (meta, meta_file) = WalIndexMeta::read_from_path(&db_path)?;
+        if let Some(meta) = meta.filter(WalIndexMeta::interrupted_commit) {
+            tracing::info!(
+                "replication was interrupted while applying the frames up to {}, requesting them again",
+                meta.pre_commit_frame_no,
+            );
+        }
+        if let Some(ref standby) = standby {
+            let applied = meta
+                .map(|m| m.post_commit_frame_no)
+                .filter(|&fno| fno != FrameNo::MAX);
+            standby.start_at(applied)?;
+        }
         let meta_file = Arc::new(meta_file);
         let (applied_frame_notifier, current_frame_no_notifier) =
             watch::channel(meta.map(|m| m.post_commit_frame_no).unwrap_or(FrameNo::MAX));
@@ -63,7 +105,7 @@ impl Replicator {
                     .as_mut()
                     .expect("commit called before meta inialization");
                 meta.pre_commit_frame_no = fno;
-                meta_file.write_all_at(bytes_of(meta), 0)?;
+                meta.write(&meta_file)?;
 
                 Ok(())
             }
@@ -73,6 +115,7 @@ impl Replicator {
             let meta = meta.clone();
             let meta_file = meta_file;
             let notifier = applied_frame_notifier;
+            let standby = standby.clone();
             move |fno| {
                 let mut lock = meta.lock();
                 let meta = lock
@@ -80,8 +123,11 @@ impl Replicator {
                     .expect("commit called before meta inialization");
                 assert_eq!(meta.pre_commit_frame_no, fno);
                 meta.post_commit_frame_no = fno;
-                meta_file.write_all_at(bytes_of(meta), 0)?;
+                meta.write(&meta_file)?;
                 let _ = notifier.send(fno);
+                if let Some(ref standby) = standby {
+                    standby.set_applied_frame_no(Some(fno));
+                }
 
                 Ok(())
             }
@@ -89,8 +135,15 @@ impl Replicator {
 
         tokio::task::spawn_blocking({
             let db_path = db_path.clone();
+            let standby = standby.clone();
             move || -> anyhow::Result<()> {
                 let mut ctx = InjectorHookCtx::new(receiver, pre_commit, post_commit);
+                if let Some(standby) = standby {
+                    ctx = ctx.with_mirror(move |frames| match frames {
+                        Frames::Vec(frames) => standby.mirror_frames(frames),
+                        Frames::Snapshot(snap) => standby.mirror_snapshot(snap.iter()),
+                    });
+                }
                 let mut injector = FrameInjector::new(&db_path, &mut ctx)?;
 
                 while injector.step()? {}
@@ -101,11 +154,20 @@ impl Replicator {
 
         Ok(Self {
             client,
+            primary_uri: uri,
             db_path,
             current_frame_no_notifier,
             allow_replica_overwrite,
             meta,
             frames_sender,
+            topology,
+            standby,
+            replica_id: Uuid::new_v4(),
+            session_token: None,
+            frame_batches: false,
+            codec: CompressionCodec::None,
+            resumable_snapshots: true,
+            reconnect: Reconnect::new("primary", backoff),
         })
     }
 
@@ -113,63 +175,117 @@ impl Replicator {
         loop {
             self.try_perform_handshake().await?;
 
-            if let Err(e) = self.replicate().await {
-                // Replication encountered an error. We log the error, and then shut down the
-                // injector and propagate a potential panic from there.
-                tracing::warn!("replication error: {e}");
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            let log_status = poll_log_status(
+                self.client.clone(),
+                self.session_token.clone(),
+                self.current_frame_no_notifier.clone(),
+                self.topology.clone(),
+            );
+            let res = tokio::select! {
+                res = self.replicate() => res,
+                never = log_status => match never {},
+            };
+            self.topology.set_disconnected();
+            // Replication encountered an error: we wait for the primary to come back before
+            // performing a new handshake.
+            let error = res
+                .err()
+                .unwrap_or_else(|| anyhow::anyhow!("the primary closed the stream"));
+            let delay = self
+                .reconnect
+                .failed(format_args!("replication error: {error}"));
+            tokio::time::sleep(delay).await;
         }
     }
 
     async fn try_perform_handshake(&mut self) -> anyhow::Result<()> {
-        let mut error_printed = false;
-        for _ in 0..HANDSHAKE_MAX_RETRIES {
-            tracing::info!("Attempting to perform handshake with primary.");
-            match self.client.hello(HelloRequest {}).await {
+        loop {
+            tracing::debug!("Attempting to perform handshake with primary.");
+            let req = HelloRequest {
+                protocol_version: Some(ProtocolVersion::CURRENT),
+                replica_id: Some(self.replica_id.to_string()),
+                frame_batches: true,
+                compression_codecs: SUPPORTED_CODECS.iter().map(|&c| c.into()).collect(),
+                ..Default::default()
+            };
+            match self.client.hello(req).await {
                 Ok(resp) => {
                     let hello = resp.into_inner();
-                    return tokio::task::block_in_place(|| {
+                    ProtocolVersion::check_peer(hello.protocol_version.as_ref())?;
+                    self.session_token = hello.session_token.clone();
+                    self.frame_batches = hello.frame_batches;
+                    self.codec = hello.compression_codec();
+                    let primary_next_frame_no = hello.next_frame_no;
+                    let primary = PrimaryInfo {
+                        advertise_addrs: hello.advertise_addrs.clone(),
+                        generation_id: Some(hello.generation_id.clone()),
+                    };
+                    let generation = UpstreamGeneration {
+                        id: hello.generation_id.clone(),
+                        start_index: hello.generation_start_index,
+                    };
+                    tokio::task::block_in_place(|| {
                         let mut lock = self.meta.lock();
                         let meta = match *lock {
-                            Some(meta) => match meta.merge_from_hello(hello) {
-                                Ok(meta) => meta,
-                                Err(e @ ReplicationError::Lagging) => {
-                                    tracing::error!(
-                                        "Replica ahead of primary: hard-reseting replica"
-                                    );
-                                    HARD_RESET.notify_waiters();
-
-                                    anyhow::bail!(e);
-                                }
-                                Err(e @ ReplicationError::DbIncompatible)
-                                    if self.allow_replica_overwrite =>
-                                {
-                                    tracing::error!("Primary is attempting to replicate a different database, overwriting replica.");
-                                    HARD_RESET.notify_waiters();
+                            Some(meta) => {
+                                let mismatch = meta.mismatch_details(&hello);
+                                match meta.merge_from_hello(hello) {
+                                    Ok(meta) => meta,
+                                    Err(e @ ReplicationError::Lagging) => {
+                                        tracing::error!(
+                                            "Replica ahead of primary: hard-reseting replica"
+                                        );
+                                        HARD_RESET.request(format!(
+                                            "{e}, primary at {} ({mismatch})",
+                                            self.primary_uri
+                                        ));
 
-                                    anyhow::bail!(e);
+                                        anyhow::bail!(e);
+                                    }
+                                    Err(e @ ReplicationError::DbIncompatible)
+                                        if self.allow_replica_overwrite =>
+                                    {
+                                        tracing::error!("Primary is attempting to replicate a different database, overwriting replica.");
+                                        HARD_RESET.request(format!(
+                                            "{e}, primary at {} ({mismatch})",
+                                            self.primary_uri
+                                        ));
+
+                                        anyhow::bail!(e);
+                                    }
+                                    Err(e) => anyhow::bail!(e),
                                 }
-                                Err(e) => anyhow::bail!(e),
-                            },
+                            }
                             None => WalIndexMeta::new_from_hello(hello)?,
                         };
 
                         *lock = Some(meta);
 
                         Ok(())
-                    });
+                    })?;
+                    self.topology.set_connected(primary);
+                    if let Some(next_frame_no) = primary_next_frame_no.filter(|&fno| fno > 0) {
+                        self.topology.set_primary_frame_no(next_frame_no - 1);
+                    }
+                    if let Some(ref standby) = self.standby {
+                        standby.set_upstream_generation(generation);
+                    }
+                    self.reconnect.succeeded();
+
+                    return Ok(());
                 }
-                Err(e) if !error_printed => {
-                    tracing::error!("error connecting to primary. retrying. error: {e}");
-                    error_printed = true;
+                Err(e) => {
+                    let delay = self.reconnect.failed(&e);
+                    if self.reconnect.exhausted() {
+                        bail!(
+                            "couldn't connect to primary after {} tries.",
+                            self.reconnect.failures()
+                        );
+                    }
+                    tokio::time::sleep(delay).await;
                 }
-                _ => (),
             }
-            tokio::time::sleep(Duration::from_secs(1)).await;
         }
-
-        bail!("couldn't connect to primary after {HANDSHAKE_MAX_RETRIES} tries.");
     }
 
     async fn replicate(&mut self) -> anyhow::Result<()> {
@@ -177,22 +293,50 @@ impl Replicator {
         let offset = LogOffset {
             // if current == FrameNo::Max then it means that we're starting fresh
             next_offset: self.next_offset(),
+            ..Default::default()
         };
-        let mut stream = self.client.log_entries(offset).await?.into_inner();
+        let req = session_request(offset, self.session_token.as_deref());
+        let codec = self.codec;
+        // a stream of batches of frames, with a single frame each if the primary doesn't batch them
+        let mut stream: BoxStream<'static, Result<Vec<Frame>, tonic::Status>> =
+            if self.frame_batches {
+                let stream = self.client.batch_log_entries(req).await?.into_inner();
+                stream
+                    .map(move |batch| unpack_frames(batch?, codec))
+                    .boxed()
+            } else {
+                let stream = self.client.log_entries(req).await?.into_inner();
+                stream
+                    .map(move |frame| Ok(vec![parse_frame(frame?, codec)?]))
+                    .boxed()
+            };
 
         let mut buffer = Vec::new();
+        let mut last_checksum = None;
         loop {
             match stream.next().await {
-                Some(Ok(frame)) => {
-                    let frame = Frame::try_from_bytes(frame.data)?;
-                    buffer.push(frame.clone());
-                    if frame.header().size_after != 0
-                        || buffer.len() > MAX_REPLICA_REPLICATION_BUFFER_LEN
-                    {
-                        let _ = self
-                            .frames_sender
-                            .send(Frames::Vec(std::mem::take(&mut buffer)))
-                            .await;
+                Some(Ok(frames)) => {
+                    for frame in frames {
+                        // the decompressed frames must chain with the previous ones
+                        let compressed = codec != CompressionCodec::None;
+                        if let Some(previous) = last_checksum.filter(|_| compressed) {
+                            anyhow::ensure!(
+                                frame.verify_checksum(previous),
+                                "checksum mismatch on frame {} from the primary",
+                                frame.header().frame_no
+                            );
+                        }
+                        last_checksum = Some(frame.header().checksum);
+                        self.topology
+                            .advance_primary_frame_no(frame.header().frame_no);
+                        let commit = frame.header().size_after != 0;
+                        buffer.push(frame);
+                        if commit || buffer.len() > MAX_REPLICA_REPLICATION_BUFFER_LEN {
+                            let _ = self
+                                .frames_sender
+                                .send(Frames::Vec(std::mem::take(&mut buffer)))
+                                .await;
+                        }
                     }
                 }
                 Some(Err(err))
@@ -203,8 +347,20 @@ impl Replicator {
                     // remove any outstanding frames in the buffer that are not part of a
                     // transaction: they are now part of the snapshot.
                     buffer.clear();
+                    last_checksum = None;
+                    self.load_snapshot().await?;
+                }
+                // the frames can't be read from the log of the primary, but may be in a snapshot
+                Some(Err(err))
+                    if err.code() == tonic::Code::DataLoss
+                        && err.message() == LOG_CORRUPTED_ERROR_MSG =>
+                {
+                    tracing::warn!("replication log of the primary is corrupted, loading snapshot");
+                    buffer.clear();
+                    last_checksum = None;
                     self.load_snapshot().await?;
                 }
+                // other errors are retried from the same offset, after a new handshake
                 Some(Err(e)) => return Err(e.into()),
                 None => return Ok(()),
             }
@@ -213,23 +369,67 @@ impl Replicator {
 
     async fn load_snapshot(&mut self) -> anyhow::Result<()> {
         let next_offset = self.next_offset();
-        let frames = self
-            .client
-            .snapshot(LogOffset { next_offset })
-            .await?
-            .into_inner();
-
-        let stream = frames.map(|data| match data {
-            Ok(frame) => Frame::try_from_bytes(frame.data),
-            Err(e) => anyhow::bail!(e),
-        });
-        let snap = TempSnapshot::from_stream(&self.db_path, stream).await?;
+        let snap = match self.transfer_snapshot(next_offset).await {
+            Ok(snap) => snap,
+            Err(e) if is_history_truncated(&e) => {
+                tracing::error!(
+                    "Primary no longer has the frames the replica needs: hard-reseting replica"
+                );
+                HARD_RESET.request(format!(
+                    "history truncated before frame {next_offset}, primary at {}",
+                    self.primary_uri
+                ));
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
 
         let _ = self.frames_sender.send(Frames::Snapshot(snap)).await;
 
         Ok(())
     }
 
+    /// Transfers the snapshot from `next_offset`, resuming the previous transfer if possible.
+    async fn transfer_snapshot(&mut self, next_offset: FrameNo) -> anyhow::Result<TempSnapshot> {
+        if self.resumable_snapshots {
+            let req = PartialSnapshot::request(&self.db_path, next_offset)?;
+            let req = session_request(req, self.session_token.as_deref());
+            match self.client.resumable_snapshot(req).await {
+                Ok(resp) => {
+                    let stream = resp.into_inner();
+                    return receive_snapshot(&self.db_path, next_offset, stream, self.codec).await;
+                }
+                Err(e) if e.code() == tonic::Code::Unimplemented => {
+                    tracing::info!("the primary can't resume the transfer of snapshots");
+                    self.resumable_snapshots = false;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.fetch_snapshot(next_offset).await
+    }
+
+    /// Fetches the snapshot from `next_offset` from a primary that predates the resumable
+    /// transfers.
+    async fn fetch_snapshot(&mut self, next_offset: FrameNo) -> anyhow::Result<TempSnapshot> {
+        let req = session_request(
+            LogOffset {
+                next_offset,
+                ..Default::default()
+            },
+            self.session_token.as_deref(),
+        );
+        let frames = self.client.snapshot(req).await?.into_inner();
+
+        let codec = self.codec;
+        let stream = frames.map(move |data| match data {
+            Ok(frame) => compression::decompress(codec, frame.data),
+            Err(e) => anyhow::bail!(e),
+        });
+        TempSnapshot::from_stream(&self.db_path, stream).await
+    }
+
     fn next_offset(&mut self) -> FrameNo {
         self.current_frame_no().map(|x| x + 1).unwrap_or(0)
     }
@@ -239,3 +439,262 @@ impl Replicator {
         (current != FrameNo::MAX).then_some(current)
     }
 }
+
+/// Polls the lag of the replica behind its primary, and records it in `topology` and in the
+/// metrics. Never returns: it is dropped when the replication stream ends.
+async fn poll_log_status(
+    mut client: Client,
+    session_token: Option<String>,
+    applied: watch::Receiver<FrameNo>,
+    topology: Arc<Topology>,
+) -> Infallible {
+    let mut interval = tokio::time::interval(LOG_STATUS_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let next_offset = match *applied.borrow() {
+            FrameNo::MAX => 0,
+            fno => fno + 1,
+        };
+        let req = session_request(
+            LogOffset {
+                next_offset,
+                ..Default::default()
+            },
+            session_token.as_deref(),
+        );
+        match client.get_log_status(req).await {
+            Ok(resp) => {
+                let status = resp.into_inner();
+                let lag = status.lag_ms.map(Duration::from_millis);
+                if status.next_frame_no > 0 {
+                    topology.advance_primary_frame_no(status.next_frame_no - 1);
+                }
+                topology.set_primary_lag(lag);
+                metrics::set_replication_lag(status.next_frame_no.saturating_sub(next_offset), lag);
+            }
+            Err(e) if e.code() == tonic::Code::Unimplemented => {
+                tracing::info!("the primary doesn't report the replication lag");
+                return std::future::pending().await;
+            }
+            Err(e) => tracing::debug!("failed to get the replication lag from the primary: {e}"),
+        }
+    }
+}
+
+/// Whether the primary answered that it no longer has the frames the replica needs.
+fn is_history_truncated(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<tonic::Status>().map_or(false, |status| {
+        status.code() == tonic::Code::FailedPrecondition
+            && status.message() == HISTORY_TRUNCATED_ERROR_MSG
+    })
+}
+
+fn parse_frame(frame: rpc::Frame, codec: CompressionCodec) -> Result<Frame, tonic::Status> {
+    compression::decompress(codec, frame.data)
+        .map_err(|e| tonic::Status::internal(format!("invalid frame from the primary: {e}")))
+}
+
+/// Unpacks a batch of frames, which must be consecutive, starting at `first_frame_no`.
+fn unpack_frames(batch: rpc::Frames, codec: CompressionCodec) -> Result<Vec<Frame>, tonic::Status> {
+    let mut frames = Vec::with_capacity(batch.frames.len());
+    for (frame, frame_no) in batch.frames.into_iter().zip(batch.first_frame_no..) {
+        let frame = parse_frame(frame, codec)?;
+        if frame.header().frame_no != frame_no {
+            return Err(tonic::Status::internal(format!(
+                "invalid batch from the primary: expected frame {frame_no}, got frame {}",
+                frame.header().frame_no
+            )));
+        }
+        frames.push(frame);
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::replication::frame::FrameHeader;
+    use crate::replication::WAL_PAGE_SIZE;
+    use crate::rpc::replication_log::rpc::HelloResponse;
+
+    use super::*;
+
+    /// Returns the frames of a commit of all the pages of the database at `path`.
+    fn commit_frames(path: &std::path::Path, first_frame_no: FrameNo) -> Vec<Frame> {
+        let data = std::fs::read(path).unwrap();
+        let pages: Vec<_> = data.chunks(WAL_PAGE_SIZE as usize).collect();
+        pages
+            .iter()
+            .enumerate()
+            .map(|(i, page)| {
+                let header = FrameHeader {
+                    frame_no: first_frame_no + i as FrameNo,
+                    checksum: 0,
+                    page_no: i as u32 + 1,
+                    size_after: if i == pages.len() - 1 {
+                        pages.len() as u32
+                    } else {
+                        0
+                    },
+                };
+                Frame::from_parts(&header, page)
+            })
+            .collect()
+    }
+
+    /// Injects `groups` in the replica at `db_path`, like the replicator does, resuming from the
+    /// offset in the meta file. If `crash_at` is set, the replica crashes after injecting the
+    /// group ending at that frame, before writing the meta file.
+    fn replicate(db_path: &std::path::Path, groups: &[Vec<Frame>], crash_at: Option<FrameNo>) {
+        let (meta, meta_file) = WalIndexMeta::read_from_path(db_path).unwrap();
+        let meta = meta.unwrap_or_else(|| {
+            WalIndexMeta::new_from_hello(HelloResponse {
+                generation_id: Uuid::from_u128(1).to_string(),
+                database_id: Uuid::from_u128(2).to_string(),
+                ..Default::default()
+            })
+            .unwrap()
+        });
+        let next_frame_no = match meta.post_commit_frame_no {
+            FrameNo::MAX => 0,
+            fno => fno + 1,
+        };
+        let meta = Arc::new(Mutex::new(meta));
+        let meta_file = Arc::new(meta_file);
+
+        let pre_commit = {
+            let meta = meta.clone();
+            let meta_file = meta_file.clone();
+            move |fno| {
+                let mut meta = meta.lock();
+                meta.pre_commit_frame_no = fno;
+                meta.write(&meta_file)
+            }
+        };
+        let post_commit = move |fno| {
+            if Some(fno) == crash_at {
+                anyhow::bail!("crash");
+            }
+            let mut meta = meta.lock();
+            meta.post_commit_frame_no = fno;
+            meta.write(&meta_file)
+        };
+
+        let (sender, receiver) = mpsc::channel(1);
+        let injector = std::thread::spawn({
+            let db_path = db_path.to_path_buf();
+            move || -> anyhow::Result<()> {
+                let mut ctx = InjectorHookCtx::new(receiver, pre_commit, post_commit);
+                let mut injector = FrameInjector::new(&db_path, &mut ctx)?;
+                while injector.step()? {}
+                Ok(())
+            }
+        });
+        // the replica requests the frames after its offset
+        for group in groups {
+            if group[0].header().frame_no < next_frame_no {
+                continue;
+            }
+            if sender.blocking_send(Frames::Vec(group.clone())).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+
+        let res = injector.join().unwrap();
+        assert_eq!(res.is_err(), crash_at.is_some());
+    }
+
+    fn count(db_path: &std::path::Path) -> u64 {
+        let conn = rusqlite::Connection::open(db_path.join("data")).unwrap();
+        let check: String = conn
+            .query_row("PRAGMA integrity_check", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(check, "ok");
+        conn.query_row("SELECT count(*) FROM test", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn resume_after_crash_before_meta_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let primary = tmp.path().join("primary.db");
+        let conn = rusqlite::Connection::open(&primary).unwrap();
+        conn.execute_batch("PRAGMA journal_mode = WAL; CREATE TABLE test (x);")
+            .unwrap();
+        let commit = |first_frame_no| {
+            conn.execute_batch(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+                INSERT INTO test SELECT randomblob(1000) FROM n;
+                PRAGMA wal_checkpoint(TRUNCATE);",
+            )
+            .unwrap();
+            commit_frames(&primary, first_frame_no)
+        };
+        let first = commit(0);
+        let second = commit(first.len() as FrameNo);
+        let groups = [first, second];
+        let first_commit = groups[0].last().unwrap().header().frame_no;
+        let last_commit = groups[1].last().unwrap().header().frame_no;
+
+        let replica = tmp.path().join("replica");
+        std::fs::create_dir(&replica).unwrap();
+        // the first group is in the database, but the meta file doesn't know
+        replicate(&replica, &groups, Some(first_commit));
+        let (meta, _) = WalIndexMeta::read_from_path(&replica).unwrap();
+        let meta = meta.unwrap();
+        assert!(meta.interrupted_commit());
+        assert_eq!(meta.pre_commit_frame_no, first_commit);
+        assert_eq!(meta.post_commit_frame_no, FrameNo::MAX);
+        assert_eq!(count(&replica), 10);
+
+        // the first group is applied again, and the second one after it
+        replicate(&replica, &groups, None);
+        let (meta, _) = WalIndexMeta::read_from_path(&replica).unwrap();
+        let meta = meta.unwrap();
+        assert!(!meta.interrupted_commit());
+        assert_eq!(meta.post_commit_frame_no, last_commit);
+        assert_eq!(count(&replica), 20);
+
+        // nothing left to apply
+        replicate(&replica, &groups, None);
+        assert_eq!(count(&replica), 20);
+    }
+
+    #[test]
+    fn unpack_frame_batches() {
+        let frame = |frame_no| {
+            let header = FrameHeader {
+                frame_no,
+                checksum: 0,
+                page_no: 1,
+                size_after: 0,
+            };
+            rpc::Frame {
+                data: Frame::from_parts(&header, &[0; WAL_PAGE_SIZE as usize]).bytes(),
+            }
+        };
+
+        let batch = rpc::Frames {
+            first_frame_no: 5,
+            frames: vec![frame(5), frame(6), frame(7)],
+        };
+        let frames = unpack_frames(batch, CompressionCodec::None).unwrap();
+        let frame_nos: Vec<_> = frames.iter().map(|f| f.header().frame_no).collect();
+        assert_eq!(frame_nos, [5, 6, 7]);
+
+        let batch = rpc::Frames {
+            first_frame_no: 5,
+            frames: vec![frame(5), frame(7)],
+        };
+        assert!(unpack_frames(batch, CompressionCodec::None).is_err());
+        let batch = rpc::Frames {
+            first_frame_no: 4,
+            frames: vec![frame(5)],
+        };
+        assert!(unpack_frames(batch, CompressionCodec::None).is_err());
+    }
+}
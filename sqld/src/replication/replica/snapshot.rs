@@ -1,10 +1,25 @@
 use std::path::{Path, PathBuf};
 
+use anyhow::{bail, ensure};
 use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 use tokio::io::{AsyncWriteExt, BufWriter};
+use uuid::Uuid;
 
+use crate::replication::compression;
 use crate::replication::frame::{Frame, FrameBorrowed};
+use crate::replication::FrameNo;
+use crate::rpc::replication_log::rpc::snapshot_chunk::Chunk;
+use crate::rpc::replication_log::rpc::{
+    CompressionCodec, LogOffset, SnapshotChunk, SnapshotHeader,
+};
+
+/// The frames of the snapshot being received from the primary, in the directory of the database.
+const PARTIAL_SNAPSHOT_FILE: &str = "snapshot.partial";
+/// Describes the snapshot being received from the primary.
+const PARTIAL_SNAPSHOT_META_FILE: &str = "snapshot.partial.json";
 
 #[derive(Debug)]
 pub struct TempSnapshot {
@@ -37,6 +52,14 @@ impl TempSnapshot {
         Ok(Self { path, map })
     }
 
+    /// Takes ownership of the snapshot at `path`, which is removed once the snapshot is dropped.
+    fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(&path)?;
+        let map = unsafe { memmap::Mmap::map(&file)? };
+
+        Ok(Self { path, map })
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &FrameBorrowed> {
         self.map.chunks(Frame::SIZE).map(FrameBorrowed::from_bytes)
     }
@@ -48,3 +71,372 @@ impl Drop for TempSnapshot {
         let _ = std::fs::remove_file(path);
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PartialSnapshotMeta {
+    /// The offset the snapshot was requested from.
+    next_offset: FrameNo,
+    snapshot_id: String,
+    frame_count: u64,
+    /// SHA-256 of the frames of the snapshot.
+    content_hash: Vec<u8>,
+}
+
+/// A snapshot streamed by the primary, written to disk as it is received, so that its transfer
+/// resumes where it stopped if the replica is disconnected, or restarts.
+pub struct PartialSnapshot {
+    db_path: PathBuf,
+    meta: PartialSnapshotMeta,
+    file: BufWriter<tokio::fs::File>,
+    frames_received: u64,
+}
+
+impl PartialSnapshot {
+    /// The request for the snapshot from `next_offset`, which resumes the transfer of the partial
+    /// snapshot left by a previous request for the same offset, if any. The partial snapshots of
+    /// other offsets are discarded.
+    pub fn request(db_path: &Path, next_offset: FrameNo) -> anyhow::Result<LogOffset> {
+        let mut req = LogOffset {
+            next_offset,
+            ..Default::default()
+        };
+        match read_meta(db_path)? {
+            Some(meta) if meta.next_offset == next_offset => {
+                let len = match std::fs::metadata(db_path.join(PARTIAL_SNAPSHOT_FILE)) {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e.into()),
+                };
+                req.snapshot_id = Some(meta.snapshot_id);
+                req.frames_received = len / Frame::SIZE as u64;
+            }
+            _ => discard(db_path)?,
+        }
+
+        Ok(req)
+    }
+
+    /// Starts receiving the snapshot described by `header`, requested from `next_offset`, or
+    /// resumes receiving it if the primary skipped the frames received before.
+    async fn start(
+        db_path: &Path,
+        next_offset: FrameNo,
+        header: SnapshotHeader,
+    ) -> anyhow::Result<Self> {
+        let meta = PartialSnapshotMeta {
+            next_offset,
+            snapshot_id: header.snapshot_id,
+            frame_count: header.frame_count,
+            content_hash: header.content_hash,
+        };
+        let path = db_path.join(PARTIAL_SNAPSHOT_FILE);
+        let file = if header.frames_skipped > 0 {
+            ensure!(
+                read_meta(db_path)?.as_ref() == Some(&meta),
+                "the primary resumed the transfer of another snapshot than {}",
+                meta.snapshot_id
+            );
+            let file = tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .await?;
+            let len = header.frames_skipped * Frame::SIZE as u64;
+            ensure!(
+                file.metadata().await?.len() >= len,
+                "the primary skipped frames of snapshot {} that were not received",
+                meta.snapshot_id
+            );
+            // a frame may have been partially written when the transfer stopped
+            file.set_len(len).await?;
+            tracing::info!(
+                "resuming the transfer of snapshot {} after {} of its {} frames",
+                meta.snapshot_id,
+                header.frames_skipped,
+                meta.frame_count
+            );
+            file
+        } else {
+            discard(db_path)?;
+            write_meta(db_path, &meta)?;
+            tokio::fs::File::create(&path).await?
+        };
+
+        Ok(Self {
+            db_path: db_path.to_path_buf(),
+            meta,
+            file: BufWriter::new(file),
+            frames_received: header.frames_skipped,
+        })
+    }
+
+    async fn push(&mut self, frame: &Frame) -> anyhow::Result<()> {
+        ensure!(
+            self.frames_received < self.meta.frame_count,
+            "snapshot {} has more than {} frames",
+            self.meta.snapshot_id,
+            self.meta.frame_count
+        );
+        self.file.write_all(frame.as_slice()).await?;
+        self.frames_received += 1;
+
+        Ok(())
+    }
+
+    /// Verifies the snapshot once all its frames are received, and hands it over to be applied.
+    /// A snapshot that doesn't match its hash is discarded.
+    async fn finish(mut self) -> anyhow::Result<TempSnapshot> {
+        self.file.flush().await?;
+        ensure!(
+            self.frames_received == self.meta.frame_count,
+            "the transfer of snapshot {} stopped after {} of its {} frames",
+            self.meta.snapshot_id,
+            self.frames_received,
+            self.meta.frame_count
+        );
+
+        let path = self.db_path.join(PARTIAL_SNAPSHOT_FILE);
+        let hash = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || -> anyhow::Result<_> {
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+                Ok(hasher.finalize())
+            }
+        })
+        .await??;
+        if hash[..] != self.meta.content_hash[..] {
+            discard(&self.db_path)?;
+            bail!(
+                "snapshot {} doesn't match its hash, it must be transferred again",
+                self.meta.snapshot_id
+            );
+        }
+
+        // the snapshot is renamed, so that the next one can't overwrite it while it is applied
+        let temp_dir = self.db_path.join("temp");
+        tokio::fs::create_dir_all(&temp_dir).await?;
+        let temp_path = temp_dir.join(format!("snapshot-{}", Uuid::new_v4()));
+        tokio::fs::rename(&path, &temp_path).await?;
+        tokio::fs::remove_file(self.db_path.join(PARTIAL_SNAPSHOT_META_FILE)).await?;
+
+        TempSnapshot::open(temp_path)
+    }
+}
+
+/// Receives the snapshot streamed by `ResumableSnapshot`, for a request made with
+/// [`PartialSnapshot::request`]. If the stream fails, the frames received so far are kept, and the
+/// next request resumes the transfer after them.
+pub async fn receive_snapshot(
+    db_path: &Path,
+    next_offset: FrameNo,
+    mut stream: impl Stream<Item = Result<SnapshotChunk, tonic::Status>> + Unpin,
+    codec: CompressionCodec,
+) -> anyhow::Result<TempSnapshot> {
+    let header = match stream.next().await {
+        Some(Ok(SnapshotChunk {
+            chunk: Some(Chunk::Header(header)),
+        })) => header,
+        Some(Err(e)) => return Err(e.into()),
+        _ => bail!("the snapshot streamed by the primary doesn't start with a header"),
+    };
+    let mut partial = PartialSnapshot::start(db_path, next_offset, header).await?;
+
+    let received = async {
+        while let Some(chunk) = stream.next().await {
+            let Some(Chunk::Frame(frame)) = chunk?.chunk else {
+                bail!("the snapshot streamed by the primary has several headers");
+            };
+            let frame = compression::decompress(codec, frame.data)?;
+            partial.push(&frame).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if let Err(e) = received {
+        // the error of the stream matters more than the one of the flush
+        let _ = partial.file.flush().await;
+        return Err(e);
+    }
+
+    partial.finish().await
+}
+
+fn read_meta(db_path: &Path) -> anyhow::Result<Option<PartialSnapshotMeta>> {
+    match std::fs::read(db_path.join(PARTIAL_SNAPSHOT_META_FILE)) {
+        Ok(data) => Ok(serde_json::from_slice(&data).ok()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_meta(db_path: &Path, meta: &PartialSnapshotMeta) -> anyhow::Result<()> {
+    let path = db_path.join(PARTIAL_SNAPSHOT_META_FILE);
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(meta)?)?;
+    std::fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+/// Removes the partial snapshot of the database at `db_path`, if any.
+fn discard(db_path: &Path) -> anyhow::Result<()> {
+    for name in [PARTIAL_SNAPSHOT_META_FILE, PARTIAL_SNAPSHOT_FILE] {
+        match std::fs::remove_file(db_path.join(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::FileExt;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    use tonic::Status;
+
+    use crate::replication::frame::FrameHeader;
+    use crate::replication::snapshot::write_snapshot;
+    use crate::replication::{ReplicationLogger, WAL_PAGE_SIZE};
+    use crate::rpc::replication_log::rpc::replication_log_server::ReplicationLog;
+    use crate::rpc::replication_log::ReplicationLogService;
+
+    use super::*;
+
+    /// Writes a snapshot of the frames from 0 to `count` (excluded), each of a page of its own.
+    fn write_test_snapshot(logger: &ReplicationLogger, path: &Path, count: u64) {
+        let frames = (0..count).rev().map(|frame_no| {
+            let header = FrameHeader {
+                frame_no,
+                checksum: 0,
+                page_no: frame_no as u32 + 1,
+                size_after: if frame_no == count - 1 { count as _ } else { 0 },
+            };
+            Ok(Frame::from_parts(
+                &header,
+                &[frame_no as u8; WAL_PAGE_SIZE as usize],
+            ))
+        });
+        let db_id = logger.database_id().unwrap().as_u128();
+        write_snapshot(path, db_id, 0, frames).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_snapshot_transfer() {
+        let primary = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(primary.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write_test_snapshot(&logger, primary.path(), 20);
+        let service = ReplicationLogService::new(logger, None, None, Vec::new());
+        let replica = tempfile::tempdir().unwrap();
+        let codec = CompressionCodec::None;
+
+        let frames = service
+            .snapshot(tonic::Request::new(LogOffset::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let expected: Vec<_> = frames.map(|frame| frame.unwrap().data).collect().await;
+        assert_eq!(expected.len(), 20);
+
+        // the connection is lost after 7 frames
+        let req = PartialSnapshot::request(replica.path(), 0).unwrap();
+        assert_eq!(req.snapshot_id, None);
+        let stream = service
+            .resumable_snapshot(tonic::Request::new(req))
+            .await
+            .unwrap()
+            .into_inner()
+            .take(8)
+            .chain(futures::stream::iter([Err(Status::unavailable("lost"))]));
+        let err = receive_snapshot(replica.path(), 0, stream, codec)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("lost"), "{err}");
+
+        // the replica resumes after the frames it received
+        let req = PartialSnapshot::request(replica.path(), 0).unwrap();
+        assert!(req.snapshot_id.is_some());
+        assert_eq!(req.frames_received, 7);
+        let mut stream = service
+            .resumable_snapshot(tonic::Request::new(req))
+            .await
+            .unwrap()
+            .into_inner()
+            .peekable();
+        match Pin::new(&mut stream).peek().await {
+            Some(Ok(SnapshotChunk {
+                chunk: Some(Chunk::Header(header)),
+            })) => {
+                assert_eq!(header.frame_count, 20);
+                assert_eq!(header.frames_skipped, 7);
+            }
+            other => panic!("the snapshot doesn't start with a header: {other:?}"),
+        }
+        let snapshot = receive_snapshot(replica.path(), 0, stream, codec)
+            .await
+            .unwrap();
+        let received: Vec<_> = snapshot.iter().map(|frame| frame.as_slice()).collect();
+        assert_eq!(received, expected);
+        assert!(!replica.path().join("snapshot.partial").exists());
+    }
+
+    #[tokio::test]
+    async fn corrupted_partial_snapshot_is_discarded() {
+        let primary = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(primary.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write_test_snapshot(&logger, primary.path(), 10);
+        let service = ReplicationLogService::new(logger, None, None, Vec::new());
+        let replica = tempfile::tempdir().unwrap();
+        let codec = CompressionCodec::None;
+
+        let req = PartialSnapshot::request(replica.path(), 0).unwrap();
+        let stream = service
+            .resumable_snapshot(tonic::Request::new(req))
+            .await
+            .unwrap()
+            .into_inner()
+            .take(5);
+        assert!(receive_snapshot(replica.path(), 0, stream, codec)
+            .await
+            .is_err());
+
+        // the frames received before are damaged on disk
+        let partial = replica.path().join("snapshot.partial");
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&partial)
+            .unwrap();
+        file.write_all_at(b"garbage", 100).unwrap();
+
+        let req = PartialSnapshot::request(replica.path(), 0).unwrap();
+        assert_eq!(req.frames_received, 4);
+        let stream = service
+            .resumable_snapshot(tonic::Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let err = receive_snapshot(replica.path(), 0, stream, codec)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("doesn't match its hash"), "{err}");
+        assert!(!partial.exists());
+
+        // the transfer starts over
+        let req = PartialSnapshot::request(replica.path(), 0).unwrap();
+        assert_eq!(req.snapshot_id, None);
+        let stream = service
+            .resumable_snapshot(tonic::Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let snapshot = receive_snapshot(replica.path(), 0, stream, codec)
+            .await
+            .unwrap();
+        assert_eq!(snapshot.iter().count(), 10);
+    }
+}
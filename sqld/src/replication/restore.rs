@@ -0,0 +1,446 @@
+//! Point-in-time restores of a database from its replication log.
+//!
+//! The database is rebuilt as it was right after one of its transactions, from the frames of the
+//! log and of the snapshots, into a fresh directory: the live database is never touched. The
+//! frames are applied from the most recent to the oldest, and each page is written once, from its
+//! most recent frame. The frames of the log are chained by their checksums, which are verified as
+//! they are applied.
+//!
+//! The frames of a snapshot are deduplicated, so the transactions it contains can't be told apart:
+//! a database can only be restored to a transaction of the log, or to the end of a snapshot.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use super::frame::Frame;
+use super::{FrameNo, HistoryTruncated, ReplicationLogger, SnapshotFile, WAL_PAGE_SIZE};
+
+/// Directory of the database the restores are exported to.
+const EXPORTS_DIR: &str = "exports";
+const MAX_EXPORT_NAME_LEN: usize = 64;
+/// How long to wait for the snapshot of a log being compacted.
+const SNAPSHOT_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub enum RestoreTarget {
+    /// The last transaction committed at or before this frame.
+    FrameNo(FrameNo),
+    /// The last transaction committed at or before this time.
+    Timestamp(SystemTime),
+}
+
+/// The transaction a database was restored to.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct RestorePoint {
+    /// Last frame of the transaction.
+    pub frame_no: FrameNo,
+    /// Size of the restored database, in pages.
+    pub page_count: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestoreError {
+    #[error("invalid export name `{0}`: names are 1 to 64 ASCII letters, digits, `-` or `_`")]
+    InvalidName(String),
+    #[error("export `{0}` already exists")]
+    AlreadyExists(String),
+    #[error("cannot restore the database: {0}")]
+    Unreachable(String),
+    #[error("replication log is corrupted: checksum mismatch at frame {0}")]
+    ChecksumMismatch(FrameNo),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// The directory the export `name` of the database at `db_path` is restored to.
+pub fn export_path(db_path: &Path, name: &str) -> Result<PathBuf, RestoreError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_EXPORT_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(RestoreError::InvalidName(name.to_string()));
+    }
+
+    Ok(db_path.join(EXPORTS_DIR).join(name))
+}
+
+/// Rebuilds the database of `logger` as it was after `target`, into the `data` file of the new
+/// directory `export_path`. The directory is removed if the restore fails.
+pub fn restore_to(
+    logger: &ReplicationLogger,
+    target: RestoreTarget,
+    export_path: &Path,
+) -> Result<RestorePoint, RestoreError> {
+    if let Some(parent) = export_path.parent() {
+        std::fs::create_dir_all(parent).context("could not create the exports directory")?;
+    }
+    match std::fs::create_dir(export_path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(RestoreError::AlreadyExists(
+                export_path.display().to_string(),
+            ))
+        }
+        Err(e) => return Err(anyhow::Error::from(e).into()),
+    }
+
+    let res = restore_into(logger, target, &export_path.join("data"));
+    match &res {
+        Ok(point) => tracing::info!(
+            "restored frame {} of the database to {}",
+            point.frame_no,
+            export_path.display()
+        ),
+        Err(_) => {
+            let _ = std::fs::remove_dir_all(export_path);
+        }
+    }
+
+    res
+}
+
+fn restore_into(
+    logger: &ReplicationLogger,
+    target: RestoreTarget,
+    data_path: &Path,
+) -> Result<RestorePoint, RestoreError> {
+    let frame_no = match target {
+        RestoreTarget::FrameNo(frame_no) => frame_no,
+        RestoreTarget::Timestamp(at) => match logger.next_frame_no_at(at)? {
+            Some(next_frame_no) if next_frame_no > 0 => next_frame_no - 1,
+            _ => {
+                let unix_ms = at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                return Err(RestoreError::Unreachable(format!(
+                    "no transaction is known to be committed by {unix_ms}ms since the unix epoch"
+                )));
+            }
+        },
+    };
+
+    let (start_frame_no, start_checksum, next_frame_no) = {
+        let log_file = logger.log_file.read();
+        let header = log_file.header();
+        (
+            header.start_frame_no,
+            header.start_checksum,
+            header.last_frame_no(),
+        )
+    };
+    if frame_no >= next_frame_no {
+        return Err(RestoreError::Unreachable(format!(
+            "frame {frame_no} was not committed yet"
+        )));
+    }
+
+    // the last transaction committed at or before `frame_no` in the log, if any
+    let mut commit = None;
+    if frame_no >= start_frame_no {
+        for frame_no in (start_frame_no..=frame_no).rev() {
+            let frame = read_log_frame(logger, frame_no)?;
+            if frame.header().size_after != 0 {
+                commit = Some(frame);
+                break;
+            }
+        }
+    }
+
+    let point = match &commit {
+        Some(frame) => RestorePoint {
+            frame_no: frame.header().frame_no,
+            page_count: frame.header().size_after,
+        },
+        None => {
+            let frame_no = frame_no.min(start_frame_no.checked_sub(1).ok_or_else(|| {
+                RestoreError::Unreachable(format!(
+                    "no transaction was committed by frame {frame_no}"
+                ))
+            })?);
+            let snapshot = find_snapshot(logger, frame_no)?;
+            let header = snapshot.header();
+            if header.end_frame_no != frame_no {
+                let mut points = vec![header.end_frame_no.to_string()];
+                if header.start_frame_no > 0 {
+                    points.insert(0, (header.start_frame_no - 1).to_string());
+                }
+                return Err(RestoreError::Unreachable(format!(
+                    "frame {frame_no} was compacted into a snapshot of frames {} to {}, the closest frames it can be restored to are {}",
+                    header.start_frame_no,
+                    header.end_frame_no,
+                    points.join(" and ")
+                )));
+            }
+            RestorePoint {
+                frame_no,
+                page_count: header.size_after,
+            }
+        }
+    };
+
+    let file = File::create(data_path).context("could not create the restored database file")?;
+    let mut restored = RestoredFile {
+        file,
+        page_count: point.page_count,
+        written: HashSet::new(),
+    };
+
+    let mut snapshots_end = Some(point.frame_no);
+    if let Some(mut frame) = commit {
+        // apply the log back to its first frame, verifying the checksum of each frame with the
+        // checksum of the frame before it
+        loop {
+            let frame_no = frame.header().frame_no;
+            let previous = if frame_no > start_frame_no {
+                Some(read_log_frame(logger, frame_no - 1)?)
+            } else {
+                None
+            };
+            let previous_checksum = previous
+                .as_ref()
+                .map_or(start_checksum, |previous| previous.header().checksum);
+            if !frame.verify_checksum(previous_checksum) {
+                return Err(RestoreError::ChecksumMismatch(frame_no));
+            }
+            restored.apply(&frame)?;
+
+            match previous {
+                Some(previous) => frame = previous,
+                None => break,
+            }
+        }
+        snapshots_end = start_frame_no.checked_sub(1);
+    }
+
+    // apply the snapshots back to the first frame of the database
+    while let Some(end_frame_no) = snapshots_end {
+        let snapshot = find_snapshot(logger, end_frame_no)?;
+        let header = *snapshot.header();
+        if header.end_frame_no != end_frame_no {
+            return Err(anyhow::anyhow!(
+                "the snapshot ending at frame {end_frame_no} was merged during the restore"
+            )
+            .into());
+        }
+        for bytes in snapshot.frames_iter() {
+            restored.apply(&Frame::try_from_bytes(bytes?)?)?;
+        }
+        snapshots_end = header.start_frame_no.checked_sub(1);
+    }
+
+    restored.finish()?;
+
+    Ok(point)
+}
+
+fn read_log_frame(logger: &ReplicationLogger, frame_no: FrameNo) -> Result<Frame, RestoreError> {
+    logger
+        .get_frame(frame_no)
+        .with_context(|| format!("could not read frame {frame_no} of the log"))
+        .map_err(RestoreError::from)
+}
+
+/// Returns the snapshot containing `frame_no`, waiting for it if the log containing the frame is
+/// being compacted, or if the snapshots are being merged.
+fn find_snapshot(
+    logger: &ReplicationLogger,
+    frame_no: FrameNo,
+) -> Result<SnapshotFile, RestoreError> {
+    let deadline = Instant::now() + SNAPSHOT_WAIT;
+    loop {
+        let error = match logger.get_snapshot_file(frame_no) {
+            Ok(Some(snapshot)) => return Ok(snapshot),
+            Ok(None) => anyhow::anyhow!("no snapshot contains frame {frame_no}"),
+            Err(e) if e.is::<HistoryTruncated>() => {
+                return Err(RestoreError::Unreachable(e.to_string()))
+            }
+            Err(e) => e,
+        };
+        if Instant::now() >= deadline {
+            return Err(error.into());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The database file being restored, whose pages are written from their most recent frame.
+struct RestoredFile {
+    file: File,
+    /// Size of the database after the transaction it is restored to, in pages.
+    page_count: u32,
+    written: HashSet<u32>,
+}
+
+impl RestoredFile {
+    /// Writes the page of `frame`, unless it was already written from a more recent frame.
+    fn apply(&mut self, frame: &Frame) -> anyhow::Result<()> {
+        let page_no = frame.header().page_no;
+        if page_no > self.page_count || !self.written.insert(page_no) {
+            return Ok(());
+        }
+        let offset = (page_no as u64 - 1) * WAL_PAGE_SIZE as u64;
+        self.file.write_all_at(frame.page(), offset)?;
+
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.written.len() == self.page_count as usize,
+            "only {} of the {} pages of the database were found",
+            self.written.len(),
+            self.page_count
+        );
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::database::libsql::open_db;
+    use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
+
+    use super::*;
+
+    fn write(logger: &Arc<ReplicationLogger>, path: &Path, sql: &str) {
+        let mut ctx = ReplicationLoggerHookCtx::new(logger.clone(), None);
+        let conn = open_db(path, &REPLICATION_METHODS, &mut ctx, None).unwrap();
+        conn.execute_batch(sql).unwrap();
+    }
+
+    fn count_rows(export_path: &Path) -> i64 {
+        let conn = rusqlite::Connection::open(export_path.join("data")).unwrap();
+        conn.query_row("SELECT count(*) FROM t", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn restore_between_two_inserts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 200, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write(
+            &logger,
+            tmp.path(),
+            "CREATE TABLE t (x); INSERT INTO t VALUES (1)",
+        );
+        let first = logger.next_frame_no() - 1;
+        std::thread::sleep(Duration::from_millis(10));
+        let between = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(10));
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (2)");
+        let second = logger.next_frame_no() - 1;
+
+        let export = export_path(tmp.path(), "by-frame").unwrap();
+        let point = restore_to(&logger, RestoreTarget::FrameNo(first), &export).unwrap();
+        assert_eq!(point.frame_no, first);
+        assert_eq!(count_rows(&export), 1);
+
+        let export = export_path(tmp.path(), "by-time").unwrap();
+        let point = restore_to(&logger, RestoreTarget::Timestamp(between), &export).unwrap();
+        assert_eq!(point.frame_no, first);
+        assert_eq!(count_rows(&export), 1);
+
+        let export = export_path(tmp.path(), "last").unwrap();
+        let point = restore_to(&logger, RestoreTarget::FrameNo(second), &export).unwrap();
+        assert_eq!(point.frame_no, second);
+        assert_eq!(count_rows(&export), 2);
+
+        // an export is never overwritten
+        assert!(matches!(
+            restore_to(&logger, RestoreTarget::FrameNo(first), &export),
+            Err(RestoreError::AlreadyExists(_))
+        ));
+        assert_eq!(count_rows(&export), 2);
+        assert!(matches!(
+            restore_to(
+                &logger,
+                RestoreTarget::FrameNo(second + 1),
+                &tmp.path().join("ahead")
+            ),
+            Err(RestoreError::Unreachable(_))
+        ));
+        assert!(!tmp.path().join("ahead").exists());
+        assert!(matches!(
+            export_path(tmp.path(), "../data"),
+            Err(RestoreError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn restore_from_snapshots() {
+        let tmp = tempfile::tempdir().unwrap();
+        // the log is compacted after every transaction
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write(
+            &logger,
+            tmp.path(),
+            "BEGIN; CREATE TABLE t (x); INSERT INTO t VALUES (1); COMMIT",
+        );
+        let first = logger.next_frame_no() - 1;
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (2)");
+        let second = logger.next_frame_no() - 1;
+
+        let export = tmp.path().join("first");
+        restore_to(&logger, RestoreTarget::FrameNo(first), &export).unwrap();
+        assert_eq!(count_rows(&export), 1);
+        let export = tmp.path().join("second");
+        restore_to(&logger, RestoreTarget::FrameNo(second), &export).unwrap();
+        assert_eq!(count_rows(&export), 2);
+
+        // the frames of the first transaction were deduplicated in its snapshot
+        let res = restore_to(&logger, RestoreTarget::FrameNo(first - 1), &export);
+        assert!(matches!(res, Err(RestoreError::AlreadyExists(_))));
+        let res = restore_to(
+            &logger,
+            RestoreTarget::FrameNo(first - 1),
+            &tmp.path().join("x"),
+        );
+        assert!(matches!(res, Err(RestoreError::Unreachable(_))), "{res:?}");
+    }
+
+    #[test]
+    fn corrupted_log_is_not_restored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 200, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write(
+            &logger,
+            tmp.path(),
+            "CREATE TABLE t (x); INSERT INTO t VALUES (1)",
+        );
+        let last = logger.next_frame_no() - 1;
+
+        // flip a byte of the page of the first frame
+        let log = std::fs::OpenOptions::new()
+            .write(true)
+            .open(tmp.path().join("wallog"))
+            .unwrap();
+        let offset = std::mem::size_of::<crate::replication::primary::logger::LogFileHeader>()
+            + std::mem::size_of::<crate::replication::frame::FrameHeader>()
+            + 100;
+        log.write_all_at(&[0xff], offset as u64).unwrap();
+
+        let export = tmp.path().join("export");
+        let res = restore_to(&logger, RestoreTarget::FrameNo(last), &export);
+        assert!(
+            matches!(res, Err(RestoreError::ChecksumMismatch(0))),
+            "{res:?}"
+        );
+        assert!(!export.exists());
+    }
+}
@@ -2,24 +2,30 @@ use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufWriter;
 use std::io::Write;
+use std::io::{Seek, SeekFrom};
 use std::mem::size_of;
 use std::os::unix::prelude::FileExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use bytemuck::{bytes_of, pod_read_unaligned, Pod, Zeroable};
 use bytes::{Bytes, BytesMut};
-use crossbeam::channel::bounded;
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use regex::Regex;
+use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 use uuid::Uuid;
 
+use crate::metrics;
+
 use super::frame::Frame;
-use super::primary::logger::LogFile;
+use super::primary::logger::{LogFile, LogReadError, Version};
 use super::FrameNo;
 
 /// This is the ratio of the space required to store snapshot vs size of the actual database.
@@ -28,9 +34,20 @@ const SNAPHOT_SPACE_AMPLIFICATION_FACTOR: u64 = 2;
 /// The maximum amount of snapshot allowed before a compaction is required
 const MAX_SNAPSHOT_NUMBER: usize = 32;
 
+/// magic number of snapshot files: b"SQLDSNAP" as u64
+const SNAPSHOT_MAGIC: u64 = u64::from_le_bytes(*b"SQLDSNAP");
+/// Version of the snapshot format written by this version of sqld.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
 #[derive(Debug, Copy, Clone, Zeroable, Pod, PartialEq, Eq)]
 #[repr(C)]
 pub struct SnapshotFileHeader {
+    /// magic number: b"SQLDSNAP" as u64
+    pub magic: u64,
+    /// Snapshot format version number, currently: 2 (see [`SNAPSHOT_FORMAT_VERSION`])
+    pub version: u32,
+    /// safe of the database after applying the snapshot
+    pub size_after: u32,
     /// id of the database
     pub db_id: u128,
     /// first frame in the snapshot
@@ -39,9 +56,101 @@ pub struct SnapshotFileHeader {
     pub end_frame_no: u64,
     /// number of frames in the snapshot
     pub frame_count: u64,
-    /// safe of the database after applying the snapshot
-    pub size_after: u32,
-    pub _pad: u32,
+    /// sqld version when creating this snapshot
+    pub sqld_version: [u16; 4],
+}
+
+/// Header of the snapshots written before the format was versioned, implicitly version 1.
+#[derive(Debug, Copy, Clone, Zeroable, Pod, PartialEq, Eq)]
+#[repr(C)]
+struct SnapshotFileHeaderV1 {
+    db_id: u128,
+    start_frame_no: u64,
+    end_frame_no: u64,
+    frame_count: u64,
+    size_after: u32,
+    _pad: u32,
+}
+
+impl From<SnapshotFileHeaderV1> for SnapshotFileHeader {
+    fn from(v1: SnapshotFileHeaderV1) -> Self {
+        Self {
+            magic: SNAPSHOT_MAGIC,
+            version: SNAPSHOT_FORMAT_VERSION,
+            size_after: v1.size_after,
+            db_id: v1.db_id,
+            start_frame_no: v1.start_frame_no,
+            end_frame_no: v1.end_frame_no,
+            frame_count: v1.frame_count,
+            sqld_version: Version::current().0,
+        }
+    }
+}
+
+/// The frames requested from the snapshots predate the oldest snapshot: the replica that needs
+/// them can't catch up, and must start over from scratch.
+#[derive(Debug, thiserror::Error)]
+#[error("history truncated: frame {frame_no} predates the oldest snapshot, which starts at frame {start_frame_no}")]
+pub struct HistoryTruncated {
+    pub frame_no: FrameNo,
+    pub start_frame_no: FrameNo,
+}
+
+/// Which snapshots are kept as they are. The others are merged into the snapshot that follows
+/// them, and their files are removed.
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotRetention {
+    /// The oldest snapshots beyond this number are merged.
+    pub max_snapshots: Option<usize>,
+    /// The snapshots older than this are merged.
+    pub max_age: Option<Duration>,
+    /// Number of most recent snapshots that are never merged, at least one.
+    pub min_keep: usize,
+}
+
+impl SnapshotRetention {
+    pub fn is_enabled(&self) -> bool {
+        self.max_snapshots.is_some() || self.max_age.is_some()
+    }
+}
+
+/// Header of a snapshot file of any version
+enum AnySnapshotHeader {
+    V1(SnapshotFileHeaderV1),
+    Current(SnapshotFileHeader),
+    TooRecent(SnapshotFileHeader),
+}
+
+impl AnySnapshotHeader {
+    fn read(file: &File, name: &str) -> anyhow::Result<Self> {
+        let mut buf = [0; size_of::<SnapshotFileHeader>()];
+        file.read_exact_at(&mut buf[..size_of::<u64>()], 0)?;
+        if pod_read_unaligned::<u64>(&buf[..size_of::<u64>()]) != SNAPSHOT_MAGIC {
+            // unversioned snapshots start with the database id, so we make sure that the header
+            // is consistent with the snapshot name
+            let mut buf = [0; size_of::<SnapshotFileHeaderV1>()];
+            file.read_exact_at(&mut buf, 0)?;
+            let header: SnapshotFileHeaderV1 = pod_read_unaligned(&buf);
+            match parse_snapshot_name(name) {
+                Some((db_id, start_frame_no, end_frame_no))
+                    if db_id.as_u128() == header.db_id
+                        && start_frame_no == header.start_frame_no
+                        && end_frame_no == header.end_frame_no =>
+                {
+                    return Ok(Self::V1(header))
+                }
+                _ => anyhow::bail!("invalid snapshot header in `{name}`"),
+            }
+        }
+
+        file.read_exact_at(&mut buf, 0)?;
+        let header: SnapshotFileHeader = pod_read_unaligned(&buf);
+        if header.version > SNAPSHOT_FORMAT_VERSION {
+            Ok(Self::TooRecent(header))
+        } else {
+            Ok(Self::Current(header))
+        }
+    }
 }
 
 pub struct SnapshotFile {
@@ -49,6 +158,15 @@ pub struct SnapshotFile {
     header: SnapshotFileHeader,
 }
 
+fn snapshot_name(header: &SnapshotFileHeader) -> String {
+    format!(
+        "{}-{}-{}.snap",
+        Uuid::from_u128(header.db_id),
+        header.start_frame_no,
+        header.end_frame_no,
+    )
+}
+
 /// returns (db_id, start_frame_no, end_frame_no) for the given snapshot name
 fn parse_snapshot_name(name: &str) -> Option<(Uuid, u64, u64)> {
     static SNAPSHOT_FILE_MATCHER: Lazy<Regex> = Lazy::new(|| {
@@ -96,6 +214,7 @@ pub fn find_snapshot_file(
     frame_no: FrameNo,
 ) -> anyhow::Result<Option<SnapshotFile>> {
     let snapshot_dir_path = snapshot_dir_path(db_path);
+    let mut oldest_frame_no = FrameNo::MAX;
     for name in snapshot_list(db_path)? {
         let Some((_, start_frame_no, end_frame_no)) = parse_snapshot_name(&name) else { continue; };
         // we're looking for the frame right after the last applied frame on the replica
@@ -105,24 +224,156 @@ pub fn find_snapshot_file(
             let snapshot_file = SnapshotFile::open(&snapshot_path)?;
             return Ok(Some(snapshot_file));
         }
+        oldest_frame_no = oldest_frame_no.min(start_frame_no);
+    }
+
+    if frame_no < oldest_frame_no && oldest_frame_no != FrameNo::MAX {
+        return Err(HistoryTruncated {
+            frame_no,
+            start_frame_no: oldest_frame_no,
+        }
+        .into());
     }
 
     Ok(None)
 }
 
+/// Migrates the snapshots of the database at `db_path` to the current format. Each snapshot is
+/// rewritten side-by-side, and atomically renamed over the original.
+pub fn migrate_snapshots(db_path: &Path) -> anyhow::Result<()> {
+    let snapshot_dir_path = snapshot_dir_path(db_path);
+    if !snapshot_dir_path.exists() {
+        return Ok(());
+    }
+
+    for name in snapshot_list(db_path)? {
+        if parse_snapshot_name(&name).is_none() {
+            continue;
+        }
+        let path = snapshot_dir_path.join(&name);
+        let file = File::open(&path)?;
+        match AnySnapshotHeader::read(&file, &name)? {
+            AnySnapshotHeader::Current(_) => (),
+            AnySnapshotHeader::TooRecent(header) => {
+                anyhow::bail!("{}", too_recent_error(&name, &header))
+            }
+            AnySnapshotHeader::V1(header) => {
+                tracing::info!(
+                    "migrating snapshot `{name}` to format version {SNAPSHOT_FORMAT_VERSION}"
+                );
+                let mut target = BufWriter::new(NamedTempFile::new_in(&snapshot_dir_path)?);
+                target.write_all(bytes_of(&SnapshotFileHeader::from(header)))?;
+                let mut frames = &file;
+                frames.seek(SeekFrom::Start(size_of::<SnapshotFileHeaderV1>() as u64))?;
+                std::io::copy(&mut frames, &mut target)?;
+                let target = target.into_inner()?;
+                target.as_file().sync_all()?;
+                target.persist(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn too_recent_error(name: &str, header: &SnapshotFileHeader) -> String {
+    format!(
+        "snapshot `{name}` has format version {}, and was written by sqld {}, but this version of sqld ({}) only supports up to version {SNAPSHOT_FORMAT_VERSION}: sqld {} or later is required to open it",
+        header.version,
+        Version(header.sqld_version),
+        Version::current(),
+        Version(header.sqld_version),
+    )
+}
+
+/// Prints the header of every snapshot of the database at `db_path`.
+pub fn check_snapshots(db_path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    if !snapshot_dir_path(db_path).exists() {
+        return Ok(());
+    }
+
+    for name in snapshot_list(db_path)? {
+        let file = File::open(snapshot_dir_path(db_path).join(&name))?;
+        writeln!(out, "snapshot: {name}")?;
+        let header = match AnySnapshotHeader::read(&file, &name) {
+            Ok(AnySnapshotHeader::V1(header)) => {
+                writeln!(out, "  format version: 1 (outdated, will be migrated)")?;
+                SnapshotFileHeader::from(header)
+            }
+            Ok(AnySnapshotHeader::Current(header)) => {
+                writeln!(out, "  format version: {} (current)", header.version)?;
+                writeln!(out, "  written by: sqld {}", Version(header.sqld_version))?;
+                header
+            }
+            Ok(AnySnapshotHeader::TooRecent(header)) => {
+                writeln!(out, "  format version: {} (too recent)", header.version)?;
+                writeln!(out, "  written by: sqld {}", Version(header.sqld_version))?;
+                header
+            }
+            Err(e) => {
+                writeln!(out, "  error: {e}")?;
+                continue;
+            }
+        };
+        writeln!(out, "  database id: {}", Uuid::from_u128(header.db_id))?;
+        writeln!(out, "  start frame_no: {}", header.start_frame_no)?;
+        writeln!(out, "  end frame_no: {}", header.end_frame_no)?;
+        writeln!(out, "  frame count: {}", header.frame_count)?;
+        writeln!(out, "  size after: {}", header.size_after)?;
+    }
+
+    Ok(())
+}
+
 impl SnapshotFile {
     pub fn open(path: &Path) -> anyhow::Result<Self> {
         let file = File::open(path)?;
-        let mut header_buf = [0; size_of::<SnapshotFileHeader>()];
-        file.read_exact_at(&mut header_buf, 0)?;
-        let header: SnapshotFileHeader = pod_read_unaligned(&header_buf);
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let header = match AnySnapshotHeader::read(&file, name)? {
+            AnySnapshotHeader::Current(header) => header,
+            AnySnapshotHeader::TooRecent(header) => {
+                anyhow::bail!("{}", too_recent_error(name, &header))
+            }
+            AnySnapshotHeader::V1(_) => {
+                anyhow::bail!("snapshot `{name}` has not been migrated to the current format")
+            }
+        };
 
         Ok(Self { file, header })
     }
 
+    pub fn header(&self) -> &SnapshotFileHeader {
+        &self.header
+    }
+
+    /// The name of the snapshot file, which identifies the snapshot.
+    pub fn name(&self) -> String {
+        snapshot_name(&self.header)
+    }
+
+    /// Returns the number of frames returned by `frames_iter_from(frame_no)`, and their SHA-256.
+    pub fn digest_from(&self, frame_no: FrameNo) -> anyhow::Result<(u64, [u8; 32])> {
+        let mut hasher = Sha256::new();
+        let mut count = 0;
+        for frame in self.frames_iter_from(frame_no) {
+            hasher.update(frame?);
+            count += 1;
+        }
+
+        Ok((count, hasher.finalize().into()))
+    }
+
     /// Iterator on the frames contained in the snapshot file, in reverse frame_no order.
     pub fn frames_iter(&self) -> impl Iterator<Item = anyhow::Result<Bytes>> + '_ {
-        let mut current_offset = 0;
+        self.frames_iter_skip(0)
+    }
+
+    /// Like `frames_iter`, but skips the first `skip` frames without reading them.
+    fn frames_iter_skip(&self, skip: u64) -> impl Iterator<Item = anyhow::Result<Bytes>> + '_ {
+        let mut current_offset = skip;
         std::iter::from_fn(move || {
             if current_offset >= self.header.frame_count {
                 return None;
@@ -143,7 +394,16 @@ impl SnapshotFile {
         &self,
         frame_no: u64,
     ) -> impl Iterator<Item = anyhow::Result<Bytes>> + '_ {
-        let mut iter = self.frames_iter();
+        self.frames_iter_from_skip(frame_no, 0)
+    }
+
+    /// Like `frames_iter_from`, but skips the first `skip` frames without reading them.
+    pub fn frames_iter_from_skip(
+        &self,
+        frame_no: u64,
+        skip: u64,
+    ) -> impl Iterator<Item = anyhow::Result<Bytes>> + '_ {
+        let mut iter = self.frames_iter_skip(skip);
         std::iter::from_fn(move || match iter.next() {
             Some(Ok(bytes)) => match Frame::try_from_bytes(bytes.clone()) {
                 Ok(frame) => {
@@ -162,7 +422,11 @@ impl SnapshotFile {
 
 #[derive(Clone)]
 pub struct LogCompactor {
-    sender: crossbeam::channel::Sender<(LogFile, PathBuf, u32)>,
+    sender: Sender<(Arc<LogFile>, PathBuf, u32)>,
+    merger: Sender<MergerMessage>,
+    /// The logs sent to the compaction thread, whose frames are read from until their snapshot is
+    /// created.
+    compacting: Arc<Mutex<Vec<Arc<LogFile>>>>,
 }
 
 pub type SnapshotCallback = Box<dyn Fn(&Path) -> anyhow::Result<()> + Send>;
@@ -173,13 +437,19 @@ impl LogCompactor {
         // keep up with snapshop creation: if there isn't any ongoind comptaction task processing,
         // the compact does not block, and the log is compacted in the background. Otherwise, the
         // block until there is a free slot to perform compaction.
-        let (sender, receiver) = bounded::<(LogFile, PathBuf, u32)>(0);
+        let (sender, receiver) = bounded::<(Arc<LogFile>, PathBuf, u32)>(0);
+        let compacting: Arc<Mutex<Vec<Arc<LogFile>>>> = Default::default();
         let mut merger = SnapshotMerger::new(db_path, db_id)?;
+        let merger_sender = merger.sender.clone();
         let db_path = db_path.to_path_buf();
         let snapshot_dir_path = snapshot_dir_path(&db_path);
+        let compacting_logs = compacting.clone();
         let _handle = std::thread::spawn(move || {
             while let Ok((file, log_path, size_after)) = receiver.recv() {
-                match perform_compaction(&db_path, file, db_id) {
+                let start = Instant::now();
+                let res = perform_compaction(&db_path, &file, db_id);
+                metrics::snapshot_duration().observe(start.elapsed().as_secs_f64());
+                match res {
                     Ok((snapshot_name, snapshot_frame_count)) => {
                         tracing::info!("snapshot `{snapshot_name}` successfully created");
 
@@ -200,6 +470,12 @@ impl LogCompactor {
                             break;
                         }
 
+                        // the replicas that still need the frames of the log now find them in the
+                        // snapshot
+                        compacting_logs
+                            .lock()
+                            .retain(|compacting| !Arc::ptr_eq(compacting, &file));
+
                         if let Err(e) = std::fs::remove_file(&log_path) {
                             tracing::error!(
                                 "failed to remove old log file `{}`: {e}",
@@ -216,29 +492,80 @@ impl LogCompactor {
             }
         });
 
-        Ok(Self { sender })
+        Ok(Self {
+            sender,
+            merger: merger_sender,
+            compacting,
+        })
     }
 
     /// Sends a compaction task to the background compaction thread. Blocks if a compaction task is
-    /// already ongoing.
+    /// already ongoing. The frames of `file` can be read with [`Self::compacting_frame`] until
+    /// its snapshot is created.
     pub fn compact(&self, file: LogFile, path: PathBuf, size_after: u32) -> anyhow::Result<()> {
+        let file = Arc::new(file);
+        self.compacting.lock().push(file.clone());
         self.sender
             .send((file, path, size_after))
             .context("failed to compact log: log compactor thread exited")?;
 
         Ok(())
     }
+
+    /// Reads frame `frame_no` from the logs being compacted, if one of them contains it.
+    pub fn compacting_frame(&self, frame_no: FrameNo) -> Option<Result<Frame, LogReadError>> {
+        let log = self
+            .compacting
+            .lock()
+            .iter()
+            .find(|log| {
+                let header = log.header();
+                (header.start_frame_no..header.last_frame_no()).contains(&frame_no)
+            })
+            .cloned()?;
+        Some(log.frame(frame_no))
+    }
+
+    /// Merges the snapshots that `retention` doesn't keep, but those that a replica needing the
+    /// frames from `pinned` on may still be transferring, and waits for the merge. Returns the
+    /// number of snapshot files removed.
+    pub fn retain_snapshots(
+        &self,
+        retention: SnapshotRetention,
+        pinned: Option<FrameNo>,
+    ) -> anyhow::Result<usize> {
+        let (done, result) = bounded(1);
+        self.merger
+            .send(MergerMessage::Retain {
+                retention,
+                pinned,
+                done,
+            })
+            .map_err(|_| anyhow::anyhow!("snapshot merger thread exited"))?;
+        result.recv().context("snapshot merger thread exited")?
+    }
+}
+
+enum MergerMessage {
+    /// A snapshot was created: (snapshot_name, snapshot_frame_count, db_page_count)
+    Register(String, u64, u32),
+    /// Applies a retention policy, see [`LogCompactor::retain_snapshots`].
+    Retain {
+        retention: SnapshotRetention,
+        pinned: Option<FrameNo>,
+        done: Sender<anyhow::Result<usize>>,
+    },
 }
 
 struct SnapshotMerger {
-    /// Sending part of a channel of (snapshot_name, snapshot_frame_count, db_page_count) to the merger thread
-    sender: mpsc::Sender<(String, u64, u32)>,
+    /// Sending part of a channel to the merger thread
+    sender: Sender<MergerMessage>,
     handle: Option<JoinHandle<anyhow::Result<()>>>,
 }
 
 impl SnapshotMerger {
     fn new(db_path: &Path, db_id: u128) -> anyhow::Result<Self> {
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = unbounded();
 
         let db_path = db_path.to_path_buf();
         let handle =
@@ -257,23 +584,93 @@ impl SnapshotMerger {
     }
 
     fn run_snapshot_merger_loop(
-        receiver: mpsc::Receiver<(String, u64, u32)>,
+        receiver: Receiver<MergerMessage>,
         db_path: &Path,
         db_id: u128,
     ) -> anyhow::Result<()> {
         let mut snapshots = Self::init_snapshot_info_list(db_path)?;
-        while let Ok((name, size, db_page_count)) = receiver.recv() {
-            snapshots.push((name, size));
-            if Self::should_compact(&snapshots, db_page_count) {
-                let compacted_snapshot_info = Self::merge_snapshots(&snapshots, db_path, db_id)?;
-                snapshots.clear();
-                snapshots.push(compacted_snapshot_info);
+        while let Ok(message) = receiver.recv() {
+            match message {
+                MergerMessage::Register(name, size, db_page_count) => {
+                    snapshots.push((name, size));
+                    if Self::should_compact(&snapshots, db_page_count) {
+                        let compacted_snapshot_info =
+                            Self::merge_snapshots(&snapshots, db_path, db_id)?;
+                        snapshots.clear();
+                        snapshots.push(compacted_snapshot_info);
+                    }
+                }
+                MergerMessage::Retain {
+                    retention,
+                    pinned,
+                    done,
+                } => {
+                    match Self::retain_snapshots(&mut snapshots, db_path, db_id, retention, pinned)
+                    {
+                        Ok(removed) => {
+                            let _ = done.send(Ok(removed));
+                        }
+                        Err(e) => {
+                            let _ = done.send(Err(anyhow::anyhow!("{e}")));
+                            return Err(e);
+                        }
+                    }
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Merges the oldest snapshots expired by `retention` into the snapshot that follows them, so
+    /// that the replicas starting from scratch can still catch up. The snapshots a replica needing
+    /// the frames from `pinned` on may be transferring are kept, since their transfer would have
+    /// to start over. Returns the number of snapshot files removed.
+    fn retain_snapshots(
+        snapshots: &mut Vec<(String, u64)>,
+        db_path: &Path,
+        db_id: u128,
+        retention: SnapshotRetention,
+        pinned: Option<FrameNo>,
+    ) -> anyhow::Result<usize> {
+        let snapshot_dir_path = snapshot_dir_path(db_path);
+        let keep = retention.min_keep.max(1);
+        let mut expired = 0;
+        while snapshots.len() - expired > keep {
+            // the snapshot the expired one is merged into is rewritten as well
+            let (_, _, next_end_frame_no) =
+                parse_snapshot_name(&snapshots[expired + 1].0).context("invalid snapshot name")?;
+            if pinned.map_or(false, |frame_no| frame_no <= next_end_frame_no) {
+                break;
+            }
+            let too_many = retention
+                .max_snapshots
+                .map_or(false, |max| snapshots.len() - expired > max);
+            let too_old = match retention.max_age {
+                Some(max_age) => {
+                    let path = snapshot_dir_path.join(&snapshots[expired].0);
+                    let modified = std::fs::metadata(path)?.modified()?;
+                    modified.elapsed().unwrap_or_default() > max_age
+                }
+                None => false,
+            };
+            if !too_many && !too_old {
+                break;
+            }
+            expired += 1;
+        }
+
+        if expired == 0 {
+            return Ok(0);
+        }
+
+        let merged = Self::merge_snapshots(&snapshots[..=expired], db_path, db_id)?;
+        tracing::info!("merged {} expired snapshots into `{}`", expired, merged.0);
+        snapshots.splice(..=expired, [merged]);
+
+        Ok(expired)
+    }
+
     /// Reads the snapshot dir and returns the list of snapshots along with their size, sorted in
     /// chronological order.
     ///
@@ -341,7 +738,11 @@ impl SnapshotMerger {
     ) -> anyhow::Result<()> {
         if self
             .sender
-            .send((snapshot_name, snapshot_frame_count, db_page_count))
+            .send(MergerMessage::Register(
+                snapshot_name,
+                snapshot_frame_count,
+                db_page_count,
+            ))
             .is_err()
         {
             if let Some(handle) = self.handle.take() {
@@ -381,12 +782,14 @@ impl SnapshotBuilder {
         Ok(Self {
             seen_pages: HashSet::new(),
             header: SnapshotFileHeader {
+                magic: SNAPSHOT_MAGIC,
+                version: SNAPSHOT_FORMAT_VERSION,
                 db_id,
                 start_frame_no: u64::MAX,
                 end_frame_no: u64::MIN,
                 frame_count: 0,
                 size_after: 0,
-                _pad: 0,
+                sqld_version: Version::current().0,
             },
             snapshot_file: target,
             db_path: db_path.to_path_buf(),
@@ -433,12 +836,7 @@ impl SnapshotBuilder {
         self.snapshot_file.flush()?;
         let file = self.snapshot_file.into_inner()?;
         file.as_file().write_all_at(bytes_of(&self.header), 0)?;
-        let snapshot_name = format!(
-            "{}-{}-{}.snap",
-            Uuid::from_u128(self.header.db_id),
-            self.header.start_frame_no,
-            self.header.end_frame_no,
-        );
+        let snapshot_name = snapshot_name(&self.header);
 
         file.persist(snapshot_dir_path(&self.db_path).join(&snapshot_name))?;
 
@@ -446,9 +844,26 @@ impl SnapshotBuilder {
     }
 }
 
+/// Writes a snapshot received from the primary, covering the changes since `start_frame_no`. The
+/// frames must be in decreasing frame_no order, and returns the name of the snapshot.
+pub fn write_snapshot(
+    db_path: &Path,
+    db_id: u128,
+    start_frame_no: FrameNo,
+    frames: impl Iterator<Item = anyhow::Result<Frame>>,
+) -> anyhow::Result<String> {
+    let mut builder = SnapshotBuilder::new(db_path, db_id)?;
+    builder.append_frames(frames)?;
+    anyhow::ensure!(builder.header.frame_count > 0, "empty snapshot");
+    builder.header.start_frame_no = start_frame_no;
+    let (name, _) = builder.finish()?;
+
+    Ok(name)
+}
+
 fn perform_compaction(
     db_path: &Path,
-    file_to_compact: LogFile,
+    file_to_compact: &LogFile,
     db_id: u128,
 ) -> anyhow::Result<(String, u64)> {
     let mut builder = SnapshotBuilder::new(db_path, db_id)?;
@@ -468,6 +883,7 @@ mod test {
     use crate::replication::frame::FrameHeader;
     use crate::replication::primary::logger::WalPage;
     use crate::replication::snapshot::SnapshotFile;
+    use crate::replication::WAL_PAGE_SIZE;
 
     use super::*;
 
@@ -543,4 +959,94 @@ mod test {
 
         assert_eq!(expected_frame_no, 24);
     }
+
+    const SNAPSHOT_V1_NAME: &str = "5f0b8b3e-7d3c-4f4e-9a57-2f3c1d9e8a10-0-2.snap";
+    const SNAPSHOT_V1: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/assets/test/5f0b8b3e-7d3c-4f4e-9a57-2f3c1d9e8a10-0-2.snap"
+    ));
+
+    #[test]
+    fn migrate_unversioned_snapshot() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(snapshot_dir_path(dir.path())).unwrap();
+        let path = snapshot_dir_path(dir.path()).join(SNAPSHOT_V1_NAME);
+        std::fs::write(&path, SNAPSHOT_V1).unwrap();
+        assert!(SnapshotFile::open(&path).is_err());
+
+        migrate_snapshots(dir.path()).unwrap();
+        // migrating an up to date snapshot is a noop
+        migrate_snapshots(dir.path()).unwrap();
+
+        let snapshot = SnapshotFile::open(&path).unwrap();
+        assert_eq!(snapshot.header.magic, SNAPSHOT_MAGIC);
+        assert_eq!(snapshot.header.version, SNAPSHOT_FORMAT_VERSION);
+        assert_eq!(
+            Uuid::from_u128(snapshot.header.db_id).to_string(),
+            "5f0b8b3e-7d3c-4f4e-9a57-2f3c1d9e8a10"
+        );
+        assert_eq!(snapshot.header.start_frame_no, 0);
+        assert_eq!(snapshot.header.end_frame_no, 2);
+        assert_eq!(snapshot.header.frame_count, 3);
+        assert_eq!(snapshot.header.size_after, 3);
+
+        let frames = snapshot
+            .frames_iter()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            frames.concat(),
+            &SNAPSHOT_V1[size_of::<SnapshotFileHeaderV1>()..]
+        );
+    }
+
+    #[test]
+    fn refuse_snapshot_from_newer_format() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(snapshot_dir_path(dir.path())).unwrap();
+        let path = snapshot_dir_path(dir.path()).join(SNAPSHOT_V1_NAME);
+        std::fs::write(&path, SNAPSHOT_V1).unwrap();
+        migrate_snapshots(dir.path()).unwrap();
+
+        let mut data = read(&path).unwrap();
+        let mut header: SnapshotFileHeader =
+            pod_read_unaligned(&data[..size_of::<SnapshotFileHeader>()]);
+        header.version = SNAPSHOT_FORMAT_VERSION + 1;
+        header.sqld_version = [0, 1, 0, 0];
+        data[..size_of::<SnapshotFileHeader>()].copy_from_slice(bytes_of(&header));
+        std::fs::write(&path, &data).unwrap();
+
+        let err = migrate_snapshots(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("sqld 1.0.0 or later is required"));
+        assert!(SnapshotFile::open(&path).is_err());
+
+        let mut out = Vec::new();
+        check_snapshots(dir.path(), &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("format version: 3 (too recent)"));
+    }
+
+    #[test]
+    fn history_truncated_before_oldest_snapshot() {
+        let dir = tempdir().unwrap();
+        let db_id = Uuid::new_v4().as_u128();
+        let frames = (10..20).rev().map(|frame_no| {
+            let header = FrameHeader {
+                frame_no,
+                checksum: 0,
+                page_no: frame_no as u32,
+                size_after: if frame_no == 19 { 20 } else { 0 },
+            };
+            Ok(Frame::from_parts(&header, &[0; WAL_PAGE_SIZE as usize]))
+        });
+        write_snapshot(dir.path(), db_id, 10, frames).unwrap();
+
+        assert!(find_snapshot_file(dir.path(), 12).unwrap().is_some());
+        // the frames after the snapshots may not be in a snapshot yet
+        assert!(find_snapshot_file(dir.path(), 20).unwrap().is_none());
+        let err = find_snapshot_file(dir.path(), 3).unwrap_err();
+        let err = err.downcast::<HistoryTruncated>().unwrap();
+        assert_eq!((err.frame_no, err.start_frame_no), (3, 10));
+    }
 }
@@ -0,0 +1,324 @@
+//! Warm standby: a replica that can be promoted to primary without losing its replication log.
+//!
+//! A standby logs the frames it receives from the primary in a replication log of its own, with
+//! the numbering of the primary, before applying them, and serves that log to other replicas. When
+//! it is promoted, it restarts as a primary on top of that log, so that the replicas of the former
+//! primary can resume replicating from their current offset.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+use crate::replication::frame::{Frame, FrameBorrowed};
+use crate::replication::primary::logger::Generation;
+use crate::replication::replica::WalIndexMeta;
+use crate::replication::{FrameNo, ReplicationLogger, SnapshotCallback};
+use crate::rpc::replication_log::rpc::replication_log_client::ReplicationLogClient;
+use crate::rpc::replication_log::rpc::{HelloRequest, ProtocolVersion};
+use crate::utils::backoff::{BackoffPolicy, Reconnect};
+
+/// Records the promotion of the standby, in the database directory.
+const PROMOTION_FILE: &str = "promotion.json";
+/// How long a promotion waits for the standby to apply the frames it has logged.
+const PROMOTION_CATCH_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The generation of the primary a standby replicates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamGeneration {
+    pub id: String,
+    pub start_index: FrameNo,
+}
+
+/// The generation started by the promotion of a standby.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Promotion {
+    pub database_id: Uuid,
+    /// The generation of the former primary, if the standby ever reached it.
+    pub previous_generation_id: Option<String>,
+    pub generation_id: Uuid,
+    /// The first frame of the new generation, right after the last frame applied by the standby.
+    pub start_index: FrameNo,
+}
+
+impl Promotion {
+    /// Reads the promotion of the standby at `db_path`, if it was promoted.
+    pub fn read(db_path: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(db_path.join(PROMOTION_FILE)) {
+            Ok(data) => Ok(Some(
+                serde_json::from_slice(&data).context("invalid promotion file")?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, db_path: &Path) -> anyhow::Result<()> {
+        let tmp_path = db_path.join(format!("{PROMOTION_FILE}.tmp"));
+        std::fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(tmp_path, db_path.join(PROMOTION_FILE))?;
+
+        Ok(())
+    }
+
+    /// Makes `logger` continue the generation started by the promotion, if nothing was logged
+    /// since. Otherwise the logger keeps the new generation it was opened with.
+    pub fn resume(&self, logger: &mut ReplicationLogger) -> anyhow::Result<()> {
+        if logger.database_id()? == self.database_id
+            && logger.generation.start_index == self.start_index
+        {
+            tracing::info!(
+                "resuming generation {} of promoted standby, starting at frame {}",
+                self.generation_id,
+                self.start_index
+            );
+            logger.generation = Generation {
+                id: self.generation_id,
+                start_index: self.start_index,
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PromoteError {
+    #[error("the standby was already promoted")]
+    AlreadyPromoted,
+    #[error("the standby has not applied all the frames it logged yet (next frame to apply: {applied}, next frame logged: {logged}), retry later")]
+    NotCaughtUp { logged: FrameNo, applied: FrameNo },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub struct Standby {
+    db_path: PathBuf,
+    logger: Arc<ReplicationLogger>,
+    /// Generation of the primary, as of the last handshake.
+    upstream: Mutex<Option<UpstreamGeneration>>,
+    /// Set once the standby is promoted, it then stops logging frames. Held by the promotion while
+    /// it waits for the standby to apply the frames it has logged.
+    promoted: tokio::sync::Mutex<bool>,
+}
+
+impl Standby {
+    pub fn open(
+        db_path: &Path,
+        database_id: Uuid,
+        max_log_size: u64,
+        max_log_duration: Option<Duration>,
+        callback: SnapshotCallback,
+    ) -> anyhow::Result<Self> {
+        let logger = ReplicationLogger::open_standby(
+            db_path,
+            database_id,
+            max_log_size,
+            max_log_duration,
+            callback,
+        )?;
+
+        Ok(Self {
+            db_path: db_path.to_path_buf(),
+            logger: Arc::new(logger),
+            upstream: Mutex::new(None),
+            promoted: tokio::sync::Mutex::new(false),
+        })
+    }
+
+    pub fn logger(&self) -> &Arc<ReplicationLogger> {
+        &self.logger
+    }
+
+    pub fn upstream_generation(&self) -> Option<UpstreamGeneration> {
+        self.upstream.lock().clone()
+    }
+
+    pub fn set_upstream_generation(&self, generation: UpstreamGeneration) {
+        *self.upstream.lock() = Some(generation);
+    }
+
+    /// Returns the frame_no of the next frame the standby will apply.
+    pub fn next_applied_frame_no(&self) -> FrameNo {
+        *self.logger.new_frame_notifier.borrow()
+    }
+
+    /// Called with the last frame applied before the standby starts replicating. If the log is
+    /// behind, because the replica has just been turned into a standby, it starts after that frame.
+    pub fn start_at(&self, applied_frame_no: Option<FrameNo>) -> anyhow::Result<()> {
+        let next_applied = applied_frame_no.map_or(0, |f| f + 1);
+        if self.logger.next_frame_no() < next_applied {
+            tracing::warn!(
+                "mirrored replication log starts at frame {next_applied}, replicas behind it can't catch up from this node"
+            );
+            self.logger.restart_at(next_applied)?;
+        }
+        self.set_applied_frame_no(applied_frame_no);
+
+        Ok(())
+    }
+
+    /// Announces the frames up to `frame_no` to the replicas of the standby, once they are applied.
+    pub fn set_applied_frame_no(&self, frame_no: Option<FrameNo>) {
+        self.logger.announce_frames(frame_no.map_or(0, |f| f + 1));
+    }
+
+    pub fn mirror_frames(&self, frames: &[Frame]) -> anyhow::Result<()> {
+        let promoted = self.promoted.blocking_lock();
+        anyhow::ensure!(!*promoted, "the standby was promoted");
+        self.logger.mirror_frames(frames)
+    }
+
+    pub fn mirror_snapshot<'a>(
+        &self,
+        frames: impl Iterator<Item = &'a FrameBorrowed>,
+    ) -> anyhow::Result<()> {
+        let promoted = self.promoted.blocking_lock();
+        anyhow::ensure!(!*promoted, "the standby was promoted");
+        self.logger.mirror_snapshot(frames)
+    }
+
+    /// Stops logging frames, and records the promotion of the standby, which must then be
+    /// restarted to run as a primary.
+    pub async fn promote(&self) -> Result<Promotion, PromoteError> {
+        // no frame is logged while the lock is held
+        let mut promoted = self.promoted.lock().await;
+        if *promoted {
+            return Err(PromoteError::AlreadyPromoted);
+        }
+
+        // the frames logged last may still be being applied
+        let logged = self.logger.next_frame_no();
+        let mut applied = self.logger.new_frame_notifier.subscribe();
+        let _ = tokio::time::timeout(PROMOTION_CATCH_UP_TIMEOUT, async {
+            while *applied.borrow_and_update() < logged {
+                if applied.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await;
+        let next_applied = self.next_applied_frame_no();
+        if next_applied != logged {
+            return Err(PromoteError::NotCaughtUp {
+                logged,
+                applied: next_applied,
+            });
+        }
+
+        let promotion = Promotion {
+            database_id: self.logger.database_id()?,
+            previous_generation_id: self.upstream_generation().map(|g| g.id),
+            generation_id: Uuid::new_v4(),
+            start_index: next_applied,
+        };
+        tokio::task::block_in_place(|| promotion.write(&self.db_path))?;
+        *promoted = true;
+        tracing::info!(
+            "standby promoted to primary of database {}, starting generation {} at frame {}",
+            promotion.database_id,
+            promotion.generation_id,
+            promotion.start_index,
+        );
+
+        Ok(promotion)
+    }
+}
+
+/// Returns the id of the database replicated by the standby at `db_path`, asking the primary if
+/// the standby hasn't replicated anything yet.
+pub async fn upstream_database_id(
+    db_path: &Path,
+    channel: Channel,
+    uri: tonic::transport::Uri,
+    backoff: BackoffPolicy,
+) -> anyhow::Result<Uuid> {
+    let (meta, _) = tokio::task::block_in_place(|| WalIndexMeta::read_from_path(db_path))?;
+    if let Some(meta) = meta {
+        return Ok(meta.database_id());
+    }
+
+    let mut client = ReplicationLogClient::with_origin(channel, uri);
+    let mut reconnect = Reconnect::new("primary", backoff);
+    loop {
+        let req = HelloRequest {
+            protocol_version: Some(ProtocolVersion::CURRENT),
+            ..Default::default()
+        };
+        match client.hello(req).await {
+            Ok(resp) => {
+                reconnect.succeeded();
+                let hello = resp.into_inner();
+                ProtocolVersion::check_peer(hello.protocol_version.as_ref())?;
+                return Uuid::from_str(&hello.database_id)
+                    .context("invalid database id from primary");
+            }
+            Err(e) => {
+                let delay = reconnect.failed(&e);
+                if reconnect.exhausted() {
+                    anyhow::bail!(
+                        "couldn't reach the primary after {} tries",
+                        reconnect.failures()
+                    );
+                }
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn promote_standby() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_id = Uuid::new_v4();
+        let standby = Arc::new(
+            Standby::open(dir.path(), database_id, 0, None, Box::new(|_| Ok(()))).unwrap(),
+        );
+        // the replica had applied frames up to 9 before it was turned into a standby
+        standby.start_at(Some(9)).unwrap();
+        standby.set_upstream_generation(UpstreamGeneration {
+            id: "primary".into(),
+            start_index: 0,
+        });
+
+        let promotion = standby.promote().await.unwrap();
+        assert_eq!(promotion.database_id, database_id);
+        assert_eq!(promotion.previous_generation_id.as_deref(), Some("primary"));
+        assert_eq!(promotion.start_index, 10);
+        assert_eq!(
+            Promotion::read(dir.path()).unwrap(),
+            Some(promotion.clone())
+        );
+
+        assert!(matches!(
+            standby.promote().await,
+            Err(PromoteError::AlreadyPromoted)
+        ));
+        let mirrored = tokio::task::spawn_blocking({
+            let standby = standby.clone();
+            move || standby.mirror_frames(&[])
+        })
+        .await
+        .unwrap();
+        assert!(mirrored.is_err());
+        drop(standby);
+
+        // restarted as a primary, the standby continues the generation started by the promotion
+        let mut logger =
+            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        promotion.resume(&mut logger).unwrap();
+        assert_eq!(logger.database_id().unwrap(), database_id);
+        assert_eq!(logger.generation.id, promotion.generation_id);
+        assert_eq!(logger.generation.start_index, 10);
+    }
+}
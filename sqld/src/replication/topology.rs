@@ -0,0 +1,301 @@
+//! Tracks where the primary is, and whether this node is connected to it.
+//!
+//! Replicas learn the client-facing addresses advertised by the primary during the hello
+//! handshake, and persist them, so that clients can be pointed to the primary even while the
+//! replica is disconnected from it.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::replication::FrameNo;
+
+const PRIMARY_INFO_FILE: &str = "primary_info.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Primary,
+    Replica,
+}
+
+/// What is known about the primary.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrimaryInfo {
+    /// Client-facing URLs advertised by the primary
+    pub advertise_addrs: Vec<String>,
+    /// Uuid of the current generation of the primary
+    pub generation_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "event")]
+pub enum TopologyEvent {
+    /// The replica (re)connected to the primary.
+    Connected { primary: PrimaryInfo },
+    /// The replica lost its connection to the primary.
+    Disconnected,
+    /// The primary advertises different addresses than it used to, e.g after a failover.
+    PrimaryChanged { primary: PrimaryInfo },
+}
+
+impl TopologyEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TopologyEvent::Connected { .. } => "connected",
+            TopologyEvent::Disconnected => "disconnected",
+            TopologyEvent::PrimaryChanged { .. } => "primary_changed",
+        }
+    }
+}
+
+struct TopologyInner {
+    primary: PrimaryInfo,
+    connected: bool,
+}
+
+pub struct Topology {
+    role: Role,
+    inner: Mutex<TopologyInner>,
+    events: broadcast::Sender<TopologyEvent>,
+    /// Where the primary info is persisted, only set on replicas.
+    primary_info_path: Option<PathBuf>,
+    /// Last frame received from the primary, or reported by it, or `FrameNo::MAX` if none was
+    /// received yet.
+    primary_frame_no: AtomicU64,
+    /// Lag of the replica behind the primary in milliseconds, as last reported by the primary, or
+    /// `u64::MAX` if unknown.
+    primary_lag_ms: AtomicU64,
+}
+
+impl Topology {
+    pub fn primary(advertise_addrs: Vec<String>, generation_id: String) -> Self {
+        Self::new(
+            Role::Primary,
+            PrimaryInfo {
+                advertise_addrs,
+                generation_id: Some(generation_id),
+            },
+            true,
+            None,
+        )
+    }
+
+    /// Creates the topology of a replica, restoring the last known primary info from `db_path`.
+    pub fn replica(db_path: &Path) -> anyhow::Result<Self> {
+        let path = db_path.join(PRIMARY_INFO_FILE);
+        let primary = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::warn!("invalid primary info file, ignoring: {e}");
+                PrimaryInfo::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => PrimaryInfo::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self::new(Role::Replica, primary, false, Some(path)))
+    }
+
+    fn new(
+        role: Role,
+        primary: PrimaryInfo,
+        connected: bool,
+        primary_info_path: Option<PathBuf>,
+    ) -> Self {
+        let (events, _) = broadcast::channel(16);
+        Self {
+            role,
+            inner: Mutex::new(TopologyInner { primary, connected }),
+            events,
+            primary_info_path,
+            primary_frame_no: AtomicU64::new(FrameNo::MAX),
+            primary_lag_ms: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn primary_info(&self) -> PrimaryInfo {
+        self.inner.lock().primary.clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.lock().connected
+    }
+
+    /// Returns the last frame received from the primary, if any.
+    pub fn primary_frame_no(&self) -> Option<FrameNo> {
+        let frame_no = self.primary_frame_no.load(Ordering::Relaxed);
+        (frame_no != FrameNo::MAX).then_some(frame_no)
+    }
+
+    /// Records the last frame of the primary, as reported in the handshake.
+    pub fn set_primary_frame_no(&self, frame_no: FrameNo) {
+        self.primary_frame_no.store(frame_no, Ordering::Relaxed);
+    }
+
+    /// Records a frame received from, or reported by, the primary, which has at least reached
+    /// this frame.
+    pub fn advance_primary_frame_no(&self, frame_no: FrameNo) {
+        let _ =
+            self.primary_frame_no
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                    (current == FrameNo::MAX || current < frame_no).then_some(frame_no)
+                });
+    }
+
+    /// Time since the primary committed the oldest frame the replica had not received, the last
+    /// time the primary reported it.
+    pub fn primary_lag(&self) -> Option<Duration> {
+        let lag_ms = self.primary_lag_ms.load(Ordering::Relaxed);
+        (lag_ms != u64::MAX).then(|| Duration::from_millis(lag_ms))
+    }
+
+    pub fn set_primary_lag(&self, lag: Option<Duration>) {
+        let lag_ms = lag.map_or(u64::MAX, |lag| lag.as_millis() as u64);
+        self.primary_lag_ms.store(lag_ms, Ordering::Relaxed);
+    }
+
+    /// Returns the current state, as the event that led to it, along with a receiver for the
+    /// subsequent events.
+    pub fn subscribe(&self) -> (TopologyEvent, broadcast::Receiver<TopologyEvent>) {
+        let inner = self.inner.lock();
+        let current = if inner.connected {
+            TopologyEvent::Connected {
+                primary: inner.primary.clone(),
+            }
+        } else {
+            TopologyEvent::Disconnected
+        };
+
+        (current, self.events.subscribe())
+    }
+
+    /// Records a successful handshake with the primary.
+    pub fn set_connected(&self, primary: PrimaryInfo) {
+        let mut inner = self.inner.lock();
+        if inner.primary != primary {
+            if !inner.primary.advertise_addrs.is_empty()
+                && inner.primary.advertise_addrs != primary.advertise_addrs
+            {
+                let _ = self.events.send(TopologyEvent::PrimaryChanged {
+                    primary: primary.clone(),
+                });
+            }
+            if let Err(e) = self.persist(&primary) {
+                tracing::warn!("failed to persist primary info: {e}");
+            }
+            inner.primary = primary;
+        }
+
+        if !inner.connected {
+            inner.connected = true;
+            let _ = self.events.send(TopologyEvent::Connected {
+                primary: inner.primary.clone(),
+            });
+        }
+    }
+
+    /// Records the loss of the connection to the primary.
+    pub fn set_disconnected(&self) {
+        let mut inner = self.inner.lock();
+        if inner.connected {
+            inner.connected = false;
+            let _ = self.events.send(TopologyEvent::Disconnected);
+        }
+    }
+
+    fn persist(&self, primary: &PrimaryInfo) -> anyhow::Result<()> {
+        if let Some(path) = self.primary_info_path.as_ref() {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_vec(primary)?)?;
+            std::fs::rename(tmp, path)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn primary_info(addr: &str, generation_id: &str) -> PrimaryInfo {
+        PrimaryInfo {
+            advertise_addrs: vec![addr.to_string()],
+            generation_id: Some(generation_id.to_string()),
+        }
+    }
+
+    #[test]
+    fn primary_restart() {
+        let tmp = tempfile::tempdir().unwrap();
+        let topology = Topology::replica(tmp.path()).unwrap();
+        let (current, mut events) = topology.subscribe();
+        assert_eq!(current, TopologyEvent::Disconnected);
+
+        topology.set_connected(primary_info("http://primary:8080", "gen1"));
+        // the primary restarts with a new generation, but at the same address
+        topology.set_disconnected();
+        topology.set_disconnected();
+        topology.set_connected(primary_info("http://primary:8080", "gen2"));
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            TopologyEvent::Connected {
+                primary: primary_info("http://primary:8080", "gen1")
+            }
+        );
+        assert_eq!(events.try_recv().unwrap(), TopologyEvent::Disconnected);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            TopologyEvent::Connected {
+                primary: primary_info("http://primary:8080", "gen2")
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn primary_failover() {
+        let tmp = tempfile::tempdir().unwrap();
+        let topology = Topology::replica(tmp.path()).unwrap();
+        topology.set_connected(primary_info("http://primary:8080", "gen1"));
+        topology.set_disconnected();
+
+        let (_, mut events) = topology.subscribe();
+        topology.set_connected(primary_info("http://new-primary:8080", "gen2"));
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            TopologyEvent::PrimaryChanged {
+                primary: primary_info("http://new-primary:8080", "gen2")
+            }
+        );
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            TopologyEvent::Connected { .. }
+        ));
+    }
+
+    #[test]
+    fn primary_info_is_persisted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let topology = Topology::replica(tmp.path()).unwrap();
+        topology.set_connected(primary_info("http://primary:8080", "gen1"));
+        drop(topology);
+
+        let topology = Topology::replica(tmp.path()).unwrap();
+        assert!(!topology.is_connected());
+        assert_eq!(
+            topology.primary_info(),
+            primary_info("http://primary:8080", "gen1")
+        );
+    }
+}
@@ -2,15 +2,20 @@ use anyhow::Context;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tower::util::option_layer;
+use tower::util::{option_layer, MapRequestLayer};
 
 use crate::database::factory::DbFactory;
 use crate::database::Database;
+use crate::namespace::NamespaceStore;
+use crate::replication::compression::ReplicationCompression;
+use crate::replication::primary::change_log::ChangeLog;
+use crate::replication::primary::frame_stream::BatchLimits;
+use crate::replication::standby::Standby;
 use crate::replication::ReplicationLogger;
 use crate::rpc::proxy::rpc::proxy_server::ProxyServer;
-use crate::rpc::proxy::ProxyService;
+use crate::rpc::proxy::{IdentityKey, ProxyService};
 use crate::rpc::replication_log::rpc::replication_log_server::ReplicationLogServer;
-use crate::rpc::replication_log::ReplicationLogService;
+use crate::rpc::replication_log::{Replicas, ReplicationLogService};
 use crate::utils::services::idle_shutdown::IdleShutdownLayer;
 
 pub mod proxy;
@@ -25,13 +30,92 @@ pub async fn run_rpc_server<D: Database>(
     ca_cert_path: Option<PathBuf>,
     factory: Arc<dyn DbFactory<Db = D>>,
     logger: Arc<ReplicationLogger>,
+    change_log: Option<Arc<ChangeLog>>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
+    advertise_addrs: Vec<String>,
+    namespaces: Option<Arc<NamespaceStore>>,
+    replicas: Arc<Replicas>,
+    batch_limits: BatchLimits,
+    compression: ReplicationCompression,
+    identity_key: Option<IdentityKey>,
 ) -> anyhow::Result<()> {
-    let proxy_service = ProxyService::new(factory, logger.new_frame_notifier.subscribe());
-    let logger_service = ReplicationLogService::new(logger, idle_shutdown_layer.clone());
+    let proxy_service = ProxyService::new(factory, logger.new_frame_notifier.subscribe())
+        .with_identity_key(identity_key);
+    let logger_service = ReplicationLogService::new(
+        logger,
+        change_log,
+        idle_shutdown_layer.clone(),
+        advertise_addrs,
+    )
+    .with_namespaces(namespaces)
+    .with_replicas(replicas)
+    .with_batch_limits(batch_limits)
+    .with_compression(compression);
 
     tracing::info!("serving write proxy server at {addr}");
 
+    server_builder(tls, cert_path, key_path, ca_cert_path)?
+        .layer(&option_layer(idle_shutdown_layer))
+        .layer(MapRequestLayer::new(upgrade_legacy_path))
+        .add_service(ProxyServer::new(proxy_service))
+        .add_service(ReplicationLogServer::new(logger_service))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+/// Serves the replication log of a standby. A standby doesn't accept writes.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_standby_rpc_server(
+    addr: SocketAddr,
+    tls: bool,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    ca_cert_path: Option<PathBuf>,
+    standby: Arc<Standby>,
+    idle_shutdown_layer: Option<IdleShutdownLayer>,
+    replicas: Arc<Replicas>,
+    batch_limits: BatchLimits,
+    compression: ReplicationCompression,
+) -> anyhow::Result<()> {
+    let logger_service = ReplicationLogService::standby(standby, idle_shutdown_layer.clone())
+        .with_replicas(replicas)
+        .with_batch_limits(batch_limits)
+        .with_compression(compression);
+
+    tracing::info!("serving standby replication log at {addr}");
+
+    server_builder(tls, cert_path, key_path, ca_cert_path)?
+        .layer(&option_layer(idle_shutdown_layer))
+        .layer(MapRequestLayer::new(upgrade_legacy_path))
+        .add_service(ReplicationLogServer::new(logger_service))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+/// Serves the calls of the replicas that predate the versioning of the protocol packages, made to
+/// the unversioned services, with the current services.
+fn upgrade_legacy_path(mut req: hyper::Request<hyper::Body>) -> hyper::Request<hyper::Body> {
+    if let Some(path) = sqld_proto::upgrade_legacy_path(req.uri().path()) {
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = path.parse().ok();
+        if let Ok(uri) = hyper::Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+    }
+
+    req
+}
+
+fn server_builder(
+    tls: bool,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    ca_cert_path: Option<PathBuf>,
+) -> anyhow::Result<tonic::transport::Server> {
     let mut builder = tonic::transport::Server::builder();
     if tls {
         let cert_pem = std::fs::read_to_string(cert_path.unwrap())?;
@@ -48,12 +132,6 @@ pub async fn run_rpc_server<D: Database>(
             .tls_config(tls_config)
             .context("Failed to read the TSL config of RPC server")?;
     }
-    builder
-        .layer(&option_layer(idle_shutdown_layer))
-        .add_service(ProxyServer::new(proxy_service))
-        .add_service(ReplicationLogServer::new(logger_service))
-        .serve(addr)
-        .await?;
 
-    Ok(())
+    Ok(builder)
 }
@@ -1,18 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use async_lock::{RwLock, RwLockUpgradableReadGuard};
-use tokio::sync::watch;
+use hmac::Mac as _;
+use tokio::sync::{watch, Mutex};
 use uuid::Uuid;
 
-use crate::auth::{Authenticated, Authorized};
+use crate::auth::{self, AuthMethod, Authenticated, Authorized, Identity};
 use crate::database::factory::DbFactory;
 use crate::database::{Database, Program};
+use crate::error::Error;
 use crate::query_result_builder::{
     Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
 use crate::replication::FrameNo;
+use crate::utils::panic::catch_panic_async;
 
 use self::rpc::proxy_server::Proxy;
 use self::rpc::query_result::RowResult;
@@ -25,16 +30,25 @@ pub mod rpc {
 
     use anyhow::Context;
 
+    use crate::database::constraint;
+    use crate::database::settings::ResultLimits;
     use crate::query_analysis::Statement;
     use crate::{database, error::Error as SqldError};
 
+    pub use sqld_proto::proxy::v1::*;
+
     use self::{error::ErrorCode, execute_results::State};
-    tonic::include_proto!("proxy");
 
     impl From<SqldError> for Error {
         fn from(other: SqldError) -> Self {
             Error {
-                message: other.to_string(),
+                // the replica forwards the message to its client
+                message: other.user_error().message,
+                constraint_violations: other
+                    .constraint_violations()
+                    .iter()
+                    .map(Into::into)
+                    .collect(),
                 code: ErrorCode::from(other).into(),
             }
         }
@@ -44,13 +58,64 @@ pub mod rpc {
         fn from(other: SqldError) -> Self {
             match other {
                 SqldError::LibSqlInvalidQueryParams(_) => ErrorCode::SqlError,
-                SqldError::LibSqlTxTimeout => ErrorCode::TxTimeout,
+                SqldError::LibSqlTxTimeout(_) => ErrorCode::TxTimeout,
                 SqldError::LibSqlTxBusy => ErrorCode::TxBusy,
+                SqldError::StatementClassNotAllowed(_) => ErrorCode::SqlError,
+                SqldError::ConstraintViolation { .. } => ErrorCode::ConstraintViolation,
+                SqldError::DeferredConstraintViolation(_) => ErrorCode::DeferredConstraintViolation,
                 _ => ErrorCode::Internal,
             }
         }
     }
 
+    /// Recovers the constraint violations reported by the primary, other errors are kept as is.
+    impl From<Error> for SqldError {
+        fn from(error: Error) -> Self {
+            let mut violations: Vec<constraint::ConstraintViolation> = error
+                .constraint_violations
+                .iter()
+                .cloned()
+                .map(Into::into)
+                .collect();
+            match error.code() {
+                ErrorCode::ConstraintViolation if violations.len() == 1 => {
+                    SqldError::ConstraintViolation {
+                        message: error.message,
+                        violation: violations.pop().unwrap(),
+                    }
+                }
+                ErrorCode::DeferredConstraintViolation => {
+                    SqldError::DeferredConstraintViolation(violations)
+                }
+                _ => SqldError::RpcQueryError(error),
+            }
+        }
+    }
+
+    impl From<&constraint::ConstraintViolation> for ConstraintViolation {
+        fn from(violation: &constraint::ConstraintViolation) -> Self {
+            Self {
+                kind: violation.kind.as_str().into(),
+                table: violation.table.clone(),
+                constraint: violation.constraint.clone(),
+                parent: violation.parent.clone(),
+                rowid: violation.rowid,
+            }
+        }
+    }
+
+    impl From<ConstraintViolation> for constraint::ConstraintViolation {
+        fn from(violation: ConstraintViolation) -> Self {
+            Self {
+                kind: violation.kind.parse().unwrap(),
+                table: violation.table,
+                constraint: violation.constraint,
+                parent: violation.parent,
+                rowid: violation.rowid,
+            }
+        }
+    }
+
     impl From<crate::query_analysis::State> for State {
         fn from(other: crate::query_analysis::State) -> Self {
             match other {
@@ -134,7 +199,12 @@ pub mod rpc {
                 .map(TryInto::try_into)
                 .collect::<anyhow::Result<_>>()?;
 
-            Ok(Self::new(steps))
+            Ok(Self::new(steps)
+                .with_expected_replication_index(pgm.expected_replication_index)
+                .with_result_limits(ResultLimits {
+                    max_rows: pgm.max_response_rows,
+                    max_bytes: pgm.max_response_bytes,
+                }))
         }
     }
 
@@ -182,9 +252,15 @@ pub mod rpc {
         type Error = anyhow::Error;
 
         fn try_from(query: Query) -> Result<Self, Self::Error> {
-            let stmt = Statement::parse(&query.stmt)
+            let mut stmt = Statement::parse(&query.stmt)
                 .next()
                 .context("invalid empty statement")??;
+            if query.allow_inline_literals {
+                stmt.inline_literal = None;
+            }
+            if query.allow_denied_statements {
+                stmt.denied_by = None;
+            }
 
             Ok(Self {
                 stmt,
@@ -207,6 +283,9 @@ pub mod rpc {
 
             Self {
                 steps: steps.into_iter().map(|s| s.into()).collect(),
+                expected_replication_index: pgm.expected_replication_index,
+                max_response_rows: pgm.result_limits.max_rows,
+                max_response_bytes: pgm.result_limits.max_bytes,
             }
         }
     }
@@ -214,6 +293,10 @@ pub mod rpc {
     impl From<crate::query::Query> for Query {
         fn from(query: crate::query::Query) -> Self {
             Self {
+                // the replica and the primary find the same literals in the statement, unless
+                // the replica exempted it
+                allow_inline_literals: query.stmt.inline_literal.is_none(),
+                allow_denied_statements: query.stmt.denied_by.is_none(),
                 stmt: query.stmt.stmt,
                 params: Some(query.params.try_into().unwrap()),
                 skip_rows: !query.want_rows,
@@ -251,10 +334,176 @@ pub mod rpc {
     }
 }
 
+/// Metadata of the `FAILED_PRECONDITION` status returned when a program is rejected because the
+/// database changed since its expected replication index, with the current replication index.
+const REPLICATION_INDEX_METADATA: &str = "x-sqld-replication-index";
+
+fn replication_index_conflict_to_status(expected: FrameNo, current: FrameNo) -> tonic::Status {
+    let mut status = tonic::Status::failed_precondition(
+        Error::ReplicationIndexConflict { expected, current }.to_string(),
+    );
+    status
+        .metadata_mut()
+        .insert(REPLICATION_INDEX_METADATA, current.into());
+    status
+}
+
+/// Recovers the [`Error::ReplicationIndexConflict`] returned by the primary for a program
+/// expecting the replication index `expected`.
+pub fn replication_index_conflict_from_status(
+    status: &tonic::Status,
+    expected: FrameNo,
+) -> Option<Error> {
+    if status.code() != tonic::Code::FailedPrecondition {
+        return None;
+    }
+    let current = status
+        .metadata()
+        .get(REPLICATION_INDEX_METADATA)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+
+    Some(Error::ReplicationIndexConflict { expected, current })
+}
+
+/// Number of replies kept by a session for the retries of its requests.
+const REPLY_WINDOW_SIZE: usize = 16;
+
+static REPLIES_REPLAYED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of proxied requests that were answered with the reply of their previous
+/// execution since the process started, rather than executed again.
+pub fn replies_replayed_total() -> u64 {
+    REPLIES_REPLAYED.load(Ordering::Relaxed)
+}
+
+impl From<AuthMethod> for rpc::AuthMethod {
+    fn from(method: AuthMethod) -> Self {
+        match method {
+            AuthMethod::Anonymous => Self::Anonymous,
+            AuthMethod::Basic => Self::Basic,
+            AuthMethod::Jwt => Self::Jwt,
+        }
+    }
+}
+
+impl From<rpc::AuthMethod> for AuthMethod {
+    fn from(method: rpc::AuthMethod) -> Self {
+        match method {
+            rpc::AuthMethod::Anonymous => Self::Anonymous,
+            rpc::AuthMethod::Basic => Self::Basic,
+            rpc::AuthMethod::Jwt => Self::Jwt,
+        }
+    }
+}
+
+/// The secret shared by a primary and its replicas, with which the replicas sign the identity of
+/// the clients on whose behalf they proxy programs, so that the primary can trust it.
+#[derive(Clone)]
+pub struct IdentityKey(Arc<[u8]>);
+
+impl IdentityKey {
+    pub fn new(secret: &str) -> Self {
+        Self(secret.as_bytes().into())
+    }
+
+    fn mac(
+        &self,
+        req: &rpc::ProgramReq,
+        identity: &rpc::ClientIdentity,
+    ) -> hmac::Hmac<sha2::Sha256> {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.0).unwrap();
+        // the fields are length-prefixed, so that they can't be shifted into one another
+        let mut field = |bytes: &[u8]| {
+            mac.update(&(bytes.len() as u64).to_be_bytes());
+            mac.update(bytes);
+        };
+        field(req.client_id.as_bytes());
+        field(&req.sequence_no.to_be_bytes());
+        field(&req.authorized.map_or(-1, |a| a as i64).to_be_bytes());
+        field(&identity.method.to_be_bytes());
+        field(identity.principal.as_deref().unwrap_or_default().as_bytes());
+        field(identity.source_ip.as_deref().unwrap_or_default().as_bytes());
+        mac
+    }
+
+    /// Signs the identity attached to `req`.
+    pub fn sign(&self, req: &mut rpc::ProgramReq) {
+        if let Some(mut identity) = req.identity.take() {
+            identity.signature = self.mac(req, &identity).finalize().into_bytes().to_vec();
+            req.identity = Some(identity);
+        }
+    }
+
+    /// Whether `req` carries an identity signed with this key.
+    pub fn verify(&self, req: &rpc::ProgramReq) -> bool {
+        match req.identity.as_ref() {
+            Some(identity) => self
+                .mac(req, identity)
+                .verify_slice(&identity.signature)
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// The identity of the client a replica proxies a program for, and the address it connected from.
+pub fn client_identity(identity: &Identity, source_ip: Option<IpAddr>) -> rpc::ClientIdentity {
+    rpc::ClientIdentity {
+        principal: identity.principal.clone(),
+        method: rpc::AuthMethod::from(identity.method).into(),
+        source_ip: source_ip.map(|ip| ip.to_string()),
+        signature: Vec::new(),
+    }
+}
+
 pub struct ProxyService<D> {
-    clients: RwLock<HashMap<Uuid, Arc<D>>>,
+    clients: RwLock<HashMap<Uuid, Arc<Session<D>>>>,
     factory: Arc<dyn DbFactory<Db = D>>,
     new_frame_notifier: watch::Receiver<FrameNo>,
+    /// When set, the programs are only executed if the replica signed the identity of their client
+    /// with this key.
+    identity_key: Option<IdentityKey>,
+    /// Number of replies to drop after executing their request, to simulate lost replies.
+    #[cfg(test)]
+    dropped_replies: std::sync::atomic::AtomicUsize,
+    /// Delay of the replies, to simulate a distant primary.
+    #[cfg(test)]
+    reply_delay: std::time::Duration,
+}
+
+/// The connection of a replica session, and the replies to its last requests.
+struct Session<D> {
+    db: Arc<D>,
+    /// Also serializes the requests of the session, so that a retry waits for the request it
+    /// retries to complete.
+    replies: Mutex<ReplyWindow>,
+}
+
+#[derive(Default)]
+struct ReplyWindow {
+    /// Highest sequence number executed by the session.
+    last_sequence_no: u64,
+    replies: VecDeque<(u64, ExecuteResults)>,
+}
+
+impl ReplyWindow {
+    fn get(&self, sequence_no: u64) -> Option<&ExecuteResults> {
+        self.replies
+            .iter()
+            .find(|(n, _)| *n == sequence_no)
+            .map(|(_, reply)| reply)
+    }
+
+    fn record(&mut self, sequence_no: u64, reply: ExecuteResults) {
+        if self.replies.len() == REPLY_WINDOW_SIZE {
+            self.replies.pop_front();
+        }
+        self.replies.push_back((sequence_no, reply));
+        self.last_sequence_no = self.last_sequence_no.max(sequence_no);
+    }
 }
 
 impl<D: Database> ProxyService<D> {
@@ -266,7 +515,152 @@ impl<D: Database> ProxyService<D> {
             clients: Default::default(),
             factory,
             new_frame_notifier,
+            identity_key: None,
+            #[cfg(test)]
+            dropped_replies: Default::default(),
+            #[cfg(test)]
+            reply_delay: Default::default(),
+        }
+    }
+
+    pub fn with_identity_key(mut self, key: Option<IdentityKey>) -> Self {
+        self.identity_key = key;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_reply_delay(mut self, delay: std::time::Duration) -> Self {
+        self.reply_delay = delay;
+        self
+    }
+
+    async fn execute_program(&self, req: rpc::ProgramReq) -> Result<ExecuteResults, tonic::Status> {
+        if let Some(key) = &self.identity_key {
+            if !key.verify(&req) {
+                return Err(tonic::Status::permission_denied(
+                    "the identity of the client is missing or not signed with the proxy identity key",
+                ));
+            }
+        }
+        let (identity, source_ip) = match &req.identity {
+            Some(identity) => (
+                Identity {
+                    principal: identity.principal.clone(),
+                    method: identity.method().into(),
+                    // the namespaces are only served by the primary
+                    namespace: None,
+                },
+                identity.source_ip.as_deref().and_then(|ip| ip.parse().ok()),
+            ),
+            // replicas that predate the identities send none
+            None => (Identity::anonymous(), None),
+        };
+        auth::with_identity(identity, self.execute_program_as(req, source_ip)).await
+    }
+
+    /// Executes the program on behalf of the client identity of the task.
+    async fn execute_program_as(
+        &self,
+        req: rpc::ProgramReq,
+        source_ip: Option<IpAddr>,
+    ) -> Result<ExecuteResults, tonic::Status> {
+        let pgm = Program::try_from(req.pgm.unwrap())
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
+        let client_id = Uuid::from_str(&req.client_id).unwrap();
+        let auth = match req.authorized {
+            Some(0) => Authenticated::Authorized(Authorized::ReadOnly),
+            Some(1) => Authenticated::Authorized(Authorized::FullAccess),
+            Some(_) => {
+                return Err(tonic::Status::new(
+                    tonic::Code::PermissionDenied,
+                    "invalid authorization level",
+                ))
+            }
+            None => Authenticated::Anonymous,
+        };
+        let lock = self.clients.upgradable_read().await;
+        let session = match lock.get(&client_id) {
+            Some(session) => session.clone(),
+            None => {
+                tracing::debug!("connected: {client_id}");
+                match self.factory.create().await {
+                    Ok(db) => {
+                        let session = Arc::new(Session {
+                            db: Arc::new(db),
+                            replies: Default::default(),
+                        });
+                        let mut lock = RwLockUpgradableReadGuard::upgrade(lock).await;
+                        lock.insert(client_id, session.clone());
+                        session
+                    }
+                    Err(e) => {
+                        return Err(tonic::Status::new(
+                            tonic::Code::Internal,
+                            e.user_error().message,
+                        ))
+                    }
+                }
+            }
+        };
+
+        let mut replies = session.replies.lock().await;
+        let sequence_no = req.sequence_no;
+        if sequence_no != 0 {
+            if let Some(reply) = replies.get(sequence_no) {
+                tracing::debug!("replaying reply to request {sequence_no} of {client_id}");
+                REPLIES_REPLAYED.fetch_add(1, Ordering::Relaxed);
+                return Ok(reply.clone());
+            }
+            if sequence_no <= replies.last_sequence_no {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "request {sequence_no} was already executed, and its reply was evicted"
+                )));
+            }
+        }
+
+        tracing::debug!("executing request for {client_id}");
+        auth::audit(
+            auth::current_identity().as_ref(),
+            source_ip,
+            Some(&req.client_id),
+            pgm.steps().len(),
+        );
+        let builder = ExecuteResultBuilder::default();
+        let (results, state) = session
+            .db
+            .execute_program(pgm, auth, builder)
+            .await
+            .map_err(|e| match e {
+                Error::ReplicationIndexConflict { expected, current } => {
+                    replication_index_conflict_to_status(expected, current)
+                }
+                // TODO: this is no necessarily a permission denied error!
+                e => tonic::Status::new(tonic::Code::PermissionDenied, e.user_error().message),
+            })?;
+        let current_frame_no = *self.new_frame_notifier.borrow();
+
+        let reply = ExecuteResults {
+            current_frame_no,
+            results: results.into_ret(),
+            state: rpc::execute_results::State::from(state).into(),
+        };
+        // failed requests are not recorded: their retries are executed again
+        if sequence_no != 0 {
+            replies.record(sequence_no, reply.clone());
+        }
+
+        #[cfg(test)]
+        tokio::time::sleep(self.reply_delay).await;
+        #[cfg(test)]
+        if self
+            .dropped_replies
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(tonic::Status::unavailable("injected fault: reply dropped"));
         }
+
+        Ok(reply)
     }
 }
 
@@ -280,6 +674,8 @@ struct ExecuteResultBuilder {
     max_size: u64,
     current_size: u64,
     current_step_size: u64,
+    /// Whether the current step was skipped, without columns nor error.
+    is_step_skipped: bool,
 }
 
 impl QueryResultBuilder for ExecuteResultBuilder {
@@ -297,6 +693,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
         assert!(self.current_err.is_none());
         assert!(self.current_rows.is_empty());
         self.current_step_size = 0;
+        self.is_step_skipped = true;
         Ok(())
     }
 
@@ -307,6 +704,8 @@ impl QueryResultBuilder for ExecuteResultBuilder {
     ) -> Result<(), QueryResultBuilderError> {
         self.current_size += self.current_step_size;
         match self.current_err.take() {
+            // the skipped steps have no result, so that the replica reports them as such
+            None if self.is_step_skipped => self.results.push(QueryResult { row_result: None }),
             Some(err) => {
                 self.current_rows.clear();
                 self.current_row.values.clear();
@@ -339,6 +738,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
             return Err(QueryResultBuilderError::ResponseTooLarge(self.max_size));
         }
         self.current_step_size = error_size;
+        self.is_step_skipped = false;
 
         self.current_err = Some(error);
 
@@ -350,6 +750,7 @@ impl QueryResultBuilder for ExecuteResultBuilder {
         cols: impl IntoIterator<Item = impl Into<Column<'a>>>,
     ) -> Result<(), QueryResultBuilderError> {
         assert!(self.current_col_description.is_empty());
+        self.is_step_skipped = false;
         for col in cols {
             let col = col.into();
             let col_len =
@@ -431,52 +832,10 @@ impl<D: Database> Proxy for ProxyService<D> {
         &self,
         req: tonic::Request<rpc::ProgramReq>,
     ) -> Result<tonic::Response<ExecuteResults>, tonic::Status> {
-        let req = req.into_inner();
-        let pgm = Program::try_from(req.pgm.unwrap())
-            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
-        let client_id = Uuid::from_str(&req.client_id).unwrap();
-        let auth = match req.authorized {
-            Some(0) => Authenticated::Authorized(Authorized::ReadOnly),
-            Some(1) => Authenticated::Authorized(Authorized::FullAccess),
-            Some(_) => {
-                return Err(tonic::Status::new(
-                    tonic::Code::PermissionDenied,
-                    "invalid authorization level",
-                ))
-            }
-            None => Authenticated::Anonymous,
-        };
-        let lock = self.clients.upgradable_read().await;
-        let db = match lock.get(&client_id) {
-            Some(db) => db.clone(),
-            None => {
-                tracing::debug!("connected: {client_id}");
-                match self.factory.create().await {
-                    Ok(db) => {
-                        let db = Arc::new(db);
-                        let mut lock = RwLockUpgradableReadGuard::upgrade(lock).await;
-                        lock.insert(client_id, db.clone());
-                        db
-                    }
-                    Err(e) => return Err(tonic::Status::new(tonic::Code::Internal, e.to_string())),
-                }
-            }
-        };
-
-        tracing::debug!("executing request for {client_id}");
-        let builder = ExecuteResultBuilder::default();
-        let (results, state) = db
-            .execute_program(pgm, auth, builder)
-            .await
-            // TODO: this is no necessarily a permission denied error!
-            .map_err(|e| tonic::Status::new(tonic::Code::PermissionDenied, e.to_string()))?;
-        let current_frame_no = *self.new_frame_notifier.borrow();
-
-        Ok(tonic::Response::new(ExecuteResults {
-            current_frame_no,
-            results: results.into_ret(),
-            state: rpc::execute_results::State::from(state).into(),
-        }))
+        match catch_panic_async(self.execute_program(req.into_inner())).await {
+            Ok(res) => res.map(tonic::Response::new),
+            Err(_) => Err(tonic::Status::new(tonic::Code::Internal, "internal error")),
+        }
     }
 
     //TODO: also handle cleanup on peer disconnect
@@ -494,3 +853,242 @@ impl<D: Database> Proxy for ProxyService<D> {
         Ok(tonic::Response::new(Ack {}))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use crate::database::config::DatabaseConfigStore;
+    use crate::database::libsql::LibSqlDb;
+    use crate::database::settings::SessionConfig;
+    use crate::stats::Stats;
+
+    use super::*;
+
+    fn proxy_service(path: &Path) -> ProxyService<LibSqlDb> {
+        let path = path.to_path_buf();
+        let factory: Arc<dyn DbFactory<Db = LibSqlDb>> = Arc::new(move || {
+            LibSqlDb::new(
+                path.clone(),
+                Vec::new(),
+                &TRANSPARENT_METHODS,
+                (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                QueryBuilderConfig::default(),
+                None,
+                None,
+                None,
+                None,
+                SessionConfig::default(),
+                false,
+            )
+        });
+        let (_, new_frame_notifier) = watch::channel(0);
+        ProxyService::new(factory, new_frame_notifier)
+    }
+
+    fn request(client_id: Uuid, sequence_no: u64, stmts: &[&str]) -> rpc::ProgramReq {
+        rpc::ProgramReq {
+            client_id: client_id.to_string(),
+            pgm: Some(Program::seq(stmts).into()),
+            authorized: Some(1),
+            sequence_no,
+            identity: None,
+        }
+    }
+
+    /// Returns the count returned by the last step.
+    fn last_count(reply: &ExecuteResults) -> i64 {
+        match reply.results.last().and_then(|r| r.row_result.as_ref()) {
+            Some(RowResult::Row(rows)) => {
+                match bincode::deserialize::<crate::query::Value>(&rows.rows[0].values[0].data)
+                    .unwrap()
+                {
+                    crate::query::Value::Integer(n) => n,
+                    other => panic!("unexpected count: {other:?}"),
+                }
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    async fn count(service: &ProxyService<LibSqlDb>) -> i64 {
+        let reply = service
+            .execute_program(request(Uuid::new_v4(), 0, &["select count(*) from t"]))
+            .await
+            .unwrap();
+        last_count(&reply)
+    }
+
+    #[tokio::test]
+    async fn retries_replay_lost_replies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let service = proxy_service(tmp.path());
+        let client_id = Uuid::new_v4();
+
+        service
+            .execute_program(request(client_id, 1, &["create table t (x)"]))
+            .await
+            .unwrap();
+
+        let insert = request(
+            client_id,
+            2,
+            &["insert into t values (42)", "select count(*) from t"],
+        );
+        service.dropped_replies.store(1, Ordering::Relaxed);
+        let err = service.execute_program(insert.clone()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+
+        let replayed = replies_replayed_total();
+        let reply = service.execute_program(insert).await.unwrap();
+        assert_eq!(last_count(&reply), 1);
+        assert_eq!(replies_replayed_total(), replayed + 1);
+        assert_eq!(count(&service).await, 1);
+
+        // requests without a sequence number are executed every time
+        let insert = request(client_id, 0, &["insert into t values (42)"]);
+        service.execute_program(insert.clone()).await.unwrap();
+        service.execute_program(insert).await.unwrap();
+        assert_eq!(count(&service).await, 3);
+    }
+
+    #[tokio::test]
+    async fn retries_in_transaction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let service = proxy_service(tmp.path());
+        let client_id = Uuid::new_v4();
+
+        service
+            .execute_program(request(client_id, 1, &["create table t (x)"]))
+            .await
+            .unwrap();
+        let reply = service
+            .execute_program(request(client_id, 2, &["begin"]))
+            .await
+            .unwrap();
+        assert_eq!(reply.state(), rpc::execute_results::State::Txn);
+
+        let insert = request(
+            client_id,
+            3,
+            &["insert into t values (42)", "select count(*) from t"],
+        );
+        service.dropped_replies.store(2, Ordering::Relaxed);
+        for _ in 0..2 {
+            let err = service.execute_program(insert.clone()).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::Unavailable);
+        }
+        let reply = service.execute_program(insert).await.unwrap();
+        assert_eq!(reply.state(), rpc::execute_results::State::Txn);
+        assert_eq!(last_count(&reply), 1);
+
+        let reply = service
+            .execute_program(request(client_id, 4, &["commit"]))
+            .await
+            .unwrap();
+        assert_eq!(reply.state(), rpc::execute_results::State::Init);
+        assert_eq!(count(&service).await, 1);
+    }
+
+    #[tokio::test]
+    async fn reply_window_is_bounded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let service = proxy_service(tmp.path());
+        let client_id = Uuid::new_v4();
+
+        service
+            .execute_program(request(client_id, 1, &["create table t (x)"]))
+            .await
+            .unwrap();
+        let insert = |n| request(client_id, n, &["insert into t values (42)"]);
+        for n in 2..REPLY_WINDOW_SIZE as u64 + 3 {
+            service.execute_program(insert(n)).await.unwrap();
+        }
+
+        // the reply to the oldest requests was evicted, so they can't be executed again
+        let err = service.execute_program(insert(2)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        service
+            .execute_program(insert(REPLY_WINDOW_SIZE as u64 + 2))
+            .await
+            .unwrap();
+        assert_eq!(count(&service).await, REPLY_WINDOW_SIZE as i64 + 1);
+
+        // the window is evicted with the session
+        service
+            .disconnect(tonic::Request::new(DisconnectMessage {
+                client_id: client_id.to_string(),
+            }))
+            .await
+            .unwrap();
+        assert!(service.clients.read().await.get(&client_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn internal_errors_are_not_forwarded() {
+        let factory: Arc<dyn DbFactory<Db = LibSqlDb>> = Arc::new(|| async {
+            Err::<LibSqlDb, _>(Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "failed to open /var/lib/sqld/data.sqld/data",
+            )))
+        });
+        let (_, new_frame_notifier) = watch::channel(0);
+        let service = ProxyService::new(factory, new_frame_notifier);
+        let status = service
+            .execute_program(request(Uuid::new_v4(), 1, &["select 1"]))
+            .await
+            .unwrap_err();
+        assert!(!status.message().contains("/var/lib"), "{status:?}");
+        assert!(status.message().contains("logged by the server with id"));
+
+        let error = rpc::Error::from(Error::RpcQueryExecutionError(tonic::Status::unavailable(
+            "tcp connect error: 10.0.3.7:5001",
+        )));
+        assert!(!error.message.contains("10.0.3.7"), "{error:?}");
+        assert_eq!(error.code(), rpc::error::ErrorCode::Internal);
+    }
+
+    #[tokio::test]
+    async fn identities_must_be_signed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let key = IdentityKey::new("s3cr3t");
+        let service = proxy_service(tmp.path()).with_identity_key(Some(key.clone()));
+        let client_id = Uuid::new_v4();
+        let alice = Identity {
+            principal: Some("alice".into()),
+            method: AuthMethod::Jwt,
+            namespace: None,
+        };
+
+        // no identity
+        let status = service
+            .execute_program(request(client_id, 1, &["select 1"]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // signed with another key
+        let mut req = request(client_id, 1, &["select 1"]);
+        req.identity = Some(client_identity(&alice, None));
+        IdentityKey::new("guess").sign(&mut req);
+        let status = service.execute_program(req).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // signed, then tampered with
+        let mut req = request(client_id, 1, &["select 1"]);
+        req.identity = Some(client_identity(&alice, None));
+        key.sign(&mut req);
+        req.identity.as_mut().unwrap().principal = Some("root".into());
+        let status = service.execute_program(req).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let mut req = request(client_id, 1, &["select 1"]);
+        req.identity = Some(client_identity(&alice, Some([10, 0, 0, 1].into())));
+        key.sign(&mut req);
+        service.execute_program(req).await.unwrap();
+    }
+}
@@ -1,64 +1,734 @@
 pub mod rpc {
-    #![allow(clippy::all)]
-    tonic::include_proto!("wal_log");
+    pub use sqld_proto::wal_log::v1::*;
 }
 
-use std::collections::HashSet;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use futures::stream::BoxStream;
 use futures::StreamExt;
+use serde::Serialize;
+use sqld_proto::REPLICA_SESSION_METADATA;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::Status;
+use uuid::Uuid;
 
-use crate::replication::primary::frame_stream::FrameStream;
-use crate::replication::{LogReadError, ReplicationLogger};
+use crate::metrics;
+use crate::namespace::NamespaceStore;
+use crate::replication::compression::{self, ReplicationCompression};
+use crate::replication::logical::{LogicalOffset, TableFilter};
+use crate::replication::primary::change_log::{ChangeLog, LogicalReadError};
+use crate::replication::primary::frame_stream::{BatchLimits, FrameStream};
+use crate::replication::standby::Standby;
+use crate::replication::{
+    FrameNo, HistoryTruncated, LogReadError, ReplicationLogger, SnapshotFile,
+};
 use crate::utils::services::idle_shutdown::IdleShutdownLayer;
 
 use self::rpc::replication_log_server::ReplicationLog;
-use self::rpc::{Frame, HelloRequest, HelloResponse, LogOffset};
+use self::rpc::{
+    CompressionCodec, Frame, Frames, HelloRequest, HelloResponse, LogOffset, LogStatus,
+    LogicalBatch, ProtocolVersion, ReplicationMode, SnapshotChunk, SnapshotHeader,
+};
 
 pub struct ReplicationLogService {
     logger: Arc<ReplicationLogger>,
-    replicas_with_hello: RwLock<HashSet<SocketAddr>>,
+    change_log: Option<Arc<ChangeLog>>,
+    replicas: Arc<Replicas>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
+    advertise_addrs: Vec<String>,
+    /// Set on a standby, which serves the log it mirrors from its primary.
+    standby: Option<Arc<Standby>>,
+    /// Set on a primary that serves namespaces.
+    namespaces: Option<Arc<NamespaceStore>>,
+    /// Bounds the frames read from the log at once for a replica.
+    batch_limits: BatchLimits,
+    /// The compression of the frames, for the replicas that support it.
+    compression: ReplicationCompression,
+    snapshot_digests: Arc<SnapshotDigests>,
+}
+
+/// Number of snapshot digests remembered by [`SnapshotDigests`].
+const MAX_SNAPSHOT_DIGESTS: usize = 16;
+
+/// The digests of the last snapshots streamed by `ResumableSnapshot`, by snapshot and offset, so
+/// that a resumed transfer doesn't read the whole snapshot again.
+#[derive(Default)]
+struct SnapshotDigests {
+    digests: Mutex<VecDeque<((String, FrameNo), (u64, [u8; 32]))>>,
+}
+
+impl SnapshotDigests {
+    fn get(&self, snapshot: &SnapshotFile, frame_no: FrameNo) -> anyhow::Result<(u64, [u8; 32])> {
+        let key = (snapshot.name(), frame_no);
+        if let Some((_, digest)) = self.digests.lock().unwrap().iter().find(|(k, _)| *k == key) {
+            return Ok(*digest);
+        }
+
+        let digest = snapshot.digest_from(frame_no)?;
+        let mut digests = self.digests.lock().unwrap();
+        if digests.len() >= MAX_SNAPSHOT_DIGESTS {
+            digests.pop_front();
+        }
+        digests.push_back((key, digest));
+
+        Ok(digest)
+    }
+}
+
+/// How long the session of a replica is kept after its last request, by default.
+pub const DEFAULT_REPLICA_SESSION_TTL: Duration = Duration::from_secs(3600);
+/// Maximum number of sessions of replicas, by default.
+pub const DEFAULT_MAX_REPLICA_SESSIONS: usize = 10_000;
+/// Interval between the reports of the throughput of the streams to the replicas.
+const THROUGHPUT_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The replicas that have performed a handshake, by session token. The replicas that don't send
+/// their id get no token, and their session is keyed by their address.
+pub struct Replicas {
+    sessions: RwLock<HashMap<String, ReplicaHello>>,
+    /// Sessions unused for longer than this expire, unless they are streaming.
+    ttl: Duration,
+    /// The handshakes beyond this number of sessions are refused.
+    max_sessions: usize,
+}
+
+/// What a replica asked for in its handshake.
+#[derive(Clone)]
+struct ReplicaHello {
+    replica_id: Option<String>,
+    addr: Option<SocketAddr>,
+    /// The table filter of a replica replicated logically.
+    filter: Option<TableFilter>,
+    namespace: Option<String>,
+    /// The replication log of the database the replica replicates, the default database or a
+    /// namespace.
+    logger: Arc<ReplicationLogger>,
+    /// The codec of the frames streamed to the replica.
+    codec: CompressionCodec,
+    /// The offset of the last frame, or logical batch, streamed to the replica, `u64::MAX` before
+    /// the first one.
+    last_offset: Arc<AtomicU64>,
+    last_seen: Arc<Mutex<Instant>>,
+    /// Cloned by the streams of the session, which keep it alive.
+    streams: Arc<()>,
+}
+
+impl ReplicaHello {
+    fn is_expired(&self, ttl: Duration) -> bool {
+        Arc::strong_count(&self.streams) == 1 && self.last_seen.lock().unwrap().elapsed() > ttl
+    }
+}
+
+/// The state of a replica, as reported by the admin API.
+#[derive(Debug, Serialize)]
+pub struct ReplicaStatus {
+    pub replica_id: Option<String>,
+    pub addr: Option<SocketAddr>,
+    pub mode: &'static str,
+    pub namespace: Option<String>,
+    /// The offset of the last frame, or logical batch, streamed to the replica.
+    pub last_offset: Option<u64>,
+}
+
+/// Records the frames streamed to a replica, and logs the throughput of the stream periodically
+/// and when it ends.
+struct StreamProgress {
+    replica: String,
+    last_offset: Arc<AtomicU64>,
+    frames_streamed: Option<Arc<AtomicU64>>,
+    started: Instant,
+    last_report: Instant,
+    frames: u64,
+    bytes: u64,
+}
+
+impl StreamProgress {
+    fn new(session: &ReplicaHello) -> Self {
+        let replica = match (&session.replica_id, session.addr) {
+            (Some(id), _) => id.clone(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => "<unknown>".to_string(),
+        };
+        let now = Instant::now();
+        Self {
+            replica,
+            last_offset: session.last_offset.clone(),
+            frames_streamed: session.addr.map(|addr| metrics::frames_streamed(addr.ip())),
+            started: now,
+            last_report: now,
+            frames: 0,
+            bytes: 0,
+        }
+    }
+
+    fn record(&mut self, frame: &crate::replication::frame::Frame) {
+        self.last_offset
+            .store(frame.header().frame_no, Ordering::Relaxed);
+        if let Some(ref frames_streamed) = self.frames_streamed {
+            frames_streamed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.frames += 1;
+        self.bytes += frame.bytes().len() as u64;
+        if self.last_report.elapsed() >= THROUGHPUT_REPORT_INTERVAL {
+            self.report();
+            self.last_report = Instant::now();
+        }
+    }
+
+    fn report(&self) {
+        let secs = self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        tracing::info!(
+            "streamed {} frames to replica {} in {secs:.1}s ({:.0} frames/s, {:.1} KiB/s)",
+            self.frames,
+            self.replica,
+            self.frames as f64 / secs,
+            self.bytes as f64 / 1024.0 / secs,
+        );
+    }
+}
+
+impl Drop for StreamProgress {
+    fn drop(&mut self) {
+        if self.frames > 0 {
+            self.report();
+        }
+    }
+}
+
+/// The key of the session of a replica that didn't send its id.
+fn addr_session_key(addr: SocketAddr) -> String {
+    format!("addr:{addr}")
+}
+
+impl Default for Replicas {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLICA_SESSION_TTL, DEFAULT_MAX_REPLICA_SESSIONS)
+    }
+}
+
+impl Replicas {
+    pub fn new(ttl: Duration, max_sessions: usize) -> Self {
+        Self {
+            sessions: Default::default(),
+            ttl,
+            max_sessions,
+        }
+    }
+
+    pub fn status(&self) -> Vec<ReplicaStatus> {
+        let sessions = self.sessions.read().unwrap();
+        let mut replicas: Vec<_> = sessions
+            .values()
+            .filter(|hello| !hello.is_expired(self.ttl))
+            .map(|hello| {
+                let last_offset = hello.last_offset.load(Ordering::Relaxed);
+                ReplicaStatus {
+                    replica_id: hello.replica_id.clone(),
+                    addr: hello.addr,
+                    mode: match hello.filter {
+                        Some(_) => "logical",
+                        None => "physical",
+                    },
+                    namespace: hello.namespace.clone(),
+                    last_offset: (last_offset != u64::MAX).then_some(last_offset),
+                }
+            })
+            .collect();
+        replicas.sort_by(|a, b| (&a.replica_id, a.addr).cmp(&(&b.replica_id, b.addr)));
+        replicas
+    }
+
+    /// The oldest frame the physical replicas of the database of `logger` still need: the frame
+    /// after the last one streamed to them, or the first frame if they weren't streamed any yet.
+    /// Returns `None` if no replica replicates the database.
+    pub fn oldest_needed_frame_no(&self, logger: &Arc<ReplicationLogger>) -> Option<FrameNo> {
+        let sessions = self.sessions.read().unwrap();
+        sessions
+            .values()
+            .filter(|hello| {
+                hello.filter.is_none()
+                    && Arc::ptr_eq(&hello.logger, logger)
+                    && !hello.is_expired(self.ttl)
+            })
+            .map(|hello| match hello.last_offset.load(Ordering::Relaxed) {
+                u64::MAX => 0,
+                last_offset => last_offset + 1,
+            })
+            .min()
+    }
+
+    /// Records the handshake of a replica, replacing its previous session, and returns the key of
+    /// its session.
+    fn insert(&self, hello: ReplicaHello) -> Result<String, Status> {
+        let key = match (&hello.replica_id, hello.addr) {
+            (Some(_), _) => Uuid::new_v4().to_string(),
+            (None, Some(addr)) => addr_session_key(addr),
+            (None, None) => {
+                return Err(Status::failed_precondition(
+                    "the replica must send its id in its handshake",
+                ))
+            }
+        };
+        let mut sessions = self.sessions.write().unwrap();
+        let mut evicted = 0;
+        sessions.retain(|_, session| {
+            if session.is_expired(self.ttl) {
+                evicted += 1;
+                return false;
+            }
+            hello.replica_id.is_none() || session.replica_id != hello.replica_id
+        });
+        if evicted > 0 {
+            tracing::info!("evicted {evicted} expired replica sessions");
+            metrics::replica_sessions_evicted().fetch_add(evicted, Ordering::Relaxed);
+        }
+        if sessions.len() >= self.max_sessions && !sessions.contains_key(&key) {
+            tracing::warn!(
+                "refusing the handshake of a replica: {} sessions are open",
+                sessions.len()
+            );
+            return Err(Status::resource_exhausted(format!(
+                "too many replica sessions, the limit is {}",
+                self.max_sessions
+            )));
+        }
+        sessions.insert(key.clone(), hello);
+
+        Ok(key)
+    }
+
+    /// The session of the replica that sent `req`, from its session token or its address.
+    fn session<T>(&self, req: &tonic::Request<T>) -> Result<ReplicaHello, Status> {
+        let token = req
+            .metadata()
+            .get(REPLICA_SESSION_METADATA)
+            .and_then(|token| token.to_str().ok());
+        let key = match (token, req.remote_addr()) {
+            (Some(token), _) => token.to_string(),
+            (None, Some(addr)) => addr_session_key(addr),
+            (None, None) => return Err(Status::failed_precondition(NO_HELLO_ERROR_MSG)),
+        };
+        let sessions = self.sessions.read().unwrap();
+        match sessions.get(&key) {
+            Some(hello) if !hello.is_expired(self.ttl) => {
+                *hello.last_seen.lock().unwrap() = Instant::now();
+                Ok(hello.clone())
+            }
+            _ => Err(Status::failed_precondition(NO_HELLO_ERROR_MSG)),
+        }
+    }
+}
+
+/// Rejects the replicas that speak an incompatible version of the protocol.
+fn check_protocol_version(req: &HelloRequest) -> Result<(), Status> {
+    match ProtocolVersion::check_peer(req.protocol_version.as_ref()) {
+        Ok(version) => {
+            tracing::debug!("replica speaks version {version} of the replication protocol");
+            Ok(())
+        }
+        Err(e) => {
+            tracing::warn!("rejecting replica: {e}");
+            Err(Status::failed_precondition(e.to_string()))
+        }
+    }
+}
+
+/// A request of a replica, which presents the session token of its handshake, if it got one.
+pub fn session_request<T>(message: T, session_token: Option<&str>) -> tonic::Request<T> {
+    let mut req = tonic::Request::new(message);
+    if let Some(token) = session_token.and_then(|token| token.parse().ok()) {
+        req.metadata_mut().insert(REPLICA_SESSION_METADATA, token);
+    }
+    req
 }
 
 pub const NO_HELLO_ERROR_MSG: &str = "NO_HELLO";
 pub const NEED_SNAPSHOT_ERROR_MSG: &str = "NEED_SNAPSHOT";
+pub const LOG_CORRUPTED_ERROR_MSG: &str = "LOG_CORRUPTED";
+pub const HISTORY_TRUNCATED_ERROR_MSG: &str = "HISTORY_TRUNCATED";
+pub const LOGICAL_MODE_ERROR_MSG: &str = "LOGICAL_MODE";
 
 impl ReplicationLogService {
     pub fn new(
         logger: Arc<ReplicationLogger>,
+        change_log: Option<Arc<ChangeLog>>,
         idle_shutdown_layer: Option<IdleShutdownLayer>,
+        advertise_addrs: Vec<String>,
     ) -> Self {
         Self {
             logger,
-            replicas_with_hello: RwLock::new(HashSet::<SocketAddr>::new()),
+            change_log,
+            replicas: Default::default(),
+            idle_shutdown_layer,
+            advertise_addrs,
+            standby: None,
+            namespaces: None,
+            batch_limits: BatchLimits::default(),
+            compression: ReplicationCompression::None,
+            snapshot_digests: Default::default(),
+        }
+    }
+
+    /// Bounds the frames read from the log at once for a replica.
+    pub fn with_batch_limits(mut self, batch_limits: BatchLimits) -> Self {
+        self.batch_limits = batch_limits;
+        self
+    }
+
+    /// Compresses the frames streamed to the replicas that support `compression`.
+    pub fn with_compression(mut self, compression: ReplicationCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Records the replicas in `replicas`, shared with the admin API.
+    pub fn with_replicas(mut self, replicas: Arc<Replicas>) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// Lets the replicas replicate the namespaces of `namespaces`.
+    pub fn with_namespaces(mut self, namespaces: Option<Arc<NamespaceStore>>) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Serves the replication log of a standby, up to the frames it has applied.
+    pub fn standby(standby: Arc<Standby>, idle_shutdown_layer: Option<IdleShutdownLayer>) -> Self {
+        Self {
+            logger: standby.logger().clone(),
+            change_log: None,
+            replicas: Default::default(),
             idle_shutdown_layer,
+            advertise_addrs: Vec::new(),
+            standby: Some(standby),
+            namespaces: None,
+            batch_limits: BatchLimits::default(),
+            compression: ReplicationCompression::None,
+            snapshot_digests: Default::default(),
+        }
+    }
+
+    /// Streams the frames of the log of the replica of `session`, from `next_offset`.
+    fn stream_log_entries(
+        &self,
+        session: ReplicaHello,
+        next_offset: FrameNo,
+    ) -> Result<BoxStream<'static, Result<Frame, Status>>, Status> {
+        let frames = self.frame_stream(&session, next_offset)?;
+        let mut progress = StreamProgress::new(&session);
+        let codec = session.codec;
+        let stream = StreamGuard::new(frames, self.idle_shutdown_layer.clone())
+            .map(move |frame| {
+                if let Ok(ref frame) = frame {
+                    progress.record(frame);
+                }
+                map_frame_stream_output(frame, codec)
+            })
+            .boxed();
+
+        Ok(stream)
+    }
+
+    /// Streams the frames of the log of the replica of `session`, from `next_offset`, in batches
+    /// of the frames read at once from the log.
+    fn stream_log_batches(
+        &self,
+        session: ReplicaHello,
+        next_offset: FrameNo,
+    ) -> Result<BoxStream<'static, Result<Frames, Status>>, Status> {
+        let frames = self.frame_stream(&session, next_offset)?;
+        let mut progress = StreamProgress::new(&session);
+        let codec = session.codec;
+        let stream = StreamGuard::new(frames.batches(), self.idle_shutdown_layer.clone())
+            .map(move |batch| {
+                let batch = batch.map_err(map_log_read_error)?;
+                batch.iter().for_each(|frame| progress.record(frame));
+                Ok(Frames {
+                    first_frame_no: batch.first().map_or(next_offset, |f| f.header().frame_no),
+                    frames: batch
+                        .into_iter()
+                        .map(|frame| compress_frame(codec, frame.bytes()))
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .boxed();
+
+        Ok(stream)
+    }
+
+    fn frame_stream(
+        &self,
+        session: &ReplicaHello,
+        next_offset: FrameNo,
+    ) -> Result<FrameStream, Status> {
+        if session.filter.is_some() {
+            return Err(Status::failed_precondition(LOGICAL_MODE_ERROR_MSG));
+        }
+
+        if let Some(ref standby) = self.standby {
+            if next_offset > standby.next_applied_frame_no() {
+                return Err(Status::out_of_range("frame not yet applied by the standby"));
+            }
+        }
+
+        Ok(FrameStream::new(session.logger.clone(), next_offset)
+            .with_batch_limits(self.batch_limits))
+    }
+
+    /// The snapshot of the log of the replica that sent `req`, from the offset of the request,
+    /// and the codec of the frames streamed to the replica.
+    async fn snapshot_file(
+        &self,
+        req: &tonic::Request<LogOffset>,
+    ) -> Result<(SnapshotFile, CompressionCodec), Status> {
+        // the replicas that predate the handshake get the snapshots of the default database
+        let (logger, codec) = match self.replicas.session(req) {
+            Ok(session) => (session.logger, session.codec),
+            Err(e) if req.metadata().contains_key(REPLICA_SESSION_METADATA) => return Err(e),
+            Err(_) => (self.logger.clone(), CompressionCodec::None),
+        };
+        let offset = req.get_ref().next_offset;
+        match tokio::task::spawn_blocking(move || logger.get_snapshot_file(offset)).await {
+            Ok(Ok(Some(snapshot))) => Ok((snapshot, codec)),
+            Ok(Ok(None)) => Err(Status::new(tonic::Code::Unavailable, "snapshot not found")),
+            Ok(Err(e)) if e.is::<HistoryTruncated>() => {
+                tracing::warn!("{e}");
+                Err(Status::failed_precondition(HISTORY_TRUNCATED_ERROR_MSG))
+            }
+            Err(e) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+            Ok(Err(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+        }
+    }
+
+    /// How far behind the log a replica that needs the frames from `next_offset` on is. The
+    /// replicas that present a session token are answered for the database of their session.
+    fn log_status<T>(
+        &self,
+        req: &tonic::Request<T>,
+        next_offset: FrameNo,
+    ) -> Result<LogStatus, Status> {
+        let logger = if req.metadata().contains_key(REPLICA_SESSION_METADATA) {
+            let session = self.replicas.session(req)?;
+            if session.filter.is_some() {
+                return Err(Status::failed_precondition(LOGICAL_MODE_ERROR_MSG));
+            }
+            session.logger
+        } else {
+            self.logger.clone()
+        };
+
+        Ok(LogStatus {
+            next_frame_no: *logger.new_frame_notifier.borrow(),
+            lag_ms: logger
+                .commit_lag(next_offset)
+                .map(|lag| lag.as_millis() as u64),
+        })
+    }
+
+    /// Records the handshake of the replica at `replica_addr`, if its address is known.
+    fn hello_replica(
+        &self,
+        replica_addr: Option<SocketAddr>,
+        req: HelloRequest,
+    ) -> Result<HelloResponse, Status> {
+        let HelloRequest {
+            table_filter,
+            namespace,
+            replica_id,
+            frame_batches,
+            compression_codecs,
+            ..
+        } = req;
+        let logger = match namespace {
+            Some(ref name) => {
+                let Some(ref namespaces) = self.namespaces else {
+                    return Err(Status::failed_precondition(
+                        "namespaces are not enabled on the primary",
+                    ));
+                };
+                if !table_filter.is_empty() {
+                    return Err(Status::failed_precondition(
+                        "namespaces can't be replicated logically",
+                    ));
+                }
+                match namespaces.get(name) {
+                    Ok(namespace) => namespace.logger.clone(),
+                    Err(e) => return Err(Status::not_found(e.to_string())),
+                }
+            }
+            None => self.logger.clone(),
+        };
+        let (filter, mode, logical_log_id) = if table_filter.is_empty() {
+            (None, ReplicationMode::Physical, None)
+        } else {
+            let Some(ref change_log) = self.change_log else {
+                return Err(Status::failed_precondition(
+                    "logical replication is not enabled on the primary",
+                ));
+            };
+            (
+                Some(TableFilter::new(table_filter)),
+                ReplicationMode::Logical,
+                Some(change_log.log_id().to_string()),
+            )
+        };
+        if let Some(ref id) = replica_id {
+            if Uuid::from_str(id).is_err() {
+                return Err(Status::invalid_argument(format!(
+                    "invalid replica id `{id}`"
+                )));
+            }
+        }
+        let codec = match mode {
+            ReplicationMode::Physical => self.compression.negotiate(&compression_codecs),
+            ReplicationMode::Logical => CompressionCodec::None,
+        };
+        let has_token = replica_id.is_some();
+        let session_key = self.replicas.insert(ReplicaHello {
+            replica_id,
+            addr: replica_addr,
+            filter,
+            namespace: namespace.clone(),
+            logger: logger.clone(),
+            codec,
+            last_offset: Arc::new(AtomicU64::new(u64::MAX)),
+            last_seen: Arc::new(Mutex::new(Instant::now())),
+            streams: Arc::new(()),
+        })?;
+        // a standby relays the generation of its primary
+        let (generation_id, generation_start_index) = match self.standby {
+            Some(ref standby) => match standby.upstream_generation() {
+                Some(generation) => (generation.id, generation.start_index),
+                None => {
+                    return Err(Status::unavailable(
+                        "the standby has not reached its primary yet",
+                    ))
+                }
+            },
+            None => (
+                logger.generation.id.to_string(),
+                logger.generation.start_index,
+            ),
+        };
+        let response = HelloResponse {
+            database_id: logger.database_id().unwrap().to_string(),
+            generation_start_index,
+            generation_id,
+            mode: mode.into(),
+            logical_log_id,
+            advertise_addrs: self.advertise_addrs.clone(),
+            protocol_version: Some(ProtocolVersion::CURRENT),
+            namespace,
+            session_token: has_token.then_some(session_key),
+            frame_batches: frame_batches && mode == ReplicationMode::Physical,
+            compression_codec: codec.into(),
+            next_frame_no: (mode == ReplicationMode::Physical)
+                .then(|| *logger.new_frame_notifier.borrow()),
+        };
+
+        Ok(response)
+    }
+}
+
+/// Streams the batches of `change_log` allowed by `filter` to `sender`, starting after `offset`
+/// in log `log_id`. If the position is unknown, a copy of the filtered tables is sent first.
+async fn stream_logical_batches(
+    change_log: Arc<ChangeLog>,
+    filter: TableFilter,
+    mut position: Option<(Uuid, LogicalOffset)>,
+    sender: mpsc::Sender<Result<LogicalBatch, Status>>,
+) {
+    let mut notifier = change_log.subscribe();
+    loop {
+        let _ = notifier.borrow_and_update();
+        let Some((log_id, offset)) = position else {
+            let dump = tokio::task::spawn_blocking({
+                let change_log = change_log.clone();
+                let filter = filter.clone();
+                let sender = sender.clone();
+                move || {
+                    change_log.dump(&filter, |batch| {
+                        sender.blocking_send(Ok((&batch).into())).is_ok()
+                    })
+                }
+            })
+            .await;
+            match dump {
+                Ok(Ok(pos)) => position = Some(pos),
+                Ok(Err(e)) => {
+                    let _ = sender.send(Err(Status::internal(e.to_string()))).await;
+                    return;
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(Status::internal(e.to_string()))).await;
+                    return;
+                }
+            }
+            continue;
+        };
+
+        match change_log.batches_after(log_id, offset) {
+            Ok(batches) if batches.is_empty() => {
+                tokio::select! {
+                    res = notifier.changed() => if res.is_err() { return },
+                    _ = sender.closed() => return,
+                }
+            }
+            Ok(batches) => {
+                for batch in batches {
+                    if let Some(filtered) = filter.filter_batch(&batch) {
+                        if sender.send(Ok((&filtered).into())).await.is_err() {
+                            return;
+                        }
+                    }
+                    position = Some((log_id, batch.offset));
+                }
+            }
+            Err(LogicalReadError::ResyncRequired) => {
+                tracing::debug!("logical replica must resync");
+                position = None;
+            }
         }
     }
 }
 
 fn map_frame_stream_output(
     r: Result<crate::replication::frame::Frame, LogReadError>,
+    codec: CompressionCodec,
 ) -> Result<Frame, Status> {
     match r {
-        Ok(frame) => Ok(Frame {
-            data: frame.bytes(),
-        }),
-        Err(LogReadError::SnapshotRequired) => Err(Status::new(
-            tonic::Code::FailedPrecondition,
-            NEED_SNAPSHOT_ERROR_MSG,
-        )),
-        Err(LogReadError::Error(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+        Ok(frame) => compress_frame(codec, frame.bytes()),
+        Err(e) => Err(map_log_read_error(e)),
+    }
+}
+
+fn compress_frame(codec: CompressionCodec, data: Bytes) -> Result<Frame, Status> {
+    let data = compression::compress(codec, data)
+        .map_err(|e| Status::internal(format!("failed to compress frame: {e}")))?;
+    Ok(Frame { data })
+}
+
+fn map_log_read_error(e: LogReadError) -> Status {
+    match e {
+        LogReadError::SnapshotRequired => {
+            Status::new(tonic::Code::FailedPrecondition, NEED_SNAPSHOT_ERROR_MSG)
+        }
+        e @ LogReadError::Corrupted { .. } => {
+            tracing::error!("{e}");
+            Status::new(tonic::Code::DataLoss, LOG_CORRUPTED_ERROR_MSG)
+        }
+        // the frame stream already retried the read
+        LogReadError::Error(e) => Status::new(tonic::Code::Internal, e.to_string()),
         // this error should be caught before, but we handle it nicely anyways
-        Err(LogReadError::Ahead) => Err(Status::new(
-            tonic::Code::OutOfRange,
-            "frame not yet available",
-        )),
+        LogReadError::Ahead => Status::new(tonic::Code::OutOfRange, "frame not yet available"),
     }
 }
 
@@ -101,49 +771,44 @@ impl<S: futures::stream::Stream + Unpin> futures::stream::Stream for StreamGuard
 #[tonic::async_trait]
 impl ReplicationLog for ReplicationLogService {
     type LogEntriesStream = BoxStream<'static, Result<Frame, Status>>;
+    type BatchLogEntriesStream = BoxStream<'static, Result<Frames, Status>>;
     type SnapshotStream = BoxStream<'static, Result<Frame, Status>>;
+    type ResumableSnapshotStream = BoxStream<'static, Result<SnapshotChunk, Status>>;
+    type LogicalEntriesStream = BoxStream<'static, Result<LogicalBatch, Status>>;
 
     async fn log_entries(
         &self,
         req: tonic::Request<LogOffset>,
     ) -> Result<tonic::Response<Self::LogEntriesStream>, Status> {
-        let replica_addr = req
-            .remote_addr()
-            .ok_or(Status::internal("No remote RPC address"))?;
-        {
-            let guard = self.replicas_with_hello.read().unwrap();
-            if !guard.contains(&replica_addr) {
-                return Err(Status::failed_precondition(NO_HELLO_ERROR_MSG));
-            }
-        }
-
-        let stream = StreamGuard::new(
-            FrameStream::new(self.logger.clone(), req.into_inner().next_offset),
-            self.idle_shutdown_layer.clone(),
-        )
-        .map(map_frame_stream_output)
-        .boxed();
+        let session = self.replicas.session(&req)?;
+        let stream = self.stream_log_entries(session, req.into_inner().next_offset)?;
+        Ok(tonic::Response::new(stream))
+    }
 
+    async fn batch_log_entries(
+        &self,
+        req: tonic::Request<LogOffset>,
+    ) -> Result<tonic::Response<Self::BatchLogEntriesStream>, Status> {
+        let session = self.replicas.session(&req)?;
+        let stream = self.stream_log_batches(session, req.into_inner().next_offset)?;
         Ok(tonic::Response::new(stream))
     }
 
+    async fn get_log_status(
+        &self,
+        req: tonic::Request<LogOffset>,
+    ) -> Result<tonic::Response<LogStatus>, Status> {
+        let status = self.log_status(&req, req.get_ref().next_offset)?;
+        Ok(tonic::Response::new(status))
+    }
+
     async fn hello(
         &self,
         req: tonic::Request<HelloRequest>,
     ) -> Result<tonic::Response<HelloResponse>, Status> {
-        let replica_addr = req
-            .remote_addr()
-            .ok_or(Status::internal("No remote RPC address"))?;
-        {
-            let mut guard = self.replicas_with_hello.write().unwrap();
-            guard.insert(replica_addr);
-        }
-        let response = HelloResponse {
-            database_id: self.logger.database_id().unwrap().to_string(),
-            generation_start_index: self.logger.generation.start_index,
-            generation_id: self.logger.generation.id.to_string(),
-        };
-
+        check_protocol_version(req.get_ref())?;
+        let replica_addr = req.remote_addr();
+        let response = self.hello_replica(replica_addr, req.into_inner())?;
         Ok(tonic::Response::new(response))
     }
 
@@ -152,36 +817,611 @@ impl ReplicationLog for ReplicationLogService {
         req: tonic::Request<LogOffset>,
     ) -> Result<tonic::Response<Self::SnapshotStream>, Status> {
         let (sender, receiver) = mpsc::channel(10);
-        let logger = self.logger.clone();
+        let (snapshot, codec) = self.snapshot_file(&req).await?;
         let offset = req.into_inner().next_offset;
-        match tokio::task::spawn_blocking(move || logger.get_snapshot_file(offset)).await {
-            Ok(Ok(Some(snapshot))) => {
-                tokio::task::spawn_blocking(move || {
-                    let mut frames = snapshot.frames_iter_from(offset);
-                    loop {
-                        match frames.next() {
-                            Some(Ok(data)) => {
-                                let _ = sender.blocking_send(Ok(Frame { data }));
-                            }
-                            Some(Err(e)) => {
-                                let _ = sender.blocking_send(Err(Status::new(
-                                    tonic::Code::Internal,
-                                    e.to_string(),
-                                )));
-                                break;
-                            }
-                            None => {
-                                break;
-                            }
-                        }
+        tokio::task::spawn_blocking(move || {
+            let mut frames = snapshot.frames_iter_from(offset);
+            loop {
+                match frames.next() {
+                    Some(Ok(data)) => {
+                        let _ = sender.blocking_send(compress_frame(codec, data));
+                    }
+                    Some(Err(e)) => {
+                        let _ = sender
+                            .blocking_send(Err(Status::new(tonic::Code::Internal, e.to_string())));
+                        break;
                     }
-                });
+                    None => {
+                        break;
+                    }
+                }
+            }
+        });
 
-                Ok(tonic::Response::new(ReceiverStream::new(receiver).boxed()))
+        Ok(tonic::Response::new(ReceiverStream::new(receiver).boxed()))
+    }
+
+    async fn resumable_snapshot(
+        &self,
+        req: tonic::Request<LogOffset>,
+    ) -> Result<tonic::Response<Self::ResumableSnapshotStream>, Status> {
+        let (sender, receiver) = mpsc::channel(10);
+        let (snapshot, codec) = self.snapshot_file(&req).await?;
+        let LogOffset {
+            next_offset,
+            snapshot_id,
+            frames_received,
+        } = req.into_inner();
+        let digests = self.snapshot_digests.clone();
+        tokio::task::spawn_blocking(move || {
+            let (frame_count, content_hash) = match digests.get(&snapshot, next_offset) {
+                Ok(digest) => digest,
+                Err(e) => {
+                    let _ = sender.blocking_send(Err(Status::internal(e.to_string())));
+                    return;
+                }
+            };
+            let name = snapshot.name();
+            // the replica received the start of this very snapshot
+            let frames_skipped = if snapshot_id.as_deref() == Some(name.as_str()) {
+                frames_received.min(frame_count)
+            } else {
+                0
+            };
+            let header = SnapshotHeader {
+                snapshot_id: name,
+                frame_count,
+                content_hash: content_hash.to_vec(),
+                frames_skipped,
+            };
+            if sender.blocking_send(Ok(header.into())).is_err() {
+                return;
             }
-            Ok(Ok(None)) => Err(Status::new(tonic::Code::Unavailable, "snapshot not found")),
-            Err(e) => Err(Status::new(tonic::Code::Internal, e.to_string())),
-            Ok(Err(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+
+            for frame in snapshot.frames_iter_from_skip(next_offset, frames_skipped) {
+                let chunk = match frame {
+                    Ok(data) => compress_frame(codec, data).map(SnapshotChunk::from),
+                    Err(e) => Err(Status::internal(e.to_string())),
+                };
+                let failed = chunk.is_err();
+                if sender.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(ReceiverStream::new(receiver).boxed()))
+    }
+
+    async fn logical_entries(
+        &self,
+        req: tonic::Request<rpc::LogicalOffset>,
+    ) -> Result<tonic::Response<Self::LogicalEntriesStream>, Status> {
+        let session = self.replicas.session(&req)?;
+        let Some(filter) = session.filter.clone() else {
+            return Err(Status::failed_precondition(
+                "replica is not in logical replication mode",
+            ));
+        };
+        let Some(change_log) = self.change_log.clone() else {
+            return Err(Status::failed_precondition(
+                "logical replication is not enabled on the primary",
+            ));
+        };
+
+        let req = req.into_inner();
+        let position = match (Uuid::from_str(&req.log_id), req.next_offset) {
+            (Ok(log_id), Some(next_offset)) if next_offset > 0 => {
+                Some((log_id, LogicalOffset(next_offset - 1)))
+            }
+            _ => None,
+        };
+
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(stream_logical_batches(change_log, filter, position, sender));
+
+        let stream = StreamGuard::new(
+            ReceiverStream::new(receiver),
+            self.idle_shutdown_layer.clone(),
+        )
+        .map(move |batch| {
+            if let Ok(ref batch) = batch {
+                session.last_offset.store(batch.offset, Ordering::Relaxed);
+            }
+            batch
+        })
+        .boxed();
+
+        Ok(tonic::Response::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::database::libsql::open_db;
+    use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
+
+    use super::*;
+
+    fn write(logger: &Arc<ReplicationLogger>, path: &Path, sql: &str) {
+        let mut ctx = ReplicationLoggerHookCtx::new(logger.clone(), None);
+        let conn = open_db(path, &REPLICATION_METHODS, &mut ctx, None).unwrap();
+        conn.execute_batch(sql).unwrap();
+    }
+
+    /// Streams frames to the replica until it received the last frame of the log, and returns it.
+    async fn catch_up(
+        stream: &mut BoxStream<'static, Result<Frame, Status>>,
+        replicas: &Replicas,
+        logger: &ReplicationLogger,
+    ) -> FrameNo {
+        let last_frame_no = *logger.new_frame_notifier.borrow() - 1;
+        while replicas.status()[0].last_offset != Some(last_frame_no) {
+            tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
         }
+        last_frame_no
+    }
+
+    fn hello_request(replica_id: Option<Uuid>) -> tonic::Request<HelloRequest> {
+        tonic::Request::new(HelloRequest {
+            protocol_version: Some(ProtocolVersion::CURRENT),
+            replica_id: replica_id.map(|id| id.to_string()),
+            ..Default::default()
+        })
+    }
+
+    fn log_offset(token: &str, next_offset: FrameNo) -> tonic::Request<LogOffset> {
+        let mut req = tonic::Request::new(LogOffset {
+            next_offset,
+            ..Default::default()
+        });
+        req.metadata_mut()
+            .insert(REPLICA_SESSION_METADATA, token.parse().unwrap());
+        req
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replica_offset_advances() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let replicas = Arc::new(Replicas::default());
+        let service = ReplicationLogService::new(logger.clone(), None, None, Vec::new())
+            .with_replicas(replicas.clone());
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+
+        // without a remote address, the requests are only matched to a handshake by their token
+        let replica_id = Uuid::new_v4();
+        let res = service.log_entries(log_offset("unknown", 0)).await;
+        assert_eq!(res.err().unwrap().message(), NO_HELLO_ERROR_MSG);
+        let hello = service
+            .hello(hello_request(Some(replica_id)))
+            .await
+            .unwrap()
+            .into_inner();
+        let token = hello.session_token.unwrap();
+        let status = replicas.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].replica_id, Some(replica_id.to_string()));
+        assert_eq!(status[0].addr, None);
+        assert_eq!(status[0].mode, "physical");
+        assert_eq!(status[0].last_offset, None);
+
+        let mut stream = service
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        let first = catch_up(&mut stream, &replicas, &logger).await;
+
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (1)");
+        let second = catch_up(&mut stream, &replicas, &logger).await;
+        assert!(second > first);
+        assert_eq!(replicas.status()[0].last_offset, Some(second));
+
+        // a new handshake of the same replica replaces its session
+        let new_token = service
+            .hello(hello_request(Some(replica_id)))
+            .await
+            .unwrap()
+            .into_inner()
+            .session_token
+            .unwrap();
+        assert_ne!(new_token, token);
+        assert_eq!(replicas.status().len(), 1);
+        assert!(service.log_entries(log_offset(&token, 0)).await.is_err());
+        assert!(service.log_entries(log_offset(&new_token, 0)).await.is_ok());
+
+        // a replica that doesn't send its id must be reachable by address
+        assert!(service.hello(hello_request(None)).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lag_of_paused_replica() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let replicas = Arc::new(Replicas::default());
+        let service = ReplicationLogService::new(logger.clone(), None, None, Vec::new())
+            .with_replicas(replicas.clone());
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+
+        let hello = service
+            .hello(hello_request(Some(Uuid::new_v4())))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            hello.next_frame_no,
+            Some(*logger.new_frame_notifier.borrow())
+        );
+        let token = hello.session_token.unwrap();
+        let mut stream = service
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        let next_offset = catch_up(&mut stream, &replicas, &logger).await + 1;
+        let (service, token) = (&service, &token);
+        let status = |next_offset| async move {
+            service
+                .get_log_status(log_offset(token, next_offset))
+                .await
+                .unwrap()
+                .into_inner()
+        };
+        let up_to_date = status(next_offset).await;
+        assert_eq!(up_to_date.next_frame_no, next_offset);
+        assert_eq!(up_to_date.lag_ms, Some(0));
+
+        // the replica stops receiving frames while the primary keeps committing
+        drop(stream);
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (1)");
+        let lagging = status(next_offset).await;
+        assert!(lagging.next_frame_no > next_offset);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (2)");
+        let later = status(next_offset).await;
+        assert!(later.next_frame_no > lagging.next_frame_no);
+        assert!(later.lag_ms.unwrap() >= lagging.lag_ms.unwrap() + 50);
+
+        // without a token, the status is that of the default database
+        let req = tonic::Request::new(LogOffset {
+            next_offset: 0,
+            ..Default::default()
+        });
+        let status = service.get_log_status(req).await.unwrap().into_inner();
+        assert_eq!(status.next_frame_no, later.next_frame_no);
+        assert!(status.lag_ms.unwrap() >= later.lag_ms.unwrap());
+    }
+
+    #[tokio::test]
+    async fn replica_sessions_expire() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let replicas = Arc::new(Replicas::new(Duration::from_millis(100), 10));
+        let service = ReplicationLogService::new(Arc::new(logger.unwrap()), None, None, Vec::new())
+            .with_replicas(replicas.clone());
+
+        let replica_id = Some(Uuid::new_v4());
+        let hello = service.hello(hello_request(replica_id)).await;
+        let token = hello.unwrap().into_inner().session_token.unwrap();
+        // a streaming session doesn't expire
+        let stream = service.log_entries(log_offset(&token, 0)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(replicas.status().len(), 1);
+
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(replicas.status().is_empty());
+        let res = service.log_entries(log_offset(&token, 0)).await;
+        assert_eq!(res.err().unwrap().message(), NO_HELLO_ERROR_MSG);
+
+        // the replica recovers with a new handshake, which evicts the expired sessions
+        let evicted = metrics::replica_sessions_evicted().load(Ordering::Relaxed);
+        let hello = service.hello(hello_request(replica_id)).await;
+        let token = hello.unwrap().into_inner().session_token.unwrap();
+        assert!(service.log_entries(log_offset(&token, 0)).await.is_ok());
+        assert!(metrics::replica_sessions_evicted().load(Ordering::Relaxed) > evicted);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replicas_pin_the_frames_they_need() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 200, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let replicas = Arc::new(Replicas::new(Duration::from_millis(100), 10));
+        let service = ReplicationLogService::new(logger.clone(), None, None, Vec::new())
+            .with_replicas(replicas.clone());
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+        assert_eq!(replicas.oldest_needed_frame_no(&logger), None);
+
+        let hello = service.hello(hello_request(Some(Uuid::new_v4()))).await;
+        let token = hello.unwrap().into_inner().session_token.unwrap();
+        // until it is streamed frames, the replica may need the whole history
+        assert_eq!(replicas.oldest_needed_frame_no(&logger), Some(0));
+
+        let mut stream = service
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        let first = catch_up(&mut stream, &replicas, &logger).await;
+        assert_eq!(replicas.oldest_needed_frame_no(&logger), Some(first + 1));
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (1)");
+        let second = catch_up(&mut stream, &replicas, &logger).await;
+        assert_eq!(replicas.oldest_needed_frame_no(&logger), Some(second + 1));
+
+        // the replica doesn't replicate the other databases
+        let other = tempfile::tempdir().unwrap();
+        let other = ReplicationLogger::open(other.path(), 200, None, false, Box::new(|_| Ok(())));
+        assert_eq!(
+            replicas.oldest_needed_frame_no(&Arc::new(other.unwrap())),
+            None
+        );
+
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(replicas.oldest_needed_frame_no(&logger), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn frame_read_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let replicas = Arc::new(Replicas::default());
+        let service = ReplicationLogService::new(logger.clone(), None, None, Vec::new())
+            .with_replicas(replicas.clone());
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+        let hello = service.hello(hello_request(Some(Uuid::new_v4()))).await;
+        let token = hello.unwrap().into_inner().session_token.unwrap();
+
+        // a transient error is retried
+        logger.inject_read_faults(1, 2);
+        let mut stream = service
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        catch_up(&mut stream, &replicas, &logger).await;
+        drop(stream);
+
+        // a persistent error ends the stream, the replica resumes from the failed frame
+        logger.inject_read_faults(1, 10);
+        let mut stream = service
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(stream.next().await.unwrap().is_ok());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Internal);
+        assert!(stream.next().await.is_none());
+        assert_eq!(replicas.status()[0].last_offset, Some(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batched_log_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let service = ReplicationLogService::new(logger.clone(), None, None, Vec::new())
+            .with_batch_limits(BatchLimits {
+                max_frames: 2,
+                max_bytes: usize::MAX,
+            });
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+        for i in 0..5 {
+            write(&logger, tmp.path(), &format!("INSERT INTO t VALUES ({i})"));
+        }
+        let last_frame_no = *logger.new_frame_notifier.borrow();
+
+        // the primary only batches the frames of the replicas that ask for it
+        let hello = service.hello(hello_request(Some(Uuid::new_v4()))).await;
+        assert!(!hello.unwrap().into_inner().frame_batches);
+        let mut req = hello_request(Some(Uuid::new_v4()));
+        req.get_mut().frame_batches = true;
+        let hello = service.hello(req).await.unwrap().into_inner();
+        assert!(hello.frame_batches);
+        let token = hello.session_token.unwrap();
+
+        let mut stream = service
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut frames = Vec::new();
+        for _ in 0..last_frame_no {
+            frames.push(stream.next().await.unwrap().unwrap());
+        }
+        drop(stream);
+
+        let mut stream = service
+            .batch_log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut batched_frames = Vec::new();
+        while (batched_frames.len() as FrameNo) < last_frame_no {
+            let batch = stream.next().await.unwrap().unwrap();
+            assert_eq!(batch.first_frame_no, batched_frames.len() as FrameNo);
+            assert!(!batch.frames.is_empty() && batch.frames.len() <= 2);
+            batched_frames.extend(batch.frames);
+        }
+        assert_eq!(batched_frames, frames);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compressed_frames() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        let service = ReplicationLogService::new(logger.clone(), None, None, Vec::new())
+            .with_compression(ReplicationCompression::Zstd);
+        write(&logger, tmp.path(), "CREATE TABLE t (x)");
+        write(&logger, tmp.path(), "INSERT INTO t VALUES (zeroblob(1000))");
+        let last_frame_no = *logger.new_frame_notifier.borrow();
+
+        let stream_frames = |token: String| {
+            let service = &service;
+            async move {
+                let mut stream = service
+                    .log_entries(log_offset(&token, 0))
+                    .await
+                    .unwrap()
+                    .into_inner();
+                let mut frames = Vec::new();
+                for _ in 0..last_frame_no {
+                    frames.push(stream.next().await.unwrap().unwrap().data);
+                }
+                frames
+            }
+        };
+
+        // a replica that doesn't support the codec of the primary receives plain frames
+        let mut req = hello_request(Some(Uuid::new_v4()));
+        req.get_mut().compression_codecs = vec![42];
+        let hello = service.hello(req).await.unwrap().into_inner();
+        assert_eq!(hello.compression_codec(), CompressionCodec::None);
+        let plain = stream_frames(hello.session_token.unwrap()).await;
+        for data in &plain {
+            crate::replication::frame::Frame::try_from_bytes(data.clone()).unwrap();
+        }
+
+        let mut req = hello_request(Some(Uuid::new_v4()));
+        req.get_mut().compression_codecs = vec![CompressionCodec::Zstd.into()];
+        let hello = service.hello(req).await.unwrap().into_inner();
+        assert_eq!(hello.compression_codec(), CompressionCodec::Zstd);
+        let compressed = stream_frames(hello.session_token.unwrap()).await;
+        let mut previous = None;
+        for (data, plain) in compressed.into_iter().zip(&plain) {
+            assert!(data.len() < plain.len());
+            let frame = compression::decompress(CompressionCodec::Zstd, data).unwrap();
+            assert_eq!(&frame.bytes(), plain);
+            if let Some(previous) = previous {
+                assert!(frame.verify_checksum(previous));
+            }
+            previous = Some(frame.header().checksum);
+        }
+    }
+
+    /// Compares the throughput of `LogEntries` and `BatchLogEntries` over a local connection,
+    /// with `cargo test --release frame_batches_throughput -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn frame_batches_throughput() {
+        use rpc::replication_log_client::ReplicationLogClient;
+        use rpc::replication_log_server::ReplicationLogServer;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let logger = Arc::new(logger.unwrap());
+        write(
+            &logger,
+            tmp.path(),
+            "CREATE TABLE t (x);
+            INSERT INTO t
+            WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 50000)
+            SELECT randomblob(1024) FROM s;",
+        );
+        let frame_count = *logger.new_frame_notifier.borrow();
+
+        let service = ReplicationLogService::new(logger, None, None, Vec::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(conn, _)| conn);
+            Some((conn, listener))
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ReplicationLogServer::new(service))
+                .serve_with_incoming(incoming),
+        );
+        let mut client = ReplicationLogClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        let mut req = hello_request(Some(Uuid::new_v4()));
+        req.get_mut().frame_batches = true;
+        let hello = client.hello(req).await.unwrap().into_inner();
+        let token = hello.session_token.unwrap();
+
+        let start = Instant::now();
+        let mut stream = client
+            .log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        for _ in 0..frame_count {
+            stream.next().await.unwrap().unwrap();
+        }
+        let single = start.elapsed();
+        drop(stream);
+
+        let start = Instant::now();
+        let mut stream = client
+            .batch_log_entries(log_offset(&token, 0))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut received = 0;
+        while received < frame_count {
+            received += stream.next().await.unwrap().unwrap().frames.len() as FrameNo;
+        }
+        let batched = start.elapsed();
+
+        let rate = |elapsed: Duration| frame_count as f64 / elapsed.as_secs_f64();
+        println!(
+            "{frame_count} frames: LogEntries in {single:?} ({:.0} frames/s), BatchLogEntries in {batched:?} ({:.0} frames/s)",
+            rate(single),
+            rate(batched),
+        );
+    }
+
+    #[test]
+    fn corrupted_frames_are_data_loss() {
+        let err = LogReadError::Corrupted {
+            frame_no: 1,
+            found: 3,
+        };
+        let status = map_frame_stream_output(Err(err), CompressionCodec::None).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        assert_eq!(status.message(), LOG_CORRUPTED_ERROR_MSG);
+    }
+
+    #[tokio::test]
+    async fn replica_sessions_are_bounded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let logger = ReplicationLogger::open(tmp.path(), 0, None, false, Box::new(|_| Ok(())));
+        let replicas = Arc::new(Replicas::new(Duration::from_millis(100), 2));
+        let service = ReplicationLogService::new(Arc::new(logger.unwrap()), None, None, Vec::new())
+            .with_replicas(replicas.clone());
+
+        let replica_id = Some(Uuid::new_v4());
+        service.hello(hello_request(replica_id)).await.unwrap();
+        service
+            .hello(hello_request(Some(Uuid::new_v4())))
+            .await
+            .unwrap();
+        let res = service.hello(hello_request(Some(Uuid::new_v4()))).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::ResourceExhausted);
+        // a replica that reconnects replaces its own session
+        service.hello(hello_request(replica_id)).await.unwrap();
+        assert_eq!(replicas.status().len(), 2);
+
+        // the expired sessions make room for new replicas
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        service
+            .hello(hello_request(Some(Uuid::new_v4())))
+            .await
+            .unwrap();
+        assert_eq!(replicas.status().len(), 1);
     }
 }
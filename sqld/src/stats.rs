@@ -17,6 +17,10 @@ struct StatsInner {
     rows_written: AtomicU64,
     rows_read: AtomicU64,
     storage_bytes_used: AtomicU64,
+    #[serde(default)]
+    vacuum_pages_reclaimed: AtomicU64,
+    #[serde(default)]
+    vacuum_bytes_reclaimed: AtomicU64,
 }
 
 impl Stats {
@@ -51,6 +55,16 @@ impl Stats {
         self.inner.storage_bytes_used.store(n, Ordering::Relaxed);
     }
 
+    /// records that a vacuum reclaimed `pages` pages, for a total of `bytes` bytes
+    pub fn inc_vacuum_reclaimed(&self, pages: u64, bytes: u64) {
+        self.inner
+            .vacuum_pages_reclaimed
+            .fetch_add(pages, Ordering::Relaxed);
+        self.inner
+            .vacuum_bytes_reclaimed
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
     /// returns the total number of rows read since this database was created
     pub fn rows_read(&self) -> u64 {
         self.inner.rows_read.load(Ordering::Relaxed)
@@ -65,6 +79,16 @@ impl Stats {
     pub fn storage_bytes_used(&self) -> u64 {
         self.inner.storage_bytes_used.load(Ordering::Relaxed)
     }
+
+    /// returns the total number of pages reclaimed by vacuums
+    pub fn vacuum_pages_reclaimed(&self) -> u64 {
+        self.inner.vacuum_pages_reclaimed.load(Ordering::Relaxed)
+    }
+
+    /// returns the total number of bytes reclaimed by vacuums
+    pub fn vacuum_bytes_reclaimed(&self) -> u64 {
+        self.inner.vacuum_bytes_reclaimed.load(Ordering::Relaxed)
+    }
 }
 
 fn spawn_stats_persist_thread(stats: Arc<StatsInner>, mut file: File) {
@@ -0,0 +1,172 @@
+//! Degraded read-only mode, entered when the storage fails.
+//!
+//! When the disk is full, or when writing to the database or to the replication log fails with an
+//! IO error, accepting more writes risks applying them only partially. Instead, the node enters a
+//! degraded mode:
+//! - writes are rejected with [`Error::StorageDegraded`], reads are still served,
+//! - `GET /health` fails, so that the node is taken out of rotation,
+//! - the `storage_degraded_total` metric is incremented.
+//!
+//! The node stays degraded until an operator frees some space and clears the mode with the admin
+//! API, which first checks that the tail of the replication log and the database are intact.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use anyhow::ensure;
+use parking_lot::Mutex;
+use rusqlite::{ErrorCode, OpenFlags};
+
+use crate::error::Error;
+use crate::replication::ReplicationLogger;
+
+static DEGRADATIONS_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of times the node entered the degraded mode since the process started.
+pub fn degradations_total() -> u64 {
+    DEGRADATIONS_TOTAL.load(Ordering::Relaxed)
+}
+
+/// Whether `error` was caused by the storage, rather than by the statement.
+pub fn is_storage_error(error: &rusqlite::Error) -> bool {
+    matches!(
+        error,
+        rusqlite::Error::SqliteFailure(e, _)
+            if matches!(e.code, ErrorCode::DiskFull | ErrorCode::SystemIoFailure)
+    )
+}
+
+#[derive(Default)]
+pub struct StorageHealth {
+    degraded: AtomicBool,
+    /// The cause of the first storage failure since the node was last healthy.
+    reason: Mutex<Option<String>>,
+}
+
+impl StorageHealth {
+    /// Enters the degraded mode. Failures reported while already degraded are only logged.
+    pub fn degrade(&self, reason: impl Into<String>) {
+        let reason = reason.into();
+        let mut current = self.reason.lock();
+        if current.is_some() {
+            tracing::debug!("storage failure while already degraded: {reason}");
+            return;
+        }
+
+        tracing::error!(
+            "STORAGE DEGRADED: writes are rejected until space is freed and the degraded mode is \
+            cleared with `POST /v1/storage/clear_degraded` on the admin API. The node is now \
+            reported as unhealthy. Reason: {reason}"
+        );
+        DEGRADATIONS_TOTAL.fetch_add(1, Ordering::Relaxed);
+        *current = Some(reason);
+        self.degraded.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Returns an error if writes must be rejected.
+    pub fn check_writable(&self) -> Result<(), Error> {
+        if !self.is_degraded() {
+            return Ok(());
+        }
+
+        let reason = self.reason.lock().clone().unwrap_or_default();
+        Err(Error::StorageDegraded(reason))
+    }
+
+    /// Checks the replication log and the database of the node at `db_path`, and leaves the
+    /// degraded mode if they are intact.
+    pub fn clear(&self, db_path: &Path, logger: Option<&ReplicationLogger>) -> anyhow::Result<()> {
+        if let Some(logger) = logger {
+            logger.verify_tail()?;
+        }
+        quick_check(&db_path.join("data"))?;
+
+        let mut reason = self.reason.lock();
+        if let Some(reason) = reason.take() {
+            tracing::info!("storage checked, leaving degraded mode entered because of: {reason}");
+        }
+        self.degraded.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Runs `PRAGMA quick_check` on the database at `data_path`. The connection is read-only, so that
+/// it doesn't checkpoint the WAL behind the back of the replication log.
+fn quick_check(data_path: &Path) -> anyhow::Result<()> {
+    let conn = rusqlite::Connection::open_with_flags(
+        data_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let problems = conn
+        .prepare("PRAGMA quick_check")?
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure!(
+        problems == ["ok"],
+        "database quick check failed: {}",
+        problems.join("; ")
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn degrade_and_clear() {
+        let tmp = tempfile::tempdir().unwrap();
+        rusqlite::Connection::open(tmp.path().join("data"))
+            .unwrap()
+            .execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+            .unwrap();
+
+        let health = StorageHealth::default();
+        assert!(health.check_writable().is_ok());
+
+        let total = degradations_total();
+        health.degrade("database or disk is full");
+        health.degrade("disk I/O error");
+        assert!(health.is_degraded());
+        assert_eq!(degradations_total(), total + 1);
+        // the first failure is reported
+        assert!(matches!(
+            health.check_writable(),
+            Err(Error::StorageDegraded(reason)) if reason == "database or disk is full"
+        ));
+
+        health.clear(tmp.path(), None).unwrap();
+        assert!(!health.is_degraded());
+        assert!(health.check_writable().is_ok());
+    }
+
+    #[test]
+    fn stay_degraded_if_database_is_corrupt() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("data"), vec![42; 4096]).unwrap();
+
+        let health = StorageHealth::default();
+        health.degrade("disk I/O error");
+        assert!(health.clear(tmp.path(), None).is_err());
+        assert!(health.is_degraded());
+    }
+
+    #[test]
+    fn storage_errors() {
+        let full = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_FULL),
+            None,
+        );
+        let constraint = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+            None,
+        );
+        assert!(is_storage_error(&full));
+        assert!(!is_storage_error(&constraint));
+    }
+}
@@ -0,0 +1,322 @@
+//! Supervision of the tasks of the server.
+//!
+//! Every long-running task of the server (the listeners, the replicator, the periodic compactions,
+//! ...) is registered in the [`System`], which reports the tasks that exit, and stops all of them
+//! when the server shuts down, restarts, or is reset. Embedders of `sqld` register their own tasks
+//! with [`crate::run_server_with_tasks`], so that they are supervised and stopped together with the
+//! services of `sqld`.
+//!
+//! The shutdown goes through the [`ShutdownPhase`]s in order. In each phase, the tasks registered
+//! for that phase are stopped: tasks that follow the [`ShutdownSignal`] are given a grace period
+//! to finish their work, the others are cancelled right away.
+
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+
+use crate::utils::panic::catch_panic_async;
+use crate::utils::supervisor::supervise;
+pub use crate::utils::supervisor::{Restart, RestartPolicy};
+
+/// Time given to the tasks following the [`ShutdownSignal`] to stop, in each phase of the shutdown.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The phases of the shutdown, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownPhase {
+    /// Stop accepting new connections and requests.
+    StopAccepting,
+    /// Finish serving the connections and requests in flight.
+    Drain,
+    /// Stop the background tasks: replication, compactions, statistics...
+    StopBackground,
+}
+
+impl ShutdownPhase {
+    const ALL: [ShutdownPhase; 3] = [Self::StopAccepting, Self::Drain, Self::StopBackground];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Handle passed to the supervised tasks, to find out when they must stop.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<Option<ShutdownPhase>>,
+}
+
+impl ShutdownSignal {
+    /// Waits until the shutdown reaches `phase`. Also returns if the [`System`] was dropped.
+    pub async fn reached(&self, phase: ShutdownPhase) {
+        let mut receiver = self.receiver.clone();
+        loop {
+            if receiver.borrow().map_or(false, |current| current >= phase) {
+                return;
+            }
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// The current phase of the shutdown, `None` if the system is not shutting down.
+    pub fn phase(&self) -> Option<ShutdownPhase> {
+        *self.receiver.borrow()
+    }
+}
+
+/// The exit of a task registered in the [`System`].
+#[derive(Debug)]
+pub struct TaskExit {
+    pub name: &'static str,
+    pub result: anyhow::Result<()>,
+}
+
+#[derive(Default)]
+struct PhaseTasks {
+    tasks: JoinSet<TaskExit>,
+    /// The tasks that don't follow the shutdown signal, and are cancelled right away.
+    cancel: Vec<AbortHandle>,
+}
+
+/// The supervised tasks of the server.
+pub struct System {
+    phases: [PhaseTasks; 3],
+    shutdown: watch::Sender<Option<ShutdownPhase>>,
+    grace_period: Duration,
+}
+
+impl Default for System {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl System {
+    pub fn new() -> Self {
+        Self {
+            phases: Default::default(),
+            shutdown: watch::channel(None).0,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+
+    /// Sets the time given to the tasks to stop in each phase of the shutdown.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Registers a background task, which is cancelled when the background tasks are stopped, and
+    /// is not restarted.
+    pub fn register<Fut>(&mut self, fut: Fut, name: &'static str)
+    where
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.register_in(ShutdownPhase::StopBackground, fut, name)
+    }
+
+    /// Registers a task which is cancelled in the given phase of the shutdown, and is not
+    /// restarted.
+    pub fn register_in<Fut>(&mut self, phase: ShutdownPhase, fut: Fut, name: &'static str)
+    where
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let phase = &mut self.phases[phase.index()];
+        let handle = phase.tasks.spawn(async move {
+            let result = catch_panic_async(fut)
+                .await
+                .unwrap_or_else(|msg| Err(anyhow::anyhow!("{name} panicked: {msg}")));
+            TaskExit { name, result }
+        });
+        phase.cancel.push(handle);
+    }
+
+    /// Registers a task built by `make_task`, which is not restarted. The task is given a
+    /// [`ShutdownSignal`], and must return once the shutdown reaches `phase`.
+    pub fn register_graceful<F, Fut>(
+        &mut self,
+        phase: ShutdownPhase,
+        make_task: F,
+        name: &'static str,
+    ) where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let fut = make_task(self.signal());
+        self.phases[phase.index()].tasks.spawn(async move {
+            let result = catch_panic_async(fut)
+                .await
+                .unwrap_or_else(|msg| Err(anyhow::anyhow!("{name} panicked: {msg}")));
+            TaskExit { name, result }
+        });
+    }
+
+    /// Registers a task built by `make_task`, which is restarted according to `policy`. The task
+    /// is given a [`ShutdownSignal`], and must return once the shutdown reaches `phase`.
+    pub fn supervise<F, Fut>(
+        &mut self,
+        name: &'static str,
+        phase: ShutdownPhase,
+        policy: RestartPolicy,
+        mut make_task: F,
+    ) where
+        F: FnMut(ShutdownSignal) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let signal = self.signal();
+        self.phases[phase.index()].tasks.spawn(async move {
+            let result = supervise(name, policy, move || make_task(signal.clone())).await;
+            TaskExit { name, result }
+        });
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: self.shutdown.subscribe(),
+        }
+    }
+
+    /// Waits for the next task to exit. Returns `None` if there are no tasks left.
+    pub async fn next_exit(&mut self) -> Option<TaskExit> {
+        let [accepting, draining, background] = &mut self.phases;
+        loop {
+            let joined = tokio::select! {
+                Some(joined) = accepting.tasks.join_next() => joined,
+                Some(joined) = draining.tasks.join_next() => joined,
+                Some(joined) = background.tasks.join_next() => joined,
+                else => return None,
+            };
+
+            match joined {
+                Ok(exit) => return Some(exit),
+                // panics are caught in the tasks, they can only have been cancelled
+                Err(e) if e.is_cancelled() => continue,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+    }
+
+    /// Stops all the tasks, phase by phase.
+    pub async fn shutdown(mut self) {
+        for phase in ShutdownPhase::ALL {
+            tracing::debug!("shutdown phase: {phase:?}");
+            self.shutdown.send_replace(Some(phase));
+            let PhaseTasks { tasks, cancel } = &mut self.phases[phase.index()];
+            cancel.drain(..).for_each(|handle| handle.abort());
+
+            let grace_period = tokio::time::sleep(self.grace_period);
+            tokio::pin!(grace_period);
+            loop {
+                tokio::select! {
+                    joined = tasks.join_next() => match joined {
+                        Some(Ok(TaskExit { name, result: Err(e) })) => {
+                            tracing::warn!("{name} failed while shutting down: {e:#}");
+                        }
+                        Some(_) => (),
+                        None => break,
+                    },
+                    _ = &mut grace_period => {
+                        tracing::warn!(
+                            "{} tasks did not stop within {:?} in phase {phase:?}, cancelling them",
+                            tasks.len(),
+                            self.grace_period,
+                        );
+                        tasks.shutdown().await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn report_failed_task() {
+        let mut system = System::new();
+        system.register(futures::future::pending::<anyhow::Result<()>>(), "idle");
+        system.register_in(
+            ShutdownPhase::StopAccepting,
+            async { anyhow::bail!("address already in use") },
+            "listener",
+        );
+
+        let exit = system.next_exit().await.unwrap();
+        assert_eq!(exit.name, "listener");
+        assert_eq!(
+            exit.result.unwrap_err().to_string(),
+            "address already in use"
+        );
+    }
+
+    #[tokio::test]
+    async fn restart_on_failure() {
+        let mut system = System::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        system.supervise(
+            "flaky",
+            ShutdownPhase::StopBackground,
+            RestartPolicy::on_failure(2, Duration::ZERO),
+            {
+                let runs = runs.clone();
+                move |_| {
+                    let runs = runs.clone();
+                    async move {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                        anyhow::bail!("connection refused")
+                    }
+                }
+            },
+        );
+
+        let exit = system.next_exit().await.unwrap();
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            exit.result.unwrap_err().to_string(),
+            "flaky failed: connection refused, giving up after 2 restarts"
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_in_phases() {
+        let mut system = System::new().with_grace_period(Duration::from_secs(1));
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+        for phase in [
+            ShutdownPhase::StopBackground,
+            ShutdownPhase::Drain,
+            ShutdownPhase::StopAccepting,
+        ] {
+            let stopped = stopped.clone();
+            system.supervise("task", phase, RestartPolicy::never(), move |signal| {
+                let stopped = stopped.clone();
+                async move {
+                    signal.reached(phase).await;
+                    stopped.lock().unwrap().push(phase);
+                    Ok(())
+                }
+            });
+        }
+        // a task ignoring the signal is cancelled
+        system.register(futures::future::pending::<anyhow::Result<()>>(), "stuck");
+
+        system.shutdown().await;
+        assert_eq!(
+            *stopped.lock().unwrap(),
+            vec![
+                ShutdownPhase::StopAccepting,
+                ShutdownPhase::Drain,
+                ShutdownPhase::StopBackground
+            ]
+        );
+    }
+}
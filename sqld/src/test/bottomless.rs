@@ -1,12 +1,11 @@
 use crate::{run_server, Config};
 use anyhow::Result;
-use libsql_client::{Connection, QueryResult, Statement, Value};
+use sqld_client::{Client, QueryObject, ResultSet, Value};
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
 use std::time::Duration;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
-use url::Url;
 
 const S3_URL: &str = "http://localhost:9000/";
 
@@ -35,7 +34,7 @@ async fn backup_restore() {
         .unwrap()
         .next()
         .unwrap();
-    let connection_addr = Url::parse(&format!("http://localhost:{}", PORT)).unwrap();
+    let connection_addr = Client::new(format!("http://localhost:{}", PORT));
     let db_config = Config {
         bottomless_replication: Some(bottomless::replicator::Options {
             create_bucket_if_not_exists: true,
@@ -101,19 +100,14 @@ async fn backup_restore() {
         let result = sql(&connection_addr, ["SELECT id, name FROM t ORDER BY id;"])
             .await
             .unwrap();
-        let rs = result
-            .into_iter()
-            .next()
-            .unwrap()
-            .into_result_set()
-            .unwrap();
+        let rs = result.into_iter().next().unwrap();
         const OPS_CEIL: usize = (OPS + 9) / 10;
         assert_eq!(rs.rows.len(), OPS_CEIL, "unexpected number of rows");
         let base = if OPS < 10 { 0 } else { OPS - 10 } as i64;
         for (i, row) in rs.rows.iter().enumerate() {
             let i = i as i64;
-            let id = row.cells["id"].clone();
-            let name = row.cells["name"].clone();
+            let id = row[0].clone();
+            let name = row[1].clone();
             assert_eq!(
                 (id, name),
                 (Value::Integer(i), Value::Text((base + i).to_string())),
@@ -166,19 +160,14 @@ async fn backup_restore() {
         let result = sql(&connection_addr, ["SELECT id, name FROM t ORDER BY id;"])
             .await
             .unwrap();
-        let rs = result
-            .into_iter()
-            .next()
-            .unwrap()
-            .into_result_set()
-            .unwrap();
+        let rs = result.into_iter().next().unwrap();
         const OPS_CEIL: usize = (OPS + 9) / 10;
         assert_eq!(rs.rows.len(), OPS_CEIL, "unexpected number of rows");
         let base = if OPS < 10 { 0 } else { OPS - 10 } as i64;
         for (i, row) in rs.rows.iter().enumerate() {
             let i = i as i64;
-            let id = row.cells["id"].clone();
-            let name = row.cells["name"].clone();
+            let id = row[0].clone();
+            let name = row[1].clone();
             assert_eq!(
                 (id, name),
                 (Value::Integer(i), Value::Text(format!("{}-x", base + i))),
@@ -199,16 +188,15 @@ async fn rollback_restore() {
     const PATH: &str = "rollback_restore.sqld";
     const PORT: u16 = 15002;
 
-    async fn get_data(conn: &Url) -> Result<Vec<(Value, Value)>> {
-        let result = sql(conn, ["SELECT * FROM t"]).await?;
+    async fn get_data(conn: &Client) -> Result<Vec<(Value, Value)>> {
+        let result = sql(conn, ["SELECT id, name FROM t"]).await?;
         let rows = result
             .into_iter()
             .next()
             .unwrap()
-            .into_result_set()?
             .rows
             .into_iter()
-            .map(|row| (row.cells["id"].clone(), row.cells["name"].clone()))
+            .map(|row| (row[0].clone(), row[1].clone()))
             .collect();
         Ok(rows)
     }
@@ -221,7 +209,7 @@ async fn rollback_restore() {
         .unwrap()
         .next()
         .unwrap();
-    let conn = Url::parse(&format!("http://localhost:{}", PORT)).unwrap();
+    let conn = Client::new(format!("http://localhost:{}", PORT));
     let db_config = Config {
         bottomless_replication: Some(bottomless::replicator::Options {
             create_bucket_if_not_exists: true,
@@ -314,13 +302,41 @@ async fn rollback_restore() {
     }
 }
 
-async fn sql<I, S>(url: &Url, stmts: I) -> Result<Vec<QueryResult>>
+#[tokio::test]
+async fn unreachable_storage() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let tmp = tempfile::tempdir().unwrap();
+    let data_path = tmp.path().join("data");
+    let options = bottomless::replicator::Options {
+        aws_endpoint: Some("http://localhost:1/".into()),
+        bucket_name: "testunreachable".to_string(),
+        ..bottomless::replicator::Options::from_env().unwrap()
+    };
+
+    // an empty node can't tell whether its database is in the backup
+    assert!(crate::restore_bottomless(&data_path, options.clone(), None)
+        .await
+        .is_err());
+
+    // a node with a database of its own starts without bottomless replication
+    let conn = rusqlite::Connection::open(&data_path).unwrap();
+    conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+        .unwrap();
+    drop(conn);
+    let size = data_path.metadata().unwrap().len();
+    assert!(crate::restore_bottomless(&data_path, options, None)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(data_path.metadata().unwrap().len(), size);
+}
+
+async fn sql<I, S>(client: &Client, stmts: I) -> Result<Vec<ResultSet>>
 where
     I: IntoIterator<Item = S>,
-    S: Into<Statement>,
+    S: Into<QueryObject>,
 {
-    let db = libsql_client::reqwest::Connection::connect_from_url(url)?;
-    db.batch(stmts).await
+    Ok(client.execute(stmts).await?)
 }
 
 /// Checks if the corresponding bucket is empty (has any elements) or not.
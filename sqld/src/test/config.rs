@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+
+use crate::Config;
+
+fn validation_error(config: Config) -> String {
+    config.validate().unwrap_err().to_string()
+}
+
+#[test]
+fn default_config_is_valid() {
+    Config::default().validate().unwrap();
+}
+
+#[test]
+fn invalid_configs_are_rejected() {
+    let http_tls = Config {
+        http_tls_cert: Some(PathBuf::from("cert.pem")),
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(http_tls),
+        "HTTP TLS requires both a certificate (`--http-tls-cert-file`) and a private key (`--http-tls-key-file`)"
+    );
+
+    let client_certs = Config {
+        http_tls_ca_cert: Some(PathBuf::from("ca.pem")),
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(client_certs),
+        "client certificates (`--http-tls-ca-cert-file`) require HTTP TLS (`--http-tls-cert-file`)"
+    );
+
+    let grpc_tls = Config {
+        rpc_server_tls: true,
+        rpc_server_cert: Some(PathBuf::from("cert.pem")),
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(grpc_tls),
+        "gRPC TLS (`--grpc-tls`) requires a certificate (`--grpc-cert-file`) and a private key (`--grpc-key-file`)"
+    );
+
+    let primary_tls = Config {
+        writer_rpc_addr: Some("https://primary:5001".into()),
+        writer_rpc_tls: true,
+        writer_rpc_key: Some(PathBuf::from("key.pem")),
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(primary_tls),
+        "TLS to the primary (`--primary-grpc-tls`) requires a certificate (`--primary-grpc-cert-file`) and a private key (`--primary-grpc-key-file`)"
+    );
+
+    let replica_listener = Config {
+        writer_rpc_addr: Some("http://primary:5001".into()),
+        rpc_server_addr: Some("0.0.0.0:5001".parse().unwrap()),
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(replica_listener.clone()),
+        "`--grpc-listen-addr` can only be used with `--primary-grpc-url` by a standby (`--standby`)"
+    );
+    Config {
+        standby: true,
+        ..replica_listener
+    }
+    .validate()
+    .unwrap();
+
+    let standby = Config {
+        standby: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(standby),
+        "a standby (`--standby`) replicates a primary, set with `--primary-grpc-url`"
+    );
+
+    let replica_load = Config {
+        writer_rpc_addr: Some("http://primary:5001".into()),
+        load_from: Some(PathBuf::from("app.db")),
+        ..Default::default()
+    };
+    assert_eq!(
+        validation_error(replica_load),
+        "a replica can't load a dump or a database file (`--load-from-dump`, `--load-from`), load it into the primary"
+    );
+}
+
+#[test]
+fn secrets_are_redacted() {
+    let config = Config {
+        http_auth: Some("basic:c2VjcmV0".into()),
+        auth_jwt_key: Some("jwt-secret-key".into()),
+        consistency_token_key: Some("token-secret".into()),
+        http_self_url: Some("http://sqld:8080".into()),
+        ..Default::default()
+    };
+    let logged = format!("{:?}", config.redacted());
+    for secret in ["c2VjcmV0", "jwt-secret-key", "token-secret"] {
+        assert!(!logged.contains(secret), "{secret} is logged");
+    }
+    assert!(logged.contains("<redacted>"));
+    assert!(logged.contains("http://sqld:8080"));
+    assert_eq!(config.redacted().admin_auth, None);
+}
@@ -0,0 +1,270 @@
+//! TLS of the listeners of the clients: the HTTP API, and Hrana over WebSockets. The certificate
+//! files are read again on `SIGHUP`, and when they are modified: the new connections use the new
+//! certificates, the open ones are not affected.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use parking_lot::RwLock;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Time given to a client to complete the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate of the server, and the CA of the clients for mutual TLS, reloaded from their
+/// files when they change.
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /// Clients must present a certificate signed by this CA, if set.
+    ca_cert_path: Option<PathBuf>,
+    current: RwLock<Loaded>,
+}
+
+struct Loaded {
+    acceptor: TlsAcceptor,
+    /// Modification times of the files, when they were loaded.
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl TlsConfig {
+    pub fn load(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        ca_cert_path: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let loaded = read(&cert_path, &key_path, ca_cert_path.as_deref())?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            ca_cert_path,
+            current: RwLock::new(loaded),
+        })
+    }
+
+    /// The acceptor of the new connections, with the current certificates.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.current.read().acceptor.clone()
+    }
+
+    /// Reads the files again. The current certificates are kept if they can't be read.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let loaded = read(
+            &self.cert_path,
+            &self.key_path,
+            self.ca_cert_path.as_deref(),
+        )?;
+        *self.current.write() = loaded;
+        tracing::info!("reloaded the TLS certificate {}", self.cert_path.display());
+        Ok(())
+    }
+
+    /// Reads the files again if one of them was modified since they were loaded, and returns
+    /// whether they were.
+    pub fn reload_if_modified(&self) -> anyhow::Result<bool> {
+        let paths = [
+            Some(self.cert_path.as_path()),
+            Some(self.key_path.as_path()),
+            self.ca_cert_path.as_deref(),
+        ];
+        if modified(&paths) == self.current.read().modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+}
+
+fn modified(paths: &[Option<&Path>]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .flatten()
+        .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn read(cert_path: &Path, key_path: &Path, ca_cert_path: Option<&Path>) -> anyhow::Result<Loaded> {
+    // the times are read first, so that a file modified while it is read is read again
+    let modified = modified(&[Some(cert_path), Some(key_path), ca_cert_path]);
+    let certs = read_certs(cert_path)?;
+    let key = read_key(key_path)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match ca_cert_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+
+    Ok(Loaded {
+        acceptor: TlsAcceptor::from(Arc::new(config)),
+        modified,
+    })
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("could not read the certificates of {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificate in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("could not read the private key of {}", path.display()))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key)
+                | rustls_pemfile::Item::RSAKey(key),
+            ) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key in {}", path.display()),
+        }
+    }
+}
+
+/// Reloads the certificates on `SIGHUP`, and when their files are modified, which is checked every
+/// `interval`.
+pub async fn watch(config: Arc<TlsConfig>, interval: Duration) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        #[cfg(unix)]
+        let res = tokio::select! {
+            _ = hangup.recv() => config.reload(),
+            _ = interval.tick() => config.reload_if_modified().map(|_| ()),
+        };
+        #[cfg(not(unix))]
+        let res = {
+            interval.tick().await;
+            config.reload_if_modified().map(|_| ())
+        };
+        if let Err(e) = res {
+            tracing::error!(
+                "could not reload the TLS certificates, keeping the current ones: {e:#}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use tokio_rustls::rustls::{ClientConfig, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    pub fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("assets/test/tls")
+            .join(name)
+    }
+
+    /// A connector that trusts the certificates of `ca`, and presents the client certificate if
+    /// `client_cert` is set.
+    pub fn connector(ca: &str, client_cert: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&fixture(ca)).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if client_cert {
+            builder
+                .with_client_auth_cert(
+                    read_certs(&fixture("client.pem")).unwrap(),
+                    read_key(&fixture("client-key.pem")).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    pub fn localhost() -> ServerName {
+        ServerName::try_from("localhost").unwrap()
+    }
+
+    #[test]
+    fn invalid_files() {
+        let err = TlsConfig::load(fixture("server.pem"), fixture("ca.pem"), None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("no private key"), "{err}");
+
+        let err = TlsConfig::load(fixture("missing.pem"), fixture("server-key.pem"), None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("could not open"), "{err}");
+
+        // the key doesn't match the certificate
+        assert!(TlsConfig::load(fixture("server.pem"), fixture("client-key.pem"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_modified_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cert = tmp.path().join("cert.pem");
+        let key = tmp.path().join("key.pem");
+        std::fs::copy(fixture("server.pem"), &cert).unwrap();
+        std::fs::copy(fixture("server-key.pem"), &key).unwrap();
+        let config = TlsConfig::load(cert.clone(), key.clone(), None).unwrap();
+        assert!(!config.reload_if_modified().unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(config);
+        tokio::spawn({
+            let config = config.clone();
+            async move {
+                loop {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    let _ = config.acceptor().accept(socket).await;
+                }
+            }
+        });
+        let handshake = |ca: &'static str| async move {
+            let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            connector(ca, false).connect(localhost(), socket).await
+        };
+        assert!(handshake("ca.pem").await.is_ok());
+        assert!(handshake("other-ca.pem").await.is_err());
+
+        // an invalid file is not loaded
+        std::fs::write(&key, "").unwrap();
+        assert!(config.reload_if_modified().is_err());
+        assert!(handshake("ca.pem").await.is_ok());
+
+        std::fs::copy(fixture("other-server.pem"), &cert).unwrap();
+        std::fs::copy(fixture("other-server-key.pem"), &key).unwrap();
+        assert!(config.reload_if_modified().unwrap());
+        assert!(handshake("other-ca.pem").await.is_ok());
+        assert!(handshake("ca.pem").await.is_err());
+    }
+}
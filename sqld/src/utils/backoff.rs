@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How long to wait before trying to reach a peer again after failed attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffPolicy {
+    /// Delay after the first failure.
+    pub initial: Duration,
+    /// Upper bound of the delay, however many attempts failed.
+    pub max: Duration,
+    /// Number of consecutive failed attempts after which the peer is given up on, `None` to try
+    /// forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            max_attempts: Some(100),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// The delay after `failures` consecutive failures: the delay doubles with each failure up to
+    /// `max`, and half of it is random, so that the replicas of a primary that went down don't all
+    /// reconnect at once.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        let base = self.initial.saturating_mul(factor).min(self.max);
+        let half = base / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Tracks the attempts to reach a peer, and logs when it becomes reachable or unreachable, rather
+/// than on each attempt.
+#[derive(Debug)]
+pub struct Reconnect {
+    peer: &'static str,
+    policy: BackoffPolicy,
+    failures: u32,
+    /// Whether the last attempt succeeded, `None` before the first one.
+    connected: Option<bool>,
+}
+
+impl Reconnect {
+    pub fn new(peer: &'static str, policy: BackoffPolicy) -> Self {
+        Self {
+            peer,
+            policy,
+            failures: 0,
+            connected: None,
+        }
+    }
+
+    /// Number of consecutive failed attempts.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether the policy gives up on the peer after the attempts that failed.
+    pub fn exhausted(&self) -> bool {
+        self.policy
+            .max_attempts
+            .is_some_and(|max| self.failures >= max)
+    }
+
+    pub fn succeeded(&mut self) {
+        if self.connected != Some(true) {
+            tracing::info!("{} is reachable", self.peer);
+        }
+        self.connected = Some(true);
+        self.failures = 0;
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one.
+    pub fn failed(&mut self, error: impl std::fmt::Display) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let delay = self.policy.delay(self.failures);
+        if self.connected != Some(false) {
+            tracing::warn!(
+                "{} is unreachable, retrying with a backoff of up to {:?}: {error}",
+                self.peer,
+                self.policy.max
+            );
+        } else {
+            tracing::debug!(
+                "{} is still unreachable after {} attempts, retrying in {delay:?}: {error}",
+                self.peer,
+                self.failures
+            );
+        }
+        self.connected = Some(false);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delay_grows_up_to_max() {
+        let policy = BackoffPolicy {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(2),
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay(0), Duration::ZERO);
+        for failures in 1..100 {
+            let base = (Duration::from_millis(100) * 2u32.pow(failures.min(20) - 1))
+                .min(Duration::from_secs(2));
+            let delay = policy.delay(failures);
+            assert!(delay >= base / 2 && delay <= base, "{failures}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn delay_is_jittered() {
+        let policy = BackoffPolicy::default();
+        let delays: std::collections::HashSet<_> = (0..20).map(|_| policy.delay(10)).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn reset_on_success() {
+        let policy = BackoffPolicy {
+            max_attempts: Some(2),
+            ..Default::default()
+        };
+        let mut reconnect = Reconnect::new("primary", policy);
+        reconnect.failed("refused");
+        assert!(!reconnect.exhausted());
+        reconnect.failed("refused");
+        assert!(reconnect.exhausted());
+        reconnect.succeeded();
+        assert_eq!(reconnect.failures(), 0);
+        assert!(!reconnect.exhausted());
+    }
+}
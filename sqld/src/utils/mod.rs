@@ -1 +1,5 @@
+pub mod backoff;
+pub mod panic;
 pub mod services;
+pub mod shared;
+pub mod supervisor;
@@ -0,0 +1,77 @@
+//! Panic isolation helpers.
+//!
+//! A panic while serving a client must only affect that client: the helpers in this module catch
+//! the panic, log it along with the backtrace captured by the panic hook, and count it in the
+//! `panics_total` metric.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+
+use futures::FutureExt;
+
+static PANICS_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Backtrace of the last panic that happened on this thread, set by the panic hook.
+    static LAST_BACKTRACE: RefCell<Option<Backtrace>> = RefCell::new(None);
+}
+
+/// Installs a panic hook capturing the backtrace of every panic, so that it can be logged once the
+/// panic is caught. The previous hook is still called.
+pub fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            LAST_BACKTRACE.with(|bt| *bt.borrow_mut() = Some(Backtrace::force_capture()));
+            prev(info);
+        }));
+    });
+}
+
+/// Returns the number of panics caught since the process started.
+pub fn panics_total() -> u64 {
+    PANICS_TOTAL.load(Ordering::Relaxed)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "<unknown panic payload>"
+    }
+}
+
+/// Logs and counts a caught panic, and returns its message. Must be called on the thread that
+/// panicked for the backtrace to be logged.
+pub fn report_panic(payload: &(dyn Any + Send)) -> String {
+    PANICS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    let msg = panic_message(payload).to_string();
+    match LAST_BACKTRACE.with(|bt| bt.borrow_mut().take()) {
+        Some(backtrace) => tracing::error!("caught panic: {msg}\n{backtrace}"),
+        None => tracing::error!("caught panic: {msg}"),
+    }
+
+    msg
+}
+
+/// Calls `f`, catching and reporting any panic. On panic, the message of the panic is returned.
+pub fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| report_panic(&*payload))
+}
+
+/// Polls `fut` to completion, catching and reporting any panic. On panic, the message of the panic
+/// is returned.
+pub async fn catch_panic_async<F: Future>(fut: F) -> Result<F::Output, String> {
+    AssertUnwindSafe(fut)
+        .catch_unwind()
+        .await
+        .map_err(|payload| report_panic(&*payload))
+}
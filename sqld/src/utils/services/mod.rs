@@ -1 +1,2 @@
 pub mod idle_shutdown;
+pub mod request_decompression;
@@ -0,0 +1,364 @@
+use std::io;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use bytes::Bytes;
+use futures::future::{self, BoxFuture};
+use futures::{Stream, StreamExt, TryStreamExt};
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower::{Layer, Service};
+
+use crate::http::error;
+
+/// Decompresses `gzip` and `zstd` encoded request bodies, and rejects requests whose body is
+/// larger than `max_size` once decompressed. The bodies are decompressed as they are read by the
+/// handler: one that fails to read its body because it's too large, or not validly encoded, is
+/// answered with `413` or `400`. Streamed bodies (`application/x-ndjson`, and the SQL dumps in
+/// `application/sql`) are not limited, their size is checked by the handler. The size of the
+/// imported dumps is not limited.
+#[derive(Clone, Copy)]
+pub struct RequestDecompressionLayer {
+    max_size: u64,
+}
+
+impl RequestDecompressionLayer {
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+}
+
+impl<S> Layer<S> for RequestDecompressionLayer {
+    type Service = RequestDecompression<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestDecompression {
+            inner,
+            max_size: self.max_size,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestDecompression<S> {
+    inner: S,
+    max_size: u64,
+}
+
+impl<S> Service<Request<Body>> for RequestDecompression<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // the service that was polled ready must be the one called.
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(call(inner, req, self.max_size))
+    }
+}
+
+fn too_large(max_size: u64) -> Response<Body> {
+    error(
+        &format!("request body is larger than {max_size} bytes"),
+        StatusCode::PAYLOAD_TOO_LARGE,
+    )
+}
+
+/// Why the body of a request couldn't be read to the end by the handler.
+enum BodyError {
+    TooLarge,
+    Invalid(String),
+}
+
+/// Set by the body of a request when it fails, and turned into the response by the layer.
+type BodyErrorSlot = Arc<Mutex<Option<BodyError>>>;
+
+enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+async fn call<S>(
+    mut inner: S,
+    req: Request<Body>,
+    max_size: u64,
+) -> Result<Response<Body>, S::Error>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    let body_error = BodyErrorSlot::default();
+    let req = match decompress(req, max_size, &body_error) {
+        Ok(req) => req,
+        Err(resp) => return Ok(resp),
+    };
+    let res = inner.call(req).await;
+    // the handler failed to read the body, whatever it answered
+    let body_error = body_error.lock().take();
+    match body_error {
+        Some(BodyError::TooLarge) => Ok(too_large(max_size)),
+        Some(BodyError::Invalid(e)) => Ok(error(
+            &format!("invalid compressed request body: {e}"),
+            StatusCode::BAD_REQUEST,
+        )),
+        None => res,
+    }
+}
+
+fn decompress(
+    req: Request<Body>,
+    max_size: u64,
+    body_error: &BodyErrorSlot,
+) -> Result<Request<Body>, Response<Body>> {
+    let encoding = req
+        .headers()
+        .get(CONTENT_ENCODING)
+        .map(|v| v.to_str().unwrap_or_default().trim().to_ascii_lowercase());
+    let encoding = match encoding.as_deref() {
+        None | Some("identity") => {
+            if is_dump(req.headers()) {
+                return Ok(req);
+            }
+            let content_length = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+            if content_length.map_or(false, |len| len > max_size) {
+                return Err(too_large(max_size));
+            }
+            // a chunked body has no length upfront
+            let (parts, body) = req.into_parts();
+            let body = limited(body.map_err(io_error), max_size, body_error.clone());
+            return Ok(Request::from_parts(parts, body));
+        }
+        Some("gzip" | "x-gzip") => Encoding::Gzip,
+        Some("zstd") => Encoding::Zstd,
+        Some(other) => {
+            return Err(error(
+                &format!("unsupported content encoding `{other}`"),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ))
+        }
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let reader = StreamReader::new(body.map_err(io_error));
+    let decoded = match encoding {
+        Encoding::Gzip => ReaderStream::new(GzipDecoder::new(reader)).boxed(),
+        Encoding::Zstd => ReaderStream::new(ZstdDecoder::new(reader)).boxed(),
+    };
+    let body = if is_streamed(&parts.headers) {
+        Body::wrap_stream(decoded)
+    } else {
+        let decoded = decoded.map_err({
+            let body_error = body_error.clone();
+            move |e| {
+                body_error
+                    .lock()
+                    .get_or_insert(BodyError::Invalid(e.to_string()));
+                e
+            }
+        });
+        limited(decoded, max_size, body_error.clone())
+    };
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+
+    Ok(Request::from_parts(parts, body))
+}
+
+fn io_error(e: hyper::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// A body that fails once `stream` yields more than `max_size` bytes.
+fn limited(
+    stream: impl Stream<Item = io::Result<Bytes>> + Send + 'static,
+    max_size: u64,
+    body_error: BodyErrorSlot,
+) -> Body {
+    let mut read = 0u64;
+    Body::wrap_stream(stream.and_then(move |chunk| {
+        read += chunk.len() as u64;
+        let res = if read > max_size {
+            *body_error.lock() = Some(BodyError::TooLarge);
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("request body is larger than {max_size} bytes"),
+            ))
+        } else {
+            Ok(chunk)
+        };
+        future::ready(res)
+    }))
+}
+
+fn content_type(headers: &hyper::HeaderMap) -> Option<&str> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or_default().trim())
+}
+
+fn is_streamed(headers: &hyper::HeaderMap) -> bool {
+    matches!(
+        content_type(headers),
+        Some("application/x-ndjson" | "application/sql")
+    )
+}
+
+fn is_dump(headers: &hyper::HeaderMap) -> bool {
+    content_type(headers) == Some("application/sql")
+}
+
+#[cfg(test)]
+mod test {
+    use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+    use tokio::io::AsyncReadExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn compress(data: &[u8], encoding: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        match encoding {
+            "gzip" => GzipEncoder::new(data).read_to_end(&mut out).await.unwrap(),
+            "zstd" => ZstdEncoder::new(data).read_to_end(&mut out).await.unwrap(),
+            _ => unreachable!(),
+        };
+        out
+    }
+
+    /// Sends a request with `body` encoded with `encoding` to a service that echoes the body.
+    async fn send(body: Vec<u8>, encoding: Option<&str>, max_size: u64) -> Response<Body> {
+        send_with_type(body, encoding, "application/json", max_size).await
+    }
+
+    async fn send_with_type(
+        body: Vec<u8>,
+        encoding: Option<&str>,
+        content_type: &str,
+        max_size: u64,
+    ) -> Response<Body> {
+        let service = RequestDecompressionLayer::new(max_size).layer(tower::service_fn(
+            |req: Request<Body>| async move {
+                assert!(req.headers().get(CONTENT_ENCODING).is_none());
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                Ok::<_, hyper::Error>(Response::new(body.into()))
+            },
+        ));
+        let mut req = Request::post("/")
+            .header(CONTENT_LENGTH, body.len())
+            .header(CONTENT_TYPE, content_type);
+        if let Some(encoding) = encoding {
+            req = req.header(CONTENT_ENCODING, encoding);
+        }
+
+        service
+            .oneshot(req.body(body.into()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body(resp: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn decompress_body() {
+        let payload = br#"{"statements": ["select 1"]}"#.repeat(1000);
+        for encoding in ["gzip", "zstd"] {
+            let compressed = compress(&payload, encoding).await;
+            let resp = send(compressed, Some(encoding), 1024 * 1024).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(body(resp).await, payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_gzip_stream() {
+        let payload = br#"{"statements": ["select 1"]}"#.repeat(1000);
+        let mut compressed = compress(&payload, "gzip").await;
+        compressed.truncate(compressed.len() / 2);
+
+        let resp = send(compressed, Some("gzip"), 1024 * 1024).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn decompressed_size_limit() {
+        // compresses to a few KB, but decompresses to 10MB
+        let payload = vec![0; 10 * 1024 * 1024];
+        let compressed = compress(&payload, "gzip").await;
+        assert!(compressed.len() < 1024 * 1024);
+
+        let resp = send(compressed, Some("gzip"), 1024 * 1024).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn streamed_body_is_not_limited() {
+        // the size of a streamed body is checked by its handler
+        let payload = vec![0; 10 * 1024 * 1024];
+        let compressed = compress(&payload, "gzip").await;
+        let resp = send_with_type(compressed, Some("gzip"), "application/x-ndjson", 1024).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await, payload);
+    }
+
+    #[tokio::test]
+    async fn uncompressed_size_limit() {
+        let resp = send(vec![0; 1024], None, 1024).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = send(vec![0; 1025], None, 1024).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = send(vec![0; 1025], Some("br"), 1024).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn chunked_size_limit() {
+        let service = RequestDecompressionLayer::new(1024).layer(tower::service_fn(
+            |req: Request<Body>| async move {
+                hyper::body::to_bytes(req.into_body()).await?;
+                Ok::<_, hyper::Error>(Response::new(Body::empty()))
+            },
+        ));
+        let chunks = vec![Ok::<_, io::Error>(vec![0; 1000]), Ok(vec![0; 1000])];
+        let req = Request::post("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let resp = service.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn dump_size_is_not_limited() {
+        let dump = b"INSERT INTO t VALUES(1);\n".repeat(100);
+        let resp = send_with_type(dump.clone(), None, "application/sql", 1024).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await, dump);
+
+        let compressed = compress(&dump, "gzip").await;
+        let resp = send_with_type(compressed, Some("gzip"), "application/sql", 1024).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await, dump);
+    }
+}
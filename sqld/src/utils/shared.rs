@@ -0,0 +1,41 @@
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+/// A setting shared by the tasks that read it, which is replaced as a whole when the configuration
+/// is reloaded. The readers get a copy of the current value, and keep it for as long as they need
+/// a consistent one.
+pub struct Shared<T>(Arc<RwLock<T>>);
+
+impl<T: Clone> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(value)))
+    }
+
+    pub fn get(&self) -> T {
+        self.0.read().clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write() = value;
+    }
+}
+
+impl<T: Clone> From<T> for Shared<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.read().fmt(f)
+    }
+}
@@ -0,0 +1,93 @@
+use std::future::Future;
+use std::time::Duration;
+
+use super::panic::catch_panic_async;
+
+/// Which exits of a supervised task cause it to be restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// The task is never restarted.
+    Never,
+    /// The task is restarted when it panics.
+    OnPanic,
+    /// The task is restarted when it panics or returns an error.
+    OnFailure,
+}
+
+/// How a supervised task is restarted after it failed.
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    /// Maximum number of restarts before the failure is considered fatal.
+    pub max_restarts: usize,
+    /// Delay before restarting the task.
+    pub backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            restart: Restart::OnPanic,
+            max_restarts: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn never() -> Self {
+        Self {
+            restart: Restart::Never,
+            ..Default::default()
+        }
+    }
+
+    pub fn on_failure(max_restarts: usize, backoff: Duration) -> Self {
+        Self {
+            restart: Restart::OnFailure,
+            max_restarts,
+            backoff,
+        }
+    }
+}
+
+/// Runs the task built by `make_task`, and builds and runs it again if it fails, as long as
+/// `policy` allows it.
+///
+/// By default only panics are recovered: a task returning an error is a failure of the service it
+/// provides, and the error is returned to the caller as is, unless the policy is
+/// [`Restart::OnFailure`]. Tasks serving a single connection should not be supervised; catching
+/// their panic with [`catch_panic_async`] is enough.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    policy: RestartPolicy,
+    mut make_task: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut restarts = 0;
+    loop {
+        let failure = match catch_panic_async(make_task()).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) if policy.restart != Restart::OnFailure => return Err(e),
+            Ok(Err(e)) => format!("failed: {e:#}"),
+            Err(msg) => format!("panicked: {msg}"),
+        };
+
+        if policy.restart == Restart::Never {
+            anyhow::bail!("{name} {failure}")
+        }
+        if restarts >= policy.max_restarts {
+            anyhow::bail!("{name} {failure}, giving up after {restarts} restarts")
+        }
+
+        restarts += 1;
+        tracing::error!(
+            "{name} {failure}, restarting ({restarts}/{})",
+            policy.max_restarts
+        );
+        tokio::time::sleep(policy.backoff).await;
+    }
+}
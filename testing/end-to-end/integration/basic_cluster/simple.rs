@@ -1,54 +1,32 @@
 use std::time::Duration;
 
 use octopod::App;
-use serde_json::json;
+use sqld_client::Client;
 
 #[octopod::test(app = "simple-cluster")]
 async fn proxy_write(app: App) {
     let replica_ip = app.service("replica").unwrap().ip().await.unwrap();
     let primary_ip = app.service("primary").unwrap().ip().await.unwrap();
-    let primary_url = format!("http://{primary_ip}:8080/");
-    let replica_url = format!("http://{replica_ip}:8080/");
-    let client = reqwest::Client::new();
+    let primary = Client::new(format!("http://{primary_ip}:8080"));
+    let replica = Client::new(format!("http://{replica_ip}:8080"));
 
     // perform a write to the writer and ensure it's proxied to to primary
-    let payload =
-        json!({ "statements": ["create table test (x)", "insert into test values (123)"] });
-    let resp = client
-        .post(&replica_url)
-        .json(&payload)
-        .send()
+    let results = replica
+        .execute(["create table test (x)", "insert into test values (123)"])
         .await
         .unwrap();
-    assert_eq!(resp.status(), 200);
-    let json: serde_json::Value = resp.json().await.unwrap();
-    insta::assert_json_snapshot!(json);
+    insta::assert_json_snapshot!(results);
 
     // read from primary to ensure it got the write
-    let payload = json!({ "statements": ["select * from test"] });
-    let resp = client
-        .post(&primary_url)
-        .json(&payload)
-        .send()
-        .await
-        .unwrap();
-    assert_eq!(resp.status(), 200);
-    let json: serde_json::Value = resp.json().await.unwrap();
-    insta::assert_json_snapshot!(json);
+    let results = primary.execute(["select * from test"]).await.unwrap();
+    insta::assert_json_snapshot!(results);
 
     // wait for replication
     tokio::time::sleep(Duration::from_secs(2)).await;
 
     // read from replica to ensure replication
-    let resp = client
-        .post(&replica_url)
-        .json(&payload)
-        .send()
-        .await
-        .unwrap();
-    assert_eq!(resp.status(), 200);
-    let json: serde_json::Value = resp.json().await.unwrap();
-    insta::assert_json_snapshot!(json);
+    let results = replica.execute(["select * from test"]).await.unwrap();
+    insta::assert_json_snapshot!(results);
 }
 
 #[octopod::test(app = "simple-cluster")]
@@ -56,40 +34,20 @@ async fn replica_catch_up(app: App) {
     let replica = app.service("replica").unwrap();
     replica.pause().await.unwrap();
     let primary_ip = app.service("primary").unwrap().ip().await.unwrap();
-    let primary_url = format!("http://{primary_ip}:8080/");
-    let client = reqwest::Client::new();
-
-    let payload = json!({ "statements": ["create table test (x)"] });
-    let resp = client
-        .post(&primary_url)
-        .json(&payload)
-        .send()
-        .await
-        .unwrap();
-    assert_eq!(resp.status(), 200);
+    let primary = Client::new(format!("http://{primary_ip}:8080"));
+
+    primary.execute(["create table test (x)"]).await.unwrap();
     // insert a few entries
     for i in 0..100 {
-        let payload = json!({ "statements": [format!("insert into test values (\"value{i}\")")] });
-        let resp = client
-            .post(&primary_url)
-            .json(&payload)
-            .send()
+        primary
+            .execute([format!("insert into test values (\"value{i}\")")])
             .await
             .unwrap();
-        assert_eq!(resp.status(), 200);
     }
 
     // check that everything is there
-    let payload = json!({ "statements": [format!("select * from test")] });
-    let resp = client
-        .post(&primary_url)
-        .json(&payload)
-        .send()
-        .await
-        .unwrap();
-    assert_eq!(resp.status(), 200);
-    let json: serde_json::Value = resp.json().await.unwrap();
-    insta::assert_json_snapshot!(json);
+    let results = primary.execute(["select * from test"]).await.unwrap();
+    insta::assert_json_snapshot!(results);
 
     // bring back replica to the network
     replica.unpause().await.unwrap();
@@ -97,16 +55,59 @@ async fn replica_catch_up(app: App) {
     tokio::time::sleep(Duration::from_secs(3)).await;
 
     let replica_ip = replica.ip().await.unwrap();
-    let replica_url = format!("http://{replica_ip}:8080/");
+    let replica_client = Client::new(format!("http://{replica_ip}:8080"));
     // check that everything is there
-    let payload = json!({ "statements": [format!("select * from test")] });
-    let resp = client
-        .post(&replica_url)
-        .json(&payload)
-        .send()
+    let results = replica_client
+        .execute(["select * from test"])
+        .await
+        .unwrap();
+    insta::assert_json_snapshot!(results);
+}
+
+#[octopod::test(app = "simple-cluster")]
+async fn replicate_triggers(app: App) {
+    let replica_ip = app.service("replica").unwrap().ip().await.unwrap();
+    let primary_ip = app.service("primary").unwrap().ip().await.unwrap();
+    let primary = Client::new(format!("http://{primary_ip}:8080"));
+    let replica = Client::new(format!("http://{replica_ip}:8080"));
+
+    primary
+        .execute([
+            "create table orders (id integer primary key, amount)",
+            "create table totals (n, amount)",
+            "insert into totals values (0, 0)",
+            "create trigger count_orders after insert on orders \
+            begin update totals set n = n + 1, amount = amount + new.amount; end",
+            "create view big_orders as select * from orders where amount >= 100",
+            "create trigger insert_big_orders instead of insert on big_orders \
+            begin insert into orders (amount) values (max(new.amount, 100)); end",
+        ])
+        .await
+        .unwrap();
+
+    // writes through the view and the triggers are proxied to the primary
+    replica
+        .execute([
+            "insert into orders (amount) values (10)",
+            "insert into big_orders (amount) values (20)",
+        ])
         .await
         .unwrap();
-    assert_eq!(resp.status(), 200);
-    let json: serde_json::Value = resp.json().await.unwrap();
-    insta::assert_json_snapshot!(json);
+    primary
+        .execute(["insert into big_orders (amount) values (500)"])
+        .await
+        .unwrap();
+
+    // wait for replication
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let queries = [
+        "select n, amount from totals",
+        "select id, amount from big_orders",
+    ];
+    let on_primary = primary.execute(queries).await.unwrap();
+    let on_replica = replica.execute(queries).await.unwrap();
+    assert_eq!(on_primary[0].rows.len(), 1);
+    assert_eq!(on_primary[1].rows.len(), 2);
+    assert_eq!(on_primary, on_replica);
 }
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, StatusCode};
+use octopod::App;
+use sqld_client::{Client, Value};
+
+#[octopod::test(app = "failover-cluster")]
+async fn promote_standby(app: App) {
+    let primary = app.service("primary").unwrap();
+    let primary_ip = primary.ip().await.unwrap();
+    let standby_ip = app.service("standby").unwrap().ip().await.unwrap();
+    let replica_ip = app.service("replica").unwrap().ip().await.unwrap();
+    let primary_client = Client::new(format!("http://{primary_ip}:8080"));
+    let standby_client = Client::new(format!("http://{standby_ip}:8080"));
+    let replica_client = Client::new(format!("http://{replica_ip}:8080"));
+
+    primary_client
+        .execute(["create table test (x)"])
+        .await
+        .unwrap();
+    for i in 0..50 {
+        primary_client
+            .execute([format!("insert into test values ({i})")])
+            .await
+            .unwrap();
+    }
+
+    // wait for the standby, and the replica of the standby, to catch up
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // the primary is lost
+    primary.pause().await.unwrap();
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{standby_ip}:9090/v1/promote"))
+        .body(Body::empty())
+        .unwrap();
+    let resp = hyper::Client::new().request(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // wait for the standby to restart as a primary
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    for i in 50..100 {
+        standby_client
+            .execute([format!("insert into test values ({i})")])
+            .await
+            .unwrap();
+    }
+
+    // the replica resumes from its offset, without losing or replaying any frame
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let results = replica_client
+        .execute(["select count(*), count(distinct x), min(x), max(x) from test"])
+        .await
+        .unwrap();
+    assert_eq!(
+        results[0].rows,
+        vec![vec![
+            Value::Integer(100),
+            Value::Integer(100),
+            Value::Integer(0),
+            Value::Integer(99),
+        ]]
+    );
+}
@@ -1,4 +1,5 @@
 mod basic_cluster;
+mod failover;
 
 use anyhow::bail;
 use clap::Parser;
@@ -24,6 +25,36 @@ fn create_simple_cluster_app() -> AppConfig {
     app
 }
 
+/// A primary, a standby of the primary, and a replica of the standby.
+fn create_failover_cluster_app() -> AppConfig {
+    let mut app = AppConfig::new("failover-cluster");
+    app.add_service(
+        ServiceConfig::new("primary", "sqld")
+            .env([("SQLD_NODE", "primary"), ("RUST_LOG", "sqld=debug")])
+            .health("/health", 8080),
+    );
+    app.add_service(
+        ServiceConfig::new("standby", "sqld")
+            .env([
+                ("SQLD_NODE", "standby"),
+                ("RUST_LOG", "sqld=debug"),
+                ("SQLD_PRIMARY_URL", "http://primary:5001"),
+                ("SQLD_ADMIN_LISTEN_ADDR", "0.0.0.0:9090"),
+            ])
+            .health("/health", 8080),
+    );
+    app.add_service(
+        ServiceConfig::new("replica", "sqld")
+            .env([
+                ("SQLD_NODE", "replica"),
+                ("RUST_LOG", "sqld=debug"),
+                ("SQLD_PRIMARY_URL", "http://standby:5001"),
+            ])
+            .health("/health", 8080),
+    );
+    app
+}
+
 #[derive(clap::Parser)]
 struct Opts {
     #[clap(long, env = "SQLD_TEST_PODMAN_ADDR", requires("run"))]
@@ -39,7 +70,7 @@ async fn main() -> anyhow::Result<()> {
     if opts.run {
         let success = Octopod::init(
             opts.podman_addr.as_ref().unwrap(),
-            vec![create_simple_cluster_app()],
+            vec![create_simple_cluster_app(), create_failover_cluster_app()],
         )?
         .run()
         .await?;